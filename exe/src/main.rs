@@ -1,4 +1,4 @@
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use daemonize::Daemonize;
 use env_logger::Env;
 use libmount::mountinfo;
@@ -8,20 +8,43 @@ use nix::mount::umount;
 use nix::unistd::Uid;
 use os_pipe::{PipeReader, PipeWriter};
 use puzzlefs_lib::{
-    builder::{add_rootfs_delta, build_initial_rootfs, enable_fs_verity},
-    compression::{Noop, Zstd},
-    extractor::extract_rootfs,
+    builder::{
+        add_rootfs_delta_with_options, build_initial_rootfs_with_options, enable_fs_verity,
+        recorded_chunk_parameters, recorded_compression_dictionary, BuildOptions, BuildProgress,
+        BuildReport, Chunking, ChunkingParams, IdMap, IdMapping, InodeNumbering, PathFilter,
+        ProgressReporter, SpecialFileAction, SpecialFilePolicy, XattrFilter,
+    },
+    compression::{Compression, Lz4, Noop, Xz, Zstd, ZstdDictionary},
+    convert::{convert_docker_archive, convert_oci_image, StagedRootfs},
+    encryption::{Cipher, Encryption, EncryptionKey},
+    export::{export_block_image, export_oci_image_with_format, ExportFormat},
+    extractor::{extract_delta, extract_image, extract_image_with_verity},
+    format::{self, escape_path, unescape_name, DigestAlgorithm, Features},
     fsverity_helpers::get_fs_verity_digest,
-    oci::Image,
-    reader::{fuse::PipeDescriptor, mount, spawn_mount},
+    hook::{HookMount, State},
+    http::{BlobServer, HttpServer},
+    image_store::ImageStore,
+    nydus::{export_nydus, import_nydus},
+    oci::{parse_platform, BlobCache, BlobSource, Image, LayoutBlobStore, LazyFetcher},
+    reader::{fuse::PipeDescriptor, mount, spawn_mount, verify_image, PuzzleFS},
+    registry::{
+        pull, pull_lazy, push, sync, Reference, Registry, RegistryOptions, TransferProgress,
+        TransferReporter, DEFAULT_PARALLELISM,
+    },
+    signature::{SignaturePolicy, SigningKey},
+    vectors,
 };
+use std::any::Any;
 use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
+use std::io::IsTerminal;
+use std::os::unix::ffi::OsStringExt;
 use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::Arc;
+use std::time::Duration;
 use syslog::{BasicLogger, Facility, Formatter3164};
 
 #[derive(Parser)]
@@ -38,6 +61,121 @@ enum SubCommand {
     Umount(Umount),
     Extract(Extract),
     EnableFsVerity(FsVerity),
+    Convert(Convert),
+    ConvertDockerArchive(ConvertDockerArchive),
+    Export(Export),
+    ImportNydus(ImportNydus),
+    ExportNydus(ExportNydus),
+    ExportBlock(ExportBlock),
+    Push(Push),
+    Sync(Push),
+    Pull(Pull),
+    Copy(Copy),
+    Gc(Gc),
+    Tags(Tags),
+    DeleteTag(DeleteTag),
+    Retag(Retag),
+    Annotations(Annotations),
+    Config(Config),
+    Stats(Stats),
+    Dump(Dump),
+    Fsck(Fsck),
+    Verify(Verify),
+    Repair(Repair),
+    Upgrade(Upgrade),
+    StoreAdd(StoreAdd),
+    StoreRemove(StoreRemove),
+    StoreMount(StoreMount),
+    Hook(Hook),
+    ServeBlobs(ServeBlobs),
+    GenVectors(GenVectors),
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum HookStage {
+    CreateRuntime,
+    Poststop,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CompressionAlgorithm {
+    Zstd,
+    Lz4,
+    Xz,
+}
+
+impl From<format::CompressionAlgorithm> for CompressionAlgorithm {
+    fn from(algorithm: format::CompressionAlgorithm) -> Self {
+        match algorithm {
+            format::CompressionAlgorithm::Zstd => CompressionAlgorithm::Zstd,
+            format::CompressionAlgorithm::Lz4 => CompressionAlgorithm::Lz4,
+            format::CompressionAlgorithm::Xz => CompressionAlgorithm::Xz,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SpecialFiles {
+    Include,
+    Skip,
+    Error,
+}
+
+impl From<SpecialFiles> for SpecialFileAction {
+    fn from(special_files: SpecialFiles) -> Self {
+        match special_files {
+            SpecialFiles::Include => SpecialFileAction::Include,
+            SpecialFiles::Skip => SpecialFileAction::Skip,
+            SpecialFiles::Error => SpecialFileAction::Error,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum InodeNumbers {
+    Rebased,
+    PerLayer,
+}
+
+impl From<InodeNumbers> for InodeNumbering {
+    fn from(numbers: InodeNumbers) -> Self {
+        match numbers {
+            InodeNumbers::Rebased => InodeNumbering::Rebased,
+            InodeNumbers::PerLayer => InodeNumbering::PerLayer,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportLayerFormat {
+    Tar,
+    ZstdChunked,
+}
+
+impl From<ExportLayerFormat> for ExportFormat {
+    fn from(format: ExportLayerFormat) -> Self {
+        match format {
+            ExportLayerFormat::Tar => ExportFormat::Tar,
+            ExportLayerFormat::ZstdChunked => ExportFormat::ZstdChunked,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum EncryptionCipher {
+    #[value(name = "aes-256-gcm")]
+    Aes256Gcm,
+    #[value(name = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+}
+
+impl From<EncryptionCipher> for Cipher {
+    fn from(cipher: EncryptionCipher) -> Self {
+        match cipher {
+            EncryptionCipher::Aes256Gcm => Cipher::Aes256Gcm,
+            EncryptionCipher::XChaCha20Poly1305 => Cipher::XChaCha20Poly1305,
+        }
+    }
 }
 
 #[derive(Args)]
@@ -48,6 +186,129 @@ struct Build {
     base_layer: Option<String>,
     #[arg(short, long, value_name = "compressed")]
     compression: bool,
+    #[arg(
+        long,
+        value_name = "level",
+        requires = "compression",
+        value_parser = clap::value_parser!(i32).range(1..=22)
+    )]
+    compression_level: Option<i32>,
+    /// zstd by default, or the algorithm of the base layer if it's compressed
+    #[arg(long, value_enum, requires = "compression")]
+    compression_algorithm: Option<CompressionAlgorithm>,
+    /// a pre-trained zstd dictionary, e.g. from `zstd --train`; deltas compressed with zstd use
+    /// the one of their base layer by default
+    #[arg(long, value_name = "file", requires = "compression")]
+    compression_dictionary: Option<PathBuf>,
+    #[arg(long, value_name = "bytes")]
+    min_chunk_size: Option<u32>,
+    #[arg(long, value_name = "bytes")]
+    avg_chunk_size: Option<u32>,
+    #[arg(long, value_name = "bytes")]
+    max_chunk_size: Option<u32>,
+    #[arg(
+        long,
+        value_name = "bytes",
+        conflicts_with_all = ["min_chunk_size", "avg_chunk_size", "max_chunk_size"]
+    )]
+    fixed_chunk_size: Option<u32>,
+    #[arg(long, requires = "fixed_chunk_size")]
+    pack_small_files: bool,
+    #[arg(long, value_name = "bytes")]
+    max_blob_size: Option<u64>,
+    #[arg(long, value_name = "bytes")]
+    pack_files_below: Option<u32>,
+    #[arg(long, value_name = "bytes")]
+    inline_files_below: Option<u32>,
+    #[arg(long)]
+    reproducible: bool,
+    #[arg(long, requires = "base_layer")]
+    layer_diff: bool,
+    #[arg(long, requires = "base_layer")]
+    thin_delta: bool,
+    #[arg(long, value_name = "json file")]
+    report: Option<PathBuf>,
+    #[arg(long, value_name = "pattern")]
+    include: Vec<String>,
+    #[arg(long, value_name = "pattern")]
+    exclude: Vec<String>,
+    #[arg(long, value_name = "prefix")]
+    keep_xattr: Vec<String>,
+    #[arg(long, value_name = "prefix")]
+    drop_xattr: Vec<String>,
+    #[arg(long, value_name = "from=to", value_parser = parse_xattr_rename)]
+    rename_xattr: Vec<(String, String)>,
+    #[arg(long, value_name = "uid:gid", value_parser = parse_chown)]
+    chown: Option<(u32, u32)>,
+    #[arg(long, value_name = "image:host:count", value_parser = parse_id_mapping)]
+    uid_map: Vec<IdMapping>,
+    #[arg(long, value_name = "image:host:count", value_parser = parse_id_mapping)]
+    gid_map: Vec<IdMapping>,
+    #[arg(long, value_enum, default_value_t = SpecialFiles::Include)]
+    devices: SpecialFiles,
+    #[arg(long, value_enum, default_value_t = SpecialFiles::Include)]
+    sockets: SpecialFiles,
+    #[arg(long, value_enum, default_value_t = SpecialFiles::Include)]
+    fifos: SpecialFiles,
+    #[arg(long, value_enum, value_name = "cipher", requires = "key_file")]
+    encrypt: Option<EncryptionCipher>,
+    #[arg(long, value_name = "file")]
+    key_file: Option<PathBuf>,
+    #[arg(long)]
+    one_file_system: bool,
+    #[arg(long, requires = "one_file_system")]
+    keep_mountpoints: bool,
+    #[arg(long)]
+    dry_run: bool,
+    #[arg(long, conflicts_with = "dry_run")]
+    resume: bool,
+    /// the platform of the image, as os/arch[/variant], instead of the host's
+    #[arg(long, value_name = "os/arch")]
+    platform: Option<String>,
+    /// an annotation for the manifest, e.g. org.opencontainers.image.revision=<commit>
+    #[arg(long, value_name = "key=value", value_parser = parse_annotation)]
+    annotation: Vec<(String, String)>,
+    /// an annotation for the rootfs blob
+    #[arg(long, value_name = "key=value", value_parser = parse_annotation)]
+    rootfs_annotation: Vec<(String, String)>,
+    /// an OCI image config (JSON) with the entrypoint, environment and labels of the image
+    #[arg(long, value_name = "config.json")]
+    config: Option<PathBuf>,
+    /// the features the puzzlefs releases reading the image support, e.g. sparse,hardlinks; none
+    /// targets the releases which predate feature flags
+    #[arg(long, value_name = "features")]
+    reader_features: Option<Features>,
+    /// the hash function of the file digests stored in the image and of the chunk digests
+    /// deduplication relies on, sha256 or blake3; blobs are still addressed by sha256
+    #[arg(long, value_name = "algorithm", default_value = "sha256")]
+    digest_algorithm: DigestAlgorithm,
+    /// compress the metadata blob with zstd; older puzzlefs releases can't read such images
+    #[arg(long)]
+    compress_metadata: bool,
+    /// split the metadata into blobs of this many inodes, which are only fetched when needed
+    #[arg(long, value_name = "inodes")]
+    metadata_shard_size: Option<u32>,
+    /// make name lookups ignore case; fails if a directory has names which only differ by case
+    #[arg(long)]
+    casefold: bool,
+    /// store the chunk lists of files with the same contents once, in a table their inodes refer
+    /// to; older puzzlefs releases can't read such images
+    #[arg(long)]
+    chunk_table: bool,
+    /// store the fs-verity digest of every file, to enable fs-verity when extracting them
+    #[arg(long)]
+    file_verity: bool,
+    /// number the new inodes after the highest one of the base image, or in a range of 2^32
+    /// numbers of the layer's own
+    #[arg(long, value_enum, default_value_t = InodeNumbers::Rebased)]
+    inode_numbering: InodeNumbers,
+    /// fail on file names which aren't valid UTF-8
+    #[arg(long)]
+    utf8_names: bool,
+    /// sign the manifest with the Ed25519 key in this (PEM, PKCS#8) file, for mount
+    /// --require-signature
+    #[arg(long, value_name = "file", conflicts_with = "dry_run")]
+    sign_key: Option<PathBuf>,
 }
 
 #[derive(Args)]
@@ -66,6 +327,61 @@ struct Mount {
     writable: bool,
     #[arg(short, long, conflicts_with = "foreground")]
     persist: Option<String>,
+    /// the key of an encrypted image, by default the hex encoded one in $PUZZLEFS_KEY
+    #[arg(long, value_name = "file")]
+    key_file: Option<PathBuf>,
+    /// mount the image from a registry, fetching its chunks into oci_dir as they are read
+    #[arg(long, value_name = "registry-ref")]
+    lazy_from: Option<String>,
+    #[arg(long, requires = "lazy_from")]
+    plain_http: bool,
+    #[arg(long, value_name = "user:password", requires = "lazy_from")]
+    creds: Option<String>,
+    /// mount the image from an OCI layout served over http(s), fetching its chunks into oci_dir
+    /// as they are read
+    #[arg(long, value_name = "url", conflicts_with = "lazy_from")]
+    lazy_from_http: Option<String>,
+    /// fetch the chunks of lazily mounted images into this directory instead of oci_dir, so all
+    /// the mounts sharing it download each chunk once
+    #[arg(long, value_name = "dir")]
+    cache_dir: Option<PathBuf>,
+    /// remove the least recently used chunks from the cache once it grows over this size
+    #[arg(long, value_name = "bytes", requires = "cache_dir")]
+    cache_size: Option<u64>,
+    /// only mount the image if it's signed with the cosign key in this (PEM) file
+    #[arg(long, value_name = "file", conflicts_with = "certificate_identity")]
+    verify_key: Option<PathBuf>,
+    /// only mount the image if it's signed keylessly by this identity (an email or a URI)
+    #[arg(
+        long,
+        value_name = "identity",
+        requires_all = ["certificate_oidc_issuer", "fulcio_roots", "rekor_key"]
+    )]
+    certificate_identity: Option<String>,
+    /// the OIDC issuer which has to vouch for --certificate-identity
+    #[arg(long, value_name = "url", requires = "certificate_identity")]
+    certificate_oidc_issuer: Option<String>,
+    /// the (PEM) Fulcio root certificates keyless signatures are checked with
+    #[arg(long, value_name = "file", requires = "certificate_identity")]
+    fulcio_roots: Option<PathBuf>,
+    /// the (PEM) public key of the Rekor log keyless signatures are logged in
+    #[arg(long, value_name = "file", requires = "certificate_identity")]
+    rekor_key: Option<PathBuf>,
+    /// only mount the image if it's signed (see build --sign-key) with the Ed25519 key whose
+    /// public half is in this (PEM) file
+    #[arg(
+        long,
+        value_name = "file",
+        conflicts_with_all = ["verify_key", "certificate_identity"]
+    )]
+    require_signature: Option<PathBuf>,
+    /// the platform of the image, as os/arch[/variant], instead of the host's
+    #[arg(long, value_name = "os/arch")]
+    platform: Option<String>,
+    /// fail reads of files taking longer than this with ETIMEDOUT, e.g. when the source of a lazy
+    /// mount stopped answering
+    #[arg(long, value_name = "seconds")]
+    read_timeout: Option<u64>,
 }
 
 #[derive(Args)]
@@ -77,6 +393,280 @@ struct Umount {
 struct Extract {
     oci_dir: String,
     extract_dir: String,
+    /// the key of an encrypted image, by default the hex encoded one in $PUZZLEFS_KEY
+    #[arg(long, value_name = "file")]
+    key_file: Option<PathBuf>,
+    /// only extract what the image changes in the one it was built on, as an overlayfs upper
+    /// directory with whiteouts for what it deletes
+    #[arg(long)]
+    delta: bool,
+    /// enable fs-verity on the extracted files, checking them against the digests the image
+    /// stores for them (see `build --file-verity`)
+    #[arg(long, conflicts_with = "delta")]
+    verity: bool,
+}
+
+#[derive(Args)]
+struct Convert {
+    oci_dir: String,
+    puzzlefs_oci_dir: String,
+    #[arg(short, long, value_name = "compressed")]
+    compression: bool,
+}
+
+#[derive(Args)]
+struct ConvertDockerArchive {
+    /// a tarball written by `docker save`
+    archive: PathBuf,
+    puzzlefs_oci_dir: String,
+    #[arg(short, long, value_name = "compressed")]
+    compression: bool,
+}
+
+#[derive(Args)]
+struct ImportNydus {
+    bootstrap: PathBuf,
+    /// the directory with the blobs of the image, named by their blob id
+    blobs_dir: PathBuf,
+    oci_dir: String,
+    #[arg(short, long, value_name = "compressed")]
+    compression: bool,
+}
+
+#[derive(Args)]
+struct ExportNydus {
+    oci_dir: String,
+    bootstrap: PathBuf,
+    blobs_dir: PathBuf,
+}
+
+#[derive(Args)]
+struct ExportBlock {
+    oci_dir: String,
+    /// where to write the EROFS filesystem
+    image: PathBuf,
+    /// where to write its dm-verity hash tree
+    hash_tree: PathBuf,
+    /// the key of an encrypted image, by default the hex encoded one in $PUZZLEFS_KEY
+    #[arg(long, value_name = "file")]
+    key_file: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct Export {
+    puzzlefs_oci_dir: String,
+    oci_dir: String,
+    /// zstd-chunked layers can be pulled lazily by runtimes supporting them
+    #[arg(long, value_enum, default_value_t = ExportLayerFormat::Tar)]
+    format: ExportLayerFormat,
+    /// the key of an encrypted image, by default the hex encoded one in $PUZZLEFS_KEY
+    #[arg(long, value_name = "file")]
+    key_file: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct Push {
+    oci_dir: String,
+    registry_ref: String,
+    /// talk to the registry over http instead of https
+    #[arg(long)]
+    plain_http: bool,
+    /// credentials for the registry, as user:password, instead of the ones docker login stored
+    #[arg(long, value_name = "user:password")]
+    creds: Option<String>,
+    /// another repository of the registry to mount the blobs it has from, e.g. the one of the
+    /// base image; can be given more than once
+    #[arg(long, value_name = "repository")]
+    mount_from: Vec<String>,
+    #[command(flatten)]
+    transfer: Transfer,
+}
+
+#[derive(Args)]
+struct Transfer {
+    /// how many blobs to transfer at once
+    #[arg(long, value_name = "blobs", default_value_t = DEFAULT_PARALLELISM)]
+    parallel: usize,
+    /// a cap on the bandwidth, in bytes per second with an optional k, M or G suffix
+    #[arg(long, value_name = "rate", value_parser = parse_rate)]
+    limit_rate: Option<u64>,
+}
+
+#[derive(Args)]
+struct Pull {
+    registry_ref: String,
+    oci_dir: String,
+    /// talk to the registry over http instead of https
+    #[arg(long)]
+    plain_http: bool,
+    /// credentials for the registry, as user:password, instead of the ones docker login stored
+    #[arg(long, value_name = "user:password")]
+    creds: Option<String>,
+    /// the platform of the image, as os/arch[/variant], instead of the host's
+    #[arg(long, value_name = "os/arch")]
+    platform: Option<String>,
+    #[command(flatten)]
+    transfer: Transfer,
+}
+
+#[derive(Args)]
+struct Copy {
+    /// <oci_dir>:<tag>, or docker://<registry-ref> for an image in a registry
+    src: String,
+    /// <oci_dir>:<tag>, or docker://<registry-ref> for an image in a registry
+    dst: String,
+    /// talk to the registry over http instead of https
+    #[arg(long)]
+    plain_http: bool,
+    /// credentials for the registry, as user:password, instead of the ones docker login stored
+    #[arg(long, value_name = "user:password")]
+    creds: Option<String>,
+    #[command(flatten)]
+    transfer: Transfer,
+}
+
+#[derive(Args)]
+struct Gc {
+    oci_dir: String,
+    /// only list the blobs which would be removed
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+struct Tags {
+    oci_dir: String,
+}
+
+#[derive(Args)]
+struct DeleteTag {
+    oci_dir: String,
+}
+
+#[derive(Args)]
+struct Retag {
+    oci_dir: String,
+    new_tag: String,
+}
+
+#[derive(Args)]
+struct Upgrade {
+    oci_dir: String,
+}
+
+#[derive(Args)]
+struct GenVectors {
+    /// where to write the test vectors and vectors.json, which lists them with their digests
+    out_dir: PathBuf,
+}
+
+#[derive(Args)]
+struct StoreAdd {
+    oci_dir: String,
+    store: PathBuf,
+    /// the name of the image in the store, e.g. localhost/alpine:3.19
+    name: String,
+}
+
+#[derive(Args)]
+struct StoreRemove {
+    store: PathBuf,
+    name: String,
+}
+
+#[derive(Args)]
+struct StoreMount {
+    store: PathBuf,
+}
+
+#[derive(Args)]
+struct Hook {
+    stage: HookStage,
+}
+
+#[derive(Args)]
+struct ServeBlobs {
+    oci_dir: PathBuf,
+    /// the address to listen on
+    #[arg(long, value_name = "address:port", default_value = "0.0.0.0:8080")]
+    listen: String,
+    /// serve https with this certificate chain (PEM)
+    #[arg(long, value_name = "cert.pem", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// the private key of the certificate (PEM)
+    #[arg(long, value_name = "key.pem", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct Annotations {
+    oci_dir: String,
+    /// the annotations of the rootfs blob instead of the manifest's
+    #[arg(long)]
+    rootfs: bool,
+}
+
+#[derive(Args)]
+struct Config {
+    oci_dir: String,
+}
+
+#[derive(Args)]
+struct Stats {
+    oci_dir: String,
+    /// print the stats as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct Dump {
+    oci_dir: String,
+    /// only print this inode
+    #[arg(long, conflicts_with = "path")]
+    ino: Option<u64>,
+    /// only print the inode of this path, escaped as in the output of the other commands, e.g.
+    /// /dir/caf\xe9 for a name which isn't valid UTF-8
+    #[arg(long)]
+    path: Option<String>,
+}
+
+#[derive(Args)]
+struct Fsck {
+    oci_dir: String,
+}
+
+#[derive(Args)]
+struct Verify {
+    oci_dir: String,
+    /// the fs-verity digest the manifest has to have, as printed by build
+    #[arg(short, long, value_name = "fs verity root digest")]
+    digest: Option<String>,
+    /// check this many blobs and files at once, one per CPU by default
+    #[arg(short, long, value_name = "N", default_value_t = 0)]
+    jobs: usize,
+    /// the key of an encrypted image, by default the hex encoded one in $PUZZLEFS_KEY
+    #[arg(long, value_name = "file")]
+    key_file: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct Repair {
+    oci_dir: String,
+    /// download the blobs again from this image in a registry
+    #[arg(
+        long,
+        value_name = "registry-ref",
+        required_unless_present = "from_http"
+    )]
+    from: Option<String>,
+    #[arg(long, requires = "from")]
+    plain_http: bool,
+    #[arg(long, value_name = "user:password", requires = "from")]
+    creds: Option<String>,
+    /// download the blobs again from an OCI layout served over http(s)
+    #[arg(long, value_name = "url", conflicts_with = "from")]
+    from_http: Option<String>,
 }
 
 #[derive(Args)]
@@ -128,6 +718,7 @@ fn mount_background(
     mountpoint: &Path,
     options: Option<Vec<String>>,
     manifest_verity: Option<Vec<u8>>,
+    read_timeout: Option<Duration>,
     mut recv: PipeReader,
     init_notify: &PipeWriter,
     parent_action: impl FnOnce() -> anyhow::Result<()> + 'static,
@@ -155,6 +746,7 @@ fn mount_background(
                 &options.unwrap_or_default()[..],
                 Some(PipeDescriptor::UnnamedPipe(init_notify.try_clone()?)),
                 manifest_verity.as_deref(),
+                read_timeout,
             )?;
         }
         Err(e) => {
@@ -164,6 +756,9 @@ fn mount_background(
     Ok(())
 }
 
+// marks the images in a registry among the sources and destinations of copies, like skopeo
+const REGISTRY_PREFIX: &str = "docker://";
+
 fn parse_oci_dir(oci_dir: &str) -> anyhow::Result<(&str, &str)> {
     let components: Vec<&str> = oci_dir.split_terminator(":").collect();
     if components.len() != 2 {
@@ -173,6 +768,220 @@ fn parse_oci_dir(oci_dir: &str) -> anyhow::Result<(&str, &str)> {
     Ok((components[0], components[1]))
 }
 
+fn build_image<C: Compression + Any>(
+    rootfs: &Path,
+    image: Image,
+    tag: &str,
+    base_layer: Option<&str>,
+    options: &BuildOptions,
+) -> anyhow::Result<(Arc<Image>, BuildReport)> {
+    match base_layer {
+        Some(base_layer) => {
+            let (_desc, image, report) =
+                add_rootfs_delta_with_options::<C>(rootfs, image, tag, base_layer, options)?;
+            Ok((image, report))
+        }
+        None => {
+            let (_desc, report) =
+                build_initial_rootfs_with_options::<C>(rootfs, &image, tag, options)?;
+            Ok((Arc::new(image), report))
+        }
+    }
+}
+
+fn with_optional_key(image: Image, key: Option<EncryptionKey>) -> Image {
+    match key {
+        Some(key) => image.with_key(key),
+        None => image,
+    }
+}
+
+// Sets the key to read the encrypted image `tag` with: the one in `key_file`, or else the one in
+// the environment, see EncryptionKey::from_env.
+fn with_read_key(image: Image, tag: &str, key_file: Option<&Path>) -> anyhow::Result<Image> {
+    let key = match key_file {
+        Some(key_file) => Some(EncryptionKey::from_file(key_file)?),
+        None => EncryptionKey::from_env()?,
+    };
+    Ok(match key {
+        Some(key) => image.with_key_from(tag, &key)?,
+        None => image,
+    })
+}
+
+fn with_optional_platform(image: Image, platform: Option<&str>) -> anyhow::Result<Image> {
+    Ok(match platform {
+        Some(platform) => image.with_platform(parse_platform(platform)?),
+        None => image,
+    })
+}
+
+fn signature_policy(m: &Mount) -> anyhow::Result<Option<SignaturePolicy>> {
+    if let Some(key) = &m.verify_key {
+        return Ok(Some(SignaturePolicy::from_key_file(key)?));
+    }
+    if let Some(key) = &m.require_signature {
+        return Ok(Some(SignaturePolicy::from_ed25519_key_file(key)?));
+    }
+    match (
+        &m.certificate_identity,
+        &m.certificate_oidc_issuer,
+        &m.fulcio_roots,
+        &m.rekor_key,
+    ) {
+        (Some(identity), Some(issuer), Some(roots), Some(rekor_key)) => Ok(Some(
+            SignaturePolicy::keyless(roots, rekor_key, identity, issuer)?,
+        )),
+        _ => Ok(None),
+    }
+}
+
+// Fetches the blobs of a lazily mounted image into the cache given with --cache-dir, or into
+// the layout of the image.
+fn lazy_fetcher(
+    source: impl BlobSource + 'static,
+    image: &Image,
+    m: &Mount,
+) -> anyhow::Result<LazyFetcher> {
+    Ok(match &m.cache_dir {
+        Some(dir) => LazyFetcher::new(source, BlobCache::new(dir, m.cache_size)?),
+        None => LazyFetcher::new(source, LayoutBlobStore::new(&image.0)?),
+    })
+}
+
+// Mounts each layer of the store which isn't mounted yet with a mount of its own, running in
+// the background.
+fn mount_store_layers(store: &ImageStore) -> anyhow::Result<()> {
+    for layer in store.layers()? {
+        if store.is_mounted(&layer)? {
+            continue;
+        }
+        let status = std::process::Command::new(std::env::current_exe()?)
+            .arg("mount")
+            .arg(format!("{}:{}", layer.oci_dir.display(), layer.tag))
+            .arg(store.diff_dir(&layer))
+            .status()?;
+        if !status.success() {
+            anyhow::bail!("mounting layer {} failed with {status}", layer.id);
+        }
+    }
+    Ok(())
+}
+
+fn mount_rootfs(hook_mount: &HookMount) -> anyhow::Result<()> {
+    if hook_mount.is_mounted()? {
+        return Ok(());
+    }
+    fs::create_dir_all(&hook_mount.rootfs)?;
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command.arg("mount");
+    if let Some(digest) = &hook_mount.manifest_verity {
+        command.arg("--digest").arg(hex::encode(digest));
+    }
+    let status = command
+        .arg(format!(
+            "{}:{}",
+            hook_mount.oci_dir.display(),
+            hook_mount.tag
+        ))
+        .arg(&hook_mount.rootfs)
+        // the runtime waits for the hook's stdout to be closed, the mount daemon keeps it open
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        anyhow::bail!(
+            "mounting {} failed with {status}",
+            hook_mount.rootfs.display()
+        );
+    }
+    Ok(())
+}
+
+fn fusermount_u(mountpoint: &Path) -> anyhow::Result<()> {
+    let status = std::process::Command::new("fusermount")
+        .arg("-u")
+        .arg(mountpoint)
+        .status()?;
+    if !status.success() {
+        anyhow::bail!("unmounting {} failed with {status}", mountpoint.display());
+    }
+    Ok(())
+}
+
+fn progress_bar() -> ProgressReporter {
+    const MIB: u64 = 1024 * 1024;
+    ProgressReporter::new(|p: &BuildProgress| {
+        let percent = (p.bytes_chunked * 100)
+            .checked_div(p.total_bytes)
+            .unwrap_or(0);
+        eprint!(
+            "\r{} files, {}/{} MiB chunked ({percent}%), {} blobs, {} deduplicated\x1b[K",
+            p.files_scanned,
+            p.bytes_chunked / MIB,
+            p.total_bytes / MIB,
+            p.blobs_written,
+            p.dedup_hits
+        );
+    })
+}
+
+fn transfer_progress_bar() -> TransferReporter {
+    const MIB: u64 = 1024 * 1024;
+    TransferReporter::new(|p: &TransferProgress| {
+        eprint!(
+            "\r{}/{} blobs, {}/{} MiB, {} MiB transferred\x1b[K",
+            p.blobs_done,
+            p.blobs_total,
+            p.bytes_done / MIB,
+            p.bytes_total / MIB,
+            p.bytes_transferred / MIB
+        );
+    })
+}
+
+fn parse_rate(rate: &str) -> anyhow::Result<u64> {
+    let (number, multiplier) = match rate.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&rate[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&rate[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&rate[..i], 1 << 30),
+        _ => (rate, 1),
+    };
+    Ok(number.parse::<u64>()? * multiplier)
+}
+
+fn parse_xattr_rename(rename: &str) -> anyhow::Result<(String, String)> {
+    let (from, to) = rename
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Expected xattr rename in the format <from>=<to>"))?;
+    Ok((from.to_string(), to.to_string()))
+}
+
+fn parse_annotation(annotation: &str) -> anyhow::Result<(String, String)> {
+    let (key, value) = annotation
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Expected annotation in the format <key>=<value>"))?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+fn parse_chown(chown: &str) -> anyhow::Result<(u32, u32)> {
+    let (uid, gid) = chown
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Expected owner in the format <uid>:<gid>"))?;
+    Ok((uid.parse()?, gid.parse()?))
+}
+
+fn parse_id_mapping(mapping: &str) -> anyhow::Result<IdMapping> {
+    let fields = mapping
+        .split(':')
+        .map(str::parse)
+        .collect::<Result<Vec<u32>, _>>()?;
+    let [image, host, count] = fields[..] else {
+        anyhow::bail!("Expected id mapping in the format <image>:<host>:<count>");
+    };
+    Ok(IdMapping { image, host, count })
+}
+
 fn get_mount_type(mountpoint: &str) -> anyhow::Result<OsString> {
     let contents = fs::read_to_string("/proc/self/mountinfo")?;
     let mut parser = mountinfo::Parser::new(contents.as_bytes());
@@ -191,28 +1000,174 @@ fn main() -> anyhow::Result<()> {
     let opts: Opts = Opts::parse();
     match opts.subcmd {
         SubCommand::Build(b) => {
-            let rootfs = Path::new(&b.rootfs);
+            // a tar stream on stdin is unpacked into a staging directory first
+            let staged = (b.rootfs == "-")
+                .then(|| StagedRootfs::unpack([Ok(std::io::stdin().lock())]))
+                .transpose()?;
+            let rootfs = match &staged {
+                Some(staged) => staged.path(),
+                None => PathBuf::from(&b.rootfs),
+            };
+            let rootfs = rootfs.as_path();
             let (oci_dir, tag) = parse_oci_dir(&b.oci_dir)?;
             let oci_dir = Path::new(oci_dir);
-            let image = Image::new(oci_dir)?;
-            let new_image = match b.base_layer {
-                Some(base_layer) => {
-                    let (_desc, image) = if b.compression {
-                        add_rootfs_delta::<Zstd>(rootfs, image, tag, &base_layer)?
-                    } else {
-                        add_rootfs_delta::<Noop>(rootfs, image, tag, &base_layer)?
-                    };
-                    image
+            let key = b
+                .key_file
+                .as_deref()
+                .map(EncryptionKey::from_file)
+                .transpose()?;
+            let signing_key = b
+                .sign_key
+                .as_deref()
+                .map(SigningKey::from_file)
+                .transpose()?;
+            // a dry run estimates against the existing blob store, without creating anything
+            let image = if b.dry_run {
+                Image::open(oci_dir)?
+            } else {
+                Image::new(oci_dir)?
+            };
+            let image = with_optional_key(image, key.clone());
+            let image = with_optional_platform(image, b.platform.as_deref())?;
+            // deltas are built with the chunk parameters of their base unless told otherwise,
+            // chunks built with different ones hardly ever match
+            let base = match &b.base_layer {
+                Some(base_layer) => recorded_chunk_parameters(&image, base_layer)?,
+                None => None,
+            };
+            let base_algorithm = base
+                .and_then(|base| base.compression)
+                .map(CompressionAlgorithm::from);
+            let compression_algorithm = b
+                .compression_algorithm
+                .or(base_algorithm)
+                .unwrap_or(CompressionAlgorithm::Zstd);
+            let compression_level = match (b.compression_level, base) {
+                (None, Some(base)) if base_algorithm == Some(compression_algorithm) => {
+                    base.compression_level
                 }
-                None => {
-                    if b.compression {
-                        build_initial_rootfs::<Zstd>(rootfs, &image, tag)?
-                    } else {
-                        build_initial_rootfs::<Noop>(rootfs, &image, tag)?
-                    };
-                    Arc::new(image)
+                (level, _) => level,
+            };
+            let compression_dictionary = match (&b.compression_dictionary, &b.base_layer) {
+                (Some(path), _) => Some(ZstdDictionary::new(fs::read(path)?)?),
+                (None, Some(base_layer))
+                    if b.compression && compression_algorithm == CompressionAlgorithm::Zstd =>
+                {
+                    recorded_compression_dictionary(&image, base_layer)?
+                }
+                (None, _) => None,
+            };
+            let chunk_sizes_given = b.min_chunk_size.is_some()
+                || b.avg_chunk_size.is_some()
+                || b.max_chunk_size.is_some();
+            let chunking = match (b.fixed_chunk_size, base) {
+                (Some(block_size), _) => Chunking::fixed(block_size, b.pack_small_files)?,
+                (None, Some(base)) if !chunk_sizes_given => base.chunking,
+                (None, _) => {
+                    let default_params = ChunkingParams::default();
+                    Chunking::Fastcdc(ChunkingParams::new(
+                        b.min_chunk_size.unwrap_or(default_params.min_size()),
+                        b.avg_chunk_size.unwrap_or(default_params.avg_size()),
+                        b.max_chunk_size.unwrap_or(default_params.max_size()),
+                    )?)
+                }
+            };
+            let config = b
+                .config
+                .as_deref()
+                .map(|path| -> anyhow::Result<_> { Ok(serde_json::from_slice(&fs::read(path)?)?) })
+                .transpose()?;
+            let show_progress = std::io::stderr().is_terminal();
+            let options = BuildOptions {
+                chunking,
+                compression_level,
+                compression_dictionary,
+                max_blob_size: b.max_blob_size,
+                pack_files_below: b.pack_files_below,
+                inline_files_below: b.inline_files_below,
+                reproducible: b.reproducible,
+                layer_diff: b.layer_diff,
+                thin_delta: b.thin_delta,
+                filter: PathFilter::new(&b.include, &b.exclude)?,
+                one_file_system: b.one_file_system,
+                keep_mountpoints: b.keep_mountpoints,
+                xattrs: XattrFilter {
+                    allow: b.keep_xattr,
+                    drop: b.drop_xattr,
+                    rename: b.rename_xattr,
+                },
+                ids: IdMap {
+                    uids: b.uid_map,
+                    gids: b.gid_map,
+                    chown: b.chown,
+                },
+                special_files: SpecialFilePolicy {
+                    devices: b.devices.into(),
+                    sockets: b.sockets.into(),
+                    fifos: b.fifos.into(),
+                },
+                encryption: b.encrypt.zip(key).map(|(cipher, key)| Encryption {
+                    cipher: cipher.into(),
+                    key,
+                }),
+                dry_run: b.dry_run,
+                resume: b.resume,
+                progress: if show_progress {
+                    progress_bar()
+                } else {
+                    ProgressReporter::default()
+                },
+                annotations: b.annotation.into_iter().collect(),
+                rootfs_annotations: b.rootfs_annotation.into_iter().collect(),
+                config,
+                reader_features: b.reader_features,
+                digest_algorithm: b.digest_algorithm,
+                compress_metadata: b.compress_metadata,
+                metadata_shard_size: b.metadata_shard_size,
+                casefold: b.casefold,
+                chunk_table: b.chunk_table,
+                file_verity: b.file_verity,
+                inode_numbering: b.inode_numbering.into(),
+                utf8_names: b.utf8_names,
+            };
+            let base_layer = b.base_layer.as_deref();
+            let (new_image, report) = match (b.compression, compression_algorithm) {
+                (false, _) => build_image::<Noop>(rootfs, image, tag, base_layer, &options)?,
+                (true, CompressionAlgorithm::Zstd) => {
+                    build_image::<Zstd>(rootfs, image, tag, base_layer, &options)?
+                }
+                (true, CompressionAlgorithm::Lz4) => {
+                    build_image::<Lz4>(rootfs, image, tag, base_layer, &options)?
+                }
+                (true, CompressionAlgorithm::Xz) => {
+                    build_image::<Xz>(rootfs, image, tag, base_layer, &options)?
                 }
             };
+            if show_progress {
+                // finish the progress line
+                eprintln!();
+            }
+            for difference in &report.parameter_differences {
+                eprintln!(
+                    "warning: the image won't share chunks with its base layer, {difference}"
+                );
+            }
+            if let Some(report_path) = b.report {
+                fs::write(report_path, serde_json::to_string_pretty(&report)?)?;
+            }
+            if b.dry_run {
+                println!(
+                    "would add {} bytes of chunks and {} bytes of metadata, {} bytes ({} chunks) are already in the image",
+                    report.stored_bytes,
+                    report.metadata_bytes,
+                    report.reused_bytes,
+                    report.reused_chunks
+                );
+                return Ok(());
+            }
+            if let Some(signing_key) = &signing_key {
+                new_image.sign(tag, signing_key)?;
+            }
             let mut manifest_fd = new_image.get_image_manifest_fd(tag)?;
             let mut read_buffer = Vec::new();
             manifest_fd.read_to_end(&mut read_buffer)?;
@@ -237,8 +1192,40 @@ fn main() -> anyhow::Result<()> {
 
             let (oci_dir, tag) = parse_oci_dir(&m.oci_dir)?;
             let oci_dir = Path::new(oci_dir);
-            let oci_dir = fs::canonicalize(oci_dir)?;
-            let image = Image::open(&oci_dir)?;
+            let platform = m.platform.as_deref();
+            let read_timeout = m.read_timeout.map(Duration::from_secs);
+            let image = match (&m.lazy_from, &m.lazy_from_http) {
+                (Some(registry_ref), _) => {
+                    let image = with_optional_platform(Image::new(oci_dir)?, platform)?;
+                    let reference = registry_ref.parse::<Reference>()?;
+                    let options = RegistryOptions {
+                        timeout: read_timeout,
+                        ..registry_options(m.plain_http, &m.creds)?
+                    };
+                    let registry = Registry::new(&reference, &options);
+                    pull_lazy(&image, tag, &registry, &reference.reference)?;
+                    let fetcher = lazy_fetcher(registry, &image, &m)?;
+                    image.with_blob_store(fetcher)
+                }
+                (None, Some(url)) => {
+                    let image = with_optional_platform(Image::new(oci_dir)?, platform)?;
+                    let server = match read_timeout {
+                        Some(timeout) => HttpServer::new(url).with_timeout(timeout),
+                        None => HttpServer::new(url),
+                    };
+                    server.fetch_image(&image, tag)?;
+                    let fetcher = lazy_fetcher(server, &image, &m)?;
+                    image.with_blob_store(fetcher)
+                }
+                (None, None) => {
+                    with_optional_platform(Image::open(&fs::canonicalize(oci_dir)?)?, platform)?
+                }
+            };
+            let image = with_read_key(image, tag, m.key_file.as_deref())?;
+            let image = match signature_policy(&m)? {
+                Some(policy) => image.with_signature_policy(policy),
+                None => image,
+            };
             let mountpoint = Path::new(&m.mountpoint);
             let mountpoint = fs::canonicalize(mountpoint)?;
 
@@ -256,6 +1243,7 @@ fn main() -> anyhow::Result<()> {
                     &pfs_mountpoint.clone(),
                     m.options,
                     manifest_verity,
+                    read_timeout,
                     recv,
                     &init_notify,
                     move || {
@@ -304,6 +1292,7 @@ fn main() -> anyhow::Result<()> {
                     named_pipe.clone().map(PipeDescriptor::NamedPipe),
                     Some(fuse_thread_finished),
                     manifest_verity.as_deref(),
+                    read_timeout,
                 );
                 if let Err(e) = result {
                     if let Some(pipe) = named_pipe {
@@ -333,6 +1322,7 @@ fn main() -> anyhow::Result<()> {
                     &mountpoint,
                     m.options,
                     manifest_verity,
+                    read_timeout,
                     recv,
                     &init_notify,
                     || Ok(()),
@@ -398,7 +1388,15 @@ fn main() -> anyhow::Result<()> {
         SubCommand::Extract(e) => {
             let (oci_dir, tag) = parse_oci_dir(&e.oci_dir)?;
             init_logging("info");
-            extract_rootfs(oci_dir, tag, &e.extract_dir)
+            let image =
+                with_read_key(Image::open(Path::new(oci_dir))?, tag, e.key_file.as_deref())?;
+            if e.delta {
+                extract_delta(image, tag, &e.extract_dir)
+            } else if e.verity {
+                extract_image_with_verity(image, tag, &e.extract_dir)
+            } else {
+                extract_image(image, tag, &e.extract_dir)
+            }
         }
         SubCommand::EnableFsVerity(v) => {
             let (oci_dir, tag) = parse_oci_dir(&v.oci_dir)?;
@@ -408,5 +1406,418 @@ fn main() -> anyhow::Result<()> {
             enable_fs_verity(image, tag, &v.root_hash)?;
             Ok(())
         }
+        SubCommand::Convert(c) => {
+            let (oci_dir, tag) = parse_oci_dir(&c.oci_dir)?;
+            init_logging("info");
+            let image = Image::new(Path::new(&c.puzzlefs_oci_dir))?;
+            if c.compression {
+                convert_oci_image::<Zstd>(Path::new(oci_dir), tag, &image)?
+            } else {
+                convert_oci_image::<Noop>(Path::new(oci_dir), tag, &image)?
+            };
+            let mut manifest_fd = image.get_image_manifest_fd(tag)?;
+            let mut read_buffer = Vec::new();
+            manifest_fd.read_to_end(&mut read_buffer)?;
+            let manifest_digest = get_fs_verity_digest(&read_buffer)?;
+            println!(
+                "puzzlefs image manifest digest: {}",
+                hex::encode(manifest_digest)
+            );
+            Ok(())
+        }
+        SubCommand::Export(e) => {
+            let (puzzlefs_oci_dir, tag) = parse_oci_dir(&e.puzzlefs_oci_dir)?;
+            init_logging("info");
+            let image = with_read_key(
+                Image::open(Path::new(puzzlefs_oci_dir))?,
+                tag,
+                e.key_file.as_deref(),
+            )?;
+            let descriptor =
+                export_oci_image_with_format(image, tag, Path::new(&e.oci_dir), e.format.into())?;
+            println!("exported {tag} ({})", descriptor.digest());
+            Ok(())
+        }
+        SubCommand::ConvertDockerArchive(c) => {
+            init_logging("info");
+            let image = Image::new(Path::new(&c.puzzlefs_oci_dir))?;
+            let converted = if c.compression {
+                convert_docker_archive::<Zstd>(&c.archive, &image)?
+            } else {
+                convert_docker_archive::<Noop>(&c.archive, &image)?
+            };
+            for (tag, descriptor) in converted {
+                println!("converted {tag} ({})", descriptor.digest());
+            }
+            Ok(())
+        }
+        SubCommand::ImportNydus(i) => {
+            let (oci_dir, tag) = parse_oci_dir(&i.oci_dir)?;
+            init_logging("info");
+            let image = Image::new(Path::new(oci_dir))?;
+            if i.compression {
+                import_nydus::<Zstd>(&i.bootstrap, &i.blobs_dir, &image, tag)?
+            } else {
+                import_nydus::<Noop>(&i.bootstrap, &i.blobs_dir, &image, tag)?
+            };
+            println!("imported {tag}");
+            Ok(())
+        }
+        SubCommand::ExportNydus(e) => {
+            let (oci_dir, tag) = parse_oci_dir(&e.oci_dir)?;
+            init_logging("info");
+            let image = Image::open(Path::new(oci_dir))?;
+            let blob_id = export_nydus(image, tag, &e.bootstrap, &e.blobs_dir)?;
+            println!("exported {tag}, blob {blob_id}");
+            Ok(())
+        }
+        SubCommand::ExportBlock(e) => {
+            let (oci_dir, tag) = parse_oci_dir(&e.oci_dir)?;
+            init_logging("info");
+            let image =
+                with_read_key(Image::open(Path::new(oci_dir))?, tag, e.key_file.as_deref())?;
+            let block_image = export_block_image(image, tag, &e.image, &e.hash_tree)?;
+            println!(
+                "exported {tag}, {} blocks, root hash {}, salt {}",
+                block_image.data_blocks,
+                hex::encode(block_image.root_hash),
+                hex::encode(block_image.salt)
+            );
+            Ok(())
+        }
+        SubCommand::Push(p) => {
+            let (oci_dir, tag) = parse_oci_dir(&p.oci_dir)?;
+            init_logging("info");
+            let image = Image::open(Path::new(oci_dir))?;
+            let reference = p.registry_ref.parse::<Reference>()?;
+            let mut options = registry_options(p.plain_http, &p.creds)?;
+            options.mount_from = p.mount_from;
+            let registry = Registry::new(&reference, &with_transfer(options, &p.transfer));
+            let digest = push(&image, tag, &registry, &reference.reference)?;
+            finish_transfer_progress();
+            println!("pushed {reference} ({digest})");
+            Ok(())
+        }
+        SubCommand::Sync(p) => {
+            let (oci_dir, tag) = parse_oci_dir(&p.oci_dir)?;
+            init_logging("info");
+            let image = Image::open(Path::new(oci_dir))?;
+            let reference = p.registry_ref.parse::<Reference>()?;
+            let mut options = registry_options(p.plain_http, &p.creds)?;
+            options.mount_from = p.mount_from;
+            let registry = Registry::new(&reference, &with_transfer(options, &p.transfer));
+            let report = sync(&image, tag, &registry, &reference.reference)?;
+            finish_transfer_progress();
+            println!(
+                "synced {reference} ({}), uploaded {} blobs, {} bytes",
+                report.digest,
+                report.uploaded.len(),
+                report.uploaded_bytes
+            );
+            Ok(())
+        }
+        SubCommand::Pull(p) => {
+            let (oci_dir, tag) = parse_oci_dir(&p.oci_dir)?;
+            init_logging("info");
+            let image =
+                with_optional_platform(Image::new(Path::new(oci_dir))?, p.platform.as_deref())?;
+            let reference = p.registry_ref.parse::<Reference>()?;
+            let options = registry_options(p.plain_http, &p.creds)?;
+            let registry = Registry::new(&reference, &with_transfer(options, &p.transfer));
+            let digest = pull(&image, tag, &registry, &reference.reference)?;
+            finish_transfer_progress();
+            println!("pulled {reference} ({digest})");
+            Ok(())
+        }
+        SubCommand::Copy(c) => {
+            init_logging("info");
+            let options = with_transfer(registry_options(c.plain_http, &c.creds)?, &c.transfer);
+            match (
+                c.src.strip_prefix(REGISTRY_PREFIX),
+                c.dst.strip_prefix(REGISTRY_PREFIX),
+            ) {
+                (None, None) => {
+                    let (src_dir, src_tag) = parse_oci_dir(&c.src)?;
+                    let (dst_dir, dst_tag) = parse_oci_dir(&c.dst)?;
+                    let image = Image::open(Path::new(src_dir))?;
+                    let report =
+                        image.copy_to(src_tag, &Image::new(Path::new(dst_dir))?, dst_tag)?;
+                    println!(
+                        "copied {} blobs, {} bytes",
+                        report.copied.len(),
+                        report.copied_bytes
+                    );
+                }
+                (None, Some(registry_ref)) => {
+                    let (oci_dir, tag) = parse_oci_dir(&c.src)?;
+                    let image = Image::open(Path::new(oci_dir))?;
+                    let reference = registry_ref.parse::<Reference>()?;
+                    let registry = Registry::new(&reference, &options);
+                    let digest = push(&image, tag, &registry, &reference.reference)?;
+                    finish_transfer_progress();
+                    println!("pushed {reference} ({digest})");
+                }
+                (Some(registry_ref), None) => {
+                    let (oci_dir, tag) = parse_oci_dir(&c.dst)?;
+                    let image = Image::new(Path::new(oci_dir))?;
+                    let reference = registry_ref.parse::<Reference>()?;
+                    let registry = Registry::new(&reference, &options);
+                    let digest = pull(&image, tag, &registry, &reference.reference)?;
+                    finish_transfer_progress();
+                    println!("pulled {reference} ({digest})");
+                }
+                (Some(_), Some(_)) => {
+                    anyhow::bail!("copying between registries isn't supported, pull and push")
+                }
+            }
+            Ok(())
+        }
+        SubCommand::Gc(g) => {
+            let image = Image::open(Path::new(&g.oci_dir))?;
+            let report = image.gc(g.dry_run)?;
+            for digest in &report.removed {
+                println!("{digest}");
+            }
+            let verb = if g.dry_run { "would remove" } else { "removed" };
+            println!(
+                "{verb} {} blobs, {} bytes",
+                report.removed.len(),
+                report.removed_bytes
+            );
+            Ok(())
+        }
+        SubCommand::Tags(t) => {
+            let image = Image::open(Path::new(&t.oci_dir))?;
+            for tag in image.tags()? {
+                println!("{tag}");
+            }
+            Ok(())
+        }
+        SubCommand::Annotations(a) => {
+            let (oci_dir, tag) = parse_oci_dir(&a.oci_dir)?;
+            let image = Image::open(Path::new(oci_dir))?;
+            let annotations = if a.rootfs {
+                image.rootfs_annotations(tag)?
+            } else {
+                image.annotations(tag)?
+            };
+            for (key, value) in annotations {
+                println!("{key}={value}");
+            }
+            Ok(())
+        }
+        SubCommand::Config(c) => {
+            let (oci_dir, tag) = parse_oci_dir(&c.oci_dir)?;
+            let image = Image::open(Path::new(oci_dir))?;
+            match image.config(tag)? {
+                Some(config) => println!("{}", serde_json::to_string_pretty(&config)?),
+                None => info!("{tag} has no config"),
+            }
+            Ok(())
+        }
+        SubCommand::Stats(s) => {
+            let (oci_dir, tag) = parse_oci_dir(&s.oci_dir)?;
+            let image = Arc::new(Image::open(Path::new(oci_dir))?);
+            let stats = image.stats(tag)?;
+            if s.json {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+                return Ok(());
+            }
+            println!("logical size: {} bytes", stats.logical_size);
+            println!(
+                "stored size: {} bytes in {} blobs",
+                stats.stored_size, stats.blob_count
+            );
+            println!("chunks: {}", stats.chunk_count);
+            println!(
+                "shared with other tags: {} bytes in {} blobs",
+                stats.shared_size, stats.shared_blob_count
+            );
+            println!("largest files:");
+            for file in &stats.largest_files {
+                println!("{:>12} {}", file.size, escape_path(&file.path));
+            }
+            if !stats.parents.is_empty() {
+                println!("built on:");
+            }
+            for parent in &stats.parents {
+                println!("  sha256:{} {}", parent.digest, parent.tags.join(", "));
+            }
+            Ok(())
+        }
+        SubCommand::Dump(d) => {
+            let (oci_dir, tag) = parse_oci_dir(&d.oci_dir)?;
+            let image = Image::open(Path::new(oci_dir))?;
+            let rootfs = image.open_rootfs_blob(tag, None)?;
+            let json = match (d.ino, d.path) {
+                (Some(ino), _) => rootfs.find_inode(ino)?.to_json()?,
+                (None, Some(path)) => {
+                    let path = PathBuf::from(OsString::from_vec(unescape_name(&path)?));
+                    let pfs = PuzzleFS::open(image, tag, None)?;
+                    pfs.lookup(&path)?
+                        .ok_or_else(|| anyhow::anyhow!("no {} in {tag}", escape_path(&path)))?
+                        .to_json()?
+                }
+                (None, None) => serde_json::json!({
+                    "manifest": image.find_manifest(tag)?,
+                    "rootfs": rootfs.to_json()?,
+                }),
+            };
+            println!("{}", serde_json::to_string_pretty(&json)?);
+            Ok(())
+        }
+        SubCommand::Fsck(f) => {
+            let image = Image::open(Path::new(&f.oci_dir))?;
+            let problems = image.fsck()?;
+            for problem in &problems {
+                println!("{problem}");
+            }
+            if !problems.is_empty() {
+                anyhow::bail!("found {} problems in {}", problems.len(), f.oci_dir);
+            }
+            Ok(())
+        }
+        SubCommand::Verify(v) => {
+            let (oci_dir, tag) = parse_oci_dir(&v.oci_dir)?;
+            let image =
+                with_read_key(Image::open(Path::new(oci_dir))?, tag, v.key_file.as_deref())?;
+            let manifest_verity = v.digest.map(hex::decode).transpose()?;
+            let results = verify_image(image, tag, manifest_verity.as_deref(), v.jobs)?;
+            for result in &results {
+                println!("{result}");
+            }
+            let failed = results.iter().filter(|result| !result.is_ok()).count();
+            if failed > 0 {
+                anyhow::bail!(
+                    "{failed} of {} checks of {} failed",
+                    results.len(),
+                    v.oci_dir
+                );
+            }
+            Ok(())
+        }
+        SubCommand::Repair(r) => {
+            init_logging("info");
+            let image = Image::open(Path::new(&r.oci_dir))?;
+            let report = match (&r.from, &r.from_http) {
+                (Some(registry_ref), _) => {
+                    let reference = registry_ref.parse::<Reference>()?;
+                    let registry =
+                        Registry::new(&reference, &registry_options(r.plain_http, &r.creds)?);
+                    image.repair(&registry)?
+                }
+                (None, Some(url)) => image.repair(&HttpServer::new(url))?,
+                (None, None) => unreachable!("clap requires a source"),
+            };
+            for digest in &report.repaired {
+                println!("repaired {digest}");
+            }
+            for problem in &report.remaining {
+                println!("{problem}");
+            }
+            if !report.remaining.is_empty() {
+                anyhow::bail!("{} problems left in {}", report.remaining.len(), r.oci_dir);
+            }
+            Ok(())
+        }
+        SubCommand::DeleteTag(d) => {
+            let (oci_dir, tag) = parse_oci_dir(&d.oci_dir)?;
+            let image = Image::open(Path::new(oci_dir))?;
+            image.delete_tag(tag)?;
+            Ok(())
+        }
+        SubCommand::Retag(r) => {
+            let (oci_dir, tag) = parse_oci_dir(&r.oci_dir)?;
+            let image = Image::open(Path::new(oci_dir))?;
+            image.retag(tag, &r.new_tag)?;
+            Ok(())
+        }
+        SubCommand::StoreAdd(a) => {
+            let (oci_dir, tag) = parse_oci_dir(&a.oci_dir)?;
+            let image = Arc::new(Image::open(Path::new(oci_dir))?);
+            let store = ImageStore::open(&a.store)?.with_unmount(fusermount_u);
+            store.add(&image, Path::new(oci_dir), tag, &a.name)?;
+            mount_store_layers(&store)
+        }
+        SubCommand::StoreRemove(r) => {
+            let store = ImageStore::open(&r.store)?.with_unmount(fusermount_u);
+            store.remove(&r.name)
+        }
+        SubCommand::StoreMount(m) => mount_store_layers(&ImageStore::open(&m.store)?),
+        SubCommand::Hook(h) => {
+            let state = State::read(std::io::stdin())?;
+            let Some(hook_mount) = HookMount::from_state(&state)? else {
+                return Ok(());
+            };
+            match h.stage {
+                HookStage::CreateRuntime => mount_rootfs(&hook_mount),
+                HookStage::Poststop => hook_mount.teardown(fusermount_u),
+            }
+        }
+        SubCommand::ServeBlobs(s) => {
+            init_logging("info");
+            let mut server = BlobServer::new(Image::open(&s.oci_dir)?);
+            if let (Some(cert), Some(key)) = (&s.tls_cert, &s.tls_key) {
+                server = server.with_tls(cert, key)?;
+            }
+            let listener = std::net::TcpListener::bind(&s.listen)?;
+            info!(
+                "serving {} on {}",
+                s.oci_dir.display(),
+                listener.local_addr()?
+            );
+            Ok(server.serve(listener)?)
+        }
+        SubCommand::Upgrade(u) => {
+            let (oci_dir, tag) = parse_oci_dir(&u.oci_dir)?;
+            let image = Image::open(Path::new(oci_dir))?;
+            if !image.upgrade(tag)? {
+                println!("{tag} is already in the current format");
+            }
+            Ok(())
+        }
+        SubCommand::GenVectors(g) => {
+            for vector in vectors::generate(&g.out_dir)? {
+                println!("{}  {}", vector.sha256, vector.path);
+            }
+            Ok(())
+        }
+    }
+}
+
+// Transfers show their progress on terminals.
+fn with_transfer(options: RegistryOptions, transfer: &Transfer) -> RegistryOptions {
+    RegistryOptions {
+        parallelism: transfer.parallel,
+        limit_rate: transfer.limit_rate,
+        progress: if std::io::stderr().is_terminal() {
+            transfer_progress_bar()
+        } else {
+            TransferReporter::default()
+        },
+        ..options
     }
 }
+
+fn finish_transfer_progress() {
+    if std::io::stderr().is_terminal() {
+        eprintln!();
+    }
+}
+
+fn registry_options(plain_http: bool, creds: &Option<String>) -> anyhow::Result<RegistryOptions> {
+    let credentials = creds
+        .as_deref()
+        .map(|creds| {
+            creds
+                .split_once(':')
+                .map(|(user, password)| (user.to_string(), password.to_string()))
+                .ok_or_else(|| anyhow::anyhow!("credentials must be given as user:password"))
+        })
+        .transpose()?;
+    Ok(RegistryOptions {
+        plain_http,
+        credentials,
+        ..Default::default()
+    })
+}
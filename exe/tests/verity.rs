@@ -121,7 +121,7 @@ fn test_fs_verity() -> anyhow::Result<()> {
     assert!(mount_output
         .unwrap_err()
         .to_string()
-        .contains("invalid fs_verity data: fsverity mismatch"));
+        .contains("digest mismatch: expected"));
 
     // test that we can mount with the right digest
     puzzlefs([
@@ -1,5 +1,5 @@
-use crate::common::{AVG_CHUNK_SIZE, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
-use crate::compression::{Compression, Noop, Zstd};
+use crate::common::{OPAQUE_WHITEOUT, WHITEOUT_PREFIX};
+use crate::compression::{Compression, Noop, Zstd, ZstdDictionary};
 use crate::fsverity_helpers::{
     check_fs_verity, fsverity_enable, InnerHashAlgorithm, FS_VERITY_BLOCK_SIZE_DEFAULT,
 };
@@ -7,44 +7,66 @@ use crate::oci::Digest;
 use std::any::Any;
 use std::backtrace::Backtrace;
 use std::cmp::min;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 use std::os::fd::AsRawFd;
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
-use std::os::unix::fs::MetadataExt;
-use std::path::Path;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use walkdir::WalkDir;
-
 use crate::format::{
-    BlobRef, DirEnt, DirList, FileChunk, FileChunkList, Ino, Inode, InodeAdditional, InodeMode,
-    Result, Rootfs, VerityData, WireFormatError,
+    escape_name, escape_path, find_collision, BlobRef, DigestAlgorithm, DirEnt, DirList, Features,
+    FileChunk, FileChunkList, FileDigest, FileHasher, Ino, Inode, InodeAdditional, InodeMode,
+    InodeVector, MetadataShard, Result, Rootfs, VerityData, WireFormatError, Xattr,
+    SHA256_BLOCK_SIZE,
 };
 use crate::metadata_capnp;
 use crate::oci::media_types;
 use crate::oci::{Descriptor, Image};
-use crate::reader::{PuzzleFS, PUZZLEFS_IMAGE_MANIFEST_VERSION};
-use ocidir::oci_spec::image::{ImageManifest, Platform};
+use crate::reader::{PuzzleFS, PUZZLEFS_IMAGE_MANIFEST_VERSION, UNFLAGGED_MANIFEST_VERSION};
+use ocidir::oci_spec::image::ImageManifest;
 
+use fs_verity::FsVeritySha256;
+use log::warn;
 use nix::errno::Errno;
+use nix::unistd::{lseek, Whence};
+use rayon::prelude::*;
+use sha2::Digest as _;
 
 use fastcdc::v2020::StreamCDC;
 mod filesystem;
 use filesystem::FilesystemStream;
-
-fn walker(rootfs: &Path) -> WalkDir {
-    // breadth first search for sharing, don't cross filesystems just to be safe, order by file
-    // name. we only return directories here, so we can more easily do delta generation to detect
-    // what's missing in an existing puzzlefs.
-    WalkDir::new(rootfs)
-        .contents_first(false)
-        .follow_links(false)
-        .same_file_system(true)
-        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
-}
+mod inodes;
+use inodes::InodeAllocator;
+mod checkpoint;
+pub(crate) use checkpoint::checkpointed_blobs;
+use checkpoint::Checkpoint;
+mod chunker;
+use chunker::{BlobGroup, BlobGrouper, FixedSizeChunker, GroupedChunk};
+mod options;
+pub use options::{
+    BuildOptions, ChunkParameters, Chunking, ChunkingParams, IdMap, IdMapping, InodeNumbering,
+    PathFilter, SpecialFileAction, SpecialFilePolicy, XattrFilter, INODES_PER_LAYER,
+    MAX_INLINE_SIZE,
+};
+mod progress;
+pub use progress::{BuildProgress, ProgressReporter};
+mod report;
+pub use report::{BuildReport, DirectoryReport};
+mod tree;
+pub use tree::TreeBuilder;
+mod walk;
+use walk::ParallelWalker;
+mod xattrs;
+
+// how many blobs (single chunks, unless max_blob_size groups them) each worker thread gets per
+// batch; bounds the chunk data held in memory while keeping the pool busy
+const CHUNKS_PER_THREAD: usize = 4;
 
 // a struct to hold a directory's information before it can be rendered into a InodeSpecific::Dir
 // (aka the offset is unknown because we haven't accumulated all the inodes yet)
@@ -67,9 +89,17 @@ impl Dir {
 // similar to the above, but holding file metadata
 struct File {
     ino: u64,
+    // the path in the image, for the build checkpoint
+    path: PathBuf,
     chunk_list: FileChunkList,
     md: fs::Metadata,
     additional: Option<InodeAdditional>,
+    // the regions of the file which aren't holes, in order
+    extents: Vec<Range<u64>>,
+    // the top level directory the file is in, for the build report
+    top_level: String,
+    digest: Option<FileDigest>,
+    verity: Option<[u8; SHA256_BLOCK_SIZE]>,
 }
 
 struct Other {
@@ -78,87 +108,394 @@ struct Other {
     additional: Option<InodeAdditional>,
 }
 
-fn serialize_metadata(rootfs: Rootfs) -> Result<Vec<u8>> {
+pub(crate) fn serialize_metadata(rootfs: Rootfs) -> Result<Vec<u8>> {
+    serialize_sharded_metadata(rootfs, &[])
+}
+
+fn serialize_sharded_metadata(rootfs: Rootfs, shards: &[Vec<MetadataShard>]) -> Result<Vec<u8>> {
     let mut message = ::capnp::message::Builder::new_default();
     let mut capnp_rootfs = message.init_root::<metadata_capnp::rootfs::Builder<'_>>();
 
-    rootfs.fill_capnp(&mut capnp_rootfs)?;
+    rootfs.fill_capnp_sharded(&mut capnp_rootfs, shards)?;
 
     let mut buf = Vec::new();
     ::capnp::serialize::write_message(&mut buf, &message)?;
     Ok(buf)
 }
 
-fn process_chunks<C: Compression + Any>(
+// Writes the inodes of each inode vector of `rootfs` to blobs of `shard_size` inodes, which the
+// rootfs refers to instead of storing them. Their fs-verity digests go into the verity data of
+// the rootfs, so verified mounts check each shard as it's loaded.
+fn shard_metadata(
     oci: &Image,
-    mut chunker: StreamCDC,
-    files: &mut [File],
-    verity_data: &mut VerityData,
+    rootfs: &mut Rootfs,
     image_manifest: &mut ImageManifest,
-) -> Result<()> {
-    let mut file_iter = files.iter_mut();
-    let mut file_used = 0;
-    let mut file = None;
-    for f in file_iter.by_ref() {
-        if f.md.size() > 0 {
-            file = Some(f);
+    shard_size: u32,
+    options: &BuildOptions,
+) -> Result<Vec<Vec<MetadataShard>>> {
+    let chunk_table = rootfs.features.contains(Features::CHUNK_TABLE);
+    let mut shards = Vec::new();
+    for inodes in &rootfs.metadatas {
+        let mut vector_shards = Vec::new();
+        for shard_inodes in inodes.chunks(shard_size as usize) {
+            let mut message = ::capnp::message::Builder::new_default();
+            let mut capnp_inodes = message.init_root::<metadata_capnp::inode_vector::Builder<'_>>();
+            InodeVector::fill_capnp(shard_inodes, &mut capnp_inodes, chunk_table)?;
+            let mut buf = Vec::new();
+            ::capnp::serialize::write_message(&mut buf, &message)?;
+            if options.compress_metadata {
+                buf = zstd::bulk::compress(&buf, 0)?;
+            }
+
+            let blob = Image::prepare_blob::<Noop>(&buf, media_types::MetadataShard {})?;
+            let (desc, fs_verity_digest, _) = if options.dry_run {
+                blob.into_parts()
+            } else {
+                oci.write_blob(blob, image_manifest)?
+            };
+            let digest = Digest::try_from(desc.digest().digest())?.underlying();
+            rootfs.fs_verity_data.insert(digest, fs_verity_digest);
+            // the inodes are sorted by ino
+            vector_shards.push(MetadataShard {
+                digest,
+                first_ino: shard_inodes[0].ino,
+                last_ino: shard_inodes[shard_inodes.len() - 1].ino,
+            });
+        }
+        shards.push(vector_shards);
+    }
+    Ok(shards)
+}
+
+// Returns the data regions of a file, with their boundaries rounded out to `alignment`, so that
+// holes don't have to be read or stored. Filesystems without hole support report the whole file
+// as data.
+fn data_extents(path: &Path, md: &fs::Metadata, alignment: u64) -> io::Result<Vec<Range<u64>>> {
+    let len = md.len();
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+
+    // if all the blocks are allocated there can't be any holes
+    if md.blocks() * 512 >= len {
+        return Ok(vec![0..len]);
+    }
+
+    let file = fs::File::open(path)?;
+    let fd = file.as_raw_fd();
+    let mut extents: Vec<Range<u64>> = Vec::new();
+    let mut pos = 0;
+    while pos < len {
+        let start = match lseek(fd, pos as i64, Whence::SeekData) {
+            Ok(start) => start as u64,
+            // no more data until the end of the file
+            Err(Errno::ENXIO) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if start >= len {
             break;
         }
+        let end = min(lseek(fd, start as i64, Whence::SeekHole)? as u64, len);
+
+        let start = start / alignment * alignment;
+        let end = min(end.div_ceil(alignment) * alignment, len);
+        match extents.last_mut() {
+            Some(last) if last.end >= start => last.end = end,
+            _ => extents.push(start..end),
+        }
+        pos = end;
     }
 
-    'outer: for result in &mut chunker {
-        let chunk = result.unwrap();
-        let mut chunk_used: u64 = 0;
+    Ok(extents)
+}
+
+// Feeds the contents of a file to its digest and, for per-file verity, to its fs-verity digest.
+struct FileHashers {
+    digest: FileHasher,
+    verity: Option<FsVeritySha256>,
+}
+
+impl Write for FileHashers {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.digest.update(buf);
+        if let Some(verity) = &mut self.verity {
+            verity.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
 
-        let (desc, fs_verity_digest, compressed) =
-            oci.put_blob::<C>(&chunk.data, image_manifest, media_types::Chunk {})?;
-        let digest = Digest::try_from(desc.digest().digest())?.underlying();
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
 
-        let verity_hash = fs_verity_digest;
-        verity_data.insert(digest, verity_hash);
+// Hashes the contents of a file, reading only its `extents` of data; the holes in between are
+// hashed as the zeros they read as. With `verity`, the fs-verity digest is computed from the same
+// read.
+fn file_digest(
+    path: &Path,
+    md: &fs::Metadata,
+    extents: &[Range<u64>],
+    algorithm: DigestAlgorithm,
+    verity: bool,
+) -> io::Result<(FileDigest, Option<[u8; SHA256_BLOCK_SIZE]>)> {
+    let mut hasher = FileHashers {
+        digest: algorithm.hasher(),
+        verity: verity.then(FsVeritySha256::new),
+    };
+    let mut file = fs::File::open(path)?;
+    let mut pos = 0;
+    for extent in extents {
+        io::copy(&mut io::repeat(0).take(extent.start - pos), &mut hasher)?;
+        file.seek(SeekFrom::Start(extent.start))?;
+        let len = extent.end - extent.start;
+        if io::copy(&mut (&mut file).take(len), &mut hasher)? != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} shrank while hashing it", path.display()),
+            ));
+        }
+        pos = extent.end;
+    }
+    io::copy(&mut io::repeat(0).take(md.len() - pos), &mut hasher)?;
+    Ok((
+        hasher.digest.finish(),
+        hasher.verity.map(|v| v.finalize().into()),
+    ))
+}
 
-        while chunk_used < chunk.length as u64 {
-            let room = min(
-                file.as_ref().unwrap().md.len() - file_used,
-                chunk.length as u64 - chunk_used,
-            );
+fn next_data_file<'a>(files: &mut impl Iterator<Item = &'a mut File>) -> Option<&'a mut File> {
+    files.find(|f| !f.extents.is_empty())
+}
 
-            let blob = BlobRef {
-                offset: chunk_used,
-                digest,
-                compressed,
-            };
+// Hands out the chunk stream to the data extents of the files, in order, recording the holes in
+// between.
+struct FileAssigner<'a> {
+    files: std::slice::IterMut<'a, File>,
+    file: Option<&'a mut File>,
+    file_pos: u64,
+    extent: usize,
+    // the files which got all their chunks since the last checkpoint, when checkpointing
+    finished: Option<Vec<&'a mut File>>,
+}
 
-            file.as_mut()
-                .unwrap()
-                .chunk_list
-                .chunks
-                .push(FileChunk { blob, len: room });
+impl<'a> FileAssigner<'a> {
+    fn new(files: &'a mut [File], checkpointing: bool) -> Self {
+        let mut files = files.iter_mut();
+        let file = next_data_file(&mut files);
+        FileAssigner {
+            files,
+            file,
+            file_pos: 0,
+            extent: 0,
+            finished: checkpointing.then(Vec::new),
+        }
+    }
+
+    // assign `chunk`, some stream data stored in `blob`; `stored` is what storing it cost
+    fn assign(&mut self, blob: BlobRef, chunk: &[u8], stored: u64, report: &mut BuildReport) {
+        let length = chunk.len() as u64;
+        let mut chunk_used: u64 = 0;
+        while chunk_used < length {
+            // the chunks cover exactly the data of the files, so we can't run out of files
+            // while there's still chunk data left
+            let f = self.file.as_mut().unwrap();
+            let data = f.extents[self.extent].clone();
+
+            // holes aren't part of the stream, record them in between the data
+            if self.file_pos < data.start {
+                f.chunk_list
+                    .chunks
+                    .push(FileChunk::hole(data.start - self.file_pos));
+                self.file_pos = data.start;
+            }
+
+            let room = min(data.end - self.file_pos, length - chunk_used);
+
+            f.chunk_list.chunks.push(FileChunk {
+                blob: Some(BlobRef {
+                    offset: blob.offset + chunk_used,
+                    ..blob
+                }),
+                len: room,
+                checksum: Some(FileChunk::checksum(
+                    &chunk[chunk_used as usize..(chunk_used + room) as usize],
+                )),
+                inline: None,
+            });
+            if let Some(dir) = report.directories.get_mut(&f.top_level) {
+                dir.stored_bytes += (stored as u128 * room as u128 / length as u128) as u64;
+            }
 
             chunk_used += room;
-            file_used += room;
+            self.file_pos += room;
+
+            if self.file_pos == data.end {
+                self.extent += 1;
+            }
 
             // get next file
-            if file_used == file.as_ref().unwrap().md.len() {
-                file_used = 0;
-                file = None;
-
-                for f in file_iter.by_ref() {
-                    if f.md.size() > 0 {
-                        file = Some(f);
-                        break;
-                    }
+            if self.extent == f.extents.len() {
+                if self.file_pos < f.md.len() {
+                    f.chunk_list
+                        .chunks
+                        .push(FileChunk::hole(f.md.len() - self.file_pos));
                 }
+                self.file_pos = 0;
+                self.extent = 0;
+                let done = std::mem::replace(&mut self.file, next_data_file(&mut self.files));
+                if let (Some(finished), Some(done)) = (self.finished.as_mut(), done) {
+                    finished.push(done);
+                }
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn process_chunks<C: Compression + Any>(
+    oci: &Image,
+    mut groups: impl Iterator<Item = io::Result<BlobGroup>>,
+    files: &mut [File],
+    verity_data: &mut VerityData,
+    image_manifest: &mut ImageManifest,
+    options: &BuildOptions,
+    progress: &mut BuildProgress,
+    report: &mut BuildReport,
+    mut checkpoint: Option<&mut Checkpoint>,
+) -> Result<()> {
+    // files without any data are a single hole, they never show up in the stream
+    for f in files.iter_mut() {
+        if f.extents.is_empty() && f.md.len() > 0 {
+            f.chunk_list.chunks.push(FileChunk::hole(f.md.len()));
+        }
+    }
 
-                if file.is_none() {
-                    break 'outer;
+    let mut assigner = FileAssigner::new(files, checkpoint.is_some());
+    // the blob of each group written so far, None for the groups made only of duplicates
+    let mut group_blobs = Vec::<Option<BlobRef>>::new();
+
+    // Chunk boundaries depend on everything before them, so chunking stays sequential. Blobs are
+    // pulled off the stream in batches which are compressed and hashed on the rayon pool, then
+    // written and assigned to files in stream order so the image doesn't depend on scheduling.
+    let batch_size = rayon::current_num_threads() * CHUNKS_PER_THREAD;
+    loop {
+        let batch = groups
+            .by_ref()
+            .take(batch_size)
+            .collect::<io::Result<Vec<_>>>()?;
+        if batch.is_empty() {
+            break;
+        }
+
+        let blobs = batch
+            .par_iter()
+            .map(|group| {
+                (!group.data.is_empty())
+                    .then(|| {
+                        Image::prepare_blob_with_dictionary::<C>(
+                            &group.data,
+                            media_types::Chunk {},
+                            options.compression_level,
+                            options.compression_dictionary.as_ref(),
+                            options.encryption.as_ref(),
+                        )
+                    })
+                    .transpose()
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for (group, blob) in batch.iter().zip(blobs) {
+            let mut duplicate_data = &group.duplicate_data[..];
+            // (blob, compressed size of the new data, whether the blob was a duplicate)
+            let written = match blob {
+                Some(blob) => {
+                    let existed = oci.has_blob(blob.descriptor());
+                    let (desc, fs_verity_digest, compressed) = if options.dry_run {
+                        blob.into_parts()
+                    } else {
+                        oci.write_blob(blob, image_manifest)?
+                    };
+                    let digest = Digest::try_from(desc.digest().digest())?.underlying();
+                    if let Some(checkpoint) = checkpoint.as_deref_mut() {
+                        checkpoint.blob_written(digest, &desc, fs_verity_digest);
+                    }
+                    let blob = BlobRef {
+                        offset: 0,
+                        digest,
+                        compressed,
+                        algorithm: C::ALGORITHM.unwrap_or_default(),
+                        encryption: options.encryption.as_ref().map(|e| e.cipher),
+                    };
+
+                    let duplicate = verity_data.insert(digest, fs_verity_digest).is_some();
+                    if !duplicate {
+                        progress.blobs_written += 1;
+                        progress.bytes_compressed += desc.size();
+                    }
+                    let stored = if duplicate || existed {
+                        0
+                    } else {
+                        report.stored_bytes += desc.size();
+                        desc.size()
+                    };
+                    Some((blob, stored, duplicate, existed))
+                }
+                None => None,
+            };
+            group_blobs.push(written.map(|(blob, ..)| blob));
+
+            for chunk in &group.chunks {
+                report.chunks += 1;
+                progress.bytes_chunked += chunk.length();
+
+                match *chunk {
+                    GroupedChunk::Stored { offset, length } => {
+                        // a group holding data always has a blob
+                        let (blob, stored, duplicate, existed) = written.unwrap();
+                        if duplicate {
+                            progress.dedup_hits += 1;
+                            report.deduplicated_chunks += 1;
+                        } else if existed {
+                            report.reused_chunks += 1;
+                            report.reused_bytes += length;
+                        }
+                        let stored =
+                            (stored as u128 * length as u128 / group.data.len() as u128) as u64;
+                        let blob = BlobRef { offset, ..blob };
+                        let data = &group.data[offset as usize..(offset + length) as usize];
+                        assigner.assign(blob, data, stored, report);
+                    }
+                    GroupedChunk::Duplicate { location, length } => {
+                        progress.dedup_hits += 1;
+                        report.deduplicated_chunks += 1;
+                        let blob = BlobRef {
+                            offset: location.offset,
+                            ..group_blobs[location.group].unwrap()
+                        };
+                        let (data, rest) = duplicate_data.split_at(length as usize);
+                        duplicate_data = rest;
+                        assigner.assign(blob, data, 0, report);
+                    }
                 }
             }
         }
+
+        // the blobs of the batch are written, so the files it finished can be resumed
+        if let (Some(checkpoint), Some(finished)) =
+            (checkpoint.as_deref_mut(), assigner.finished.as_mut())
+        {
+            for file in finished.drain(..) {
+                checkpoint.record(file)?;
+            }
+            checkpoint.flush()?;
+        }
+
+        options.progress.report(progress);
     }
 
-    // If there are no files left we also expect there are no chunks left
-    assert!(chunker.next().is_none());
+    // If there are no chunks left we also expect there are no files left
+    assert!(assigner.file.is_none());
 
     Ok(())
 }
@@ -169,20 +506,37 @@ fn build_delta<C: Compression + Any>(
     mut existing: Option<PuzzleFS>,
     verity_data: &mut VerityData,
     image_manifest: &mut ImageManifest,
-) -> Result<Vec<Inode>> {
-    let mut dirs = HashMap::<u64, Dir>::new();
+    options: &BuildOptions,
+    mut checkpoint: Option<&mut Checkpoint>,
+) -> Result<(Vec<Inode>, BuildReport)> {
+    // by host (dev, ino), the walk may cross filesystems
+    let mut dirs = HashMap::<(u64, u64), Dir>::new();
     let mut files = Vec::<File>::new();
     let mut others = Vec::<Other>::new();
     let mut pfs_inodes = Vec::<Inode>::new();
     let mut fs_stream = FilesystemStream::new();
+    // the small files packed into blobs of their own, and their data
+    let mut packed_files = Vec::<File>::new();
+    let mut pack_stream = FilesystemStream::new();
+    // the files which don't go through chunking: those finished by the build being resumed, and
+    // the tiny ones whose data is stored in their inode
+    let mut unchunked_files = Vec::<File>::new();
+    let mut progress = BuildProgress::default();
+    let mut report = BuildReport::default();
+    // with fixed size chunks, data extents start on a chunk boundary, so that the chunks stay
+    // aligned to the file offsets
+    let extent_alignment = match options.chunking {
+        Chunking::Fastcdc(_) => 1,
+        Chunking::Fixed { block_size, .. } => block_size.into(),
+    };
 
-    // host to puzzlefs inode mapping for hard link deteciton
-    let mut host_to_pfs = HashMap::<u64, Ino>::new();
+    // host (dev, ino) to puzzlefs inode mapping for hard link detection
+    let mut host_to_pfs = HashMap::<(u64, u64), Ino>::new();
 
-    let mut next_ino: u64 = existing
-        .as_mut()
-        .map(|pfs| pfs.max_inode().map(|i| i + 1))
-        .unwrap_or_else(|| Ok(2))?;
+    let base_max = existing.as_ref().map(PuzzleFS::max_inode).transpose()?;
+    let mut inodes = InodeAllocator::new(options.inode_numbering, base_max)?;
+    // the entries of the base image which were deleted: their directory, name and inode
+    let mut deleted = Vec::<((u64, u64), Vec<u8>, Ino)>::new();
 
     fn lookup_existing(existing: &mut Option<PuzzleFS>, p: &Path) -> Result<Option<Inode>> {
         existing
@@ -192,16 +546,22 @@ fn build_delta<C: Compression + Any>(
             .map(|o| o.flatten())
     }
 
-    let rootfs_dirs = walker(rootfs)
-        .into_iter()
-        .filter_entry(|de| de.metadata().map(|md| md.is_dir()).unwrap_or(true));
+    // we only get directories here, so we can more easily do delta generation to detect what's
+    // missing in an existing puzzlefs. they come in a fixed order (depth first, by file name) no
+    // matter how many threads read them.
+    let rootfs_dirs = ParallelWalker::new(
+        rootfs,
+        options,
+        extent_alignment,
+        rayon::current_num_threads(),
+    )?;
 
     // we specially create the "/" InodeMode::Dir object, since we will not iterate over it as a
     // child of some other directory
     let root_metadata = fs::symlink_metadata(rootfs)?;
     let root_additional = InodeAdditional::new(rootfs, &root_metadata)?;
     dirs.insert(
-        root_metadata.ino(),
+        (root_metadata.dev(), root_metadata.ino()),
         Dir {
             ino: 1,
             md: root_metadata,
@@ -220,8 +580,8 @@ fn build_delta<C: Compression + Any>(
     };
 
     for dir in rootfs_dirs {
-        let d = dir.map_err(io::Error::from)?;
-        let dir_path = rootfs_relative(d.path());
+        let d = dir?;
+        let dir_path = rootfs_relative(&d.path);
         let existing_dirents: Vec<_> = lookup_existing(&mut existing, &dir_path)?
             .and_then(|ex| -> Option<Vec<_>> {
                 if let InodeMode::Dir { dir_list } = ex.mode {
@@ -232,82 +592,116 @@ fn build_delta<C: Compression + Any>(
             })
             .unwrap_or_default();
 
-        let mut new_dirents = fs::read_dir(d.path())?.collect::<io::Result<Vec<fs::DirEntry>>>()?;
-        // sort the entries so we have reproducible puzzlefs images
-        new_dirents.sort_by_key(|a| a.file_name());
+        let mut new_dirents = d.entries;
+
+        // in a layer diff, the whiteout markers tell us what to remove from the lower layers;
+        // they aren't part of the image themselves
+        let mut whiteouts = Vec::<OsString>::new();
+        let mut opaque = false;
+        if options.layer_diff {
+            opaque = is_overlay_opaque(&d.path)?;
+            let mut kept = Vec::with_capacity(new_dirents.len());
+            for e in new_dirents {
+                match whiteout_marker(&e.name, &e.md, e.additional.as_ref()) {
+                    Some(Whiteout::Opaque) => opaque = true,
+                    Some(Whiteout::Entry(name)) => whiteouts.push(name),
+                    None => kept.push(e),
+                }
+            }
+            new_dirents = kept;
+        }
+
+        let mut kept = Vec::with_capacity(new_dirents.len());
+        for e in new_dirents {
+            if options.special_files.keeps(&e.path, e.md.file_type())? {
+                kept.push(e);
+            }
+        }
+        new_dirents = kept;
+
+        if options.utf8_names {
+            if let Some(e) = new_dirents
+                .iter()
+                .find(|e| std::str::from_utf8(e.name.as_bytes()).is_err())
+            {
+                return Err(WireFormatError::InvalidName(
+                    format!(
+                        "{} isn't valid UTF-8",
+                        escape_path(&rootfs_relative(&e.path))
+                    ),
+                    Backtrace::capture(),
+                ));
+            }
+        }
 
         // add whiteout information
-        let this_metadata = fs::symlink_metadata(d.path())?;
         let this_dir = dirs
-            .get_mut(&this_metadata.ino())
+            .get_mut(&(d.md.dev(), d.md.ino()))
             .ok_or_else(|| WireFormatError::from_errno(Errno::ENOENT))?;
         for dir_ent in existing_dirents {
-            if !(new_dirents).iter().any(|new| {
-                new.path().file_name().unwrap_or_else(|| OsStr::new(""))
-                    == OsStr::from_bytes(&dir_ent.name)
-            }) {
-                pfs_inodes.push(Inode::new_whiteout(dir_ent.ino));
-                this_dir.add_entry(OsString::from_vec(dir_ent.name), dir_ent.ino);
+            let name = OsStr::from_bytes(&dir_ent.name);
+            if new_dirents.iter().any(|new| new.name == name) {
+                continue;
+            }
+
+            // a layer diff only lists what changed, everything else is still there
+            let is_deleted = !options.layer_diff || opaque || whiteouts.iter().any(|w| w == name);
+            if is_deleted {
+                deleted.push(((d.md.dev(), d.md.ino()), dir_ent.name.clone(), dir_ent.ino));
             }
+            this_dir.add_entry(OsString::from_vec(dir_ent.name), dir_ent.ino);
         }
 
         for e in new_dirents {
-            let md = e.metadata()?;
+            let md = e.md;
 
             let existing_inode = existing
                 .as_mut()
                 .map(|pfs| {
-                    let puzzlefs_path = rootfs_relative(&e.path());
+                    let puzzlefs_path = rootfs_relative(&e.path);
                     pfs.lookup(&puzzlefs_path)
                 })
                 .transpose()?
                 .flatten();
 
-            let cur_ino = existing_inode.map(|ex| ex.ino).unwrap_or_else(|| {
-                let next = next_ino;
-                next_ino += 1;
-                next
-            });
+            // is this a hard link? if so, just use the existing ino we have rendered. otherwise,
+            // use a new one
+            let link_key = (!md.is_dir() && md.nlink() > 1).then(|| (md.dev(), md.ino()));
+            let hard_link = link_key.and_then(|key| host_to_pfs.get(&key).copied());
+
+            let cur_ino = match (hard_link, existing_inode) {
+                (Some(ino), _) => ino,
+                (None, Some(ex)) if inodes.reuse(ex.ino) => ex.ino,
+                _ => inodes.allocate()?,
+            };
 
             // now that we know the ino of this thing, let's put it in the parent directory (assuming
             // this is not "/" for our image, aka inode #1)
             if cur_ino != 1 {
-                // is this a hard link? if so, just use the existing ino we have rendered. otherewise,
-                // use a new one
-                let the_ino = host_to_pfs.get(&md.ino()).copied().unwrap_or(cur_ino);
-                let parent_path = e.path().parent().map(|p| p.to_path_buf()).ok_or_else(|| {
-                    io::Error::other(format!("no parent for {}", e.path().display()))
+                let parent = dirs.get_mut(&(d.md.dev(), d.md.ino())).ok_or_else(|| {
+                    io::Error::other(format!("no pfs inode for {}", e.path.display()))
                 })?;
-                let parent = dirs
-                    .get_mut(&fs::symlink_metadata(parent_path)?.ino())
-                    .ok_or_else(|| {
-                        io::Error::other(format!("no pfs inode for {}", e.path().display()))
-                    })?;
-                parent.add_entry(
-                    e.path()
-                        .file_name()
-                        .unwrap_or_else(|| OsStr::new(""))
-                        .to_os_string(),
-                    the_ino,
-                );
+                parent.add_entry(e.name, cur_ino);
 
                 // if it was a hard link, we don't need to actually render it again
-                if host_to_pfs.contains_key(&md.ino()) {
+                if hard_link.is_some() {
                     continue;
                 }
             }
 
-            host_to_pfs.insert(md.ino(), cur_ino);
+            if let Some(key) = link_key {
+                host_to_pfs.insert(key, cur_ino);
+            }
 
             // render as much of the inode as we can
             // TODO: here are a bunch of optimizations we should do: no need to re-render things
             // that are the same (whole inodes, metadata, etc.). For now we just re-render the
             // whole metadata tree.
-            let additional = InodeAdditional::new(&e.path(), &md)?;
+            let additional = e.additional;
 
             if md.is_dir() {
                 dirs.insert(
-                    md.ino(),
+                    (md.dev(), md.ino()),
                     Dir {
                         ino: cur_ino,
                         md,
@@ -319,18 +713,72 @@ fn build_delta<C: Compression + Any>(
                     },
                 );
             } else if md.is_file() {
-                fs_stream.push(&e.path());
-
+                let extents = e.extents;
+                let data_len = extents.iter().map(|e| e.end - e.start).sum::<u64>();
+                let path = rootfs_relative(&e.path);
+                let inlined = options
+                    .inline_files_below
+                    .is_some_and(|threshold| data_len > 0 && md.len() <= threshold.into());
+                let resumed = match checkpoint.as_deref_mut() {
+                    Some(checkpoint) if data_len > 0 && !inlined => {
+                        checkpoint.resume(oci, &path, &md)?
+                    }
+                    _ => None,
+                };
+                let packed = options
+                    .pack_files_below
+                    .is_some_and(|threshold| data_len > 0 && data_len <= threshold.into());
+                if let Some(resumed) = &resumed {
+                    for (desc, fs_verity_digest) in &resumed.blobs {
+                        let digest = Digest::try_from(desc.digest().digest())?.underlying();
+                        if verity_data.insert(digest, *fs_verity_digest).is_none() {
+                            image_manifest.layers_mut().push(desc.clone());
+                        }
+                    }
+                    progress.bytes_chunked += data_len;
+                    report.reused_bytes += data_len;
+                } else if inlined {
+                    progress.bytes_chunked += data_len;
+                } else if packed {
+                    pack_stream.push_extents(&e.path, extents.clone());
+                } else {
+                    fs_stream.push_extents(&e.path, extents.clone());
+                }
+                progress.files_scanned += 1;
+                progress.total_bytes += data_len;
+
+                let top_level = top_level_dir(rootfs, &e.path);
+                let dir_report = report.directories.entry(top_level.clone()).or_default();
+                dir_report.files += 1;
+                dir_report.logical_bytes += md.len();
+                report.logical_bytes += md.len();
+
+                let unchunked = inlined || resumed.is_some();
+                let chunks = if inlined {
+                    // holes included, there's no point in keeping track of them in a few bytes
+                    vec![FileChunk::inline(fs::read(&e.path)?)]
+                } else {
+                    resumed.map(|r| r.chunks).unwrap_or_default()
+                };
                 let file = File {
                     ino: cur_ino,
+                    path,
                     md,
-                    chunk_list: FileChunkList {
-                        chunks: Vec::<FileChunk>::new(),
-                    },
+                    chunk_list: FileChunkList { chunks },
                     additional,
+                    extents,
+                    top_level,
+                    digest: e.digest,
+                    verity: e.verity,
                 };
 
-                files.push(file);
+                if unchunked {
+                    unchunked_files.push(file);
+                } else if packed {
+                    packed_files.push(file);
+                } else {
+                    files.push(file);
+                }
             } else {
                 let o = Other {
                     ino: cur_ino,
@@ -340,15 +788,110 @@ fn build_delta<C: Compression + Any>(
                 others.push(o);
             }
         }
+
+        options.progress.report(&progress);
     }
 
-    let fcdc = StreamCDC::new(
-        Box::new(fs_stream),
-        MIN_CHUNK_SIZE,
-        AVG_CHUNK_SIZE,
-        MAX_CHUNK_SIZE,
-    );
-    process_chunks::<C>(oci, fcdc, &mut files, verity_data, image_manifest)?;
+    match options.chunking {
+        Chunking::Fastcdc(params) => {
+            let fcdc = StreamCDC::new(
+                Box::new(fs_stream),
+                params.min_size(),
+                params.avg_size(),
+                params.max_size(),
+            )
+            .map(|chunk| chunk.map_err(io::Error::other));
+            process_chunks::<C>(
+                oci,
+                BlobGrouper::new(fcdc, options.max_blob_size, options.digest_algorithm),
+                &mut files,
+                verity_data,
+                image_manifest,
+                options,
+                &mut progress,
+                &mut report,
+                checkpoint.as_deref_mut(),
+            )?;
+        }
+        Chunking::Fixed {
+            block_size,
+            pack_small_files,
+        } => {
+            let segment_sizes = files
+                .iter()
+                .flat_map(|f| &f.extents)
+                .map(|extent| extent.end - extent.start)
+                .collect();
+            let chunker =
+                FixedSizeChunker::new(fs_stream, segment_sizes, block_size, pack_small_files);
+            process_chunks::<C>(
+                oci,
+                BlobGrouper::new(chunker, options.max_blob_size, options.digest_algorithm),
+                &mut files,
+                verity_data,
+                image_manifest,
+                options,
+                &mut progress,
+                &mut report,
+                checkpoint.as_deref_mut(),
+            )?;
+        }
+    }
+
+    // each small file is a single chunk (or one per extent, if it's sparse), several of which
+    // share a blob
+    if let Some(threshold) = options.pack_files_below {
+        let segment_sizes = packed_files
+            .iter()
+            .flat_map(|f| &f.extents)
+            .map(|extent| extent.end - extent.start)
+            .collect();
+        let chunker = FixedSizeChunker::new(pack_stream, segment_sizes, threshold, false);
+        process_chunks::<C>(
+            oci,
+            BlobGrouper::new(
+                chunker,
+                Some(options.pack_blob_size()),
+                options.digest_algorithm,
+            ),
+            &mut packed_files,
+            verity_data,
+            image_manifest,
+            options,
+            &mut progress,
+            &mut report,
+            checkpoint.as_deref_mut(),
+        )?;
+        files.append(&mut packed_files);
+    }
+    files.append(&mut unchunked_files);
+
+    // the deleted entries become whiteouts, unless their inode is still there under another
+    // name, e.g. one of the links of a hard link was removed, in which case they get one of their
+    // own rather than the whiteout hiding the inode
+    let mut whiteout_inos = BTreeSet::<Ino>::new();
+    for (dir, name, ino) in deleted {
+        let ino = if inodes.is_used(ino) {
+            let whiteout = inodes.allocate()?;
+            let entry = dirs
+                .get_mut(&dir)
+                .and_then(|d| d.dir_list.entries.iter_mut().find(|e| e.name == name))
+                .ok_or_else(|| WireFormatError::from_errno(Errno::ENOENT))?;
+            entry.ino = whiteout;
+            whiteout
+        } else {
+            ino
+        };
+        whiteout_inos.insert(ino);
+    }
+    pfs_inodes.extend(whiteout_inos.into_iter().map(Inode::new_whiteout));
+
+    // the link count of a file is the number of directory entries in the image referring to it,
+    // which may differ from the host's if some of the links are outside the rootfs
+    let mut link_counts = HashMap::<Ino, u32>::new();
+    for entry in dirs.values().flat_map(|d| &d.dir_list.entries) {
+        *link_counts.entry(entry.ino).or_default() += 1;
+    }
 
     // TODO: not render this whole thing in memory, stick it all in the same blob, etc.
     let mut sorted_dirs = dirs.into_values().collect::<Vec<_>>();
@@ -357,7 +900,23 @@ fn build_delta<C: Compression + Any>(
     pfs_inodes.extend(
         sorted_dirs
             .drain(..)
-            .map(|d| Ok(Inode::new_dir(d.ino, &d.md, d.dir_list, d.additional)?))
+            .map(|mut d| {
+                // sorted entries can be binary searched by readers
+                d.dir_list.entries.sort_by(|a, b| a.name.cmp(&b.name));
+                if options.casefold {
+                    if let Some((a, b)) = find_collision(&d.dir_list.entries) {
+                        return Err(WireFormatError::NameCollision(
+                            format!(
+                                "{} and {} only differ by case",
+                                escape_name(&a.name),
+                                escape_name(&b.name)
+                            ),
+                            Backtrace::capture(),
+                        ));
+                    }
+                }
+                Ok(Inode::new_dir(d.ino, &d.md, d.dir_list, d.additional)?)
+            })
             .collect::<Result<Vec<Inode>>>()?,
     );
 
@@ -371,6 +930,8 @@ fn build_delta<C: Compression + Any>(
                     &f.md,
                     f.chunk_list.chunks,
                     f.additional,
+                    f.digest,
+                    f.verity,
                 )?)
             })
             .collect::<Result<Vec<Inode>>>()?,
@@ -384,8 +945,168 @@ fn build_delta<C: Compression + Any>(
     );
 
     pfs_inodes.sort_by(|a, b| a.ino.cmp(&b.ino));
+    if let Some(w) = pfs_inodes.windows(2).find(|w| w[0].ino == w[1].ino) {
+        return Err(WireFormatError::InodeCollision(
+            format!("two inodes numbered {}", w[0].ino),
+            Backtrace::capture(),
+        ));
+    }
+
+    for inode in &mut pfs_inodes {
+        if !matches!(inode.mode, InodeMode::Dir { .. } | InodeMode::Wht) {
+            inode.nlink = link_counts.get(&inode.ino).copied().unwrap_or(1);
+        }
+    }
+
+    if options.layer_diff {
+        pfs_inodes.iter_mut().for_each(strip_overlay_xattrs);
+    }
+
+    if !options.ids.is_empty() {
+        for inode in &mut pfs_inodes {
+            if !matches!(inode.mode, InodeMode::Wht) {
+                inode.uid = options.ids.map_uid(inode.uid);
+                inode.gid = options.ids.map_gid(inode.gid);
+            }
+        }
+    }
+
+    if options.reproducible {
+        pfs_inodes.iter_mut().for_each(strip_host_metadata);
+    }
+
+    if !options.xattrs.is_empty() {
+        for inode in &mut pfs_inodes {
+            edit_xattrs(inode, |xattrs| options.xattrs.apply(xattrs));
+        }
+    }
+
+    for inode in &mut pfs_inodes {
+        edit_xattrs(inode, |x| xattrs::canonicalize(x, &options.ids));
+    }
+
+    // a thin delta is looked up on top of the base image, whatever didn't change is found there
+    if let (true, Some(pfs)) = (options.thin_delta, &existing) {
+        let mut changed = Vec::with_capacity(pfs_inodes.len());
+        for inode in pfs_inodes {
+            if pfs.lookup_inode(inode.ino)?.as_ref() != Some(&inode) {
+                changed.push(inode);
+            }
+        }
+        pfs_inodes = changed;
+    }
+
+    Ok((pfs_inodes, report))
+}
+
+// the key of the build report entry for a file: its top level directory, or "/" for the files
+// directly in the rootfs
+fn top_level_dir(rootfs: &Path, path: &Path) -> String {
+    // .unwrap() is fine, everything we walk is below rootfs
+    let mut components = path.strip_prefix(rootfs).unwrap().components();
+    match (components.next(), components.next()) {
+        (Some(top), Some(_)) => escape_path(&Path::new("/").join(top)).into_owned(),
+        _ => "/".to_string(),
+    }
+}
+
+enum Whiteout {
+    Opaque,
+    Entry(OsString),
+}
 
-    Ok(pfs_inodes)
+// Recognizes both the OCI layer whiteout files and the overlayfs ones, where a whiteout is a 0/0
+// character device with the name of the entry it removes, or (since Linux 6.7, for when the upper
+// directory can't have device nodes) a file with an overlay.whiteout xattr.
+fn whiteout_marker(
+    name: &OsStr,
+    md: &fs::Metadata,
+    additional: Option<&InodeAdditional>,
+) -> Option<Whiteout> {
+    if name.as_bytes() == OPAQUE_WHITEOUT {
+        return Some(Whiteout::Opaque);
+    }
+
+    if let Some(hidden) = name.as_bytes().strip_prefix(WHITEOUT_PREFIX) {
+        return Some(Whiteout::Entry(OsStr::from_bytes(hidden).to_os_string()));
+    }
+
+    if md.file_type().is_char_device() && md.rdev() == 0 {
+        return Some(Whiteout::Entry(name.to_os_string()));
+    }
+
+    let xattr_whiteout = additional.is_some_and(|additional| {
+        additional
+            .xattrs
+            .iter()
+            .any(|x| OVERLAY_WHITEOUT_XATTRS.contains(&x.key.as_slice()))
+    });
+    if xattr_whiteout {
+        return Some(Whiteout::Entry(name.to_os_string()));
+    }
+
+    None
+}
+
+const OVERLAY_XATTR_PREFIXES: &[&[u8]] = &[b"trusted.overlay.", b"user.overlay."];
+// an escaped xattr is the file's own xattr in the overlay namespace, e.g. from a nested overlay
+const OVERLAY_ESCAPE: &[u8] = b"overlay.";
+const OVERLAY_OPAQUE_XATTRS: &[&str] = &["trusted.overlay.opaque", "user.overlay.opaque"];
+const OVERLAY_WHITEOUT_XATTRS: &[&[u8]] = &[b"trusted.overlay.whiteout", b"user.overlay.whiteout"];
+
+// A directory with overlay.opaque set to "x" isn't opaque, the value only tells overlayfs that
+// some of its entries are xattr whiteouts.
+fn is_overlay_opaque(dir: &Path) -> io::Result<bool> {
+    for key in OVERLAY_OPAQUE_XATTRS {
+        if xattr::get(dir, key)?.as_deref() == Some(b"y") {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// edit the xattrs of an inode, dropping the additional data if nothing is left in it
+fn edit_xattrs(inode: &mut Inode, edit: impl FnOnce(&mut Vec<Xattr>)) {
+    if let Some(additional) = inode.additional.as_mut() {
+        edit(&mut additional.xattrs);
+        if additional.xattrs.is_empty() && additional.symlink_target.is_none() {
+            inode.additional = None;
+        }
+    }
+}
+
+// overlayfs bookkeeping on the upper directory, already taken into account by the whiteout logic;
+// escaped overlay xattrs are unescaped instead
+fn strip_overlay_xattrs(inode: &mut Inode) {
+    edit_xattrs(inode, |xattrs| {
+        xattrs.retain_mut(|x| {
+            let Some(prefix) = OVERLAY_XATTR_PREFIXES
+                .iter()
+                .find(|prefix| x.key.starts_with(prefix))
+            else {
+                return true;
+            };
+            if !x.key[prefix.len()..].starts_with(OVERLAY_ESCAPE) {
+                return false;
+            }
+            x.key
+                .drain(prefix.len()..prefix.len() + OVERLAY_ESCAPE.len());
+            true
+        })
+    });
+}
+
+// SELinux labels are assigned by the policy of the host the rootfs was created on
+const HOST_XATTRS: &[&[u8]] = &[b"security.selinux"];
+
+fn strip_host_metadata(inode: &mut Inode) {
+    inode.uid = 0;
+    inode.gid = 0;
+    inode.mtime = None;
+    inode.ctime = None;
+    edit_xattrs(inode, |xattrs| {
+        xattrs.retain(|x| !HOST_XATTRS.contains(&x.key.as_slice()))
+    });
 }
 
 pub fn build_initial_rootfs<C: Compression + Any>(
@@ -393,26 +1114,156 @@ pub fn build_initial_rootfs<C: Compression + Any>(
     oci: &Image,
     tag: &str,
 ) -> Result<Descriptor> {
+    build_initial_rootfs_with_options::<C>(rootfs, oci, tag, &BuildOptions::default())
+        .map(|(desc, _report)| desc)
+}
+
+pub fn build_initial_rootfs_with_options<C: Compression + Any>(
+    rootfs: &Path,
+    oci: &Image,
+    tag: &str,
+    options: &BuildOptions,
+) -> Result<(Descriptor, BuildReport)> {
     let mut verity_data: VerityData = BTreeMap::new();
+    options.validate(C::ALGORITHM)?;
     let mut image_manifest = oci.get_empty_manifest()?;
-    let inodes = build_delta::<C>(rootfs, oci, None, &mut verity_data, &mut image_manifest)?;
+    options.annotate(&mut image_manifest, C::ALGORITHM)?;
+    let mut checkpoint = open_checkpoint::<C>(oci, tag, options)?;
+    let (inodes, mut report) = build_delta::<C>(
+        rootfs,
+        oci,
+        None,
+        &mut verity_data,
+        &mut image_manifest,
+        options,
+        checkpoint.as_mut(),
+    )?;
 
-    let rootfs_buf = serialize_metadata(Rootfs {
+    let rootfs = Rootfs {
         metadatas: vec![inodes],
         fs_verity_data: verity_data,
         manifest_version: PUZZLEFS_IMAGE_MANIFEST_VERSION,
-    })?;
+        parent: None,
+        features: Features::empty(),
+        digest_algorithm: options.digest_algorithm,
+    };
+    let rootfs_descriptor = write_rootfs(oci, rootfs, image_manifest, tag, options)?;
+    report.metadata_bytes = rootfs_descriptor.size();
+    if let Some(checkpoint) = checkpoint {
+        checkpoint.remove(oci)?;
+    }
+
+    Ok((rootfs_descriptor, report))
+}
+
+fn open_checkpoint<C: Compression>(
+    oci: &Image,
+    tag: &str,
+    options: &BuildOptions,
+) -> Result<Option<Checkpoint>> {
+    (options.resume && !options.dry_run)
+        .then(|| Checkpoint::open::<C>(oci, tag, options))
+        .transpose()
+}
+
+// Records the features the rootfs relies on, checking the readers targeted by the options support
+// them. Readers predating the feature flags get a version 3 rootfs, which only works if there are
+// none.
+fn target_readers(mut rootfs: Rootfs, options: &BuildOptions) -> Result<Rootfs> {
+    let mut features = Features::used_by(&rootfs.metadatas);
+    if options.metadata_shard_size.is_some() {
+        features = features | Features::SHARDED_METADATA;
+    }
+    if options.casefold {
+        features = features | Features::CASEFOLD;
+    }
+    if options.chunk_table {
+        features = features | Features::CHUNK_TABLE;
+    }
+    // a reader which doesn't follow the parent would show only what changed
+    if rootfs.parent.is_some() {
+        features = features | Features::THIN_DELTA;
+    }
+    let supported = options.reader_features.unwrap_or(Features::SUPPORTED);
+    let unsupported = features.difference(supported);
+    if !unsupported.is_empty() {
+        return Err(WireFormatError::InvalidBuildOptions(
+            format!("the image needs features the targeted readers don't support: {unsupported}"),
+            Backtrace::capture(),
+        ));
+    }
+
+    if supported.is_empty() {
+        rootfs.manifest_version = UNFLAGGED_MANIFEST_VERSION;
+        rootfs.features = Features::empty();
+    } else {
+        rootfs.manifest_version = PUZZLEFS_IMAGE_MANIFEST_VERSION;
+        rootfs.features = features;
+    }
+    Ok(rootfs)
+}
+
+// Stores the metadata blob and tags the manifest. A dry run only works out the descriptor the
+// metadata blob would get.
+pub(crate) fn write_rootfs(
+    oci: &Image,
+    rootfs: Rootfs,
+    mut image_manifest: ImageManifest,
+    tag: &str,
+    options: &BuildOptions,
+) -> Result<Descriptor> {
+    let mut rootfs = target_readers(rootfs, options)?;
+    let shards = match options.metadata_shard_size {
+        Some(shard_size) => {
+            shard_metadata(oci, &mut rootfs, &mut image_manifest, shard_size, options)?
+        }
+        None => Vec::new(),
+    };
+    let mut rootfs_buf = serialize_sharded_metadata(rootfs, &shards)?;
+    if options.compress_metadata {
+        // bulk compression records the size of the rootfs in the frame, so readers can
+        // decompress it in one go
+        rootfs_buf = zstd::bulk::compress(&rootfs_buf, 0)?;
+    }
+    if options.dry_run {
+        let blob = if options.compress_metadata {
+            Image::prepare_blob::<Noop>(rootfs_buf.as_slice(), media_types::RootfsZstd {})?
+        } else {
+            Image::prepare_blob::<Noop>(rootfs_buf.as_slice(), media_types::Rootfs {})?
+        };
+        return Ok(blob.descriptor().clone());
+    }
 
-    let rootfs_descriptor = oci
-        .put_blob::<Noop>(
+    if let Some(config) = &options.config {
+        image_manifest.set_config(oci.0.write_config(config.clone())?);
+    }
+    if let Some(dictionary) = &options.compression_dictionary {
+        let blob =
+            Image::prepare_blob::<Noop>(dictionary.as_bytes(), media_types::ZstdDictionary {})?;
+        // deltas built with the dictionary of their base list it already
+        if !image_manifest.layers().contains(blob.descriptor()) {
+            oci.write_blob(blob, &mut image_manifest)?;
+        }
+    }
+    if options.compress_metadata {
+        oci.put_blob::<Noop>(
+            rootfs_buf.as_slice(),
+            &mut image_manifest,
+            media_types::RootfsZstd {},
+        )?;
+    } else {
+        oci.put_blob::<Noop>(
             rootfs_buf.as_slice(),
             &mut image_manifest,
             media_types::Rootfs {},
-        )?
-        .0;
-    oci.0
-        .insert_manifest(image_manifest, Some(tag), Platform::default())?;
-
+        )?;
+    }
+    // the rootfs is the first layer
+    let rootfs_descriptor = &mut image_manifest.layers_mut()[0];
+    options.annotate_rootfs(rootfs_descriptor);
+    let rootfs_descriptor = rootfs_descriptor.clone();
+    oci.insert_manifest(image_manifest, tag)?;
+    oci.attach_verity(tag)?;
     Ok(rootfs_descriptor)
 }
 
@@ -424,40 +1275,131 @@ pub fn add_rootfs_delta<C: Compression + Any>(
     tag: &str,
     base_layer: &str,
 ) -> Result<(Descriptor, Arc<Image>)> {
+    add_rootfs_delta_with_options::<C>(rootfs_path, oci, tag, base_layer, &BuildOptions::default())
+        .map(|(desc, image, _report)| (desc, image))
+}
+
+pub fn add_rootfs_delta_with_options<C: Compression + Any>(
+    rootfs_path: &Path,
+    oci: Image,
+    tag: &str,
+    base_layer: &str,
+    options: &BuildOptions,
+) -> Result<(Descriptor, Arc<Image>, BuildReport)> {
     let mut verity_data: VerityData = BTreeMap::new();
+    options.validate(C::ALGORITHM)?;
     let mut image_manifest = oci.get_empty_manifest()?;
+    options.annotate(&mut image_manifest, C::ALGORITHM)?;
+    image_manifest.set_config(oci.find_manifest(base_layer)?.config().clone());
+    // lookups in the delta have to work the way they do in its base
+    let mut options = options.clone();
+    options.casefold |= oci
+        .open_rootfs_blob(base_layer, None)?
+        .get_features()?
+        .contains(Features::CASEFOLD);
+    let options = &options;
+
+    let parameter_differences = match recorded_chunk_parameters(&oci, base_layer)? {
+        Some(base) => options.chunk_parameters(C::ALGORITHM).differences(&base),
+        None => Vec::new(),
+    };
+    for difference in &parameter_differences {
+        warn!("the delta won't share chunks with {base_layer}, {difference}");
+    }
+
+    // the chunks of the base may be compressed with its dictionaries
+    image_manifest.layers_mut().extend(
+        oci.find_manifest(base_layer)?
+            .layers()
+            .iter()
+            .filter(|desc| media_types::is_zstd_dictionary(desc.media_type()))
+            .cloned(),
+    );
 
     let pfs = PuzzleFS::open(oci, base_layer, None)?;
     let oci = Arc::clone(&pfs.oci);
-    let mut rootfs = Rootfs::try_from(oci.open_rootfs_blob(base_layer, None)?)?;
 
-    let inodes = build_delta::<C>(
+    let mut checkpoint = open_checkpoint::<C>(&oci, tag, options)?;
+    let (inodes, mut report) = build_delta::<C>(
         rootfs_path,
         &oci,
         Some(pfs),
         &mut verity_data,
         &mut image_manifest,
+        options,
+        checkpoint.as_mut(),
     )?;
+    report.parameter_differences = parameter_differences;
+
+    let rootfs = if options.thin_delta {
+        let parents = oci.get_pfs_rootfs_descriptors(base_layer)?;
+        let parent = Digest::try_from(parents[0].digest().digest())?.underlying();
+        // a verified image vouches for its parent
+        verity_data.insert(parent, oci.get_pfs_rootfs_verity(base_layer)?);
+        // list the whole chain of rootfs blobs, so they are copied along with the image
+        image_manifest.layers_mut().extend(parents);
+        Rootfs {
+            metadatas: vec![inodes],
+            fs_verity_data: verity_data,
+            manifest_version: PUZZLEFS_IMAGE_MANIFEST_VERSION,
+            parent: Some(parent),
+            features: Features::empty(),
+            digest_algorithm: options.digest_algorithm,
+        }
+    } else {
+        let mut rootfs = Rootfs::try_from(oci.open_rootfs_blob(base_layer, None)?)?;
+        if !rootfs.metadatas.contains(&inodes) {
+            rootfs.metadatas.insert(0, inodes);
+        }
+        rootfs.fs_verity_data.extend(verity_data);
+        rootfs.digest_algorithm = options.digest_algorithm;
+        rootfs
+    };
 
-    if !rootfs.metadatas.contains(&inodes) {
-        rootfs.metadatas.insert(0, inodes);
+    let rootfs_descriptor = write_rootfs(&oci, rootfs, image_manifest, tag, options)?;
+    report.metadata_bytes = rootfs_descriptor.size();
+    if let Some(checkpoint) = checkpoint {
+        checkpoint.remove(&oci)?;
     }
+    Ok((rootfs_descriptor, oci, report))
+}
 
-    rootfs.fs_verity_data.extend(verity_data);
-    let rootfs_buf = serialize_metadata(rootfs)?;
-    let rootfs_descriptor = oci
-        .put_blob::<Noop>(
-            rootfs_buf.as_slice(),
-            &mut image_manifest,
-            media_types::Rootfs {},
-        )?
-        .0;
-    oci.0
-        .insert_manifest(image_manifest, Some(tag), Platform::default())?;
-    Ok((rootfs_descriptor, oci))
+/// The chunk parameters the image `tag` was built with, as recorded in its manifest, e.g. to
+/// build a delta on top of it with the same ones. None for images which don't record them all,
+/// i.e. the ones built by other tools or by older puzzlefs releases.
+pub fn recorded_chunk_parameters(oci: &Image, tag: &str) -> Result<Option<ChunkParameters>> {
+    let Some(recorded) = recorded_options(oci, tag)? else {
+        return Ok(None);
+    };
+    let compression_dictionary = recorded_compression_dictionary(oci, tag)?;
+    Ok(recorded.compression.map(|compression| ChunkParameters {
+        chunking: recorded.chunking,
+        compression: compression.into(),
+        compression_level: recorded.compression_level,
+        compression_dictionary: compression_dictionary.as_ref().map(ZstdDictionary::id),
+    }))
+}
+
+/// The zstd dictionary the chunks of the image `tag` were compressed with, as recorded in its
+/// manifest, e.g. to build a delta on top of it with the same one.
+pub fn recorded_compression_dictionary(oci: &Image, tag: &str) -> Result<Option<ZstdDictionary>> {
+    recorded_options(oci, tag)?
+        .and_then(|recorded| recorded.compression_dictionary)
+        .map(|digest| oci.read_dictionary(&digest))
+        .transpose()
+}
+
+fn recorded_options(oci: &Image, tag: &str) -> Result<Option<options::RecordedOptions>> {
+    let manifest = oci.find_manifest(tag)?;
+    manifest
+        .annotations()
+        .as_ref()
+        .and_then(|annotations| annotations.get(media_types::BUILD_OPTIONS_ANNOTATION))
+        .map(|recorded| Ok(serde_json::from_str(recorded)?))
+        .transpose()
 }
 
-fn enable_verity_for_file(file: &cap_std::fs::File) -> Result<()> {
+pub(crate) fn enable_verity_for_file(file: &cap_std::fs::File) -> Result<()> {
     if let Err(e) = fsverity_enable(
         file.as_raw_fd(),
         FS_VERITY_BLOCK_SIZE_DEFAULT,
@@ -491,17 +1433,12 @@ pub fn enable_fs_verity(oci: Image, tag: &str, manifest_root_hash: &str) -> Resu
 
     enable_and_check_verity_for_file(&rootfs_fd, &rootfs_verity[..])?;
 
-    let manifest = oci
-        .0
-        .find_manifest_with_tag(tag)?
-        .ok_or_else(|| WireFormatError::MissingManifest(tag.to_string(), Backtrace::capture()))?;
+    let manifest = oci.find_manifest(tag)?;
     let config_digest = manifest.config().digest().digest();
-    let config_digest_path = Image::blob_path().join(config_digest);
-    enable_verity_for_file(&oci.0.dir().open(config_digest_path)?)?;
+    enable_verity_for_file(&oci.open_raw_blob(config_digest, None)?)?;
 
     for (content_addressed_file, verity_hash) in rootfs.get_verity_data()? {
-        let file_path = Image::blob_path().join(Digest::new(&content_addressed_file).to_string());
-        let fd = oci.0.dir().open(&file_path)?;
+        let fd = oci.open_raw_blob(&Digest::new(&content_addressed_file).to_string(), None)?;
         if let Err(e) = fsverity_enable(
             fd.as_raw_fd(),
             FS_VERITY_BLOCK_SIZE_DEFAULT,
@@ -530,10 +1467,13 @@ pub mod tests {
 
     use tempfile::tempdir;
 
+    use crate::format::unescape_name;
+    use crate::fsverity_helpers::get_fs_verity_digest;
     use crate::reader::WalkPuzzleFS;
     use cap_std::fs::MetadataExt;
     use std::path::PathBuf;
     use tempfile::TempDir;
+    use walkdir::WalkDir;
 
     type DefaultCompression = Zstd;
 
@@ -649,12 +1589,495 @@ pub mod tests {
         Ok(())
     }
 
-    fn do_vecs_match<T: PartialEq>(a: &[T], b: &[T]) -> bool {
-        if a.len() != b.len() {
-            return false;
-        }
+    #[test]
+    fn test_layer_diff_whiteouts() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("oci"))?;
+
+        let base = dir.path().join("base");
+        fs::create_dir_all(base.join("etc"))?;
+        fs::create_dir_all(base.join("opt"))?;
+        fs::write(base.join("etc/passwd"), b"root")?;
+        fs::write(base.join("etc/shadow"), b"secret")?;
+        fs::write(base.join("opt/old"), b"old")?;
+        fs::write(base.join("keep"), b"keep")?;
+        build_initial_rootfs::<Noop>(&base, &image, "base")?;
+
+        let diff = dir.path().join("diff");
+        fs::create_dir_all(diff.join("etc"))?;
+        fs::create_dir_all(diff.join("opt"))?;
+        fs::write(diff.join("etc/.wh.shadow"), b"")?;
+        fs::write(diff.join("etc/hostname"), b"puzzlefs")?;
+        fs::write(diff.join("opt/.wh..wh..opq"), b"")?;
+        fs::write(diff.join("opt/new"), b"new")?;
+        let options = BuildOptions {
+            layer_diff: true,
+            ..Default::default()
+        };
+        add_rootfs_delta_with_options::<Noop>(&diff, image, "diff", "base", &options)?;
 
-        let matching = a.iter().zip(b.iter()).filter(|&(a, b)| a == b).count();
+        let image = Image::open(&dir.path().join("oci"))?;
+        let pfs = PuzzleFS::open(image, "diff", None)?;
+        for present in ["/keep", "/etc/passwd", "/etc/hostname", "/opt/new"] {
+            assert!(pfs.lookup(Path::new(present))?.is_some(), "{present}");
+        }
+        for absent in [
+            "/etc/shadow",
+            "/etc/.wh.shadow",
+            "/opt/old",
+            "/opt/.wh..wh..opq",
+        ] {
+            assert!(pfs.lookup(Path::new(absent))?.is_none(), "{absent}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlay_xattr_whiteouts() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("oci"))?;
+
+        let base = dir.path().join("base");
+        fs::create_dir_all(base.join("etc"))?;
+        fs::create_dir_all(base.join("opt"))?;
+        fs::write(base.join("etc/passwd"), b"root")?;
+        fs::write(base.join("etc/shadow"), b"secret")?;
+        fs::write(base.join("opt/old"), b"old")?;
+        build_initial_rootfs::<Noop>(&base, &image, "base")?;
+
+        // an overlayfs upper directory with xattr whiteouts, as unprivileged overlay mounts
+        // create them
+        let diff = dir.path().join("diff");
+        fs::create_dir_all(diff.join("etc"))?;
+        fs::create_dir_all(diff.join("opt"))?;
+        xattr::set(diff.join("etc"), "user.overlay.opaque", b"x")?;
+        fs::write(diff.join("etc/shadow"), b"")?;
+        xattr::set(diff.join("etc/shadow"), "user.overlay.whiteout", b"")?;
+        fs::write(diff.join("etc/hostname"), b"puzzlefs")?;
+        xattr::set(
+            diff.join("etc/hostname"),
+            "user.overlay.overlay.origin",
+            b"1",
+        )?;
+        xattr::set(diff.join("opt"), "user.overlay.opaque", b"y")?;
+        fs::write(diff.join("opt/new"), b"new")?;
+        let options = BuildOptions {
+            layer_diff: true,
+            ..Default::default()
+        };
+        add_rootfs_delta_with_options::<Noop>(&diff, image, "diff", "base", &options)?;
+
+        let image = Image::open(&dir.path().join("oci"))?;
+        let pfs = PuzzleFS::open(image, "diff", None)?;
+        for present in ["/etc/passwd", "/etc/hostname", "/opt/new"] {
+            assert!(pfs.lookup(Path::new(present))?.is_some(), "{present}");
+        }
+        for absent in ["/etc/shadow", "/opt/old"] {
+            assert!(pfs.lookup(Path::new(absent))?.is_none(), "{absent}");
+        }
+
+        // the overlay xattrs, the host may add others (e.g. SELinux labels)
+        let xattrs = |path: &str| -> anyhow::Result<Vec<Xattr>> {
+            let inode = pfs.lookup(Path::new(path))?.unwrap();
+            let mut xattrs = inode.additional.map(|a| a.xattrs).unwrap_or_default();
+            xattrs.retain(|x| x.key.starts_with(b"user.overlay."));
+            Ok(xattrs)
+        };
+        assert!(xattrs("/etc")?.is_empty());
+        assert!(xattrs("/opt")?.is_empty());
+        assert_eq!(
+            xattrs("/etc/hostname")?,
+            [Xattr {
+                key: b"user.overlay.origin".to_vec(),
+                val: b"1".to_vec(),
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_thin_delta() -> anyhow::Result<()> {
+        use std::io::Read;
+
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("d"))?;
+        fs::write(rootfs.join("a"), b"a")?;
+        fs::write(rootfs.join("b"), b"b")?;
+        fs::write(rootfs.join("d/c"), b"c")?;
+
+        // fixed size chunks, so the data of the files which don't change stays in the same chunks
+        let options = BuildOptions {
+            chunking: Chunking::fixed(4096, false)?,
+            thin_delta: true,
+            ..Default::default()
+        };
+        let image = Image::new(&dir.path().join("oci"))?;
+        build_initial_rootfs_with_options::<Noop>(&rootfs, &image, "base", &options)?;
+
+        fs::remove_file(rootfs.join("a"))?;
+        fs::write(rootfs.join("b"), b"changed")?;
+        fs::write(rootfs.join("e"), b"e")?;
+        add_rootfs_delta_with_options::<Noop>(&rootfs, image, "delta", "base", &options)?;
+
+        // the root directory, the whiteout for "a", "b" and "e"
+        let image = Image::open(&dir.path().join("oci"))?;
+        let delta = Rootfs::try_from(image.open_rootfs_blob("delta", None)?)?;
+        let base = image.get_pfs_rootfs_descriptors("base")?;
+        assert_eq!(
+            delta.parent,
+            Some(Digest::try_from(base[0].digest().digest())?.underlying())
+        );
+        assert_eq!(delta.metadatas.len(), 1);
+        let inos = delta.metadatas[0].iter().map(|i| i.ino).collect::<Vec<_>>();
+        assert_eq!(inos, [1, 2, 3, 6]);
+        assert_eq!(image.get_pfs_rootfs_descriptors("delta")?.len(), 2);
+
+        let mut pfs = PuzzleFS::open(image, "delta", None)?;
+        let mut files = Vec::new();
+        WalkPuzzleFS::walk(&mut pfs)?.try_for_each(|de| -> anyhow::Result<()> {
+            let de = de?;
+            if let InodeMode::File { .. } = de.inode.mode {
+                let mut contents = String::new();
+                de.open()?.read_to_string(&mut contents)?;
+                files.push((de.path.to_string_lossy().into_owned(), contents));
+            }
+            Ok(())
+        })?;
+        assert_eq!(
+            files,
+            [("/b", "changed"), ("/e", "e"), ("/d/c", "c")]
+                .map(|(path, contents)| (path.to_string(), contents.to_string()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_special_file_policy() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        fs::write(rootfs.join("file"), b"file")?;
+        nix::unistd::mkfifo(&rootfs.join("fifo"), nix::sys::stat::Mode::S_IRWXU)?;
+        let _socket = std::os::unix::net::UnixListener::bind(rootfs.join("socket"))?;
+
+        let image = Image::new(&dir.path().join("oci"))?;
+        let options = BuildOptions {
+            special_files: SpecialFilePolicy {
+                sockets: SpecialFileAction::Error,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(
+            build_initial_rootfs_with_options::<Noop>(&rootfs, &image, "test", &options).is_err()
+        );
+
+        let options = BuildOptions {
+            special_files: SpecialFilePolicy {
+                fifos: SpecialFileAction::Skip,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        build_initial_rootfs_with_options::<Noop>(&rootfs, &image, "test", &options)?;
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        assert!(pfs.lookup(Path::new("/fifo"))?.is_none());
+        let socket = pfs.lookup(Path::new("/socket"))?.unwrap();
+        assert_eq!(socket.mode, InodeMode::Sock);
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_filter() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("var/cache/apt"))?;
+        fs::create_dir_all(rootfs.join("usr/lib"))?;
+        fs::write(rootfs.join("var/cache/apt/pkgcache.bin"), b"cache")?;
+        fs::write(rootfs.join("var/log"), b"log")?;
+        fs::write(rootfs.join("usr/lib/libc.so"), b"libc")?;
+        fs::write(rootfs.join("usr/lib/libc.so.swp"), b"swap")?;
+        fs::write(rootfs.join("usr/README"), b"readme")?;
+
+        let image = Image::new(&dir.path().join("oci"))?;
+        let options = BuildOptions {
+            filter: PathFilter::new(&["/usr/lib", "var/*"], &["/var/cache", "*.swp"])?,
+            ..Default::default()
+        };
+        build_initial_rootfs_with_options::<Noop>(&rootfs, &image, "test", &options)?;
+
+        let mut pfs = PuzzleFS::open(image, "test", None)?;
+        let paths = WalkPuzzleFS::walk(&mut pfs)?
+            .map(|de| Ok(de?.path))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(
+            paths,
+            [
+                "/",
+                "/usr",
+                "/var",
+                "/usr/lib",
+                "/var/log",
+                "/usr/lib/libc.so"
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_xattr_filter() -> anyhow::Result<()> {
+        // user xattrs may not be supported on tmpfs
+        let dir = TempDir::new_in(".")?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        fs::write(rootfs.join("file"), b"puzzlefs")?;
+        xattr::set(rootfs.join("file"), "user.keep", b"1")?;
+        xattr::set(rootfs.join("file"), "user.drop.me", b"2")?;
+        xattr::set(rootfs.join("file"), "user.old.name", b"3")?;
+        xattr::set(rootfs.join("file"), "user.mime_type", b"4")?;
+
+        let image = Image::new(&dir.path().join("oci"))?;
+        let options = BuildOptions {
+            xattrs: XattrFilter {
+                allow: vec!["user.keep".into(), "user.drop".into(), "user.old.".into()],
+                drop: vec!["user.drop.".into()],
+                rename: vec![("user.old.".into(), "user.new.".into())],
+            },
+            ..Default::default()
+        };
+        build_initial_rootfs_with_options::<Noop>(&rootfs, &image, "test", &options)?;
+
+        let rootfs = image.open_rootfs_blob("test", None)?;
+        let xattrs = rootfs.find_inode(2)?.additional.unwrap().xattrs;
+        assert_eq!(
+            xattrs,
+            [
+                Xattr {
+                    key: b"user.keep".to_vec(),
+                    val: b"1".to_vec()
+                },
+                Xattr {
+                    key: b"user.new.name".to_vec(),
+                    val: b"3".to_vec()
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_progress_reporting() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        write_random_file(&rootfs.join("a"), 1024 * 1024, 0);
+        write_random_file(&rootfs.join("b"), 1024 * 1024, 0);
+
+        let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let options = BuildOptions {
+            progress: ProgressReporter::new({
+                let reports = Arc::clone(&reports);
+                move |progress| reports.lock().unwrap().push(*progress)
+            }),
+            ..Default::default()
+        };
+        let image = Image::new(&dir.path().join("oci"))?;
+        build_initial_rootfs_with_options::<Noop>(&rootfs, &image, "test", &options)?;
+
+        let reports = reports.lock().unwrap();
+        assert!(reports.len() > 1);
+        assert!(reports
+            .windows(2)
+            .all(|w| w[0].bytes_chunked <= w[1].bytes_chunked));
+
+        let last = reports.last().unwrap();
+        assert_eq!(last.files_scanned, 2);
+        assert_eq!(last.total_bytes, 2 * 1024 * 1024);
+        assert_eq!(last.bytes_chunked, last.total_bytes);
+        // the second file is a copy of the first, so most of its chunks are duplicates
+        assert!(last.dedup_hits > 0);
+        assert!(last.bytes_compressed < last.bytes_chunked);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_report() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("usr/lib"))?;
+        fs::create_dir_all(rootfs.join("opt"))?;
+        write_random_file(&rootfs.join("usr/lib/a"), 1024 * 1024, 0);
+        write_random_file(&rootfs.join("opt/b"), 1024 * 1024, 0);
+        fs::write(rootfs.join("hostname"), b"puzzlefs")?;
+
+        let image = Image::new(&dir.path().join("oci"))?;
+        let (_desc, report) = build_initial_rootfs_with_options::<Noop>(
+            &rootfs,
+            &image,
+            "base",
+            &BuildOptions::default(),
+        )?;
+
+        assert_eq!(report.logical_bytes, 2 * 1024 * 1024 + 8);
+        assert!(report.deduplicated_chunks > 0);
+        assert_eq!(report.reused_chunks, 0);
+        assert!(report.stored_bytes < report.logical_bytes);
+        assert!(report.metadata_bytes > 0);
+        assert_eq!(
+            report.directories.keys().collect::<Vec<_>>(),
+            ["/", "/opt", "/usr"]
+        );
+        assert_eq!(report.directories["/usr"].files, 1);
+        assert_eq!(report.directories["/usr"].logical_bytes, 1024 * 1024);
+        let stored = report
+            .directories
+            .values()
+            .map(|d| d.stored_bytes)
+            .sum::<u64>();
+        // each file's share of a chunk is rounded down
+        assert!(stored <= report.stored_bytes);
+        assert!(report.stored_bytes - stored <= 2 * report.chunks);
+
+        // the same data again is all in the image already
+        let (_desc, _image, report) = add_rootfs_delta_with_options::<Noop>(
+            &rootfs,
+            image,
+            "delta",
+            "base",
+            &BuildOptions::default(),
+        )?;
+        assert_eq!(report.stored_bytes, 0);
+        assert_eq!(
+            report.reused_chunks + report.deduplicated_chunks,
+            report.chunks
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        write_random_file(&rootfs.join("a"), 1024 * 1024, 0);
+
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir)?;
+        build_initial_rootfs::<Zstd>(&rootfs, &image, "base")?;
+        let count_blobs = || {
+            fs::read_dir(oci_dir.join(Image::blob_path()))
+                .unwrap()
+                .count()
+        };
+        let blobs = count_blobs();
+
+        write_random_file(&rootfs.join("b"), 1024 * 1024, 1);
+        let dry_run = BuildOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let (dry_desc, dry_report) =
+            build_initial_rootfs_with_options::<Zstd>(&rootfs, &image, "next", &dry_run)?;
+
+        assert_eq!(count_blobs(), blobs);
+        assert!(image.0.find_manifest_with_tag("next")?.is_none());
+        assert!(dry_report.reused_bytes > 0);
+        assert!(dry_report.stored_bytes > 0);
+
+        // the estimate is what the real build does
+        let (desc, report) = build_initial_rootfs_with_options::<Zstd>(
+            &rootfs,
+            &image,
+            "next",
+            &BuildOptions::default(),
+        )?;
+        assert_eq!(dry_desc, desc);
+        assert_eq!(dry_report, report);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resumed_build() -> anyhow::Result<()> {
+        use std::io::Read;
+
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        for i in 0..8 {
+            write_random_file(&rootfs.join(format!("file{i}")), 64 * 1024, i);
+        }
+
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir)?;
+        // one thread, so the chunks are written in batches of CHUNKS_PER_THREAD, each of them
+        // finishing one file
+        let chunking = Chunking::fixed(16 * 1024, false)?;
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build()?;
+
+        // interrupt the build once the first batch is written
+        let interrupted = BuildOptions {
+            chunking,
+            resume: true,
+            progress: ProgressReporter::new(|progress| {
+                assert_eq!(progress.bytes_chunked, 0, "interrupted");
+            }),
+            ..Default::default()
+        };
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            pool.install(|| {
+                build_initial_rootfs_with_options::<Zstd>(&rootfs, &image, "test", &interrupted)
+            })
+        }));
+        assert!(result.is_err());
+        let checkpoint = oci_dir.join("puzzlefs-checkpoint-test");
+        assert!(checkpoint.exists());
+
+        let walked = Arc::new(std::sync::Mutex::new(None));
+        let resumed = BuildOptions {
+            chunking,
+            resume: true,
+            progress: ProgressReporter::new({
+                let walked = Arc::clone(&walked);
+                move |progress| {
+                    walked.lock().unwrap().get_or_insert(*progress);
+                }
+            }),
+            ..Default::default()
+        };
+        pool.install(|| {
+            build_initial_rootfs_with_options::<Zstd>(&rootfs, &image, "test", &resumed)
+        })?;
+        // the files finished before the interruption didn't have to be chunked again
+        let walked = walked.lock().unwrap().unwrap();
+        assert_eq!(walked.bytes_chunked, 64 * 1024);
+        assert!(!checkpoint.exists());
+
+        let mut pfs = PuzzleFS::open(image, "test", None)?;
+        let mut files = 0;
+        for de in WalkPuzzleFS::walk(&mut pfs)? {
+            let mut de = de?;
+            if let InodeMode::File { .. } = de.inode.mode {
+                let mut contents = Vec::new();
+                de.open()?.read_to_end(&mut contents)?;
+                let expected = fs::read(rootfs.join(de.path.strip_prefix("/")?))?;
+                assert!(contents == expected, "{} differs", de.path.display());
+                files += 1;
+            }
+        }
+        assert_eq!(files, 8);
+        Ok(())
+    }
+
+    fn do_vecs_match<T: PartialEq>(a: &[T], b: &[T]) -> bool {
+        if a.len() != b.len() {
+            return false;
+        }
+
+        let matching = a.iter().zip(b.iter()).filter(|&(a, b)| a == b).count();
         matching == a.len()
     }
 
@@ -716,6 +2139,1375 @@ pub mod tests {
         true
     }
 
+    // incompressible data which FastCDC splits into many chunks
+    pub(crate) fn random_data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state: u64 = 0x9e3779b97f4a7c15 ^ seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn write_random_file(path: &Path, len: usize, seed: u64) {
+        fs::write(path, random_data(len, seed)).unwrap();
+    }
+
+    #[test]
+    fn test_chunking_params() {
+        assert!(ChunkingParams::new(4096, 1024, 16384).is_err());
+        assert!(ChunkingParams::new(1024, 4096, 1 << 30).is_err());
+        assert!(ChunkingParams::new(0, 4096, 16384).is_err());
+
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        write_random_file(&rootfs.join("file"), 1 << 20, 0);
+
+        let build = |options: &BuildOptions| {
+            let oci_dir = tempdir().unwrap();
+            let image = Image::new(oci_dir.path()).unwrap();
+            build_initial_rootfs_with_options::<Noop>(&rootfs, &image, "test", options).unwrap();
+            image.0.find_manifest_with_tag("test").unwrap().unwrap()
+        };
+
+        let default_manifest = build(&BuildOptions::default());
+        let options = BuildOptions {
+            chunking: Chunking::Fastcdc(ChunkingParams::new(1024, 4096, 16384).unwrap()),
+            ..Default::default()
+        };
+        let small_manifest = build(&options);
+        assert!(small_manifest.layers().len() > 4 * default_manifest.layers().len());
+
+        let recorded = small_manifest
+            .annotations()
+            .as_ref()
+            .unwrap()
+            .get(media_types::BUILD_OPTIONS_ANNOTATION)
+            .unwrap();
+        let recorded: options::RecordedOptions = serde_json::from_str(recorded).unwrap();
+        assert_eq!(recorded.chunking, options.chunking);
+        assert_eq!(
+            recorded.compression,
+            Some(options::RecordedCompression::None)
+        );
+        assert_eq!(recorded.compression_level, None);
+    }
+
+    #[test]
+    fn test_chunk_parameter_differences() -> anyhow::Result<()> {
+        use crate::format::CompressionAlgorithm;
+
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        write_random_file(&rootfs.join("a"), 100_000, 0);
+        let image = Image::new(&dir.path().join("oci"))?;
+        build_test_fs(&rootfs, &image, "base")?;
+
+        let base = recorded_chunk_parameters(&image, "base")?.unwrap();
+        assert_eq!(
+            base,
+            ChunkParameters {
+                chunking: Chunking::Fastcdc(ChunkingParams::default()),
+                compression: Some(CompressionAlgorithm::Zstd),
+                compression_level: None,
+                compression_dictionary: None,
+            }
+        );
+
+        let (_, _, report) = add_rootfs_delta_with_options::<Zstd>(
+            &rootfs,
+            image,
+            "same",
+            "base",
+            &Default::default(),
+        )?;
+        assert!(report.parameter_differences.is_empty());
+
+        let options = BuildOptions {
+            chunking: Chunking::fixed(4096, false)?,
+            ..Default::default()
+        };
+        let image = Image::open(&dir.path().join("oci"))?;
+        let (_, _, report) =
+            add_rootfs_delta_with_options::<Noop>(&rootfs, image, "other", "base", &options)?;
+        assert_eq!(
+            report.parameter_differences,
+            [
+                format!(
+                    "chunking: fixed 4096 byte chunks, base has {}",
+                    base.chunking
+                ),
+                "compression: none, base has zstd".to_string(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_dictionary() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        let samples = (0..1000)
+            .map(|i| {
+                format!(
+                    "{{\"name\": \"service-{i}\", \"enabled\": {}, \"timeout\": {}}}\n",
+                    i % 3 == 0,
+                    i * 7
+                )
+                .repeat(4)
+            })
+            .collect::<Vec<_>>();
+        for (i, sample) in samples.iter().take(100).enumerate() {
+            fs::write(rootfs.join(format!("{i}.json")), sample)?;
+        }
+        let dictionary = ZstdDictionary::new(zstd::dict::from_samples(&samples, 4096)?)?;
+        let options = BuildOptions {
+            compression_dictionary: Some(dictionary.clone()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            options.validate(None),
+            Err(WireFormatError::InvalidBuildOptions(..))
+        ));
+
+        let image = Image::new(&dir.path().join("oci"))?;
+        build_initial_rootfs_with_options::<Zstd>(&rootfs, &image, "base", &options)?;
+        let recorded = recorded_compression_dictionary(&image, "base")?.unwrap();
+        assert_eq!(recorded.as_bytes(), dictionary.as_bytes());
+        assert_eq!(
+            recorded_chunk_parameters(&image, "base")?
+                .unwrap()
+                .compression_dictionary,
+            Some(dictionary.id())
+        );
+
+        // the delta's own chunks aren't compressed with it, but the ones of its base are
+        let (_, image, report) = add_rootfs_delta_with_options::<Zstd>(
+            &rootfs,
+            image,
+            "delta",
+            "base",
+            &Default::default(),
+        )?;
+        assert_eq!(
+            report.parameter_differences,
+            [format!(
+                "compression dictionary: none, base has {}",
+                dictionary.id()
+            )]
+        );
+        let dictionaries = |manifest: ImageManifest| {
+            manifest
+                .layers()
+                .iter()
+                .filter(|desc| media_types::is_zstd_dictionary(desc.media_type()))
+                .count()
+        };
+        assert_eq!(dictionaries(image.find_manifest("base")?), 1);
+        assert_eq!(dictionaries(image.find_manifest("delta")?), 1);
+        assert!(recorded_compression_dictionary(&image, "delta")?.is_none());
+
+        let pfs = PuzzleFS::open(Image::open(&dir.path().join("oci"))?, "delta", None)?;
+        for (i, sample) in samples.iter().take(100).enumerate() {
+            let inode = pfs.lookup(Path::new(&format!("/{i}.json")))?.unwrap();
+            let mut data = String::new();
+            crate::reader::FileReader::new(&pfs.oci, &inode)?.read_to_string(&mut data)?;
+            assert_eq!(&data, sample);
+        }
+
+        // without the dictionary, the chunks can't be decompressed
+        let inode = pfs.lookup(Path::new("/0.json"))?.unwrap();
+        let image = Image::open(&dir.path().join("oci"))?;
+        let err = crate::reader::FileReader::new(&image, &inode)?
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(Errno::EIO as i32));
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_annotations() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let options = BuildOptions {
+            annotations: BTreeMap::from([
+                (
+                    "org.opencontainers.image.revision".to_string(),
+                    "4a4aa49".to_string(),
+                ),
+                (
+                    "org.opencontainers.image.created".to_string(),
+                    "2024-01-01T00:00:00Z".to_string(),
+                ),
+            ]),
+            rootfs_annotations: BTreeMap::from([(
+                "dev.example.sbom".to_string(),
+                "sha256:1234".to_string(),
+            )]),
+            ..Default::default()
+        };
+        build_initial_rootfs_with_options::<Noop>(
+            Path::new("src/builder/test/test-1"),
+            &image,
+            "test",
+            &options,
+        )?;
+
+        assert_eq!(image.annotations("test")?, options.annotations);
+        assert_eq!(
+            image.rootfs_annotations("test")?,
+            options.rootfs_annotations
+        );
+        // the puzzlefs annotations are still there, just not among the custom ones
+        image.get_pfs_rootfs_verity("test")?;
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        assert_eq!(pfs.annotations(), &options.annotations);
+
+        let reserved = BuildOptions {
+            annotations: BTreeMap::from([(
+                media_types::BUILD_OPTIONS_ANNOTATION.to_string(),
+                String::new(),
+            )]),
+            ..Default::default()
+        };
+        assert!(reserved.validate(None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixed_size_chunking() -> anyhow::Result<()> {
+        assert!(Chunking::fixed(1000, false).is_err());
+        assert!(Chunking::fixed(256, false).is_err());
+        // the variant can be built without the checks, a zero block size would never end a chunk
+        let zero = BuildOptions {
+            chunking: Chunking::Fixed {
+                block_size: 0,
+                pack_small_files: false,
+            },
+            ..Default::default()
+        };
+        assert!(matches!(
+            zero.validate(None),
+            Err(WireFormatError::InvalidBuildOptions(..))
+        ));
+
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs_dir)?;
+        write_random_file(&rootfs_dir.join("a"), 10000, 0);
+        write_random_file(&rootfs_dir.join("b"), 5000, 1);
+
+        let image = Image::new(&dir.path().join("oci"))?;
+        let options = BuildOptions {
+            chunking: Chunking::fixed(4096, false)?,
+            ..Default::default()
+        };
+        build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "test", &options)?;
+        let rootfs = image.open_rootfs_blob("test", None)?;
+
+        for (ino, expected) in [(2, vec![4096, 4096, 1808]), (3, vec![4096, 904])] {
+            let inode = rootfs.find_inode(ino)?;
+            let InodeMode::File { chunks } = inode.mode else {
+                panic!("bad inode mode: {:?}", inode.mode);
+            };
+            assert_eq!(chunks.iter().map(|c| c.len).collect::<Vec<_>>(), expected);
+            assert!(chunks.iter().all(|c| c.blob.unwrap().offset == 0));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_blob_size() -> anyhow::Result<()> {
+        use std::io::Read;
+
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs_dir)?;
+        write_random_file(&rootfs_dir.join("a"), 20000, 0);
+        write_random_file(&rootfs_dir.join("b"), 20000, 0);
+
+        let image = Image::new(&dir.path().join("oci"))?;
+        let too_small = BuildOptions {
+            chunking: Chunking::fixed(4096, false)?,
+            max_blob_size: Some(1024),
+            ..Default::default()
+        };
+        assert!(
+            build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "test", &too_small)
+                .is_err()
+        );
+
+        let options = BuildOptions {
+            max_blob_size: Some(16384),
+            ..too_small
+        };
+        let (_desc, report) =
+            build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "test", &options)?;
+        assert_eq!(report.chunks, 10);
+        assert_eq!(report.deduplicated_chunks, 5);
+
+        // four chunks in the first blob, the tail of "a" in the second; "b" is all duplicates
+        let rootfs = image.open_rootfs_blob("test", None)?;
+        let blobs = |ino| -> anyhow::Result<Vec<BlobRef>> {
+            let InodeMode::File { chunks } = rootfs.find_inode(ino)?.mode else {
+                panic!("{ino} is not a file");
+            };
+            Ok(chunks.iter().map(|c| c.blob.unwrap()).collect())
+        };
+        let a = blobs(2)?;
+        assert_eq!(
+            a.iter().map(|b| b.offset).collect::<Vec<_>>(),
+            [0, 4096, 8192, 12288, 0]
+        );
+        assert!(a[..4].iter().all(|b| b.digest == a[0].digest));
+        assert_ne!(a[4].digest, a[0].digest);
+        assert_eq!(blobs(3)?, a);
+
+        let manifest = image.0.find_manifest_with_tag("test")?.unwrap();
+        let recorded = manifest
+            .annotations()
+            .as_ref()
+            .unwrap()
+            .get(media_types::BUILD_OPTIONS_ANNOTATION)
+            .unwrap();
+        let recorded: options::RecordedOptions = serde_json::from_str(recorded)?;
+        assert_eq!(recorded.max_blob_size, Some(16384));
+
+        let expected = fs::read(rootfs_dir.join("a"))?;
+        let mut pfs = PuzzleFS::open(image, "test", None)?;
+        let mut walker = WalkPuzzleFS::walk(&mut pfs)?;
+        walker.try_for_each(|de| -> anyhow::Result<()> {
+            let de = de?;
+            if let InodeMode::File { .. } = de.inode.mode {
+                let mut contents = Vec::new();
+                de.open()?.read_to_end(&mut contents)?;
+                assert!(
+                    contents == expected,
+                    "bad contents for {}",
+                    de.path.display()
+                );
+            }
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pack_small_files() -> anyhow::Result<()> {
+        use std::io::Read;
+
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs_dir)?;
+        for i in 0..50 {
+            write_random_file(&rootfs_dir.join(format!("small{i:02}")), 100 + i, i as u64);
+        }
+        write_random_file(&rootfs_dir.join("large"), 1 << 20, 0);
+
+        let image = Image::new(&dir.path().join("oci"))?;
+        for threshold in [0, 2 << 20] {
+            let options = BuildOptions {
+                pack_files_below: Some(threshold),
+                ..Default::default()
+            };
+            assert!(build_initial_rootfs_with_options::<Noop>(
+                &rootfs_dir,
+                &image,
+                "test",
+                &options
+            )
+            .is_err());
+        }
+
+        let options = BuildOptions {
+            pack_files_below: Some(4096),
+            ..Default::default()
+        };
+        build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "test", &options)?;
+
+        let mut pfs = PuzzleFS::open(image, "test", None)?;
+        let mut small_blobs = Vec::new();
+        let mut walker = WalkPuzzleFS::walk(&mut pfs)?;
+        walker.try_for_each(|de| -> anyhow::Result<()> {
+            let de = de?;
+            if let InodeMode::File { chunks } = &de.inode.mode {
+                let name = de.path.file_name().unwrap().to_str().unwrap();
+                if name.starts_with("small") {
+                    assert_eq!(chunks.len(), 1);
+                    small_blobs.push(chunks[0].blob.unwrap());
+                } else {
+                    assert!(chunks.len() > 1);
+                }
+
+                let mut contents = Vec::new();
+                de.open()?.read_to_end(&mut contents)?;
+                assert!(
+                    contents == fs::read(rootfs_dir.join(name))?,
+                    "bad contents for {name}"
+                );
+            }
+            Ok(())
+        })?;
+
+        // all the small files are in a single blob
+        assert_eq!(small_blobs.len(), 50);
+        assert!(small_blobs
+            .iter()
+            .all(|b| b.digest == small_blobs[0].digest));
+        assert!(small_blobs.windows(2).all(|w| w[0].offset < w[1].offset));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_build() -> anyhow::Result<()> {
+        use crate::encryption::{Cipher, Encryption, EncryptionKey};
+        use std::io::Read;
+
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs_dir)?;
+        let secret = b"meshuggah rocks ".repeat(1000);
+        fs::write(rootfs_dir.join("secret"), &secret)?;
+        write_random_file(&rootfs_dir.join("random"), 1 << 20, 0);
+
+        let oci_dir = dir.path().join("oci");
+        let key = EncryptionKey::new([42; 32]);
+        let options = BuildOptions {
+            encryption: Some(Encryption {
+                cipher: Cipher::XChaCha20Poly1305,
+                key: key.clone(),
+            }),
+            ..Default::default()
+        };
+        build_initial_rootfs_with_options::<Zstd>(
+            &rootfs_dir,
+            &Image::new(&oci_dir)?,
+            "test",
+            &options,
+        )?;
+
+        let read_file = |image: Image, name: &str| -> anyhow::Result<Vec<u8>> {
+            let mut pfs = PuzzleFS::open(image, "test", None)?;
+            let de = WalkPuzzleFS::walk(&mut pfs)?
+                .find(|de| {
+                    de.as_ref()
+                        .is_ok_and(|de| de.path == Path::new("/").join(name))
+                })
+                .unwrap()?;
+            let mut contents = Vec::new();
+            de.open()?.read_to_end(&mut contents)?;
+            Ok(contents)
+        };
+
+        assert_eq!(
+            read_file(Image::open(&oci_dir)?.with_key(key.clone()), "secret")?,
+            secret
+        );
+        assert_eq!(
+            read_file(Image::open(&oci_dir)?.with_key(key.clone()), "random")?,
+            fs::read(rootfs_dir.join("random"))?
+        );
+        assert!(read_file(Image::open(&oci_dir)?, "secret").is_err());
+        let no_key = read_file(Image::open(&oci_dir)?, "secret").unwrap_err();
+        assert_eq!(
+            no_key.downcast_ref::<io::Error>().unwrap().raw_os_error(),
+            Some(Errno::ENOKEY as i32)
+        );
+        let wrong_key = EncryptionKey::new([43; 32]);
+        assert!(matches!(
+            PuzzleFS::open(
+                Image::open(&oci_dir)?.with_key(wrong_key.clone()),
+                "test",
+                None
+            ),
+            Err(WireFormatError::WrongKey(..))
+        ));
+        assert!(matches!(
+            Image::open(&oci_dir)?.with_key_from("test", &wrong_key),
+            Err(WireFormatError::WrongKey(..))
+        ));
+        assert_eq!(
+            read_file(
+                Image::open(&oci_dir)?.with_key_from("test", &key)?,
+                "secret"
+            )?,
+            secret
+        );
+
+        // none of the file data is stored in the clear
+        let image = Image::open(&oci_dir)?;
+        let manifest = image.0.find_manifest_with_tag("test")?.unwrap();
+        for layer in manifest.layers() {
+            let blob = fs::read(
+                oci_dir
+                    .join(Image::blob_path())
+                    .join(layer.digest().digest()),
+            )?;
+            assert!(!blob.windows(16).any(|w| w == &secret[..16]));
+        }
+
+        // without the check of the key id when opening the image, the chunks tell a wrong key
+        // from a corrupted blob
+        let pfs = PuzzleFS::open(Image::open(&oci_dir)?, "test", None)?;
+        let inode = pfs.lookup(Path::new("/secret"))?.unwrap();
+        let InodeMode::File { chunks } = &inode.mode else {
+            panic!("/secret isn't a file");
+        };
+        let mut buf = vec![0; chunks[0].len as usize];
+        let read_chunk =
+            |image: Image, buf: &mut [u8]| image.fill_from_chunk(&chunks[0], 0, buf, None, None);
+        assert!(matches!(
+            read_chunk(Image::open(&oci_dir)?.with_key(wrong_key), &mut buf),
+            Err(WireFormatError::WrongKey(..))
+        ));
+        let blob_path = oci_dir
+            .join(Image::blob_path())
+            .join(hex::encode(chunks[0].blob.as_ref().unwrap().digest));
+        let mut blob = fs::read(&blob_path)?;
+        *blob.last_mut().unwrap() ^= 1;
+        fs::remove_file(&blob_path)?;
+        fs::write(&blob_path, blob)?;
+        assert!(matches!(
+            read_chunk(Image::open(&oci_dir)?.with_key(key), &mut buf),
+            Err(WireFormatError::CorruptedChunk(..))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_id_mapping() -> anyhow::Result<()> {
+        let ids = IdMap {
+            uids: vec![IdMapping {
+                image: 0,
+                host: 1000,
+                count: 10,
+            }],
+            ..Default::default()
+        };
+        assert_eq!(ids.map_uid(1000), 0);
+        assert_eq!(ids.map_uid(1009), 9);
+        assert_eq!(ids.map_uid(1010), 65534);
+        assert_eq!(ids.map_gid(1010), 1010);
+
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        fs::write(rootfs.join("file"), b"owned")?;
+        let md = fs::metadata(rootfs.join("file"))?;
+
+        let image = Image::new(&dir.path().join("oci"))?;
+        let options = BuildOptions {
+            ids: IdMap {
+                uids: vec![IdMapping {
+                    image: 0,
+                    host: md.uid(),
+                    count: 1,
+                }],
+                gids: vec![IdMapping {
+                    image: 100,
+                    host: md.gid(),
+                    count: 1,
+                }],
+                chown: None,
+            },
+            ..Default::default()
+        };
+        build_initial_rootfs_with_options::<Noop>(&rootfs, &image, "mapped", &options)?;
+        let options = BuildOptions {
+            ids: IdMap {
+                chown: Some((42, 43)),
+                ..options.ids
+            },
+            ..Default::default()
+        };
+        build_initial_rootfs_with_options::<Noop>(&rootfs, &image, "chown", &options)?;
+
+        for (tag, owner) in [("mapped", (0, 100)), ("chown", (42, 43))] {
+            let rootfs = image.open_rootfs_blob(tag, None)?;
+            for ino in [1, 2] {
+                let inode = rootfs.find_inode(ino)?;
+                assert_eq!((inode.uid, inode.gid), owner, "{tag}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_reproducible_build() -> anyhow::Result<()> {
+        let build_copy = |xattrs: &[(&str, &[u8])]| -> anyhow::Result<_> {
+            // user xattrs may not be supported on tmpfs
+            let dir = TempDir::new_in(".")?;
+            let rootfs = dir.path().join("rootfs");
+            fs::create_dir_all(rootfs.join("etc"))?;
+            fs::write(rootfs.join("etc/hostname"), b"puzzlefs")?;
+            write_random_file(&rootfs.join("data"), 300000, 0);
+            for (key, val) in xattrs {
+                xattr::set(rootfs.join("data"), key, val)?;
+            }
+
+            let oci_dir = tempdir()?;
+            let image = Image::new(oci_dir.path())?;
+            let options = BuildOptions {
+                reproducible: true,
+                ..Default::default()
+            };
+            build_initial_rootfs_with_options::<Zstd>(&rootfs, &image, "test", &options)?;
+            let manifest = image.0.find_manifest_descriptor_with_tag("test")?.unwrap();
+
+            let rootfs = image.open_rootfs_blob("test", None)?;
+            let data = rootfs.find_inode(2)?;
+            assert_eq!((data.uid, data.gid), (0, 0));
+            let keys = data
+                .additional
+                .map(|a| a.xattrs.into_iter().map(|x| x.key).collect::<Vec<_>>())
+                .unwrap_or_default();
+            assert_eq!(keys, [b"user.a".to_vec(), b"user.b".to_vec()]);
+
+            Ok(manifest.digest().clone())
+        };
+
+        let first = build_copy(&[("user.a", b"1"), ("user.b", b"2")])?;
+        let second = build_copy(&[("user.b", b"2"), ("user.a", b"1")])?;
+        assert_eq!(first, second);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hard_links() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs_dir.join("sub"))?;
+        fs::write(rootfs_dir.join("a"), b"linked")?;
+        fs::hard_link(rootfs_dir.join("a"), rootfs_dir.join("b"))?;
+        fs::hard_link(rootfs_dir.join("a"), rootfs_dir.join("sub/c"))?;
+        fs::write(rootfs_dir.join("d"), b"not linked")?;
+
+        let image = Image::new(&dir.path().join("oci"))?;
+        build_test_fs(&rootfs_dir, &image, "test")?;
+        let rootfs = image.open_rootfs_blob("test", None)?;
+
+        let root = rootfs.find_inode(1)?;
+        let entries = root
+            .dir_entries()?
+            .iter()
+            .map(|e| (e.name.clone(), e.ino))
+            .collect::<Vec<_>>();
+        // the second link doesn't use up an inode number
+        assert_eq!(
+            entries,
+            [
+                (b"a".to_vec(), 2),
+                (b"b".to_vec(), 2),
+                (b"d".to_vec(), 3),
+                (b"sub".to_vec(), 4)
+            ]
+        );
+        let sub = rootfs.find_inode(4)?;
+        assert_eq!(sub.dir_lookup(b"c")?, 2);
+
+        assert_eq!(rootfs.find_inode(2)?.nlink, 3);
+        assert_eq!(rootfs.find_inode(3)?.nlink, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_hard_links() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs_dir)?;
+        fs::write(rootfs_dir.join("a"), b"linked")?;
+        fs::hard_link(rootfs_dir.join("a"), rootfs_dir.join("b"))?;
+        fs::hard_link(rootfs_dir.join("a"), rootfs_dir.join("c"))?;
+        let image = Image::new(&dir.path().join("oci"))?;
+        build_test_fs(&rootfs_dir, &image, "base")?;
+
+        // a and b aren't the same file anymore, and c is gone
+        fs::remove_file(rootfs_dir.join("b"))?;
+        fs::remove_file(rootfs_dir.join("c"))?;
+        fs::write(rootfs_dir.join("b"), b"copy")?;
+        add_rootfs_delta::<DefaultCompression>(&rootfs_dir, image, "delta", "base")?;
+
+        let image = Image::open(&dir.path().join("oci"))?;
+        let pfs = PuzzleFS::open(image, "delta", None)?;
+        let a = pfs.lookup(Path::new("/a"))?.unwrap();
+        let b = pfs.lookup(Path::new("/b"))?.unwrap();
+        assert_eq!(a.ino, 2);
+        assert_eq!(b.ino, 3);
+        assert_eq!((a.nlink, b.nlink), (1, 1));
+        // c shared its inode with a, which is still there, so it gets a whiteout of its own
+        assert!(pfs.lookup(Path::new("/c"))?.is_none());
+        assert!(matches!(
+            pfs.lookup_inode(2)?.unwrap().mode,
+            InodeMode::File { .. }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_utf8_names() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        let weird = OsStr::from_bytes(b"caf\xe9");
+        fs::create_dir_all(rootfs_dir.join(weird))?;
+        fs::write(rootfs_dir.join(weird).join("menu"), b"crepes")?;
+        let image = Image::new(&dir.path().join("oci"))?;
+
+        let options = BuildOptions {
+            utf8_names: true,
+            ..Default::default()
+        };
+        match build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "utf8", &options) {
+            Err(WireFormatError::InvalidName(message, _)) => {
+                assert_eq!(message, "/caf\\xe9 isn't valid UTF-8")
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+
+        let (_, report) = build_initial_rootfs_with_options::<Noop>(
+            &rootfs_dir,
+            &image,
+            "bytes",
+            &BuildOptions::default(),
+        )?;
+        assert_eq!(report.directories["/caf\\xe9"].files, 1);
+        let pfs = PuzzleFS::open(Image::open(&dir.path().join("oci"))?, "bytes", None)?;
+        let path = PathBuf::from(OsString::from_vec(unescape_name("/caf\\xe9/menu")?));
+        assert!(pfs.lookup(&path)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_per_layer_inode_numbering() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs_dir)?;
+        fs::write(rootfs_dir.join("a"), b"a")?;
+        let options = BuildOptions {
+            inode_numbering: InodeNumbering::PerLayer,
+            ..Default::default()
+        };
+        let image = Image::new(&dir.path().join("oci"))?;
+        build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "base", &options)?;
+
+        fs::write(rootfs_dir.join("b"), b"b")?;
+        fs::write(rootfs_dir.join("c"), b"c")?;
+        add_rootfs_delta_with_options::<Noop>(&rootfs_dir, image, "first", "base", &options)?;
+        fs::write(rootfs_dir.join("d"), b"d")?;
+        let image = Image::open(&dir.path().join("oci"))?;
+        add_rootfs_delta_with_options::<Noop>(&rootfs_dir, image, "second", "first", &options)?;
+
+        let image = Image::open(&dir.path().join("oci"))?;
+        let pfs = PuzzleFS::open(image, "second", None)?;
+        let inos = ["a", "b", "c", "d"]
+            .map(|name| pfs.lookup(&Path::new("/").join(name)).unwrap().unwrap().ino);
+        assert_eq!(
+            inos,
+            [
+                2,
+                INODES_PER_LAYER,
+                INODES_PER_LAYER + 1,
+                2 * INODES_PER_LAYER
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reader_features() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs_dir)?;
+        fs::write(rootfs_dir.join("a"), b"data")?;
+        let image = Image::new(&dir.path().join("oci"))?;
+
+        // without any feature, the image can be read by releases predating feature flags
+        let unflagged = BuildOptions {
+            reader_features: Some(Features::empty()),
+            ..Default::default()
+        };
+        build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "plain", &unflagged)?;
+        let rootfs = image.open_rootfs_blob("plain", None)?;
+        assert_eq!(rootfs.get_manifest_version()?, UNFLAGGED_MANIFEST_VERSION);
+        PuzzleFS::open(Image::open(&dir.path().join("oci"))?, "plain", None)?;
+
+        fs::hard_link(rootfs_dir.join("a"), rootfs_dir.join("b"))?;
+        build_test_fs(&rootfs_dir, &image, "linked")?;
+        let rootfs = image.open_rootfs_blob("linked", None)?;
+        assert_eq!(
+            rootfs.get_manifest_version()?,
+            PUZZLEFS_IMAGE_MANIFEST_VERSION
+        );
+        assert_eq!(rootfs.get_features()?, Features::HARDLINKS);
+
+        let err = build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "old", &unflagged)
+            .unwrap_err();
+        assert!(matches!(err, WireFormatError::InvalidBuildOptions(..)));
+        let sparse_only = BuildOptions {
+            reader_features: Some(Features::SPARSE),
+            ..Default::default()
+        };
+        assert!(build_initial_rootfs_with_options::<Noop>(
+            &rootfs_dir,
+            &image,
+            "old",
+            &sparse_only
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_thin_delta_reader_features() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs_dir)?;
+        fs::write(rootfs_dir.join("a"), b"a")?;
+        let unflagged = BuildOptions {
+            reader_features: Some(Features::empty()),
+            thin_delta: true,
+            ..Default::default()
+        };
+        let image = Image::new(&dir.path().join("oci"))?;
+        build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "base", &unflagged)?;
+
+        // readers predating feature flags would show the delta without its base
+        fs::write(rootfs_dir.join("b"), b"b")?;
+        let err = add_rootfs_delta_with_options::<Noop>(
+            &rootfs_dir,
+            Image::open(&dir.path().join("oci"))?,
+            "delta",
+            "base",
+            &unflagged,
+        )
+        .unwrap_err();
+        assert!(matches!(err, WireFormatError::InvalidBuildOptions(..)));
+
+        let options = BuildOptions {
+            reader_features: Some(Features::THIN_DELTA),
+            ..unflagged
+        };
+        add_rootfs_delta_with_options::<Noop>(
+            &rootfs_dir,
+            Image::open(&dir.path().join("oci"))?,
+            "delta",
+            "base",
+            &options,
+        )?;
+        let rootfs = image.open_rootfs_blob("delta", None)?;
+        assert_eq!(
+            rootfs.get_manifest_version()?,
+            PUZZLEFS_IMAGE_MANIFEST_VERSION
+        );
+        assert_eq!(rootfs.get_features()?, Features::THIN_DELTA);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sparse_file() -> anyhow::Result<()> {
+        use std::io::Read;
+        use std::os::unix::fs::{FileExt, MetadataExt as _};
+
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs_dir)?;
+
+        const LEN: u64 = 1 << 20;
+        const DATA_OFFSET: u64 = 1 << 19;
+        let sparse = rootfs_dir.join("sparse");
+        let f = fs::File::create(&sparse)?;
+        f.set_len(LEN)?;
+        f.write_all_at(&[0xAA; 4096], DATA_OFFSET)?;
+        fs::File::create(rootfs_dir.join("empty"))?.set_len(LEN)?;
+
+        let image = Image::new(&dir.path().join("oci"))?;
+        build_test_fs(&rootfs_dir, &image, "test")?;
+
+        // not all filesystems support holes, in which case the file is stored as usual
+        if fs::metadata(&sparse)?.blocks() * 512 < LEN {
+            let rootfs = image.open_rootfs_blob("test", None)?;
+            let InodeMode::File { chunks } = rootfs.find_inode(3)?.mode else {
+                panic!("sparse is not a file");
+            };
+            assert_eq!(chunks.first().unwrap(), &FileChunk::hole(DATA_OFFSET));
+            assert!(chunks.last().unwrap().blob.is_none());
+            assert_eq!(chunks.iter().map(|c| c.len).sum::<u64>(), LEN);
+
+            let InodeMode::File { chunks } = rootfs.find_inode(2)?.mode else {
+                panic!("empty is not a file");
+            };
+            assert_eq!(chunks, [FileChunk::hole(LEN)]);
+        }
+
+        let mut pfs = PuzzleFS::open(image, "test", None)?;
+        let mut walker = WalkPuzzleFS::walk(&mut pfs)?;
+        walker.try_for_each(|de| -> anyhow::Result<()> {
+            let de = de?;
+            if let InodeMode::File { .. } = de.inode.mode {
+                let mut contents = Vec::new();
+                de.open()?.read_to_end(&mut contents)?;
+                let mut expected = vec![0; LEN as usize];
+                if de.path == Path::new("/sparse") {
+                    expected[DATA_OFFSET as usize..DATA_OFFSET as usize + 4096].fill(0xAA);
+                }
+                assert!(
+                    contents == expected,
+                    "bad contents for {}",
+                    de.path.display()
+                );
+                // the holes are hashed too
+                assert_eq!(
+                    de.inode.digest,
+                    Some(FileDigest::of(DigestAlgorithm::Sha256, &expected))
+                );
+            }
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_digests() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs_dir.join("dir"))?;
+        fs::write(rootfs_dir.join("dir/a"), b"data")?;
+        fs::write(rootfs_dir.join("empty"), b"")?;
+        let image = Image::new(&dir.path().join("oci"))?;
+
+        let options = BuildOptions {
+            digest_algorithm: DigestAlgorithm::Blake3,
+            ..Default::default()
+        };
+        build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "test", &options)?;
+        let rootfs = image.open_rootfs_blob("test", None)?;
+        assert_eq!(rootfs.get_digest_algorithm()?, DigestAlgorithm::Blake3);
+
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        let digest =
+            |path: &str| -> anyhow::Result<_> { Ok(pfs.lookup(Path::new(path))?.unwrap().digest) };
+        assert_eq!(
+            digest("/dir/a")?,
+            Some(FileDigest::of(DigestAlgorithm::Blake3, b"data"))
+        );
+        assert_eq!(
+            digest("/empty")?.unwrap().to_string(),
+            format!("blake3:{}", blake3::hash(b"").to_hex())
+        );
+        assert_eq!(digest("/dir")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_metadata() -> anyhow::Result<()> {
+        use std::io::Read;
+
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs_dir)?;
+        for i in 0..100 {
+            fs::write(rootfs_dir.join(format!("file{i}")), format!("data {i}"))?;
+        }
+        let image = Image::new(&dir.path().join("oci"))?;
+
+        let plain = build_test_fs(&rootfs_dir, &image, "plain")?;
+        let options = BuildOptions {
+            compress_metadata: true,
+            ..Default::default()
+        };
+        let (desc, _report) =
+            build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "test", &options)?;
+        assert_eq!(
+            desc.media_type().to_string(),
+            media_types::PUZZLEFS_ROOTFS_ZSTD
+        );
+        assert!(desc.size() < plain.size());
+
+        assert_eq!(image.fsck()?, Vec::new());
+        image.get_pfs_rootfs_verity("test")?;
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        let inode = pfs.lookup(Path::new("/file42"))?.unwrap();
+        let mut data = String::new();
+        crate::reader::FileReader::new(&pfs.oci, &inode)?.read_to_string(&mut data)?;
+        assert_eq!(data, "data 42");
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_shards() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs_dir)?;
+        for i in 0..100 {
+            fs::write(rootfs_dir.join(format!("file{i}")), format!("data {i}"))?;
+        }
+        let image = Image::new(&dir.path().join("oci"))?;
+
+        let too_small = BuildOptions {
+            metadata_shard_size: Some(0),
+            ..Default::default()
+        };
+        assert!(
+            build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "test", &too_small)
+                .is_err()
+        );
+        let options = BuildOptions {
+            metadata_shard_size: Some(10),
+            compress_metadata: true,
+            ..Default::default()
+        };
+        build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "test", &options)?;
+
+        let shard_layers = image
+            .find_manifest("test")?
+            .layers()
+            .iter()
+            .filter(|d| d.media_type().to_string() == media_types::PUZZLEFS_METADATA_SHARD)
+            .count();
+        let rootfs = image.open_rootfs_blob("test", None)?;
+        assert!(rootfs.get_features()?.contains(Features::SHARDED_METADATA));
+        let shards = rootfs.shard_digests()?;
+        assert_eq!(shards.len(), 11);
+        assert_eq!(shard_layers, shards.len());
+        assert_eq!(rootfs.max_inode()?, 101);
+        assert_eq!(Rootfs::try_from(rootfs)?.metadatas[0].len(), 101);
+        assert_eq!(image.fsck()?, Vec::new());
+
+        // only the shards of the inodes looked up are needed
+        fs::remove_file(
+            dir.path()
+                .join("oci")
+                .join(Image::blob_path())
+                .join(hex::encode(shards[10])),
+        )?;
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        for i in 0..100 {
+            let name = format!("file{i}");
+            let ino = pfs.lookup_name(1, name.as_bytes())?.unwrap();
+            if ino != 101 {
+                let len = format!("data {i}").len() as u64;
+                assert_eq!(pfs.find_inode(ino)?.file_len()?, len);
+            }
+        }
+        assert!(pfs.find_inode(101).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunk_checksums() -> anyhow::Result<()> {
+        use std::io::Read;
+
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs_dir)?;
+        write_random_file(&rootfs_dir.join("a"), 20000, 0);
+        write_random_file(&rootfs_dir.join("b"), 20000, 0);
+        let image = Image::new(&dir.path().join("oci"))?;
+
+        // "b" is made of duplicates, which get checksums all the same
+        let options = BuildOptions {
+            chunking: Chunking::fixed(4096, false)?,
+            max_blob_size: Some(16384),
+            ..Default::default()
+        };
+        build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "test", &options)?;
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        let read = |pfs: &PuzzleFS, ino| -> io::Result<Vec<u8>> {
+            let inode = pfs.find_inode(ino).unwrap();
+            let mut data = Vec::new();
+            crate::reader::FileReader::new(&pfs.oci, &inode)
+                .unwrap()
+                .read_to_end(&mut data)?;
+            Ok(data)
+        };
+        assert_eq!(read(&pfs, 3)?, fs::read(rootfs_dir.join("b"))?);
+
+        let inode = pfs.find_inode(2)?;
+        let InodeMode::File { chunks } = &inode.mode else {
+            panic!("a is not a file");
+        };
+        assert!(chunks.iter().all(|c| c.checksum.is_some()));
+
+        // flip a bit of the first chunk, which nothing but the checksum catches without verity
+        let blob = dir
+            .path()
+            .join("oci")
+            .join(Image::blob_path())
+            .join(hex::encode(chunks[0].blob.unwrap().digest));
+        let mut data = fs::read(&blob)?;
+        data[100] ^= 1;
+        fs::remove_file(&blob)?;
+        fs::write(&blob, data)?;
+        // the chunks checked by the read above are kept, and aren't read again
+        assert_eq!(read(&pfs, 2)?, fs::read(rootfs_dir.join("a"))?);
+        let pfs = PuzzleFS::open(Image::open(&dir.path().join("oci"))?, "test", None)?;
+        for ino in [2, 3] {
+            let err = read(&pfs, ino).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(Errno::EIO as i32));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_inline_files() -> anyhow::Result<()> {
+        use std::io::Read;
+
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs_dir)?;
+        fs::write(rootfs_dir.join("a"), b"tiny")?;
+        write_random_file(&rootfs_dir.join("b"), 20000, 0);
+        let image = Image::new(&dir.path().join("oci"))?;
+
+        let too_large = BuildOptions {
+            inline_files_below: Some(MAX_INLINE_SIZE + 1),
+            ..Default::default()
+        };
+        assert!(
+            build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "test", &too_large)
+                .is_err()
+        );
+        let options = BuildOptions {
+            inline_files_below: Some(64),
+            ..Default::default()
+        };
+        build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "test", &options)?;
+        let rootfs = image.open_rootfs_blob("test", None)?;
+        assert!(rootfs.get_features()?.contains(Features::INLINE_DATA));
+
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        for (ino, name) in [(2, "a"), (3, "b")] {
+            let inode = pfs.find_inode(ino)?;
+            let InodeMode::File { chunks } = &inode.mode else {
+                panic!("{name} is not a file");
+            };
+            assert_eq!(chunks[0].inline.is_some(), name == "a");
+            let mut data = Vec::new();
+            crate::reader::FileReader::new(&pfs.oci, &inode)?.read_to_end(&mut data)?;
+            assert_eq!(data, fs::read(rootfs_dir.join(name))?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_verity() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs_dir)?;
+        write_random_file(&rootfs_dir.join("a"), 100_000, 0);
+        fs::write(rootfs_dir.join("b"), b"")?;
+        let image = Image::new(&dir.path().join("oci"))?;
+        let options = BuildOptions {
+            file_verity: true,
+            ..Default::default()
+        };
+        build_initial_rootfs_with_options::<Zstd>(&rootfs_dir, &image, "test", &options)?;
+        build_initial_rootfs::<Zstd>(&rootfs_dir, &image, "plain")?;
+
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        for name in ["a", "b"] {
+            let mut inode = pfs.lookup(&Path::new("/").join(name))?.unwrap();
+            let expected = get_fs_verity_digest(&fs::read(rootfs_dir.join(name))?)?;
+            assert_eq!(inode.verity, Some(expected));
+            pfs.verify_file(&inode)?;
+            inode.verity = Some([0; SHA256_BLOCK_SIZE]);
+            match pfs.verify_file(&inode) {
+                Err(WireFormatError::DigestMismatch {
+                    expected: wanted,
+                    found,
+                    ..
+                }) => {
+                    assert_eq!(wanted, Digest::new(&[0; SHA256_BLOCK_SIZE]));
+                    assert_eq!(found, Digest::new(&expected));
+                }
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+
+        let plain = PuzzleFS::open(Image::open(&dir.path().join("oci"))?, "plain", None)?;
+        let inode = plain.lookup(Path::new("/a"))?.unwrap();
+        assert_eq!(inode.verity, None);
+        assert!(plain.verify_file(&inode).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunk_table() -> anyhow::Result<()> {
+        use std::io::Read;
+
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs_dir.join("dir"))?;
+        write_random_file(&rootfs_dir.join("a"), 1 << 20, 0);
+        fs::copy(rootfs_dir.join("a"), rootfs_dir.join("b"))?;
+        fs::copy(rootfs_dir.join("a"), rootfs_dir.join("dir/c"))?;
+        write_random_file(&rootfs_dir.join("d"), 1 << 20, 1);
+        let image = Image::new(&dir.path().join("oci"))?;
+
+        let (_, plain) = build_initial_rootfs_with_options::<Noop>(
+            &rootfs_dir,
+            &image,
+            "plain",
+            &Default::default(),
+        )?;
+        let options = BuildOptions {
+            chunk_table: true,
+            ..Default::default()
+        };
+        let (_, shared) =
+            build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "shared", &options)?;
+        assert!(shared.metadata_bytes < plain.metadata_bytes);
+        let rootfs = image.open_rootfs_blob("shared", None)?;
+        assert!(rootfs.get_features()?.contains(Features::CHUNK_TABLE));
+
+        let plain = Rootfs::try_from(image.open_rootfs_blob("plain", None)?)?;
+        let shared = Rootfs::try_from(rootfs)?;
+        assert_eq!(shared.metadatas, plain.metadatas);
+
+        let pfs = PuzzleFS::open(image, "shared", None)?;
+        for path in ["/a", "/b", "/dir/c", "/d"] {
+            let inode = pfs.lookup(Path::new(path))?.unwrap();
+            let mut data = Vec::new();
+            crate::reader::FileReader::new(&pfs.oci, &inode)?.read_to_end(&mut data)?;
+            assert_eq!(data, fs::read(rootfs_dir.join(&path[1..]))?, "{path}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_casefold() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs_dir = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs_dir.join("Docs"))?;
+        fs::write(rootfs_dir.join("Docs/README.txt"), b"hello")?;
+        fs::write(rootfs_dir.join("Docs/caf\u{e9}"), b"coffee")?;
+        let image = Image::new(&dir.path().join("oci"))?;
+        let options = BuildOptions {
+            casefold: true,
+            ..Default::default()
+        };
+        build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "test", &options)?;
+        let rootfs = image.open_rootfs_blob("test", None)?;
+        assert!(rootfs.get_features()?.contains(Features::CASEFOLD));
+
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        let readme = pfs.lookup(Path::new("/Docs/README.txt"))?.unwrap();
+        assert_eq!(
+            pfs.lookup(Path::new("/docs/readme.TXT"))?.unwrap().ino,
+            readme.ino
+        );
+        assert!(pfs.lookup(Path::new("/DOCS/CAFE\u{301}"))?.is_some());
+        assert!(pfs.lookup(Path::new("/docs/readme"))?.is_none());
+
+        // names which only differ by case can't be told apart
+        fs::write(rootfs_dir.join("Docs/readme.txt"), b"other")?;
+        let image = Image::new(&dir.path().join("oci2"))?;
+        let err = build_initial_rootfs_with_options::<Noop>(&rootfs_dir, &image, "test", &options)
+            .unwrap_err();
+        assert!(matches!(err, WireFormatError::NameCollision(..)));
+        build_initial_rootfs_with_options::<Noop>(
+            &rootfs_dir,
+            &image,
+            "test",
+            &BuildOptions::default(),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_build_deterministic() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+
+        // enough pseudo-random data for several batches of chunks, split across files so chunks
+        // straddle file boundaries
+        for i in 0..6 {
+            write_random_file(&rootfs.join(format!("file{i}")), 1 << 20, i);
+        }
+
+        let build_with_threads = |threads| {
+            let oci_dir = tempdir().unwrap();
+            let image = Image::new(oci_dir.path()).unwrap();
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let desc = pool
+                .install(|| build_test_fs(&rootfs, &image, "test"))
+                .unwrap();
+            let manifest = image.0.find_manifest_with_tag("test").unwrap().unwrap();
+            (desc, manifest.layers().clone())
+        };
+
+        let (serial_rootfs, serial_layers) = build_with_threads(1);
+        let (parallel_rootfs, parallel_layers) = build_with_threads(8);
+        assert!(serial_layers.len() > 8 * CHUNKS_PER_THREAD);
+        assert_eq!(serial_rootfs.digest(), parallel_rootfs.digest());
+        assert_eq!(serial_layers, parallel_layers);
+    }
+
+    #[test]
+    fn test_concurrent_builds() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        Image::new(&oci_dir).unwrap();
+
+        // every rootfs shares a file with the others, so the builds also race on writing its blob
+        let rootfs = (0..4u64)
+            .map(|i| {
+                let rootfs = dir.path().join(format!("rootfs{i}"));
+                fs::create_dir_all(&rootfs).unwrap();
+                write_random_file(&rootfs.join("shared"), 1 << 20, 0);
+                write_random_file(&rootfs.join("own"), 1 << 16, i + 1);
+                rootfs
+            })
+            .collect::<Vec<_>>();
+
+        std::thread::scope(|s| {
+            for (i, rootfs) in rootfs.iter().enumerate() {
+                let oci_dir = &oci_dir;
+                s.spawn(move || {
+                    let image = Image::open(oci_dir).unwrap();
+                    build_test_fs(rootfs, &image, &format!("tag{i}")).unwrap();
+                });
+            }
+        });
+
+        let image = Image::open(&oci_dir).unwrap();
+        let index = image.get_index().unwrap();
+        let images = index
+            .manifests()
+            .iter()
+            .filter(|d| d.artifact_type().is_none());
+        assert_eq!(images.count(), rootfs.len());
+        for (i, rootfs) in rootfs.iter().enumerate() {
+            let image = Image::open(&oci_dir).unwrap();
+            let mut pfs = PuzzleFS::open(image, &format!("tag{i}"), None).unwrap();
+            for de in WalkPuzzleFS::walk(&mut pfs).unwrap() {
+                let de = de.unwrap();
+                if let InodeMode::File { .. } = de.inode.mode {
+                    let mut contents = Vec::new();
+                    de.open().unwrap().read_to_end(&mut contents).unwrap();
+                    let path = de.path.strip_prefix("/").unwrap();
+                    assert_eq!(contents, fs::read(rootfs.join(path)).unwrap());
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_reproducibility() {
         fn build_dummy_fs(dir: &Path) -> PathBuf {
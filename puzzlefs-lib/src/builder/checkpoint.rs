@@ -0,0 +1,304 @@
+// Checkpoints of resumable builds. As the chunks of a build are written, every file whose data is
+// entirely in the blob store is appended to a checkpoint in the image directory. A build resuming
+// from it takes the chunks of the files which haven't changed since from there, instead of reading
+// and chunking them again. The blob store is content addressed, so whatever the checkpoint refers
+// to stays valid whether or not the build which wrote it finished.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::{BuildOptions, File};
+use crate::compression::Compression;
+use crate::encryption::Cipher;
+use crate::format::{BlobRef, CompressionAlgorithm, FileChunk, Result, SHA256_BLOCK_SIZE};
+use crate::oci::{Descriptor, Digest, Image};
+
+const CHECKPOINT_PREFIX: &str = "puzzlefs-checkpoint-";
+
+// A checkpoint is only any use to a build with the same options.
+#[derive(PartialEq, Eq, Serialize, Deserialize)]
+struct Header {
+    options: String,
+    algorithm: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    path: PathBuf,
+    // the version of the file which was chunked
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+    chunks: Vec<Chunk>,
+    blobs: Vec<Blob>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Chunk {
+    len: u64,
+    // None for holes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    blob: Option<ChunkBlob>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChunkBlob {
+    digest: String,
+    offset: u64,
+    compressed: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Blob {
+    descriptor: Descriptor,
+    verity: String,
+}
+
+// The chunks of a file finished by an earlier build, and the blobs they are in along with their
+// fs-verity digests.
+pub(crate) struct ResumedFile {
+    pub(crate) chunks: Vec<FileChunk>,
+    pub(crate) blobs: Vec<(Descriptor, [u8; SHA256_BLOCK_SIZE])>,
+}
+
+pub(crate) struct Checkpoint {
+    name: String,
+    writer: io::BufWriter<cap_std::fs::File>,
+    // the files finished by the build being resumed, by image path
+    done: HashMap<PathBuf, Entry>,
+    // the blobs written or resumed so far, and their fs-verity digests
+    blobs: HashMap<[u8; SHA256_BLOCK_SIZE], (Descriptor, [u8; SHA256_BLOCK_SIZE])>,
+    algorithm: CompressionAlgorithm,
+    encryption: Option<Cipher>,
+}
+
+impl Checkpoint {
+    // Picks up the checkpoint left by an interrupted build of `tag`, or starts a new one.
+    pub(crate) fn open<C: Compression>(
+        oci: &Image,
+        tag: &str,
+        options: &BuildOptions,
+    ) -> Result<Self> {
+        let name = format!("{CHECKPOINT_PREFIX}{tag}");
+        let header = Header {
+            options: options.recorded_options(C::ALGORITHM)?,
+            algorithm: format!("{:?}", C::ALGORITHM),
+        };
+
+        let mut done = HashMap::new();
+        let mut resumable = false;
+        match oci.0.dir().open(&name) {
+            Ok(file) => {
+                let mut lines = io::BufReader::new(file).lines();
+                let recorded = lines.next().transpose()?;
+                let recorded = recorded.and_then(|l| serde_json::from_str::<Header>(&l).ok());
+                if recorded.as_ref() == Some(&header) {
+                    resumable = true;
+                    // the last line may have been cut short by whatever interrupted the build
+                    for line in lines {
+                        let Ok(entry) = serde_json::from_str::<Entry>(&line?) else {
+                            break;
+                        };
+                        done.insert(entry.path.clone(), entry);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let writer = if resumable {
+            let mut open_options = cap_std::fs::OpenOptions::new();
+            open_options.append(true);
+            io::BufWriter::new(oci.0.dir().open_with(&name, &open_options)?)
+        } else {
+            let mut writer = io::BufWriter::new(oci.0.dir().create(&name)?);
+            writeln!(writer, "{}", serde_json::to_string(&header)?)?;
+            writer.flush()?;
+            writer
+        };
+
+        Ok(Checkpoint {
+            name,
+            writer,
+            done,
+            blobs: HashMap::new(),
+            algorithm: C::ALGORITHM.unwrap_or_default(),
+            encryption: options.encryption.as_ref().map(|e| e.cipher),
+        })
+    }
+
+    // The chunks of the file at `path` in the image, if the build being resumed finished it and
+    // neither the file nor the blobs holding its data changed since.
+    pub(crate) fn resume(
+        &mut self,
+        oci: &Image,
+        path: &Path,
+        md: &fs::Metadata,
+    ) -> Result<Option<ResumedFile>> {
+        let Some(entry) = self.done.remove(path) else {
+            return Ok(None);
+        };
+        if (entry.size, entry.mtime, entry.ctime) != file_version(md) {
+            return Ok(None);
+        }
+
+        let mut blobs = Vec::with_capacity(entry.blobs.len());
+        for blob in entry.blobs {
+            let Some(verity) = parse_digest(&blob.verity) else {
+                return Ok(None);
+            };
+            if !oci.has_blob(&blob.descriptor) {
+                return Ok(None);
+            }
+            blobs.push((blob.descriptor, verity));
+        }
+
+        let mut chunks = Vec::with_capacity(entry.chunks.len());
+        for chunk in entry.chunks {
+            let blob = match chunk.blob {
+                Some(blob) => {
+                    let Some(digest) = parse_digest(&blob.digest) else {
+                        return Ok(None);
+                    };
+                    Some(BlobRef {
+                        digest,
+                        offset: blob.offset,
+                        compressed: blob.compressed,
+                        algorithm: self.algorithm,
+                        encryption: self.encryption,
+                    })
+                }
+                None => None,
+            };
+            chunks.push(FileChunk {
+                blob,
+                len: chunk.len,
+                checksum: chunk.checksum,
+                inline: None,
+            });
+        }
+
+        for (descriptor, verity) in &blobs {
+            let digest = Digest::try_from(descriptor.digest().digest())?.underlying();
+            self.blobs.insert(digest, (descriptor.clone(), *verity));
+        }
+        Ok(Some(ResumedFile { chunks, blobs }))
+    }
+
+    pub(crate) fn blob_written(
+        &mut self,
+        digest: [u8; SHA256_BLOCK_SIZE],
+        descriptor: &Descriptor,
+        verity: [u8; SHA256_BLOCK_SIZE],
+    ) {
+        self.blobs
+            .entry(digest)
+            .or_insert_with(|| (descriptor.clone(), verity));
+    }
+
+    // Records a file whose chunks have all been written.
+    pub(crate) fn record(&mut self, file: &File) -> Result<()> {
+        let mut chunks = Vec::with_capacity(file.chunk_list.chunks.len());
+        let mut blobs = Vec::new();
+        let mut seen = HashSet::new();
+        for chunk in &file.chunk_list.chunks {
+            let blob = match chunk.blob {
+                Some(blob) => {
+                    if seen.insert(blob.digest) {
+                        let Some((descriptor, verity)) = self.blobs.get(&blob.digest) else {
+                            // not resumable, but the build goes on just fine
+                            return Ok(());
+                        };
+                        blobs.push(Blob {
+                            descriptor: descriptor.clone(),
+                            verity: hex::encode(verity),
+                        });
+                    }
+                    Some(ChunkBlob {
+                        digest: hex::encode(blob.digest),
+                        offset: blob.offset,
+                        compressed: blob.compressed,
+                    })
+                }
+                None => None,
+            };
+            chunks.push(Chunk {
+                len: chunk.len,
+                blob,
+                checksum: chunk.checksum,
+            });
+        }
+
+        let (size, mtime, ctime) = file_version(&file.md);
+        let entry = Entry {
+            path: file.path.clone(),
+            size,
+            mtime,
+            ctime,
+            chunks,
+            blobs,
+        };
+        // JSON can't hold paths which aren't UTF-8, those files are just chunked again
+        if let Ok(line) = serde_json::to_string(&entry) {
+            writeln!(self.writer, "{line}")?;
+        }
+        Ok(())
+    }
+
+    // Called once the blobs of the recorded files are written.
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    // The build finished, there's nothing left to resume.
+    pub(crate) fn remove(self, oci: &Image) -> Result<()> {
+        drop(self.writer);
+        oci.0.dir().remove_file(&self.name)?;
+        Ok(())
+    }
+}
+
+// The blobs the checkpoints of interrupted builds refer to, which a resumed build would reuse.
+pub(crate) fn checkpointed_blobs(oci: &Image) -> Result<HashSet<String>> {
+    let mut digests = HashSet::new();
+    for entry in oci.0.dir().entries()? {
+        let entry = entry?;
+        let is_checkpoint = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(CHECKPOINT_PREFIX));
+        if !is_checkpoint {
+            continue;
+        }
+        // the header doesn't parse as an entry, and neither does a line cut short
+        for line in io::BufReader::new(entry.open()?).lines() {
+            if let Ok(entry) = serde_json::from_str::<Entry>(&line?) {
+                for blob in entry.blobs {
+                    digests.insert(blob.descriptor.digest().digest().to_string());
+                }
+            }
+        }
+    }
+    Ok(digests)
+}
+
+fn file_version(md: &fs::Metadata) -> (u64, (i64, i64), (i64, i64)) {
+    (
+        md.len(),
+        (md.mtime(), md.mtime_nsec()),
+        (md.ctime(), md.ctime_nsec()),
+    )
+}
+
+fn parse_digest(digest: &str) -> Option<[u8; SHA256_BLOCK_SIZE]> {
+    hex::decode(digest).ok()?.try_into().ok()
+}
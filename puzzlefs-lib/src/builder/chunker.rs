@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Read;
+use std::iter::Peekable;
+use std::vec;
+
+use fastcdc::v2020::ChunkData;
+
+use crate::format::DigestAlgorithm;
+
+/// Splits a stream of concatenated segments (the data regions of the files) into fixed size
+/// chunks. Chunk boundaries are aligned to `block_size` relative to the start of each segment,
+/// so every chunk except a segment's last one is exactly `block_size` long and no chunk spans two
+/// segments.
+///
+/// With `pack_small_segments`, runs of consecutive segments shorter than `block_size` are treated
+/// as a single segment, so small files share chunks instead of getting a (short) chunk each.
+pub struct FixedSizeChunker<R: Read> {
+    source: R,
+    segment_sizes: Peekable<vec::IntoIter<u64>>,
+    pack_small_segments: bool,
+    remaining: u64,
+    block_size: u64,
+    offset: u64,
+}
+
+impl<R: Read> FixedSizeChunker<R> {
+    pub fn new(
+        source: R,
+        segment_sizes: Vec<u64>,
+        block_size: u32,
+        pack_small_segments: bool,
+    ) -> Self {
+        FixedSizeChunker {
+            source,
+            segment_sizes: segment_sizes.into_iter().peekable(),
+            pack_small_segments,
+            remaining: 0,
+            block_size: block_size.into(),
+            offset: 0,
+        }
+    }
+}
+
+impl<R: Read> Iterator for FixedSizeChunker<R> {
+    type Item = io::Result<ChunkData>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.remaining == 0 {
+            self.remaining = self.segment_sizes.next()?;
+            if self.pack_small_segments && self.remaining < self.block_size {
+                while let Some(size) = self.segment_sizes.next_if(|&s| s < self.block_size) {
+                    self.remaining += size;
+                }
+            }
+        }
+
+        let length = std::cmp::min(self.remaining, self.block_size);
+        let mut data = vec![0; length as usize];
+        if let Err(e) = self.source.read_exact(&mut data) {
+            return Some(Err(e));
+        }
+
+        let chunk = ChunkData {
+            hash: 0,
+            offset: self.offset,
+            length: data.len(),
+            data,
+        };
+        self.remaining -= length;
+        self.offset += length;
+        Some(Ok(chunk))
+    }
+}
+
+/// Where a chunk is stored: a group yielded by [`BlobGrouper`] and the offset in its data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkLocation {
+    pub group: usize,
+    pub offset: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum GroupedChunk {
+    /// The chunk data is part of this group, at `offset`.
+    Stored { offset: u64, length: u64 },
+    /// The same data was grouped before.
+    Duplicate {
+        location: ChunkLocation,
+        length: u64,
+    },
+}
+
+impl GroupedChunk {
+    pub fn length(&self) -> u64 {
+        match self {
+            GroupedChunk::Stored { length, .. } | GroupedChunk::Duplicate { length, .. } => *length,
+        }
+    }
+}
+
+/// The chunks stored together in one blob, in stream order.
+pub struct BlobGroup {
+    pub data: Vec<u8>,
+    pub chunks: Vec<GroupedChunk>,
+    /// The data of the duplicate chunks, back to back; it isn't stored again, but the checksums
+    /// of the file chunks are computed from it.
+    pub duplicate_data: Vec<u8>,
+}
+
+/// Groups consecutive chunks of a stream into blobs of at most `max_blob_size` bytes (before
+/// compression); a chunk is never split, so the bound must be at least the maximum chunk size.
+/// Since blob digests no longer identify single chunks, chunks identical to one grouped earlier
+/// refer to its location instead of being stored again. Without a bound, every chunk is a group
+/// of its own and identical chunks are deduplicated by their blob digest as before.
+pub struct BlobGrouper<I: Iterator<Item = io::Result<ChunkData>>> {
+    chunks: Peekable<I>,
+    max_blob_size: Option<u64>,
+    seen: HashMap<[u8; 32], ChunkLocation>,
+    digest_algorithm: DigestAlgorithm,
+    next_group: usize,
+}
+
+impl<I: Iterator<Item = io::Result<ChunkData>>> BlobGrouper<I> {
+    pub fn new(chunks: I, max_blob_size: Option<u64>, digest_algorithm: DigestAlgorithm) -> Self {
+        BlobGrouper {
+            chunks: chunks.peekable(),
+            max_blob_size,
+            seen: HashMap::new(),
+            digest_algorithm,
+            next_group: 0,
+        }
+    }
+}
+
+impl<I: Iterator<Item = io::Result<ChunkData>>> Iterator for BlobGrouper<I> {
+    type Item = io::Result<BlobGroup>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Some(max_blob_size) = self.max_blob_size else {
+            return self.chunks.next().map(|chunk| {
+                let chunk = chunk?;
+                self.next_group += 1;
+                Ok(BlobGroup {
+                    chunks: vec![GroupedChunk::Stored {
+                        offset: 0,
+                        length: chunk.length as u64,
+                    }],
+                    data: chunk.data,
+                    duplicate_data: Vec::new(),
+                })
+            });
+        };
+
+        let mut group = BlobGroup {
+            data: Vec::new(),
+            chunks: Vec::new(),
+            duplicate_data: Vec::new(),
+        };
+        while let Some(next) = self.chunks.peek() {
+            if let Ok(chunk) = next {
+                if !group.data.is_empty()
+                    && group.data.len() as u64 + chunk.length as u64 > max_blob_size
+                {
+                    break;
+                }
+            }
+
+            let chunk = match self.chunks.next()? {
+                Ok(chunk) => chunk,
+                Err(e) => return Some(Err(e)),
+            };
+            let length = chunk.length as u64;
+            let hash = self.digest_algorithm.digest(&chunk.data);
+            if let Some(location) = self.seen.get(&hash) {
+                group.chunks.push(GroupedChunk::Duplicate {
+                    location: *location,
+                    length,
+                });
+                group.duplicate_data.extend_from_slice(&chunk.data);
+                continue;
+            }
+
+            let offset = group.data.len() as u64;
+            self.seen.insert(
+                hash,
+                ChunkLocation {
+                    group: self.next_group,
+                    offset,
+                },
+            );
+            group.chunks.push(GroupedChunk::Stored { offset, length });
+            group.data.extend_from_slice(&chunk.data);
+        }
+
+        if group.chunks.is_empty() {
+            return None;
+        }
+        self.next_group += 1;
+        Some(Ok(group))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_size_chunks() {
+        let data = (0..100u8).collect::<Vec<u8>>();
+        let chunks = FixedSizeChunker::new(&data[..], vec![0, 10, 0, 40, 50], 16, false)
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+
+        let lengths = chunks.iter().map(|c| c.length).collect::<Vec<_>>();
+        assert_eq!(lengths, [10, 16, 16, 8, 16, 16, 16, 2]);
+        assert_eq!(chunks[3].offset, 42);
+        assert_eq!(
+            chunks.into_iter().flat_map(|c| c.data).collect::<Vec<_>>(),
+            data
+        );
+    }
+
+    #[test]
+    fn test_packed_small_segments() {
+        let data = (0..100u8).collect::<Vec<u8>>();
+        let chunks = FixedSizeChunker::new(&data[..], vec![5, 6, 7, 40, 3, 4, 35], 16, true)
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+
+        // 5 + 6 + 7 share chunks, the 40 byte segment starts a new one, then 3 + 4 go together
+        // and the trailing 35 bytes start on a chunk boundary again
+        let lengths = chunks.iter().map(|c| c.length).collect::<Vec<_>>();
+        assert_eq!(lengths, [16, 2, 16, 16, 8, 7, 16, 16, 3]);
+        assert_eq!(
+            chunks.into_iter().flat_map(|c| c.data).collect::<Vec<_>>(),
+            data
+        );
+    }
+
+    fn chunk(data: &[u8]) -> io::Result<ChunkData> {
+        Ok(ChunkData {
+            hash: 0,
+            offset: 0,
+            length: data.len(),
+            data: data.to_vec(),
+        })
+    }
+
+    #[test]
+    fn test_blob_grouping() {
+        let chunks = vec![
+            chunk(&[1; 10]),
+            chunk(&[2; 10]),
+            chunk(&[1; 10]),
+            chunk(&[3; 15]),
+            chunk(&[2; 10]),
+        ];
+        let groups = BlobGrouper::new(chunks.into_iter(), Some(30), DigestAlgorithm::Blake3)
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].data.len(), 20);
+        assert_eq!(
+            groups[0].chunks,
+            [
+                GroupedChunk::Stored {
+                    offset: 0,
+                    length: 10
+                },
+                GroupedChunk::Stored {
+                    offset: 10,
+                    length: 10
+                },
+                GroupedChunk::Duplicate {
+                    location: ChunkLocation {
+                        group: 0,
+                        offset: 0
+                    },
+                    length: 10
+                },
+            ]
+        );
+        assert_eq!(groups[1].data, [3; 15]);
+        assert_eq!(
+            groups[1].chunks[1],
+            GroupedChunk::Duplicate {
+                location: ChunkLocation {
+                    group: 0,
+                    offset: 10
+                },
+                length: 10
+            }
+        );
+    }
+}
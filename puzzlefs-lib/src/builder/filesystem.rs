@@ -1,9 +1,12 @@
 use std::io;
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 
 struct ReaderLink {
     file: PathBuf,
+    // the regions of the file to read, so that holes in sparse files can be skipped
+    extents: Vec<Range<u64>>,
     done: bool,
 }
 
@@ -12,7 +15,8 @@ struct ReaderLink {
 /// and [multi_reader](https://docs.rs/multi_reader/latest/multi_reader/)
 pub struct FilesystemStream {
     reader_chain: Vec<ReaderLink>,
-    current_reader: Option<std::fs::File>,
+    current_reader: Option<io::Take<std::fs::File>>,
+    current_extent: usize,
 }
 
 impl FilesystemStream {
@@ -20,12 +24,19 @@ impl FilesystemStream {
         FilesystemStream {
             reader_chain: Vec::new(),
             current_reader: None,
+            current_extent: 0,
         }
     }
 
     pub fn push(&mut self, file: &Path) {
+        self.push_extents(file, vec![0..u64::MAX])
+    }
+
+    /// Only the given regions of the file are added to the stream, in order.
+    pub fn push_extents(&mut self, file: &Path, extents: Vec<Range<u64>>) {
         self.reader_chain.push(ReaderLink {
             file: file.into(),
+            extents,
             done: false,
         })
     }
@@ -38,18 +49,28 @@ impl Read for FilesystemStream {
                 continue;
             }
 
-            let current_reader = match self.current_reader.as_mut() {
-                Some(reader) => reader,
-                None => self.current_reader.insert(std::fs::File::open(&link.file)?),
-            };
+            while let Some(extent) = link.extents.get(self.current_extent) {
+                let current_reader = match self.current_reader.as_mut() {
+                    Some(reader) => reader,
+                    None => {
+                        let mut file = std::fs::File::open(&link.file)?;
+                        file.seek(SeekFrom::Start(extent.start))?;
+                        self.current_reader
+                            .insert(file.take(extent.end - extent.start))
+                    }
+                };
 
-            match current_reader.read(buf)? {
-                0 if !buf.is_empty() => {
-                    self.current_reader = None;
-                    link.done = true
+                match current_reader.read(buf)? {
+                    0 if !buf.is_empty() => {
+                        self.current_reader = None;
+                        self.current_extent += 1;
+                    }
+                    n => return Ok(n),
                 }
-                n => return Ok(n),
             }
+
+            link.done = true;
+            self.current_extent = 0;
         }
         Ok(0)
     }
@@ -89,6 +110,15 @@ pub mod tests {
             "Lorem ipsum dolor sit amet, consectetur adipiscing elit.".as_bytes()
         );
 
+        let mut fs_stream = FilesystemStream::new();
+        fs_stream.push_extents(&file_name1, vec![0..5, 6..11]);
+        fs_stream.push_extents(&file_name2, Vec::new());
+        fs_stream.push_extents(&file_name3, vec![12..28]);
+
+        buffer.clear();
+        fs_stream.read_to_end(&mut buffer)?;
+        assert_eq!(buffer, "Loremipsumadipiscing elit.".as_bytes());
+
         Ok(())
     }
 }
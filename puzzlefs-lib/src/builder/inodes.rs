@@ -0,0 +1,109 @@
+// Hands out the inode numbers of a build, following its InodeNumbering. It keeps track of every
+// number given to an inode of the build, the ones carried over from the base image included, so
+// that no two inodes end up with the same one.
+
+use std::backtrace::Backtrace;
+use std::collections::HashSet;
+
+use super::options::{InodeNumbering, INODES_PER_LAYER};
+use crate::format::{Ino, Result, WireFormatError};
+
+pub(crate) struct InodeAllocator {
+    // None once past Ino::MAX
+    next: Option<Ino>,
+    // the last number of the range, inclusive
+    last: Ino,
+    used: HashSet<Ino>,
+}
+
+impl InodeAllocator {
+    /// `base_max` is the highest inode number of the base image, for deltas.
+    pub(crate) fn new(numbering: InodeNumbering, base_max: Option<Ino>) -> Result<Self> {
+        let (next, last) = match (numbering, base_max) {
+            (InodeNumbering::Rebased, None) => (Some(2), Ino::MAX),
+            (InodeNumbering::Rebased, Some(max)) => (max.checked_add(1), Ino::MAX),
+            (InodeNumbering::PerLayer, None) => (Some(2), INODES_PER_LAYER - 1),
+            (InodeNumbering::PerLayer, Some(max)) => {
+                let layer = max / INODES_PER_LAYER + 1;
+                if layer == Ino::MAX / INODES_PER_LAYER + 1 {
+                    return Err(WireFormatError::InodeOverflow(
+                        format!("no inode range left after inode {max} of the base image"),
+                        Backtrace::capture(),
+                    ));
+                }
+                let first = layer * INODES_PER_LAYER;
+                (Some(first), first + (INODES_PER_LAYER - 1))
+            }
+        };
+        Ok(InodeAllocator {
+            next,
+            last,
+            // the root directory
+            used: HashSet::from([1]),
+        })
+    }
+
+    pub(crate) fn allocate(&mut self) -> Result<Ino> {
+        let ino = self.next.filter(|ino| *ino <= self.last).ok_or_else(|| {
+            WireFormatError::InodeOverflow(
+                format!("all the inode numbers up to {} are taken", self.last),
+                Backtrace::capture(),
+            )
+        })?;
+        self.next = ino.checked_add(1);
+        self.used.insert(ino);
+        Ok(ino)
+    }
+
+    /// Claims the number an inode has in the base image. Returns false if another inode of the
+    /// build already has it, i.e. the inode was hard linked in the base image and its links aren't
+    /// anymore, so they need numbers of their own.
+    pub(crate) fn reuse(&mut self, ino: Ino) -> bool {
+        self.used.insert(ino)
+    }
+
+    /// Whether an inode of the build has the number `ino`.
+    pub(crate) fn is_used(&self, ino: Ino) -> bool {
+        self.used.contains(&ino)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inode_allocator() -> anyhow::Result<()> {
+        let mut rebased = InodeAllocator::new(InodeNumbering::Rebased, Some(41))?;
+        assert_eq!(rebased.allocate()?, 42);
+        assert_eq!(rebased.allocate()?, 43);
+        assert!(rebased.reuse(7));
+        assert!(!rebased.reuse(42));
+        assert!(rebased.is_used(7));
+
+        let mut per_layer = InodeAllocator::new(InodeNumbering::PerLayer, None)?;
+        assert_eq!(per_layer.allocate()?, 2);
+        let mut per_layer = InodeAllocator::new(InodeNumbering::PerLayer, Some(41))?;
+        assert_eq!(per_layer.allocate()?, INODES_PER_LAYER);
+        let mut per_layer =
+            InodeAllocator::new(InodeNumbering::PerLayer, Some(3 * INODES_PER_LAYER + 5))?;
+        assert_eq!(per_layer.allocate()?, 4 * INODES_PER_LAYER);
+
+        let mut full = InodeAllocator::new(InodeNumbering::Rebased, Some(Ino::MAX - 1))?;
+        assert_eq!(full.allocate()?, Ino::MAX);
+        assert!(matches!(
+            full.allocate(),
+            Err(WireFormatError::InodeOverflow(..))
+        ));
+        let mut full = InodeAllocator::new(InodeNumbering::Rebased, Some(Ino::MAX))?;
+        assert!(full.allocate().is_err());
+        let mut last_layer =
+            InodeAllocator::new(InodeNumbering::PerLayer, Some(Ino::MAX - INODES_PER_LAYER))?;
+        assert_eq!(last_layer.allocate()?, Ino::MAX - (INODES_PER_LAYER - 1));
+        assert!(matches!(
+            InodeAllocator::new(InodeNumbering::PerLayer, Some(Ino::MAX - 1)),
+            Err(WireFormatError::InodeOverflow(..))
+        ));
+        Ok(())
+    }
+}
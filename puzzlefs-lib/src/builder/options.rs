@@ -0,0 +1,772 @@
+use std::backtrace::Backtrace;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+use fastcdc::v2020::{
+    AVERAGE_MAX, AVERAGE_MIN, MAXIMUM_MAX, MAXIMUM_MIN, MINIMUM_MAX, MINIMUM_MIN,
+};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use log::warn;
+use ocidir::oci_spec::image::{Descriptor, ImageConfiguration, ImageManifest};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as Sha2Digest, Sha256};
+
+use super::progress::ProgressReporter;
+use crate::common::{AVG_CHUNK_SIZE, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+use crate::compression::ZstdDictionary;
+use crate::encryption::{Encryption, KeyReference};
+use crate::format::{
+    CompressionAlgorithm, Digest, DigestAlgorithm, Features, Result, WireFormatError, Xattr,
+};
+use crate::oci::media_types::{BUILD_OPTIONS_ANNOTATION, PUZZLEFS_ANNOTATION_PREFIX};
+
+/// FastCDC chunk size bounds, in bytes. Smaller chunks dedup better across images with many
+/// small files, at the cost of more metadata; larger chunks suit big, VM image like payloads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkingParams {
+    min_size: u32,
+    avg_size: u32,
+    max_size: u32,
+}
+
+impl ChunkingParams {
+    pub fn new(min_size: u32, avg_size: u32, max_size: u32) -> Result<Self> {
+        let check = |name, size, lower, upper| {
+            if size < lower || size > upper {
+                return Err(WireFormatError::InvalidBuildOptions(
+                    format!("{name} chunk size {size} not in range [{lower}, {upper}]"),
+                    Backtrace::capture(),
+                ));
+            }
+            Ok(())
+        };
+        check("minimum", min_size, MINIMUM_MIN, MINIMUM_MAX)?;
+        check("average", avg_size, AVERAGE_MIN, AVERAGE_MAX)?;
+        check("maximum", max_size, MAXIMUM_MIN, MAXIMUM_MAX)?;
+
+        if min_size > avg_size || avg_size > max_size {
+            return Err(WireFormatError::InvalidBuildOptions(
+                format!(
+                    "chunk sizes must satisfy min <= avg <= max, got {min_size}, {avg_size}, {max_size}"
+                ),
+                Backtrace::capture(),
+            ));
+        }
+
+        Ok(ChunkingParams {
+            min_size,
+            avg_size,
+            max_size,
+        })
+    }
+
+    pub fn min_size(&self) -> u32 {
+        self.min_size
+    }
+
+    pub fn avg_size(&self) -> u32 {
+        self.avg_size
+    }
+
+    pub fn max_size(&self) -> u32 {
+        self.max_size
+    }
+}
+
+impl Default for ChunkingParams {
+    fn default() -> Self {
+        ChunkingParams {
+            min_size: MIN_CHUNK_SIZE,
+            avg_size: AVG_CHUNK_SIZE,
+            max_size: MAX_CHUNK_SIZE,
+        }
+    }
+}
+
+impl fmt::Display for Chunking {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Chunking::Fastcdc(params) => write!(
+                f,
+                "fastcdc chunks of {}/{}/{} min/avg/max bytes",
+                params.min_size, params.avg_size, params.max_size
+            ),
+            Chunking::Fixed {
+                block_size,
+                pack_small_files,
+            } => {
+                write!(f, "fixed {block_size} byte chunks")?;
+                if *pack_small_files {
+                    write!(f, " with small files packed")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The parameters deciding how the chunks of an image come out, as recorded in its manifest, see
+/// [`crate::builder::recorded_chunk_parameters`]. Images built with different ones hardly share
+/// any chunks, so deltas should be built with the ones of their base.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkParameters {
+    pub chunking: Chunking,
+    /// None for uncompressed chunks.
+    pub compression: Option<CompressionAlgorithm>,
+    pub compression_level: Option<i32>,
+    /// The id of the zstd dictionary, see [`ZstdDictionary::id`].
+    pub compression_dictionary: Option<u32>,
+}
+
+impl ChunkParameters {
+    /// How `self` differs from `base`, one line per parameter, e.g. to explain why a delta
+    /// doesn't reuse the chunks of its base.
+    pub fn differences(&self, base: &ChunkParameters) -> Vec<String> {
+        let compression = |compression: Option<CompressionAlgorithm>| {
+            format!("{:?}", RecordedCompression::from(compression)).to_lowercase()
+        };
+        let level = |level: Option<i32>| match level {
+            Some(level) => level.to_string(),
+            None => "default".to_string(),
+        };
+        let dictionary = |id: Option<u32>| match id {
+            Some(id) => id.to_string(),
+            None => "none".to_string(),
+        };
+
+        let mut differences = Vec::new();
+        if self.chunking != base.chunking {
+            differences.push(format!(
+                "chunking: {}, base has {}",
+                self.chunking, base.chunking
+            ));
+        }
+        if self.compression != base.compression {
+            differences.push(format!(
+                "compression: {}, base has {}",
+                compression(self.compression),
+                compression(base.compression)
+            ));
+        } else if self.compression.is_some() && self.compression_level != base.compression_level {
+            differences.push(format!(
+                "compression level: {}, base has {}",
+                level(self.compression_level),
+                level(base.compression_level)
+            ));
+        }
+        if self.compression.is_some() && self.compression_dictionary != base.compression_dictionary
+        {
+            differences.push(format!(
+                "compression dictionary: {}, base has {}",
+                dictionary(self.compression_dictionary),
+                dictionary(base.compression_dictionary)
+            ));
+        }
+        differences
+    }
+}
+
+const DEFAULT_PACK_BLOB_SIZE: u64 = 1024 * 1024;
+
+/// The largest files whose data can be stored in their inode; past this, they bloat the
+/// metadata every reader loads more than they save.
+pub const MAX_INLINE_SIZE: u32 = 4096;
+
+/// How file data is split into chunks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "chunker", rename_all = "snake_case")]
+pub enum Chunking {
+    /// Content defined chunking with FastCDC; chunks may span multiple files.
+    Fastcdc(ChunkingParams),
+    /// Chunks of `block_size` bytes aligned to the start of each file, for consumers that need
+    /// predictable chunk boundaries. With `pack_small_files`, files smaller than a block are
+    /// packed back to back into shared chunks instead of getting a chunk each, which keeps trees
+    /// with lots of small files from turning into one tiny blob per file.
+    Fixed {
+        block_size: u32,
+        #[serde(default)]
+        pack_small_files: bool,
+    },
+}
+
+impl Chunking {
+    pub fn fixed(block_size: u32, pack_small_files: bool) -> Result<Self> {
+        if !block_size.is_power_of_two() || !(512..=MAXIMUM_MAX).contains(&block_size) {
+            return Err(WireFormatError::InvalidBuildOptions(
+                format!(
+                    "block size {block_size} must be a power of two in range [512, {MAXIMUM_MAX}]"
+                ),
+                Backtrace::capture(),
+            ));
+        }
+
+        Ok(Chunking::Fixed {
+            block_size,
+            pack_small_files,
+        })
+    }
+
+    // Chunkings built without the constructors, e.g. the ones recorded in the manifest of the
+    // base of a delta, get the same checks.
+    fn check(&self) -> Result<()> {
+        match *self {
+            Chunking::Fastcdc(params) => {
+                ChunkingParams::new(params.min_size, params.avg_size, params.max_size)?;
+            }
+            Chunking::Fixed {
+                block_size,
+                pack_small_files,
+            } => {
+                Chunking::fixed(block_size, pack_small_files)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Chunking {
+    fn default() -> Self {
+        Chunking::Fastcdc(ChunkingParams::default())
+    }
+}
+
+/// Include and exclude rules for the rootfs walk, in the spirit of `tar --exclude`. Patterns
+/// containing a `/` are matched against the path relative to the rootfs (a leading `/` is
+/// ignored), the others against file names at any depth. An excluded directory is skipped along
+/// with everything below it. When include patterns are given, only the files that match one of
+/// them, or that are below a directory which does, make it into the image; directories are
+/// always kept so the included files have their parents.
+#[derive(Clone, Debug, Default)]
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl PathFilter {
+    pub fn new<S: AsRef<str>>(include: &[S], exclude: &[S]) -> Result<Self> {
+        Ok(PathFilter {
+            include: if include.is_empty() {
+                None
+            } else {
+                Some(glob_set(include)?)
+            },
+            exclude: glob_set(exclude)?,
+        })
+    }
+
+    // path is relative to the rootfs
+    pub(crate) fn excludes(&self, path: &Path, is_dir: bool) -> bool {
+        if self.exclude.is_match(path) {
+            return true;
+        }
+
+        match &self.include {
+            Some(include) if !is_dir => !path.ancestors().any(|p| include.is_match(p)),
+            _ => false,
+        }
+    }
+}
+
+fn glob_set<S: AsRef<str>>(patterns: &[S]) -> Result<GlobSet> {
+    let invalid = |pattern: &str, e: globset::Error| {
+        WireFormatError::InvalidBuildOptions(
+            format!("invalid pattern {pattern}: {e}"),
+            Backtrace::capture(),
+        )
+    };
+
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let pattern = pattern.as_ref();
+        let glob = if pattern.contains('/') {
+            pattern.trim_start_matches('/').to_string()
+        } else {
+            format!("**/{pattern}")
+        };
+        builder.add(
+            GlobBuilder::new(&glob)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| invalid(pattern, e))?,
+        );
+    }
+    builder.build().map_err(|e| invalid("set", e))
+}
+
+/// Which extended attributes make it into the image, for source trees carrying host specific
+/// xattrs that shouldn't end up in portable images. Keys are matched by prefix, e.g. `user.`
+/// for the whole user namespace or `security.capability` for a single attribute. The allowlist
+/// is applied first, then the drop list, then the renames.
+#[derive(Clone, Debug, Default)]
+pub struct XattrFilter {
+    /// If not empty, only the xattrs matching one of these prefixes are kept.
+    pub allow: Vec<String>,
+    /// The xattrs matching one of these prefixes are dropped.
+    pub drop: Vec<String>,
+    /// (from, to) pairs: the first matching `from` prefix of a key is replaced with `to`.
+    pub rename: Vec<(String, String)>,
+}
+
+impl XattrFilter {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.drop.is_empty() && self.rename.is_empty()
+    }
+
+    pub(crate) fn apply(&self, xattrs: &mut Vec<Xattr>) {
+        let matches = |prefixes: &[String], key: &[u8]| {
+            prefixes.iter().any(|p| key.starts_with(p.as_bytes()))
+        };
+
+        xattrs.retain(|x| {
+            (self.allow.is_empty() || matches(&self.allow, &x.key)) && !matches(&self.drop, &x.key)
+        });
+
+        for xattr in xattrs.iter_mut() {
+            if let Some((from, to)) = self
+                .rename
+                .iter()
+                .find(|(from, _)| xattr.key.starts_with(from.as_bytes()))
+            {
+                xattr.key.splice(..from.len(), to.bytes());
+            }
+        }
+
+        // renames may change the order; keep it sorted so builds stay reproducible
+        xattrs.sort_by(|a, b| a.key.cmp(&b.key));
+    }
+}
+
+/// A range of ids, as in a user namespace mapping: the `count` host ids starting at `host` are
+/// the ids starting at `image` in the image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdMapping {
+    pub image: u32,
+    pub host: u32,
+    pub count: u32,
+}
+
+/// How the owners of the files in the rootfs translate to the owners in the image, e.g. so that
+/// a rootless user can build an image from a tree they own whose files belong to root. Like in a
+/// user namespace, ids which aren't mapped become the overflow id (65534), unless there is no
+/// mapping for that kind of id at all, in which case they're kept as is. `chown` takes
+/// precedence over the mappings and gives every file the same owner.
+#[derive(Clone, Debug, Default)]
+pub struct IdMap {
+    pub uids: Vec<IdMapping>,
+    pub gids: Vec<IdMapping>,
+    /// (uid, gid) owning everything in the image.
+    pub chown: Option<(u32, u32)>,
+}
+
+const OVERFLOW_ID: u32 = 65534;
+
+/// What to do with a kind of special file found in the rootfs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpecialFileAction {
+    /// Store it in the image; device nodes keep their major and minor numbers.
+    #[default]
+    Include,
+    /// Leave it out of the image, with a warning.
+    Skip,
+    /// Fail the build.
+    Error,
+}
+
+/// Which special files make it into the image. Base OS images need their device nodes, while
+/// application images built from a live system usually shouldn't pick up its sockets and fifos.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SpecialFilePolicy {
+    /// Character and block devices.
+    pub devices: SpecialFileAction,
+    pub sockets: SpecialFileAction,
+    pub fifos: SpecialFileAction,
+}
+
+impl SpecialFilePolicy {
+    pub(crate) fn keeps(&self, path: &Path, file_type: fs::FileType) -> io::Result<bool> {
+        let (kind, action) = if file_type.is_char_device() || file_type.is_block_device() {
+            ("device", self.devices)
+        } else if file_type.is_socket() {
+            ("socket", self.sockets)
+        } else if file_type.is_fifo() {
+            ("fifo", self.fifos)
+        } else {
+            return Ok(true);
+        };
+
+        match action {
+            SpecialFileAction::Include => Ok(true),
+            SpecialFileAction::Skip => {
+                warn!("skipping {kind} {}", path.display());
+                Ok(false)
+            }
+            SpecialFileAction::Error => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{kind} {} is not allowed in the image", path.display()),
+            )),
+        }
+    }
+}
+
+pub(crate) fn map_id(mappings: &[IdMapping], id: u32) -> u32 {
+    if mappings.is_empty() {
+        return id;
+    }
+
+    mappings
+        .iter()
+        .find(|m| id >= m.host && id - m.host < m.count)
+        .map(|m| m.image + (id - m.host))
+        .unwrap_or(OVERFLOW_ID)
+}
+
+impl IdMap {
+    pub fn is_empty(&self) -> bool {
+        self.uids.is_empty() && self.gids.is_empty() && self.chown.is_none()
+    }
+
+    pub fn map_uid(&self, uid: u32) -> u32 {
+        match self.chown {
+            Some((uid, _)) => uid,
+            None => map_id(&self.uids, uid),
+        }
+    }
+
+    pub fn map_gid(&self, gid: u32) -> u32 {
+        match self.chown {
+            Some((_, gid)) => gid,
+            None => map_id(&self.gids, gid),
+        }
+    }
+}
+
+/// How a build numbers the inodes it adds. Either way, the files which were already in the base
+/// image keep their numbers, so that all the layers agree on which inode is which, and the build
+/// fails rather than give two inodes the same number when it runs out of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InodeNumbering {
+    /// Right after the highest inode number of the base image.
+    #[default]
+    Rebased,
+    /// In a range of [`INODES_PER_LAYER`] numbers of the layer's own, the one after the range of
+    /// the highest inode of the base image. The layer a file was added in can be told from its
+    /// inode number, and the numbers a delta gives its new files don't depend on how many the
+    /// base holds.
+    PerLayer,
+}
+
+/// The size of the inode ranges of [`InodeNumbering::PerLayer`].
+pub const INODES_PER_LAYER: u64 = 1 << 32;
+
+/// Knobs for building a puzzlefs image; the defaults match what plain `build_initial_rootfs` and
+/// `add_rootfs_delta` use.
+#[derive(Clone, Debug, Default)]
+pub struct BuildOptions {
+    pub chunking: Chunking,
+    /// The compression level for the chunks, from 1 to 22 for zstd (xz clamps it to 9, lz4
+    /// ignores it); higher levels take longer to build but give smaller images, while reads are
+    /// about as fast. Only used when building compressed images.
+    pub compression_level: Option<i32>,
+    /// Compress the chunks with a pre-trained zstd dictionary, which mostly pays off for trees
+    /// with lots of small, similar files. The dictionary is stored in the image and recorded in
+    /// its build options; deltas list the dictionaries of their base as well, so readers find
+    /// all the ones the chunks need. Only for zstd compressed images.
+    pub compression_dictionary: Option<ZstdDictionary>,
+    /// Group consecutive chunks into blobs of up to this many bytes (uncompressed), instead of
+    /// storing every chunk in a blob of its own. Useful to keep the number of blobs down, or to
+    /// stay within the upload limits of a registry with large chunk sizes. Must be at least the
+    /// maximum chunk size.
+    pub max_blob_size: Option<u64>,
+    /// Files with at most this many bytes of data skip chunking and are packed back to back into
+    /// shared blobs (of `max_blob_size`, or 1 MiB by default), which saves a lot of blobs and
+    /// registry round trips for trees with many tiny files.
+    pub pack_files_below: Option<u32>,
+    /// Files of at most this many bytes (e.g. `.keep` files or one line configs) have their data
+    /// stored in their inode instead of in a chunk, so reading them doesn't take a blob read,
+    /// and they don't cost a blob reference each. Up to [`MAX_INLINE_SIZE`] bytes.
+    pub inline_files_below: Option<u32>,
+    /// Leave out host dependent metadata (file ownership, timestamps, SELinux labels), so
+    /// identical input trees produce bit-identical images no matter who builds them and where.
+    pub reproducible: bool,
+    /// The rootfs is a layer diff (e.g. an overlayfs upper directory or an unpacked OCI layer)
+    /// rather than a full filesystem: entries missing from it are inherited from the base layer,
+    /// and only whiteouts (`.wh.` files, opaque markers or overlayfs 0/0 character devices)
+    /// delete them.
+    pub layer_diff: bool,
+    /// Build deltas as thin layers: only the inodes which differ from the base image are stored,
+    /// and instead of carrying a copy of the base metadata, the rootfs refers to the base rootfs
+    /// by digest. Readers resolve the chain of parents.
+    pub thin_delta: bool,
+    /// Which parts of the rootfs go into the image; everything by default.
+    pub filter: PathFilter,
+    /// Don't cross into the filesystems mounted below the rootfs, e.g. /proc, /sys or network
+    /// mounts when building from /. Whatever is on them is left out of the image.
+    pub one_file_system: bool,
+    /// With `one_file_system`, keep the mountpoints of the skipped filesystems as empty
+    /// directories, so there's something to mount them on when running the image.
+    pub keep_mountpoints: bool,
+    pub xattrs: XattrFilter,
+    /// Owners of the files in the image; ignored for reproducible builds, where root owns
+    /// everything.
+    pub ids: IdMap,
+    pub special_files: SpecialFilePolicy,
+    /// Encrypt the chunks, so the image can be stored in untrusted registries; reading it takes
+    /// the same key. The metadata (file names, sizes, owners) is not encrypted.
+    pub encryption: Option<Encryption>,
+    /// Go through the whole build, compressing and hashing the chunks, but don't write anything
+    /// to the image; the build report tells how much data would be added and how much is
+    /// already in the image.
+    pub dry_run: bool,
+    /// Checkpoint the build in the image directory as the chunks are written, and pick up where
+    /// an interrupted build of the same tag with the same options left off: the files it finished,
+    /// if they haven't changed since, aren't read and chunked again. A resumed image is just as
+    /// valid, but its chunk boundaries may differ from those of an uninterrupted build. Ignored
+    /// for dry runs.
+    pub resume: bool,
+    pub progress: ProgressReporter,
+    /// Extra annotations for the manifest, e.g. the source revision
+    /// (`org.opencontainers.image.revision`), the build time (`org.opencontainers.image.created`)
+    /// or where to find the SBOM. Readers get them back with [`crate::oci::Image::annotations`].
+    /// Keys starting with `io.puzzlefsoci.puzzlefs.` are reserved.
+    pub annotations: BTreeMap<String, String>,
+    /// Extra annotations for the descriptor of the rootfs blob, see
+    /// [`crate::oci::Image::rootfs_annotations`].
+    pub rootfs_annotations: BTreeMap<String, String>,
+    /// The runtime configuration of the image (entrypoint, environment, labels...), stored as the
+    /// config blob of the manifest. Deltas inherit the one of their base when it's not given.
+    pub config: Option<ImageConfiguration>,
+    /// The features the puzzlefs releases meant to read the image support, when they're older
+    /// than this one; the build fails if the image needs others, e.g. because it has hard links.
+    /// With no features at all, the image is written in manifest version 3 so that the releases
+    /// predating feature flags can read it.
+    pub reader_features: Option<Features>,
+    /// The hash function of the digests of the whole contents of regular files, stored in their
+    /// inodes so they can be compared and verified without reading their chunks, and of the chunk
+    /// digests used to find duplicates. Blake3 is a lot faster than sha256 on large trees. The
+    /// blobs are still addressed by their sha256 digests, which is what OCI registries expect.
+    pub digest_algorithm: DigestAlgorithm,
+    /// Compress the rootfs blob with zstd, which mostly pays off for trees with lots of files.
+    /// The blob gets its own media type, so the puzzlefs releases predating compressed metadata
+    /// report a missing rootfs instead of misreading it.
+    pub compress_metadata: bool,
+    /// Split the inodes into metadata shards of this many inodes, blobs of their own which the
+    /// rootfs refers to by ino range. Readers only fetch and verify the shards of the inodes they
+    /// look up, so mounting huge images, especially lazily pulled ones, doesn't wait for all of
+    /// their metadata.
+    pub metadata_shard_size: Option<u32>,
+    /// Make name lookups ignore case and Unicode normalization, for images served to Windows
+    /// oriented workloads (e.g. Wine) or re-exported over Samba. The names are stored as they
+    /// are; the build fails if a directory has names which only differ by case. Deltas of a
+    /// casefolded image are casefolded too.
+    pub casefold: bool,
+    /// Store the chunk lists several files have once, in a chunk table their inodes refer to,
+    /// rather than in each of their inodes, which shrinks the metadata of images holding copies
+    /// of large files under several paths.
+    pub chunk_table: bool,
+    /// Store the fs-verity digest of every regular file in its inode, computed along with the
+    /// file digest, so that extracted files can get fs-verity enabled and checked against the
+    /// image, and single files can be verified without going through the blobs they share
+    /// chunks with.
+    pub file_verity: bool,
+    pub inode_numbering: InodeNumbering,
+    /// Fail the build on file names which aren't valid UTF-8, for images meant for tools and
+    /// platforms which can't represent them. Names are raw bytes otherwise, like on Linux; see
+    /// [`crate::format::escape_name`] for printing them.
+    pub utf8_names: bool,
+}
+
+// The options which affect the image contents, as recorded in the manifest.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct RecordedOptions {
+    pub(crate) chunking: Chunking,
+    // None for images built before the compression was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) compression: Option<RecordedCompression>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) compression_level: Option<i32>,
+    // the digest of the blob holding the zstd dictionary
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) compression_dictionary: Option<Digest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_blob_size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) pack_files_below: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) inline_files_below: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) encryption: Option<KeyReference>,
+}
+
+// How the chunks are compressed, as recorded in the manifest, which unlike an
+// Option<CompressionAlgorithm> tells uncompressed chunks apart from older images not saying.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RecordedCompression {
+    None,
+    Zstd,
+    Lz4,
+    Xz,
+}
+
+impl From<Option<CompressionAlgorithm>> for RecordedCompression {
+    fn from(algorithm: Option<CompressionAlgorithm>) -> Self {
+        match algorithm {
+            None => RecordedCompression::None,
+            Some(CompressionAlgorithm::Zstd) => RecordedCompression::Zstd,
+            Some(CompressionAlgorithm::Lz4) => RecordedCompression::Lz4,
+            Some(CompressionAlgorithm::Xz) => RecordedCompression::Xz,
+        }
+    }
+}
+
+impl From<RecordedCompression> for Option<CompressionAlgorithm> {
+    fn from(compression: RecordedCompression) -> Self {
+        match compression {
+            RecordedCompression::None => None,
+            RecordedCompression::Zstd => Some(CompressionAlgorithm::Zstd),
+            RecordedCompression::Lz4 => Some(CompressionAlgorithm::Lz4),
+            RecordedCompression::Xz => Some(CompressionAlgorithm::Xz),
+        }
+    }
+}
+
+// The digest of the blob the dictionary is stored in.
+pub(crate) fn dictionary_digest(dictionary: &ZstdDictionary) -> Digest {
+    Digest::new(&Sha256::digest(dictionary.as_bytes()).into())
+}
+
+impl BuildOptions {
+    // `compression` is the algorithm the chunks are compressed with.
+    pub(crate) fn validate(&self, compression: Option<CompressionAlgorithm>) -> Result<()> {
+        self.chunking.check()?;
+        if self.compression_dictionary.is_some() && compression != Some(CompressionAlgorithm::Zstd)
+        {
+            return Err(WireFormatError::InvalidBuildOptions(
+                "compression dictionaries only work with zstd".to_string(),
+                Backtrace::capture(),
+            ));
+        }
+        if let Some(max_blob_size) = self.max_blob_size {
+            let max_chunk_size = match self.chunking {
+                Chunking::Fastcdc(params) => params.max_size(),
+                Chunking::Fixed { block_size, .. } => block_size,
+            };
+            if max_blob_size < max_chunk_size.into() {
+                return Err(WireFormatError::InvalidBuildOptions(
+                    format!(
+                        "maximum blob size {max_blob_size} is smaller than the maximum chunk size {max_chunk_size}"
+                    ),
+                    Backtrace::capture(),
+                ));
+            }
+        }
+
+        if let Some(threshold) = self.pack_files_below {
+            if threshold == 0 || u64::from(threshold) > self.pack_blob_size() {
+                return Err(WireFormatError::InvalidBuildOptions(
+                    format!(
+                        "packing threshold {threshold} not in range [1, {}]",
+                        self.pack_blob_size()
+                    ),
+                    Backtrace::capture(),
+                ));
+            }
+        }
+
+        if let Some(threshold) = self.inline_files_below {
+            if threshold == 0 || threshold > MAX_INLINE_SIZE {
+                return Err(WireFormatError::InvalidBuildOptions(
+                    format!("inlining threshold {threshold} not in range [1, {MAX_INLINE_SIZE}]"),
+                    Backtrace::capture(),
+                ));
+            }
+        }
+
+        if self.metadata_shard_size == Some(0) {
+            return Err(WireFormatError::InvalidBuildOptions(
+                "metadata shards must hold at least one inode".to_string(),
+                Backtrace::capture(),
+            ));
+        }
+
+        let reserved = self
+            .annotations
+            .keys()
+            .chain(self.rootfs_annotations.keys())
+            .find(|key| key.starts_with(PUZZLEFS_ANNOTATION_PREFIX));
+        if let Some(key) = reserved {
+            return Err(WireFormatError::InvalidBuildOptions(
+                format!("annotation {key} is reserved for puzzlefs"),
+                Backtrace::capture(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn pack_blob_size(&self) -> u64 {
+        self.max_blob_size.unwrap_or(DEFAULT_PACK_BLOB_SIZE)
+    }
+
+    pub(crate) fn chunk_parameters(
+        &self,
+        compression: Option<CompressionAlgorithm>,
+    ) -> ChunkParameters {
+        ChunkParameters {
+            chunking: self.chunking,
+            compression,
+            compression_level: self.compression_level,
+            compression_dictionary: self.compression_dictionary.as_ref().map(ZstdDictionary::id),
+        }
+    }
+
+    // The options that affect the image contents, serialized; `compression` is the algorithm
+    // the chunks are compressed with.
+    pub(crate) fn recorded_options(
+        &self,
+        compression: Option<CompressionAlgorithm>,
+    ) -> Result<String> {
+        let recorded = RecordedOptions {
+            chunking: self.chunking,
+            compression: Some(compression.into()),
+            compression_level: self.compression_level,
+            compression_dictionary: self.compression_dictionary.as_ref().map(dictionary_digest),
+            max_blob_size: self.max_blob_size,
+            pack_files_below: self.pack_files_below,
+            inline_files_below: self.inline_files_below,
+            encryption: self.encryption.as_ref().map(Encryption::key_reference),
+        };
+        Ok(serde_json::to_string(&recorded)?)
+    }
+
+    // Record the options that affect the image contents in the manifest annotations, so it's
+    // possible to tell how an image was built, along with the custom annotations. The manifest is
+    // written as canonical JSON, so the order of the annotations doesn't change its digest.
+    pub(crate) fn annotate(
+        &self,
+        image_manifest: &mut ImageManifest,
+        compression: Option<CompressionAlgorithm>,
+    ) -> Result<()> {
+        let mut annotations = image_manifest.annotations().clone().unwrap_or_default();
+        annotations.extend(self.annotations.clone());
+        annotations.insert(
+            BUILD_OPTIONS_ANNOTATION.to_string(),
+            self.recorded_options(compression)?,
+        );
+        image_manifest.set_annotations(Some(annotations));
+        Ok(())
+    }
+
+    // Adds the custom rootfs annotations to its descriptor, next to its fs-verity digest.
+    pub(crate) fn annotate_rootfs(&self, rootfs: &mut Descriptor) {
+        if self.rootfs_annotations.is_empty() {
+            return;
+        }
+        let mut annotations = rootfs.annotations().clone().unwrap_or_default();
+        annotations.extend(self.rootfs_annotations.clone());
+        rootfs.set_annotations(Some(annotations));
+    }
+}
@@ -0,0 +1,44 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// Counters describing how far along a build is. Byte counts are for file data only; metadata
+/// is written at the very end of the build.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BuildProgress {
+    /// Regular files found while walking the rootfs.
+    pub files_scanned: u64,
+    /// File data to chunk, known once the rootfs walk is over.
+    pub total_bytes: u64,
+    /// File data split into chunks so far.
+    pub bytes_chunked: u64,
+    /// Size of the chunks after compression.
+    pub bytes_compressed: u64,
+    pub blobs_written: u64,
+    /// Chunks identical to one written earlier in the same build.
+    pub dedup_hits: u64,
+}
+
+/// A callback receiving [`BuildProgress`] updates, e.g. to draw a progress bar or log the build
+/// in CI. It is called after each directory of the rootfs walk and after each batch of chunks.
+#[derive(Clone, Default)]
+pub struct ProgressReporter(Option<Arc<dyn Fn(&BuildProgress) + Send + Sync>>);
+
+impl ProgressReporter {
+    pub fn new(callback: impl Fn(&BuildProgress) + Send + Sync + 'static) -> Self {
+        ProgressReporter(Some(Arc::new(callback)))
+    }
+
+    pub(crate) fn report(&self, progress: &BuildProgress) {
+        if let Some(callback) = &self.0 {
+            callback(progress)
+        }
+    }
+}
+
+impl fmt::Debug for ProgressReporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ProgressReporter")
+            .field(&self.0.as_ref().map(|_| "callback"))
+            .finish()
+    }
+}
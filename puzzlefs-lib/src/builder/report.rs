@@ -0,0 +1,39 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+/// Space usage of one top level entry of the rootfs; files directly in `/` are accounted under
+/// `/` itself.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct DirectoryReport {
+    pub files: u64,
+    pub logical_bytes: u64,
+    /// The share of the newly stored chunk data taken by this directory's files. Chunks may span
+    /// files, so their size is split proportionally to how much of each file they hold.
+    pub stored_bytes: u64,
+}
+
+/// What a build stored and how much it deduplicated, e.g. to track image sizes in CI.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct BuildReport {
+    /// The apparent size of the regular files, holes included.
+    pub logical_bytes: u64,
+    /// Size of the chunk blobs this build added to the image, after compression.
+    pub stored_bytes: u64,
+    /// Size of the metadata blob.
+    pub metadata_bytes: u64,
+    pub chunks: u64,
+    /// Chunks identical to one stored earlier in the same build.
+    pub deduplicated_chunks: u64,
+    /// Chunks which were already in the image, e.g. from the base layer of a delta.
+    pub reused_chunks: u64,
+    /// Size of the reused chunks, before compression.
+    pub reused_bytes: u64,
+    /// By top level directory, e.g. `/usr`, in escaped form (see
+    /// [`crate::format::escape_name`]); the files directly in the rootfs are under `/`.
+    pub directories: BTreeMap<String, DirectoryReport>,
+    /// How the chunk parameters of a delta differ from the ones of its base image, which keeps
+    /// their chunks from being shared; see [`super::ChunkParameters::differences`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub parameter_differences: Vec<String>,
+}
@@ -0,0 +1,215 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs::{self, Permissions};
+use std::io::{self, Read};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+use super::{build_initial_rootfs_with_options, BuildOptions, BuildReport};
+use crate::compression::Compression;
+use crate::convert::StagedRootfs;
+use crate::format::Result;
+use crate::oci::{Descriptor, Image};
+
+const DEFAULT_DIR_MODE: u32 = 0o755;
+
+/// Builds an image from entries added one by one, for tools which generate the rootfs contents
+/// themselves (package managers, initramfs generators) instead of having them in a directory.
+///
+/// The entries are streamed into a temporary staging directory as they are added, so file data
+/// isn't held in memory. Everything is owned by root, unless `BuildOptions::ids` sets an owner,
+/// and nothing from the staging filesystem (e.g. its SELinux labels) makes it into the image.
+pub struct TreeBuilder {
+    staged: StagedRootfs,
+    dir_modes: HashMap<PathBuf, u32>,
+}
+
+impl TreeBuilder {
+    pub fn new() -> Result<Self> {
+        Ok(TreeBuilder {
+            staged: StagedRootfs::empty()?,
+            dir_modes: HashMap::new(),
+        })
+    }
+
+    /// Adds a directory, or changes the mode of an existing one. Missing parent directories are
+    /// added with mode 0755, for this and the other entry types.
+    pub fn add_dir(&mut self, path: impl AsRef<Path>, mode: u32) -> Result<()> {
+        let staged = self.staged_path(path.as_ref())?;
+        self.create_parents(&staged)?;
+        match fs::symlink_metadata(&staged) {
+            Ok(md) if md.is_dir() => {}
+            Ok(_) => {
+                fs::remove_file(&staged)?;
+                fs::create_dir(&staged)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir(&staged)?,
+            Err(e) => return Err(e.into()),
+        }
+        self.dir_modes.insert(staged, mode);
+        Ok(())
+    }
+
+    /// Adds a regular file with the contents of `reader`, replacing any non-directory entry
+    /// already at `path`.
+    pub fn add_file(
+        &mut self,
+        path: impl AsRef<Path>,
+        mut reader: impl Read,
+        mode: u32,
+    ) -> Result<()> {
+        let staged = self.staged_path(path.as_ref())?;
+        self.create_parents(&staged)?;
+        remove_non_dir(&staged)?;
+        let mut file = fs::File::create(&staged)?;
+        io::copy(&mut reader, &mut file)?;
+        file.set_permissions(Permissions::from_mode(mode))?;
+        Ok(())
+    }
+
+    /// Adds a symlink to `target`, which is stored as is, replacing any non-directory entry
+    /// already at `path`.
+    pub fn add_symlink(&mut self, path: impl AsRef<Path>, target: impl AsRef<Path>) -> Result<()> {
+        let staged = self.staged_path(path.as_ref())?;
+        self.create_parents(&staged)?;
+        remove_non_dir(&staged)?;
+        symlink(target, &staged)?;
+        Ok(())
+    }
+
+    /// Builds the tree into `oci` as `tag`, like [`build_initial_rootfs_with_options`].
+    pub fn build<C: Compression + Any>(
+        mut self,
+        oci: &Image,
+        tag: &str,
+        options: &BuildOptions,
+    ) -> Result<(Descriptor, BuildReport)> {
+        self.staged
+            .set_dir_modes(std::mem::take(&mut self.dir_modes))?;
+
+        let mut options = options.clone();
+        // the staged files belong to whoever runs the build, which says nothing about the image
+        options.ids.chown.get_or_insert((0, 0));
+        // the entries don't have any xattrs, all of them come from the staging filesystem
+        options.xattrs.drop.push(String::new());
+        build_initial_rootfs_with_options::<C>(&self.staged.path(), oci, tag, &options)
+    }
+
+    // Image paths are relative to the root of the tree (a leading / is fine) and can't go above
+    // it.
+    fn staged_path(&self, path: &Path) -> Result<PathBuf> {
+        let mut staged = self.staged.path();
+        for component in path.components() {
+            match component {
+                Component::RootDir | Component::CurDir => {}
+                Component::Normal(name) => staged.push(name),
+                Component::ParentDir | Component::Prefix(_) => {
+                    return Err(invalid_path(path).into());
+                }
+            }
+        }
+        Ok(staged)
+    }
+
+    // Symlinks added earlier are never followed, so entries can't end up outside the tree.
+    fn create_parents(&self, staged: &Path) -> Result<()> {
+        let root = self.staged.path();
+        let Some(parent) = staged.parent().filter(|_| staged != root) else {
+            return Ok(());
+        };
+
+        let mut dir = root.clone();
+        // .unwrap() is fine, staged_path() only returns paths below the root
+        for component in parent.strip_prefix(&root).unwrap().components() {
+            dir.push(component);
+            match fs::symlink_metadata(&dir) {
+                Ok(md) if md.is_dir() => {}
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotADirectory,
+                        format!(
+                            "{} is not a directory",
+                            dir.strip_prefix(&root).unwrap().display()
+                        ),
+                    )
+                    .into());
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    fs::create_dir(&dir)?;
+                    fs::set_permissions(&dir, Permissions::from_mode(DEFAULT_DIR_MODE))?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid_path(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid image path {}", path.display()),
+    )
+}
+
+fn remove_non_dir(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(md) if md.is_dir() => Err(io::Error::new(
+            io::ErrorKind::IsADirectory,
+            format!("{} is a directory", path.display()),
+        )),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Zstd;
+    use crate::format::InodeMode;
+    use crate::reader::{PuzzleFS, WalkPuzzleFS};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_tree_builder() -> anyhow::Result<()> {
+        let mut tree = TreeBuilder::new()?;
+        tree.add_dir("/etc", 0o555)?;
+        tree.add_file("/etc/hostname", &b"puzzlefs"[..], 0o600)?;
+        tree.add_file("usr/lib/os-release", &b"ID=puzzlefs"[..], 0o644)?;
+        tree.add_symlink("/etc/os-release", "../usr/lib/os-release")?;
+        assert!(tree.add_file("/etc/../../escape", &b""[..], 0o644).is_err());
+        assert!(tree.add_file("/etc/os-release/x", &b""[..], 0o644).is_err());
+
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        tree.build::<Zstd>(&image, "test", &BuildOptions::default())?;
+
+        let mut pfs = PuzzleFS::open(image, "test", None)?;
+        let mut entries = HashMap::new();
+        for de in WalkPuzzleFS::walk(&mut pfs)? {
+            let mut de = de?;
+            assert_eq!((de.inode.uid, de.inode.gid), (0, 0));
+            let contents = match de.inode.mode {
+                InodeMode::File { .. } => {
+                    let mut contents = Vec::new();
+                    de.open()?.read_to_end(&mut contents)?;
+                    contents
+                }
+                InodeMode::Lnk => de.inode.additional.take().unwrap().symlink_target.unwrap(),
+                _ => Vec::new(),
+            };
+            entries.insert(de.path, (de.inode.permissions, contents));
+        }
+
+        let entry = |path: &str| entries[Path::new(path)].clone();
+        assert_eq!(entry("/etc"), (0o555, Vec::new()));
+        assert_eq!(entry("/usr"), (0o755, Vec::new()));
+        assert_eq!(entry("/etc/hostname"), (0o600, b"puzzlefs".to_vec()));
+        assert_eq!(entry("/usr/lib/os-release").1, b"ID=puzzlefs");
+        assert_eq!(entry("/etc/os-release").1, b"../usr/lib/os-release");
+        assert_eq!(entries.len(), 7);
+        Ok(())
+    }
+}
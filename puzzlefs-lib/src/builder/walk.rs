@@ -0,0 +1,291 @@
+// A parallel walk of the rootfs. The directories are read, and their entries stat()ed, scanned
+// for xattrs and holes, and hashed, by a pool of threads, while the builder consumes them one at a
+// time in the same order a sequential walk would: depth first, ordered by file name. Only a
+// bounded number of directories is read ahead, so memory use doesn't grow with the size of the
+// tree.
+//
+// With one_file_system, the walk doesn't go into other filesystems mounted below the rootfs. The
+// entries on them are left out, except for mountpoints kept as empty directories.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::options::{BuildOptions, PathFilter};
+use super::{data_extents, file_digest};
+use crate::format::{DigestAlgorithm, FileDigest, InodeAdditional, SHA256_BLOCK_SIZE};
+
+// how many directories each walker thread may read ahead of the builder
+const DIRS_PER_THREAD: usize = 4;
+
+pub(crate) struct ScannedDir {
+    pub(crate) path: PathBuf,
+    pub(crate) md: fs::Metadata,
+    // the entries which aren't excluded, ordered by name
+    pub(crate) entries: Vec<ScannedEntry>,
+}
+
+pub(crate) struct ScannedEntry {
+    pub(crate) name: OsString,
+    pub(crate) path: PathBuf,
+    pub(crate) md: fs::Metadata,
+    pub(crate) additional: Option<InodeAdditional>,
+    // the data regions of regular files, see data_extents()
+    pub(crate) extents: Vec<Range<u64>>,
+    pub(crate) digest: Option<FileDigest>,
+    // the fs-verity digest of regular files, with per-file verity
+    pub(crate) verity: Option<[u8; SHA256_BLOCK_SIZE]>,
+}
+
+struct WalkConfig {
+    rootfs: PathBuf,
+    filter: PathFilter,
+    extent_alignment: u64,
+    digest_algorithm: DigestAlgorithm,
+    file_verity: bool,
+    // the device of the rootfs, with one_file_system
+    root_dev: Option<u64>,
+    keep_mountpoints: bool,
+}
+
+type Job = (PathBuf, SyncSender<io::Result<ScannedDir>>);
+
+pub(crate) struct ParallelWalker {
+    // dropping it stops the walker threads
+    jobs: SyncSender<Job>,
+    root_dev: Option<u64>,
+    // the directories left to visit, the next one last
+    pending: Vec<PathBuf>,
+    // the directories being read ahead
+    in_flight: HashMap<PathBuf, Receiver<io::Result<ScannedDir>>>,
+    lookahead: usize,
+}
+
+impl ParallelWalker {
+    // The walker threads are plain threads rather than rayon tasks: the builder blocks waiting for
+    // them, and it may itself run on the only thread of a rayon pool.
+    pub(crate) fn new(
+        rootfs: &Path,
+        options: &BuildOptions,
+        extent_alignment: u64,
+        threads: usize,
+    ) -> io::Result<Self> {
+        let threads = threads.max(1);
+        let lookahead = threads * DIRS_PER_THREAD;
+        let root_dev = options
+            .one_file_system
+            .then(|| fs::symlink_metadata(rootfs).map(|md| md.dev()))
+            .transpose()?;
+        let config = Arc::new(WalkConfig {
+            rootfs: rootfs.to_path_buf(),
+            filter: options.filter.clone(),
+            extent_alignment,
+            digest_algorithm: options.digest_algorithm,
+            file_verity: options.file_verity,
+            root_dev,
+            keep_mountpoints: options.keep_mountpoints,
+        });
+
+        let (jobs, job_rx) = sync_channel::<Job>(lookahead);
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        for _ in 0..threads {
+            let job_rx = Arc::clone(&job_rx);
+            let config = Arc::clone(&config);
+            thread::Builder::new()
+                .name("puzzlefs-walker".to_string())
+                .spawn(move || loop {
+                    let job = job_rx.lock().unwrap().recv();
+                    let Ok((path, result)) = job else {
+                        break;
+                    };
+                    // the builder may have given up on the walk already
+                    let _ = result.send(scan_dir(&config, path));
+                })?;
+        }
+
+        Ok(ParallelWalker {
+            jobs,
+            root_dev,
+            pending: vec![rootfs.to_path_buf()],
+            in_flight: HashMap::new(),
+            lookahead,
+        })
+    }
+
+    fn request(&mut self, path: &Path) -> io::Result<()> {
+        if self.in_flight.contains_key(path) {
+            return Ok(());
+        }
+        let (tx, rx) = sync_channel(1);
+        self.jobs
+            .send((path.to_path_buf(), tx))
+            .map_err(|_| io::Error::other("the walker threads exited"))?;
+        self.in_flight.insert(path.to_path_buf(), rx);
+        Ok(())
+    }
+
+    fn walk_dir(&mut self, path: PathBuf) -> io::Result<ScannedDir> {
+        self.request(&path)?;
+        // read ahead the directories coming up next while waiting for this one
+        let upcoming = self
+            .pending
+            .iter()
+            .rev()
+            .filter(|p| !self.in_flight.contains_key(*p))
+            .take(self.lookahead.saturating_sub(self.in_flight.len()))
+            .cloned()
+            .collect::<Vec<_>>();
+        for p in upcoming {
+            self.request(&p)?;
+        }
+
+        // .unwrap() is fine, it was requested above
+        let dir = self.in_flight.remove(&path).unwrap().recv().map_err(|_| {
+            io::Error::other(format!("walker thread died reading {}", path.display()))
+        })??;
+
+        self.pending.extend(
+            dir.entries
+                .iter()
+                .rev()
+                .filter(|e| e.md.is_dir() && self.root_dev.is_none_or(|dev| e.md.dev() == dev))
+                .map(|e| e.path.clone()),
+        );
+        Ok(dir)
+    }
+}
+
+impl Iterator for ParallelWalker {
+    type Item = io::Result<ScannedDir>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let path = self.pending.pop()?;
+        let dir = self.walk_dir(path);
+        if dir.is_err() {
+            // there's no way to tell which directories would have been below it
+            self.pending.clear();
+        }
+        Some(dir)
+    }
+}
+
+fn scan_dir(config: &WalkConfig, path: PathBuf) -> io::Result<ScannedDir> {
+    let md = fs::symlink_metadata(&path)?;
+    let mut entries = Vec::new();
+    for e in fs::read_dir(&path)? {
+        let e = e?;
+        let md = e.metadata()?;
+        let path = e.path();
+        // .unwrap() is fine, everything we walk is below rootfs
+        let relative = path.strip_prefix(&config.rootfs).unwrap();
+        if config.filter.excludes(relative, md.is_dir()) {
+            continue;
+        }
+        if config.root_dev.is_some_and(|dev| md.dev() != dev)
+            && !(md.is_dir() && config.keep_mountpoints)
+        {
+            continue;
+        }
+
+        let additional = InodeAdditional::new(&path, &md)?;
+        let (extents, digest, verity) = if md.is_file() {
+            let extents = data_extents(&path, &md, config.extent_alignment)?;
+            let (digest, verity) = file_digest(
+                &path,
+                &md,
+                &extents,
+                config.digest_algorithm,
+                config.file_verity,
+            )?;
+            (extents, Some(digest), verity)
+        } else {
+            (Vec::new(), None, None)
+        };
+        entries.push(ScannedEntry {
+            name: e.file_name(),
+            path,
+            md,
+            additional,
+            extents,
+            digest,
+            verity,
+        });
+    }
+    // sort the entries so we have reproducible puzzlefs images
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(ScannedDir { path, md, entries })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use walkdir::WalkDir;
+
+    #[test]
+    fn test_parallel_walk_order() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        for i in 0..20 {
+            for j in 0..5 {
+                let sub = dir.path().join(format!("d{i}")).join(format!("e{j}"));
+                fs::create_dir_all(&sub)?;
+                fs::write(sub.join("file"), b"data")?;
+            }
+        }
+        fs::create_dir_all(dir.path().join("skipped/below"))?;
+        std::os::unix::fs::symlink("d1", dir.path().join("link"))?;
+
+        let options = BuildOptions {
+            filter: PathFilter::new::<&str>(&[], &["skipped"])?,
+            ..Default::default()
+        };
+        let walked = ParallelWalker::new(dir.path(), &options, 1, 3)?
+            .map(|d| d.map(|d| d.path))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let expected = WalkDir::new(dir.path())
+            .follow_links(false)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .into_iter()
+            .filter_entry(|de| de.file_type().is_dir() && de.file_name() != "skipped")
+            .map(|de| de.map(|de| de.into_path()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        assert_eq!(walked, expected);
+        assert_eq!(walked.len(), 1 + 20 + 20 * 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_other_filesystems() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        fs::create_dir(dir.path().join("mnt"))?;
+        fs::write(dir.path().join("file"), b"data")?;
+
+        // pretend the rootfs is on some other device, so all its entries are on a different one
+        let mut config = WalkConfig {
+            rootfs: dir.path().to_path_buf(),
+            filter: PathFilter::default(),
+            extent_alignment: 1,
+            digest_algorithm: DigestAlgorithm::default(),
+            root_dev: None,
+            keep_mountpoints: false,
+        };
+        let names = |config: &WalkConfig| -> io::Result<Vec<OsString>> {
+            let scanned = scan_dir(config, dir.path().to_path_buf())?;
+            Ok(scanned.entries.into_iter().map(|e| e.name).collect())
+        };
+        assert_eq!(names(&config)?, ["file", "mnt"]);
+        config.root_dev = Some(u64::MAX);
+        assert!(names(&config)?.is_empty());
+        config.keep_mountpoints = true;
+        assert_eq!(names(&config)?, ["mnt"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+// Canonical forms of the xattrs the kernel interprets itself: file capabilities and POSIX ACLs.
+// Their values are binary structures whose exact bytes depend on how the source tree was
+// created, so they're normalized to keep images reproducible and portable.
+
+use super::options::{map_id, IdMap};
+use crate::format::{is_acl_xattr, Acl, Xattr};
+
+const CAPABILITY_XATTR: &[u8] = b"security.capability";
+
+const VFS_CAP_REVISION_MASK: u32 = 0xff00_0000;
+const VFS_CAP_REVISION_2: u32 = 0x0200_0000;
+const VFS_CAP_REVISION_3: u32 = 0x0300_0000;
+const VFS_CAP_V2_SIZE: usize = 20;
+const VFS_CAP_V3_SIZE: usize = 24;
+
+pub(crate) fn canonicalize(xattrs: &mut [Xattr], ids: &IdMap) {
+    for xattr in xattrs {
+        if xattr.key == CAPABILITY_XATTR {
+            canonicalize_capability(&mut xattr.val);
+        } else if is_acl_xattr(&xattr.key) {
+            canonicalize_acl(&mut xattr.val, ids);
+        }
+    }
+}
+
+// Capabilities read inside a user namespace (e.g. by a rootless build) are revision 3, which
+// carries the host uid owning the namespace. That uid means nothing on the machines running the
+// image, so they're stored as plain revision 2 capabilities, which apply in any namespace.
+fn canonicalize_capability(val: &mut Vec<u8>) {
+    if val.len() != VFS_CAP_V3_SIZE {
+        return;
+    }
+
+    let magic = u32::from_le_bytes(val[..4].try_into().unwrap());
+    if magic & VFS_CAP_REVISION_MASK != VFS_CAP_REVISION_3 {
+        return;
+    }
+
+    let magic = (magic & !VFS_CAP_REVISION_MASK) | VFS_CAP_REVISION_2;
+    val[..4].copy_from_slice(&magic.to_le_bytes());
+    val.truncate(VFS_CAP_V2_SIZE);
+}
+
+// The kernel requires ACL entries sorted by tag and id, which Acl takes care of. The ids of the
+// named user and group entries are translated like the file owners; a chown doesn't apply to
+// them, since it would give all the named entries the same id.
+fn canonicalize_acl(val: &mut Vec<u8>, ids: &IdMap) {
+    // the ACLs were checked when reading them from the rootfs
+    let Ok(acl) = Acl::from_xattr(val) else {
+        return;
+    };
+    if let Ok(acl) = acl.map_ids(|uid| map_id(&ids.uids, uid), |gid| map_id(&ids.gids, gid)) {
+        *val = acl.to_xattr();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::IdMapping;
+
+    const ACL_XATTR_VERSION: u32 = 2;
+    const ACL_USER: u16 = 0x02;
+
+    fn acl(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut acl = ACL_XATTR_VERSION.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            acl.extend_from_slice(&tag.to_le_bytes());
+            acl.extend_from_slice(&perm.to_le_bytes());
+            acl.extend_from_slice(&id.to_le_bytes());
+        }
+        acl
+    }
+
+    #[test]
+    fn test_canonical_xattrs() {
+        let mut cap = (VFS_CAP_REVISION_3 | 1).to_le_bytes().to_vec();
+        cap.extend_from_slice(&[0x00, 0x04, 0, 0, 0, 0, 0, 0]);
+        cap.extend_from_slice(&[0; 8]);
+        cap.extend_from_slice(&1000u32.to_le_bytes());
+
+        const UNDEFINED: u32 = u32::MAX;
+        let mut xattrs = vec![
+            Xattr {
+                key: CAPABILITY_XATTR.to_vec(),
+                val: cap,
+            },
+            Xattr {
+                key: crate::format::ACL_ACCESS_XATTR.to_vec(),
+                val: acl(&[
+                    (0x01, 6, UNDEFINED),
+                    (ACL_USER, 4, 1001),
+                    (ACL_USER, 6, 1000),
+                    (0x04, 4, UNDEFINED),
+                    (0x20, 4, UNDEFINED),
+                    (0x10, 6, UNDEFINED),
+                ]),
+            },
+        ];
+        let ids = IdMap {
+            uids: vec![IdMapping {
+                image: 0,
+                host: 1000,
+                count: 1000,
+            }],
+            ..Default::default()
+        };
+        canonicalize(&mut xattrs, &ids);
+
+        let mut expected_cap = (VFS_CAP_REVISION_2 | 1).to_le_bytes().to_vec();
+        expected_cap.extend_from_slice(&[0x00, 0x04, 0, 0, 0, 0, 0, 0]);
+        expected_cap.extend_from_slice(&[0; 8]);
+        assert_eq!(xattrs[0].val, expected_cap);
+        assert_eq!(
+            xattrs[1].val,
+            acl(&[
+                (0x01, 6, UNDEFINED),
+                (ACL_USER, 6, 0),
+                (ACL_USER, 4, 1),
+                (0x04, 4, UNDEFINED),
+                (0x10, 6, UNDEFINED),
+                (0x20, 4, UNDEFINED),
+            ])
+        );
+    }
+}
@@ -3,3 +3,8 @@
 pub const MIN_CHUNK_SIZE: u32 = 16 * 1024;
 pub const AVG_CHUNK_SIZE: u32 = 64 * 1024;
 pub const MAX_CHUNK_SIZE: u32 = 256 * 1024;
+
+// OCI layer whiteouts: a `.wh.<name>` entry deletes `<name>` from the lower layers, an opaque
+// whiteout hides all the lower layers' entries in its directory
+pub(crate) const WHITEOUT_PREFIX: &[u8] = b".wh.";
+pub(crate) const OPAQUE_WHITEOUT: &[u8] = b".wh..wh..opq";
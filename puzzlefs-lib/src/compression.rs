@@ -1,5 +1,5 @@
 use std::io;
-use std::io::Seek;
+use std::io::{Read, Seek};
 
 mod noop;
 pub use noop::Noop;
@@ -7,6 +7,14 @@ pub use noop::Noop;
 mod zstd_seekable_wrapper;
 pub use zstd_seekable_wrapper::*;
 
+mod lz4;
+pub use lz4::Lz4;
+
+mod xz;
+pub use xz::Xz;
+
+use crate::format::CompressionAlgorithm;
+
 pub trait Compressor: io::Write {
     // https://users.rust-lang.org/t/how-to-move-self-when-using-dyn-trait/50123
     fn end(self: Box<Self>) -> io::Result<()>;
@@ -17,13 +25,101 @@ pub trait Decompressor: io::Read + io::Seek {
 }
 
 pub trait Compression {
+    // recorded in the blob references, so the reader knows how to decompress the blob; None
+    // means the data is stored as is
+    const ALGORITHM: Option<CompressionAlgorithm>;
+
     fn compress<'a, W: std::io::Write + 'a>(dest: W) -> io::Result<Box<dyn Compressor + 'a>>;
+    // Compressors without a notion of compression level ignore it.
+    fn compress_with_level<'a, W: std::io::Write + 'a>(
+        dest: W,
+        _level: i32,
+    ) -> io::Result<Box<dyn Compressor + 'a>> {
+        Self::compress(dest)
+    }
+    // Only zstd has dictionaries; `None` is the default compression level.
+    fn compress_with_dictionary<'a, W: std::io::Write + 'a>(
+        _dest: W,
+        _level: Option<i32>,
+        _dictionary: &ZstdDictionary,
+    ) -> io::Result<Box<dyn Compressor + 'a>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "compression dictionaries only work with zstd",
+        ))
+    }
     fn decompress<'a, R: std::io::Read + Seek + 'a>(
         source: R,
     ) -> io::Result<Box<dyn Decompressor + 'a>>;
     fn append_extension(media_type: &str) -> String;
 }
 
+// Formats without a seek table have to be decompressed in one go before seeking; chunks are small
+// enough for that to be fine.
+struct BufferedDecompressor(io::Cursor<Vec<u8>>);
+
+impl BufferedDecompressor {
+    fn new(mut decoder: impl io::Read) -> io::Result<Self> {
+        let mut data = Vec::new();
+        decoder.read_to_end(&mut data)?;
+        Ok(BufferedDecompressor(io::Cursor::new(data)))
+    }
+}
+
+impl io::Read for BufferedDecompressor {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        self.0.read(out)
+    }
+}
+
+impl io::Seek for BufferedDecompressor {
+    fn seek(&mut self, offset: io::SeekFrom) -> io::Result<u64> {
+        self.0.seek(offset)
+    }
+}
+
+impl Decompressor for BufferedDecompressor {
+    fn get_uncompressed_length(&mut self) -> io::Result<u64> {
+        Ok(self.0.get_ref().len() as u64)
+    }
+}
+
+// how many bytes of a buffer looks_incompressible() looks at, in evenly spread windows
+const ENTROPY_SAMPLE_WINDOWS: usize = 4;
+const ENTROPY_WINDOW_SIZE: usize = 4096;
+// in bits per byte; compressed or encrypted data is close to 8
+const INCOMPRESSIBLE_ENTROPY: f64 = 7.95;
+
+/// Guesses whether compressing `buf` is a waste of time, from the Shannon entropy of a sample of
+/// its bytes. It's cheap compared to a trial compression, but it only catches data with close to
+/// uniformly distributed bytes, e.g. content that is already compressed.
+pub fn looks_incompressible(buf: &[u8]) -> bool {
+    let sample_size = ENTROPY_SAMPLE_WINDOWS * ENTROPY_WINDOW_SIZE;
+    let mut histogram = [0_u64; 256];
+    if buf.len() <= sample_size {
+        buf.iter().for_each(|b| histogram[*b as usize] += 1);
+    } else {
+        let stride = (buf.len() - ENTROPY_WINDOW_SIZE) / (ENTROPY_SAMPLE_WINDOWS - 1);
+        for window in 0..ENTROPY_SAMPLE_WINDOWS {
+            let start = window * stride;
+            buf[start..start + ENTROPY_WINDOW_SIZE]
+                .iter()
+                .for_each(|b| histogram[*b as usize] += 1);
+        }
+    }
+
+    let total = histogram.iter().sum::<u64>() as f64;
+    let entropy = histogram
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / total;
+            -p * p.log2()
+        })
+        .sum::<f64>();
+    entropy >= INCOMPRESSIBLE_ENTROPY
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
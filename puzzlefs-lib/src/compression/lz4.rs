@@ -0,0 +1,68 @@
+use std::io;
+use std::io::{Read, Seek, Write};
+
+use lz4_flex::frame::{FrameDecoder, FrameEncoder};
+
+use crate::compression::{BufferedDecompressor, Compression, Compressor, Decompressor};
+use crate::format::CompressionAlgorithm;
+
+pub struct Lz4Compressor<W: Write> {
+    encoder: FrameEncoder<W>,
+}
+
+impl<W: Write> Compressor for Lz4Compressor<W> {
+    fn end(self: Box<Self>) -> io::Result<()> {
+        self.encoder.finish()?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Lz4Compressor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+/// LZ4 frames: worse ratios than zstd, but very cheap to decompress, for images where mount time
+/// read latency matters most. Blobs are decompressed whole on access.
+pub struct Lz4 {}
+
+impl Compression for Lz4 {
+    const ALGORITHM: Option<CompressionAlgorithm> = Some(CompressionAlgorithm::Lz4);
+
+    fn compress<'a, W: Write + 'a>(dest: W) -> io::Result<Box<dyn Compressor + 'a>> {
+        Ok(Box::new(Lz4Compressor {
+            encoder: FrameEncoder::new(dest),
+        }))
+    }
+
+    fn decompress<'a, R: Read + Seek + 'a>(source: R) -> io::Result<Box<dyn Decompressor + 'a>> {
+        Ok(Box::new(BufferedDecompressor::new(FrameDecoder::new(
+            source,
+        ))?))
+    }
+
+    fn append_extension(media_type: &str) -> String {
+        format!("{media_type}+lz4")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::tests::{compress_decompress, compression_is_seekable};
+
+    #[test]
+    fn test_lz4_roundtrip() -> anyhow::Result<()> {
+        compress_decompress::<Lz4>()
+    }
+
+    #[test]
+    fn test_lz4_seekable() -> anyhow::Result<()> {
+        compression_is_seekable::<Lz4>()
+    }
+}
@@ -1,4 +1,5 @@
 use crate::compression::{Compression, Compressor, Decompressor};
+use crate::format::CompressionAlgorithm;
 use std::io;
 use std::io::{Read, Seek, Write};
 
@@ -47,6 +48,8 @@ impl<R: Read + Seek> Decompressor for NoopDecompressor<R> {
 }
 
 impl Compression for Noop {
+    const ALGORITHM: Option<CompressionAlgorithm> = None;
+
     fn compress<'a, W: std::io::Write + 'a>(dest: W) -> io::Result<Box<dyn Compressor + 'a>> {
         Ok(Box::new(NoopCompressor {
             encoder: Box::new(dest),
@@ -0,0 +1,80 @@
+use std::io;
+use std::io::{Read, Seek, Write};
+
+use liblzma::read::XzDecoder;
+use liblzma::write::XzEncoder;
+
+use crate::compression::{BufferedDecompressor, Compression, Compressor, Decompressor};
+use crate::format::CompressionAlgorithm;
+
+// the xz default; 9 is noticeably slower for little gain on chunk sized inputs
+const COMPRESSION_LEVEL: u32 = 6;
+const MAX_COMPRESSION_LEVEL: u32 = 9;
+
+pub struct XzCompressor<W: Write> {
+    encoder: XzEncoder<W>,
+}
+
+impl<W: Write> Compressor for XzCompressor<W> {
+    fn end(self: Box<Self>) -> io::Result<()> {
+        self.encoder.finish()?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for XzCompressor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+/// xz (LZMA2): the best compression ratios and the slowest reads, for archival images. Blobs are
+/// decompressed whole on access.
+pub struct Xz {}
+
+impl Compression for Xz {
+    const ALGORITHM: Option<CompressionAlgorithm> = Some(CompressionAlgorithm::Xz);
+
+    fn compress<'a, W: Write + 'a>(dest: W) -> io::Result<Box<dyn Compressor + 'a>> {
+        Self::compress_with_level(dest, COMPRESSION_LEVEL as i32)
+    }
+
+    // levels above the xz maximum are clamped, so the zstd style range still works
+    fn compress_with_level<'a, W: Write + 'a>(
+        dest: W,
+        level: i32,
+    ) -> io::Result<Box<dyn Compressor + 'a>> {
+        let level = level.clamp(0, MAX_COMPRESSION_LEVEL as i32) as u32;
+        Ok(Box::new(XzCompressor {
+            encoder: XzEncoder::new(dest, level),
+        }))
+    }
+
+    fn decompress<'a, R: Read + Seek + 'a>(source: R) -> io::Result<Box<dyn Decompressor + 'a>> {
+        Ok(Box::new(BufferedDecompressor::new(XzDecoder::new(source))?))
+    }
+
+    fn append_extension(media_type: &str) -> String {
+        format!("{media_type}+xz")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::tests::{compress_decompress, compression_is_seekable};
+
+    #[test]
+    fn test_xz_roundtrip() -> anyhow::Result<()> {
+        compress_decompress::<Xz>()
+    }
+
+    #[test]
+    fn test_xz_seekable() -> anyhow::Result<()> {
+        compression_is_seekable::<Xz>()
+    }
+}
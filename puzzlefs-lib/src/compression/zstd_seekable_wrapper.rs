@@ -1,9 +1,14 @@
+use std::fmt;
 use std::io;
 use std::io::{Read, Seek, Write};
+use std::num::NonZeroU32;
+use std::sync::Arc;
 
-use zeekstd::{Decoder, EncodeOptions, Encoder, FrameSizePolicy};
+use zeekstd::{DecodeOptions, Decoder, EncodeOptions, Encoder, FrameSizePolicy};
+use zstd::zstd_safe::{self, CCtx, DCtx};
 
 use crate::compression::{Compression, Compressor, Decompressor};
+use crate::format::CompressionAlgorithm;
 
 // We compress files in 4KB frames; it's not clear what the ideal size for this is, but each frame
 // is compressed independently so the bigger they are the more compression savings we get. However,
@@ -15,11 +20,58 @@ use crate::compression::{Compression, Compressor, Decompressor};
 // also possible that we want different frame sizes for metadata blobs and file content.
 const FRAME_SIZE: u32 = 4096;
 const COMPRESSION_LEVEL: i32 = 3;
+// the largest a frame header gets, see ZSTD_FRAMEHEADERSIZE_MAX
+const FRAME_HEADER_SIZE_MAX: usize = 18;
 
 fn err_to_io<E: 'static + std::error::Error + Send + Sync>(e: E) -> io::Error {
     io::Error::other(e)
 }
 
+fn zstd_err_to_io(code: zstd_safe::ErrorCode) -> io::Error {
+    io::Error::other(zstd_safe::get_error_name(code))
+}
+
+/// A pre-trained zstd dictionary, e.g. one made with `zstd --train` from samples of the files
+/// going into the image, which makes small chunks of similar data compress a lot better. Data
+/// compressed with a dictionary can only be decompressed with it; the frames record its id so
+/// the reader knows which one to use.
+#[derive(Clone)]
+pub struct ZstdDictionary {
+    data: Arc<[u8]>,
+    id: NonZeroU32,
+}
+
+impl ZstdDictionary {
+    /// Fails for data which isn't a zstd dictionary: raw content dictionaries have no id, so
+    /// they can't be told apart once the data is compressed.
+    pub fn new(data: Vec<u8>) -> io::Result<Self> {
+        let id = zstd_safe::get_dict_id_from_dict(&data).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "not a trained zstd dictionary")
+        })?;
+        Ok(ZstdDictionary {
+            data: data.into(),
+            id,
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id.get()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl fmt::Debug for ZstdDictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZstdDictionary")
+            .field("id", &self.id)
+            .field("len", &self.data.len())
+            .finish()
+    }
+}
+
 pub struct ZstdCompressor<'a, W> {
     encoder: Encoder<'a, W>,
 }
@@ -66,21 +118,40 @@ impl<R: Seek + Read> Read for ZstdDecompressor<'_, R> {
 
 pub struct Zstd {}
 
-impl Compression for Zstd {
-    fn compress<'a, W: Write + 'a>(dest: W) -> io::Result<Box<dyn Compressor + 'a>> {
-        // a "pretty high" compression level, since decompression should be nearly the same no
-        // matter what compression level. Maybe we should turn this to 22 or whatever the max is...
-        let encoder = EncodeOptions::new()
-            .compression_level(COMPRESSION_LEVEL)
+impl Zstd {
+    fn encoder<'a, W: Write + 'a>(
+        dest: W,
+        level: i32,
+        dictionary: Option<&ZstdDictionary>,
+    ) -> io::Result<Box<dyn Compressor + 'a>> {
+        let mut cctx = CCtx::create();
+        if let Some(dictionary) = dictionary {
+            // the dictionary is copied into the context, and kept from one frame to the next
+            cctx.load_dictionary(dictionary.as_bytes())
+                .map_err(zstd_err_to_io)?;
+        }
+        let encoder = EncodeOptions::with_cctx(cctx)
+            .compression_level(level)
             .frame_size_policy(FrameSizePolicy::Uncompressed(FRAME_SIZE))
             .into_encoder(dest)
             .map_err(err_to_io)?;
         Ok(Box::new(ZstdCompressor { encoder }))
     }
 
-    fn decompress<'a, R: Read + Seek + 'a>(source: R) -> io::Result<Box<dyn Decompressor + 'a>> {
-        // let decoder = Seekable::init(Box::new(source)).map_err(err_to_io)?;
-        let decoder = Decoder::new(source).map_err(err_to_io)?;
+    /// Like [`Compression::decompress`], for data which may have been compressed with
+    /// `dictionary`.
+    pub fn decompress_with_dictionary<'a, R: Read + Seek + 'a>(
+        source: R,
+        dictionary: Option<&ZstdDictionary>,
+    ) -> io::Result<Box<dyn Decompressor + 'a>> {
+        let mut dctx = DCtx::create();
+        if let Some(dictionary) = dictionary {
+            dctx.load_dictionary(dictionary.as_bytes())
+                .map_err(zstd_err_to_io)?;
+        }
+        let decoder = DecodeOptions::with_dctx(source, dctx)
+            .into_decoder()
+            .map_err(err_to_io)?;
         let seek_table = decoder.seek_table();
 
         // zstd-seekable doesn't like it when we pass a buffer past the end of the uncompressed
@@ -93,6 +164,47 @@ impl Compression for Zstd {
         }))
     }
 
+    /// The id of the dictionary the data in `source` was compressed with, as recorded in the
+    /// header of its first frame; None if it was compressed without one.
+    pub fn dictionary_id<R: Read + Seek>(source: &mut R) -> io::Result<Option<u32>> {
+        let mut header = Vec::with_capacity(FRAME_HEADER_SIZE_MAX);
+        source
+            .by_ref()
+            .take(FRAME_HEADER_SIZE_MAX as u64)
+            .read_to_end(&mut header)?;
+        source.rewind()?;
+        Ok(zstd_safe::get_dict_id_from_frame(&header).map(NonZeroU32::get))
+    }
+}
+
+impl Compression for Zstd {
+    const ALGORITHM: Option<CompressionAlgorithm> = Some(CompressionAlgorithm::Zstd);
+
+    fn compress<'a, W: Write + 'a>(dest: W) -> io::Result<Box<dyn Compressor + 'a>> {
+        // a "pretty high" compression level, since decompression should be nearly the same no
+        // matter what compression level. Maybe we should turn this to 22 or whatever the max is...
+        Self::compress_with_level(dest, COMPRESSION_LEVEL)
+    }
+
+    fn compress_with_level<'a, W: Write + 'a>(
+        dest: W,
+        level: i32,
+    ) -> io::Result<Box<dyn Compressor + 'a>> {
+        Self::encoder(dest, level, None)
+    }
+
+    fn compress_with_dictionary<'a, W: Write + 'a>(
+        dest: W,
+        level: Option<i32>,
+        dictionary: &ZstdDictionary,
+    ) -> io::Result<Box<dyn Compressor + 'a>> {
+        Self::encoder(dest, level.unwrap_or(COMPRESSION_LEVEL), Some(dictionary))
+    }
+
+    fn decompress<'a, R: Read + Seek + 'a>(source: R) -> io::Result<Box<dyn Decompressor + 'a>> {
+        Self::decompress_with_dictionary(source, None)
+    }
+
     fn append_extension(media_type: &str) -> String {
         format!("{media_type}+zstd")
     }
@@ -101,7 +213,7 @@ impl Compression for Zstd {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::compression::tests::{compress_decompress, compression_is_seekable};
+    use crate::compression::tests::{compress_decompress, compression_is_seekable, TRUTH};
 
     #[test]
     fn test_ztsd_roundtrip() -> anyhow::Result<()> {
@@ -112,4 +224,52 @@ mod tests {
     fn test_zstd_seekable() -> anyhow::Result<()> {
         compression_is_seekable::<Zstd>()
     }
+
+    #[test]
+    fn test_zstd_compression_level() -> anyhow::Result<()> {
+        let data = "meshuggah rocks, ".repeat(4096);
+        let compress = |level| -> anyhow::Result<Vec<u8>> {
+            let mut compressed = Vec::new();
+            let mut compressor = Zstd::compress_with_level(&mut compressed, level)?;
+            compressor.write_all(data.as_bytes())?;
+            compressor.end()?;
+            Ok(compressed)
+        };
+
+        let fast = compress(1)?;
+        let small = compress(19)?;
+        assert!(small.len() <= fast.len());
+
+        let mut decompressed = String::new();
+        Zstd::decompress(io::Cursor::new(small))?.read_to_string(&mut decompressed)?;
+        assert_eq!(decompressed, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_zstd_dictionary() -> anyhow::Result<()> {
+        let samples = (0..1000)
+            .map(|i| format!("{TRUTH} {i} times, louder than {} bands; ", i * 7).repeat(4))
+            .collect::<Vec<_>>();
+        let dictionary = ZstdDictionary::new(zstd::dict::from_samples(&samples, 4096)?)?;
+        // raw content can't be told apart from the data compressed with it
+        assert!(ZstdDictionary::new(TRUTH.as_bytes().to_vec()).is_err());
+
+        let mut compressed = Vec::new();
+        let mut compressor = Zstd::compress_with_dictionary(&mut compressed, None, &dictionary)?;
+        compressor.write_all(samples[0].as_bytes())?;
+        compressor.end()?;
+        let mut compressed = io::Cursor::new(compressed);
+        assert_eq!(Zstd::dictionary_id(&mut compressed)?, Some(dictionary.id()));
+
+        let mut decompressed = String::new();
+        assert!(Zstd::decompress(compressed.clone())?
+            .read_to_string(&mut decompressed)
+            .is_err());
+        decompressed.clear();
+        Zstd::decompress_with_dictionary(compressed, Some(&dictionary))?
+            .read_to_string(&mut decompressed)?;
+        assert_eq!(decompressed, samples[0]);
+        Ok(())
+    }
 }
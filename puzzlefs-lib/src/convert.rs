@@ -0,0 +1,490 @@
+use crate::builder::{build_initial_rootfs_with_options, BuildOptions};
+use crate::common::{OPAQUE_WHITEOUT, WHITEOUT_PREFIX};
+use crate::compression::Compression;
+use crate::oci::{Descriptor, Image};
+use flate2::read::GzDecoder;
+use log::info;
+use nix::unistd::Uid;
+use ocidir::oci_spec::image::{ImageConfiguration, MediaType};
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, Permissions};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+mod docker_archive;
+pub use docker_archive::convert_docker_archive;
+
+const DOCKER_LAYER_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+const DOCKER_LAYER_TAR: &str = "application/vnd.docker.image.rootfs.diff.tar";
+
+fn runs_privileged() -> bool {
+    Uid::effective().is_root()
+}
+
+// Resolve a path from a layer tarball inside the staging directory. Unlike the extractor, we
+// need the full path even if it doesn't exist yet, and we never follow symlinks in its parents,
+// because that could point a whiteout at something outside the staging directory.
+fn layer_path(root: &Path, path: &Path) -> anyhow::Result<PathBuf> {
+    let mut buf = root.to_path_buf();
+    let mut components = path.components().peekable();
+
+    while let Some(component) = components.next() {
+        match component {
+            Component::Prefix(..) => bail!("Path prefix not understood"), // "Does not occur on Unix."
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => bail!("layer path escapes rootfs: {:#?}", path),
+            Component::Normal(c) => {
+                buf.push(c);
+                if components.peek().is_some() {
+                    if let Ok(md) = fs::symlink_metadata(&buf) {
+                        if md.file_type().is_symlink() {
+                            bail!("symlink prefixes are not allowed: {:#?}", buf)
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(buf)
+}
+
+fn remove_path(path: &Path) -> io::Result<()> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(md) if md.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+// Removes `path`, along with the modes of the directories which were at or below it.
+fn remove_layer_path(path: &Path, dir_modes: &mut HashMap<PathBuf, u32>) -> io::Result<()> {
+    dir_modes.retain(|dir, _| !dir.starts_with(path));
+    remove_path(path)
+}
+
+// An opaque whiteout hides everything the lower layers put in the directory, but not what the
+// current layer adds to it, regardless of the order of the tar entries.
+fn apply_opaque_whiteout(
+    dir: &Path,
+    layer_paths: &HashSet<PathBuf>,
+    dir_modes: &mut HashMap<PathBuf, u32>,
+) -> io::Result<()> {
+    let ents = WalkDir::new(dir)
+        .contents_first(true)
+        .follow_links(false)
+        .min_depth(1)
+        .into_iter()
+        .collect::<Result<Vec<walkdir::DirEntry>, walkdir::Error>>()?;
+
+    for ent in ents {
+        if layer_paths.contains(ent.path()) {
+            continue;
+        }
+
+        if ent.file_type().is_dir() {
+            // directories still holding entries from the current layer stay
+            match fs::remove_dir(ent.path()) {
+                Ok(()) => {
+                    dir_modes.remove(ent.path());
+                }
+                Err(e) if e.raw_os_error() != Some(nix::errno::Errno::ENOTEMPTY as i32) => {
+                    return Err(e)
+                }
+                Err(_) => {}
+            }
+        } else {
+            fs::remove_file(ent.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn layer_reader(image: &Image, desc: &Descriptor) -> anyhow::Result<Box<dyn Read>> {
+    let blob = image.0.read_blob(desc)?;
+    let reader: Box<dyn Read> = match desc.media_type() {
+        MediaType::ImageLayer | MediaType::ImageLayerNonDistributable => Box::new(blob),
+        MediaType::ImageLayerGzip | MediaType::ImageLayerNonDistributableGzip => {
+            Box::new(GzDecoder::new(blob))
+        }
+        MediaType::ImageLayerZstd | MediaType::ImageLayerNonDistributableZstd => {
+            Box::new(zstd::Decoder::new(blob)?)
+        }
+        MediaType::Other(media_type) if media_type == DOCKER_LAYER_TAR => Box::new(blob),
+        MediaType::Other(media_type) if media_type == DOCKER_LAYER_GZIP => {
+            Box::new(GzDecoder::new(blob))
+        }
+        media_type => bail!("unsupported layer media type {media_type}"),
+    };
+    Ok(reader)
+}
+
+// Unpack one layer tarball on top of the previous ones, applying whiteouts. Directory modes are
+// collected in dir_modes and only applied once all the layers are unpacked, so read-only
+// directories don't prevent later layers from writing into them.
+fn apply_layer(
+    layer: impl Read,
+    root: &Path,
+    dir_modes: &mut HashMap<PathBuf, u32>,
+) -> anyhow::Result<()> {
+    let mut archive = tar::Archive::new(layer);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(runs_privileged());
+    archive.set_unpack_xattrs(runs_privileged());
+    archive.set_overwrite(true);
+
+    let mut layer_paths = HashSet::new();
+    let mut opaque_dirs = Vec::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+        let path = layer_path(root, &entry_path)?;
+        let name = path.file_name().map(OsStr::as_bytes).unwrap_or_default();
+
+        if name == OPAQUE_WHITEOUT {
+            if let Some(parent) = path.parent() {
+                opaque_dirs.push(parent.to_path_buf());
+            }
+            continue;
+        }
+
+        if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            let hidden = path.with_file_name(OsStr::from_bytes(hidden));
+            info!("whiteout {:#?}", hidden);
+            remove_layer_path(&hidden, dir_modes)?;
+            continue;
+        }
+
+        let is_dir = entry.header().entry_type().is_dir();
+        if path != root {
+            // a directory replacing a file or the other way around; tar only overwrites files
+            if let Ok(md) = fs::symlink_metadata(&path) {
+                if md.is_dir() != is_dir {
+                    remove_layer_path(&path, dir_modes)?;
+                }
+            }
+        }
+
+        if !entry.unpack_in(root)? {
+            bail!("layer path escapes rootfs: {:#?}", entry_path);
+        }
+
+        if is_dir {
+            let mode = entry.header().mode()?;
+            dir_modes.insert(path.clone(), mode);
+            fs::set_permissions(&path, Permissions::from_mode(mode | 0o700))?;
+        }
+        layer_paths.insert(path);
+    }
+
+    for dir in opaque_dirs {
+        apply_opaque_whiteout(&dir, &layer_paths, dir_modes)?;
+    }
+
+    Ok(())
+}
+
+/// A rootfs unpacked from layer tarballs into a temporary directory, which is removed when this
+/// is dropped.
+pub struct StagedRootfs {
+    staging: TempDir,
+    // deepest directories first
+    dir_modes: Vec<(PathBuf, u32)>,
+}
+
+impl StagedRootfs {
+    /// Applies the layers in order, with the usual whiteout semantics.
+    pub fn unpack<R: Read>(
+        layers: impl IntoIterator<Item = anyhow::Result<R>>,
+    ) -> anyhow::Result<Self> {
+        let mut staged = Self::empty()?;
+        let rootfs = staged.path();
+        let mut dir_modes = HashMap::new();
+        for layer in layers {
+            apply_layer(layer?, &rootfs, &mut dir_modes)?;
+        }
+
+        staged.set_dir_modes(dir_modes)?;
+        Ok(staged)
+    }
+
+    pub(crate) fn empty() -> io::Result<Self> {
+        let staging = tempfile::tempdir()?;
+        let rootfs = staging.path().join("rootfs");
+        fs::create_dir(&rootfs)?;
+        fs::set_permissions(&rootfs, Permissions::from_mode(0o755))?;
+        Ok(StagedRootfs {
+            staging,
+            dir_modes: Vec::new(),
+        })
+    }
+
+    // Directory modes are applied once everything is in place, since a read-only directory
+    // couldn't be filled anymore.
+    pub(crate) fn set_dir_modes(&mut self, dir_modes: HashMap<PathBuf, u32>) -> io::Result<()> {
+        // apply the deepest directories first, so restricted parents don't get in the way
+        let mut dir_modes = dir_modes.into_iter().collect::<Vec<_>>();
+        dir_modes.sort_by_key(|(path, _)| std::cmp::Reverse(path.components().count()));
+        self.dir_modes = dir_modes;
+        for (path, mode) in &self.dir_modes {
+            // set_permissions follows symlinks, which may have taken the place of a directory
+            match fs::symlink_metadata(path) {
+                Ok(md) if md.is_dir() => {}
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => continue,
+            }
+            fs::set_permissions(path, Permissions::from_mode(*mode))?;
+        }
+        Ok(())
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.staging.path().join("rootfs")
+    }
+}
+
+impl Drop for StagedRootfs {
+    fn drop(&mut self) {
+        // make sure read-only directories don't stop the staging directory from being removed
+        for (path, _) in self.dir_modes.iter().rev() {
+            let _ = fs::set_permissions(path, Permissions::from_mode(0o700));
+        }
+    }
+}
+
+// The runtime configuration of a converted image is the one of its source, but its layers are
+// gone: the puzzlefs image has no diff ids, and no history entry made a layer of it.
+fn converted_config(mut config: ImageConfiguration) -> ImageConfiguration {
+    config.rootfs_mut().diff_ids_mut().clear();
+    for entry in config.history_mut() {
+        entry.set_empty_layer(Some(true));
+    }
+    config
+}
+
+fn convert_with_config<C: Compression + Any>(
+    staged: &StagedRootfs,
+    puzzlefs_image: &Image,
+    tag: &str,
+    config: Option<ImageConfiguration>,
+) -> anyhow::Result<Descriptor> {
+    let options = BuildOptions {
+        config: config.map(converted_config),
+        ..Default::default()
+    };
+    let (descriptor, _) =
+        build_initial_rootfs_with_options::<C>(&staged.path(), puzzlefs_image, tag, &options)?;
+    Ok(descriptor)
+}
+
+/// Converts the OCI image `tag` from `oci_dir` into a puzzlefs image with the same tag. The
+/// layers are applied in order with the usual whiteout semantics and the resulting rootfs is
+/// built into `puzzlefs_image`.
+pub fn convert_oci_image<C: Compression + Any>(
+    oci_dir: &Path,
+    tag: &str,
+    puzzlefs_image: &Image,
+) -> anyhow::Result<Descriptor> {
+    // multi-platform images are converted for the platform the puzzlefs image is built for
+    let image = Image::open(oci_dir)?.with_platform(puzzlefs_image.platform());
+    let manifest = image.find_manifest(tag)?;
+
+    let staged = StagedRootfs::unpack(manifest.layers().iter().map(|desc| {
+        info!("applying layer {}", desc.digest());
+        layer_reader(&image, desc)
+    }))?;
+
+    let config = image.config(tag)?;
+    convert_with_config::<C>(&staged, puzzlefs_image, tag, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Noop;
+    use crate::export::export_oci_image;
+    use crate::reader::{PuzzleFS, WalkPuzzleFS};
+    use ocidir::oci_spec::image::{ConfigBuilder, Platform};
+    use tempfile::tempdir;
+
+    fn append_file(builder: &mut tar::Builder<impl io::Write>, path: &str, contents: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_size(contents.len() as u64);
+        builder.append_data(&mut header, path, contents).unwrap();
+    }
+
+    fn append_dir(builder: &mut tar::Builder<impl io::Write>, path: &str, mode: u32) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(mode);
+        header.set_size(0);
+        builder.append_data(&mut header, path, io::empty()).unwrap();
+    }
+
+    #[test]
+    fn test_staged_tar_stream() {
+        let mut builder = tar::Builder::new(Vec::new());
+        append_dir(&mut builder, "etc", 0o555);
+        append_file(&mut builder, "etc/hostname", b"puzzlefs");
+        let tar = builder.into_inner().unwrap();
+
+        let staged = StagedRootfs::unpack([Ok(&tar[..])]).unwrap();
+        let rootfs = staged.path();
+        assert_eq!(fs::read(rootfs.join("etc/hostname")).unwrap(), b"puzzlefs");
+        let mode = fs::metadata(rootfs.join("etc"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o555);
+
+        // the read-only directory doesn't stop the staging directory from being removed
+        drop(staged);
+        assert!(!rootfs.exists());
+    }
+
+    #[test]
+    fn test_directory_replaced_by_file() {
+        let mut lower = tar::Builder::new(Vec::new());
+        append_dir(&mut lower, "d", 0o555);
+        append_dir(&mut lower, "d/sub", 0o500);
+        append_dir(&mut lower, "gone", 0o555);
+        let lower = lower.into_inner().unwrap();
+        let mut upper = tar::Builder::new(Vec::new());
+        append_file(&mut upper, "d", b"now a file");
+        append_file(&mut upper, ".wh.gone", b"");
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        upper.append_link(&mut header, "gone", "/etc").unwrap();
+        let upper = upper.into_inner().unwrap();
+
+        let staged = StagedRootfs::unpack([Ok(&lower[..]), Ok(&upper[..])]).unwrap();
+        let rootfs = staged.path();
+        let md = fs::symlink_metadata(rootfs.join("d")).unwrap();
+        assert!(md.is_file());
+        // the file keeps its own mode, not the one of the directory it replaced
+        assert_eq!(md.permissions().mode() & 0o777, 0o644);
+        assert!(fs::symlink_metadata(rootfs.join("gone"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+    }
+
+    #[test]
+    fn test_convert_with_whiteouts() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+
+        let mut manifest = image.0.new_empty_manifest().unwrap().build().unwrap();
+        let mut config = ImageConfiguration::default();
+        config.set_config(Some(
+            ConfigBuilder::default()
+                .cmd(vec!["sh".to_string()])
+                .labels([("version".to_string(), "1".to_string())])
+                .build()
+                .unwrap(),
+        ));
+
+        let mut lower = image.0.create_layer(None).unwrap();
+        append_dir(&mut lower, "etc", 0o555);
+        append_file(&mut lower, "etc/passwd", b"root");
+        append_file(&mut lower, "etc/shadow", b"secret");
+        append_dir(&mut lower, "opt", 0o755);
+        append_file(&mut lower, "opt/old", b"old");
+        append_file(&mut lower, "replaced", b"file");
+        let lower = lower.into_inner().unwrap().complete().unwrap();
+        image
+            .0
+            .push_layer(&mut manifest, &mut config, lower, "lower", None);
+
+        let mut upper = image.0.create_layer(None).unwrap();
+        append_file(&mut upper, "etc/.wh.shadow", b"");
+        append_file(&mut upper, "etc/hostname", b"puzzlefs");
+        append_file(&mut upper, "opt/new", b"new");
+        append_file(&mut upper, "opt/.wh..wh..opq", b"");
+        append_dir(&mut upper, "replaced", 0o755);
+        let upper = upper.into_inner().unwrap().complete().unwrap();
+        image
+            .0
+            .push_layer(&mut manifest, &mut config, upper, "upper", None);
+
+        let config = image.0.write_config(config).unwrap();
+        manifest.set_config(config);
+        image
+            .0
+            .insert_manifest(manifest, Some("test"), Platform::default())
+            .unwrap();
+
+        let puzzlefs_dir = dir.path().join("puzzlefs");
+        let puzzlefs_image = Image::new(&puzzlefs_dir).unwrap();
+        convert_oci_image::<Noop>(&oci_dir, "test", &puzzlefs_image).unwrap();
+
+        // the runtime configuration survives the conversion and the export
+        let config = puzzlefs_image.config("test").unwrap().unwrap();
+        assert_eq!(
+            config.config().as_ref().unwrap().cmd(),
+            &Some(vec!["sh".to_string()])
+        );
+        assert!(config.rootfs().diff_ids().is_empty());
+        let exported_dir = dir.path().join("exported");
+        export_oci_image(Image::open(&puzzlefs_dir).unwrap(), "test", &exported_dir).unwrap();
+        let exported = Image::open(&exported_dir)
+            .unwrap()
+            .config("test")
+            .unwrap()
+            .unwrap();
+        assert_eq!(exported.config(), config.config());
+        assert_eq!(exported.rootfs().diff_ids().len(), 1);
+
+        let mut pfs = PuzzleFS::open(puzzlefs_image, "test", None).unwrap();
+        let mut walker = WalkPuzzleFS::walk(&mut pfs).unwrap();
+        let mut paths = Vec::new();
+        walker
+            .try_for_each(|de| -> anyhow::Result<()> {
+                let de = de?;
+                paths.push(de.path.clone());
+                if de.path == Path::new("/etc") {
+                    assert_eq!(de.inode.permissions, 0o555);
+                }
+                if de.path == Path::new("/etc/hostname") {
+                    let mut contents = Vec::new();
+                    de.open()?.read_to_end(&mut contents)?;
+                    assert_eq!(contents, b"puzzlefs");
+                }
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(
+            paths,
+            [
+                "/",
+                "/etc",
+                "/opt",
+                "/replaced",
+                "/etc/hostname",
+                "/etc/passwd",
+                "/opt/new"
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,259 @@
+// The tarballs written by `docker save`: a manifest.json listing, for each image, its config, its
+// tags and its layer tarballs in order, all of them being files of the archive. Layers shared by
+// several images may be symlinks to one another, and the layers are plain tars, but gzip and zstd
+// ones are accepted too.
+
+use flate2::read::GzDecoder;
+use log::{info, warn};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Seek};
+use std::path::{Component, Path, PathBuf};
+
+use super::{convert_with_config, StagedRootfs};
+use crate::compression::Compression;
+use crate::oci::{Descriptor, Image};
+
+// how many symlinks are followed to find a file of the archive
+const MAX_LINKS: usize = 16;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ArchiveManifest {
+    config: String,
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+enum ArchiveEntry {
+    File { offset: u64, size: u64 },
+    Link(PathBuf),
+}
+
+struct DockerArchive {
+    path: PathBuf,
+    entries: HashMap<PathBuf, ArchiveEntry>,
+}
+
+// Paths of the archive without `.` components, so the manifest and the links can be matched to
+// the entries.
+fn normalize(path: &Path) -> anyhow::Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir if normalized.pop() => {}
+            _ => bail!("invalid path {} in docker archive", path.display()),
+        }
+    }
+    Ok(normalized)
+}
+
+impl DockerArchive {
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut archive = tar::Archive::new(fs::File::open(path)?);
+        let mut entries = HashMap::new();
+        for entry in archive.entries_with_seek()? {
+            let entry = entry?;
+            let name = normalize(&entry.path()?)?;
+            let archive_entry = match entry.header().entry_type() {
+                tar::EntryType::Symlink => {
+                    let target = entry.link_name()?.unwrap_or_default();
+                    let parent = name.parent().unwrap_or(Path::new(""));
+                    ArchiveEntry::Link(normalize(&parent.join(target))?)
+                }
+                tar::EntryType::Link => {
+                    let target = entry.link_name()?.unwrap_or_default();
+                    ArchiveEntry::Link(normalize(&target)?)
+                }
+                _ => ArchiveEntry::File {
+                    offset: entry.raw_file_position(),
+                    size: entry.size(),
+                },
+            };
+            entries.insert(name, archive_entry);
+        }
+        Ok(DockerArchive {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    // Each file gets a handle of its own, so it doesn't depend on where the others are read.
+    fn open_file(&self, name: &str) -> anyhow::Result<io::Take<fs::File>> {
+        let mut name = normalize(Path::new(name))?;
+        for _ in 0..MAX_LINKS {
+            match self.entries.get(&name) {
+                Some(ArchiveEntry::File { offset, size }) => {
+                    let mut file = fs::File::open(&self.path)?;
+                    file.seek(io::SeekFrom::Start(*offset))?;
+                    return Ok(file.take(*size));
+                }
+                Some(ArchiveEntry::Link(target)) => name = target.clone(),
+                None => bail!("{} is missing from the docker archive", name.display()),
+            }
+        }
+        bail!("too many levels of links for {name:?} in the docker archive")
+    }
+
+    fn read_file(&self, name: &str) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.open_file(name)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn open_layer(&self, name: &str) -> anyhow::Result<Box<dyn Read>> {
+        let mut reader = BufReader::new(self.open_file(name)?);
+        let magic = reader.fill_buf()?;
+        let gzip = magic.starts_with(&[0x1f, 0x8b]);
+        let zstd = magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]);
+        let layer: Box<dyn Read> = if gzip {
+            Box::new(GzDecoder::new(reader))
+        } else if zstd {
+            Box::new(zstd::Decoder::with_buffer(reader)?)
+        } else {
+            Box::new(reader)
+        };
+        Ok(layer)
+    }
+}
+
+// Tags like `alpine:3.19` can't be used as they are, since `:` separates the layout from the
+// tag on the command line.
+fn puzzlefs_tag(repo_tag: &str) -> String {
+    repo_tag.replace([':', '/'], "_")
+}
+
+/// Converts the images of a `docker save` archive into puzzlefs images in `puzzlefs_image`,
+/// returning the tags and the rootfs descriptors of the images. The layers of each image are
+/// applied in order with the usual whiteout semantics, like [`super::convert_oci_image`] does.
+/// An image tagged `repo:tag` gets the puzzlefs tag `repo_tag` (with the `/`s of the repository
+/// replaced by `_`s too), and untagged images are tagged with the short id docker shows for them,
+/// the first 12 hex digits of their config digest.
+pub fn convert_docker_archive<C: Compression + Any>(
+    archive: &Path,
+    puzzlefs_image: &Image,
+) -> anyhow::Result<Vec<(String, Descriptor)>> {
+    let archive = DockerArchive::open(archive)?;
+    let manifests: Vec<ArchiveManifest> =
+        serde_json::from_slice(&archive.read_file("manifest.json")?)?;
+
+    let mut converted = Vec::new();
+    for manifest in manifests {
+        let mut tags = manifest
+            .repo_tags
+            .unwrap_or_default()
+            .iter()
+            .map(|repo_tag| puzzlefs_tag(repo_tag))
+            .collect::<Vec<_>>();
+        let config = archive.read_file(&manifest.config)?;
+        if tags.is_empty() {
+            tags.push(hex::encode(Sha256::digest(&config))[..12].to_string());
+        }
+        let config = match serde_json::from_slice(&config) {
+            Ok(config) => Some(config),
+            Err(e) => {
+                warn!("ignoring the config of {}: {e}", tags[0]);
+                None
+            }
+        };
+
+        let staged = StagedRootfs::unpack(manifest.layers.iter().map(|layer| {
+            info!("applying layer {layer}");
+            archive.open_layer(layer)
+        }))?;
+        let descriptor = convert_with_config::<C>(&staged, puzzlefs_image, &tags[0], config)?;
+        for tag in &tags[1..] {
+            puzzlefs_image.retag(&tags[0], tag)?;
+        }
+        converted.extend(tags.into_iter().map(|tag| (tag, descriptor.clone())));
+    }
+    Ok(converted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Noop;
+    use crate::reader::PuzzleFS;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    fn append(builder: &mut tar::Builder<impl Write>, path: &str, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+
+    fn layer(hostname: &[u8]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, "etc/hostname", hostname);
+        builder.into_inner().unwrap()
+    }
+
+    fn hostname(oci_dir: &Path, tag: &str) -> anyhow::Result<Vec<u8>> {
+        let pfs = PuzzleFS::open(Image::open(oci_dir)?, tag, None)?;
+        let inode = pfs
+            .lookup(Path::new("/etc/hostname"))?
+            .ok_or_else(|| anyhow!("no hostname"))?;
+        let mut data = Vec::new();
+        crate::reader::FileReader::new(&pfs.oci, &inode)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn test_convert_docker_archive() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let archive_path = dir.path().join("images.tar");
+        let mut archive = tar::Builder::new(fs::File::create(&archive_path)?);
+        let manifest = br#"[
+            {"Config": "one.json", "RepoTags": ["library/one:latest"], "Layers": ["a/layer.tar"]},
+            {"Config": "two.json", "RepoTags": null, "Layers": ["b/layer.tar", "c/layer.tar"]}
+        ]"#;
+        append(&mut archive, "manifest.json", manifest);
+        let config = br#"{"architecture": "amd64", "os": "linux",
+            "config": {"Env": ["PATH=/bin"], "Cmd": ["sh"]},
+            "rootfs": {"type": "layers", "diff_ids": ["sha256:00"]}}"#;
+        append(&mut archive, "one.json", config);
+        append(&mut archive, "two.json", b"{\"two\": true}");
+        append(&mut archive, "a/layer.tar", &layer(b"one"));
+        // layers shared between images are linked
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        archive.append_link(&mut header, "b/layer.tar", "../a/layer.tar")?;
+        let mut gzipped = GzEncoder::new(Vec::new(), Default::default());
+        gzipped.write_all(&layer(b"two"))?;
+        append(&mut archive, "c/layer.tar", &gzipped.finish()?);
+        archive.finish()?;
+
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir)?;
+        let converted = convert_docker_archive::<Noop>(&archive_path, &image)?;
+        let untagged = hex::encode(Sha256::digest(b"{\"two\": true}"))[..12].to_string();
+        assert_eq!(
+            converted.iter().map(|(tag, _)| tag).collect::<Vec<_>>(),
+            ["library_one_latest", untagged.as_str()]
+        );
+        assert_eq!(hostname(&oci_dir, "library_one_latest")?, b"one");
+        assert_eq!(hostname(&oci_dir, &untagged)?, b"two");
+
+        // the runtime configuration is kept, an invalid one is dropped
+        let config = image.config("library_one_latest")?.unwrap();
+        let runtime = config.config().as_ref().unwrap();
+        assert_eq!(runtime.cmd().as_deref(), Some(&["sh".to_string()][..]));
+        assert_eq!(
+            runtime.env().as_deref(),
+            Some(&["PATH=/bin".to_string()][..])
+        );
+        assert!(config.rootfs().diff_ids().is_empty());
+        assert!(image.config(&untagged)?.is_none());
+        Ok(())
+    }
+}
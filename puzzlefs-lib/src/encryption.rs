@@ -0,0 +1,250 @@
+use std::backtrace::Backtrace;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::XChaCha20Poly1305;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::format::{Result, WireFormatError};
+
+pub const KEY_SIZE: usize = 32;
+
+/// The environment variable [`EncryptionKey::from_env`] reads the key from, hex encoded.
+pub const KEY_ENV: &str = "PUZZLEFS_KEY";
+
+const NONCE_DOMAIN: &[u8] = b"puzzlefs chunk nonce";
+
+/// The AEAD used to encrypt chunk blobs. Both take 256 bit keys; XChaCha20-Poly1305 is the faster
+/// of the two on CPUs without AES instructions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Cipher {
+    Aes256Gcm,
+    XChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn nonce_size(self) -> usize {
+        match self {
+            Cipher::Aes256Gcm => 12,
+            Cipher::XChaCha20Poly1305 => 24,
+        }
+    }
+}
+
+/// A per-image secret key. Images only refer to it by [`EncryptionKey::id`], the key itself has
+/// to be handed to the readers out of band.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; KEY_SIZE]);
+
+impl EncryptionKey {
+    pub fn new(key: [u8; KEY_SIZE]) -> Self {
+        EncryptionKey(key)
+    }
+
+    /// Reads a key file holding exactly [`KEY_SIZE`] raw bytes, e.g. as generated by
+    /// `head -c 32 /dev/urandom`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let key = fs::read(path)?;
+        let key = key.try_into().map_err(|key: Vec<u8>| {
+            WireFormatError::EncryptionError(
+                format!(
+                    "key file {} has {} bytes, expected {KEY_SIZE}",
+                    path.display(),
+                    key.len()
+                ),
+                Backtrace::capture(),
+            )
+        })?;
+        Ok(EncryptionKey(key))
+    }
+
+    /// Parses a hex encoded key, e.g. as generated by `openssl rand -hex 32`.
+    pub fn from_hex(key: &str) -> Result<Self> {
+        let mut bytes = [0; KEY_SIZE];
+        hex::decode_to_slice(key.trim(), &mut bytes).map_err(|e| {
+            WireFormatError::EncryptionError(
+                format!("invalid hex key, expected {KEY_SIZE} bytes: {e}"),
+                Backtrace::capture(),
+            )
+        })?;
+        Ok(EncryptionKey(bytes))
+    }
+
+    /// Reads the key from [`KEY_ENV`], for the readers whose keys are handed out as secrets in
+    /// their environment. `None` if it isn't set.
+    pub fn from_env() -> Result<Option<Self>> {
+        match std::env::var(KEY_ENV) {
+            Ok(key) => Self::from_hex(&key).map(Some),
+            Err(std::env::VarError::NotPresent) => Ok(None),
+            Err(e) => Err(WireFormatError::EncryptionError(
+                format!("invalid {KEY_ENV}: {e}"),
+                Backtrace::capture(),
+            )),
+        }
+    }
+
+    /// Identifies the key in the image manifest without revealing it.
+    pub fn id(&self) -> String {
+        hex::encode(Sha256::digest(self.0))
+    }
+
+    // The nonce is derived from the key and the plaintext instead of being random, so builds stay
+    // reproducible and identical chunks still deduplicate. The only thing this gives away is
+    // which blobs are identical, which their digests already do.
+    pub(crate) fn encrypt(&self, cipher: Cipher, data: &[u8]) -> Result<Vec<u8>> {
+        let mut hasher = Sha256::new();
+        hasher.update(NONCE_DOMAIN);
+        hasher.update(self.0);
+        hasher.update(data);
+        let nonce = &hasher.finalize()[..cipher.nonce_size()];
+
+        let ciphertext = match cipher {
+            Cipher::Aes256Gcm => {
+                Aes256Gcm::new(&self.0.into()).encrypt(aes_gcm::Nonce::from_slice(nonce), data)
+            }
+            Cipher::XChaCha20Poly1305 => XChaCha20Poly1305::new(&self.0.into())
+                .encrypt(chacha20poly1305::XNonce::from_slice(nonce), data),
+        }
+        .map_err(|_| {
+            WireFormatError::EncryptionError(
+                "chunk encryption failed".to_string(),
+                Backtrace::capture(),
+            )
+        })?;
+
+        let mut blob = nonce.to_vec();
+        blob.extend_from_slice(&ciphertext);
+        Ok(blob)
+    }
+
+    pub(crate) fn decrypt(&self, cipher: Cipher, blob: &[u8]) -> Result<Vec<u8>> {
+        let fail = || {
+            WireFormatError::EncryptionError(
+                "chunk decryption failed, wrong key or corrupted blob".to_string(),
+                Backtrace::capture(),
+            )
+        };
+        if blob.len() < cipher.nonce_size() {
+            return Err(fail());
+        }
+
+        let (nonce, ciphertext) = blob.split_at(cipher.nonce_size());
+        match cipher {
+            Cipher::Aes256Gcm => Aes256Gcm::new(&self.0.into())
+                .decrypt(aes_gcm::Nonce::from_slice(nonce), ciphertext),
+            Cipher::XChaCha20Poly1305 => XChaCha20Poly1305::new(&self.0.into())
+                .decrypt(chacha20poly1305::XNonce::from_slice(nonce), ciphertext),
+        }
+        .map_err(|_| fail())
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("EncryptionKey").field(&self.id()).finish()
+    }
+}
+
+/// Looks up the keys of encrypted images by their [`EncryptionKey::id`], e.g. in a key management
+/// service, see [`crate::oci::Image::with_key_from`].
+pub trait KeyProvider {
+    /// The key with the id `key_id`, `None` if the provider doesn't have it.
+    fn key(&self, key_id: &str) -> Result<Option<EncryptionKey>>;
+}
+
+/// A single key provides itself, and rejects the other ids with [`WireFormatError::WrongKey`]:
+/// it was given as the key of the image.
+impl KeyProvider for EncryptionKey {
+    fn key(&self, key_id: &str) -> Result<Option<EncryptionKey>> {
+        if self.id() != key_id {
+            return Err(WireFormatError::WrongKey(
+                format!(
+                    "the image is encrypted with key {key_id}, not {}",
+                    self.id()
+                ),
+                Backtrace::capture(),
+            ));
+        }
+        Ok(Some(self.clone()))
+    }
+}
+
+/// How to encrypt the chunks of an image.
+#[derive(Clone, Debug)]
+pub struct Encryption {
+    pub cipher: Cipher,
+    pub key: EncryptionKey,
+}
+
+impl Encryption {
+    pub(crate) fn key_reference(&self) -> KeyReference {
+        KeyReference {
+            cipher: self.cipher,
+            key_id: self.key.id(),
+        }
+    }
+}
+
+// Recorded with the build options in the manifest of encrypted images, so readers can tell which
+// key they need.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct KeyReference {
+    pub(crate) cipher: Cipher,
+    pub(crate) key_id: String,
+}
+
+// The key reference in the build options annotation of a manifest, if any; the other recorded
+// options don't matter to readers.
+pub(crate) fn key_reference(build_options: &str) -> Result<Option<KeyReference>> {
+    #[derive(Deserialize)]
+    struct Recorded {
+        #[serde(default)]
+        encryption: Option<KeyReference>,
+    }
+
+    Ok(serde_json::from_str::<Recorded>(build_options)?.encryption)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encryption_roundtrip() {
+        let key = EncryptionKey::new([7; KEY_SIZE]);
+        let other_key = EncryptionKey::new([8; KEY_SIZE]);
+        let data = b"meshuggah rocks".repeat(100);
+
+        for cipher in [Cipher::Aes256Gcm, Cipher::XChaCha20Poly1305] {
+            let blob = key.encrypt(cipher, &data).unwrap();
+            assert_ne!(&blob[cipher.nonce_size()..][..data.len()], &data[..]);
+            // deterministic, so identical chunks end up in the same blob
+            assert_eq!(blob, key.encrypt(cipher, &data).unwrap());
+            assert_eq!(key.decrypt(cipher, &blob).unwrap(), data);
+
+            assert!(other_key.decrypt(cipher, &blob).is_err());
+            let mut tampered = blob.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert!(key.decrypt(cipher, &tampered).is_err());
+        }
+    }
+
+    #[test]
+    fn test_key_lookup() -> anyhow::Result<()> {
+        let key = EncryptionKey::from_hex(&format!("{}\n", "07".repeat(KEY_SIZE)))?;
+        assert_eq!(key.id(), EncryptionKey::new([7; KEY_SIZE]).id());
+        assert!(EncryptionKey::from_hex("0707").is_err());
+
+        assert_eq!(key.key(&key.id())?.map(|k| k.0), Some([7; KEY_SIZE]));
+        assert!(matches!(
+            key.key(&EncryptionKey::new([8; KEY_SIZE]).id()),
+            Err(WireFormatError::WrongKey(..))
+        ));
+        Ok(())
+    }
+}
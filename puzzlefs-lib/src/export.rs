@@ -0,0 +1,374 @@
+use crate::format::{Ino, Inode, InodeMode};
+use crate::oci::{Descriptor, Image};
+use crate::reader::{DirEntry, PuzzleFS, WalkPuzzleFS};
+use log::{info, warn};
+use ocidir::ZstdLayerWriter;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+mod dm_verity;
+mod erofs;
+mod zstd_chunked;
+use zstd_chunked::ZstdChunkedWriter;
+
+/// The layer an image is exported as.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// a plain tar+zstd layer
+    #[default]
+    Tar,
+    /// a tar+zstd layer in the zstd:chunked format: every chunk of a file is a zstd frame of its
+    /// own, the frames matching the puzzlefs chunks, and a table of contents lists where they are,
+    /// so runtimes which pull zstd:chunked layers lazily only download the chunks they miss.
+    /// Other runtimes read it like any tar+zstd layer.
+    ZstdChunked,
+}
+
+// Where the tar stream of an exported layer goes.
+trait LayerWriter: Write + Sized {
+    // Appends a regular file, `header` being filled in but for the path.
+    fn append_file(
+        builder: &mut tar::Builder<Self>,
+        header: &mut tar::Header,
+        path: &Path,
+        entry: &DirEntry,
+    ) -> anyhow::Result<()> {
+        builder.append_data(header, path, entry.open()?)?;
+        Ok(())
+    }
+
+    // Called after each entry is appended, with the target of (hard or symbolic) links.
+    fn appended(
+        &mut self,
+        _path: &Path,
+        _header: &tar::Header,
+        _link: Option<&Path>,
+        _inode: &Inode,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+impl LayerWriter for ZstdLayerWriter<'_> {}
+
+// Adds the xattrs of the next entry as a PAX extended header, the way GNU tar and the container
+// runtimes store them.
+fn append_xattrs(builder: &mut tar::Builder<impl Write>, inode: &Inode) -> io::Result<()> {
+    let Some(additional) = &inode.additional else {
+        return Ok(());
+    };
+    if additional.xattrs.is_empty() {
+        return Ok(());
+    }
+
+    let mut records = Vec::new();
+    for xattr in &additional.xattrs {
+        let key = format!("SCHILY.xattr.{}", String::from_utf8_lossy(&xattr.key));
+        // the length of a record counts its own digits
+        let rest = key.len() + xattr.val.len() + 3;
+        let mut len = rest + rest.to_string().len();
+        if len.to_string().len() > rest.to_string().len() {
+            len += 1;
+        }
+        records.extend_from_slice(format!("{len} {key}=").as_bytes());
+        records.extend_from_slice(&xattr.val);
+        records.push(b'\n');
+    }
+
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(tar::EntryType::XHeader);
+    header.set_path("PaxHeader")?;
+    header.set_mode(0o644);
+    header.set_size(records.len() as u64);
+    header.set_cksum();
+    builder.append(&header, &records[..])
+}
+
+fn append_entry<W: LayerWriter>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    entry: &DirEntry,
+    hard_links: &mut HashMap<Ino, PathBuf>,
+) -> anyhow::Result<()> {
+    let inode = &entry.inode;
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(inode.permissions.into());
+    header.set_uid(inode.uid.into());
+    header.set_gid(inode.gid.into());
+    // tar can't go before the epoch
+    header.set_mtime(inode.mtime.map_or(0, |t| t.sec.max(0) as u64));
+    header.set_size(0);
+
+    if let Some(target) = hard_links.get(&inode.ino) {
+        header.set_entry_type(tar::EntryType::Link);
+        builder.append_link(&mut header, path, target)?;
+        return builder
+            .get_mut()
+            .appended(path, &header, Some(target), inode);
+    }
+    // images built before link counts were recorded have zero here, any of their inodes may be
+    // linked
+    if !matches!(inode.mode, InodeMode::Dir { .. }) && inode.nlink != 1 {
+        hard_links.insert(inode.ino, path.to_path_buf());
+    }
+
+    let mut link = None;
+    match &inode.mode {
+        InodeMode::File { .. } => {
+            append_xattrs(builder, inode)?;
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(inode.file_len()?);
+            W::append_file(builder, &mut header, path, entry)?;
+        }
+        InodeMode::Dir { .. } => {
+            append_xattrs(builder, inode)?;
+            header.set_entry_type(tar::EntryType::Directory);
+            builder.append_data(&mut header, path, io::empty())?;
+        }
+        InodeMode::Lnk => {
+            append_xattrs(builder, inode)?;
+            header.set_entry_type(tar::EntryType::Symlink);
+            let target = Path::new(inode.symlink_target()?);
+            builder.append_link(&mut header, path, target)?;
+            link = Some(target);
+        }
+        InodeMode::Chr { major, minor } | InodeMode::Blk { major, minor } => {
+            append_xattrs(builder, inode)?;
+            header.set_entry_type(match inode.mode {
+                InodeMode::Chr { .. } => tar::EntryType::Char,
+                _ => tar::EntryType::Block,
+            });
+            header.set_device_major((*major).try_into()?)?;
+            header.set_device_minor((*minor).try_into()?)?;
+            builder.append_data(&mut header, path, io::empty())?;
+        }
+        InodeMode::Fifo => {
+            append_xattrs(builder, inode)?;
+            header.set_entry_type(tar::EntryType::Fifo);
+            builder.append_data(&mut header, path, io::empty())?;
+        }
+        // tar has no sockets, they are created by whoever listens on them anyway
+        InodeMode::Sock => {
+            warn!("skipping socket {path:?}");
+            return Ok(());
+        }
+        mode => bail!("bad inode mode {mode:#?}"),
+    }
+    builder.get_mut().appended(path, &header, link, inode)
+}
+
+fn export_layer<W: LayerWriter>(pfs: &mut PuzzleFS, writer: W) -> anyhow::Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    let mut hard_links = HashMap::new();
+    for entry in WalkPuzzleFS::walk(pfs)? {
+        let entry = entry?;
+        let path = entry.path.strip_prefix("/")?.to_path_buf();
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+        info!("exporting {path:?}");
+        append_entry(&mut builder, &path, &entry, &mut hard_links)?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+/// Exports the puzzlefs image `tag` as a conventional OCI image with the same tag in `oci_dir`:
+/// a single zstd compressed tar layer holding the whole rootfs, and a config listing its diff_id.
+/// Runtimes which don't know about puzzlefs can run it, at the cost of the chunk sharing. The
+/// custom annotations and the runtime configuration of the image go along.
+pub fn export_oci_image(image: Image, tag: &str, oci_dir: &Path) -> anyhow::Result<Descriptor> {
+    export_oci_image_with_format(image, tag, oci_dir, ExportFormat::default())
+}
+
+/// Like [`export_oci_image`], with the layer in the given format.
+pub fn export_oci_image_with_format(
+    image: Image,
+    tag: &str,
+    oci_dir: &Path,
+    format: ExportFormat,
+) -> anyhow::Result<Descriptor> {
+    let platform = image.platform();
+    let annotations = image.annotations(tag)?;
+    // the runtime configuration goes along, with the exported layer as its only one
+    let mut config = image.config(tag)?.unwrap_or_default();
+    config.rootfs_mut().diff_ids_mut().clear();
+    let target = Image::new(oci_dir)?;
+    let mut pfs = PuzzleFS::open(image, tag, None)?;
+
+    let (layer, layer_annotations) = match format {
+        ExportFormat::Tar => {
+            let writer = export_layer(&mut pfs, target.0.create_layer_zstd(None)?)?;
+            (writer.complete()?, None)
+        }
+        ExportFormat::ZstdChunked => {
+            let writer = ZstdChunkedWriter::new(target.0.create_blob()?);
+            let (layer, annotations) = export_layer(&mut pfs, writer)?.complete()?;
+            (layer, Some(annotations))
+        }
+    };
+
+    let mut manifest = target.0.new_empty_manifest()?.build()?;
+    config.set_os(platform.os().clone());
+    config.set_architecture(platform.architecture().clone());
+    target.0.push_layer_annotated(
+        &mut manifest,
+        &mut config,
+        layer,
+        layer_annotations,
+        "puzzlefs export",
+    );
+    manifest.set_config(target.0.write_config(config)?);
+    if !annotations.is_empty() {
+        manifest.set_annotations(Some(annotations.into_iter().collect()));
+    }
+    Ok(target.insert_manifest(manifest, tag)?)
+}
+
+/// A block image written by [`export_block_image`], with what dm-verity needs to check it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockImage {
+    /// The size of the filesystem, in 4K blocks.
+    pub data_blocks: u64,
+    pub root_hash: [u8; 32],
+    pub salt: [u8; 32],
+}
+
+/// Exports the puzzlefs image `tag` as an uncompressed EROFS filesystem in `data`, and its
+/// dm-verity hash tree in `hash_tree`, the way `veritysetup format` writes them (sha256, 4K
+/// blocks, preceded by a superblock), for platforms which only mount verified block devices. The
+/// salt is derived from the manifest, so exporting an image again gives the same root hash.
+pub fn export_block_image(
+    image: Image,
+    tag: &str,
+    data: &Path,
+    hash_tree: &Path,
+) -> anyhow::Result<BlockImage> {
+    let manifest = image.find_manifest_descriptor(tag)?;
+    let salt = Sha256::digest(manifest.digest().to_string().as_bytes()).into();
+    let pfs = PuzzleFS::open(image, tag, None)?;
+
+    let mut out = BufWriter::new(fs::File::create(data)?);
+    let blocks = erofs::write_image(&pfs, &mut out)?;
+    out.into_inner()?.sync_all()?;
+
+    let mut out = BufWriter::new(fs::File::create(hash_tree)?);
+    let (data_blocks, root_hash) =
+        dm_verity::write_hash_tree(BufReader::new(fs::File::open(data)?), &salt, &mut out)?;
+    out.into_inner()?.sync_all()?;
+    if data_blocks != blocks {
+        bail!("{data:?} changed while its hash tree was computed");
+    }
+    Ok(BlockImage {
+        data_blocks,
+        root_hash,
+        salt,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use crate::compression::Noop;
+    use crate::convert::convert_oci_image;
+    use ocidir::oci_spec::image::ImageConfiguration;
+    use std::fs;
+    use std::io::Read;
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_export_round_trip() -> anyhow::Result<()> {
+        let rootfs = tempdir()?;
+        fs::create_dir(rootfs.path().join("etc"))?;
+        fs::write(rootfs.path().join("etc/hostname"), b"puzzlefs")?;
+        fs::hard_link(
+            rootfs.path().join("etc/hostname"),
+            rootfs.path().join("etc/hostname.bak"),
+        )?;
+        symlink("etc/hostname", rootfs.path().join("hostname"))?;
+        fs::copy(
+            "src/builder/test/test-1/SekienAkashita.jpg",
+            rootfs.path().join("SekienAkashita.jpg"),
+        )?;
+
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("puzzlefs"))?;
+        build_test_fs(rootfs.path(), &image, "test")?;
+        let oci_dir = dir.path().join("oci");
+        export_oci_image(image, "test", &oci_dir)?;
+
+        let exported = Image::open(&oci_dir)?;
+        let manifest = exported.find_manifest("test")?;
+        assert_eq!(manifest.layers().len(), 1);
+        let config: ImageConfiguration = exported.0.read_json_blob(manifest.config())?;
+        assert_eq!(config.rootfs().diff_ids().len(), 1);
+
+        // converting it back gives the same files
+        let back = Image::new(&dir.path().join("back"))?;
+        convert_oci_image::<Noop>(&oci_dir, "test", &back)?;
+        let mut pfs = PuzzleFS::open(back, "test", None)?;
+        let mut paths = Vec::new();
+        for entry in WalkPuzzleFS::walk(&mut pfs)? {
+            let entry = entry?;
+            paths.push(entry.path.clone());
+            if entry.path == Path::new("/SekienAkashita.jpg") {
+                let mut contents = Vec::new();
+                entry.open()?.read_to_end(&mut contents)?;
+                assert_eq!(
+                    contents,
+                    fs::read("src/builder/test/test-1/SekienAkashita.jpg")?
+                );
+            }
+            if entry.path == Path::new("/hostname") {
+                assert_eq!(entry.inode.symlink_target()?, "etc/hostname");
+            }
+            if entry.path == Path::new("/etc/hostname.bak") {
+                assert_eq!(entry.inode.nlink, 2);
+            }
+        }
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                "/",
+                "/SekienAkashita.jpg",
+                "/etc",
+                "/etc/hostname",
+                "/etc/hostname.bak",
+                "/hostname",
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_block_image() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("puzzlefs"))?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+
+        let export = |name: &str| {
+            let data = dir.path().join(format!("{name}.erofs"));
+            let hash_tree = dir.path().join(format!("{name}.verity"));
+            let image = Image::open(&dir.path().join("puzzlefs"))?;
+            let block_image = export_block_image(image, "test", &data, &hash_tree)?;
+            assert_eq!(
+                fs::metadata(&data)?.len(),
+                block_image.data_blocks * dm_verity::BLOCK_SIZE as u64
+            );
+            anyhow::Ok((block_image, fs::read(hash_tree)?))
+        };
+        let (first, first_tree) = export("first")?;
+        let (second, second_tree) = export("second")?;
+        assert_eq!(first, second);
+        assert_eq!(first_tree, second_tree);
+        Ok(())
+    }
+}
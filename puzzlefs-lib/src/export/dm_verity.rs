@@ -0,0 +1,147 @@
+// dm-verity hash trees, as `veritysetup format` writes them with its defaults: sha256, 4K data
+// and hash blocks, and hash type 1, where every block is hashed with the salt before it. The
+// digests of the data blocks fill the blocks of the lowest level of the tree, zero padded, the
+// digests of those blocks the level above, and so on up to a single block, whose digest is the
+// root hash. The hash device starts with a 512 byte superblock holding the parameters, in a
+// block of its own, followed by the levels, the top one first.
+
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+
+pub(super) const BLOCK_SIZE: usize = 4096;
+const DIGEST_SIZE: usize = 32;
+const DIGESTS_PER_BLOCK: usize = BLOCK_SIZE / DIGEST_SIZE;
+
+const SIGNATURE: &[u8; 8] = b"verity\0\0";
+const SUPERBLOCK_VERSION: u32 = 1;
+const HASH_TYPE: u32 = 1;
+const ALGORITHM: &[u8] = b"sha256";
+const SUPERBLOCK_SIZE: usize = 512;
+const MAX_SALT_SIZE: usize = 256;
+
+fn hash_block(salt: &[u8], block: &[u8]) -> [u8; DIGEST_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(block);
+    hasher.finalize().into()
+}
+
+// The levels of the tree of the blocks whose digests are `digests`, the lowest one first, and
+// the root hash.
+fn hash_levels(salt: &[u8], mut digests: Vec<[u8; DIGEST_SIZE]>) -> (Vec<Vec<u8>>, [u8; 32]) {
+    let mut levels = Vec::new();
+    while digests.len() > 1 {
+        let mut level = Vec::with_capacity(digests.len().div_ceil(DIGESTS_PER_BLOCK) * BLOCK_SIZE);
+        for block in digests.chunks(DIGESTS_PER_BLOCK) {
+            level.extend(block.iter().flatten());
+            level.resize(level.len().next_multiple_of(BLOCK_SIZE), 0);
+        }
+        digests = level
+            .chunks(BLOCK_SIZE)
+            .map(|block| hash_block(salt, block))
+            .collect();
+        levels.push(level);
+    }
+    (levels, digests[0])
+}
+
+fn superblock(salt: &[u8], data_blocks: u64) -> Vec<u8> {
+    let mut sb = Vec::with_capacity(BLOCK_SIZE);
+    sb.extend_from_slice(SIGNATURE);
+    sb.extend_from_slice(&SUPERBLOCK_VERSION.to_le_bytes());
+    sb.extend_from_slice(&HASH_TYPE.to_le_bytes());
+    // the uuid, which only tells hash devices apart; the root hash does that already
+    sb.extend_from_slice(&[0; 16]);
+    let mut algorithm = [0; 32];
+    algorithm[..ALGORITHM.len()].copy_from_slice(ALGORITHM);
+    sb.extend_from_slice(&algorithm);
+    sb.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+    sb.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+    sb.extend_from_slice(&data_blocks.to_le_bytes());
+    sb.extend_from_slice(&(salt.len() as u16).to_le_bytes());
+    sb.extend_from_slice(&[0; 6]);
+    sb.extend_from_slice(salt);
+    sb.resize(SUPERBLOCK_SIZE, 0);
+    // the tree starts at the next hash block
+    sb.resize(BLOCK_SIZE, 0);
+    sb
+}
+
+/// Writes the hash tree of `data`, which is a whole number of blocks, to `out`, and returns the
+/// number of data blocks and the root hash.
+pub(super) fn write_hash_tree(
+    mut data: impl Read,
+    salt: &[u8],
+    mut out: impl Write,
+) -> io::Result<(u64, [u8; 32])> {
+    if salt.len() > MAX_SALT_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "dm-verity salts are at most 256 bytes long",
+        ));
+    }
+
+    let mut digests = Vec::new();
+    let mut block = vec![0; BLOCK_SIZE];
+    loop {
+        match data.read_exact(&mut block) {
+            Ok(()) => digests.push(hash_block(salt, &block)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+    if digests.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no data to build a hash tree of",
+        ));
+    }
+
+    let data_blocks = digests.len() as u64;
+    let (levels, root_hash) = hash_levels(salt, digests);
+    out.write_all(&superblock(salt, data_blocks))?;
+    for level in levels.iter().rev() {
+        out.write_all(level)?;
+    }
+    Ok((data_blocks, root_hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_tree() -> anyhow::Result<()> {
+        let salt = [0x5a; 32];
+        let blocks = (0..130u8).map(|i| vec![i; BLOCK_SIZE]).collect::<Vec<_>>();
+        let mut tree = Vec::new();
+        let (data_blocks, root_hash) = write_hash_tree(&blocks.concat()[..], &salt, &mut tree)?;
+        assert_eq!(data_blocks, 130);
+
+        assert_eq!(&tree[..8], SIGNATURE);
+        assert_eq!(&tree[32..38], ALGORITHM);
+        assert_eq!(u64::from_le_bytes(tree[72..80].try_into()?), 130);
+        assert_eq!(&tree[88..120], &salt);
+
+        // 130 data blocks take two hash blocks, whose digests fit in the top one
+        assert_eq!(tree.len(), 4 * BLOCK_SIZE);
+        let (top, lowest) = tree[BLOCK_SIZE..].split_at(BLOCK_SIZE);
+        for (i, block) in blocks.iter().enumerate() {
+            let digest = &lowest[i * DIGEST_SIZE..(i + 1) * DIGEST_SIZE];
+            assert_eq!(digest, hash_block(&salt, block));
+        }
+        assert!(lowest[130 * DIGEST_SIZE..].iter().all(|b| *b == 0));
+        assert_eq!(
+            &top[..DIGEST_SIZE],
+            hash_block(&salt, &lowest[..BLOCK_SIZE])
+        );
+        assert_eq!(
+            &top[DIGEST_SIZE..2 * DIGEST_SIZE],
+            hash_block(&salt, &lowest[BLOCK_SIZE..])
+        );
+        assert_eq!(root_hash, hash_block(&salt, top));
+
+        assert!(write_hash_tree(&[][..], &salt, io::sink()).is_err());
+        Ok(())
+    }
+}
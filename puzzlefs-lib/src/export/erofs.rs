@@ -0,0 +1,532 @@
+// Uncompressed EROFS images, as the kernel mounts them. Block 0 holds the superblock, at offset
+// 1024; the metadata area, from block 1 on, the inodes, each followed by its xattrs; and the data
+// area after it the contents of the regular files, directories and symlinks, each in contiguous
+// blocks of its own (the "flat plain" layout). All the inodes are the 64 byte extended ones, so
+// that ids, sizes and mtimes fit, and are addressed by their nid, their offset in the metadata
+// area in 32 byte units; none crosses a block boundary. The blocks of a directory hold its
+// entries, "." and ".." included and sorted by name across blocks, followed by their names. All
+// integers are little endian.
+
+use log::warn;
+use nix::libc::{S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFREG, S_IFSOCK};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+
+use super::dm_verity::BLOCK_SIZE;
+use crate::format::{
+    escape_name, Ino, Inode, InodeMode, Xattr, XattrNamespace, ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR,
+};
+use crate::reader::{FileReader, PuzzleFS};
+
+const MAGIC: u32 = 0xe0f5_e1e2;
+const SUPERBLOCK_OFFSET: usize = 1024;
+const BLOCK_SIZE_BITS: u8 = 12;
+const META_BLKADDR: u32 = 1;
+const SLOT_SIZE: usize = 32;
+const INODE_SIZE: usize = 64;
+// extended inode, flat plain data layout
+const INODE_FORMAT: u16 = 1;
+const XATTR_HEADER_SIZE: usize = 12;
+const DIRENT_SIZE: usize = 12;
+const NAME_LEN: usize = 255;
+
+// xattr name indexes
+const XATTR_INDEX_USER: u8 = 1;
+const XATTR_INDEX_POSIX_ACL_ACCESS: u8 = 2;
+const XATTR_INDEX_POSIX_ACL_DEFAULT: u8 = 3;
+const XATTR_INDEX_TRUSTED: u8 = 4;
+const XATTR_INDEX_SECURITY: u8 = 6;
+
+// directory entry file types
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_CHRDEV: u8 = 3;
+const FT_BLKDEV: u8 = 4;
+const FT_FIFO: u8 = 5;
+const FT_SOCK: u8 = 6;
+const FT_SYMLINK: u8 = 7;
+
+struct Node {
+    inode: Inode,
+    // S_IF* and permissions
+    mode: u16,
+    // (name, node index), for directories, "." and ".." aside
+    children: Vec<(Vec<u8>, usize)>,
+    parent: usize,
+    nlink: u32,
+    xattrs: Vec<u8>,
+    nid: u64,
+    size: u64,
+    blkaddr: u32,
+}
+
+impl Node {
+    fn file_type(&self) -> u8 {
+        match self.inode.mode {
+            InodeMode::File { .. } => FT_REG_FILE,
+            InodeMode::Dir { .. } => FT_DIR,
+            InodeMode::Chr { .. } => FT_CHRDEV,
+            InodeMode::Blk { .. } => FT_BLKDEV,
+            InodeMode::Fifo => FT_FIFO,
+            InodeMode::Sock => FT_SOCK,
+            InodeMode::Lnk => FT_SYMLINK,
+            InodeMode::Unknown | InodeMode::Wht => unreachable!(),
+        }
+    }
+
+    fn inode_size(&self) -> usize {
+        (INODE_SIZE + self.xattrs.len()).next_multiple_of(SLOT_SIZE)
+    }
+
+    fn write_inode(&self, out: &mut Vec<u8>, ino: u32) {
+        let start = out.len();
+        let i_u = match self.inode.mode {
+            InodeMode::Chr { major, minor } | InodeMode::Blk { major, minor } => {
+                encode_dev(major, minor)
+            }
+            _ => self.blkaddr,
+        };
+        let (mtime, nsec) = self
+            .inode
+            .mtime
+            .map_or((0, 0), |t| (t.sec.max(0) as u64, t.nsec));
+        let xattr_icount = match self.xattrs.len() {
+            0 => 0,
+            len => ((len - XATTR_HEADER_SIZE) / 4 + 1) as u16,
+        };
+        out.extend_from_slice(&INODE_FORMAT.to_le_bytes());
+        out.extend_from_slice(&xattr_icount.to_le_bytes());
+        out.extend_from_slice(&self.mode.to_le_bytes());
+        out.extend_from_slice(&[0; 2]);
+        out.extend_from_slice(&self.size.to_le_bytes());
+        out.extend_from_slice(&i_u.to_le_bytes());
+        out.extend_from_slice(&ino.to_le_bytes());
+        out.extend_from_slice(&self.inode.uid.to_le_bytes());
+        out.extend_from_slice(&self.inode.gid.to_le_bytes());
+        out.extend_from_slice(&mtime.to_le_bytes());
+        out.extend_from_slice(&nsec.to_le_bytes());
+        out.extend_from_slice(&self.nlink.to_le_bytes());
+        out.extend_from_slice(&[0; 16]);
+        out.extend_from_slice(&self.xattrs);
+        out.resize(start + self.inode_size(), 0);
+    }
+}
+
+// the kernel's new_encode_dev()
+fn encode_dev(major: u64, minor: u64) -> u32 {
+    ((minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)) as u32
+}
+
+// The name index of an xattr and what's left of its key, None for the xattrs EROFS can't hold.
+fn xattr_name(key: &[u8]) -> Option<(u8, &[u8])> {
+    if key == ACL_ACCESS_XATTR {
+        return Some((XATTR_INDEX_POSIX_ACL_ACCESS, b""));
+    }
+    if key == ACL_DEFAULT_XATTR {
+        return Some((XATTR_INDEX_POSIX_ACL_DEFAULT, b""));
+    }
+    let namespace = XattrNamespace::of(key)?;
+    let index = match namespace {
+        XattrNamespace::User => XATTR_INDEX_USER,
+        XattrNamespace::Trusted => XATTR_INDEX_TRUSTED,
+        XattrNamespace::Security => XATTR_INDEX_SECURITY,
+        XattrNamespace::System => return None,
+    };
+    Some((index, &key[namespace.prefix().len()..]))
+}
+
+// The xattrs of an inode as they follow it: a header without shared xattrs, then the entries,
+// each padded to 4 bytes.
+fn encode_xattrs(xattrs: &[Xattr]) -> anyhow::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    for xattr in xattrs {
+        let Some((index, name)) = xattr_name(&xattr.key) else {
+            warn!(
+                "EROFS can't hold xattr {}, skipping it",
+                escape_name(&xattr.key)
+            );
+            continue;
+        };
+        if body.is_empty() {
+            body.resize(XATTR_HEADER_SIZE, 0);
+        }
+        body.push(u8::try_from(name.len())?);
+        body.push(index);
+        body.extend_from_slice(&u16::try_from(xattr.val.len())?.to_le_bytes());
+        body.extend_from_slice(name);
+        body.extend_from_slice(&xattr.val);
+        body.resize(body.len().next_multiple_of(4), 0);
+    }
+    Ok(body)
+}
+
+// The blocks of a directory, the last one only as long as its contents.
+fn dir_data(entries: &mut [(&[u8], u64, u8)]) -> Vec<u8> {
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    let mut data = Vec::new();
+    let mut rest = &entries[..];
+    while !rest.is_empty() {
+        let mut used = 0;
+        let count = rest
+            .iter()
+            .take_while(|(name, _, _)| {
+                used += DIRENT_SIZE + name.len();
+                used <= BLOCK_SIZE
+            })
+            .count();
+        let (block, tail) = rest.split_at(count);
+        let start = data.len();
+        let mut nameoff = DIRENT_SIZE * block.len();
+        for (name, nid, file_type) in block {
+            data.extend_from_slice(&nid.to_le_bytes());
+            // the entries and names of a block fit in it, so this fits in 16 bits
+            data.extend_from_slice(&(nameoff as u16).to_le_bytes());
+            data.push(*file_type);
+            data.push(0);
+            nameoff += name.len();
+        }
+        for (name, _, _) in block {
+            data.extend_from_slice(name);
+        }
+        if !tail.is_empty() {
+            data.resize(start + BLOCK_SIZE, 0);
+        }
+        rest = tail;
+    }
+    data
+}
+
+fn pad_to_block(out: &mut impl Write, written: u64) -> io::Result<()> {
+    let padding = written.next_multiple_of(BLOCK_SIZE as u64) - written;
+    io::copy(&mut io::repeat(0).take(padding), out)?;
+    Ok(())
+}
+
+// The inodes of the image, in breadth first order from the root directory, which comes first
+// since the superblock only has room for a 16 bit root nid; hard links are one node.
+fn collect_nodes(pfs: &PuzzleFS) -> anyhow::Result<Vec<Node>> {
+    let mut nodes = Vec::new();
+    let mut indexes = HashMap::<Ino, usize>::new();
+    let mut queue = vec![(1, 0)];
+    let mut i = 0;
+    while i < queue.len() {
+        let (ino, parent) = queue[i];
+        i += 1;
+        if let Some(index) = indexes.get(&ino) {
+            nodes[*index].nlink += 1;
+            continue;
+        }
+        let inode = pfs.find_inode(ino)?;
+        let index = nodes.len();
+        indexes.insert(ino, index);
+
+        let (kind, size) = match &inode.mode {
+            InodeMode::File { .. } => (S_IFREG, inode.file_len()?),
+            InodeMode::Dir { .. } => (S_IFDIR, 0),
+            InodeMode::Lnk => (S_IFLNK, inode.symlink_target()?.len() as u64),
+            InodeMode::Chr { .. } => (S_IFCHR, 0),
+            InodeMode::Blk { .. } => (S_IFBLK, 0),
+            InodeMode::Fifo => (S_IFIFO, 0),
+            InodeMode::Sock => (S_IFSOCK, 0),
+            InodeMode::Unknown | InodeMode::Wht => bail!("cannot export inode {ino} to EROFS"),
+        };
+        let mut children = Vec::new();
+        if let InodeMode::Dir { dir_list } = &inode.mode {
+            for entry in &dir_list.entries {
+                // entries deleted by an upper layer
+                if pfs.lookup_inode(entry.ino)?.is_none() {
+                    continue;
+                }
+                if entry.name.len() > NAME_LEN {
+                    bail!(
+                        "EROFS names are at most {NAME_LEN} bytes long: {}",
+                        escape_name(&entry.name)
+                    );
+                }
+                queue.push((entry.ino, index));
+                children.push((entry.name.clone(), queue.len() - 1));
+            }
+        }
+        let xattrs = match &inode.additional {
+            Some(additional) => encode_xattrs(&additional.xattrs)?,
+            None => Vec::new(),
+        };
+        nodes.push(Node {
+            mode: kind as u16 | inode.permissions,
+            inode,
+            children,
+            parent,
+            nlink: 1,
+            xattrs,
+            nid: 0,
+            size,
+            blkaddr: 0,
+        });
+    }
+
+    // the children were recorded by their index in the queue, which is now known as a node
+    let queued = queue
+        .iter()
+        .map(|(ino, _)| indexes[ino])
+        .collect::<Vec<_>>();
+    for node in &mut nodes {
+        for child in &mut node.children {
+            child.1 = queued[child.1];
+        }
+    }
+    for i in 0..nodes.len() {
+        if let InodeMode::Dir { .. } = nodes[i].inode.mode {
+            let subdirs = nodes[i]
+                .children
+                .iter()
+                .filter(|(_, child)| matches!(nodes[*child].inode.mode, InodeMode::Dir { .. }))
+                .count();
+            nodes[i].nlink = 2 + u32::try_from(subdirs)?;
+        }
+    }
+    Ok(nodes)
+}
+
+/// Writes the merged rootfs of `pfs` to `out` as an EROFS image, and returns its size in blocks.
+pub(super) fn write_image(pfs: &PuzzleFS, mut out: impl Write) -> anyhow::Result<u64> {
+    let mut nodes = collect_nodes(pfs)?;
+
+    let mut meta_len = 0;
+    for node in &mut nodes {
+        let size = node.inode_size();
+        if size > BLOCK_SIZE {
+            bail!(
+                "the xattrs of inode {} don't fit in an EROFS block",
+                node.inode.ino
+            );
+        }
+        if meta_len % BLOCK_SIZE + size > BLOCK_SIZE {
+            meta_len = meta_len.next_multiple_of(BLOCK_SIZE);
+        }
+        node.nid = (meta_len / SLOT_SIZE) as u64;
+        meta_len += size;
+    }
+    let mut dirs = HashMap::new();
+    for i in 0..nodes.len() {
+        if !matches!(nodes[i].inode.mode, InodeMode::Dir { .. }) {
+            continue;
+        }
+        let node = &nodes[i];
+        let mut entries = vec![
+            (&b"."[..], node.nid, FT_DIR),
+            (&b".."[..], nodes[node.parent].nid, FT_DIR),
+        ];
+        for (name, child) in &node.children {
+            entries.push((
+                name.as_slice(),
+                nodes[*child].nid,
+                nodes[*child].file_type(),
+            ));
+        }
+        let data = dir_data(&mut entries);
+        nodes[i].size = data.len() as u64;
+        dirs.insert(i, data);
+    }
+
+    let mut next_block = u64::from(META_BLKADDR) + meta_len.div_ceil(BLOCK_SIZE) as u64;
+    for node in &mut nodes {
+        if node.size == 0 {
+            continue;
+        }
+        node.blkaddr = u32::try_from(next_block)
+            .map_err(|_| anyhow!("the image is too large for EROFS' 32 bit block addresses"))?;
+        next_block += node.size.div_ceil(BLOCK_SIZE as u64);
+    }
+    let blocks = next_block;
+
+    let mut superblock = vec![0; SUPERBLOCK_OFFSET];
+    superblock.extend_from_slice(&MAGIC.to_le_bytes());
+    // checksum and compatible features
+    superblock.extend_from_slice(&[0; 8]);
+    superblock.push(BLOCK_SIZE_BITS);
+    superblock.push(0);
+    superblock.extend_from_slice(&u16::try_from(nodes[0].nid)?.to_le_bytes());
+    superblock.extend_from_slice(&(nodes.len() as u64).to_le_bytes());
+    // build time, only used by compact inodes
+    superblock.extend_from_slice(&[0; 12]);
+    superblock.extend_from_slice(&u32::try_from(blocks)?.to_le_bytes());
+    superblock.extend_from_slice(&META_BLKADDR.to_le_bytes());
+    // no shared xattrs, no uuid or volume name, no incompatible features
+    superblock.resize(BLOCK_SIZE, 0);
+    out.write_all(&superblock)?;
+
+    let mut meta = Vec::with_capacity(meta_len.next_multiple_of(BLOCK_SIZE));
+    for (i, node) in nodes.iter().enumerate() {
+        meta.resize(node.nid as usize * SLOT_SIZE, 0);
+        node.write_inode(&mut meta, u32::try_from(i + 1)?);
+    }
+    meta.resize(meta.len().next_multiple_of(BLOCK_SIZE), 0);
+    out.write_all(&meta)?;
+
+    for (i, node) in nodes.iter().enumerate() {
+        if node.blkaddr == 0 {
+            continue;
+        }
+        match &node.inode.mode {
+            InodeMode::File { .. } => {
+                let copied = io::copy(&mut FileReader::new(&pfs.oci, &node.inode)?, &mut out)?;
+                if copied != node.size {
+                    bail!(
+                        "inode {} is {copied} bytes long, not {}",
+                        node.inode.ino,
+                        node.size
+                    );
+                }
+            }
+            InodeMode::Dir { .. } => out.write_all(&dirs[&i])?,
+            InodeMode::Lnk => out.write_all(node.inode.symlink_target()?.as_bytes())?,
+            _ => unreachable!(),
+        }
+        pad_to_block(&mut out, node.size)?;
+    }
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use crate::oci::Image;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    fn le_u16(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn le_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn le_u64(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    // (mode, size, block address, nlink, xattrs) of inode `nid`
+    fn read_inode(image: &[u8], nid: u64) -> (u16, u64, u32, u32, &[u8]) {
+        let offset = META_BLKADDR as usize * BLOCK_SIZE + nid as usize * SLOT_SIZE;
+        let inode = &image[offset..];
+        assert_eq!(le_u16(inode, 0), INODE_FORMAT);
+        let xattrs_len = match le_u16(inode, 2) {
+            0 => 0,
+            icount => XATTR_HEADER_SIZE + (icount as usize - 1) * 4,
+        };
+        (
+            le_u16(inode, 4),
+            le_u64(inode, 8),
+            le_u32(inode, 16),
+            le_u32(inode, 44),
+            &inode[INODE_SIZE..INODE_SIZE + xattrs_len],
+        )
+    }
+
+    fn read_data(image: &[u8], nid: u64) -> &[u8] {
+        let (_, size, blkaddr, _, _) = read_inode(image, nid);
+        let start = blkaddr as usize * BLOCK_SIZE;
+        &image[start..start + size as usize]
+    }
+
+    // (name, nid) of the entries of directory `nid`
+    fn read_dir(image: &[u8], nid: u64) -> Vec<(Vec<u8>, u64)> {
+        let data = read_data(image, nid);
+        let mut entries = Vec::new();
+        for block in data.chunks(BLOCK_SIZE) {
+            let count = le_u16(block, 8) as usize / DIRENT_SIZE;
+            for i in 0..count {
+                let nameoff = le_u16(block, i * DIRENT_SIZE + 8) as usize;
+                let end = if i + 1 < count {
+                    le_u16(block, (i + 1) * DIRENT_SIZE + 8) as usize
+                } else {
+                    block[nameoff..]
+                        .iter()
+                        .position(|b| *b == 0)
+                        .map_or(block.len(), |len| nameoff + len)
+                };
+                entries.push((block[nameoff..end].to_vec(), le_u64(block, i * DIRENT_SIZE)));
+            }
+        }
+        entries
+    }
+
+    fn lookup(image: &[u8], dir: u64, name: &[u8]) -> u64 {
+        read_dir(image, dir)
+            .into_iter()
+            .find(|(entry, _)| entry == name)
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn test_erofs_image() -> anyhow::Result<()> {
+        let rootfs = tempdir()?;
+        let jpg = fs::read("src/builder/test/test-1/SekienAkashita.jpg")?;
+        fs::create_dir(rootfs.path().join("etc"))?;
+        fs::write(rootfs.path().join("etc/hostname"), b"puzzlefs")?;
+        fs::hard_link(
+            rootfs.path().join("etc/hostname"),
+            rootfs.path().join("etc/hostname.bak"),
+        )?;
+        symlink("etc/hostname", rootfs.path().join("hostname"))?;
+        fs::write(rootfs.path().join("SekienAkashita.jpg"), &jpg)?;
+        xattr::set(rootfs.path().join("etc"), "user.comment", b"config")?;
+        // enough entries to take several directory blocks
+        fs::create_dir(rootfs.path().join("many"))?;
+        for i in 0..500 {
+            fs::write(rootfs.path().join(format!("many/file-{i:03}")), b"")?;
+        }
+
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(rootfs.path(), &image, "test")?;
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        let mut erofs = Vec::new();
+        let blocks = write_image(&pfs, &mut erofs)?;
+        assert_eq!(erofs.len(), blocks as usize * BLOCK_SIZE);
+
+        let superblock = &erofs[SUPERBLOCK_OFFSET..];
+        assert_eq!(le_u32(superblock, 0), MAGIC);
+        assert_eq!(superblock[12], BLOCK_SIZE_BITS);
+        assert_eq!(le_u32(superblock, 36) as u64, blocks);
+        let root = le_u16(superblock, 14) as u64;
+
+        let (mode, _, _, nlink, _) = read_inode(&erofs, root);
+        assert_eq!(mode as u32 & !0o7777, S_IFDIR);
+        assert_eq!(nlink, 4);
+        let names = read_dir(&erofs, root)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [".", "..", "SekienAkashita.jpg", "etc", "hostname", "many"].map(|n| n.as_bytes())
+        );
+
+        let file = lookup(&erofs, root, b"SekienAkashita.jpg");
+        assert_eq!(read_data(&erofs, file), jpg);
+        let link = lookup(&erofs, root, b"hostname");
+        assert_eq!(read_data(&erofs, link), b"etc/hostname");
+
+        let etc = lookup(&erofs, root, b"etc");
+        assert_eq!(lookup(&erofs, etc, b".."), root);
+        let hostname = lookup(&erofs, etc, b"hostname");
+        assert_eq!(lookup(&erofs, etc, b"hostname.bak"), hostname);
+        assert_eq!(read_inode(&erofs, hostname).3, 2);
+        assert_eq!(read_data(&erofs, hostname), b"puzzlefs");
+        let xattrs = read_inode(&erofs, etc).4;
+        assert_eq!(xattrs[XATTR_HEADER_SIZE + 1], XATTR_INDEX_USER);
+        assert_eq!(&xattrs[XATTR_HEADER_SIZE + 4..][..13], b"commentconfig");
+
+        let many = read_dir(&erofs, lookup(&erofs, root, b"many"));
+        assert!(read_data(&erofs, lookup(&erofs, root, b"many")).len() > BLOCK_SIZE);
+        assert_eq!(many.len(), 502);
+        assert!(many.windows(2).all(|w| w[0].0 < w[1].0));
+        Ok(())
+    }
+}
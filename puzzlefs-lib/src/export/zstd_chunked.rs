@@ -0,0 +1,381 @@
+// The zstd:chunked layer format of containers/storage, which CRI-O and podman pull lazily. It's a
+// tar+zstd layer whose files start zstd frames of their own, followed by a table of contents (TOC)
+// listing where each file, or each of its chunks, lies in the blob. The TOC, and the footer
+// pointing at it, go in skippable frames, which plain zstd decoders ignore.
+//
+// We cut a frame at every puzzlefs chunk, so the chunks runtimes fetch are the ones puzzlefs
+// dedups. The tar-split data containers/storage can use to recreate the tar stream byte for byte
+// is left out; its position in the footer is zero.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use base64::Engine;
+use ocidir::oci_spec::image::{MediaType, Sha256Digest};
+use ocidir::{BlobWriter, Layer};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use super::LayerWriter;
+use crate::format::{Inode, InodeMode};
+use crate::reader::DirEntry;
+
+const MANIFEST_CHECKSUM_ANNOTATION: &str = "io.github.containers.zstd-chunked.manifest-checksum";
+const MANIFEST_POSITION_ANNOTATION: &str = "io.github.containers.zstd-chunked.manifest-position";
+// a TOC in the format of the CRFS/eStargz one
+const MANIFEST_TYPE_CRFS: u64 = 1;
+const SKIPPABLE_FRAME_MAGIC: [u8; 4] = [0x50, 0x2a, 0x4d, 0x18];
+const FOOTER_MAGIC: &[u8; 8] = b"GNUlInUx";
+// the magic and the size of a skippable frame
+const SKIPPABLE_FRAME_HEADER: u64 = 8;
+
+#[derive(Serialize)]
+struct Toc {
+    version: u32,
+    entries: Vec<TocEntry>,
+}
+
+#[derive(Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TocEntry {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    name: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    link_name: String,
+    mode: u32,
+    size: u64,
+    uid: u64,
+    gid: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    dev_major: u32,
+    #[serde(skip_serializing_if = "is_zero")]
+    dev_minor: u32,
+    /// base64 encoded values
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    xattrs: BTreeMap<String, String>,
+    /// of the whole file
+    #[serde(skip_serializing_if = "String::is_empty")]
+    digest: String,
+    /// of the frame holding the chunk, in the blob
+    #[serde(skip_serializing_if = "is_zero")]
+    offset: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    end_offset: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    chunk_size: u64,
+    /// of the chunk, in the file
+    #[serde(skip_serializing_if = "is_zero")]
+    chunk_offset: u64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    chunk_digest: String,
+}
+
+fn is_zero<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+fn sha256_digest(data: impl AsRef<[u8]>) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(data)))
+}
+
+pub(super) struct ZstdChunkedWriter<'a> {
+    blob: BlobWriter<'a>,
+    // the size of the blob so far, i.e. where the next frame starts
+    offset: u64,
+    frame: Option<zstd::Encoder<'static, Vec<u8>>>,
+    // of the uncompressed tar stream
+    diff_id: Sha256,
+    entries: Vec<TocEntry>,
+    // the chunks of the file being appended
+    chunks: Vec<TocEntry>,
+    file_digest: String,
+}
+
+impl<'a> ZstdChunkedWriter<'a> {
+    pub(super) fn new(blob: BlobWriter<'a>) -> Self {
+        ZstdChunkedWriter {
+            blob,
+            offset: 0,
+            frame: None,
+            diff_id: Sha256::new(),
+            entries: Vec::new(),
+            chunks: Vec::new(),
+            file_digest: String::new(),
+        }
+    }
+
+    // Ends the current frame, what's written next starts a new one.
+    fn end_frame(&mut self) -> io::Result<()> {
+        if let Some(frame) = self.frame.take() {
+            let compressed = frame.finish()?;
+            self.blob.write_all(&compressed)?;
+            self.offset += compressed.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn append_skippable_frame(&mut self, data: &[u8]) -> io::Result<()> {
+        self.blob.write_all(&SKIPPABLE_FRAME_MAGIC)?;
+        self.blob.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.blob.write_all(data)?;
+        self.offset += SKIPPABLE_FRAME_HEADER + data.len() as u64;
+        Ok(())
+    }
+
+    /// Writes the TOC and the footer, and puts the blob in place. Returns the annotations the
+    /// layer descriptor needs for runtimes to find the TOC.
+    pub(super) fn complete(mut self) -> anyhow::Result<(Layer, HashMap<String, String>)> {
+        self.end_frame()?;
+        let toc = serde_json::to_vec(&Toc {
+            version: 1,
+            entries: std::mem::take(&mut self.entries),
+        })?;
+        let compressed_toc = zstd::encode_all(&toc[..], 0)?;
+        let toc_offset = self.offset + SKIPPABLE_FRAME_HEADER;
+        self.append_skippable_frame(&compressed_toc)?;
+
+        let mut footer = Vec::with_capacity(64);
+        for field in [
+            toc_offset,
+            compressed_toc.len() as u64,
+            toc.len() as u64,
+            MANIFEST_TYPE_CRFS,
+            // no tar-split
+            0,
+            0,
+            0,
+        ] {
+            footer.extend_from_slice(&field.to_le_bytes());
+        }
+        footer.extend_from_slice(FOOTER_MAGIC);
+        self.append_skippable_frame(&footer)?;
+
+        let annotations = HashMap::from([
+            (
+                MANIFEST_CHECKSUM_ANNOTATION.to_string(),
+                sha256_digest(&compressed_toc),
+            ),
+            (
+                MANIFEST_POSITION_ANNOTATION.to_string(),
+                format!(
+                    "{toc_offset}:{}:{}:{MANIFEST_TYPE_CRFS}",
+                    compressed_toc.len(),
+                    toc.len()
+                ),
+            ),
+        ]);
+        let layer = Layer {
+            blob: self.blob.complete()?,
+            uncompressed_sha256: Sha256Digest::from_str(&hex::encode(self.diff_id.finalize()))?,
+            media_type: MediaType::ImageLayerZstd,
+        };
+        Ok((layer, annotations))
+    }
+}
+
+impl Write for ZstdChunkedWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let frame = match &mut self.frame {
+            Some(frame) => frame,
+            None => self.frame.insert(zstd::Encoder::new(Vec::new(), 0)?),
+        };
+        frame.write_all(data)?;
+        self.diff_id.update(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl LayerWriter for ZstdChunkedWriter<'_> {
+    fn append_file(
+        builder: &mut tar::Builder<Self>,
+        header: &mut tar::Header,
+        path: &Path,
+        entry: &DirEntry,
+    ) -> anyhow::Result<()> {
+        let InodeMode::File { chunks } = &entry.inode.mode else {
+            bail!("{path:?} isn't a file");
+        };
+        // the header only, without padding since no data is written
+        builder.append_data(header, path, io::empty())?;
+
+        let writer = builder.get_mut();
+        let mut reader = entry.open()?;
+        let mut file_digest = Sha256::new();
+        let mut chunk_offset = 0;
+        for chunk in chunks {
+            let mut data = vec![0; chunk.len.try_into()?];
+            reader.read_exact(&mut data)?;
+            file_digest.update(&data);
+
+            writer.end_frame()?;
+            let offset = writer.offset;
+            writer.write_all(&data)?;
+            writer.end_frame()?;
+            writer.chunks.push(TocEntry {
+                kind: "chunk",
+                name: path.to_string_lossy().into_owned(),
+                offset,
+                end_offset: writer.offset,
+                chunk_size: chunk.len,
+                chunk_offset,
+                chunk_digest: sha256_digest(&data),
+                ..Default::default()
+            });
+            chunk_offset += chunk.len;
+        }
+        writer.file_digest = format!("sha256:{}", hex::encode(file_digest.finalize()));
+
+        let padding = (512 - chunk_offset % 512) % 512;
+        writer.write_all(&[0; 512][..padding as usize])?;
+        Ok(())
+    }
+
+    fn appended(
+        &mut self,
+        path: &Path,
+        header: &tar::Header,
+        link: Option<&Path>,
+        inode: &Inode,
+    ) -> anyhow::Result<()> {
+        let kind = match header.entry_type() {
+            tar::EntryType::Regular => "reg",
+            tar::EntryType::Directory => "dir",
+            tar::EntryType::Symlink => "symlink",
+            tar::EntryType::Link => "hardlink",
+            tar::EntryType::Char => "char",
+            tar::EntryType::Block => "block",
+            tar::EntryType::Fifo => "fifo",
+            kind => bail!("unexpected entry type {kind:?}"),
+        };
+        let xattrs = inode
+            .additional
+            .iter()
+            .flat_map(|additional| &additional.xattrs)
+            .map(|xattr| {
+                (
+                    String::from_utf8_lossy(&xattr.key).into_owned(),
+                    base64::engine::general_purpose::STANDARD.encode(&xattr.val),
+                )
+            })
+            .collect();
+        let mut entry = TocEntry {
+            kind,
+            name: path.to_string_lossy().into_owned(),
+            link_name: link
+                .map(|link| link.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            mode: header.mode()?,
+            size: header.size()?,
+            uid: header.uid()?,
+            gid: header.gid()?,
+            dev_major: header.device_major()?.unwrap_or_default(),
+            dev_minor: header.device_minor()?.unwrap_or_default(),
+            xattrs,
+            ..Default::default()
+        };
+
+        if kind != "reg" {
+            self.entries.push(entry);
+            return Ok(());
+        }
+        // the entry of a file describes its first chunk, the others follow it
+        let mut chunks = std::mem::take(&mut self.chunks).into_iter();
+        entry.digest = std::mem::take(&mut self.file_digest);
+        if let Some(first) = chunks.next() {
+            entry.offset = first.offset;
+            entry.end_offset = first.end_offset;
+            entry.chunk_size = first.chunk_size;
+            entry.chunk_digest = first.chunk_digest;
+        }
+        self.entries.push(entry);
+        self.entries.extend(chunks);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use crate::export::{export_oci_image_with_format, ExportFormat};
+    use crate::oci::Image;
+    use crate::reader::{PuzzleFS, WalkPuzzleFS};
+    use ocidir::oci_spec::image::ImageConfiguration;
+    use serde_json::Value;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_zstd_chunked_export() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("puzzlefs"))?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let mut pfs = PuzzleFS::open(Image::open(&dir.path().join("puzzlefs"))?, "test", None)?;
+        let mut chunk_sizes = Vec::new();
+        for entry in WalkPuzzleFS::walk(&mut pfs)? {
+            if let InodeMode::File { chunks } = entry?.inode.mode {
+                chunk_sizes.extend(chunks.iter().map(|chunk| chunk.len));
+            }
+        }
+
+        let oci_dir = dir.path().join("oci");
+        export_oci_image_with_format(image, "test", &oci_dir, ExportFormat::ZstdChunked)?;
+        let exported = Image::open(&oci_dir)?;
+        let manifest = exported.find_manifest("test")?;
+        let layer = &manifest.layers()[0];
+        let blob = fs::read(oci_dir.join("blobs/sha256").join(layer.digest().digest()))?;
+        assert_eq!(&blob[blob.len() - 8..], FOOTER_MAGIC);
+
+        // the stream decompresses to the tar the config points at, TOC and footer aside
+        let config: ImageConfiguration = exported.0.read_json_blob(manifest.config())?;
+        assert_eq!(
+            config.rootfs().diff_ids()[0],
+            sha256_digest(zstd::decode_all(&blob[..])?)
+        );
+
+        let annotations = layer.annotations().as_ref().unwrap();
+        let position = annotations[MANIFEST_POSITION_ANNOTATION]
+            .split(':')
+            .map(|n| n.parse::<usize>())
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let compressed_toc = &blob[position[0]..position[0] + position[1]];
+        assert_eq!(
+            annotations[MANIFEST_CHECKSUM_ANNOTATION],
+            sha256_digest(compressed_toc)
+        );
+        let toc: Value = serde_json::from_slice(&zstd::decode_all(compressed_toc)?)?;
+        assert_eq!(toc["version"], 1);
+
+        // one frame per puzzlefs chunk, each decompressing to the chunk
+        let chunks = toc["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry["name"] == "SekienAkashita.jpg")
+            .collect::<Vec<_>>();
+        assert_eq!(chunks[0]["type"], "reg");
+        assert_eq!(chunks[0]["size"], 109466);
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|chunk| chunk["chunkSize"].as_u64().unwrap())
+                .collect::<Vec<_>>(),
+            chunk_sizes
+        );
+        for chunk in chunks {
+            let start = chunk["offset"].as_u64().unwrap() as usize;
+            let end = chunk["endOffset"].as_u64().unwrap() as usize;
+            let data = zstd::decode_all(&blob[start..end])?;
+            assert_eq!(data.len() as u64, chunk["chunkSize"].as_u64().unwrap());
+            assert_eq!(chunk["chunkDigest"], sha256_digest(data));
+        }
+        Ok(())
+    }
+}
@@ -1,15 +1,20 @@
-use crate::format::InodeMode;
+use crate::builder::enable_verity_for_file;
+use crate::format::{escape_name, Ino, Inode, InodeMode, Timestamp};
+use crate::fsverity_helpers::check_fs_verity;
 use crate::oci::Image;
-use crate::reader::{PuzzleFS, WalkPuzzleFS};
-use log::info;
-use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use crate::reader::{FileReader, PuzzleFS, WalkPuzzleFS};
+use log::{info, warn};
+use nix::sys::stat::{makedev, mknod, utimensat, Mode, SFlag, UtimensatFlags};
+use nix::sys::time::TimeSpec;
 use nix::unistd::{chown, mkfifo, symlinkat, Gid, Uid};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::ffi::OsStr;
 use std::fs::Permissions;
+use std::io::{Read, Seek, SeekFrom};
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 use std::{fs, io};
 
 fn runs_privileged() -> bool {
@@ -69,28 +74,67 @@ fn safe_path(dir: &Path, image_path: &Path) -> anyhow::Result<PathBuf> {
 pub fn extract_rootfs(oci_dir: &str, tag: &str, extract_dir: &str) -> anyhow::Result<()> {
     let oci_dir = Path::new(oci_dir);
     let image = Image::open(oci_dir)?;
-    let dir = Path::new(extract_dir);
-    fs::create_dir_all(dir)?;
-    let mut pfs = PuzzleFS::open(image, tag, None)?;
-    let mut walker = WalkPuzzleFS::walk(&mut pfs)?;
-    let mut host_to_pfs = HashMap::<crate::format::Ino, PathBuf>::new();
+    extract_image(image, tag, extract_dir)
+}
 
-    walker.try_for_each(|de| -> anyhow::Result<()> {
-        let dir_entry = de?;
-        let path = safe_path(dir, &dir_entry.path)?;
+// Sets both the access and the modification time to the mtime, images don't keep access times.
+fn set_mtime(path: &Path, mtime: Timestamp) -> nix::Result<()> {
+    let time = TimeSpec::new(mtime.sec as _, mtime.nsec as _);
+    utimensat(None, path, &time, &time, UtimensatFlags::NoFollowSymlink)
+}
+
+// What extracting the entries of an image keeps track of.
+struct Extraction<'a> {
+    oci: &'a Image,
+    host_to_pfs: HashMap<Ino, PathBuf>,
+    // extracting the contents of a directory changes its mtime, so it's set once they're all there
+    dir_mtimes: Vec<(PathBuf, Timestamp)>,
+    // enable fs-verity on the files which have a digest in the image
+    verity: bool,
+}
+
+impl<'a> Extraction<'a> {
+    fn new(oci: &'a Image, verity: bool) -> Self {
+        Extraction {
+            oci,
+            host_to_pfs: HashMap::new(),
+            dir_mtimes: Vec::new(),
+            verity,
+        }
+    }
+
+    fn extract(&mut self, path: PathBuf, inode: &Inode) -> anyhow::Result<()> {
         let mut is_symlink = false;
         info!("extracting {:#?}", path);
-        if let Some(existing_path) = host_to_pfs.get(&dir_entry.inode.ino) {
+        if let Some(existing_path) = self.host_to_pfs.get(&inode.ino) {
             fs::hard_link(existing_path, &path)?;
             return Ok(());
         }
-        host_to_pfs.insert(dir_entry.inode.ino, path.clone());
+        self.host_to_pfs.insert(inode.ino, path.clone());
 
-        match dir_entry.inode.mode {
-            InodeMode::File { .. } => {
-                let mut reader = dir_entry.open()?;
+        match inode.mode {
+            InodeMode::File { ref chunks } => {
+                let mut reader = FileReader::new(self.oci, inode)?;
                 let mut f = fs::File::create(&path)?;
-                io::copy(&mut reader, &mut f)?;
+                for chunk in chunks {
+                    let mut chunk_reader = (&mut reader).take(chunk.len);
+                    if !chunk.is_hole() {
+                        io::copy(&mut chunk_reader, &mut f)?;
+                    } else {
+                        // recreate the hole instead of writing out the zeros
+                        io::copy(&mut chunk_reader, &mut io::sink())?;
+                        f.seek(SeekFrom::Current(chunk.len as i64))?;
+                    }
+                }
+                // a trailing hole only moved the file offset, this sets the size
+                f.set_len(chunks.iter().map(|c| c.len).sum())?;
+                // fs-verity can't be enabled while the file is open for writing
+                drop(f);
+                if let (true, Some(expected)) = (self.verity, &inode.verity) {
+                    let file = cap_std::fs::File::from_std(fs::File::open(&path)?);
+                    enable_verity_for_file(&file)?;
+                    check_fs_verity(&file, expected)?;
+                }
             }
             InodeMode::Dir { .. } => fs::create_dir_all(&path)?,
             // TODO: fix all the hard coded modes when we have modes
@@ -104,48 +148,212 @@ pub fn extract_rootfs(oci_dir: &str, tag: &str, extract_dir: &str) -> anyhow::Re
                 mknod(&path, SFlag::S_IFBLK, Mode::S_IRWXU, makedev(major, minor))?;
             }
             InodeMode::Lnk => {
-                let target = dir_entry.inode.symlink_target()?;
+                let target = inode.symlink_target()?;
                 is_symlink = true;
                 symlinkat(target, None, &path)?;
             }
+            // mknod creates the socket file without binding it: binding fails on paths longer
+            // than a socket address can hold
             InodeMode::Sock => {
-                todo!();
-            }
-            InodeMode::Wht => {
-                todo!();
+                mknod(&path, SFlag::S_IFSOCK, Mode::S_IRWXU, 0)?;
             }
             _ => {
-                bail!("bad inode mode {:#?}", dir_entry.inode.mode)
+                bail!("bad inode mode {:#?}", inode.mode)
             }
         }
-        if let Some(x) = dir_entry.inode.additional {
-            for x in &x.xattrs {
-                xattr::set(&path, OsStr::from_bytes(&x.key), &x.val)?;
-            }
-        }
-
         // trying to change permissions for a symlink would follow the symlink and we might not have extracted the target yet
         // anyway, symlink permissions are not used in Linux (although they are used in macOS and FreeBSD)
         if !is_symlink {
-            std::fs::set_permissions(
-                &path,
-                Permissions::from_mode(dir_entry.inode.permissions.into()),
-            )?;
+            std::fs::set_permissions(&path, Permissions::from_mode(inode.permissions.into()))?;
         }
 
         if runs_privileged() {
             chown(
                 &path,
-                Some(Uid::from_raw(dir_entry.inode.uid)),
-                Some(Gid::from_raw(dir_entry.inode.gid)),
+                Some(Uid::from_raw(inode.uid)),
+                Some(Gid::from_raw(inode.gid)),
             )?;
         }
 
+        // xattrs go last: a chown drops the file capabilities, and a chmod would rewrite the
+        // mask entry of the ACL
+        if let Some(additional) = &inode.additional {
+            let privileged = runs_privileged();
+            for x in &additional.xattrs {
+                if !x.is_visible(privileged) {
+                    warn!(
+                        "not extracting xattr {} of {:?}, which takes privileges",
+                        escape_name(&x.key),
+                        path
+                    );
+                    continue;
+                }
+                xattr::set(&path, OsStr::from_bytes(&x.key), &x.val)?;
+            }
+        }
+
+        if let Some(mtime) = inode.mtime {
+            if let InodeMode::Dir { .. } = inode.mode {
+                self.dir_mtimes.push((path, mtime));
+            } else {
+                set_mtime(&path, mtime)?;
+            }
+        }
+
         Ok(())
-    })?;
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        for (path, mtime) in self.dir_mtimes {
+            set_mtime(&path, mtime)?;
+        }
+        Ok(())
+    }
+}
+
+const TRUSTED_OVERLAY_OPAQUE: &str = "trusted.overlay.opaque";
+const USER_OVERLAY_OPAQUE: &str = "user.overlay.opaque";
+const USER_OVERLAY_WHITEOUT: &str = "user.overlay.whiteout";
+
+// Marks `path` deleted the way overlayfs does: with a 0/0 character device, or where device
+// nodes can't be created, with an empty file carrying the whiteout xattr, which overlayfs only
+// looks for in directories whose opaque xattr is "x".
+fn overlay_whiteout(path: &Path) -> anyhow::Result<()> {
+    if runs_privileged() {
+        mknod(path, SFlag::S_IFCHR, Mode::empty(), makedev(0, 0))?;
+        return Ok(());
+    }
+
+    fs::File::create(path)?;
+    xattr::set(path, USER_OVERLAY_WHITEOUT, b"")?;
+    // .unwrap() is fine, the root directory is never deleted
+    let parent = path.parent().unwrap();
+    if xattr::get(parent, USER_OVERLAY_OPAQUE)?.is_none() {
+        xattr::set(parent, USER_OVERLAY_OPAQUE, b"x")?;
+    }
     Ok(())
 }
 
+// Keeps overlayfs from merging the directory at `path` with the ones of the lower layers.
+fn overlay_opaque(path: &Path) -> anyhow::Result<()> {
+    let key = if runs_privileged() {
+        TRUSTED_OVERLAY_OPAQUE
+    } else {
+        USER_OVERLAY_OPAQUE
+    };
+    xattr::set(path, key, b"y")?;
+    Ok(())
+}
+
+/// Like [`extract_rootfs`], for an image which is already open, e.g. one with a decryption key.
+pub fn extract_image(image: Image, tag: &str, extract_dir: &str) -> anyhow::Result<()> {
+    extract(image, tag, extract_dir, false)
+}
+
+/// Like [`extract_image`], enabling fs-verity on the extracted files whose digest the image
+/// stores (see [`crate::builder::BuildOptions::file_verity`]) and checking the digest the
+/// kernel measures against it. The kernel builds the Merkle trees as it enables fs-verity, so
+/// the files aren't hashed another time. The extract dir must be on a filesystem supporting
+/// fs-verity.
+pub fn extract_image_with_verity(image: Image, tag: &str, extract_dir: &str) -> anyhow::Result<()> {
+    extract(image, tag, extract_dir, true)
+}
+
+fn extract(image: Image, tag: &str, extract_dir: &str, verity: bool) -> anyhow::Result<()> {
+    let dir = Path::new(extract_dir);
+    fs::create_dir_all(dir)?;
+    let mut pfs = PuzzleFS::open(image, tag, None)?;
+    let oci = Arc::clone(&pfs.oci);
+    let mut extraction = Extraction::new(&oci, verity);
+
+    WalkPuzzleFS::walk(&mut pfs)?.try_for_each(|de| -> anyhow::Result<()> {
+        let dir_entry = de?;
+        let path = safe_path(dir, &dir_entry.path)?;
+        extraction.extract(path, &dir_entry.inode)
+    })?;
+
+    extraction.finish()
+}
+
+/// Extracts only what the delta image `tag` changes in the image it was built on, as an
+/// overlayfs upper directory: the entries it deletes become whiteouts, and the directories whose
+/// entries from the base image it hides otherwise are marked opaque. An overlay of it on top of
+/// the extracted base image has the same contents as `tag`. Without root, the whiteouts and
+/// opaque markers are user.overlay xattrs, for overlays mounted with the userxattr option.
+pub fn extract_delta(image: Image, tag: &str, extract_dir: &str) -> anyhow::Result<()> {
+    let dir = Path::new(extract_dir);
+    fs::create_dir_all(dir)?;
+    let pfs = PuzzleFS::open(image, tag, None)?;
+    let mut extraction = Extraction::new(&pfs.oci, false);
+    // the directories which are only there to hold the changes below them
+    let mut skeleton = Vec::new();
+
+    // (path in the image, inode, whether nothing of the base image shows through the path, so
+    // everything below it is extracted)
+    let mut q = VecDeque::from([(PathBuf::from("/"), 1, false)]);
+    while let Some((image_path, ino, full)) = q.pop_front() {
+        let path = safe_path(dir, &image_path)?;
+        let inode = pfs.find_inode(ino)?;
+        let InodeMode::Dir { ref dir_list } = inode.mode else {
+            unreachable!("only directories are queued");
+        };
+
+        let changed = pfs.delta_inode(ino)?.is_some();
+        let base_names = match pfs.base_inode(ino)? {
+            Some(Inode {
+                mode: InodeMode::Dir { dir_list: base },
+                ..
+            }) => base.entries.into_iter().map(|e| e.name).collect(),
+            _ => Vec::new(),
+        };
+        // the directory drops entries of the base image without whiteouts for them
+        let opaque = !full
+            && changed
+            && base_names
+                .iter()
+                .any(|name| !dir_list.entries.iter().any(|e| &e.name == name));
+        let full = full || opaque;
+
+        if changed || full {
+            extraction.extract(path.clone(), &inode)?;
+        } else {
+            fs::create_dir_all(&path)?;
+            if ino != 1 {
+                skeleton.push(path.clone());
+            }
+        }
+        if opaque {
+            overlay_opaque(&path)?;
+        }
+
+        for entry in &dir_list.entries {
+            let entry_path = image_path.join(OsStr::from_bytes(&entry.name));
+            let delta = pfs.delta_inode(entry.ino)?;
+            let Some(child) = pfs.lookup_inode(entry.ino)? else {
+                // deleted by this image, or by the base one already
+                let deleted = matches!(delta.map(|d| d.mode), Some(InodeMode::Wht));
+                if deleted && !full && base_names.contains(&entry.name) {
+                    overlay_whiteout(&safe_path(dir, &entry_path)?)?;
+                }
+                continue;
+            };
+            if let InodeMode::Dir { .. } = child.mode {
+                q.push_back((entry_path, entry.ino, full));
+            } else if full || delta.is_some() {
+                extraction.extract(safe_path(dir, &entry_path)?, &child)?;
+            }
+        }
+    }
+
+    // deepest first, so that the directories only holding empty ones go too
+    for path in skeleton.iter().rev() {
+        if fs::read_dir(path)?.next().is_none() {
+            fs::remove_dir(path)?;
+        }
+    }
+    extraction.finish()
+}
+
 #[cfg(test)]
 mod tests {
     use tempfile::{tempdir, TempDir};
@@ -252,6 +460,49 @@ mod tests {
         assert_eq!(metadata.permissions().mode() & 0xFFF, TESTED_PERMISSION);
     }
 
+    #[test]
+    fn test_timestamps() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        let extract_dir = tempdir().unwrap();
+
+        fs::create_dir_all(rootfs.join("dir")).unwrap();
+        fs::write(rootfs.join("dir/file"), b"file").unwrap();
+        std::os::unix::fs::symlink("file", rootfs.join("dir/link")).unwrap();
+        let file_mtime = Timestamp {
+            sec: 1_000_000_000,
+            nsec: 123_456_789,
+        };
+        let dir_mtime = Timestamp {
+            sec: 86400,
+            nsec: 0,
+        };
+        set_mtime(&rootfs.join("dir/file"), file_mtime).unwrap();
+        set_mtime(&rootfs.join("dir/link"), file_mtime).unwrap();
+        set_mtime(&rootfs.join("dir"), dir_mtime).unwrap();
+
+        build_test_fs(&rootfs, &image, "test").unwrap();
+        extract_rootfs(
+            oci_dir.to_str().unwrap(),
+            "test",
+            extract_dir.path().to_str().unwrap(),
+        )
+        .unwrap();
+
+        let mtime = |path: &str| {
+            let md = fs::symlink_metadata(extract_dir.path().join(path)).unwrap();
+            Timestamp {
+                sec: md.mtime(),
+                nsec: md.mtime_nsec() as u32,
+            }
+        };
+        assert_eq!(mtime("dir/file"), file_mtime);
+        assert_eq!(mtime("dir/link"), file_mtime);
+        assert_eq!(mtime("dir"), dir_mtime);
+    }
+
     #[test]
     fn test_hardlink_extraction() {
         let dir = tempdir().unwrap();
@@ -314,4 +565,158 @@ mod tests {
         let extracted_foo = extract_dir.path().join("foo");
         assert_eq!(extracted_foo.metadata().unwrap().len(), 0);
     }
+
+    #[test]
+    fn test_fifos_and_sockets() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir)?;
+        let rootfs = dir.path().join("rootfs");
+        let extract_dir = tempdir()?;
+        // longer than a socket address can hold
+        let deep = "d".repeat(120);
+
+        fs::create_dir_all(rootfs.join(&deep))?;
+        mkfifo(&rootfs.join("fifo"), Mode::S_IRWXU)?;
+        let socket = rootfs.join(&deep).join("socket");
+        mknod(&socket, SFlag::S_IFSOCK, Mode::S_IRWXU, 0)?;
+        fs::set_permissions(rootfs.join("fifo"), Permissions::from_mode(0o640))?;
+        fs::set_permissions(&socket, Permissions::from_mode(0o751))?;
+
+        build_test_fs(&rootfs, &image, "test")?;
+        extract_rootfs(
+            oci_dir.to_str().unwrap(),
+            "test",
+            extract_dir.path().to_str().unwrap(),
+        )?;
+
+        let socket_path = format!("{deep}/socket");
+        for (path, mode) in [("fifo", 0o010640), (socket_path.as_str(), 0o140751)] {
+            let original = fs::symlink_metadata(rootfs.join(path))?;
+            let extracted = fs::symlink_metadata(extract_dir.path().join(path))?;
+            assert_eq!(extracted.mode(), mode, "{path}");
+            assert_eq!(
+                (extracted.uid(), extracted.gid()),
+                (original.uid(), original.gid())
+            );
+            assert_eq!(extracted.mtime(), original.mtime());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_delta() -> anyhow::Result<()> {
+        use crate::builder::{add_rootfs_delta_with_options, build_initial_rootfs, BuildOptions};
+        use crate::compression::Noop;
+        use std::os::unix::fs::FileTypeExt;
+
+        let dir = TempDir::new_in(".")?;
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir)?;
+
+        let base = dir.path().join("base");
+        fs::create_dir_all(base.join("etc"))?;
+        fs::write(base.join("etc/passwd"), b"root")?;
+        fs::write(base.join("etc/shadow"), b"secret")?;
+        fs::write(base.join("keep"), b"keep")?;
+        build_initial_rootfs::<Noop>(&base, &image, "base")?;
+
+        let diff = dir.path().join("diff");
+        fs::create_dir_all(diff.join("etc"))?;
+        fs::write(diff.join("etc/.wh.shadow"), b"")?;
+        fs::write(diff.join("etc/hostname"), b"puzzlefs")?;
+        let options = BuildOptions {
+            layer_diff: true,
+            ..Default::default()
+        };
+        add_rootfs_delta_with_options::<Noop>(&diff, image, "diff", "base", &options)?;
+
+        let upper = dir.path().join("upper");
+        extract_delta(Image::open(&oci_dir)?, "diff", upper.to_str().unwrap())?;
+        assert_eq!(fs::read(upper.join("etc/hostname"))?, b"puzzlefs");
+        assert!(!upper.join("etc/passwd").exists());
+        assert!(!upper.join("keep").exists());
+        let shadow = upper.join("etc/shadow");
+        if runs_privileged() {
+            let md = fs::symlink_metadata(&shadow)?;
+            assert!(md.file_type().is_char_device() && md.rdev() == 0);
+        } else {
+            assert!(xattr::get(&shadow, USER_OVERLAY_WHITEOUT)?.is_some());
+        }
+
+        // building the upper directory as a layer diff on the base gives the same image back
+        let image = Image::open(&oci_dir)?;
+        add_rootfs_delta_with_options::<Noop>(&upper, image, "rebuilt", "base", &options)?;
+        let pfs = PuzzleFS::open(Image::open(&oci_dir)?, "rebuilt", None)?;
+        for present in ["/keep", "/etc/passwd", "/etc/hostname"] {
+            assert!(pfs.lookup(Path::new(present))?.is_some(), "{present}");
+        }
+        assert!(pfs.lookup(Path::new("/etc/shadow"))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_device_nodes() -> anyhow::Result<()> {
+        use nix::sys::stat::{major, minor};
+        use std::os::unix::fs::FileTypeExt;
+
+        // only root can create device nodes
+        if !runs_privileged() {
+            return Ok(());
+        }
+        let dir = tempdir()?;
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir)?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        // numbers which don't fit the old 8-bit encoding of rdev, to check FUSE gets them right
+        let devices = [
+            ("null", SFlag::S_IFCHR, 1, 3),
+            ("disk", SFlag::S_IFBLK, 259, 70000),
+        ];
+        for (name, kind, major, minor) in devices {
+            mknod(
+                &rootfs.join(name),
+                kind,
+                Mode::S_IRWXU,
+                makedev(major, minor),
+            )?;
+        }
+        build_test_fs(&rootfs, &image, "test")?;
+
+        let check = |dir: &Path| -> anyhow::Result<()> {
+            for (name, kind, major_num, minor_num) in devices {
+                let md = fs::symlink_metadata(dir.join(name))?;
+                let file_type = md.file_type();
+                if kind == SFlag::S_IFCHR {
+                    assert!(file_type.is_char_device(), "{name}");
+                } else {
+                    assert!(file_type.is_block_device(), "{name}");
+                }
+                assert_eq!((major(md.rdev()), minor(md.rdev())), (major_num, minor_num));
+            }
+            Ok(())
+        };
+
+        let extract_dir = tempdir()?;
+        extract_rootfs(
+            oci_dir.to_str().unwrap(),
+            "test",
+            extract_dir.path().to_str().unwrap(),
+        )?;
+        check(extract_dir.path())?;
+
+        let mountpoint = tempdir()?;
+        let _bg = crate::reader::spawn_mount::<&str>(
+            Image::open(&oci_dir)?,
+            "test",
+            mountpoint.path(),
+            &[],
+            None,
+            None,
+            None,
+            None,
+        )?;
+        check(mountpoint.path())
+    }
 }
@@ -1,5 +1,28 @@
 mod types;
 pub use types::*;
 
+mod features;
+pub use features::*;
+
+mod acl;
+pub use acl::*;
+
+mod xattr_namespace;
+pub use xattr_namespace::*;
+
+mod file_digest;
+pub use file_digest::*;
+
+mod limits;
+pub use limits::*;
+
+mod casefold;
+pub use casefold::*;
+
+mod names;
+pub use names::*;
+
+mod json;
+
 mod error;
 pub use error::*;
@@ -0,0 +1,322 @@
+// POSIX ACLs. The kernel exposes them as the system.posix_acl_access and
+// system.posix_acl_default xattrs, whose values are binary structures: a version header followed
+// by (tag, permissions, id) entries. Images store them as typed entries rather than those bytes,
+// so that malformed ones are caught when building, and readers get them back in the one encoding
+// the kernel accepts.
+
+use std::backtrace::Backtrace;
+
+use super::error::{Result, WireFormatError};
+
+pub const ACL_ACCESS_XATTR: &[u8] = b"system.posix_acl_access";
+pub const ACL_DEFAULT_XATTR: &[u8] = b"system.posix_acl_default";
+
+const ACL_XATTR_VERSION: u32 = 2;
+const ACL_HEADER_SIZE: usize = 4;
+const ACL_ENTRY_SIZE: usize = 8;
+// the id of the entries which aren't for a named user or group
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+pub fn is_acl_xattr(key: &[u8]) -> bool {
+    key == ACL_ACCESS_XATTR || key == ACL_DEFAULT_XATTR
+}
+
+/// The kind of an ACL entry, in the order the kernel wants them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AclTag {
+    UserObj,
+    User,
+    GroupObj,
+    Group,
+    Mask,
+    Other,
+}
+
+impl AclTag {
+    fn from_raw(tag: u16) -> Option<Self> {
+        match tag {
+            0x01 => Some(AclTag::UserObj),
+            0x02 => Some(AclTag::User),
+            0x04 => Some(AclTag::GroupObj),
+            0x08 => Some(AclTag::Group),
+            0x10 => Some(AclTag::Mask),
+            0x20 => Some(AclTag::Other),
+            _ => None,
+        }
+    }
+
+    fn raw(self) -> u16 {
+        match self {
+            AclTag::UserObj => 0x01,
+            AclTag::User => 0x02,
+            AclTag::GroupObj => 0x04,
+            AclTag::Group => 0x08,
+            AclTag::Mask => 0x10,
+            AclTag::Other => 0x20,
+        }
+    }
+
+    fn is_named(self) -> bool {
+        matches!(self, AclTag::User | AclTag::Group)
+    }
+
+    fn from_capnp(tag: crate::metadata_capnp::AclTag) -> Self {
+        match tag {
+            crate::metadata_capnp::AclTag::UserObj => AclTag::UserObj,
+            crate::metadata_capnp::AclTag::User => AclTag::User,
+            crate::metadata_capnp::AclTag::GroupObj => AclTag::GroupObj,
+            crate::metadata_capnp::AclTag::Group => AclTag::Group,
+            crate::metadata_capnp::AclTag::Mask => AclTag::Mask,
+            crate::metadata_capnp::AclTag::Other => AclTag::Other,
+        }
+    }
+
+    fn to_capnp(self) -> crate::metadata_capnp::AclTag {
+        match self {
+            AclTag::UserObj => crate::metadata_capnp::AclTag::UserObj,
+            AclTag::User => crate::metadata_capnp::AclTag::User,
+            AclTag::GroupObj => crate::metadata_capnp::AclTag::GroupObj,
+            AclTag::Group => crate::metadata_capnp::AclTag::Group,
+            AclTag::Mask => crate::metadata_capnp::AclTag::Mask,
+            AclTag::Other => crate::metadata_capnp::AclTag::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    /// the uid or gid of named user and group entries, ignored for the others
+    pub id: u32,
+    /// read (4), write (2) and execute (1)
+    pub perm: u16,
+}
+
+/// A valid ACL: one entry for the owner, the owning group and the others each, any number of
+/// entries for named users and groups, with a mask limiting them if there are some.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl {
+    entries: Vec<AclEntry>,
+}
+
+fn invalid(message: String) -> WireFormatError {
+    WireFormatError::InvalidAcl(message, Backtrace::capture())
+}
+
+impl Acl {
+    /// Sorts and checks the entries. Named entries for the same id are merged, which happens
+    /// when ids are mapped to the same one.
+    pub fn new(mut entries: Vec<AclEntry>) -> Result<Self> {
+        for entry in &mut entries {
+            if !entry.tag.is_named() {
+                entry.id = ACL_UNDEFINED_ID;
+            }
+            if entry.perm & !0o7 != 0 {
+                return Err(invalid(format!("invalid permissions {:#o}", entry.perm)));
+            }
+        }
+        entries.sort_unstable();
+        entries.dedup_by(|entry, kept| {
+            let merge = entry.tag.is_named() && (entry.tag, entry.id) == (kept.tag, kept.id);
+            if merge {
+                kept.perm |= entry.perm;
+            }
+            merge
+        });
+
+        let count = |tag| entries.iter().filter(|e| e.tag == tag).count();
+        for tag in [AclTag::UserObj, AclTag::GroupObj, AclTag::Other] {
+            if count(tag) != 1 {
+                return Err(invalid(format!(
+                    "{} {tag:?} entries, expected one",
+                    count(tag)
+                )));
+            }
+        }
+        let named = count(AclTag::User) + count(AclTag::Group);
+        match count(AclTag::Mask) {
+            0 if named > 0 => return Err(invalid("named entries without a mask".to_string())),
+            0 | 1 => {}
+            n => return Err(invalid(format!("{n} mask entries"))),
+        }
+        Ok(Acl { entries })
+    }
+
+    pub fn entries(&self) -> &[AclEntry] {
+        &self.entries
+    }
+
+    /// Parses the value of an ACL xattr.
+    pub fn from_xattr(val: &[u8]) -> Result<Self> {
+        if val.len() < ACL_HEADER_SIZE || (val.len() - ACL_HEADER_SIZE) % ACL_ENTRY_SIZE != 0 {
+            return Err(invalid(format!("invalid size {}", val.len())));
+        }
+        let version = u32::from_le_bytes(val[..ACL_HEADER_SIZE].try_into().unwrap());
+        if version != ACL_XATTR_VERSION {
+            return Err(invalid(format!("unknown version {version}")));
+        }
+
+        let entries = val[ACL_HEADER_SIZE..]
+            .chunks_exact(ACL_ENTRY_SIZE)
+            .map(|e| {
+                let tag = u16::from_le_bytes([e[0], e[1]]);
+                Ok(AclEntry {
+                    tag: AclTag::from_raw(tag)
+                        .ok_or_else(|| invalid(format!("unknown tag {tag:#x}")))?,
+                    perm: u16::from_le_bytes([e[2], e[3]]),
+                    id: u32::from_le_bytes([e[4], e[5], e[6], e[7]]),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(entries)
+    }
+
+    /// The value of the ACL xattr, as the kernel expects it.
+    pub fn to_xattr(&self) -> Vec<u8> {
+        let mut val = Vec::with_capacity(ACL_HEADER_SIZE + self.entries.len() * ACL_ENTRY_SIZE);
+        val.extend_from_slice(&ACL_XATTR_VERSION.to_le_bytes());
+        for entry in &self.entries {
+            val.extend_from_slice(&entry.tag.raw().to_le_bytes());
+            val.extend_from_slice(&entry.perm.to_le_bytes());
+            val.extend_from_slice(&entry.id.to_le_bytes());
+        }
+        val
+    }
+
+    /// Translates the ids of the named user and group entries.
+    pub fn map_ids(self, uid: impl Fn(u32) -> u32, gid: impl Fn(u32) -> u32) -> Result<Self> {
+        let entries = self
+            .entries
+            .into_iter()
+            .map(|entry| AclEntry {
+                id: match entry.tag {
+                    AclTag::User => uid(entry.id),
+                    AclTag::Group => gid(entry.id),
+                    _ => entry.id,
+                },
+                ..entry
+            })
+            .collect();
+        Self::new(entries)
+    }
+
+    pub fn from_capnp(
+        reader: capnp::struct_list::Reader<'_, crate::metadata_capnp::acl_entry::Owned>,
+    ) -> Result<Self> {
+        let entries = reader
+            .iter()
+            .map(|entry| {
+                Ok(AclEntry {
+                    tag: AclTag::from_capnp(entry.get_tag().map_err(capnp::Error::from)?),
+                    id: entry.get_id(),
+                    perm: entry.get_perm(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(entries)
+    }
+
+    pub fn fill_capnp(
+        &self,
+        builder: &mut capnp::struct_list::Builder<'_, crate::metadata_capnp::acl_entry::Owned>,
+    ) {
+        for (i, entry) in self.entries.iter().enumerate() {
+            // the list was initialized with the number of entries
+            let mut entry_builder = builder.reborrow().get(i as u32);
+            entry_builder.set_tag(entry.tag.to_capnp());
+            if entry.tag.is_named() {
+                entry_builder.set_id(entry.id);
+            }
+            entry_builder.set_perm(entry.perm);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xattr(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut val = ACL_XATTR_VERSION.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            val.extend_from_slice(&tag.to_le_bytes());
+            val.extend_from_slice(&perm.to_le_bytes());
+            val.extend_from_slice(&id.to_le_bytes());
+        }
+        val
+    }
+
+    #[test]
+    fn test_acl_encoding() {
+        const UNDEFINED: u32 = ACL_UNDEFINED_ID;
+        // out of order, with ids where there shouldn't be any
+        let acl = Acl::from_xattr(&xattr(&[
+            (0x20, 4, 0),
+            (0x08, 5, 100),
+            (0x01, 7, 12),
+            (0x10, 7, UNDEFINED),
+            (0x04, 5, UNDEFINED),
+        ]))
+        .unwrap();
+        assert_eq!(
+            acl.to_xattr(),
+            xattr(&[
+                (0x01, 7, UNDEFINED),
+                (0x04, 5, UNDEFINED),
+                (0x08, 5, 100),
+                (0x10, 7, UNDEFINED),
+                (0x20, 4, UNDEFINED),
+            ])
+        );
+
+        // mapping two groups to the same id merges their entries
+        let acl = Acl::from_xattr(&xattr(&[
+            (0x01, 7, UNDEFINED),
+            (0x04, 5, UNDEFINED),
+            (0x08, 4, 100),
+            (0x08, 1, 200),
+            (0x10, 7, UNDEFINED),
+            (0x20, 4, UNDEFINED),
+        ]))
+        .unwrap()
+        .map_ids(|uid| uid, |_| 65534)
+        .unwrap();
+        assert_eq!(
+            acl.entries()[2],
+            AclEntry {
+                tag: AclTag::Group,
+                id: 65534,
+                perm: 5
+            }
+        );
+
+        for bad in [
+            // truncated
+            xattr(&[(0x01, 7, UNDEFINED)])[..10].to_vec(),
+            // no entry for the others
+            xattr(&[(0x01, 7, UNDEFINED), (0x04, 5, UNDEFINED)]),
+            // named user without a mask
+            xattr(&[
+                (0x01, 7, UNDEFINED),
+                (0x02, 7, 1000),
+                (0x04, 5, UNDEFINED),
+                (0x20, 4, UNDEFINED),
+            ]),
+            // unknown tag
+            xattr(&[
+                (0x01, 7, UNDEFINED),
+                (0x04, 5, UNDEFINED),
+                (0x20, 4, UNDEFINED),
+                (0x40, 4, UNDEFINED),
+            ]),
+            // bad permissions
+            xattr(&[
+                (0x01, 0o17, UNDEFINED),
+                (0x04, 5, UNDEFINED),
+                (0x20, 4, UNDEFINED),
+            ]),
+        ] {
+            assert!(Acl::from_xattr(&bad).is_err(), "accepted {bad:?}");
+        }
+    }
+}
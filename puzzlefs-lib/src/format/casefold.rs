@@ -0,0 +1,68 @@
+// Case-insensitive names, for images built with [`crate::builder::BuildOptions::casefold`]: the
+// names are stored as they are, and lookups which don't find an exact match compare them folded,
+// like ext4 and tmpfs do for casefolded directories.
+
+use std::borrow::Cow;
+
+use unicode_normalization::UnicodeNormalization;
+
+use super::types::DirEnt;
+
+/// The name lookups in a casefolded image compare: lowercased and NFC normalized, so that e.g.
+/// `Café` (with a precomposed é) and `CAFE\u{301}` (with a combining accent) match. Names which
+/// aren't valid UTF-8 are compared as they are.
+pub fn casefold(name: &[u8]) -> Cow<'_, [u8]> {
+    match std::str::from_utf8(name) {
+        Ok(s) if s.is_ascii() => Cow::Owned(s.to_ascii_lowercase().into_bytes()),
+        Ok(s) => Cow::Owned(
+            s.chars()
+                .flat_map(char::to_lowercase)
+                .nfc()
+                .collect::<String>()
+                .into_bytes(),
+        ),
+        Err(_) => Cow::Borrowed(name),
+    }
+}
+
+/// Two entries of a directory whose names only differ by case or normalization, which a
+/// casefolded image can't hold since lookups couldn't tell them apart.
+pub(crate) fn find_collision(entries: &[DirEnt]) -> Option<(&DirEnt, &DirEnt)> {
+    let mut folded = entries
+        .iter()
+        .map(|entry| (casefold(&entry.name), entry))
+        .collect::<Vec<_>>();
+    folded.sort_by(|a, b| a.0.cmp(&b.0));
+    folded
+        .windows(2)
+        .find(|w| w[0].0 == w[1].0)
+        .map(|w| (w[0].1, w[1].1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_casefold() {
+        assert_eq!(casefold(b"README.md"), casefold(b"readme.MD"));
+        assert_eq!(
+            casefold("CAFE\u{301}".as_bytes()),
+            casefold("caf\u{e9}".as_bytes())
+        );
+        assert_ne!(casefold(b"a"), casefold(b"b"));
+        assert_eq!(&*casefold(b"\xffA"), b"\xffA");
+
+        let entries = ["Makefile", "makefile", "src"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| DirEnt {
+                ino: i as u64 + 2,
+                name: name.as_bytes().to_vec(),
+            })
+            .collect::<Vec<_>>();
+        let (a, b) = find_collision(&entries).unwrap();
+        assert_eq!((a.ino, b.ino), (2, 3));
+        assert!(find_collision(&entries[1..]).is_none());
+    }
+}
@@ -1,6 +1,7 @@
 use std::backtrace::Backtrace;
 use std::io;
 use std::os::raw::c_int;
+use std::time::Duration;
 
 use nix::errno::Errno;
 use thiserror::Error;
@@ -17,12 +18,66 @@ pub enum WireFormatError {
     InvalidImageSchema(i32, Backtrace),
     #[error("invalid image version: {0}")]
     InvalidImageVersion(String, Backtrace),
+    #[error("image uses features this puzzlefs doesn't support: {flag}")]
+    UnsupportedFeature {
+        flag: super::Features,
+        backtrace: Backtrace,
+    },
+    #[error("missing blob {digest}")]
+    BlobMissing {
+        digest: super::Digest,
+        backtrace: Backtrace,
+    },
+    #[error("digest mismatch: expected {expected}, found {found}")]
+    DigestMismatch {
+        expected: super::Digest,
+        found: super::Digest,
+        backtrace: Backtrace,
+    },
+    #[error("metadata truncated at offset {offset}")]
+    TruncatedMetadata { offset: u64, backtrace: Backtrace },
     #[error("invalid fs_verity data: {0}")]
     InvalidFsVerityData(String, Backtrace),
     #[error("missing manifest: {0}")]
     MissingManifest(String, Backtrace),
     #[error("missing PuzzleFS rootfs")]
     MissingRootfs(Backtrace),
+    #[error("corrupted chunk: {0}")]
+    CorruptedChunk(String, Backtrace),
+    #[error("metadata over the limits: {0}")]
+    LimitExceeded(String, Backtrace),
+    #[error("invalid ACL: {0}")]
+    InvalidAcl(String, Backtrace),
+    #[error("invalid build options: {0}")]
+    InvalidBuildOptions(String, Backtrace),
+    #[error("name collision: {0}")]
+    NameCollision(String, Backtrace),
+    #[error("out of inode numbers: {0}")]
+    InodeOverflow(String, Backtrace),
+    #[error("inode collision: {0}")]
+    InodeCollision(String, Backtrace),
+    #[error("invalid name: {0}")]
+    InvalidName(String, Backtrace),
+    #[error("invalid symlink target: {0}")]
+    InvalidSymlinkTarget(String, Backtrace),
+    #[error("invalid platform: {0}")]
+    InvalidPlatform(String, Backtrace),
+    #[error("encryption error: {0}")]
+    EncryptionError(String, Backtrace),
+    #[error("missing encryption key: {0}")]
+    MissingKey(String, Backtrace),
+    #[error("wrong encryption key: {0}")]
+    WrongKey(String, Backtrace),
+    #[error("zstd dictionary error: {0}")]
+    DictionaryError(String, Backtrace),
+    #[error("registry error: {0}")]
+    RegistryError(String, Backtrace),
+    #[error("http error: {0}")]
+    HttpError(String, Backtrace),
+    #[error("signature error: {0}")]
+    SignatureError(String, Backtrace),
+    #[error("timed out after {0:?} waiting for the lock on the layout")]
+    LockTimeout(Duration, Backtrace),
     #[error("fs error: {0}")]
     IOError(#[from] io::Error, Backtrace),
     #[error("deserialization error (capnp): {0}")]
@@ -42,6 +97,15 @@ pub enum WireFormatError {
 }
 
 impl WireFormatError {
+    /// The errno the FUSE filesystem replies with. The mapping is stable, in particular:
+    /// - [`WireFormatError::BlobMissing`] and [`WireFormatError::DigestMismatch`] are `EIO`, like
+    ///   the kernel does for data it can't read or verify;
+    /// - [`WireFormatError::TruncatedMetadata`] is `EUCLEAN`, the kernel's `EFSCORRUPTED`;
+    /// - [`WireFormatError::UnsupportedFeature`] is `EOPNOTSUPP`;
+    /// - [`WireFormatError::MissingKey`] and [`WireFormatError::WrongKey`] are `ENOKEY` and
+    ///   `EKEYREJECTED`, while chunks which don't decrypt with the right key are
+    ///   [`WireFormatError::CorruptedChunk`], `EIO`;
+    /// - [`WireFormatError::IOError`] is the errno of the underlying error.
     pub fn to_errno(&self) -> c_int {
         match self {
             WireFormatError::LocalRefError(..) => Errno::EINVAL as c_int,
@@ -49,9 +113,31 @@ impl WireFormatError {
             WireFormatError::InvalidSerializedData(..) => Errno::EINVAL as c_int,
             WireFormatError::InvalidImageSchema(..) => Errno::EINVAL as c_int,
             WireFormatError::InvalidImageVersion(..) => Errno::EINVAL as c_int,
+            WireFormatError::UnsupportedFeature { .. } => Errno::EOPNOTSUPP as c_int,
+            WireFormatError::BlobMissing { .. } => Errno::EIO as c_int,
+            WireFormatError::DigestMismatch { .. } => Errno::EIO as c_int,
+            WireFormatError::TruncatedMetadata { .. } => Errno::EUCLEAN as c_int,
             WireFormatError::InvalidFsVerityData(..) => Errno::EINVAL as c_int,
             WireFormatError::MissingManifest(..) => Errno::EINVAL as c_int,
             WireFormatError::MissingRootfs(..) => Errno::EINVAL as c_int,
+            WireFormatError::CorruptedChunk(..) => Errno::EIO as c_int,
+            WireFormatError::LimitExceeded(..) => Errno::EIO as c_int,
+            WireFormatError::InvalidAcl(..) => Errno::EINVAL as c_int,
+            WireFormatError::InvalidBuildOptions(..) => Errno::EINVAL as c_int,
+            WireFormatError::NameCollision(..) => Errno::EEXIST as c_int,
+            WireFormatError::InodeOverflow(..) => Errno::EOVERFLOW as c_int,
+            WireFormatError::InodeCollision(..) => Errno::EEXIST as c_int,
+            WireFormatError::InvalidName(..) => Errno::EILSEQ as c_int,
+            WireFormatError::InvalidSymlinkTarget(..) => Errno::EIO as c_int,
+            WireFormatError::InvalidPlatform(..) => Errno::EINVAL as c_int,
+            WireFormatError::EncryptionError(..) => Errno::EIO as c_int,
+            WireFormatError::MissingKey(..) => Errno::ENOKEY as c_int,
+            WireFormatError::WrongKey(..) => Errno::EKEYREJECTED as c_int,
+            WireFormatError::DictionaryError(..) => Errno::EIO as c_int,
+            WireFormatError::RegistryError(..) => Errno::EIO as c_int,
+            WireFormatError::HttpError(..) => Errno::EIO as c_int,
+            WireFormatError::SignatureError(..) => Errno::EACCES as c_int,
+            WireFormatError::LockTimeout(..) => Errno::EAGAIN as c_int,
             WireFormatError::IOError(ioe, ..) => {
                 ioe.raw_os_error().unwrap_or(Errno::EINVAL as i32) as c_int
             }
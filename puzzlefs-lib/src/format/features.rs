@@ -0,0 +1,235 @@
+use std::backtrace::Backtrace;
+use std::fmt;
+use std::ops::BitOr;
+use std::str::FromStr;
+
+use serde::{Serialize, Serializer};
+
+use super::acl::is_acl_xattr;
+use super::error::{Result, WireFormatError};
+use super::types::{Inode, InodeMode};
+
+/// The optional parts of the format a rootfs relies on, recorded in it since manifest version 4
+/// so that readers can reject the images they would misread, instead of the whole manifest
+/// version being bumped for every addition. Images of older manifest versions don't say, and may
+/// use any of the features known back then.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Features(u64);
+
+// the names of the known features, as shown to and given by users
+const NAMES: &[(Features, &str)] = &[
+    (Features::SPARSE, "sparse"),
+    (Features::HARDLINKS, "hardlinks"),
+    (Features::ENCRYPTION, "encryption"),
+    (Features::ALT_COMPRESSION, "alt-compression"),
+    (Features::ACLS, "acls"),
+    (Features::SHARDED_METADATA, "sharded-metadata"),
+    (Features::INLINE_DATA, "inline-data"),
+    (Features::CASEFOLD, "casefold"),
+    (Features::CHUNK_TABLE, "chunk-table"),
+    (Features::THIN_DELTA, "thin-delta"),
+];
+
+impl Features {
+    /// Files with holes, which aren't stored anywhere.
+    pub const SPARSE: Features = Features(1 << 0);
+    /// Inodes which several directory entries refer to.
+    pub const HARDLINKS: Features = Features(1 << 1);
+    /// Chunks encrypted with the key of the image.
+    pub const ENCRYPTION: Features = Features(1 << 2);
+    /// Chunks compressed with something else than zstd.
+    pub const ALT_COMPRESSION: Features = Features(1 << 3);
+    /// POSIX ACLs, stored as typed entries instead of xattrs.
+    pub const ACLS: Features = Features(1 << 4);
+    /// Inodes stored in metadata shards, blobs of their own next to the rootfs.
+    pub const SHARDED_METADATA: Features = Features(1 << 5);
+    /// Tiny files whose data is stored in their inode rather than in a blob.
+    pub const INLINE_DATA: Features = Features(1 << 6);
+    /// Name lookups which ignore case, see [`super::casefold()`].
+    pub const CASEFOLD: Features = Features(1 << 7);
+    /// Chunk lists stored once for all the files which have them, in the chunk table of their
+    /// inode vector.
+    pub const CHUNK_TABLE: Features = Features(1 << 8);
+    /// A rootfs only holding what changed since its parent, which readers have to look up the
+    /// rest of the image in.
+    pub const THIN_DELTA: Features = Features(1 << 9);
+    /// Everything this version of puzzlefs can read.
+    pub const SUPPORTED: Features = Features(
+        Self::SPARSE.0
+            | Self::HARDLINKS.0
+            | Self::ENCRYPTION.0
+            | Self::ALT_COMPRESSION.0
+            | Self::ACLS.0
+            | Self::SHARDED_METADATA.0
+            | Self::INLINE_DATA.0
+            | Self::CASEFOLD.0
+            | Self::CHUNK_TABLE.0
+            | Self::THIN_DELTA.0,
+    );
+
+    pub const fn empty() -> Self {
+        Features(0)
+    }
+
+    /// Keeps the bits this version of puzzlefs doesn't know about, so they can be reported.
+    pub const fn from_bits(bits: u64) -> Self {
+        Features(bits)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn difference(self, other: Features) -> Self {
+        Features(self.0 & !other.0)
+    }
+
+    /// The features the inodes rely on.
+    pub fn used_by(metadatas: &[Vec<Inode>]) -> Self {
+        let mut features = Features::empty();
+        for inode in metadatas.iter().flatten() {
+            if inode.nlink > 1 && !matches!(inode.mode, InodeMode::Dir { .. }) {
+                features = features | Features::HARDLINKS;
+            }
+            let acls = inode
+                .additional
+                .as_ref()
+                .is_some_and(|additional| additional.xattrs.iter().any(|x| is_acl_xattr(&x.key)));
+            if acls {
+                features = features | Features::ACLS;
+            }
+            let InodeMode::File { chunks } = &inode.mode else {
+                continue;
+            };
+            for chunk in chunks {
+                if chunk.inline.is_some() {
+                    features = features | Features::INLINE_DATA;
+                    continue;
+                }
+                let Some(blob) = &chunk.blob else {
+                    features = features | Features::SPARSE;
+                    continue;
+                };
+                if blob.encryption.is_some() {
+                    features = features | Features::ENCRYPTION;
+                }
+                if blob.compressed && blob.algorithm != Default::default() {
+                    features = features | Features::ALT_COMPRESSION;
+                }
+            }
+        }
+        features
+    }
+
+    fn names(self) -> Vec<String> {
+        let mut names = Vec::new();
+        for (feature, name) in NAMES {
+            if self.contains(*feature) {
+                names.push(name.to_string());
+            }
+        }
+        for bit in 0..u64::BITS {
+            if self
+                .difference(Features::SUPPORTED)
+                .contains(Features(1 << bit))
+            {
+                names.push(format!("unknown feature {bit}"));
+            }
+        }
+        names
+    }
+
+    /// Fails if there are features this version of puzzlefs can't read.
+    pub fn check_supported(self) -> Result<()> {
+        let unsupported = self.difference(Features::SUPPORTED);
+        if !unsupported.is_empty() {
+            return Err(WireFormatError::UnsupportedFeature {
+                flag: unsupported,
+                backtrace: Backtrace::capture(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "none");
+        }
+        write!(f, "{}", self.names().join(", "))
+    }
+}
+
+/// A list of the feature names, e.g. `["sparse", "unknown feature 40"]`.
+impl Serialize for Features {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_seq(self.names())
+    }
+}
+
+/// Parses comma separated feature names, e.g. `sparse,hardlinks`, or `none`.
+impl FromStr for Features {
+    type Err = WireFormatError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut features = Features::empty();
+        for name in s
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty() && *name != "none")
+        {
+            let (feature, _) = NAMES.iter().find(|(_, n)| *n == name).ok_or_else(|| {
+                WireFormatError::InvalidBuildOptions(
+                    format!("unknown feature {name}"),
+                    Backtrace::capture(),
+                )
+            })?;
+            features = features | *feature;
+        }
+        Ok(features)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_features() {
+        let features: Features = "sparse, alt-compression".parse().unwrap();
+        assert_eq!(features, Features::SPARSE | Features::ALT_COMPRESSION);
+        assert_eq!(features.to_string(), "sparse, alt-compression");
+        assert_eq!("none".parse::<Features>().unwrap(), Features::empty());
+        assert!("holes".parse::<Features>().is_err());
+
+        assert!(Features::SUPPORTED.check_supported().is_ok());
+        let future = Features::from_bits(1 << 40) | Features::HARDLINKS;
+        assert_eq!(future.to_string(), "hardlinks, unknown feature 40");
+        match future.check_supported() {
+            Err(WireFormatError::UnsupportedFeature { flag, .. }) => {
+                assert_eq!(flag, Features::from_bits(1 << 40))
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,229 @@
+// Whole-file digests of the contents of regular files, computed when building, so that tools can
+// compare and verify files without reading and hashing their chunks.
+
+use std::backtrace::Backtrace;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use serde::{Serialize, Serializer};
+use sha2::{Digest as Sha2Digest, Sha256};
+
+use super::error::{Result, WireFormatError};
+
+/// The hash function of a [`FileDigest`], and of the chunk digests used to find duplicates while
+/// building.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestAlgorithm {
+    #[default]
+    Sha256,
+    /// Much faster than sha256 on large files.
+    Blake3,
+}
+
+impl DigestAlgorithm {
+    pub(crate) fn from_capnp(algorithm: crate::metadata_capnp::DigestAlgorithm) -> Self {
+        match algorithm {
+            crate::metadata_capnp::DigestAlgorithm::Sha256 => DigestAlgorithm::Sha256,
+            crate::metadata_capnp::DigestAlgorithm::Blake3 => DigestAlgorithm::Blake3,
+        }
+    }
+
+    pub(crate) fn to_capnp(self) -> crate::metadata_capnp::DigestAlgorithm {
+        match self {
+            DigestAlgorithm::Sha256 => crate::metadata_capnp::DigestAlgorithm::Sha256,
+            DigestAlgorithm::Blake3 => crate::metadata_capnp::DigestAlgorithm::Blake3,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DigestAlgorithm::Sha256 => "sha256",
+            DigestAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    pub fn digest(self, data: &[u8]) -> [u8; 32] {
+        match self {
+            DigestAlgorithm::Sha256 => Sha256::digest(data).into(),
+            DigestAlgorithm::Blake3 => blake3::hash(data).into(),
+        }
+    }
+
+    pub fn hasher(self) -> FileHasher {
+        match self {
+            DigestAlgorithm::Sha256 => FileHasher::Sha256(Sha256::new()),
+            DigestAlgorithm::Blake3 => FileHasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+impl fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for DigestAlgorithm {
+    type Err = WireFormatError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sha256" => Ok(DigestAlgorithm::Sha256),
+            "blake3" => Ok(DigestAlgorithm::Blake3),
+            _ => Err(WireFormatError::InvalidBuildOptions(
+                format!("unknown digest algorithm {s}"),
+                Backtrace::capture(),
+            )),
+        }
+    }
+}
+
+/// The digest of the whole contents of a regular file, holes included.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FileDigest {
+    pub algorithm: DigestAlgorithm,
+    pub digest: Vec<u8>,
+}
+
+impl FileDigest {
+    pub fn of(algorithm: DigestAlgorithm, data: &[u8]) -> Self {
+        let mut hasher = algorithm.hasher();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    /// Returns None for the digests of hash functions added after this version of puzzlefs:
+    /// they're optional, so they're ignored as if there were none rather than making the whole
+    /// inode unreadable.
+    pub fn from_capnp(
+        reader: crate::metadata_capnp::file_digest::Reader<'_>,
+    ) -> Result<Option<Self>> {
+        let Ok(algorithm) = reader.get_algorithm() else {
+            return Ok(None);
+        };
+        Ok(Some(FileDigest {
+            algorithm: DigestAlgorithm::from_capnp(algorithm),
+            digest: reader.get_digest()?.to_vec(),
+        }))
+    }
+
+    pub fn fill_capnp(&self, builder: &mut crate::metadata_capnp::file_digest::Builder<'_>) {
+        builder.set_algorithm(self.algorithm.to_capnp());
+        builder.set_digest(&self.digest);
+    }
+}
+
+/// Formatted like OCI digests, e.g. `sha256:<hex>`.
+impl fmt::Display for FileDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, hex::encode(&self.digest))
+    }
+}
+
+impl Serialize for FileDigest {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+/// Computes a [`FileDigest`] from data written to it, e.g. with `io::copy()`.
+pub enum FileHasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl FileHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            FileHasher::Sha256(hasher) => hasher.update(data),
+            FileHasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub fn finish(self) -> FileDigest {
+        match self {
+            FileHasher::Sha256(hasher) => FileDigest {
+                algorithm: DigestAlgorithm::Sha256,
+                digest: hasher.finalize().to_vec(),
+            },
+            FileHasher::Blake3(hasher) => FileDigest {
+                algorithm: DigestAlgorithm::Blake3,
+                digest: hasher.finalize().as_bytes().to_vec(),
+            },
+        }
+    }
+}
+
+impl io::Write for FileHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use capnp::message::ReaderOptions;
+    use capnp::serialize;
+
+    use super::*;
+
+    // A FileDigest message as a newer puzzlefs would write it, with a data field and a pointer
+    // field this one doesn't know about after the ones it does.
+    fn future_file_digest(algorithm: u16) -> Vec<u8> {
+        let words: [u64; 11] = [
+            // segment table: one segment of 10 words
+            10 << 32,
+            // root struct pointer: right after it, two data words and two pointers
+            (2 << 32) | (2 << 48),
+            // algorithm
+            algorithm.into(),
+            // unknown data field
+            0xdead_beef,
+            // digest: list of 32 bytes, one word after this pointer
+            1 | (1 << 2) | (2 << 32) | (32 << 35),
+            // unknown pointer field: list of 3 bytes, four words after this pointer
+            1 | (4 << 2) | (2 << 32) | (3 << 35),
+            u64::from_ne_bytes([0xab; 8]),
+            u64::from_ne_bytes([0xab; 8]),
+            u64::from_ne_bytes([0xab; 8]),
+            u64::from_ne_bytes([0xab; 8]),
+            u64::from_le_bytes(*b"new\0\0\0\0\0"),
+        ];
+        words.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    fn read_file_digest(message: &[u8]) -> Result<Option<FileDigest>> {
+        let message =
+            serialize::read_message_from_flat_slice(&mut &message[..], ReaderOptions::new())?;
+        FileDigest::from_capnp(
+            message.get_root::<crate::metadata_capnp::file_digest::Reader<'_>>()?,
+        )
+    }
+
+    #[test]
+    fn test_unknown_fields() -> Result<()> {
+        // the fields this version doesn't know are skipped
+        assert_eq!(
+            read_file_digest(&future_file_digest(0))?,
+            Some(FileDigest {
+                algorithm: DigestAlgorithm::Sha256,
+                digest: vec![0xab; 32],
+            })
+        );
+        // and so are the digests of hash functions it doesn't know
+        assert_eq!(read_file_digest(&future_file_digest(7))?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+// A JSON rendering of the metadata, for inspection tools and for comparing images in tests. It
+// holds everything the metadata does and is stable: structs are objects with their field names,
+// enums are lowercase, digests and data are hex, and names, symlink targets and xattr keys are
+// strings when they're valid UTF-8, or objects like {"escaped": "caf\\xe9"} holding their escaped
+// form (see escape_name) when they aren't.
+
+use std::borrow::Cow;
+
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+
+use super::error::Result;
+use super::names::escape_name;
+use super::types::{Inode, Rootfs, RootfsReader, VerityData};
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Bytes<'a> {
+    Utf8(&'a str),
+    Escaped { escaped: Cow<'a, str> },
+}
+
+impl<'a> Bytes<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(s) => Bytes::Utf8(s),
+            Err(_) => Bytes::Escaped {
+                escaped: escape_name(bytes),
+            },
+        }
+    }
+}
+
+pub(crate) fn hex<S, T>(bytes: &T, serializer: S) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: AsRef<[u8]>,
+{
+    serializer.serialize_str(&hex::encode(bytes))
+}
+
+pub(crate) fn opt_hex<S, T>(
+    bytes: &Option<T>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: AsRef<[u8]>,
+{
+    match bytes {
+        Some(bytes) => hex(bytes, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub(crate) fn bytes<S, T>(bytes: &T, serializer: S) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: AsRef<[u8]>,
+{
+    Bytes::new(bytes.as_ref()).serialize(serializer)
+}
+
+pub(crate) fn opt_bytes<S>(
+    bytes: &Option<Vec<u8>>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    bytes.as_deref().map(Bytes::new).serialize(serializer)
+}
+
+// blob digest -> fs-verity digest, both hex
+pub(crate) fn verity_data<S>(
+    data: &VerityData,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_map(
+        data.iter()
+            .map(|(digest, verity)| (hex::encode(digest), hex::encode(verity))),
+    )
+}
+
+impl Inode {
+    /// The inode as JSON, with its xattrs and chunk list.
+    pub fn to_json(&self) -> Result<Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+}
+
+impl RootfsReader {
+    /// The whole rootfs as JSON: its manifest version, features, digest algorithm and parent, the
+    /// inodes of each of its inode vectors, metadata shards included, and the fs-verity digests of
+    /// its blobs. Two rootfs render the same only if they hold the same metadata.
+    pub fn to_json(&self) -> Result<Value> {
+        Ok(serde_json::to_value(Rootfs::try_from(self)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::builder::serialize_metadata;
+    use crate::format::{
+        BlobRef, CompressionAlgorithm, DigestAlgorithm, DirEnt, DirList, Features, FileChunk,
+        InodeAdditional, InodeMode, Xattr,
+    };
+
+    #[test]
+    fn test_inode_json() -> anyhow::Result<()> {
+        let mut dir = Inode::new_whiteout(1);
+        dir.mode = InodeMode::Dir {
+            dir_list: DirList {
+                look_below: false,
+                entries: vec![
+                    DirEnt {
+                        ino: 2,
+                        name: b"file".to_vec(),
+                    },
+                    DirEnt {
+                        ino: 3,
+                        name: b"\xff".to_vec(),
+                    },
+                ],
+            },
+        };
+        dir.additional = Some(InodeAdditional {
+            xattrs: vec![Xattr {
+                key: b"user.comment".to_vec(),
+                val: b"hi".to_vec(),
+            }],
+            symlink_target: None,
+        });
+        let json = dir.to_json()?;
+        assert_eq!(
+            json["mode"],
+            json!({"dir": {"dir_list": {"look_below": false, "entries": [
+                {"ino": 2, "name": "file"},
+                {"ino": 3, "name": {"escaped": "\\xff"}},
+            ]}}})
+        );
+        assert_eq!(
+            json["additional"],
+            json!({"xattrs": [{"key": "user.comment", "val": "6869"}], "symlink_target": null})
+        );
+
+        let mut file = Inode::new_whiteout(2);
+        file.mode = InodeMode::File {
+            chunks: vec![
+                FileChunk {
+                    blob: Some(BlobRef {
+                        digest: [0xab; 32],
+                        offset: 10,
+                        compressed: true,
+                        algorithm: CompressionAlgorithm::Lz4,
+                        encryption: None,
+                    }),
+                    len: 100,
+                    checksum: Some(7),
+                    inline: None,
+                },
+                FileChunk::hole(4096),
+            ],
+        };
+        let json = file.to_json()?;
+        assert_eq!(
+            json["mode"]["file"]["chunks"],
+            json!([
+                {
+                    "blob": {
+                        "digest": hex::encode([0xab; 32]),
+                        "offset": 10,
+                        "compressed": true,
+                        "algorithm": "lz4",
+                        "encryption": null,
+                    },
+                    "len": 100,
+                    "checksum": 7,
+                    "inline": null,
+                },
+                {"blob": null, "len": 4096, "checksum": null, "inline": null},
+            ])
+        );
+        assert_eq!(Inode::new_whiteout(3).to_json()?["mode"], json!("wht"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rootfs_json() -> anyhow::Result<()> {
+        let rootfs = Rootfs {
+            metadatas: vec![vec![Inode::new_whiteout(1)]],
+            fs_verity_data: VerityData::from([([1; 32], [2; 32])]),
+            manifest_version: 4,
+            parent: Some([3; 32]),
+            features: Features::SPARSE,
+            digest_algorithm: DigestAlgorithm::Blake3,
+        };
+        let reader = RootfsReader::from_bytes(&serialize_metadata(rootfs)?)?;
+        let json = reader.to_json()?;
+        assert_eq!(json["manifest_version"], json!(4));
+        assert_eq!(json["features"], json!(["sparse"]));
+        assert_eq!(json["digest_algorithm"], json!("blake3"));
+        assert_eq!(json["parent"], json!(hex::encode([3; 32])));
+        assert_eq!(
+            json["fs_verity_data"],
+            json!({ (hex::encode([1; 32])): hex::encode([2; 32]) })
+        );
+        assert_eq!(
+            json["metadatas"],
+            json!([[Inode::new_whiteout(1).to_json()?]])
+        );
+        Ok(())
+    }
+}
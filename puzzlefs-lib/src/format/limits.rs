@@ -0,0 +1,153 @@
+// Bounds on what the metadata of an image may hold, so that a malicious or corrupted image can't
+// make readers allocate unbounded memory or walk arbitrarily deep trees. They're checked on the
+// serialized inodes, before anything is deserialized.
+
+use std::backtrace::Backtrace;
+
+use super::error::{Result, WireFormatError};
+use super::types::ChunkTable;
+
+/// The limits on the metadata of the images a reader accepts, see
+/// [`crate::oci::Image::with_limits`]. The defaults are well above what a filesystem a kernel can
+/// mount holds, so that only images which were crafted or corrupted run into them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// Length of a file name, in bytes.
+    pub max_name_len: u32,
+    /// Number of entries of a directory.
+    pub max_dir_entries: u32,
+    /// Number of xattrs of an inode, ACLs included.
+    pub max_xattrs: u32,
+    /// Size of an xattr, key and value together.
+    pub max_xattr_size: u32,
+    /// Number of chunks of a file.
+    pub max_chunks: u32,
+    /// Length of a symlink target, in bytes.
+    pub max_symlink_len: u32,
+    /// Number of components of the paths looked up and walked.
+    pub max_depth: u32,
+}
+
+impl Limits {
+    /// No limits at all, for images which are trusted, e.g. ones built locally.
+    pub const UNLIMITED: Limits = Limits {
+        max_name_len: u32::MAX,
+        max_dir_entries: u32::MAX,
+        max_xattrs: u32::MAX,
+        max_xattr_size: u32::MAX,
+        max_chunks: u32::MAX,
+        max_symlink_len: u32::MAX,
+        max_depth: u32::MAX,
+    };
+
+    /// Checks an inode straight from the metadata blob, along with the chunk table of its inode
+    /// vector.
+    pub(crate) fn check_inode(
+        &self,
+        inode: crate::metadata_capnp::inode::Reader<'_>,
+        chunk_table: ChunkTable<'_>,
+    ) -> Result<()> {
+        let ino = inode.get_ino();
+        match inode.get_mode().which().map_err(capnp::Error::from)? {
+            crate::metadata_capnp::inode::mode::Dir(dir) => {
+                let entries = dir?.get_entries()?;
+                exceeds(
+                    ino,
+                    "directory entries",
+                    entries.len(),
+                    self.max_dir_entries,
+                )?;
+                for entry in entries {
+                    exceeds(
+                        ino,
+                        "name length",
+                        entry.get_name()?.len() as u32,
+                        self.max_name_len,
+                    )?;
+                }
+            }
+            crate::metadata_capnp::inode::mode::File(chunks) => {
+                exceeds(ino, "chunks", chunks?.len(), self.max_chunks)?;
+            }
+            // an index out of the table fails to deserialize
+            crate::metadata_capnp::inode::mode::SharedFile(index) if index < chunk_table.len() => {
+                exceeds(
+                    ino,
+                    "chunks",
+                    chunk_table.get(index)?.len(),
+                    self.max_chunks,
+                )?;
+            }
+            _ => {}
+        }
+
+        if !inode.has_additional() {
+            return Ok(());
+        }
+        let additional = inode.get_additional()?;
+        if additional.has_symlink_target() {
+            exceeds(
+                ino,
+                "symlink target length",
+                additional.get_symlink_target()?.len() as u32,
+                self.max_symlink_len,
+            )?;
+        }
+        let mut xattrs =
+            u32::from(additional.has_access_acl()) + u32::from(additional.has_default_acl());
+        if additional.has_xattrs() {
+            let list = additional.get_xattrs()?;
+            xattrs += list.len();
+            for xattr in list {
+                let size = xattr.get_key()?.len() as u64 + xattr.get_val()?.len() as u64;
+                exceeds(
+                    ino,
+                    "xattr size",
+                    size.try_into().unwrap_or(u32::MAX),
+                    self.max_xattr_size,
+                )?;
+            }
+        }
+        exceeds(ino, "xattrs", xattrs, self.max_xattrs)
+    }
+
+    /// Checks the depth of a path, given as its number of components.
+    pub(crate) fn check_depth(&self, depth: usize) -> Result<()> {
+        if depth > self.max_depth as usize {
+            return Err(WireFormatError::LimitExceeded(
+                format!("path depth {depth} over the limit of {}", self.max_depth),
+                Backtrace::capture(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            // NAME_MAX
+            max_name_len: 255,
+            max_dir_entries: 1 << 22,
+            max_xattrs: 1 << 12,
+            // XATTR_NAME_MAX + XATTR_SIZE_MAX
+            max_xattr_size: 255 + (1 << 16),
+            // 1 TiB files, with 64 KiB chunks
+            max_chunks: 1 << 24,
+            // PATH_MAX, without the NUL
+            max_symlink_len: 4095,
+            // PATH_MAX / 2, one byte names and their separators
+            max_depth: 2048,
+        }
+    }
+}
+
+fn exceeds(ino: u64, what: &str, value: u32, limit: u32) -> Result<()> {
+    if value > limit {
+        return Err(WireFormatError::LimitExceeded(
+            format!("inode {ino}: {what} {value} over the limit of {limit}"),
+            Backtrace::capture(),
+        ));
+    }
+    Ok(())
+}
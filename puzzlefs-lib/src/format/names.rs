@@ -0,0 +1,140 @@
+// Names are raw bytes, like on Linux: they may not be valid UTF-8, and may hold control
+// characters. Tools printing them use this escaped form, which is lossless and can be given back
+// to them, e.g. to `puzzlefs dump --path`: valid UTF-8 is kept as is, except for backslashes,
+// which are doubled, and control characters and bytes which aren't valid UTF-8 are written as
+// `\xNN`.
+
+use std::backtrace::Backtrace;
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use serde::Serializer;
+
+use super::error::{Result, WireFormatError};
+
+fn needs_escaping(c: char) -> bool {
+    c == '\\' || c.is_control()
+}
+
+/// The escaped form of `name`, borrowed when there's nothing to escape.
+pub fn escape_name(name: &[u8]) -> Cow<'_, str> {
+    if let Ok(s) = std::str::from_utf8(name) {
+        if !s.chars().any(needs_escaping) {
+            return Cow::Borrowed(s);
+        }
+    }
+
+    let mut escaped = String::with_capacity(name.len());
+    for chunk in name.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                // control characters are all below 0x80 or encoded with two bytes
+                c if c.is_control() => {
+                    let mut buf = [0; 4];
+                    for b in c.encode_utf8(&mut buf).bytes() {
+                        write!(escaped, "\\x{b:02x}").unwrap();
+                    }
+                }
+                c => escaped.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            write!(escaped, "\\x{b:02x}").unwrap();
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// The escaped form of a path of the image, e.g. one given by
+/// [`crate::reader::WalkPuzzleFS`]; the `/` between names are kept.
+pub fn escape_path(path: &Path) -> Cow<'_, str> {
+    escape_name(path.as_os_str().as_bytes())
+}
+
+/// The name (or path) `escaped` is the escaped form of.
+pub fn unescape_name(escaped: &str) -> Result<Vec<u8>> {
+    let invalid = || {
+        WireFormatError::InvalidName(
+            format!("invalid escape sequence in {escaped}"),
+            Backtrace::capture(),
+        )
+    };
+
+    let mut name = Vec::with_capacity(escaped.len());
+    let mut rest = escaped.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        if b != b'\\' {
+            name.push(b);
+            continue;
+        }
+        match rest {
+            [b'\\', tail @ ..] => {
+                name.push(b'\\');
+                rest = tail;
+            }
+            [b'x', hi, lo, tail @ ..] => {
+                let digits = std::str::from_utf8(&[*hi, *lo])
+                    .ok()
+                    .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                    .ok_or_else(invalid)?;
+                name.push(digits);
+                rest = tail;
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(name)
+}
+
+// serialize_with helper for paths which may not be valid UTF-8
+pub(crate) fn serialize_escaped_path<S>(
+    path: &Path,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&escape_path(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_name() -> anyhow::Result<()> {
+        assert!(matches!(escape_name(b"caf\xc3\xa9"), Cow::Borrowed("café")));
+        assert_eq!(escape_name(b"a\\b"), "a\\\\b");
+        assert_eq!(escape_name(b"tab\there"), "tab\\x09here");
+        assert_eq!(escape_name(b"\xff\xfe.txt"), "\\xff\\xfe.txt");
+        assert_eq!(escape_name("\u{85}".as_bytes()), "\\xc2\\x85");
+        assert_eq!(
+            escape_path(Path::new(std::ffi::OsStr::from_bytes(b"/dir/\xffile"))),
+            "/dir/\\xffile"
+        );
+
+        for name in [
+            &b"plain"[..],
+            b"a\\b",
+            b"\\x41",
+            b"new\nline",
+            b"\xff\xfe",
+            b"caf\xc3\xa9\x80",
+            "\u{85}".as_bytes(),
+        ] {
+            assert_eq!(unescape_name(&escape_name(name))?, name);
+        }
+
+        for invalid in ["\\", "\\x4", "\\xzz", "\\n", "a\\"] {
+            assert!(matches!(
+                unescape_name(invalid),
+                Err(WireFormatError::InvalidName(..))
+            ));
+        }
+        Ok(())
+    }
+}
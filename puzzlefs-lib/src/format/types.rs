@@ -1,24 +1,35 @@
 use capnp::{message, serialize};
-use memmap2::{Mmap, MmapOptions};
+use memmap2::{Mmap, MmapMut, MmapOptions};
 use nix::errno::Errno;
 use nix::sys::stat;
 use std::backtrace::Backtrace;
-use std::collections::BTreeMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsStr;
 use std::ffi::OsString;
 use std::fmt;
 use std::fs;
-use std::io;
+use std::io::{self, Read};
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::ffi::OsStringExt;
 use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 use serde::de::Error as SerdeError;
 use serde::de::Visitor;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+use super::acl::{is_acl_xattr, Acl, ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR};
+use super::casefold::casefold;
 use super::error::{Result, WireFormatError};
+use super::features::Features;
+use super::file_digest::{DigestAlgorithm, FileDigest};
+use super::json;
+use super::limits::Limits;
+use super::names::escape_name;
+use crate::encryption::Cipher;
 use hex::FromHexError;
 
 pub const DEFAULT_FILE_PERMISSIONS: u16 = 0o644;
@@ -27,49 +38,75 @@ pub const SHA256_BLOCK_SIZE: usize = 32;
 // reproducible representation of the serialized metadata
 pub type VerityData = BTreeMap<[u8; SHA256_BLOCK_SIZE], [u8; SHA256_BLOCK_SIZE]>;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Rootfs {
     pub metadatas: Vec<Vec<Inode>>,
+    #[serde(serialize_with = "json::verity_data")]
     pub fs_verity_data: VerityData,
     pub manifest_version: u64,
+    /// The digest of the rootfs blob of the base image, for delta images which only carry their
+    /// changes.
+    #[serde(serialize_with = "json::opt_hex")]
+    pub parent: Option<[u8; SHA256_BLOCK_SIZE]>,
+    /// What the image relies on, for readers to check they support it.
+    pub features: Features,
+    /// The hash function the image was built with. Blobs are addressed by their sha256 digest no
+    /// matter what, since that's what OCI registries expect.
+    pub digest_algorithm: DigestAlgorithm,
 }
 
+/// Reads the whole rootfs, along with the metadata shards it's split into, if any.
 impl TryFrom<RootfsReader> for Rootfs {
     type Error = WireFormatError;
     fn try_from(rootfs_reader: RootfsReader) -> Result<Self> {
-        Rootfs::from_capnp(rootfs_reader.reader.get()?)
+        Rootfs::try_from(&rootfs_reader)
     }
 }
 
-impl Rootfs {
-    pub fn from_capnp(reader: crate::metadata_capnp::rootfs::Reader<'_>) -> Result<Self> {
+impl TryFrom<&RootfsReader> for Rootfs {
+    type Error = WireFormatError;
+    fn try_from(rootfs_reader: &RootfsReader) -> Result<Self> {
+        let reader = rootfs_reader.reader.get()?;
         let metadata_vec = reader
             .get_metadatas()?
             .iter()
-            .map(InodeVector::from_capnp)
+            .map(|layer| rootfs_reader.layer_inodes(layer))
             .collect::<Result<Vec<Vec<_>>>>()?;
 
-        let capnp_verities = reader.get_fs_verity_data()?;
-        let mut fs_verity_data = VerityData::new();
-
-        for capnp_verity in capnp_verities {
-            let digest = capnp_verity.get_digest()?.try_into()?;
-            let verity = capnp_verity.get_verity()?.try_into()?;
-            fs_verity_data.insert(digest, verity);
-        }
-
         Ok(Rootfs {
             metadatas: metadata_vec,
-            fs_verity_data,
+            fs_verity_data: rootfs_reader.get_verity_data()?,
             manifest_version: reader.get_manifest_version(),
+            parent: parent_from_capnp(reader)?,
+            features: Features::from_bits(reader.get_features()),
+            digest_algorithm: DigestAlgorithm::from_capnp(
+                reader.get_digest_algorithm().map_err(capnp::Error::from)?,
+            ),
         })
     }
+}
 
+impl Rootfs {
     pub fn fill_capnp(
         &self,
         builder: &mut crate::metadata_capnp::rootfs::Builder<'_>,
+    ) -> Result<()> {
+        self.fill_capnp_sharded(builder, &[])
+    }
+
+    /// Like [`Rootfs::fill_capnp`], referring to the shards the inodes were written to instead of
+    /// storing them, for the inode vectors which have some in `shards`.
+    pub fn fill_capnp_sharded(
+        &self,
+        builder: &mut crate::metadata_capnp::rootfs::Builder<'_>,
+        shards: &[Vec<MetadataShard>],
     ) -> Result<()> {
         builder.set_manifest_version(self.manifest_version);
+        builder.set_features(self.features.bits());
+        builder.set_digest_algorithm(self.digest_algorithm.to_capnp());
+        if let Some(parent) = &self.parent {
+            builder.set_parent(parent);
+        }
 
         let metadatas_len = self.metadatas.len().try_into()?;
         let mut capnp_metadatas = builder.reborrow().init_metadatas(metadatas_len);
@@ -77,7 +114,19 @@ impl Rootfs {
         for (i, metadata) in self.metadatas.iter().enumerate() {
             // we already checked that the length of metadatas fits inside a u32
             let mut capnp_metadata = capnp_metadatas.reborrow().get(i as u32);
-            InodeVector::fill_capnp(metadata, &mut capnp_metadata)?;
+            match shards.get(i) {
+                Some(shards) if !shards.is_empty() => {
+                    let mut capnp_shards = capnp_metadata.init_shards(shards.len().try_into()?);
+                    for (j, shard) in shards.iter().enumerate() {
+                        shard.fill_capnp(&mut capnp_shards.reborrow().get(j as u32));
+                    }
+                }
+                _ => InodeVector::fill_capnp(
+                    metadata,
+                    &mut capnp_metadata,
+                    self.features.contains(Features::CHUNK_TABLE),
+                )?,
+            }
         }
 
         let verity_data_len = self.fs_verity_data.len().try_into()?;
@@ -94,31 +143,324 @@ impl Rootfs {
     }
 }
 
+/// The rootfs of images built with manifest version 2, where each metadata was a blob of its own
+/// holding an InodeVector message.
+#[derive(Debug)]
+pub struct RootfsV2 {
+    pub metadatas: Vec<BlobRef>,
+    pub fs_verity_data: VerityData,
+}
+
+impl RootfsV2 {
+    pub fn open(mut f: cap_std::fs::File) -> Result<Self> {
+        let mut data = Vec::new();
+        io::Read::read_to_end(&mut f, &mut data)?;
+        let message = read_message(&data)?;
+        let reader = message.get_root::<crate::metadata_capnp::rootfs_v2::Reader<'_>>()?;
+
+        let metadatas = reader
+            .get_metadatas()?
+            .iter()
+            .map(BlobRef::from_capnp)
+            .collect::<Result<Vec<_>>>()?;
+        let mut fs_verity_data = VerityData::new();
+        for capnp_verity in reader.get_fs_verity_data()? {
+            let digest = capnp_verity.get_digest()?.try_into()?;
+            let verity = capnp_verity.get_verity()?.try_into()?;
+            fs_verity_data.insert(digest, verity);
+        }
+
+        Ok(RootfsV2 {
+            metadatas,
+            fs_verity_data,
+        })
+    }
+
+    /// Reads the inodes of one of the metadata blobs.
+    pub fn read_metadata(data: &[u8]) -> Result<Vec<Inode>> {
+        let message = read_message(data)?;
+        InodeVector::from_capnp(
+            message.get_root::<crate::metadata_capnp::inode_vector::Reader<'_>>()?,
+        )
+    }
+}
+
+fn parent_from_capnp(
+    reader: crate::metadata_capnp::rootfs::Reader<'_>,
+) -> Result<Option<[u8; SHA256_BLOCK_SIZE]>> {
+    if !reader.has_parent() {
+        return Ok(None);
+    }
+    Ok(Some(reader.get_parent()?.try_into()?))
+}
+
+// Upper bound for the number of fs-verity digests RootfsReader keeps around; a miss only costs a
+// binary search through the mmapped rootfs, so there's no need to hold the whole table in memory.
+const VERITY_CACHE_SIZE: usize = 1024;
+
 pub struct RootfsReader {
     reader: message::TypedReader<
         ::capnp::serialize::BufferSegments<Mmap>,
         crate::metadata_capnp::rootfs::Owned,
     >,
+    verity_cache: Mutex<HashMap<[u8; SHA256_BLOCK_SIZE], [u8; SHA256_BLOCK_SIZE]>>,
+    shards: Option<Shards>,
+    limits: Limits,
+}
+
+// The chunk lists the files of an inode vector share, see [`InodeVector::fill_capnp`].
+pub(crate) type ChunkTable<'a> = ::capnp::list_list::Reader<
+    'a,
+    ::capnp::struct_list::Owned<crate::metadata_capnp::file_chunk::Owned>,
+>;
+
+type ShardReader = message::TypedReader<
+    ::capnp::serialize::BufferSegments<Mmap>,
+    crate::metadata_capnp::inode_vector::Owned,
+>;
+
+/// Opens the blob with the given digest, checking it against the fs-verity digest if one is
+/// given.
+pub(crate) type BlobOpener =
+    dyn Fn(&[u8; SHA256_BLOCK_SIZE], Option<&[u8]>) -> Result<cap_std::fs::File> + Send + Sync;
+
+// Where the metadata shards of a sharded rootfs come from, and the ones loaded so far. They're
+// only loaded when one of their inodes is looked up, and are kept for as long as the rootfs.
+struct Shards {
+    open: Box<BlobOpener>,
+    // whether the shards are checked against the fs-verity digests in the rootfs
+    verified: bool,
+    loaded: Mutex<HashMap<[u8; SHA256_BLOCK_SIZE], Arc<ShardReader>>>,
+}
+
+// We know the loaded messages are safe, so we're allowing unlimited reads.
+const UNLIMITED_READS: message::ReaderOptions = message::ReaderOptions {
+    traversal_limit_in_words: None,
+    nesting_limit: 64,
+};
+
+// Reads a message from the start of `data`, failing with [`WireFormatError::TruncatedMetadata`] if
+// it's shorter than the segment table says: capnp doesn't check it when mapping segments.
+fn read_message(data: &[u8]) -> Result<message::Reader<serialize::BufferSegments<&[u8]>>> {
+    serialize::read_message_from_flat_slice(&mut &data[..], UNLIMITED_READS).map_err(|e| {
+        match e.kind {
+            capnp::ErrorKind::EmptySlice
+            | capnp::ErrorKind::MessageEndsPrematurely(..)
+            | capnp::ErrorKind::PrematureEndOfFile => WireFormatError::TruncatedMetadata {
+                offset: data.len() as u64,
+                backtrace: Backtrace::capture(),
+            },
+            _ => e.into(),
+        }
+    })
+}
+
+// Maps the segments of a metadata message, see [`read_message`].
+fn map_segments(region: Mmap) -> Result<serialize::BufferSegments<Mmap>> {
+    read_message(&region)?;
+    Ok(serialize::BufferSegments::new(region, UNLIMITED_READS)?)
+}
+
+// the magic number at the start of zstd frames, which capnp messages can't start with
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+// Maps a metadata blob, the rootfs or one of its shards, decompressing it if it was compressed
+// with [`crate::builder::BuildOptions::compress_metadata`].
+fn map_metadata(f: &cap_std::fs::File) -> Result<Mmap> {
+    let region = unsafe { MmapOptions::new().map_copy_read_only(f)? };
+    if region.starts_with(&ZSTD_MAGIC) {
+        return decompress_metadata(&region);
+    }
+    Ok(region)
+}
+
+// Decompresses a metadata blob straight into anonymous memory, so the compressed blob and the
+// metadata are never both copied in memory.
+fn decompress_metadata(data: &[u8]) -> Result<Mmap> {
+    let size = match zstd::zstd_safe::get_frame_content_size(data) {
+        Ok(Some(size)) => usize::try_from(size)?,
+        // not written by puzzlefs, which records the size
+        _ => return copy_to_anon(&zstd::stream::decode_all(data)?),
+    };
+    let mut decoder = zstd::stream::read::Decoder::with_buffer(data)?;
+    let mut region = MmapMut::map_anon(size)?;
+    decoder.read_exact(&mut region)?;
+    if decoder.read(&mut [0])? != 0 {
+        return Err(WireFormatError::InvalidSerializedData(Backtrace::capture()));
+    }
+    Ok(region.make_read_only()?)
+}
+
+fn copy_to_anon(data: &[u8]) -> Result<Mmap> {
+    let mut region = MmapMut::map_anon(data.len())?;
+    region.copy_from_slice(data);
+    Ok(region.make_read_only()?)
 }
 
 impl RootfsReader {
+    /// Opens a rootfs blob, decompressing it if it was compressed with
+    /// [`crate::builder::BuildOptions::compress_metadata`].
     pub fn open(f: cap_std::fs::File) -> Result<Self> {
-        // We know the loaded message is safe, so we're allowing unlimited reads.
-        let unlimited_reads = message::ReaderOptions {
-            traversal_limit_in_words: None,
-            nesting_limit: 64,
+        Self::from_mmap(map_metadata(&f)?)
+    }
+
+    /// Reads a rootfs serialized in memory, e.g. one converted from an older manifest version.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        Self::from_mmap(copy_to_anon(data)?)
+    }
+
+    fn from_mmap(region: Mmap) -> Result<Self> {
+        let segments = map_segments(region)?;
+        let reader = message::Reader::new(segments, UNLIMITED_READS).into_typed();
+
+        Ok(Self {
+            reader,
+            verity_cache: Mutex::new(HashMap::new()),
+            shards: None,
+            limits: Limits::UNLIMITED,
+        })
+    }
+
+    /// Checks every inode against `limits` before reading it.
+    pub(crate) fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Lets the inodes of a sharded rootfs be read, by opening its shards with `open`. With
+    /// `verified`, the shards are checked against the fs-verity digests in the rootfs, which is
+    /// only meaningful if the rootfs itself was.
+    pub(crate) fn with_shards(mut self, open: Box<BlobOpener>, verified: bool) -> Self {
+        self.shards = Some(Shards {
+            open,
+            verified,
+            loaded: Mutex::new(HashMap::new()),
+        });
+        self
+    }
+
+    fn shard(&self, digest: &[u8; SHA256_BLOCK_SIZE]) -> Result<Arc<ShardReader>> {
+        let shards = self.shards.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "no blob store to open metadata shard {}",
+                    hex::encode(digest)
+                ),
+            )
+        })?;
+        if let Some(shard) = shards.loaded.lock().unwrap().get(digest) {
+            return Ok(Arc::clone(shard));
+        }
+
+        let verity = if shards.verified {
+            Some(self.find_verity(digest)?.ok_or_else(|| {
+                WireFormatError::InvalidFsVerityData(
+                    format!(
+                        "missing verity data for metadata shard {}",
+                        hex::encode(digest)
+                    ),
+                    Backtrace::capture(),
+                )
+            })?)
+        } else {
+            None
         };
-        let mmapped_region = unsafe { MmapOptions::new().map_copy_read_only(&f)? };
-        let segments = serialize::BufferSegments::new(mmapped_region, unlimited_reads)?;
-        let reader = message::Reader::new(segments, unlimited_reads).into_typed();
+        let file = (shards.open)(digest, verity.as_ref().map(|v| &v[..]))?;
+        let segments = map_segments(map_metadata(&file)?)?;
+        let shard = Arc::new(message::Reader::new(segments, UNLIMITED_READS).into_typed());
+        // another thread may have loaded it in the meantime, either copy will do
+        shards
+            .loaded
+            .lock()
+            .unwrap()
+            .insert(*digest, Arc::clone(&shard));
+        Ok(shard)
+    }
 
-        Ok(Self { reader })
+    // Calls `f` on inode `ino` if `layer` has it, loading the shard it's in if the layer is
+    // sharded.
+    fn with_inode<T>(
+        &self,
+        layer: crate::metadata_capnp::inode_vector::Reader<'_>,
+        ino: Ino,
+        f: impl FnOnce(crate::metadata_capnp::inode::Reader<'_>, ChunkTable<'_>) -> Result<T>,
+    ) -> Result<Option<T>> {
+        let f = |inode: crate::metadata_capnp::inode::Reader<'_>, chunk_table: ChunkTable<'_>| {
+            self.limits.check_inode(inode, chunk_table)?;
+            f(inode, chunk_table)
+        };
+        let inode_vector = InodeVector { reader: layer };
+        if !inode_vector.is_sharded()? {
+            let chunk_table = layer.get_chunk_table()?;
+            return inode_vector
+                .find_inode(ino)?
+                .map(|inode| f(inode, chunk_table))
+                .transpose();
+        }
+
+        let Some(digest) = inode_vector.find_shard(ino)? else {
+            return Ok(None);
+        };
+        let shard = self.shard(&digest)?;
+        let shard_vector = InodeVector {
+            reader: shard.get()?,
+        };
+        let chunk_table = shard_vector.reader.get_chunk_table()?;
+        let inode = shard_vector.find_inode(ino)?;
+        inode.map(|inode| f(inode, chunk_table)).transpose()
+    }
+
+    // All the inodes of `layer`, from all its shards if it's sharded.
+    fn layer_inodes(
+        &self,
+        layer: crate::metadata_capnp::inode_vector::Reader<'_>,
+    ) -> Result<Vec<Inode>> {
+        let read = |layer: crate::metadata_capnp::inode_vector::Reader<'_>| {
+            let chunk_table = layer.get_chunk_table()?;
+            for inode in layer.get_inodes()? {
+                self.limits.check_inode(inode, chunk_table)?;
+            }
+            InodeVector::from_capnp(layer)
+        };
+        let mut inodes = read(layer)?;
+        for shard in layer.get_shards()? {
+            let shard = self.shard(&shard.get_digest()?.try_into()?)?;
+            inodes.extend(read(shard.get()?)?);
+        }
+        Ok(inodes)
+    }
+
+    /// The digests of the blobs the inodes of this rootfs are sharded into, if it is.
+    pub fn shard_digests(&self) -> Result<Vec<[u8; SHA256_BLOCK_SIZE]>> {
+        let mut digests = Vec::new();
+        for layer in self.reader.get()?.get_metadatas()?.iter() {
+            for shard in layer.get_shards()? {
+                digests.push(shard.get_digest()?.try_into()?);
+            }
+        }
+        Ok(digests)
     }
 
     pub fn get_manifest_version(&self) -> Result<u64> {
         Ok(self.reader.get()?.get_manifest_version())
     }
 
+    pub fn get_features(&self) -> Result<Features> {
+        Ok(Features::from_bits(self.reader.get()?.get_features()))
+    }
+
+    pub fn get_digest_algorithm(&self) -> Result<DigestAlgorithm> {
+        let algorithm = self.reader.get()?.get_digest_algorithm();
+        Ok(DigestAlgorithm::from_capnp(
+            algorithm.map_err(capnp::Error::from)?,
+        ))
+    }
+
+    pub fn get_parent(&self) -> Result<Option<[u8; SHA256_BLOCK_SIZE]>> {
+        parent_from_capnp(self.reader.get()?)
+    }
+
     pub fn get_verity_data(&self) -> Result<VerityData> {
         let mut fs_verity_data = VerityData::new();
 
@@ -131,21 +473,122 @@ impl RootfsReader {
         Ok(fs_verity_data)
     }
 
+    /// Looks up the fs-verity digest of a single blob. The verity entries are serialized in
+    /// digest order (VerityData is a BTreeMap), so we binary search them instead of deserializing
+    /// the whole table up front.
+    pub fn find_verity(
+        &self,
+        digest: &[u8; SHA256_BLOCK_SIZE],
+    ) -> Result<Option<[u8; SHA256_BLOCK_SIZE]>> {
+        if let Some(verity) = self.verity_cache.lock().unwrap().get(digest) {
+            return Ok(Some(*verity));
+        }
+
+        let capnp_verities = self.reader.get()?.get_fs_verity_data()?;
+        let mut left = 0;
+        let mut right = capnp_verities.len();
+
+        while left < right {
+            let mid = left + (right - left) / 2;
+            let capnp_verity = capnp_verities.get(mid);
+
+            match capnp_verity.get_digest()?.cmp(&digest[..]) {
+                Ordering::Equal => {
+                    let verity: [u8; SHA256_BLOCK_SIZE] = capnp_verity.get_verity()?.try_into()?;
+                    let mut cache = self.verity_cache.lock().unwrap();
+                    if cache.len() >= VERITY_CACHE_SIZE {
+                        cache.clear();
+                    }
+                    cache.insert(*digest, verity);
+                    return Ok(Some(verity));
+                }
+                Ordering::Less => left = mid + 1,
+                Ordering::Greater => right = mid,
+            }
+        }
+
+        Ok(None)
+    }
+
     pub fn find_inode(&self, ino: u64) -> Result<Inode> {
+        match self.get_inode(ino)? {
+            // TODO: seems like this should really be an Option.
+            Some(Inode {
+                mode: InodeMode::Wht,
+                ..
+            })
+            | None => Err(WireFormatError::from_errno(Errno::ENOENT)),
+            Some(inode) => Ok(inode),
+        }
+    }
+
+    /// Returns the topmost version of an inode in this rootfs, including whiteouts, so that
+    /// callers stacking multiple rootfs blobs can tell a deleted inode apart from a missing one.
+    pub fn get_inode(&self, ino: u64) -> Result<Option<Inode>> {
         for layer in self.reader.get()?.get_metadatas()?.iter() {
-            let inode_vector = InodeVector { reader: layer };
+            if let Some(inode) = self.with_inode(layer, ino, Inode::from_capnp_in)? {
+                return Ok(Some(inode));
+            }
+        }
 
-            if let Some(inode) = inode_vector.find_inode(ino)? {
-                let inode = Inode::from_capnp(inode)?;
-                if let InodeMode::Wht = inode.mode {
-                    // TODO: seems like this should really be an Option.
-                    return Err(WireFormatError::from_errno(Errno::ENOENT));
-                }
-                return Ok(inode);
+        Ok(None)
+    }
+
+    /// Looks an inode up in the inodes a delta image adds on top of its base, which come first;
+    /// get_inode looks in the whole rootfs.
+    pub fn get_delta_inode(&self, ino: u64) -> Result<Option<Inode>> {
+        let layers = self.reader.get()?.get_metadatas()?;
+        if layers.is_empty() {
+            return Ok(None);
+        }
+        self.with_inode(layers.get(0), ino, Inode::from_capnp_in)
+    }
+
+    /// Like get_inode, below the inodes of the delta: in the copy of the base image the rootfs
+    /// of a delta carries, unless it's a thin one.
+    pub fn get_base_inode(&self, ino: u64) -> Result<Option<Inode>> {
+        for layer in self.reader.get()?.get_metadatas()?.iter().skip(1) {
+            if let Some(inode) = self.with_inode(layer, ino, Inode::from_capnp_in)? {
+                return Ok(Some(inode));
             }
         }
 
-        Err(WireFormatError::from_errno(Errno::ENOENT))
+        Ok(None)
+    }
+
+    /// Looks up `name` in the directory `ino`, without deserializing the whole directory. The
+    /// entries are binary searched if the builder recorded they're sorted. In casefolded images,
+    /// a name without an exact match is looked up again ignoring case, going through all the
+    /// entries.
+    pub fn lookup_name(&self, ino: Ino, name: &[u8]) -> Result<NameLookup> {
+        let fold = self.get_features()?.contains(Features::CASEFOLD);
+        for layer in self.reader.get()?.get_metadatas()?.iter() {
+            if let Some(found) =
+                self.with_inode(layer, ino, |inode, _| lookup_in_dir(inode, name, fold))?
+            {
+                return Ok(found);
+            }
+        }
+
+        Ok(NameLookup::NoInode)
+    }
+
+    /// The digests of the blobs holding the data of the files in this rootfs, not counting the
+    /// ones of its parent.
+    pub fn blob_digests(&self) -> Result<HashSet<[u8; SHA256_BLOCK_SIZE]>> {
+        let mut digests = HashSet::new();
+        for layer in self.reader.get()?.get_metadatas()?.iter() {
+            for inode in self.layer_inodes(layer)? {
+                if let InodeMode::File { chunks } = inode.mode {
+                    digests.extend(
+                        chunks
+                            .iter()
+                            .filter_map(|chunk| chunk.blob.map(|b| b.digest)),
+                    );
+                }
+            }
+        }
+        Ok(digests)
     }
 
     pub fn max_inode(&self) -> Result<Ino> {
@@ -160,12 +603,122 @@ impl RootfsReader {
     }
 }
 
+// Looks up `name` in the directory `inode`, see [`RootfsReader::lookup_name`].
+fn lookup_in_dir(
+    inode: crate::metadata_capnp::inode::Reader<'_>,
+    name: &[u8],
+    fold: bool,
+) -> Result<NameLookup> {
+    let mode = inode.get_mode().which().map_err(capnp::Error::from)?;
+    let crate::metadata_capnp::inode::mode::Dir(dir) = mode else {
+        return Ok(NameLookup::NotDir);
+    };
+    let dir = dir?;
+    let entries = dir.get_entries()?;
+    let found = if dir.get_sorted() {
+        let mut left = 0;
+        let mut right = entries.len();
+        let mut found = None;
+        while left < right {
+            let mid = left + (right - left) / 2;
+            let entry = entries.get(mid);
+            match entry.get_name()?.cmp(name) {
+                Ordering::Equal => {
+                    found = Some(entry.get_ino());
+                    break;
+                }
+                Ordering::Less => left = mid + 1,
+                Ordering::Greater => right = mid,
+            }
+        }
+        found
+    } else {
+        let mut found = None;
+        for entry in entries.iter() {
+            if entry.get_name()? == name {
+                found = Some(entry.get_ino());
+                break;
+            }
+        }
+        found
+    };
+    let found = match found {
+        None if fold => {
+            let name = casefold(name);
+            let mut found = None;
+            for entry in entries.iter() {
+                if casefold(entry.get_name()?) == name {
+                    found = Some(entry.get_ino());
+                    break;
+                }
+            }
+            found
+        }
+        found => found,
+    };
+
+    Ok(match found {
+        Some(ino) => NameLookup::Found(ino),
+        None => NameLookup::Missing {
+            look_below: dir.get_look_below(),
+        },
+    })
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    #[default]
+    Zstd,
+    Lz4,
+    Xz,
+}
+
+impl CompressionAlgorithm {
+    fn from_capnp(algorithm: crate::metadata_capnp::CompressionAlgorithm) -> Self {
+        match algorithm {
+            crate::metadata_capnp::CompressionAlgorithm::Zstd => CompressionAlgorithm::Zstd,
+            crate::metadata_capnp::CompressionAlgorithm::Lz4 => CompressionAlgorithm::Lz4,
+            crate::metadata_capnp::CompressionAlgorithm::Xz => CompressionAlgorithm::Xz,
+        }
+    }
+
+    fn to_capnp(self) -> crate::metadata_capnp::CompressionAlgorithm {
+        match self {
+            CompressionAlgorithm::Zstd => crate::metadata_capnp::CompressionAlgorithm::Zstd,
+            CompressionAlgorithm::Lz4 => crate::metadata_capnp::CompressionAlgorithm::Lz4,
+            CompressionAlgorithm::Xz => crate::metadata_capnp::CompressionAlgorithm::Xz,
+        }
+    }
+}
+
+fn cipher_from_capnp(cipher: crate::metadata_capnp::Cipher) -> Option<Cipher> {
+    match cipher {
+        crate::metadata_capnp::Cipher::None => None,
+        crate::metadata_capnp::Cipher::Aes256Gcm => Some(Cipher::Aes256Gcm),
+        crate::metadata_capnp::Cipher::Xchacha20Poly1305 => Some(Cipher::XChaCha20Poly1305),
+    }
+}
+
+fn cipher_to_capnp(cipher: Option<Cipher>) -> crate::metadata_capnp::Cipher {
+    match cipher {
+        None => crate::metadata_capnp::Cipher::None,
+        Some(Cipher::Aes256Gcm) => crate::metadata_capnp::Cipher::Aes256Gcm,
+        Some(Cipher::XChaCha20Poly1305) => crate::metadata_capnp::Cipher::Xchacha20Poly1305,
+    }
+}
+
 // TODO: should this be an ociv1 digest and include size and media type?
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
 pub struct BlobRef {
+    #[serde(serialize_with = "json::hex")]
     pub digest: [u8; SHA256_BLOCK_SIZE],
     pub offset: u64,
     pub compressed: bool,
+    /// only meaningful for compressed blobs
+    pub algorithm: CompressionAlgorithm,
+    /// blobs of encrypted images are decrypted with the image key before decompressing them
+    pub encryption: Option<Cipher>,
 }
 
 impl BlobRef {
@@ -175,37 +728,73 @@ impl BlobRef {
             digest: digest.try_into()?,
             offset: reader.get_offset(),
             compressed: reader.get_compressed(),
+            algorithm: CompressionAlgorithm::from_capnp(
+                reader.get_algorithm().map_err(capnp::Error::from)?,
+            ),
+            encryption: cipher_from_capnp(reader.get_encryption().map_err(capnp::Error::from)?),
         })
     }
     pub fn fill_capnp(&self, builder: &mut crate::metadata_capnp::blob_ref::Builder<'_>) {
         builder.set_digest(&self.digest);
         builder.set_offset(self.offset);
         builder.set_compressed(self.compressed);
+        builder.set_algorithm(self.algorithm.to_capnp());
+        builder.set_encryption(cipher_to_capnp(self.encryption));
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize)]
 pub struct DirEnt {
     pub ino: Ino,
+    #[serde(serialize_with = "json::bytes")]
     pub name: Vec<u8>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize)]
 pub struct DirList {
     // TODO: flags instead?
     pub look_below: bool,
     pub entries: Vec<DirEnt>,
 }
 
+impl DirList {
+    /// Whether the entries are in strictly increasing name order, which the builder writes them
+    /// in, and which lets readers binary search them.
+    pub fn is_sorted(&self) -> bool {
+        self.entries.windows(2).all(|w| w[0].name < w[1].name)
+    }
+}
+
+/// What looking up a name in a directory of a rootfs found, see [`RootfsReader::lookup_name`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameLookup {
+    /// The rootfs doesn't have the inode.
+    NoInode,
+    /// The inode isn't a directory, or is a whiteout.
+    NotDir,
+    Found(Ino),
+    /// The directory doesn't have an entry by that name; with look_below, the same directory in
+    /// lower layers may.
+    Missing {
+        look_below: bool,
+    },
+}
+
 #[derive(Debug)]
 pub struct FileChunkList {
     pub chunks: Vec<FileChunk>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Hash, Serialize)]
 pub struct FileChunk {
-    pub blob: BlobRef,
+    /// None for a hole in a sparse file, which reads as zeros, or for inline data
+    pub blob: Option<BlobRef>,
     pub len: u64,
+    /// crc32 of the chunk data, see [`FileChunk::verify`]
+    pub checksum: Option<u32>,
+    /// The data of the chunk, for tiny files stored in their inode instead of in a blob
+    #[serde(serialize_with = "json::opt_hex")]
+    pub inline: Option<Vec<u8>>,
 }
 
 pub type Ino = u64;
@@ -213,15 +802,97 @@ pub type Ino = u64;
 impl FileChunk {
     pub fn from_capnp(reader: crate::metadata_capnp::file_chunk::Reader<'_>) -> Result<Self> {
         let len = reader.get_len();
-        let blob = BlobRef::from_capnp(reader.get_blob()?)?;
+        let inline = if reader.has_inline_data() {
+            let data = reader.get_inline_data()?.to_vec();
+            if data.len() as u64 != len {
+                return Err(WireFormatError::InvalidSerializedData(Backtrace::capture()));
+            }
+            Some(data)
+        } else {
+            None
+        };
+        let blob = if reader.get_hole() || inline.is_some() {
+            None
+        } else {
+            Some(BlobRef::from_capnp(reader.get_blob()?)?)
+        };
+
+        let checksum = reader.get_has_checksum().then(|| reader.get_checksum());
+
+        Ok(FileChunk {
+            blob,
+            len,
+            checksum,
+            inline,
+        })
+    }
+
+    pub fn fill_capnp(&self, builder: &mut crate::metadata_capnp::file_chunk::Builder<'_>) {
+        builder.set_len(self.len);
+        match (&self.blob, &self.inline) {
+            (Some(blob), _) => {
+                let mut blob_ref_builder = builder.reborrow().init_blob();
+                blob.fill_capnp(&mut blob_ref_builder);
+            }
+            (None, Some(data)) => builder.set_inline_data(data),
+            (None, None) => builder.set_hole(true),
+        }
+        if let Some(checksum) = self.checksum {
+            builder.set_checksum(checksum);
+            builder.set_has_checksum(true);
+        }
+    }
+
+    pub fn hole(len: u64) -> Self {
+        FileChunk {
+            blob: None,
+            len,
+            checksum: None,
+            inline: None,
+        }
+    }
+
+    pub fn inline(data: Vec<u8>) -> Self {
+        FileChunk {
+            blob: None,
+            len: data.len() as u64,
+            checksum: None,
+            inline: Some(data),
+        }
+    }
+
+    pub fn is_hole(&self) -> bool {
+        self.blob.is_none() && self.inline.is_none()
+    }
+
+    pub fn checksum(data: &[u8]) -> u32 {
+        crc32fast::hash(data)
+    }
 
-        Ok(FileChunk { blob, len })
+    /// Checks the data of the chunk against its checksum, if it has one. This is much cheaper
+    /// than fs-verity, and catches corruption on the local storage when it isn't enabled.
+    pub fn verify(&self, data: &[u8]) -> Result<()> {
+        let Some(expected) = self.checksum else {
+            return Ok(());
+        };
+        let actual = Self::checksum(data);
+        if actual != expected {
+            return Err(WireFormatError::CorruptedChunk(
+                format!(
+                    "checksum {actual:08x} of {} bytes, expected {expected:08x}",
+                    data.len()
+                ),
+                Backtrace::capture(),
+            ));
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::format::{AclEntry, AclTag};
 
     const DEFAULT_DIRECTORY_PERMISSIONS: u16 = 0o755;
 
@@ -259,6 +930,8 @@ mod tests {
                 0xAA, 0x3C, 0x25, 0xDD,
             ],
             compressed: true,
+            algorithm: CompressionAlgorithm::Lz4,
+            encryption: Some(Cipher::XChaCha20Poly1305),
         };
         blobref_roundtrip(local)
     }
@@ -266,6 +939,35 @@ mod tests {
     #[test]
     fn test_inode_is_constant_serialized_size() {
         // TODO: this is the sort of think quickcheck is perfect for...
+        let acl = Acl::new(vec![
+            AclEntry {
+                tag: AclTag::UserObj,
+                id: 0,
+                perm: 7,
+            },
+            AclEntry {
+                tag: AclTag::Group,
+                id: 100,
+                perm: 5,
+            },
+            AclEntry {
+                tag: AclTag::GroupObj,
+                id: 0,
+                perm: 5,
+            },
+            AclEntry {
+                tag: AclTag::Mask,
+                id: 0,
+                perm: 5,
+            },
+            AclEntry {
+                tag: AclTag::Other,
+                id: 0,
+                perm: 0,
+            },
+        ])
+        .unwrap()
+        .to_xattr();
         let testcases = vec![
             Inode {
                 ino: 0,
@@ -274,6 +976,11 @@ mod tests {
                 gid: 0,
                 permissions: 0,
                 additional: None,
+                nlink: 1,
+                mtime: None,
+                ctime: None,
+                digest: None,
+                verity: None,
             },
             Inode {
                 ino: 0,
@@ -282,27 +989,52 @@ mod tests {
                 gid: 0,
                 permissions: 0,
                 additional: None,
+                nlink: 1,
+                mtime: None,
+                ctime: None,
+                digest: None,
+                verity: None,
             },
             Inode {
                 ino: 0,
                 mode: InodeMode::File {
-                    chunks: vec![FileChunk {
-                        blob: BlobRef {
-                            digest: [
-                                0x12, 0x44, 0xFE, 0xDD, 0x13, 0x39, 0x88, 0x12, 0x48, 0xA8, 0xF8,
-                                0xE4, 0x22, 0x12, 0x15, 0x16, 0x12, 0x44, 0xFE, 0xDD, 0x31, 0x93,
-                                0x88, 0x21, 0x84, 0x8A, 0xF8, 0x4E, 0x22, 0x12, 0x51, 0x16,
-                            ],
-                            offset: 100,
-                            compressed: true,
+                    chunks: vec![
+                        FileChunk {
+                            blob: Some(BlobRef {
+                                digest: [
+                                    0x12, 0x44, 0xFE, 0xDD, 0x13, 0x39, 0x88, 0x12, 0x48, 0xA8,
+                                    0xF8, 0xE4, 0x22, 0x12, 0x15, 0x16, 0x12, 0x44, 0xFE, 0xDD,
+                                    0x31, 0x93, 0x88, 0x21, 0x84, 0x8A, 0xF8, 0x4E, 0x22, 0x12,
+                                    0x51, 0x16,
+                                ],
+                                offset: 100,
+                                compressed: true,
+                                algorithm: CompressionAlgorithm::Zstd,
+                                encryption: None,
+                            }),
+                            len: 100,
+                            checksum: Some(0xdeadbeef),
+                            inline: None,
                         },
-                        len: 100,
-                    }],
+                        FileChunk::hole(4096),
+                        FileChunk::inline(b"tiny".to_vec()),
+                    ],
                 },
                 uid: 0,
                 gid: 0,
                 permissions: DEFAULT_FILE_PERMISSIONS,
                 additional: None,
+                nlink: 1,
+                mtime: Some(Timestamp {
+                    sec: 1_700_000_000,
+                    nsec: 123,
+                }),
+                ctime: Some(Timestamp {
+                    sec: -1,
+                    nsec: 999_999_999,
+                }),
+                digest: Some(FileDigest::of(DigestAlgorithm::Blake3, b"contents")),
+                verity: Some([0x5a; SHA256_BLOCK_SIZE]),
             },
             Inode {
                 ino: 65343,
@@ -314,6 +1046,11 @@ mod tests {
                 gid: 10000,
                 permissions: DEFAULT_DIRECTORY_PERMISSIONS,
                 additional: None,
+                nlink: 1,
+                mtime: None,
+                ctime: None,
+                digest: None,
+                verity: None,
             },
             Inode {
                 ino: 0,
@@ -328,6 +1065,41 @@ mod tests {
                     }],
                     symlink_target: Some(b"some/other/path".to_vec()),
                 }),
+                nlink: 1,
+                mtime: None,
+                ctime: None,
+                digest: None,
+                verity: None,
+            },
+            Inode {
+                ino: 0,
+                mode: InodeMode::Dir {
+                    dir_list: DirList {
+                        look_below: false,
+                        entries: Vec::new(),
+                    },
+                },
+                uid: 0,
+                gid: 0,
+                permissions: DEFAULT_DIRECTORY_PERMISSIONS,
+                additional: Some(InodeAdditional {
+                    xattrs: vec![
+                        Xattr {
+                            key: ACL_DEFAULT_XATTR.to_vec(),
+                            val: acl.clone(),
+                        },
+                        Xattr {
+                            key: b"user.a".to_vec(),
+                            val: b"b".to_vec(),
+                        },
+                    ],
+                    symlink_target: None,
+                }),
+                nlink: 1,
+                mtime: None,
+                ctime: None,
+                digest: None,
+                verity: None,
             },
         ];
 
@@ -345,9 +1117,262 @@ mod tests {
             assert_eq!(test, after);
         }
     }
+
+    #[test]
+    fn test_ino_index() {
+        let inode = |ino| Inode {
+            ino,
+            ..Inode::new_whiteout(0)
+        };
+        for (inos, indexed) in [(vec![1, 2, 3, 5, 6], true), (vec![2, 1_000_000], false)] {
+            let inodes = inos.iter().map(|ino| inode(*ino)).collect::<Vec<_>>();
+            let mut message = ::capnp::message::Builder::new_default();
+            let mut builder =
+                message.init_root::<crate::metadata_capnp::inode_vector::Builder<'_>>();
+            InodeVector::fill_capnp(&inodes, &mut builder, false).unwrap();
+            let reader = InodeVector {
+                reader: builder.into_reader(),
+            };
+
+            assert_eq!(reader.reader.has_ino_index(), indexed);
+            for ino in 0..8 {
+                let found = reader.find_inode(ino).unwrap().map(|i| i.get_ino());
+                assert_eq!(found, inos.contains(&ino).then_some(ino));
+            }
+            assert_eq!(
+                reader.find_inode(1_000_000).unwrap().map(|i| i.get_ino()),
+                inos.contains(&1_000_000).then_some(1_000_000)
+            );
+        }
+    }
+
+    #[test]
+    fn test_chunk_table() -> anyhow::Result<()> {
+        let file = |ino, seed| Inode {
+            ino,
+            mode: InodeMode::File {
+                chunks: vec![FileChunk::hole(4096), FileChunk::inline(vec![seed; 10])],
+            },
+            ..Inode::new_whiteout(0)
+        };
+        let inodes = vec![file(2, 1), file(3, 2), file(4, 1), file(5, 1)];
+
+        let mut message = ::capnp::message::Builder::new_default();
+        let mut builder = message.init_root::<crate::metadata_capnp::inode_vector::Builder<'_>>();
+        InodeVector::fill_capnp(&inodes, &mut builder, true)?;
+        let reader = builder.into_reader();
+
+        assert_eq!(reader.get_chunk_table()?.len(), 1);
+        let capnp_inodes = reader.get_inodes()?;
+        for (i, shared) in [(0, true), (1, false), (2, true), (3, true)] {
+            let mode = capnp_inodes
+                .get(i)
+                .get_mode()
+                .which()
+                .map_err(capnp::Error::from)?;
+            assert_eq!(
+                matches!(mode, crate::metadata_capnp::inode::mode::SharedFile(0)),
+                shared
+            );
+        }
+        assert!(matches!(
+            Inode::from_capnp(capnp_inodes.get(0)),
+            Err(WireFormatError::InvalidSerializedData(..))
+        ));
+        assert_eq!(InodeVector::from_capnp(reader)?, inodes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lookup_name() {
+        let dir = |ino, names: &[&str]| Inode {
+            ino,
+            mode: InodeMode::Dir {
+                dir_list: DirList {
+                    look_below: false,
+                    entries: names
+                        .iter()
+                        .enumerate()
+                        .map(|(i, name)| DirEnt {
+                            ino: 10 * ino + i as u64,
+                            name: name.as_bytes().to_vec(),
+                        })
+                        .collect(),
+                },
+            },
+            ..Inode::new_whiteout(0)
+        };
+        let names = ["a", "bb", "c", "dd", "e"];
+        let unsorted = ["e", "a", "dd", "c", "bb"];
+        let rootfs = Rootfs {
+            metadatas: vec![vec![
+                dir(1, &names),
+                dir(2, &unsorted),
+                Inode::new_whiteout(3),
+            ]],
+            fs_verity_data: VerityData::new(),
+            manifest_version: 3,
+            parent: None,
+            features: Features::empty(),
+            digest_algorithm: DigestAlgorithm::default(),
+        };
+        let reader =
+            RootfsReader::from_bytes(&crate::builder::serialize_metadata(rootfs).unwrap()).unwrap();
+
+        for (ino, names) in [(1, names), (2, unsorted)] {
+            for (i, name) in names.iter().enumerate() {
+                assert_eq!(
+                    reader.lookup_name(ino, name.as_bytes()).unwrap(),
+                    NameLookup::Found(10 * ino + i as u64)
+                );
+            }
+            for missing in ["", "b", "f", "0"] {
+                assert_eq!(
+                    reader.lookup_name(ino, missing.as_bytes()).unwrap(),
+                    NameLookup::Missing { look_below: false }
+                );
+            }
+        }
+        assert_eq!(reader.lookup_name(3, b"a").unwrap(), NameLookup::NotDir);
+        assert_eq!(reader.lookup_name(4, b"a").unwrap(), NameLookup::NoInode);
+    }
+
+    #[test]
+    fn test_truncated_metadata() {
+        let rootfs = Rootfs {
+            metadatas: vec![vec![Inode::new_whiteout(1)]],
+            fs_verity_data: VerityData::new(),
+            manifest_version: 3,
+            parent: None,
+            features: Features::empty(),
+            digest_algorithm: DigestAlgorithm::default(),
+        };
+        let data = crate::builder::serialize_metadata(rootfs).unwrap();
+        assert!(RootfsReader::from_bytes(&data).is_ok());
+        for len in [8, data.len() - 8] {
+            let Err(e) = RootfsReader::from_bytes(&data[..len]) else {
+                panic!("read a rootfs truncated to {len} bytes");
+            };
+            assert!(
+                matches!(e, WireFormatError::TruncatedMetadata { offset, .. } if offset == len as u64),
+                "unexpected {e}"
+            );
+            assert_eq!(e.to_errno(), Errno::EUCLEAN as i32);
+        }
+    }
+
+    #[test]
+    fn test_fifo_and_socket_inodes() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let fifo = dir.path().join("fifo");
+        let socket = dir.path().join("socket");
+        stat::mknod(&fifo, stat::SFlag::S_IFIFO, stat::Mode::S_IRWXU, 0)?;
+        stat::mknod(&socket, stat::SFlag::S_IFSOCK, stat::Mode::S_IRWXU, 0)?;
+        fs::set_permissions(&fifo, fs::Permissions::from_mode(0o1640))?;
+        fs::set_permissions(&socket, fs::Permissions::from_mode(0o751))?;
+
+        for (ino, path, mode, permissions) in [
+            (2, &fifo, InodeMode::Fifo, 0o1640),
+            (3, &socket, InodeMode::Sock, 0o751),
+        ] {
+            let md = fs::symlink_metadata(path)?;
+            let inode = Inode::new_other(ino, &md, None)?;
+            assert_eq!(inode.mode, mode);
+            assert_eq!(inode.permissions, permissions);
+            assert_eq!((inode.uid, inode.gid), (md.uid(), md.gid()));
+
+            let wire = inode.to_wire()?;
+            let message_reader = serialize::read_message_from_flat_slice(
+                &mut &wire[..],
+                ::capnp::message::ReaderOptions::new(),
+            )?;
+            let inode_reader =
+                message_reader.get_root::<crate::metadata_capnp::inode::Reader<'_>>()?;
+            assert_eq!(Inode::from_capnp(inode_reader)?, inode);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_symlink_targets() -> anyhow::Result<()> {
+        let symlink = |target: &[u8]| Inode {
+            additional: Some(InodeAdditional {
+                xattrs: Vec::new(),
+                symlink_target: Some(target.to_vec()),
+            }),
+            mode: InodeMode::Lnk,
+            ..Inode::new_whiteout(2)
+        };
+        symlink("../a/b".repeat(600).as_bytes()).to_wire()?;
+        for invalid in [&b""[..], b"a\0b", &[b'a'; 4096]] {
+            assert!(matches!(
+                symlink(invalid).to_wire(),
+                Err(WireFormatError::InvalidSymlinkTarget(..))
+            ));
+        }
+
+        // crafted images can hold targets the builder refuses to write
+        for invalid in [&b""[..], b"\0", b"usr/\0bin"] {
+            let mut message = ::capnp::message::Builder::new_default();
+            let mut additional =
+                message.init_root::<crate::metadata_capnp::inode_additional::Builder<'_>>();
+            additional.set_symlink_target(invalid);
+            let e = InodeAdditional::from_capnp(additional.into_reader()).unwrap_err();
+            assert!(matches!(e, WireFormatError::InvalidSymlinkTarget(..)));
+            assert_eq!(e.to_errno(), Errno::EIO as i32);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_timestamp_system_time() {
+        let timestamp = |sec, nsec| Timestamp { sec, nsec }.system_time();
+        let epoch = SystemTime::UNIX_EPOCH;
+        assert_eq!(timestamp(0, 0), epoch);
+        assert_eq!(
+            timestamp(1_700_000_000, 5),
+            epoch + Duration::new(1_700_000_000, 5)
+        );
+        assert_eq!(
+            timestamp(-1, 500_000_000),
+            epoch - Duration::from_millis(500)
+        );
+    }
+}
+
+/// A point in time with nanosecond precision, as in struct timespec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Timestamp {
+    /// seconds since the Unix epoch, negative before it
+    pub sec: i64,
+    pub nsec: u32,
+}
+
+impl Timestamp {
+    fn from_capnp(reader: crate::metadata_capnp::timestamp::Reader<'_>) -> Self {
+        Timestamp {
+            sec: reader.get_sec(),
+            nsec: reader.get_nsec(),
+        }
+    }
+
+    fn fill_capnp(&self, builder: &mut crate::metadata_capnp::timestamp::Builder<'_>) {
+        builder.set_sec(self.sec);
+        builder.set_nsec(self.nsec);
+    }
+
+    pub fn system_time(&self) -> SystemTime {
+        let since_epoch = Duration::new(self.sec.unsigned_abs(), 0);
+        let seconds = if self.sec < 0 {
+            SystemTime::UNIX_EPOCH - since_epoch
+        } else {
+            SystemTime::UNIX_EPOCH + since_epoch
+        };
+        seconds + Duration::from_nanos(self.nsec.into())
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize)]
 pub struct Inode {
     pub ino: Ino,
     pub mode: InodeMode,
@@ -355,32 +1380,109 @@ pub struct Inode {
     pub gid: u32,
     pub permissions: u16,
     pub additional: Option<InodeAdditional>,
+    pub nlink: u32,
+    /// None for images built before timestamps were stored and for reproducible builds, where
+    /// the files are from the epoch.
+    pub mtime: Option<Timestamp>,
+    pub ctime: Option<Timestamp>,
+    /// The digest of the contents of regular files, None for the other inodes and in images
+    /// built before file digests were stored.
+    pub digest: Option<FileDigest>,
+    /// The fs-verity digest of the contents of regular files, as the kernel measures them once
+    /// fs-verity is enabled on them (sha256, 4096 byte blocks, no salt), for images built with
+    /// [`crate::builder::BuildOptions::file_verity`].
+    #[serde(serialize_with = "json::opt_hex")]
+    pub verity: Option<[u8; SHA256_BLOCK_SIZE]>,
 }
 
 impl Inode {
+    /// Reads an inode on its own; it fails on files whose chunk list is in the chunk table of
+    /// their inode vector, which [`InodeVector::from_capnp`] reads them with.
     pub fn from_capnp(reader: crate::metadata_capnp::inode::Reader<'_>) -> Result<Self> {
+        Self::read_capnp(reader, None)
+    }
+
+    // Reads an inode of an inode vector, whose chunk table is `chunk_table`.
+    pub(crate) fn from_capnp_in(
+        reader: crate::metadata_capnp::inode::Reader<'_>,
+        chunk_table: ChunkTable<'_>,
+    ) -> Result<Self> {
+        Self::read_capnp(reader, Some(chunk_table))
+    }
+
+    fn read_capnp(
+        reader: crate::metadata_capnp::inode::Reader<'_>,
+        chunk_table: Option<ChunkTable<'_>>,
+    ) -> Result<Self> {
         Ok(Inode {
             ino: reader.get_ino(),
-            mode: InodeMode::from_capnp(reader.get_mode())?,
+            mode: InodeMode::from_capnp(reader.get_mode(), chunk_table)?,
             uid: reader.get_uid(),
             gid: reader.get_gid(),
             permissions: reader.get_permissions(),
             additional: InodeAdditional::from_capnp(reader.get_additional()?)?,
+            nlink: reader.get_nlink(),
+            mtime: if reader.has_mtime() {
+                Some(Timestamp::from_capnp(reader.get_mtime()?))
+            } else {
+                None
+            },
+            ctime: if reader.has_ctime() {
+                Some(Timestamp::from_capnp(reader.get_ctime()?))
+            } else {
+                None
+            },
+            digest: if reader.has_digest() {
+                FileDigest::from_capnp(reader.get_digest()?)?
+            } else {
+                None
+            },
+            verity: if reader.has_verity() {
+                Some(reader.get_verity()?.try_into()?)
+            } else {
+                None
+            },
         })
     }
 
     pub fn fill_capnp(
         &self,
         builder: &mut crate::metadata_capnp::inode::Builder<'_>,
+    ) -> Result<()> {
+        self.fill_capnp_in(builder, None)
+    }
+
+    // Like fill_capnp, referring to entry `shared_chunks` of the chunk table of the inode vector
+    // instead of storing the chunk list of a file.
+    fn fill_capnp_in(
+        &self,
+        builder: &mut crate::metadata_capnp::inode::Builder<'_>,
+        shared_chunks: Option<u32>,
     ) -> Result<()> {
         builder.set_ino(self.ino);
 
         let mut mode_builder = builder.reborrow().init_mode();
-        self.mode.fill_capnp(&mut mode_builder)?;
+        match shared_chunks {
+            Some(index) => mode_builder.set_shared_file(index),
+            None => self.mode.fill_capnp(&mut mode_builder)?,
+        }
 
         builder.set_uid(self.uid);
         builder.set_gid(self.gid);
         builder.set_permissions(self.permissions);
+        builder.set_nlink(self.nlink);
+        if let Some(mtime) = &self.mtime {
+            mtime.fill_capnp(&mut builder.reborrow().init_mtime());
+        }
+        if let Some(ctime) = &self.ctime {
+            ctime.fill_capnp(&mut builder.reborrow().init_ctime());
+        }
+        if let Some(digest) = &self.digest {
+            digest.fill_capnp(&mut builder.reborrow().init_digest());
+        }
+        if let Some(verity) = &self.verity {
+            builder.set_verity(verity);
+        }
 
         if let Some(additional) = &self.additional {
             let mut additional_builder = builder.reborrow().init_additional();
@@ -409,6 +1511,8 @@ impl Inode {
         md: &fs::Metadata,
         file_chunks: Vec<FileChunk>,
         additional: Option<InodeAdditional>,
+        digest: Option<FileDigest>,
+        verity: Option<[u8; SHA256_BLOCK_SIZE]>,
     ) -> io::Result<Self> {
         if !md.is_file() {
             return Err(io::Error::other(format!("{ino} is a file")));
@@ -417,7 +1521,11 @@ impl Inode {
         let mode = InodeMode::File {
             chunks: file_chunks,
         };
-        Ok(Self::new_inode(ino, md, mode, additional))
+        Ok(Inode {
+            digest,
+            verity,
+            ..Self::new_inode(ino, md, mode, additional)
+        })
     }
 
     pub fn new_other(
@@ -459,6 +1567,11 @@ impl Inode {
             gid: 0,
             permissions: DEFAULT_FILE_PERMISSIONS,
             additional: None,
+            nlink: 0,
+            mtime: None,
+            ctime: None,
+            digest: None,
+            verity: None,
         }
     }
 
@@ -476,6 +1589,17 @@ impl Inode {
             // only preserve rwx permissions for user, group, others (9 bits) and SUID/SGID/sticky bit (3 bits)
             permissions: (md.permissions().mode() & 0xFFF) as u16,
             additional,
+            nlink: 1,
+            mtime: Some(Timestamp {
+                sec: md.mtime(),
+                nsec: md.mtime_nsec() as u32,
+            }),
+            ctime: Some(Timestamp {
+                sec: md.ctime(),
+                nsec: md.ctime_nsec() as u32,
+            }),
+            digest: None,
+            verity: None,
         }
     }
 
@@ -527,7 +1651,8 @@ impl Inode {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum InodeMode {
     Unknown,
     Fifo,
@@ -541,7 +1666,10 @@ pub enum InodeMode {
 }
 
 impl InodeMode {
-    fn from_capnp(reader: crate::metadata_capnp::inode::mode::Reader<'_>) -> Result<Self> {
+    fn from_capnp(
+        reader: crate::metadata_capnp::inode::mode::Reader<'_>,
+        chunk_table: Option<ChunkTable<'_>>,
+    ) -> Result<Self> {
         match reader.which() {
             Ok(crate::metadata_capnp::inode::mode::Unknown(())) => Ok(InodeMode::Unknown),
             Ok(crate::metadata_capnp::inode::mode::Fifo(())) => Ok(InodeMode::Fifo),
@@ -570,6 +1698,17 @@ impl InodeMode {
                     .collect::<Result<Vec<FileChunk>>>()?;
                 Ok(InodeMode::File { chunks })
             }
+            Ok(crate::metadata_capnp::inode::mode::SharedFile(index)) => {
+                let chunk_table = chunk_table
+                    .filter(|table| index < table.len())
+                    .ok_or_else(|| WireFormatError::InvalidSerializedData(Backtrace::capture()))?;
+                let chunks = chunk_table
+                    .get(index)?
+                    .iter()
+                    .map(FileChunk::from_capnp)
+                    .collect::<Result<Vec<FileChunk>>>()?;
+                Ok(InodeMode::File { chunks })
+            }
             Ok(crate::metadata_capnp::inode::mode::Dir(reader)) => {
                 let r = reader?;
                 let entries = r
@@ -613,6 +1752,7 @@ impl InodeMode {
             Self::Dir { dir_list } => {
                 let mut dir_builder = builder.reborrow().init_dir();
                 dir_builder.set_look_below(dir_list.look_below);
+                dir_builder.set_sorted(dir_list.is_sorted());
                 let entries_len = dir_list.entries.len().try_into()?;
                 let mut entries_builder = dir_builder.reborrow().init_entries(entries_len);
 
@@ -634,10 +1774,7 @@ impl InodeMode {
 
                 for (i, chunk) in chunks.iter().enumerate() {
                     // we already checked that the length of chunks fits inside a u32
-                    let mut chunk_builder = chunks_builder.reborrow().get(i as u32);
-                    chunk_builder.set_len(chunk.len);
-                    let mut blob_ref_builder = chunk_builder.init_blob();
-                    chunk.blob.fill_capnp(&mut blob_ref_builder);
+                    chunk.fill_capnp(&mut chunks_builder.reborrow().get(i as u32));
                 }
             }
             Self::Lnk => builder.set_lnk(()),
@@ -648,9 +1785,26 @@ impl InodeMode {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+// symlink(2) takes the target as a C string, so an empty target or one holding a NUL can't come
+// from a filesystem, only from a crafted or corrupted image.
+fn check_symlink_target(target: &[u8]) -> Result<()> {
+    let message = if target.is_empty() {
+        "empty target".to_string()
+    } else if target.contains(&0) {
+        format!("NUL in the target {}", escape_name(target))
+    } else {
+        return Ok(());
+    };
+    Err(WireFormatError::InvalidSymlinkTarget(
+        message,
+        Backtrace::capture(),
+    ))
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
 pub struct InodeAdditional {
     pub xattrs: Vec<Xattr>,
+    #[serde(serialize_with = "json::opt_bytes")]
     pub symlink_target: Option<Vec<u8>>,
 }
 
@@ -658,7 +1812,11 @@ impl InodeAdditional {
     pub fn from_capnp(
         reader: crate::metadata_capnp::inode_additional::Reader<'_>,
     ) -> Result<Option<Self>> {
-        if !(reader.has_xattrs() || reader.has_symlink_target()) {
+        if !(reader.has_xattrs()
+            || reader.has_symlink_target()
+            || reader.has_access_acl()
+            || reader.has_default_acl())
+        {
             return Ok(None);
         }
 
@@ -669,9 +1827,25 @@ impl InodeAdditional {
                 xattrs.push(xattr);
             }
         }
+        // ACLs are handed out as the xattrs the kernel knows them as
+        if reader.has_access_acl() {
+            xattrs.push(Xattr {
+                key: ACL_ACCESS_XATTR.to_vec(),
+                val: Acl::from_capnp(reader.get_access_acl()?)?.to_xattr(),
+            });
+        }
+        if reader.has_default_acl() {
+            xattrs.push(Xattr {
+                key: ACL_DEFAULT_XATTR.to_vec(),
+                val: Acl::from_capnp(reader.get_default_acl()?)?.to_xattr(),
+            });
+        }
+        xattrs.sort_by(|a, b| a.key.cmp(&b.key));
 
         let symlink_target = if reader.has_symlink_target() {
-            Some(reader.get_symlink_target()?.to_vec())
+            let target = reader.get_symlink_target()?;
+            check_symlink_target(target)?;
+            Some(target.to_vec())
         } else {
             None
         };
@@ -686,16 +1860,41 @@ impl InodeAdditional {
         &self,
         builder: &mut crate::metadata_capnp::inode_additional::Builder<'_>,
     ) -> Result<()> {
-        let xattrs_len = self.xattrs.len().try_into()?;
+        let (acls, xattrs): (Vec<_>, Vec<_>) =
+            self.xattrs.iter().partition(|x| is_acl_xattr(&x.key));
+        let xattrs_len = xattrs.len().try_into()?;
         let mut xattrs_builder = builder.reborrow().init_xattrs(xattrs_len);
 
-        for (i, xattr) in self.xattrs.iter().enumerate() {
+        for (i, xattr) in xattrs.iter().enumerate() {
             // we already checked that the length of xattrs fits inside a u32
             let mut xattr_builder = xattrs_builder.reborrow().get(i as u32);
             xattr.fill_capnp(&mut xattr_builder);
         }
 
+        for xattr in acls {
+            let acl = Acl::from_xattr(&xattr.val)?;
+            let len = acl.entries().len().try_into()?;
+            let mut acl_builder = if xattr.key == ACL_ACCESS_XATTR {
+                builder.reborrow().init_access_acl(len)
+            } else {
+                builder.reborrow().init_default_acl(len)
+            };
+            acl.fill_capnp(&mut acl_builder);
+        }
+
         if let Some(symlink_target) = &self.symlink_target {
+            check_symlink_target(symlink_target)?;
+            // better to fail the build than to write a symlink readers reject by default
+            let max_len = Limits::default().max_symlink_len;
+            if symlink_target.len() > max_len as usize {
+                return Err(WireFormatError::InvalidSymlinkTarget(
+                    format!(
+                        "{} bytes long target, over the limit of {max_len}",
+                        symlink_target.len()
+                    ),
+                    Backtrace::capture(),
+                ));
+            }
             builder.set_symlink_target(symlink_target);
         }
 
@@ -721,7 +1920,7 @@ impl InodeAdditional {
     }
 
     fn get_xattrs(p: &Path) -> io::Result<Vec<Xattr>> {
-        xattr::list(p)?
+        let mut xattrs = xattr::list(p)?
             .map(|xa| {
                 let value = xattr::get(p, &xa)?;
                 Ok(Xattr {
@@ -729,13 +1928,31 @@ impl InodeAdditional {
                     val: value.unwrap(),
                 })
             })
-            .collect()
+            .collect::<io::Result<Vec<Xattr>>>()?;
+        // better to fail the build than to store ACLs which can't be read back
+        for xattr in xattrs.iter().filter(|x| is_acl_xattr(&x.key)) {
+            if let Err(e) = Acl::from_xattr(&xattr.val) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} of {}: {e}",
+                        String::from_utf8_lossy(&xattr.key),
+                        p.display()
+                    ),
+                ));
+            }
+        }
+        // the listing order depends on the underlying filesystem
+        xattrs.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(xattrs)
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
 pub struct Xattr {
+    #[serde(serialize_with = "json::bytes")]
     pub key: Vec<u8>,
+    #[serde(serialize_with = "json::hex")]
     pub val: Vec<u8>,
 }
 
@@ -752,6 +1969,37 @@ impl Xattr {
     }
 }
 
+/// A blob holding the inodes `first_ino` to `last_ino` of an inode vector, for rootfs sharded with
+/// [`crate::builder::BuildOptions::metadata_shard_size`], so that mounting doesn't fetch and
+/// verify all the metadata of huge images before the first lookup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetadataShard {
+    pub digest: [u8; SHA256_BLOCK_SIZE],
+    pub first_ino: Ino,
+    pub last_ino: Ino,
+}
+
+impl MetadataShard {
+    pub fn from_capnp(reader: crate::metadata_capnp::metadata_shard::Reader<'_>) -> Result<Self> {
+        Ok(MetadataShard {
+            digest: reader.get_digest()?.try_into()?,
+            first_ino: reader.get_first_ino(),
+            last_ino: reader.get_last_ino(),
+        })
+    }
+
+    pub fn fill_capnp(&self, builder: &mut crate::metadata_capnp::metadata_shard::Builder<'_>) {
+        builder.set_digest(&self.digest);
+        builder.set_first_ino(self.first_ino);
+        builder.set_last_ino(self.last_ino);
+    }
+}
+
+// An ino index takes 4 bytes per ino between the smallest and the largest of an inode vector; past
+// this many inos per inode, e.g. in a delta which changed a few inodes here and there, the vector
+// goes without one and lookups binary search it instead.
+const MAX_INO_INDEX_SPARSENESS: u64 = 4;
+
 pub struct InodeVector<'a> {
     reader: crate::metadata_capnp::inode_vector::Reader<'a>,
 }
@@ -765,8 +2013,26 @@ impl<'a> InodeVector<'a> {
     }
 
     pub fn find_inode(&self, ino: Ino) -> Result<Option<crate::metadata_capnp::inode::Reader<'_>>> {
-        let mut left = 0;
         let inodes = self.get_inode_vector()?;
+        let index = self.reader.get_ino_index()?;
+        if !index.is_empty() {
+            let slot = ino
+                .checked_sub(self.reader.get_first_indexed_ino())
+                .filter(|slot| *slot < index.len().into());
+            let Some(slot) = slot else {
+                return Ok(None);
+            };
+            return match index.get(slot as u32) {
+                0 => Ok(None),
+                position if position <= inodes.len() => Ok(Some(inodes.get(position - 1))),
+                _ => Err(WireFormatError::InvalidSerializedData(Backtrace::capture())),
+            };
+        }
+
+        if inodes.is_empty() {
+            return Ok(None);
+        }
+        let mut left = 0;
         let mut right = inodes.len() - 1;
 
         while left <= right {
@@ -792,38 +2058,137 @@ impl<'a> InodeVector<'a> {
     }
 
     pub fn max_ino(&self) -> Result<Option<Ino>> {
+        let shards = self.reader.get_shards()?;
+        if !shards.is_empty() {
+            return Ok(Some(shards.get(shards.len() - 1).get_last_ino()));
+        }
         let inodes = self.get_inode_vector()?;
+        if inodes.is_empty() {
+            return Ok(None);
+        }
         let last_index = inodes.len() - 1;
         Ok(Some(inodes.get(last_index).get_ino()))
     }
 
+    pub fn is_sharded(&self) -> Result<bool> {
+        Ok(!self.reader.get_shards()?.is_empty())
+    }
+
+    /// The digest of the shard holding `ino`, if there's one whose range covers it.
+    pub fn find_shard(&self, ino: Ino) -> Result<Option<[u8; SHA256_BLOCK_SIZE]>> {
+        let shards = self.reader.get_shards()?;
+        let mut left = 0;
+        let mut right = shards.len();
+        while left < right {
+            let mid = left + (right - left) / 2;
+            let shard = shards.get(mid);
+            if ino < shard.get_first_ino() {
+                right = mid;
+            } else if ino > shard.get_last_ino() {
+                left = mid + 1;
+            } else {
+                return Ok(Some(shard.get_digest()?.try_into()?));
+            }
+        }
+        Ok(None)
+    }
+
     pub fn from_capnp(
         reader: crate::metadata_capnp::inode_vector::Reader<'a>,
     ) -> Result<Vec<Inode>> {
+        let chunk_table = reader.get_chunk_table()?;
         reader
             .get_inodes()?
             .iter()
-            .map(|inode| Inode::from_capnp(inode))
+            .map(|inode| Inode::from_capnp_in(inode, chunk_table))
             .collect()
     }
 
-    fn fill_capnp(
+    /// With `chunk_table`, the chunk lists several files have are stored once, in the chunk
+    /// table of the vector, which needs [`Features::CHUNK_TABLE`].
+    pub(crate) fn fill_capnp(
         inodes: &[Inode],
         builder: &mut crate::metadata_capnp::inode_vector::Builder<'_>,
+        chunk_table: bool,
     ) -> Result<()> {
+        let (table, shared) = if chunk_table {
+            shared_chunk_lists(inodes)
+        } else {
+            Default::default()
+        };
         let inodes_len = inodes.len().try_into()?;
         let mut capnp_inodes = builder.reborrow().init_inodes(inodes_len);
 
         for (i, inode) in inodes.iter().enumerate() {
             // we already checked that the length of pfs_inodes fits inside a u32
             let mut capnp_inode = capnp_inodes.reborrow().get(i as u32);
-            inode.fill_capnp(&mut capnp_inode)?;
+            let shared_chunks = match &inode.mode {
+                InodeMode::File { chunks } => shared.get(&chunks[..]).copied(),
+                _ => None,
+            };
+            inode.fill_capnp_in(&mut capnp_inode, shared_chunks)?;
+        }
+
+        if !table.is_empty() {
+            let mut capnp_table = builder.reborrow().init_chunk_table(table.len().try_into()?);
+            for (i, chunks) in table.iter().enumerate() {
+                let mut capnp_chunks = capnp_table
+                    .reborrow()
+                    .init(i as u32, chunks.len().try_into()?);
+                for (j, chunk) in chunks.iter().enumerate() {
+                    chunk.fill_capnp(&mut capnp_chunks.reborrow().get(j as u32));
+                }
+            }
+        }
+
+        let (Some(first), Some(last)) = (
+            inodes.iter().map(|i| i.ino).min(),
+            inodes.iter().map(|i| i.ino).max(),
+        ) else {
+            return Ok(());
+        };
+        let span = last - first + 1;
+        if span > inodes.len() as u64 * MAX_INO_INDEX_SPARSENESS || span > u32::MAX.into() {
+            return Ok(());
+        }
+        builder.set_first_indexed_ino(first);
+        let mut index = builder.reborrow().init_ino_index(span as u32);
+        for (i, inode) in inodes.iter().enumerate() {
+            index.set((inode.ino - first) as u32, i as u32 + 1);
         }
 
         Ok(())
     }
 }
 
+// The chunk lists which several files of `inodes` have, in the order the first of them comes
+// in, so that builds stay reproducible, along with their index in that order.
+fn shared_chunk_lists(inodes: &[Inode]) -> (Vec<&[FileChunk]>, HashMap<&[FileChunk], u32>) {
+    let mut files = HashMap::<&[FileChunk], usize>::new();
+    for inode in inodes {
+        if let InodeMode::File { chunks } = &inode.mode {
+            if !chunks.is_empty() {
+                *files.entry(&chunks[..]).or_default() += 1;
+            }
+        }
+    }
+
+    let mut table = Vec::new();
+    let mut shared = HashMap::new();
+    for inode in inodes {
+        let InodeMode::File { chunks } = &inode.mode else {
+            continue;
+        };
+        if files.get(&chunks[..]).is_some_and(|files| *files > 1)
+            && !shared.contains_key(&chunks[..])
+        {
+            shared.insert(&chunks[..], table.len() as u32);
+            table.push(&chunks[..]);
+        }
+    }
+    (table, shared)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Digest([u8; SHA256_BLOCK_SIZE]);
 
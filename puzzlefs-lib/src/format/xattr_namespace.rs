@@ -0,0 +1,107 @@
+// The namespaces Linux sorts xattrs in, by the prefix of their keys. Which processes may read and
+// write an xattr depends on its namespace, so the policies the reader, the FUSE filesystem and the
+// extractor apply to xattrs go through these instead of matching key prefixes themselves.
+
+use std::fmt;
+
+use super::types::{InodeAdditional, Xattr};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum XattrNamespace {
+    /// `user.`: anyone who can access the file.
+    User,
+    /// `trusted.`: only processes with `CAP_SYS_ADMIN`, e.g. overlayfs' bookkeeping.
+    Trusted,
+    /// `system.`: interpreted by the kernel, e.g. POSIX ACLs.
+    System,
+    /// `security.`: for security modules, e.g. SELinux labels and file capabilities.
+    Security,
+}
+
+impl XattrNamespace {
+    pub const ALL: [XattrNamespace; 4] = [
+        XattrNamespace::User,
+        XattrNamespace::Trusted,
+        XattrNamespace::System,
+        XattrNamespace::Security,
+    ];
+
+    /// The namespace of the xattr `key`, None for keys the kernel doesn't know a namespace of,
+    /// which can't be set on Linux.
+    pub fn of(key: &[u8]) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|namespace| key.starts_with(namespace.prefix()))
+    }
+
+    /// The prefix of the keys of the namespace, its trailing dot included.
+    pub fn prefix(self) -> &'static [u8] {
+        match self {
+            XattrNamespace::User => b"user.",
+            XattrNamespace::Trusted => b"trusted.",
+            XattrNamespace::System => b"system.",
+            XattrNamespace::Security => b"security.",
+        }
+    }
+
+    /// Whether only privileged processes can see and set the xattrs of the namespace: the kernel
+    /// leaves them out of the listings of the others, and fails to set them with `EPERM`.
+    pub fn is_privileged(self) -> bool {
+        self == XattrNamespace::Trusted
+    }
+}
+
+impl fmt::Display for XattrNamespace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let prefix = self.prefix();
+        // .unwrap() is fine, the prefixes are ASCII
+        f.write_str(std::str::from_utf8(&prefix[..prefix.len() - 1]).unwrap())
+    }
+}
+
+impl Xattr {
+    pub fn namespace(&self) -> Option<XattrNamespace> {
+        XattrNamespace::of(&self.key)
+    }
+
+    /// Whether a process sees the xattr, depending on whether it's `privileged`.
+    pub fn is_visible(&self, privileged: bool) -> bool {
+        privileged || !self.namespace().is_some_and(XattrNamespace::is_privileged)
+    }
+}
+
+impl InodeAdditional {
+    /// The xattrs a process sees, depending on whether it's `privileged`.
+    pub fn visible_xattrs(&self, privileged: bool) -> impl Iterator<Item = &Xattr> {
+        self.xattrs.iter().filter(move |x| x.is_visible(privileged))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xattr_namespaces() {
+        let xattr = |key: &[u8]| Xattr {
+            key: key.to_vec(),
+            val: Vec::new(),
+        };
+        for (key, namespace) in [
+            (&b"user.comment"[..], Some(XattrNamespace::User)),
+            (b"trusted.overlay.opaque", Some(XattrNamespace::Trusted)),
+            (b"system.posix_acl_access", Some(XattrNamespace::System)),
+            (b"security.capability", Some(XattrNamespace::Security)),
+            (b"user", None),
+            (b"osx.comment", None),
+        ] {
+            assert_eq!(xattr(key).namespace(), namespace);
+        }
+
+        assert!(xattr(b"trusted.overlay.opaque").is_visible(true));
+        assert!(!xattr(b"trusted.overlay.opaque").is_visible(false));
+        assert!(xattr(b"security.selinux").is_visible(false));
+        assert!(xattr(b"osx.comment").is_visible(false));
+        assert_eq!(XattrNamespace::Security.to_string(), "security");
+    }
+}
@@ -1,13 +1,13 @@
-use crate::format::{Result, WireFormatError, SHA256_BLOCK_SIZE};
+use crate::format::{Digest, Result, WireFormatError, SHA256_BLOCK_SIZE};
 use std::backtrace::Backtrace;
-use std::io::Write;
+use std::io::{self, Read, Write};
 use std::os::unix::io::AsRawFd;
 
 pub use fs_verity::linux::fsverity_enable;
 use fs_verity::linux::fsverity_measure;
 use fs_verity::FsVeritySha256;
 pub use fs_verity::InnerHashAlgorithm;
-use sha2::Digest;
+use sha2::Digest as Sha2Digest;
 
 pub const FS_VERITY_BLOCK_SIZE_DEFAULT: usize = 4096;
 
@@ -18,6 +18,14 @@ pub fn get_fs_verity_digest(data: &[u8]) -> Result<[u8; SHA256_BLOCK_SIZE]> {
     Ok(result.into())
 }
 
+/// Like [`get_fs_verity_digest`], for data too large to be read whole.
+pub fn read_fs_verity_digest(mut reader: impl Read) -> Result<[u8; SHA256_BLOCK_SIZE]> {
+    let mut digest = FsVeritySha256::new();
+    io::copy(&mut reader, &mut digest)?;
+    let result = digest.finalize();
+    Ok(result.into())
+}
+
 pub fn check_fs_verity(file: &cap_std::fs::File, expected: &[u8]) -> Result<()> {
     if expected.len() != SHA256_BLOCK_SIZE {
         return Err(WireFormatError::InvalidFsVerityData(
@@ -31,14 +39,11 @@ pub fn check_fs_verity(file: &cap_std::fs::File, expected: &[u8]) -> Result<()>
     let (_, measurement) = fsverity_measure(file.as_raw_fd())?;
 
     if *expected != measurement[..] {
-        return Err(WireFormatError::InvalidFsVerityData(
-            format!(
-                "fsverity mismatch {}, expected {}",
-                hex::encode(expected),
-                hex::encode(measurement)
-            ),
-            Backtrace::capture(),
-        ));
+        return Err(WireFormatError::DigestMismatch {
+            expected: Digest::new(expected.try_into()?),
+            found: Digest::new(measurement[..].try_into()?),
+            backtrace: Backtrace::capture(),
+        });
     }
 
     Ok(())
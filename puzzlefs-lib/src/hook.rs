@@ -0,0 +1,140 @@
+//! Entry points for an OCI runtime hook which uses a puzzlefs image as a container's rootfs: in
+//! the createRuntime hook the image is mounted on the root path of the container's bundle, and in
+//! the poststop hook it's unmounted again.
+//!
+//! The runtime passes the state of the container to the hook on its stdin. The image comes from
+//! the [`IMAGE_ANNOTATION`] annotation of the container, and if there's a
+//! [`MANIFEST_VERITY_ANNOTATION`] the image is only mounted if its manifest matches that fs-verity
+//! digest. Containers without these annotations are left alone, so the hook can be installed for
+//! all of them.
+
+use anyhow::Context;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::image_store::is_mountpoint;
+
+/// The image to mount as the rootfs, `<oci_dir>:<tag>`. A relative oci_dir is relative to the
+/// bundle.
+pub const IMAGE_ANNOTATION: &str = "io.puzzlefsoci.puzzlefs.image";
+/// The fs-verity digest (in hex) the manifest of the image has to match.
+pub const MANIFEST_VERITY_ANNOTATION: &str = "io.puzzlefsoci.puzzlefs.manifest_verity";
+
+/// The state of a container, as the runtime passes it to its hooks.
+#[derive(Debug, Deserialize)]
+pub struct State {
+    pub id: String,
+    pub bundle: PathBuf,
+    #[serde(default)]
+    pub annotations: HashMap<String, String>,
+}
+
+impl State {
+    pub fn read(reader: impl Read) -> anyhow::Result<Self> {
+        serde_json::from_reader(reader).context("invalid container state")
+    }
+}
+
+// The part of the bundle's config.json the hook needs.
+#[derive(Deserialize)]
+struct Config {
+    root: Root,
+}
+
+#[derive(Deserialize)]
+struct Root {
+    path: PathBuf,
+}
+
+/// A puzzlefs image mounted as the rootfs of a container.
+#[derive(Debug, PartialEq, Eq)]
+pub struct HookMount {
+    pub oci_dir: PathBuf,
+    pub tag: String,
+    pub rootfs: PathBuf,
+    pub manifest_verity: Option<Vec<u8>>,
+}
+
+impl HookMount {
+    /// The image to mount for the container in `state`, `None` if it doesn't use one.
+    pub fn from_state(state: &State) -> anyhow::Result<Option<Self>> {
+        let Some(image) = state.annotations.get(IMAGE_ANNOTATION) else {
+            return Ok(None);
+        };
+        let (oci_dir, tag) = image.rsplit_once(':').with_context(|| {
+            format!("{IMAGE_ANNOTATION} should be <oci_dir>:<tag>, got {image}")
+        })?;
+        let manifest_verity = state
+            .annotations
+            .get(MANIFEST_VERITY_ANNOTATION)
+            .map(hex::decode)
+            .transpose()
+            .with_context(|| format!("invalid {MANIFEST_VERITY_ANNOTATION}"))?;
+
+        let config = fs::read(state.bundle.join("config.json"))?;
+        let config: Config = serde_json::from_slice(&config).context("invalid config.json")?;
+        Ok(Some(HookMount {
+            oci_dir: state.bundle.join(oci_dir),
+            tag: tag.to_string(),
+            rootfs: state.bundle.join(config.root.path),
+            manifest_verity,
+        }))
+    }
+
+    /// Whether the image is already mounted on the rootfs.
+    pub fn is_mounted(&self) -> io::Result<bool> {
+        is_mountpoint(&self.rootfs)
+    }
+
+    /// Unmounts the image from the rootfs with `unmount`, if it's still mounted.
+    pub fn teardown(
+        &self,
+        unmount: impl FnOnce(&Path) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        if self.is_mounted()? {
+            unmount(&self.rootfs)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_hook_mount() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        fs::write(
+            dir.path().join("config.json"),
+            r#"{"ociVersion": "1.0.2", "root": {"path": "rootfs", "readonly": true}}"#,
+        )?;
+        let state = format!(
+            r#"{{"ociVersion": "1.0.2", "id": "c1", "status": "creating", "pid": 42,
+                "bundle": "{}", "annotations": {{"{IMAGE_ANNOTATION}": "oci:test",
+                "{MANIFEST_VERITY_ANNOTATION}": "00ff"}}}}"#,
+            dir.path().display()
+        );
+        let state = State::read(state.as_bytes())?;
+        let mount = HookMount::from_state(&state)?.unwrap();
+        assert_eq!(
+            mount,
+            HookMount {
+                oci_dir: dir.path().join("oci"),
+                tag: "test".to_string(),
+                rootfs: dir.path().join("rootfs"),
+                manifest_verity: Some(vec![0, 0xff]),
+            }
+        );
+        assert!(!mount.is_mounted()?);
+        mount.teardown(|_| panic!("nothing is mounted"))?;
+
+        let state = State::read(&br#"{"id": "c2", "bundle": "/nonexistent"}"#[..])?;
+        assert_eq!(HookMount::from_state(&state)?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,355 @@
+//! Images served by a plain HTTP(S) server, e.g. a CDN or an artifact server, which holds an OCI
+//! layout as is: `index.json` and `blobs/sha256/<digest>` below some base URL. Nothing but range
+//! requests is asked of the server. [`BlobServer`] is such a server, for a layout on the build
+//! host.
+
+use std::backtrace::Backtrace;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use ocidir::oci_spec::image::{ImageIndex, ImageManifest, MediaType, ANNOTATION_REF_NAME};
+use sha2::{Digest as Sha2Digest, Sha256};
+
+use crate::format::{Result, WireFormatError};
+use crate::oci::media_types::is_rootfs;
+use crate::oci::{select_platform, BlobSource, Descriptor, Image};
+
+mod server;
+pub use server::BlobServer;
+
+// the ETag of the index a tag was last fetched from, in the layout
+const ETAG_PREFIX: &str = "puzzlefs-etag-";
+
+pub struct HttpServer {
+    // built with the settings below on the first request, and shared by all of them so they
+    // reuse its connections
+    agent: OnceLock<ureq::Agent>,
+    base_url: String,
+    timeout: Option<Duration>,
+}
+
+impl HttpServer {
+    pub fn new(base_url: &str) -> Self {
+        HttpServer {
+            agent: OnceLock::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            timeout: None,
+        }
+    }
+
+    /// Fails requests when connecting, or waiting for more of a response, takes longer than
+    /// `timeout`, e.g. so reads of lazily mounted images don't hang on a server which stopped
+    /// answering.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    fn agent(&self) -> &ureq::Agent {
+        self.agent.get_or_init(|| {
+            let mut builder = ureq::AgentBuilder::new();
+            if let Some(timeout) = self.timeout {
+                builder = builder.timeout_connect(timeout).timeout_read(timeout);
+            }
+            builder.build()
+        })
+    }
+
+    fn request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<ureq::Response> {
+        let url = format!("{}/{path}", self.base_url);
+        let mut request = self.agent().request(method, &url);
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        request
+            .call()
+            .map_err(|e| http_error(format!("{method} {url} failed: {e}")))
+    }
+
+    /// Copies the manifest tagged `tag` on the server into `image`, along with its config and
+    /// rootfs, so it can be mounted with a [`crate::oci::LazyFetcher`] reading the chunks from
+    /// the server. The index is fetched with a conditional request, so nothing is downloaded
+    /// again if it didn't change since `tag` was last fetched.
+    pub fn fetch_image(&self, image: &Image, tag: &str) -> Result<()> {
+        let etag_file = format!("{ETAG_PREFIX}{tag}");
+        let etag = match image.0.find_manifest_descriptor_with_tag(tag)? {
+            Some(_) => image.0.dir().read_to_string(&etag_file).ok(),
+            None => None,
+        };
+        let headers = match &etag {
+            Some(etag) => vec![("If-None-Match", etag.as_str())],
+            None => Vec::new(),
+        };
+        let response = self.request("GET", "index.json", &headers)?;
+        if response.status() == 304 {
+            return Ok(());
+        }
+        let new_etag = response.header("ETag").map(str::to_string);
+        let index = ImageIndex::from_reader(response.into_reader())?;
+
+        let descriptor = index
+            .manifests()
+            .iter()
+            .find(|d| {
+                d.annotations()
+                    .as_ref()
+                    .and_then(|a| a.get(ANNOTATION_REF_NAME))
+                    .is_some_and(|name| name == tag)
+            })
+            .ok_or_else(|| {
+                WireFormatError::MissingManifest(tag.to_string(), Backtrace::capture())
+            })?;
+        // only the manifest for the image's platform of multi-platform images
+        let index;
+        let platform = image.platform();
+        let descriptor = if descriptor.media_type() == &MediaType::ImageIndex {
+            index = serde_json::from_slice::<ImageIndex>(&self.get_verified(descriptor)?)?;
+            select_platform(&index, &platform).ok_or_else(|| {
+                WireFormatError::MissingManifest(
+                    format!("{tag} for {}/{}", platform.os(), platform.architecture()),
+                    Backtrace::capture(),
+                )
+            })?
+        } else {
+            descriptor
+        };
+        let manifest_bytes = self.get_verified(descriptor)?;
+        let manifest: ImageManifest = serde_json::from_slice(&manifest_bytes)?;
+
+        let rootfs = manifest
+            .layers()
+            .iter()
+            .filter(|d| is_rootfs(d.media_type()));
+        for descriptor in std::iter::once(manifest.config()).chain(rootfs) {
+            if !image.has_blob(descriptor) {
+                let data = self.get_verified(descriptor)?;
+                image.blob_store().write_blob(descriptor, &mut &data[..])?;
+            }
+        }
+
+        image.insert_manifest(manifest, tag)?;
+        match new_etag {
+            Some(etag) => image.0.dir().write(&etag_file, etag)?,
+            None => match image.0.dir().remove_file(&etag_file) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            },
+        }
+        Ok(())
+    }
+
+    // Downloads a blob, checking it against its descriptor.
+    fn get_verified(&self, descriptor: &Descriptor) -> Result<Vec<u8>> {
+        let digest = descriptor.digest().digest();
+        let response = self.request("GET", &format!("blobs/sha256/{digest}"), &[])?;
+        let mut data = Vec::new();
+        response
+            .into_reader()
+            .take(descriptor.size() + 1)
+            .read_to_end(&mut data)?;
+        let found = hex::encode(Sha256::digest(&data));
+        if found != digest || data.len() as u64 != descriptor.size() {
+            return Err(http_error(format!(
+                "blob mismatch: expected {digest}, got {found}"
+            )));
+        }
+        Ok(data)
+    }
+}
+
+impl BlobSource for HttpServer {
+    fn blob_size(&self, digest: &str) -> Result<u64> {
+        let path = blob_path(digest);
+        let response = self.request("HEAD", &path, &[])?;
+        response
+            .header("Content-Length")
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| http_error(format!("HEAD {path}: no content length")))
+    }
+
+    fn get_blob_range(&self, digest: &str, range: Range<u64>) -> Result<Vec<u8>> {
+        let header = format!("bytes={}-{}", range.start, range.end - 1);
+        let response = self.request("GET", &blob_path(digest), &[("Range", &header)])?;
+        Ok(read_range(response, &range)?)
+    }
+}
+
+fn blob_path(digest: &str) -> String {
+    let digest = digest.strip_prefix("sha256:").unwrap_or(digest);
+    format!("blobs/sha256/{digest}")
+}
+
+// Reads the bytes `range` of a blob from the response to a range request. Servers which don't
+// do ranges send the whole blob, the bytes before the range are skipped then.
+pub(crate) fn read_range(response: ureq::Response, range: &Range<u64>) -> io::Result<Vec<u8>> {
+    let ranged = response.status() == 206;
+    let mut reader = response.into_reader();
+    if !ranged {
+        io::copy(&mut (&mut reader).take(range.start), &mut io::sink())?;
+    }
+    let len = range.end - range.start;
+    let mut data = Vec::with_capacity(len as usize);
+    reader.take(len).read_to_end(&mut data)?;
+    if data.len() as u64 != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("short read at offset {}", range.start),
+        ));
+    }
+    Ok(data)
+}
+
+fn http_error(message: String) -> WireFormatError {
+    WireFormatError::HttpError(message, Backtrace::capture())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use crate::format::InodeMode;
+    use crate::oci::{LayoutBlobStore, LazyFetcher};
+    use crate::reader::{CancellationToken, PuzzleFS, WalkPuzzleFS};
+    use nix::errno::Errno;
+    use std::fs;
+    use std::io::{BufRead, Write};
+    use std::net::TcpListener;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use tempfile::tempdir;
+
+    const ETAG: &str = "\"v1\"";
+
+    // Serves the files below `root`, with a fixed ETag and range requests, one request per
+    // connection. Returns the base URL and the log of requests.
+    fn serve_dir(root: PathBuf) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&requests);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let (mut range, mut if_none_match) = (None, None);
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Range: bytes=") {
+                        let (start, end) = value.trim().split_once('-').unwrap();
+                        range = Some(
+                            start.parse::<usize>().unwrap()..end.parse::<usize>().unwrap() + 1,
+                        );
+                    }
+                    if let Some(value) = line.strip_prefix("If-None-Match: ") {
+                        if_none_match = Some(value.trim().to_string());
+                    }
+                }
+                let mut parts = request.split_whitespace();
+                let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+                log.lock().unwrap().push(format!("{method} {path}"));
+
+                let (status, body) = match fs::read(root.join(&path[1..])) {
+                    Err(_) => ("404 Not Found", Vec::new()),
+                    Ok(_) if if_none_match.as_deref() == Some(ETAG) => {
+                        ("304 Not Modified", Vec::new())
+                    }
+                    Ok(data) => match range {
+                        Some(range) => ("206 Partial Content", data[range].to_vec()),
+                        None => ("200 OK", data),
+                    },
+                };
+                let header = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nETag: {ETAG}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(header.as_bytes()).unwrap();
+                if method != "HEAD" {
+                    stream.write_all(&body).unwrap();
+                }
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn test_http_blob_source() -> anyhow::Result<()> {
+        let source = tempdir()?;
+        let image = Image::new(source.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let (url, requests) = serve_dir(source.path().to_path_buf());
+
+        let cache = tempdir()?;
+        let lazy = Image::new(cache.path())?;
+        let server = HttpServer::new(&url);
+        server.fetch_image(&lazy, "test")?;
+        // index, manifest, config and rootfs
+        assert_eq!(requests.lock().unwrap().len(), 4);
+        // the index didn't change
+        server.fetch_image(&lazy, "test")?;
+        assert_eq!(requests.lock().unwrap().len(), 5);
+
+        let store = LayoutBlobStore::new(&lazy.0)?;
+        let lazy = lazy.with_blob_store(LazyFetcher::new(server, store));
+        let mut pfs = PuzzleFS::open(lazy, "test", None)?;
+        let mut contents = Vec::new();
+        for de in WalkPuzzleFS::walk(&mut pfs)? {
+            let de = de?;
+            if de.inode.file_len().is_ok() {
+                de.open()?.read_to_end(&mut contents)?;
+            }
+        }
+        assert_eq!(
+            hex::encode(Sha256::digest(&contents)),
+            "d9e749d9367fc908876749d6502eb212fee88c9a94892fb07da5ef3ba8bc39ed"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_http_timeout() -> anyhow::Result<()> {
+        let source = tempdir()?;
+        let image = Image::new(source.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let (url, _) = serve_dir(source.path().to_path_buf());
+        let cache = tempdir()?;
+        let lazy = Image::new(cache.path())?;
+        HttpServer::new(&url).fetch_image(&lazy, "test")?;
+
+        // a server which takes the connections but never answers
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stalled = format!("http://{}", listener.local_addr()?);
+        std::thread::spawn(move || {
+            let streams = listener.incoming().collect::<Vec<_>>();
+            drop(streams);
+        });
+        let server = HttpServer::new(&stalled).with_timeout(Duration::from_millis(200));
+        let store = LayoutBlobStore::new(&lazy.0)?;
+        let lazy = lazy.with_blob_store(LazyFetcher::new(server, store));
+        let pfs = PuzzleFS::open(lazy, "test", None)?;
+        let InodeMode::File { chunks } = pfs.find_inode(2)?.mode else {
+            panic!("not a file");
+        };
+
+        let token = CancellationToken::with_timeout(Duration::from_millis(100));
+        let mut buf = vec![0; 4096];
+        let err = pfs
+            .oci
+            .fill_from_chunk(&chunks[0], 0, &mut buf, None, Some(&token))
+            .unwrap_err();
+        assert_eq!(err.to_errno(), Errno::ETIMEDOUT as i32);
+        Ok(())
+    }
+}
@@ -0,0 +1,423 @@
+// Serves an OCI layout over HTTP(S), for the devices mounting its images lazily from a build
+// host. The layout is served as is (`index.json` and `blobs/sha256/<digest>`), as `HttpServer`
+// reads it, and through the read-only part of the distribution API
+// (`/v2/<name>/manifests/<tag>` and `/v2/<name>/blobs/<digest>`), as the registry client does,
+// whatever the repository name. Blobs can be read in ranges.
+
+use std::io::{self, BufRead, BufReader, Read, Seek, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use sha2::{Digest as Sha2Digest, Sha256};
+
+use super::http_error;
+use crate::format::{Result, WireFormatError};
+use crate::oci::Image;
+
+const INDEX: &str = "index.json";
+const OCI_LAYOUT: &str = "oci-layout";
+const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+const OCTET_STREAM: &str = "application/octet-stream";
+// connections idle for longer are closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+trait Stream: Read + Write + Send {}
+impl<S: Read + Write + Send> Stream for S {}
+
+/// Serves the blobs and the manifests of an image over HTTP, or HTTPS with
+/// [`BlobServer::with_tls`].
+pub struct BlobServer {
+    image: Arc<Image>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+// What a request asks for.
+enum Resource {
+    Layout(&'static str),
+    Blob(String),
+    Tag(String),
+    Referrers(String),
+    ApiVersion,
+}
+
+struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Body,
+}
+
+enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    File(cap_std::fs::File, Range<u64>),
+}
+
+impl Response {
+    fn empty(status: &'static str) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Empty,
+        }
+    }
+}
+
+impl BlobServer {
+    pub fn new(image: Image) -> Self {
+        BlobServer {
+            image: Arc::new(image),
+            tls: None,
+        }
+    }
+
+    /// Serves HTTPS with the certificate chain and the private key in the PEM files `cert` and
+    /// `key`.
+    pub fn with_tls(self, cert: &Path, key: &Path) -> Result<Self> {
+        let tls_error = |e: &dyn std::fmt::Display| http_error(format!("invalid TLS setup: {e}"));
+        let certs = CertificateDer::pem_file_iter(cert)
+            .map_err(|e| tls_error(&e))?
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| tls_error(&e))?;
+        let key = PrivateKeyDer::from_pem_file(key).map_err(|e| tls_error(&e))?;
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| tls_error(&e))?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| tls_error(&e))?;
+        Ok(BlobServer {
+            tls: Some(Arc::new(config)),
+            ..self
+        })
+    }
+
+    /// Serves the connections to `listener`, each on a thread of its own, until accepting one
+    /// fails.
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, peer) = listener.accept()?;
+            let image = Arc::clone(&self.image);
+            let tls = self.tls.clone();
+            thread::spawn(move || {
+                if let Err(e) = serve_connection(&image, tls, stream) {
+                    debug!("connection from {peer}: {e}");
+                }
+            });
+        }
+    }
+}
+
+fn serve_connection(
+    image: &Image,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    stream: TcpStream,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let stream: Box<dyn Stream> = match tls {
+        Some(config) => {
+            let connection = rustls::ServerConnection::new(config).map_err(io::Error::other)?;
+            Box::new(rustls::StreamOwned::new(connection, stream))
+        }
+        None => Box::new(stream),
+    };
+    let mut connection = BufReader::new(stream);
+    loop {
+        let mut request_line = String::new();
+        if connection.read_line(&mut request_line)? == 0 {
+            return Ok(());
+        }
+        let mut range = None;
+        let mut if_none_match = None;
+        let mut keep_alive = !request_line.trim_end().ends_with("HTTP/1.0");
+        loop {
+            let mut line = String::new();
+            connection.read_line(&mut line)?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.to_ascii_lowercase().as_str() {
+                "range" => range = Some(value.to_string()),
+                "if-none-match" => if_none_match = Some(value.to_string()),
+                "connection" => keep_alive = !value.eq_ignore_ascii_case("close"),
+                _ => {}
+            }
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let (method, path) = (
+            parts.next().unwrap_or_default(),
+            parts.next().unwrap_or("/"),
+        );
+        let response = match method {
+            "GET" | "HEAD" => respond(image, path, range.as_deref(), if_none_match.as_deref()),
+            _ => Response::empty("405 Method Not Allowed"),
+        };
+        info!("{method} {path} {}", response.status);
+        write_response(connection.get_mut(), method == "HEAD", response)?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+fn resource(path: &str) -> Option<Resource> {
+    let path = path.split('?').next().unwrap_or_default();
+    match path {
+        "/index.json" => return Some(Resource::Layout(INDEX)),
+        "/oci-layout" => return Some(Resource::Layout(OCI_LAYOUT)),
+        "/v2" | "/v2/" => return Some(Resource::ApiVersion),
+        _ => {}
+    }
+    if let Some(digest) = path.strip_prefix("/blobs/sha256/") {
+        return Some(Resource::Blob(digest.to_string()));
+    }
+    // the repository name doesn't matter, there's only the one layout
+    let (name_and_kind, reference) = path.strip_prefix("/v2/")?.rsplit_once('/')?;
+    let (_, kind) = name_and_kind.rsplit_once('/')?;
+    match (kind, reference.strip_prefix("sha256:")) {
+        ("blobs", Some(digest)) | ("manifests", Some(digest)) => {
+            Some(Resource::Blob(digest.to_string()))
+        }
+        ("manifests", None) => Some(Resource::Tag(reference.to_string())),
+        ("referrers", Some(digest)) => Some(Resource::Referrers(digest.to_string())),
+        _ => None,
+    }
+}
+
+fn respond(
+    image: &Image,
+    path: &str,
+    range: Option<&str>,
+    if_none_match: Option<&str>,
+) -> Response {
+    let response = match resource(path) {
+        Some(resource) => respond_to(image, resource, range, if_none_match),
+        None => return Response::empty("404 Not Found"),
+    };
+    response.unwrap_or_else(|e| match e.kind() {
+        io::ErrorKind::NotFound => Response::empty("404 Not Found"),
+        _ => {
+            warn!("serving {path} failed: {e}");
+            Response::empty("500 Internal Server Error")
+        }
+    })
+}
+
+fn respond_to(
+    image: &Image,
+    resource: Resource,
+    range: Option<&str>,
+    if_none_match: Option<&str>,
+) -> io::Result<Response> {
+    let (digest, media_type) = match resource {
+        Resource::ApiVersion => return Ok(json(b"{}".to_vec(), "application/json")),
+        Resource::Layout(name) => {
+            let data = image.0.dir().read(name)?;
+            // the index changes, clients only fetch it again when it did
+            let etag = format!("\"{}\"", hex::encode(Sha256::digest(&data)));
+            if if_none_match == Some(etag.as_str()) {
+                return Ok(Response::empty("304 Not Modified"));
+            }
+            let mut response = json(data, "application/json");
+            response.headers.push(("ETag", etag));
+            return Ok(response);
+        }
+        Resource::Referrers(subject) => {
+            let mut index = image.get_index().map_err(io::Error::other)?;
+            let referrers = image
+                .referrers_of(&format!("sha256:{subject}"), None)
+                .map_err(io::Error::other)?;
+            index.set_manifests(referrers.into_iter().map(|(d, _)| d).collect());
+            return Ok(json(serde_json::to_vec(&index)?, OCI_INDEX));
+        }
+        Resource::Tag(tag) => {
+            let descriptor = image
+                .0
+                .find_manifest_descriptor_with_tag(&tag)
+                .map_err(io::Error::other)?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, tag))?;
+            (
+                descriptor.digest().digest().to_string(),
+                descriptor.media_type().to_string(),
+            )
+        }
+        Resource::Blob(digest) => (digest, OCTET_STREAM.to_string()),
+    };
+    // nothing but digests gets near the blobs directory
+    if digest.len() != 64 || !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(io::Error::new(io::ErrorKind::NotFound, digest));
+    }
+
+    let file = image.open_raw_blob(&digest, None).map_err(|e| match e {
+        WireFormatError::BlobMissing { .. } => {
+            io::Error::new(io::ErrorKind::NotFound, digest.clone())
+        }
+        e => io::Error::other(e),
+    })?;
+    let size = file.metadata()?.len();
+    let mut headers = vec![
+        ("Content-Type", media_type),
+        ("Docker-Content-Digest", format!("sha256:{digest}")),
+        ("Accept-Ranges", "bytes".to_string()),
+    ];
+    let Some(range) = range else {
+        return Ok(Response {
+            status: "200 OK",
+            headers,
+            body: Body::File(file, 0..size),
+        });
+    };
+    match parse_range(range, size) {
+        Some(range) => {
+            headers.push((
+                "Content-Range",
+                format!("bytes {}-{}/{size}", range.start, range.end - 1),
+            ));
+            Ok(Response {
+                status: "206 Partial Content",
+                headers,
+                body: Body::File(file, range),
+            })
+        }
+        None => {
+            let mut response = Response::empty("416 Range Not Satisfiable");
+            response
+                .headers
+                .push(("Content-Range", format!("bytes */{size}")));
+            Ok(response)
+        }
+    }
+}
+
+fn json(data: Vec<u8>, media_type: &str) -> Response {
+    Response {
+        status: "200 OK",
+        headers: vec![("Content-Type", media_type.to_string())],
+        body: Body::Bytes(data),
+    }
+}
+
+// A single range, `bytes=<start>-<end>`, `bytes=<start>-` or `bytes=-<suffix length>`.
+fn parse_range(range: &str, size: u64) -> Option<Range<u64>> {
+    let (start, end) = range.strip_prefix("bytes=")?.trim().split_once('-')?;
+    let range = match (start.parse::<u64>().ok(), end.parse::<u64>().ok()) {
+        (Some(start), Some(end)) => start..size.min(end + 1),
+        (Some(start), None) if end.is_empty() => start..size,
+        (None, Some(suffix)) if start.is_empty() => size.saturating_sub(suffix)..size,
+        _ => return None,
+    };
+    (range.start < range.end).then_some(range)
+}
+
+fn write_response(stream: &mut dyn Stream, head: bool, response: Response) -> io::Result<()> {
+    let len = match &response.body {
+        Body::Empty => 0,
+        Body::Bytes(data) => data.len() as u64,
+        Body::File(_, range) => range.end - range.start,
+    };
+    let mut header = format!("HTTP/1.1 {}\r\nContent-Length: {len}\r\n", response.status);
+    for (name, value) in &response.headers {
+        header.push_str(&format!("{name}: {value}\r\n"));
+    }
+    header.push_str("\r\n");
+    stream.write_all(header.as_bytes())?;
+    if !head {
+        match response.body {
+            Body::Empty => {}
+            Body::Bytes(data) => stream.write_all(&data)?,
+            Body::File(mut file, range) => {
+                file.seek(io::SeekFrom::Start(range.start))?;
+                io::copy(&mut file.take(range.end - range.start), stream)?;
+            }
+        }
+    }
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use crate::http::HttpServer;
+    use crate::oci::{LayoutBlobStore, LazyFetcher};
+    use crate::reader::{PuzzleFS, WalkPuzzleFS};
+    use crate::registry::{pull, Reference, Registry, RegistryOptions};
+    use std::str::FromStr;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-9", 100), Some(0..10));
+        assert_eq!(parse_range("bytes=90-", 100), Some(90..100));
+        assert_eq!(parse_range("bytes=-10", 100), Some(90..100));
+        assert_eq!(parse_range("bytes=50-500", 100), Some(50..100));
+        assert_eq!(parse_range("bytes=100-", 100), None);
+        assert_eq!(parse_range("lines=0-9", 100), None);
+    }
+
+    #[test]
+    fn test_blob_server() -> anyhow::Result<()> {
+        let source = tempdir()?;
+        let image = Image::new(source.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?.to_string();
+        let server = BlobServer::new(Image::open(source.path())?);
+        thread::spawn(move || server.serve(listener));
+
+        // mounted lazily from the layout
+        let cache = tempdir()?;
+        let lazy = Image::new(cache.path())?;
+        let client = HttpServer::new(&format!("http://{address}"));
+        client.fetch_image(&lazy, "test")?;
+        // nothing changed, the index isn't downloaded again
+        client.fetch_image(&lazy, "test")?;
+        let store = LayoutBlobStore::new(&lazy.0)?;
+        let lazy = lazy.with_blob_store(LazyFetcher::new(client, store));
+        let mut pfs = PuzzleFS::open(lazy, "test", None)?;
+        let mut contents = Vec::new();
+        for de in WalkPuzzleFS::walk(&mut pfs)? {
+            let de = de?;
+            if de.inode.file_len().is_ok() {
+                de.open()?.read_to_end(&mut contents)?;
+            }
+        }
+        assert_eq!(
+            hex::encode(Sha256::digest(&contents)),
+            "d9e749d9367fc908876749d6502eb212fee88c9a94892fb07da5ef3ba8bc39ed"
+        );
+
+        // and pulled as from a registry
+        let pulled_dir = tempdir()?;
+        let pulled = Image::new(pulled_dir.path())?;
+        let reference = Reference::from_str(&format!("{address}/any/name:test"))?;
+        let options = RegistryOptions {
+            plain_http: true,
+            ..Default::default()
+        };
+        pull(
+            &pulled,
+            "test",
+            &Registry::new(&reference, &options),
+            "test",
+        )?;
+        assert_eq!(
+            pulled.verity_digests("test")?,
+            image.verity_digests("test")?
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,407 @@
+//! A directory of puzzlefs images laid out like a containers/storage overlay store, which podman
+//! and CRI-O can use as an additional read-only image store (`additionalimagestores` in
+//! storage.conf).
+//!
+//! Each image is a single layer, whose `diff` directory is where the puzzlefs image is mounted;
+//! the runtime stacks its overlay on top of that mount like on any other layer. The images are
+//! only usable while those mounts are there, [`ImageStore::layers`] lists what to mount where.
+
+use anyhow::Context;
+use base64::Engine;
+use nix::fcntl::{flock, FlockArg};
+use ocidir::oci_spec::image::RootFsBuilder;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{symlink, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::oci::Image;
+
+const IMAGES: &str = "overlay-images";
+const LAYERS: &str = "overlay-layers";
+const OVERLAY: &str = "overlay";
+// where the store remembers which puzzlefs image each layer is
+const PUZZLEFS_LAYERS: &str = "puzzlefs-layers.json";
+// puzzlefs images don't keep timestamps
+const EPOCH: &str = "1970-01-01T00:00:00Z";
+
+/// A layer of the store: the puzzlefs image to mount on its `diff` directory.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreLayer {
+    pub id: String,
+    pub oci_dir: PathBuf,
+    pub tag: String,
+}
+
+// The records of containers/storage, with the fields a read-only store needs.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct StorageImage {
+    id: String,
+    digest: String,
+    names: Vec<String>,
+    layer: String,
+    metadata: String,
+    big_data_names: Vec<String>,
+    big_data_sizes: BTreeMap<String, u64>,
+    big_data_digests: BTreeMap<String, String>,
+    created: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct StorageLayer {
+    id: String,
+    created: String,
+    compressed_diff_digest: String,
+    compressed_size: u64,
+    diff_digest: String,
+    diff_size: u64,
+    compression: u32,
+}
+
+// Unmounts the image mounted on a layer which is removed from the store.
+type Unmount = Box<dyn Fn(&Path) -> anyhow::Result<()>>;
+
+pub struct ImageStore {
+    dir: PathBuf,
+    unmount: Unmount,
+}
+
+impl ImageStore {
+    /// Opens the store at `dir`, creating it if needed.
+    pub fn open(dir: &Path) -> anyhow::Result<Self> {
+        for subdir in [IMAGES, LAYERS, OVERLAY] {
+            fs::create_dir_all(dir.join(subdir))?;
+        }
+        fs::create_dir_all(dir.join(OVERLAY).join("l"))?;
+        // containers/storage takes these locks even on read-only stores
+        for lock in [
+            Path::new(IMAGES).join("images.lock"),
+            Path::new(LAYERS).join("layers.lock"),
+        ] {
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join(lock))?;
+        }
+        Ok(ImageStore {
+            dir: dir.to_path_buf(),
+            unmount: Box::new(|diff| bail!("{} is still mounted", diff.display())),
+        })
+    }
+
+    /// Sets how the images mounted on the layers which are removed from the store get
+    /// unmounted. Without it, removing a mounted layer fails.
+    pub fn with_unmount(self, unmount: impl Fn(&Path) -> anyhow::Result<()> + 'static) -> Self {
+        ImageStore {
+            unmount: Box::new(unmount),
+            ..self
+        }
+    }
+
+    /// Adds the image `tag` of the layout at `oci_dir` as `name`, e.g. `localhost/alpine:3.19`,
+    /// taking the name from the image which had it, if any. Returns the layer to mount it on.
+    pub fn add(
+        &self,
+        image: &Arc<Image>,
+        oci_dir: &Path,
+        tag: &str,
+        name: &str,
+    ) -> anyhow::Result<StoreLayer> {
+        let _lock = self.lock()?;
+        let mut manifest = Vec::new();
+        image
+            .get_image_manifest_fd(tag)?
+            .read_to_end(&mut manifest)?;
+        let manifest_digest = format!("sha256:{}", hex::encode(Sha256::digest(&manifest)));
+        let rootfs = image
+            .get_pfs_rootfs_descriptors(tag)?
+            .into_iter()
+            .next()
+            .with_context(|| format!("{tag} has no rootfs"))?;
+        let stats = image.stats(tag)?;
+
+        // the layer is the mounted image, identified by its rootfs
+        let layer_id = rootfs.digest().digest().to_string();
+        let diff_digest = rootfs.digest().to_string();
+        let layer_dir = self.dir.join(OVERLAY).join(&layer_id);
+        fs::create_dir_all(layer_dir.join("diff"))?;
+        let link = layer_id[..26].to_uppercase();
+        fs::write(layer_dir.join("link"), &link)?;
+        let link_path = self.dir.join(OVERLAY).join("l").join(&link);
+        if fs::symlink_metadata(&link_path).is_err() {
+            symlink(Path::new("..").join(&layer_id).join("diff"), link_path)?;
+        }
+
+        // the runtime gets the configuration of the image, describing the mounted layer
+        let platform = image.platform();
+        let mut config = image.config(tag)?.unwrap_or_default();
+        config.set_os(platform.os().clone());
+        config.set_architecture(platform.architecture().clone());
+        config.set_rootfs(
+            RootFsBuilder::default()
+                .typ("layers")
+                .diff_ids(vec![diff_digest.clone()])
+                .build()?,
+        );
+        let config = serde_json::to_vec(&config)?;
+        let id = hex::encode(Sha256::digest(&config));
+
+        let mut big_data = BTreeMap::new();
+        big_data.insert(format!("sha256:{id}"), config);
+        big_data.insert(format!("manifest-{manifest_digest}"), manifest.clone());
+        big_data.insert("manifest".to_string(), manifest);
+        let image_dir = self.dir.join(IMAGES).join(&id);
+        fs::create_dir_all(&image_dir)?;
+        for (key, data) in &big_data {
+            fs::write(image_dir.join(big_data_name(key)), data)?;
+        }
+
+        // the name moves from whichever image had it, the image keeps its other names
+        let mut images: Vec<StorageImage> = self.read(IMAGES, "images.json")?;
+        for other in &mut images {
+            other.names.retain(|n| n != name);
+        }
+        let mut names = images
+            .iter()
+            .find(|i| i.id == id)
+            .map(|i| i.names.clone())
+            .unwrap_or_default();
+        names.push(name.to_string());
+        images.retain(|i| i.id != id && !i.names.is_empty());
+        images.push(StorageImage {
+            id: id.clone(),
+            digest: manifest_digest,
+            names,
+            layer: layer_id.clone(),
+            metadata: String::new(),
+            big_data_names: big_data.keys().cloned().collect(),
+            big_data_sizes: big_data
+                .iter()
+                .map(|(key, data)| (key.clone(), data.len() as u64))
+                .collect(),
+            big_data_digests: big_data
+                .iter()
+                .map(|(key, data)| {
+                    (
+                        key.clone(),
+                        format!("sha256:{}", hex::encode(Sha256::digest(data))),
+                    )
+                })
+                .collect(),
+            created: EPOCH.to_string(),
+        });
+
+        let mut layers: Vec<StorageLayer> = self.read(LAYERS, "layers.json")?;
+        layers.retain(|l| l.id != layer_id);
+        layers.push(StorageLayer {
+            id: layer_id.clone(),
+            created: EPOCH.to_string(),
+            compressed_diff_digest: diff_digest.clone(),
+            compressed_size: stats.stored_size,
+            diff_digest,
+            diff_size: stats.logical_size,
+            compression: 0,
+        });
+
+        let layer = StoreLayer {
+            id: layer_id,
+            oci_dir: fs::canonicalize(oci_dir)?,
+            tag: tag.to_string(),
+        };
+        let mut puzzlefs_layers: Vec<StoreLayer> = self.read(".", PUZZLEFS_LAYERS)?;
+        puzzlefs_layers.retain(|l| l.id != layer.id);
+        puzzlefs_layers.push(layer.clone());
+
+        self.write(IMAGES, "images.json", &images)?;
+        self.write(LAYERS, "layers.json", &layers)?;
+        self.write(".", PUZZLEFS_LAYERS, &puzzlefs_layers)?;
+        self.drop_unused_layers(&images, &layers)?;
+        Ok(layer)
+    }
+
+    /// Removes the name `name` from the store, along with its image if it has no other names.
+    /// The layer of the image goes too, unless other images use it, so it has to be unmounted
+    /// first.
+    pub fn remove(&self, name: &str) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        let mut images: Vec<StorageImage> = self.read(IMAGES, "images.json")?;
+        if !images.iter().any(|i| i.names.iter().any(|n| n == name)) {
+            bail!("no image {name} in the store");
+        }
+        for image in &mut images {
+            image.names.retain(|n| n != name);
+        }
+        images.retain(|i| !i.names.is_empty());
+        let layers: Vec<StorageLayer> = self.read(LAYERS, "layers.json")?;
+        self.write(IMAGES, "images.json", &images)?;
+        self.drop_unused_layers(&images, &layers)
+    }
+
+    /// The layers of the store, each to be mounted on [`ImageStore::diff_dir`].
+    pub fn layers(&self) -> anyhow::Result<Vec<StoreLayer>> {
+        self.read(".", PUZZLEFS_LAYERS)
+    }
+
+    pub fn diff_dir(&self, layer: &StoreLayer) -> PathBuf {
+        self.dir.join(OVERLAY).join(&layer.id).join("diff")
+    }
+
+    /// Whether something is mounted on the `diff` directory of `layer`.
+    pub fn is_mounted(&self, layer: &StoreLayer) -> io::Result<bool> {
+        is_mountpoint(&self.diff_dir(layer))
+    }
+
+    // Forgets the layers no image uses anymore, along with the images which are gone.
+    fn drop_unused_layers(
+        &self,
+        images: &[StorageImage],
+        layers: &[StorageLayer],
+    ) -> anyhow::Result<()> {
+        let used = |id: &str| images.iter().any(|i| i.layer == id);
+        let (kept, unused): (Vec<_>, Vec<_>) = layers.iter().partition(|l| used(&l.id));
+        self.write(LAYERS, "layers.json", &kept)?;
+        let mut puzzlefs_layers: Vec<StoreLayer> = self.read(".", PUZZLEFS_LAYERS)?;
+        puzzlefs_layers.retain(|l| used(&l.id));
+        self.write(".", PUZZLEFS_LAYERS, &puzzlefs_layers)?;
+
+        for layer in unused {
+            let layer_dir = self.dir.join(OVERLAY).join(&layer.id);
+            let diff = layer_dir.join("diff");
+            if is_mountpoint(&diff)? {
+                (self.unmount)(&diff)?;
+            }
+            if let Ok(link) = fs::read_to_string(layer_dir.join("link")) {
+                let _ = fs::remove_file(self.dir.join(OVERLAY).join("l").join(link.trim()));
+            }
+            fs::remove_dir_all(&layer_dir)
+                .with_context(|| format!("can't remove layer {}", layer.id))?;
+        }
+
+        for entry in fs::read_dir(self.dir.join(IMAGES))? {
+            let entry = entry?;
+            let id = entry.file_name();
+            if entry.file_type()?.is_dir() && !images.iter().any(|i| id == i.id.as_str()) {
+                fs::remove_dir_all(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Serializes updates of the store, the way containers/storage does for its own.
+    fn lock(&self) -> io::Result<fs::File> {
+        let file = fs::File::open(self.dir.join(IMAGES).join("images.lock"))?;
+        flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+        Ok(file)
+    }
+
+    fn read<T: for<'a> Deserialize<'a> + Default>(
+        &self,
+        subdir: &str,
+        file: &str,
+    ) -> anyhow::Result<T> {
+        match fs::read(self.dir.join(subdir).join(file)) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Replaces the file atomically, runtimes read it without taking the lock.
+    fn write<T: Serialize>(&self, subdir: &str, file: &str, value: &T) -> anyhow::Result<()> {
+        let path = self.dir.join(subdir).join(file);
+        let temp = self.dir.join(subdir).join(format!(".{file}.tmp"));
+        fs::write(&temp, serde_json::to_vec(value)?)?;
+        fs::rename(temp, path)?;
+        Ok(())
+    }
+}
+
+// Whether `dir` is on another filesystem than its parent.
+pub(crate) fn is_mountpoint(dir: &Path) -> io::Result<bool> {
+    let parent = dir.parent().unwrap_or(dir);
+    match fs::metadata(dir) {
+        Ok(metadata) => Ok(metadata.dev() != fs::metadata(parent)?.dev()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// The name of the file containers/storage keeps a piece of image data in: the key itself if it's
+// made of lowercase letters, digits and dots, otherwise its base64 encoding after a `=`.
+fn big_data_name(key: &str) -> String {
+    if key
+        .chars()
+        .all(|c| c == '.' || c.is_ascii_digit() || c.is_ascii_lowercase())
+    {
+        key.to_string()
+    } else {
+        format!(
+            "={}",
+            base64::engine::general_purpose::STANDARD.encode(key.as_bytes())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use ocidir::oci_spec::image::ImageConfiguration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_image_store() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let oci_dir = dir.path().join("oci");
+        let image = Arc::new(Image::new(&oci_dir)?);
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let store = ImageStore::open(&dir.path().join("store"))?;
+
+        let layer = store.add(&image, &oci_dir, "test", "localhost/test:latest")?;
+        assert_eq!(store.layers()?, vec![layer.clone()]);
+        assert!(store.diff_dir(&layer).is_dir());
+        assert!(!store.is_mounted(&layer)?);
+        let images: Vec<StorageImage> = store.read(IMAGES, "images.json")?;
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].layer, layer.id);
+        let image_dir = dir.path().join("store").join(IMAGES).join(&images[0].id);
+        assert!(image_dir.join("manifest").exists());
+        let config: ImageConfiguration = serde_json::from_slice(&fs::read(
+            image_dir.join(big_data_name(&format!("sha256:{}", images[0].id))),
+        )?)?;
+        assert_eq!(
+            config.rootfs().diff_ids(),
+            &vec![format!("sha256:{}", layer.id)]
+        );
+
+        // the same image under another name
+        store.add(&image, &oci_dir, "test", "localhost/test:v1")?;
+        let images: Vec<StorageImage> = store.read(IMAGES, "images.json")?;
+        assert_eq!(images.len(), 1);
+        assert_eq!(
+            images[0].names,
+            vec!["localhost/test:latest", "localhost/test:v1"]
+        );
+
+        store.remove("localhost/test:v1")?;
+        assert_eq!(store.layers()?, vec![layer.clone()]);
+        store.remove("localhost/test:latest")?;
+        assert!(store.layers()?.is_empty());
+        assert!(!store.diff_dir(&layer).exists());
+        assert!(store.remove("localhost/test:v1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_big_data_name() {
+        assert_eq!(big_data_name("manifest"), "manifest");
+        assert_eq!(big_data_name("sha256:ab"), "=c2hhMjU2OmFi");
+    }
+}
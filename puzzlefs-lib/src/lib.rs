@@ -6,11 +6,21 @@ extern crate anyhow;
 pub mod builder;
 mod common;
 pub mod compression;
+pub mod convert;
+pub mod encryption;
+pub mod export;
 pub mod extractor;
-mod format;
+pub mod format;
 pub mod fsverity_helpers;
+pub mod hook;
+pub mod http;
+pub mod image_store;
+pub mod nydus;
 pub mod oci;
 pub mod reader;
+pub mod registry;
+pub mod signature;
+pub mod vectors;
 
 #[allow(clippy::needless_lifetimes)]
 #[allow(clippy::uninlined_format_args)]
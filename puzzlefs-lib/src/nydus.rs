@@ -0,0 +1,590 @@
+//! Conversion between puzzlefs images and Nydus RAFS v5 images (a bootstrap holding the metadata
+//! and the blobs holding the chunks of the files), both ways. RAFS v6, the EROFS based layout,
+//! isn't supported.
+
+use flate2::read::GzDecoder;
+use log::info;
+use nix::libc::{S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK};
+use nix::sys::stat;
+use ocidir::oci_spec::image::ImageManifest;
+use sha2::{Digest as _, Sha256};
+use std::any::Any;
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, Permissions};
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, PermissionsExt};
+use std::path::Path;
+use tempfile::NamedTempFile;
+
+use crate::builder::{write_rootfs, BuildOptions};
+use crate::compression::Compression;
+use crate::format::{
+    BlobRef, DigestAlgorithm, DirEnt, DirList, Features, FileChunk, Ino, Inode, InodeAdditional,
+    InodeMode, Rootfs, Timestamp, VerityData,
+};
+use crate::oci::{media_types, Descriptor, Digest, Image};
+use crate::reader::{FileReader, PuzzleFS, PUZZLEFS_IMAGE_MANIFEST_VERSION};
+
+mod rafs;
+use rafs::{
+    Blob, Bootstrap, ChunkInfo, RafsInode, CHUNK_COMPRESSED, CHUNK_ENCRYPTED, CHUNK_HOLE,
+    COMPRESSION_GZIP, COMPRESSION_LZ4, COMPRESSION_NONE, COMPRESSION_ZSTD, EXPLICIT_UID_GID,
+    HASH_SHA256, HAS_XATTR, INODE_HARDLINK, ROOT_INDEX,
+};
+
+/// The size of the chunks of exported images, the default of nydus-image.
+const EXPORT_CHUNK_SIZE: u32 = 0x100000;
+
+fn decompress_chunk(flags: u64, chunk: &ChunkInfo, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    // chunks which don't get any smaller are stored as they are
+    if chunk.flags & CHUNK_COMPRESSED == 0 || flags & COMPRESSION_NONE != 0 {
+        return Ok(data);
+    }
+    let size = chunk.uncompressed_size as usize;
+    if flags & COMPRESSION_ZSTD != 0 {
+        Ok(zstd::bulk::decompress(&data, size)?)
+    } else if flags & COMPRESSION_LZ4 != 0 {
+        Ok(lz4_flex::block::decompress(&data, size)?)
+    } else if flags & COMPRESSION_GZIP != 0 {
+        let mut decompressed = Vec::with_capacity(size);
+        GzDecoder::new(&data[..]).read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    } else {
+        bail!("unsupported compression (flags {flags:#x})")
+    }
+}
+
+// Puts the uncompressed chunks of a Nydus blob at their uncompressed offsets in a puzzlefs blob.
+fn import_blob<C: Compression + Any>(
+    bootstrap: &Bootstrap,
+    index: usize,
+    blobs_dir: &Path,
+    image: &Image,
+    manifest: &mut ImageManifest,
+    verity_data: &mut VerityData,
+) -> anyhow::Result<Option<BlobRef>> {
+    let chunks = bootstrap
+        .inodes
+        .iter()
+        .flat_map(|inode| &inode.chunks)
+        .filter(|chunk| chunk.blob_index as usize == index && chunk.flags & CHUNK_HOLE == 0)
+        .map(|chunk| (chunk.uncompressed_offset, chunk))
+        .collect::<BTreeMap<_, _>>();
+    if chunks.is_empty() {
+        return Ok(None);
+    }
+
+    let id = &bootstrap.blobs[index].id;
+    info!("importing blob {id}");
+    let blob =
+        fs::File::open(blobs_dir.join(id)).map_err(|e| anyhow!("cannot open blob {id}: {e}"))?;
+    let mut data = Vec::new();
+    for (offset, chunk) in chunks {
+        if chunk.flags & CHUNK_ENCRYPTED != 0 {
+            bail!("encrypted Nydus images aren't supported");
+        }
+        let mut compressed = vec![0; chunk.compressed_size as usize];
+        blob.read_exact_at(&mut compressed, chunk.compressed_offset)?;
+        let chunk_data = decompress_chunk(bootstrap.flags, chunk, compressed)?;
+        if chunk_data.len() != chunk.uncompressed_size as usize
+            || (bootstrap.flags & HASH_SHA256 != 0
+                && Sha256::digest(&chunk_data)[..] != chunk.digest)
+        {
+            bail!(
+                "chunk {} of blob {id} is corrupted",
+                hex::encode(chunk.digest)
+            );
+        }
+        let offset = usize::try_from(offset)?;
+        let end = offset + chunk_data.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[offset..end].copy_from_slice(&chunk_data);
+    }
+
+    let (descriptor, verity, compressed) =
+        image.put_blob::<C>(&data, manifest, media_types::Chunk {})?;
+    let digest = Digest::try_from(descriptor.digest().digest())?.underlying();
+    verity_data.insert(digest, verity);
+    Ok(Some(BlobRef {
+        digest,
+        offset: 0,
+        compressed,
+        algorithm: C::ALGORITHM.unwrap_or_default(),
+        encryption: None,
+    }))
+}
+
+// The chunks of a regular file, with the parts of the file no chunk covers as holes.
+fn file_chunks(inode: &RafsInode, blobs: &[Option<BlobRef>]) -> anyhow::Result<Vec<FileChunk>> {
+    let mut chunks = inode.chunks.clone();
+    chunks.sort_by_key(|chunk| chunk.file_offset);
+    let mut file_chunks = Vec::new();
+    let mut pos = 0;
+    for chunk in chunks {
+        if chunk.file_offset > pos {
+            file_chunks.push(FileChunk::hole(chunk.file_offset - pos));
+        }
+        let len = min(
+            chunk.uncompressed_size.into(),
+            inode.size.saturating_sub(chunk.file_offset),
+        );
+        if len == 0 {
+            continue;
+        }
+        if chunk.flags & CHUNK_HOLE != 0 {
+            file_chunks.push(FileChunk::hole(len));
+        } else {
+            let blob = blobs
+                .get(chunk.blob_index as usize)
+                .copied()
+                .flatten()
+                .ok_or_else(|| anyhow!("invalid blob index {}", chunk.blob_index))?;
+            file_chunks.push(FileChunk {
+                blob: Some(BlobRef {
+                    offset: chunk.uncompressed_offset,
+                    ..blob
+                }),
+                len,
+                // the nydus blobs are used as they are, without reading them
+                checksum: None,
+                inline: None,
+            });
+        }
+        pos = chunk.file_offset + len;
+    }
+    if inode.size > pos {
+        file_chunks.push(FileChunk::hole(inode.size - pos));
+    }
+    Ok(file_chunks)
+}
+
+/// Converts the Nydus image made of `bootstrap` and the blobs in `blobs_dir` (named by their
+/// blob id, the way nydus-image writes them) into the puzzlefs image `tag`. The files keep their
+/// Nydus chunks instead of being chunked again: every Nydus blob becomes a puzzlefs blob holding
+/// its uncompressed chunks at their uncompressed offsets, compressed with `C`. Each blob is
+/// decompressed in memory.
+pub fn import_nydus<C: Compression + Any>(
+    bootstrap: &Path,
+    blobs_dir: &Path,
+    image: &Image,
+    tag: &str,
+) -> anyhow::Result<Descriptor> {
+    let bootstrap = Bootstrap::read(&fs::read(bootstrap)?)?;
+    let mut manifest = image.get_empty_manifest()?;
+    let mut verity_data = VerityData::new();
+    let blobs = (0..bootstrap.blobs.len())
+        .map(|i| {
+            import_blob::<C>(
+                &bootstrap,
+                i,
+                blobs_dir,
+                image,
+                &mut manifest,
+                &mut verity_data,
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // hard links share the puzzlefs inode of their first link; the root comes first, so it gets
+    // inode 1
+    let mut inos = HashMap::<u64, Ino>::new();
+    let pfs_inos = bootstrap
+        .inodes
+        .iter()
+        .map(|inode| {
+            let next = inos.len() as Ino + 1;
+            *inos.entry(inode.ino).or_insert(next)
+        })
+        .collect::<Vec<_>>();
+
+    let mut link_counts = HashMap::<Ino, u32>::new();
+    let mut inodes = Vec::new();
+    for (i, inode) in bootstrap.inodes.iter().enumerate() {
+        let ino = pfs_inos[i];
+        if inodes.len() as Ino >= ino {
+            continue;
+        }
+        let mode = match inode.mode & S_IFMT {
+            S_IFDIR => {
+                let first = inode.child_index.saturating_sub(ROOT_INDEX) as usize;
+                let children = bootstrap
+                    .inodes
+                    .get(first..first + inode.child_count as usize)
+                    .ok_or_else(|| anyhow!("invalid children of inode {}", inode.ino))?;
+                let entries = children
+                    .iter()
+                    .zip(&pfs_inos[first..])
+                    .map(|(child, ino)| {
+                        *link_counts.entry(*ino).or_default() += 1;
+                        DirEnt {
+                            ino: *ino,
+                            name: child.name.clone(),
+                        }
+                    })
+                    .collect();
+                InodeMode::Dir {
+                    dir_list: DirList {
+                        look_below: false,
+                        entries,
+                    },
+                }
+            }
+            S_IFREG => InodeMode::File {
+                chunks: file_chunks(inode, &blobs)?,
+            },
+            S_IFLNK => InodeMode::Lnk,
+            S_IFCHR => InodeMode::Chr {
+                major: stat::major(inode.rdev.into()),
+                minor: stat::minor(inode.rdev.into()),
+            },
+            S_IFBLK => InodeMode::Blk {
+                major: stat::major(inode.rdev.into()),
+                minor: stat::minor(inode.rdev.into()),
+            },
+            S_IFIFO => InodeMode::Fifo,
+            S_IFSOCK => InodeMode::Sock,
+            _ => InodeMode::Unknown,
+        };
+        let mut xattrs = inode.xattrs.clone();
+        xattrs.sort_by(|a, b| a.key.cmp(&b.key));
+        let symlink_target = (inode.mode & S_IFMT == S_IFLNK).then(|| inode.symlink.clone());
+        let additional =
+            (!xattrs.is_empty() || symlink_target.is_some()).then_some(InodeAdditional {
+                xattrs,
+                symlink_target,
+            });
+        inodes.push(Inode {
+            ino,
+            mode,
+            uid: inode.uid,
+            gid: inode.gid,
+            permissions: (inode.mode & 0xFFF) as u16,
+            additional,
+            nlink: 1,
+            mtime: Some(Timestamp {
+                sec: inode.mtime as i64,
+                nsec: inode.mtime_nsec,
+            }),
+            ctime: None,
+            digest: None,
+            verity: None,
+        });
+    }
+    for inode in &mut inodes {
+        if !matches!(inode.mode, InodeMode::Dir { .. }) {
+            inode.nlink = link_counts.get(&inode.ino).copied().unwrap_or(1);
+        }
+    }
+
+    let rootfs = Rootfs {
+        metadatas: vec![inodes],
+        fs_verity_data: verity_data,
+        manifest_version: PUZZLEFS_IMAGE_MANIFEST_VERSION,
+        parent: None,
+        features: Features::empty(),
+        digest_algorithm: DigestAlgorithm::default(),
+    };
+    Ok(write_rootfs(
+        image,
+        rootfs,
+        manifest,
+        tag,
+        &BuildOptions::default(),
+    )?)
+}
+
+// The blob of an exported image, its chunks deduplicated by digest.
+struct BlobWriter {
+    file: NamedTempFile,
+    hasher: Sha256,
+    chunks: HashMap<[u8; 32], ChunkInfo>,
+    blob: Blob,
+}
+
+impl BlobWriter {
+    fn add_chunk(&mut self, data: &[u8], file_offset: u64) -> anyhow::Result<ChunkInfo> {
+        let digest: [u8; 32] = Sha256::digest(data).into();
+        if let Some(chunk) = self.chunks.get(&digest) {
+            return Ok(ChunkInfo {
+                file_offset,
+                ..*chunk
+            });
+        }
+
+        let compressed = zstd::bulk::compress(data, 0)?;
+        let (stored, flags) = if compressed.len() < data.len() {
+            (&compressed[..], CHUNK_COMPRESSED)
+        } else {
+            (data, 0)
+        };
+        self.file.write_all(stored)?;
+        self.hasher.update(stored);
+        let chunk = ChunkInfo {
+            digest,
+            blob_index: 0,
+            flags,
+            compressed_size: stored.len().try_into()?,
+            uncompressed_size: data.len().try_into()?,
+            compressed_offset: self.blob.compressed_size,
+            uncompressed_offset: self.blob.uncompressed_size,
+            file_offset,
+            index: self.blob.chunk_count,
+        };
+        self.blob.chunk_count += 1;
+        self.blob.compressed_size += stored.len() as u64;
+        self.blob.uncompressed_size += data.len() as u64;
+        self.chunks.insert(digest, chunk);
+        Ok(chunk)
+    }
+
+    fn add_file(&mut self, reader: impl Read) -> anyhow::Result<Vec<ChunkInfo>> {
+        let mut reader = reader;
+        let mut chunks = Vec::new();
+        let mut file_offset = 0;
+        loop {
+            let mut data = Vec::new();
+            (&mut reader)
+                .take(EXPORT_CHUNK_SIZE.into())
+                .read_to_end(&mut data)?;
+            if data.is_empty() {
+                return Ok(chunks);
+            }
+            chunks.push(self.add_chunk(&data, file_offset)?);
+            file_offset += data.len() as u64;
+        }
+    }
+}
+
+/// Exports the puzzlefs image `tag` as a Nydus RAFS v5 image: the bootstrap is written to
+/// `bootstrap` and the single blob holding the chunks to `blobs_dir`, named by its blob id, which
+/// is returned. Nydus only reads chunks of a fixed size, so the files are chunked again (in 1MiB
+/// chunks deduplicated across the image and compressed with zstd); the rest carries over.
+pub fn export_nydus(
+    image: Image,
+    tag: &str,
+    bootstrap: &Path,
+    blobs_dir: &Path,
+) -> anyhow::Result<String> {
+    let pfs = PuzzleFS::open(image, tag, None)?;
+    let mut blob = BlobWriter {
+        file: NamedTempFile::new_in(blobs_dir)?,
+        hasher: Sha256::new(),
+        chunks: HashMap::new(),
+        blob: Blob::default(),
+    };
+
+    // (puzzlefs inode, name, parent index) in breadth first order, which is the order of the
+    // Nydus inodes
+    let mut entries = vec![(1, b"/".to_vec(), 0)];
+    // the Nydus inode number of each puzzlefs inode, the index of its first link
+    let mut nydus_inos = HashMap::<Ino, u64>::new();
+    let mut inodes = Vec::new();
+    let mut flags = COMPRESSION_ZSTD | HASH_SHA256 | EXPLICIT_UID_GID;
+    let mut i = 0;
+    while i < entries.len() {
+        let (pfs_ino, name, parent) = entries[i].clone();
+        let index = i as u64 + 1;
+        i += 1;
+        let inode = pfs.find_inode(pfs_ino)?;
+        let ino = *nydus_inos.entry(pfs_ino).or_insert(index);
+        let mut rafs_inode = RafsInode {
+            parent,
+            ino,
+            uid: inode.uid,
+            gid: inode.gid,
+            mode: inode.permissions.into(),
+            nlink: inode.nlink,
+            mtime: inode.mtime.map_or(0, |t| t.sec.max(0) as u64),
+            mtime_nsec: inode.mtime.map_or(0, |t| t.nsec),
+            name,
+            ..Default::default()
+        };
+        if let Some(additional) = &inode.additional {
+            rafs_inode.xattrs = additional.xattrs.clone();
+        }
+        if !rafs_inode.xattrs.is_empty() {
+            flags |= HAS_XATTR;
+        }
+
+        match &inode.mode {
+            InodeMode::Dir { dir_list } => {
+                rafs_inode.mode |= S_IFDIR;
+                let mut children = dir_list
+                    .entries
+                    .iter()
+                    .map(|entry| (entry.ino, entry.name.clone(), index))
+                    .collect::<Vec<_>>();
+                children.sort_by(|a, b| a.1.cmp(&b.1));
+                rafs_inode.child_index = (entries.len() + 1).try_into()?;
+                rafs_inode.child_count = children.len().try_into()?;
+                entries.extend(children);
+            }
+            InodeMode::File { .. } => {
+                rafs_inode.mode |= S_IFREG;
+                rafs_inode.size = inode.file_len()?;
+                if ino == index {
+                    rafs_inode.chunks = blob.add_file(FileReader::new(&pfs.oci, &inode)?)?;
+                } else {
+                    // a hard link, whose chunks are already there
+                    rafs_inode.chunks = inodes[ino as usize - 1].chunks.clone();
+                }
+                let mut hasher = Sha256::new();
+                for chunk in &rafs_inode.chunks {
+                    hasher.update(chunk.digest);
+                }
+                rafs_inode.digest = hasher.finalize().into();
+            }
+            InodeMode::Lnk => {
+                rafs_inode.mode |= S_IFLNK;
+                rafs_inode.symlink = inode.symlink_target()?.as_bytes().to_vec();
+                rafs_inode.size = rafs_inode.symlink.len() as u64;
+                rafs_inode.digest = Sha256::digest(&rafs_inode.symlink).into();
+            }
+            InodeMode::Chr { major, minor } => {
+                rafs_inode.mode |= S_IFCHR;
+                rafs_inode.rdev = stat::makedev(*major, *minor).try_into()?;
+            }
+            InodeMode::Blk { major, minor } => {
+                rafs_inode.mode |= S_IFBLK;
+                rafs_inode.rdev = stat::makedev(*major, *minor).try_into()?;
+            }
+            InodeMode::Fifo => rafs_inode.mode |= S_IFIFO,
+            InodeMode::Sock => rafs_inode.mode |= S_IFSOCK,
+            InodeMode::Unknown | InodeMode::Wht => {
+                bail!("cannot export inode {pfs_ino} to Nydus")
+            }
+        }
+        if !rafs_inode.is_dir() && rafs_inode.nlink > 1 {
+            rafs_inode.flags |= INODE_HARDLINK;
+        }
+        inodes.push(rafs_inode);
+    }
+
+    // the digest of a directory covers its children, which come after it, and its link count
+    // its subdirectories
+    for i in (0..inodes.len()).rev() {
+        if !inodes[i].is_dir() {
+            continue;
+        }
+        let first = inodes[i].child_index as usize - 1;
+        let children = &inodes[first..first + inodes[i].child_count as usize];
+        let mut hasher = Sha256::new();
+        for child in children {
+            hasher.update(child.digest);
+        }
+        let subdirs = children.iter().filter(|child| child.is_dir()).count();
+        inodes[i].digest = hasher.finalize().into();
+        inodes[i].nlink = 2 + u32::try_from(subdirs)?;
+    }
+
+    let id = hex::encode(blob.hasher.finalize());
+    blob.file
+        .as_file()
+        .set_permissions(Permissions::from_mode(0o644))?;
+    blob.file.persist(blobs_dir.join(&id))?;
+    let bootstrap_data = Bootstrap {
+        flags,
+        block_size: EXPORT_CHUNK_SIZE,
+        blobs: vec![Blob {
+            id: id.clone(),
+            ..blob.blob
+        }],
+        inodes,
+    };
+    bootstrap_data.write(fs::File::create(bootstrap)?)?;
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_initial_rootfs;
+    use crate::compression::{Noop, Zstd};
+    use crate::reader::WalkPuzzleFS;
+    use sha2::Digest as _;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    // (path, inode, permissions, contents or symlink target) of every entry of the image
+    fn entries(image: Image, tag: &str) -> anyhow::Result<Vec<(PathBuf, Ino, u16, Vec<u8>)>> {
+        let mut pfs = PuzzleFS::open(image, tag, None)?;
+        let mut entries = Vec::new();
+        for entry in WalkPuzzleFS::walk(&mut pfs)? {
+            let entry = entry?;
+            let mut data = Vec::new();
+            match entry.inode.mode {
+                InodeMode::File { .. } => {
+                    entry.open()?.read_to_end(&mut data)?;
+                }
+                InodeMode::Lnk => {
+                    data = entry.inode.symlink_target()?.as_bytes().to_vec();
+                }
+                _ => {}
+            }
+            entries.push((
+                entry.path.clone(),
+                entry.inode.ino,
+                entry.inode.permissions,
+                data,
+            ));
+        }
+        Ok(entries)
+    }
+
+    #[test]
+    fn test_nydus_roundtrip() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("usr/lib"))?;
+        // two identical chunks and a shorter one
+        fs::write(rootfs.join("usr/lib/big"), vec![0; 2 * 0x100000 + 1000])?;
+        fs::write(rootfs.join("usr/hello"), b"hello")?;
+        fs::write(rootfs.join("empty"), b"")?;
+        fs::hard_link(rootfs.join("usr/hello"), rootfs.join("hello"))?;
+        symlink("usr/hello", rootfs.join("link"))?;
+
+        let image = Image::new(&dir.path().join("oci"))?;
+        build_initial_rootfs::<Noop>(&rootfs, &image, "test")?;
+        let expected = entries(Image::open(&dir.path().join("oci"))?, "test")?;
+
+        let blobs_dir = dir.path().join("blobs");
+        fs::create_dir(&blobs_dir)?;
+        let bootstrap = dir.path().join("bootstrap");
+        let id = export_nydus(image, "test", &bootstrap, &blobs_dir)?;
+        let exported = Bootstrap::read(&fs::read(&bootstrap)?)?;
+        assert_eq!(exported.blobs[0].id, id);
+        assert_eq!(exported.blobs[0].chunk_count, 3);
+        let blob = fs::read(blobs_dir.join(&id))?;
+        assert_eq!(hex::encode(Sha256::digest(&blob)), id);
+
+        let imported = Image::new(&dir.path().join("imported"))?;
+        import_nydus::<Zstd>(&bootstrap, &blobs_dir, &imported, "test")?;
+        assert_eq!(
+            entries(Image::open(&dir.path().join("imported"))?, "test")?
+                .into_iter()
+                .map(|(path, _, permissions, data)| (path, permissions, data))
+                .collect::<Vec<_>>(),
+            expected
+                .iter()
+                .map(|(path, _, permissions, data)| (path.clone(), *permissions, data.clone()))
+                .collect::<Vec<_>>()
+        );
+
+        // the hard link is still one
+        let imported = entries(Image::open(&dir.path().join("imported"))?, "test")?;
+        let ino = |path: &str| {
+            imported
+                .iter()
+                .find(|entry| entry.0 == Path::new(path))
+                .unwrap()
+                .1
+        };
+        assert_eq!(ino("/hello"), ino("/usr/hello"));
+        Ok(())
+    }
+}
@@ -0,0 +1,486 @@
+// The RAFS v5 bootstrap, as nydus-image writes it: an 8K superblock, the inode table (the offset
+// of each inode in 8 byte units, indexed by inode index - 1), the prefetch table, the blob table
+// and the extended blob table, followed by the inodes. Each inode is followed by its name, its
+// symlink target and its xattrs, each padded to 8 bytes, and the inode of a regular file by the
+// table of its chunks. All integers are little endian.
+//
+// The inodes are numbered in breadth first order, the children of a directory being the
+// contiguous range of indexes starting at its child index, sorted by name. Hard links get an
+// index of their own but share the inode number of the first link.
+
+use nix::libc::{S_IFDIR, S_IFMT, S_IFREG};
+use std::io::Write;
+
+use crate::format::Xattr;
+
+const MAGIC: u32 = 0x5241_4653;
+const VERSION: u32 = 0x500;
+const SUPERBLOCK_SIZE: usize = 8192;
+// the part of the superblock which isn't reserved
+const SUPERBLOCK_HEADER_SIZE: usize = 80;
+const ALIGNMENT: usize = 8;
+const INODE_SIZE: usize = 128;
+const CHUNK_INFO_SIZE: usize = 80;
+const EXT_BLOB_ENTRY_SIZE: usize = 64;
+pub(super) const ROOT_INDEX: u32 = 1;
+
+// superblock flags
+pub(super) const COMPRESSION_NONE: u64 = 0x1;
+pub(super) const COMPRESSION_LZ4: u64 = 0x2;
+pub(super) const HASH_SHA256: u64 = 0x8;
+pub(super) const EXPLICIT_UID_GID: u64 = 0x10;
+pub(super) const HAS_XATTR: u64 = 0x20;
+pub(super) const COMPRESSION_GZIP: u64 = 0x40;
+pub(super) const COMPRESSION_ZSTD: u64 = 0x80;
+
+// inode flags
+pub(super) const INODE_SYMLINK: u64 = 0x1;
+pub(super) const INODE_HARDLINK: u64 = 0x2;
+pub(super) const INODE_XATTR: u64 = 0x4;
+
+// chunk flags
+pub(super) const CHUNK_COMPRESSED: u32 = 0x1;
+pub(super) const CHUNK_HOLE: u32 = 0x2;
+pub(super) const CHUNK_ENCRYPTED: u32 = 0x4;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(super) struct Blob {
+    pub(super) id: String,
+    pub(super) chunk_count: u32,
+    pub(super) uncompressed_size: u64,
+    pub(super) compressed_size: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(super) struct ChunkInfo {
+    pub(super) digest: [u8; 32],
+    pub(super) blob_index: u32,
+    pub(super) flags: u32,
+    pub(super) compressed_size: u32,
+    pub(super) uncompressed_size: u32,
+    pub(super) compressed_offset: u64,
+    pub(super) uncompressed_offset: u64,
+    pub(super) file_offset: u64,
+    // the index of the chunk in its blob
+    pub(super) index: u32,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(super) struct RafsInode {
+    pub(super) digest: [u8; 32],
+    pub(super) parent: u64,
+    pub(super) ino: u64,
+    pub(super) uid: u32,
+    pub(super) gid: u32,
+    pub(super) mode: u32,
+    pub(super) size: u64,
+    pub(super) flags: u64,
+    pub(super) nlink: u32,
+    // for directories; a regular file keeps the number of its chunks in child_count
+    pub(super) child_index: u32,
+    pub(super) child_count: u32,
+    pub(super) rdev: u32,
+    pub(super) mtime: u64,
+    pub(super) mtime_nsec: u32,
+    pub(super) name: Vec<u8>,
+    pub(super) symlink: Vec<u8>,
+    pub(super) xattrs: Vec<Xattr>,
+    pub(super) chunks: Vec<ChunkInfo>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(super) struct Bootstrap {
+    pub(super) flags: u64,
+    pub(super) block_size: u32,
+    pub(super) blobs: Vec<Blob>,
+    // the inode with index i is at i - 1
+    pub(super) inodes: Vec<RafsInode>,
+}
+
+fn align(size: usize) -> usize {
+    size.next_multiple_of(ALIGNMENT)
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn at(data: &'a [u8], pos: usize) -> Self {
+        Cursor { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or_else(|| anyhow!("truncated bootstrap at offset {}", self.pos))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    // reads `len` bytes along with their padding
+    fn padded(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        let bytes = self.bytes(len)?;
+        self.bytes(align(len) - len)?;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize(align(buf.len()), 0);
+}
+
+impl ChunkInfo {
+    fn read(cursor: &mut Cursor<'_>) -> anyhow::Result<Self> {
+        let chunk = ChunkInfo {
+            digest: cursor.array()?,
+            blob_index: cursor.u32()?,
+            flags: cursor.u32()?,
+            compressed_size: cursor.u32()?,
+            uncompressed_size: cursor.u32()?,
+            compressed_offset: cursor.u64()?,
+            uncompressed_offset: cursor.u64()?,
+            file_offset: cursor.u64()?,
+            index: cursor.u32()?,
+        };
+        cursor.u32()?;
+        Ok(chunk)
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.extend_from_slice(&self.digest);
+        buf.extend_from_slice(&self.blob_index.to_le_bytes());
+        buf.extend_from_slice(&self.flags.to_le_bytes());
+        buf.extend_from_slice(&self.compressed_size.to_le_bytes());
+        buf.extend_from_slice(&self.uncompressed_size.to_le_bytes());
+        buf.extend_from_slice(&self.compressed_offset.to_le_bytes());
+        buf.extend_from_slice(&self.uncompressed_offset.to_le_bytes());
+        buf.extend_from_slice(&self.file_offset.to_le_bytes());
+        buf.extend_from_slice(&self.index.to_le_bytes());
+        buf.extend_from_slice(&[0; 4]);
+        debug_assert_eq!(buf.len() - start, CHUNK_INFO_SIZE);
+    }
+}
+
+impl RafsInode {
+    pub(super) fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub(super) fn is_reg(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    fn read(cursor: &mut Cursor<'_>) -> anyhow::Result<Self> {
+        let mut inode = RafsInode {
+            digest: cursor.array()?,
+            parent: cursor.u64()?,
+            ino: cursor.u64()?,
+            uid: cursor.u32()?,
+            gid: cursor.u32()?,
+            ..Default::default()
+        };
+        let _projid = cursor.u32()?;
+        inode.mode = cursor.u32()?;
+        inode.size = cursor.u64()?;
+        let _blocks = cursor.u64()?;
+        inode.flags = cursor.u64()?;
+        inode.nlink = cursor.u32()?;
+        inode.child_index = cursor.u32()?;
+        inode.child_count = cursor.u32()?;
+        let name_size = cursor.u16()?;
+        let symlink_size = cursor.u16()?;
+        inode.rdev = cursor.u32()?;
+        inode.mtime_nsec = cursor.u32()?;
+        inode.mtime = cursor.u64()?;
+        cursor.bytes(8)?;
+
+        inode.name = cursor.padded(name_size.into())?.to_vec();
+        if inode.flags & INODE_SYMLINK != 0 {
+            inode.symlink = cursor.padded(symlink_size.into())?.to_vec();
+        }
+        if inode.flags & INODE_XATTR != 0 {
+            let size = cursor.u64()?.try_into()?;
+            let mut pairs = Cursor::at(cursor.padded(size)?, 0);
+            while pairs.pos < pairs.data.len() {
+                let len = pairs.u32()?.try_into()?;
+                let pair = pairs.bytes(len)?;
+                let separator = pair
+                    .iter()
+                    .position(|b| *b == 0)
+                    .ok_or_else(|| anyhow!("invalid xattr in inode {}", inode.ino))?;
+                inode.xattrs.push(Xattr {
+                    key: pair[..separator].to_vec(),
+                    val: pair[separator + 1..].to_vec(),
+                });
+            }
+        }
+        if inode.is_reg() {
+            for _ in 0..inode.child_count {
+                inode.chunks.push(ChunkInfo::read(cursor)?);
+            }
+        }
+        Ok(inode)
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) -> anyhow::Result<()> {
+        let mut flags = self.flags & !(INODE_SYMLINK | INODE_XATTR);
+        if !self.symlink.is_empty() {
+            flags |= INODE_SYMLINK;
+        }
+        if !self.xattrs.is_empty() {
+            flags |= INODE_XATTR;
+        }
+        let start = buf.len();
+        buf.extend_from_slice(&self.digest);
+        buf.extend_from_slice(&self.parent.to_le_bytes());
+        buf.extend_from_slice(&self.ino.to_le_bytes());
+        buf.extend_from_slice(&self.uid.to_le_bytes());
+        buf.extend_from_slice(&self.gid.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&self.mode.to_le_bytes());
+        buf.extend_from_slice(&self.size.to_le_bytes());
+        buf.extend_from_slice(&self.size.div_ceil(512).to_le_bytes());
+        buf.extend_from_slice(&flags.to_le_bytes());
+        buf.extend_from_slice(&self.nlink.to_le_bytes());
+        buf.extend_from_slice(&self.child_index.to_le_bytes());
+        let child_count = if self.is_reg() {
+            self.chunks.len().try_into()?
+        } else {
+            self.child_count
+        };
+        buf.extend_from_slice(&child_count.to_le_bytes());
+        buf.extend_from_slice(&u16::try_from(self.name.len())?.to_le_bytes());
+        buf.extend_from_slice(&u16::try_from(self.symlink.len())?.to_le_bytes());
+        buf.extend_from_slice(&self.rdev.to_le_bytes());
+        buf.extend_from_slice(&self.mtime_nsec.to_le_bytes());
+        buf.extend_from_slice(&self.mtime.to_le_bytes());
+        buf.extend_from_slice(&[0; 8]);
+        debug_assert_eq!(buf.len() - start, INODE_SIZE);
+
+        buf.extend_from_slice(&self.name);
+        pad(buf);
+        buf.extend_from_slice(&self.symlink);
+        pad(buf);
+        if !self.xattrs.is_empty() {
+            let mut pairs = Vec::new();
+            for xattr in &self.xattrs {
+                let len = u32::try_from(xattr.key.len() + 1 + xattr.val.len())?;
+                pairs.extend_from_slice(&len.to_le_bytes());
+                pairs.extend_from_slice(&xattr.key);
+                pairs.push(0);
+                pairs.extend_from_slice(&xattr.val);
+            }
+            buf.extend_from_slice(&(pairs.len() as u64).to_le_bytes());
+            buf.extend_from_slice(&pairs);
+            pad(buf);
+        }
+        if self.is_reg() {
+            for chunk in &self.chunks {
+                chunk.write(buf);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Bootstrap {
+    pub(super) fn read(data: &[u8]) -> anyhow::Result<Self> {
+        let mut sb = Cursor::at(data, 0);
+        let magic = sb.u32()?;
+        let version = sb.u32()?;
+        if magic != MAGIC || version != VERSION {
+            bail!("not a RAFS v5 bootstrap (magic {magic:#x}, version {version:#x})");
+        }
+        let _sb_size = sb.u32()?;
+        let block_size = sb.u32()?;
+        let flags = sb.u64()?;
+        let _inodes_count = sb.u64()?;
+        let inode_table_offset = sb.u64()?.try_into()?;
+        let _prefetch_table_offset = sb.u64()?;
+        let blob_table_offset = sb.u64()?.try_into()?;
+        let inode_table_entries = sb.u32()?;
+        let _prefetch_table_entries = sb.u32()?;
+        let blob_table_size = sb.u32()?.try_into()?;
+        let ext_blob_table_entries = sb.u32()?;
+        let ext_blob_table_offset = sb.u64()?.try_into()?;
+
+        // the blob ids are 0 terminated, after the readahead range of the blob
+        let mut blobs = Vec::new();
+        let mut table = Cursor::at(
+            Cursor::at(data, blob_table_offset).bytes(blob_table_size)?,
+            0,
+        );
+        while table.data[table.pos..].iter().any(|b| *b != 0) {
+            table.bytes(8)?;
+            let rest = &table.data[table.pos..];
+            let len = rest
+                .iter()
+                .position(|b| *b == 0)
+                .ok_or_else(|| anyhow!("invalid blob table"))?;
+            let id = String::from_utf8(table.bytes(len + 1)?[..len].to_vec())?;
+            blobs.push(Blob {
+                id,
+                ..Default::default()
+            });
+        }
+        let mut ext = Cursor::at(data, ext_blob_table_offset);
+        for blob in blobs.iter_mut().take(ext_blob_table_entries as usize) {
+            let entry = ext.bytes(EXT_BLOB_ENTRY_SIZE)?;
+            let mut entry = Cursor::at(entry, 0);
+            blob.chunk_count = entry.u32()?;
+            entry.u32()?;
+            blob.uncompressed_size = entry.u64()?;
+            blob.compressed_size = entry.u64()?;
+        }
+
+        let mut inodes = Vec::new();
+        let mut table = Cursor::at(data, inode_table_offset);
+        for _ in 0..inode_table_entries {
+            let offset = table.u32()? as usize * ALIGNMENT;
+            inodes.push(RafsInode::read(&mut Cursor::at(data, offset))?);
+        }
+
+        Ok(Bootstrap {
+            flags,
+            block_size,
+            blobs,
+            inodes,
+        })
+    }
+
+    pub(super) fn write(&self, mut w: impl Write) -> anyhow::Result<()> {
+        let inode_table_size = align(self.inodes.len() * 4);
+        let mut blob_table = Vec::new();
+        for blob in &self.blobs {
+            // no readahead range
+            blob_table.extend_from_slice(&[0; 8]);
+            blob_table.extend_from_slice(blob.id.as_bytes());
+            blob_table.push(0);
+        }
+        pad(&mut blob_table);
+        let mut ext_blob_table = Vec::new();
+        for blob in &self.blobs {
+            ext_blob_table.extend_from_slice(&blob.chunk_count.to_le_bytes());
+            ext_blob_table.extend_from_slice(&[0; 4]);
+            ext_blob_table.extend_from_slice(&blob.uncompressed_size.to_le_bytes());
+            ext_blob_table.extend_from_slice(&blob.compressed_size.to_le_bytes());
+            ext_blob_table.resize(ext_blob_table.len() + EXT_BLOB_ENTRY_SIZE - 24, 0);
+        }
+
+        let inode_table_offset = SUPERBLOCK_SIZE;
+        // the prefetch table is empty
+        let prefetch_table_offset = inode_table_offset + inode_table_size;
+        let blob_table_offset = prefetch_table_offset;
+        let ext_blob_table_offset = blob_table_offset + blob_table.len();
+        let inodes_offset = ext_blob_table_offset + ext_blob_table.len();
+
+        let mut inodes = Vec::new();
+        let mut inode_table = Vec::new();
+        for inode in &self.inodes {
+            let offset = u32::try_from((inodes_offset + inodes.len()) / ALIGNMENT)?;
+            inode_table.extend_from_slice(&offset.to_le_bytes());
+            inode.write(&mut inodes)?;
+        }
+        pad(&mut inode_table);
+
+        let mut sb = Vec::with_capacity(SUPERBLOCK_SIZE);
+        sb.extend_from_slice(&MAGIC.to_le_bytes());
+        sb.extend_from_slice(&VERSION.to_le_bytes());
+        sb.extend_from_slice(&(SUPERBLOCK_SIZE as u32).to_le_bytes());
+        sb.extend_from_slice(&self.block_size.to_le_bytes());
+        sb.extend_from_slice(&self.flags.to_le_bytes());
+        sb.extend_from_slice(&(self.inodes.len() as u64).to_le_bytes());
+        sb.extend_from_slice(&(inode_table_offset as u64).to_le_bytes());
+        sb.extend_from_slice(&(prefetch_table_offset as u64).to_le_bytes());
+        sb.extend_from_slice(&(blob_table_offset as u64).to_le_bytes());
+        sb.extend_from_slice(&u32::try_from(self.inodes.len())?.to_le_bytes());
+        sb.extend_from_slice(&0u32.to_le_bytes());
+        sb.extend_from_slice(&u32::try_from(blob_table.len())?.to_le_bytes());
+        sb.extend_from_slice(&u32::try_from(self.blobs.len())?.to_le_bytes());
+        sb.extend_from_slice(&(ext_blob_table_offset as u64).to_le_bytes());
+        debug_assert_eq!(sb.len(), SUPERBLOCK_HEADER_SIZE);
+        sb.resize(SUPERBLOCK_SIZE, 0);
+
+        for part in [sb, inode_table, blob_table, ext_blob_table, inodes] {
+            w.write_all(&part)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bootstrap_roundtrip() -> anyhow::Result<()> {
+        let root = RafsInode {
+            ino: 1,
+            mode: S_IFDIR | 0o755,
+            nlink: 2,
+            child_index: 2,
+            child_count: 1,
+            name: b"/".to_vec(),
+            xattrs: vec![Xattr {
+                key: b"user.test".to_vec(),
+                val: b"value".to_vec(),
+            }],
+            ..Default::default()
+        };
+        let file = RafsInode {
+            parent: 1,
+            ino: 2,
+            mode: S_IFREG | 0o644,
+            size: 10,
+            nlink: 1,
+            name: b"file".to_vec(),
+            chunks: vec![ChunkInfo {
+                digest: [1; 32],
+                compressed_size: 10,
+                uncompressed_size: 10,
+                ..Default::default()
+            }],
+            child_count: 1,
+            ..Default::default()
+        };
+        let bootstrap = Bootstrap {
+            flags: COMPRESSION_NONE | HASH_SHA256 | EXPLICIT_UID_GID | HAS_XATTR,
+            block_size: 0x100000,
+            blobs: vec![Blob {
+                id: "a".repeat(64),
+                chunk_count: 1,
+                uncompressed_size: 10,
+                compressed_size: 10,
+            }],
+            inodes: vec![root, file],
+        };
+        let mut buf = Vec::new();
+        bootstrap.write(&mut buf)?;
+        let mut read = Bootstrap::read(&buf)?;
+        // the flags telling what follows an inode are set when it's written
+        read.inodes[0].flags &= !INODE_XATTR;
+        assert_eq!(read, bootstrap);
+        Ok(())
+    }
+}
@@ -1,39 +1,174 @@
 use crate::fsverity_helpers::{check_fs_verity, get_fs_verity_digest};
 use std::any::Any;
 use std::backtrace::Backtrace;
+use std::cmp::min;
 use std::fs;
 use std::io;
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, Write};
+use std::os::fd::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use sha2::{Digest as Sha2Digest, Sha256};
 
-use crate::compression::{Compression, Decompressor, Noop, Zstd};
-use crate::format::{Result, RootfsReader, VerityData, WireFormatError, SHA256_BLOCK_SIZE};
+use crate::compression::{
+    looks_incompressible, Compression, Decompressor, Lz4, Noop, Xz, Zstd, ZstdDictionary,
+};
+use crate::format::{
+    BlobRef, CompressionAlgorithm, FileChunk, Limits, Result, RootfsReader, WireFormatError,
+    SHA256_BLOCK_SIZE,
+};
 use std::io::{Error, ErrorKind};
 
+use crate::encryption::{key_reference, Cipher, Encryption, EncryptionKey, KeyProvider};
 pub use crate::format::Digest;
-use crate::oci::media_types::{PuzzleFSMediaType, PUZZLEFS_ROOTFS, VERITY_ROOT_HASH_ANNOTATION};
+use crate::oci::media_types::{
+    is_rootfs, is_rootfs_name, is_zstd_dictionary, PuzzleFSMediaType, BUILD_OPTIONS_ANNOTATION,
+    PUZZLEFS_ANNOTATION_PREFIX, VERITY_ROOT_HASH_ANNOTATION,
+};
+use crate::reader::CancellationToken;
+use crate::signature::SignaturePolicy;
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
 use ocidir::oci_spec::image;
 pub use ocidir::oci_spec::image::Descriptor;
-use ocidir::oci_spec::image::{ImageIndex, ImageManifest, MediaType};
+use ocidir::oci_spec::image::{
+    Arch, ImageConfiguration, ImageIndex, ImageIndexBuilder, ImageManifest, MediaType, Os,
+    Platform, PlatformBuilder, ANNOTATION_REF_NAME, SCHEMA_VERSION,
+};
 use ocidir::OciDir;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::str::FromStr;
 
 use std::io::Cursor;
+use std::thread;
+use std::time::{Duration, Instant};
 
+pub mod blob_store;
+mod copy;
+mod fsck;
+mod gc;
 pub mod media_types;
+mod recent;
+mod referrers;
+mod refs;
+mod repair;
+mod stats;
+mod tags;
+mod upgrade;
 
-pub struct Image(pub OciDir);
+pub use blob_store::{BlobCache, BlobSource, BlobStore, LayoutBlobStore, LazyFetcher};
+pub use copy::CopyReport;
+pub use fsck::FsckProblem;
+pub use gc::GcReport;
+use recent::RecentlyRead;
+pub use referrers::VerityDigests;
+pub use repair::RepairReport;
+pub use stats::{FileSize, ImageStats};
+pub use upgrade::OLDEST_READABLE_MANIFEST_VERSION;
+
+const INDEX: &str = "index.json";
+const INDEX_TEMP: &str = ".index.json.tmp";
+
+/// How long updates of the index wait for other processes updating the same layout by default,
+/// see [`Image::with_lock_timeout`].
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(300);
+// how often a held lock is tried again
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+// how much of the plaintext of encrypted blobs, and of the chunks whose checksum was checked,
+// readers keep for the reads to come
+const DECRYPTED_BLOBS_SIZE: usize = 64 * 1024 * 1024;
+const VERIFIED_CHUNKS_SIZE: usize = 32 * 1024 * 1024;
+
+/// Opens a blob of `store`, failing with [`WireFormatError::BlobMissing`] if it isn't there and
+/// with [`WireFormatError::DigestMismatch`] if `verity` is given and isn't its fs-verity digest.
+pub(crate) fn open_blob(
+    store: &dyn BlobStore,
+    digest: &str,
+    verity: Option<&[u8]>,
+) -> Result<cap_std::fs::File> {
+    let file = match store.open_blob(digest) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(match Digest::try_from(digest) {
+                Ok(digest) => WireFormatError::BlobMissing {
+                    digest,
+                    backtrace: Backtrace::capture(),
+                },
+                Err(_) => e.into(),
+            })
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(verity) = verity {
+        check_fs_verity(&file, verity)?;
+    }
+    Ok(file)
+}
+
+/// An OCI layout holding puzzlefs images, along with the key decrypting the chunks of encrypted
+/// images, if one was given with [`Image::with_key`] (and the plaintext of the blobs it decrypted
+/// last), and the store holding the blobs, the layout's own blobs directory unless another one was
+/// given with [`Image::with_blob_store`]. Images are only opened if they are signed as required by
+/// the policy given with [`Image::with_signature_policy`], if any. Tags of multi-platform images
+/// resolve to the manifest for the platform given with [`Image::with_platform`], the host's by
+/// default. Updates of the index wait for the layout lock for as long as given with
+/// [`Image::with_lock_timeout`]. The metadata of the images is checked against the limits given
+/// with [`Image::with_limits`], the default ones unless told otherwise. Chunks compressed with a
+/// zstd dictionary are read with the ones loaded by [`Image::load_dictionaries`], by id.
+pub struct Image(
+    pub OciDir,
+    Option<EncryptionKey>,
+    Arc<dyn BlobStore>,
+    Option<SignaturePolicy>,
+    Option<Platform>,
+    Duration,
+    Limits,
+    RecentlyRead<[u8; SHA256_BLOCK_SIZE]>,
+    RecentlyRead<(BlobRef, u64, Option<u32>)>,
+    Mutex<HashMap<u32, ZstdDictionary>>,
+);
+
+/// A compressed and hashed blob that hasn't been written to the image yet.
+pub struct PreparedBlob {
+    descriptor: Descriptor,
+    data: Vec<u8>,
+    digest: [u8; SHA256_BLOCK_SIZE],
+    fs_verity_digest: [u8; SHA256_BLOCK_SIZE],
+    compressed: bool,
+    is_rootfs: bool,
+}
+
+impl PreparedBlob {
+    pub fn descriptor(&self) -> &Descriptor {
+        &self.descriptor
+    }
+
+    /// What [`Image::write_blob`] would return for this blob, without writing it.
+    pub fn into_parts(self) -> (Descriptor, [u8; SHA256_BLOCK_SIZE], bool) {
+        (self.descriptor, self.fs_verity_digest, self.compressed)
+    }
+}
 
 impl Image {
     pub fn new(oci_dir: &Path) -> Result<Self> {
         fs::create_dir_all(oci_dir)?;
         let d = cap_std::fs::Dir::open_ambient_dir(oci_dir, cap_std::ambient_authority())?;
         let oci_dir = OciDir::ensure(d)?;
+        let blobs = LayoutBlobStore::new(&oci_dir)?;
 
-        Ok(Self(oci_dir))
+        Ok(Self(
+            oci_dir,
+            None,
+            Arc::new(blobs),
+            None,
+            None,
+            DEFAULT_LOCK_TIMEOUT,
+            Limits::default(),
+            RecentlyRead::new(DECRYPTED_BLOBS_SIZE),
+            RecentlyRead::new(VERIFIED_CHUNKS_SIZE),
+            Mutex::new(HashMap::new()),
+        ))
     }
 
     pub fn open(oci_dir: &Path) -> Result<Self> {
@@ -43,7 +178,93 @@ impl Image {
             cap_std::ambient_authority(),
         )?;
         let oci_dir = OciDir::open_with_external_blobs(d, blobs_dir)?;
-        Ok(Self(oci_dir))
+        let blobs = LayoutBlobStore::new(&oci_dir)?;
+        Ok(Self(
+            oci_dir,
+            None,
+            Arc::new(blobs),
+            None,
+            None,
+            DEFAULT_LOCK_TIMEOUT,
+            Limits::default(),
+            RecentlyRead::new(DECRYPTED_BLOBS_SIZE),
+            RecentlyRead::new(VERIFIED_CHUNKS_SIZE),
+            Mutex::new(HashMap::new()),
+        ))
+    }
+
+    /// Sets the key to decrypt the chunks of encrypted images with.
+    pub fn with_key(mut self, key: EncryptionKey) -> Self {
+        self.1 = Some(key);
+        // what the previous key decrypted doesn't vouch for this one
+        self.7 = RecentlyRead::new(DECRYPTED_BLOBS_SIZE);
+        self.8 = RecentlyRead::new(VERIFIED_CHUNKS_SIZE);
+        self
+    }
+
+    /// Sets the key the image tagged `tag` is encrypted with, as looked up by its id in
+    /// `provider`; failing with [`WireFormatError::MissingKey`] if it doesn't have it. Images
+    /// which aren't encrypted don't need a key.
+    pub fn with_key_from(self, tag: &str, provider: &dyn KeyProvider) -> Result<Self> {
+        let Some(key_id) = self.key_id(tag)? else {
+            return Ok(self);
+        };
+        match provider.key(&key_id)? {
+            Some(key) => Ok(self.with_key(key)),
+            None => Err(WireFormatError::MissingKey(
+                format!("{tag} is encrypted with key {key_id}"),
+                Backtrace::capture(),
+            )),
+        }
+    }
+
+    /// Only opens images signed as required by `policy`, see [`Image::check_signature`].
+    pub fn with_signature_policy(mut self, policy: SignaturePolicy) -> Self {
+        self.3 = Some(policy);
+        self
+    }
+
+    /// Reads (and builds) the images for `platform` instead of the host's. Builds for a given
+    /// platform add their manifest to an image index under the tag, next to the ones for the
+    /// other platforms.
+    pub fn with_platform(mut self, platform: Platform) -> Self {
+        self.4 = Some(platform);
+        self
+    }
+
+    /// Gives up updating the index after waiting `timeout` for the processes updating the same
+    /// layout, failing with [`WireFormatError::LockTimeout`].
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.5 = timeout;
+        self
+    }
+
+    /// Rejects the images whose metadata goes over `limits`, with
+    /// [`WireFormatError::LimitExceeded`], instead of the default limits. Images which are
+    /// trusted can be read without any with [`Limits::UNLIMITED`].
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.6 = limits;
+        self
+    }
+
+    /// The limits the metadata of the images is checked against.
+    pub fn limits(&self) -> Limits {
+        self.6
+    }
+
+    /// The platform tags are resolved for.
+    pub fn platform(&self) -> Platform {
+        self.4.clone().unwrap_or_default()
+    }
+
+    /// Keeps the blobs in `store` instead of the blobs directory of the layout.
+    pub fn with_blob_store(mut self, store: impl BlobStore + 'static) -> Self {
+        self.2 = Arc::new(store);
+        self
+    }
+
+    pub fn blob_store(&self) -> &dyn BlobStore {
+        self.2.as_ref()
     }
 
     pub fn blob_path() -> PathBuf {
@@ -57,45 +278,105 @@ impl Image {
         image_manifest: &mut ImageManifest,
         media_type: impl PuzzleFSMediaType,
     ) -> Result<(Descriptor, [u8; SHA256_BLOCK_SIZE], bool)> {
-        let mut compressed_data = Cursor::new(Vec::<u8>::new());
-        let mut compressed = C::compress(&mut compressed_data)?;
+        let blob = Self::prepare_blob::<C>(buf, media_type)?;
+        self.write_blob(blob, image_manifest)
+    }
+
+    /// Compresses and hashes a blob without touching the image, so it can run on any thread.
+    /// The result is stored with [`Image::write_blob`].
+    pub fn prepare_blob<C: Compression + Any>(
+        buf: &[u8],
+        media_type: impl PuzzleFSMediaType,
+    ) -> Result<PreparedBlob> {
+        Self::prepare_blob_with_level::<C>(buf, media_type, None)
+    }
+
+    /// Like [`Image::prepare_blob`], with an explicit compression level instead of the default
+    /// one of the compression algorithm.
+    pub fn prepare_blob_with_level<C: Compression + Any>(
+        buf: &[u8],
+        media_type: impl PuzzleFSMediaType,
+        level: Option<i32>,
+    ) -> Result<PreparedBlob> {
+        Self::prepare_blob_with_encryption::<C>(buf, media_type, level, None)
+    }
+
+    /// Like [`Image::prepare_blob_with_level`], encrypting the blob after compressing it.
+    pub fn prepare_blob_with_encryption<C: Compression + Any>(
+        buf: &[u8],
+        media_type: impl PuzzleFSMediaType,
+        level: Option<i32>,
+        encryption: Option<&Encryption>,
+    ) -> Result<PreparedBlob> {
+        Self::prepare_blob_with_dictionary::<C>(buf, media_type, level, None, encryption)
+    }
+
+    /// Like [`Image::prepare_blob_with_encryption`], compressing the blob with `dictionary`, which
+    /// only zstd supports. Readers need the dictionary to be among the ones loaded with
+    /// [`Image::load_dictionaries`].
+    pub fn prepare_blob_with_dictionary<C: Compression + Any>(
+        buf: &[u8],
+        media_type: impl PuzzleFSMediaType,
+        level: Option<i32>,
+        dictionary: Option<&ZstdDictionary>,
+        encryption: Option<&Encryption>,
+    ) -> Result<PreparedBlob> {
         let mut hasher = Sha256::new();
-        // generics may not be the best way to implement compression, alternatives:
-        // trait objects, but they add runtime overhead
-        // an enum together with enum_dispatch
-        let mut compressed_blob = std::any::TypeId::of::<C>() != std::any::TypeId::of::<Noop>();
-
-        // without the clone, the io::copy leaves us with an empty slice
-        // we're only cloning the reference, which is ok because the slice itself gets mutated
-        // i.e. the slice advances through the buffer as it is being read
-        let uncompressed_size = io::copy(&mut <&[u8]>::clone(&buf), &mut compressed)?;
-        compressed.end()?;
-        let compressed_size = compressed_data.get_ref().len() as u64;
-        let final_size = std::cmp::min(compressed_size, uncompressed_size);
-
-        // store the uncompressed blob if the compressed version has bigger size
-        let final_data = if compressed_blob && compressed_size >= uncompressed_size {
+        let mut compressed_blob = C::ALGORITHM.is_some();
+
+        // already compressed content (jpeg, gz, zstd, ...) doesn't get any smaller, don't waste
+        // time on it now and on every read
+        if compressed_blob && looks_incompressible(buf) {
             compressed_blob = false;
-            buf
+        }
+
+        let final_data = if compressed_blob {
+            let mut compressed_data = Cursor::new(Vec::<u8>::new());
+            let mut compressed = match (dictionary, level) {
+                (Some(dictionary), level) => {
+                    C::compress_with_dictionary(&mut compressed_data, level, dictionary)?
+                }
+                (None, Some(level)) => C::compress_with_level(&mut compressed_data, level)?,
+                (None, None) => C::compress(&mut compressed_data)?,
+            };
+            // without the clone, the io::copy leaves us with an empty slice
+            // we're only cloning the reference, which is ok because the slice itself gets mutated
+            // i.e. the slice advances through the buffer as it is being read
+            let uncompressed_size = io::copy(&mut <&[u8]>::clone(&buf), &mut compressed)?;
+            compressed.end()?;
+
+            // store the uncompressed blob if the compressed version has bigger size
+            if compressed_data.get_ref().len() as u64 >= uncompressed_size {
+                compressed_blob = false;
+                buf.to_vec()
+            } else {
+                compressed_data.into_inner()
+            }
         } else {
-            compressed_data.get_ref()
+            buf.to_vec()
         };
+        let final_data = match encryption {
+            Some(encryption) => encryption.key.encrypt(encryption.cipher, &final_data)?,
+            None => final_data,
+        };
+        let final_size = final_data.len() as u64;
+        let fs_verity_digest = get_fs_verity_digest(&final_data)?;
 
-        hasher.update(final_data);
+        hasher.update(&final_data);
         let digest = hasher.finalize();
         let media_type_with_extension = C::append_extension(media_type.name());
         let mut digest_string = "sha256:".to_string();
         digest_string.push_str(&hex::encode(digest.as_slice()));
 
-        let fs_verity_digest = get_fs_verity_digest(&compressed_data.get_ref()[..])?;
         let mut descriptor = Descriptor::new(
             MediaType::Other(media_type_with_extension),
             final_size,
             image::Digest::from_str(&digest_string)?,
         );
+        let is_rootfs = is_rootfs_name(media_type.name());
         // We need to store the PuzzleFS Rootfs verity digest as an annotation (obviously we cannot
         // store it in the Rootfs itself)
-        if media_type.name() == PUZZLEFS_ROOTFS {
+        if is_rootfs {
             let mut annotations = HashMap::new();
             annotations.insert(
                 VERITY_ROOT_HASH_ANNOTATION.to_string(),
@@ -103,159 +384,609 @@ impl Image {
             );
             descriptor.set_annotations(Some(annotations));
         }
-        let path = Self::blob_path().join(descriptor.digest().digest());
+
+        Ok(PreparedBlob {
+            descriptor,
+            data: final_data,
+            digest: digest.into(),
+            fs_verity_digest,
+            compressed: compressed_blob,
+            is_rootfs,
+        })
+    }
+
+    pub fn has_blob(&self, descriptor: &Descriptor) -> bool {
+        self.2
+            .has_blob(descriptor.digest().digest())
+            .unwrap_or(false)
+    }
+
+    /// Writes a blob produced by [`Image::prepare_blob`] and adds it to the image manifest.
+    pub fn write_blob(
+        &self,
+        blob: PreparedBlob,
+        image_manifest: &mut ImageManifest,
+    ) -> Result<(Descriptor, [u8; SHA256_BLOCK_SIZE], bool)> {
+        let descriptor = blob.descriptor;
 
         // avoid replacing the data blob so we don't drop fsverity data
-        if self.0.dir().exists(&path) {
+        if self.has_blob(&descriptor) {
             let mut hasher = Sha256::new();
-            let mut file = self.0.dir().open(&path)?;
+            let mut file = self.2.open_blob(descriptor.digest().digest())?;
             io::copy(&mut file, &mut hasher)?;
             let existing_digest = hasher.finalize();
-            if existing_digest != digest {
+            if existing_digest[..] != blob.digest[..] {
                 return Err(Error::new(
                     ErrorKind::AlreadyExists,
                     format!("blob already exists and it's not content addressable existing digest {}, new digest {}",
-                    hex::encode(existing_digest), hex::encode(digest))
+                    hex::encode(existing_digest), hex::encode(blob.digest))
                 )
                 .into());
             }
         } else {
-            self.0.dir().write(&path, final_data)?;
+            self.2.write_blob(&descriptor, &mut &blob.data[..])?;
         }
 
         // Let's make the PuzzleFS image rootfs the first layer so it's easy to find
         // The LXC oci template also looks at the first layer in the array to identify the image
         // type (see getlayermediatype):
         // https://github.com/lxc/lxc/commit/1a2da75b6e8431f3530ebd3f75442d3bd5eec5e2
-        if media_type.name() == PUZZLEFS_ROOTFS {
+        if blob.is_rootfs {
             image_manifest.layers_mut().insert(0, descriptor.clone());
         } else {
             image_manifest.layers_mut().push(descriptor.clone());
         }
-        Ok((descriptor, fs_verity_digest, compressed_blob))
+        Ok((descriptor, blob.fs_verity_digest, blob.compressed))
     }
 
-    fn open_raw_blob(&self, digest: &str, verity: Option<&[u8]>) -> io::Result<cap_std::fs::File> {
-        let file = self.0.blobs_dir().open(digest)?;
-        if let Some(verity) = verity {
-            check_fs_verity(&file, verity).map_err(io::Error::other)?;
+    /// Writes the manifest and tags it in the index, replacing any manifest previously tagged
+    /// with `tag`. Updates of the index are serialized by [`Image::lock_layout`], so builds
+    /// writing different tags into the same layout don't lose each other's manifests.
+    ///
+    /// If a platform was given with [`Image::with_platform`], `tag` refers to an image index
+    /// instead, where the manifest replaces the one for the same platform only.
+    pub fn insert_manifest(&self, manifest: ImageManifest, tag: &str) -> Result<Descriptor> {
+        let _lock = self.lock_layout()?;
+        let Some(platform) = &self.4 else {
+            let descriptor = self
+                .0
+                .insert_manifest(manifest, Some(tag), Platform::default())?;
+            self.update_blob_refs(&self.0.read_index()?);
+            return Ok(descriptor);
+        };
+
+        let mut descriptor = self
+            .0
+            .write_json_blob(&manifest, MediaType::ImageManifest)?
+            .build()?;
+        descriptor.set_platform(Some(platform.clone()));
+        let mut platforms = match self.0.find_manifest_descriptor_with_tag(tag)? {
+            Some(tagged) if tagged.media_type() == &MediaType::ImageIndex => {
+                let index: ImageIndex = self.0.read_json_blob(&tagged)?;
+                index.manifests().clone()
+            }
+            _ => Vec::new(),
+        };
+        platforms.retain(|d| d.platform().as_ref() != Some(platform));
+        platforms.push(descriptor.clone());
+        let platforms = ImageIndexBuilder::default()
+            .schema_version(SCHEMA_VERSION)
+            .media_type(MediaType::ImageIndex)
+            .manifests(platforms)
+            .build()?;
+        let mut tagged = self
+            .0
+            .write_json_blob(&platforms, MediaType::ImageIndex)?
+            .build()?;
+        tagged.set_annotations(Some(HashMap::from([(
+            ANNOTATION_REF_NAME.to_string(),
+            tag.to_string(),
+        )])));
+
+        let mut index = self.read_index_or_empty()?;
+        let mut manifests = index
+            .manifests()
+            .iter()
+            .filter(|d| {
+                d.annotations()
+                    .as_ref()
+                    .and_then(|a| a.get(ANNOTATION_REF_NAME))
+                    .map(String::as_str)
+                    != Some(tag)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        manifests.push(tagged);
+        index.set_manifests(self.drop_dangling_referrers(manifests)?);
+        self.write_index(&index)?;
+        Ok(descriptor)
+    }
+
+    /// The descriptor of the manifest tagged `tag`. Tags referring to an image index resolve to
+    /// the manifest for [`Image::platform`].
+    pub fn find_manifest_descriptor(&self, tag: &str) -> Result<Descriptor> {
+        let tagged = self
+            .0
+            .find_manifest_descriptor_with_tag(tag)?
+            .ok_or_else(|| {
+                WireFormatError::MissingManifest(tag.to_string(), Backtrace::capture())
+            })?;
+        if tagged.media_type() != &MediaType::ImageIndex {
+            return Ok(tagged);
         }
-        Ok(file)
+
+        let index: ImageIndex =
+            serde_json::from_reader(self.open_raw_blob(tagged.digest().digest(), None)?)?;
+        let platform = self.platform();
+        select_platform(&index, &platform).cloned().ok_or_else(|| {
+            WireFormatError::MissingManifest(
+                format!("{tag} for {}/{}", platform.os(), platform.architecture()),
+                Backtrace::capture(),
+            )
+        })
+    }
+
+    /// The manifest tagged `tag`, see [`Image::find_manifest_descriptor`].
+    pub fn find_manifest(&self, tag: &str) -> Result<ImageManifest> {
+        let descriptor = self.find_manifest_descriptor(tag)?;
+        Ok(serde_json::from_reader(
+            self.open_raw_blob(descriptor.digest().digest(), None)?,
+        )?)
+    }
+
+    // Takes an exclusive flock on the layout directory, released when the returned handle is
+    // dropped. The directory is reopened so that every call gets its own open file description,
+    // which makes the lock work between Image instances in the same process as well as between
+    // processes, and it keeps lock files out of the layout. Locks held by dead processes are
+    // released by the kernel, so waiting only times out while another process is busy with the
+    // layout, e.g. collecting its garbage.
+    fn lock_layout(&self) -> Result<cap_std::fs::Dir> {
+        let dir = self.0.dir().open_dir(".")?;
+        let start = Instant::now();
+        loop {
+            match flock(dir.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+                Ok(()) => return Ok(dir),
+                Err(Errno::EWOULDBLOCK) if start.elapsed() < self.5 => {
+                    thread::sleep(LOCK_RETRY_INTERVAL)
+                }
+                Err(Errno::EWOULDBLOCK) => {
+                    return Err(WireFormatError::LockTimeout(self.5, Backtrace::capture()))
+                }
+                Err(Errno::EINTR) => {}
+                Err(e) => return Err(io::Error::from(e).into()),
+            }
+        }
+    }
+
+    // The index, or an empty one for layouts without any.
+    fn read_index_or_empty(&self) -> Result<ImageIndex> {
+        match self.0.read_index() {
+            Ok(index) => Ok(index),
+            Err(ocidir::Error::MissingImageIndex) => Ok(ImageIndexBuilder::default()
+                .schema_version(SCHEMA_VERSION)
+                .manifests(Vec::new())
+                .build()?),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Replaces the index with a new one renamed into place, so readers see either the old or the
+    // new index, and updates the blob reference map. The layout has to be locked.
+    fn write_index(&self, index: &ImageIndex) -> Result<()> {
+        let dir = self.0.dir();
+        let mut temp = dir.create(INDEX_TEMP)?;
+        temp.write_all(&serde_json::to_vec(index)?)?;
+        // or a crash could leave an empty index behind the rename
+        temp.sync_all()?;
+        dir.rename(INDEX_TEMP, dir, INDEX)?;
+        self.update_blob_refs(index);
+        Ok(())
+    }
+
+    pub(crate) fn open_raw_blob(
+        &self,
+        digest: &str,
+        verity: Option<&[u8]>,
+    ) -> Result<cap_std::fs::File> {
+        open_blob(&*self.2, digest, verity)
     }
 
     pub fn open_compressed_blob<C: Compression>(
         &self,
         digest: &Digest,
         verity: Option<&[u8]>,
-    ) -> io::Result<Box<dyn Decompressor>> {
+    ) -> Result<Box<dyn Decompressor>> {
         let f = self.open_raw_blob(&digest.to_string(), verity)?;
-        C::decompress(f)
+        Ok(C::decompress(f)?)
     }
 
-    pub fn get_pfs_rootfs_verity(&self, tag: &str) -> Result<[u8; SHA256_BLOCK_SIZE]> {
-        let manifest = self.0.find_manifest_with_tag(tag)?.ok_or_else(|| {
-            WireFormatError::MissingManifest(tag.to_string(), Backtrace::capture())
-        })?;
-
-        let rootfs_desc = manifest
+    /// Loads the zstd dictionaries the chunks of `tag` may be compressed with, see
+    /// [`crate::builder::BuildOptions::compression_dictionary`], so that they can be read. The
+    /// manifest of a delta lists the dictionaries of its base too.
+    pub fn load_dictionaries(&self, tag: &str) -> Result<()> {
+        let manifest = self.find_manifest(tag)?;
+        let descriptors = manifest
             .layers()
             .iter()
-            .find(|desc| desc.media_type() == &MediaType::Other(PUZZLEFS_ROOTFS.to_string()))
-            .ok_or_else(|| WireFormatError::MissingRootfs(Backtrace::capture()))?;
-
-        let rootfs_verity = rootfs_desc
-            .annotations()
-            .as_ref()
-            .ok_or_else(|| {
-                WireFormatError::InvalidFsVerityData(
-                    "missing rootfs annotations".to_string(),
-                    Backtrace::capture(),
-                )
-            })?
-            .get(VERITY_ROOT_HASH_ANNOTATION)
-            .ok_or_else(|| {
-                WireFormatError::InvalidFsVerityData(
-                    "missing rootfs verity annotation".to_string(),
-                    Backtrace::capture(),
-                )
-            })?;
-        let mut verity_digest: [u8; SHA256_BLOCK_SIZE] = [0; SHA256_BLOCK_SIZE];
-        hex::decode_to_slice(rootfs_verity, &mut verity_digest)?;
+            .filter(|desc| is_zstd_dictionary(desc.media_type()));
+        for descriptor in descriptors {
+            let dictionary =
+                self.read_dictionary(&Digest::try_from(descriptor.digest().digest())?)?;
+            let mut dictionaries = self.9.lock().unwrap();
+            match dictionaries.get(&dictionary.id()) {
+                Some(loaded) if loaded.as_bytes() != dictionary.as_bytes() => {
+                    return Err(WireFormatError::DictionaryError(
+                        format!("two different dictionaries have id {}", dictionary.id()),
+                        Backtrace::capture(),
+                    ))
+                }
+                _ => dictionaries.insert(dictionary.id(), dictionary),
+            };
+        }
+        Ok(())
+    }
 
-        Ok(verity_digest)
+    // Reads the dictionary stored in the blob `digest`, checking it against the digest.
+    pub(crate) fn read_dictionary(&self, digest: &Digest) -> Result<ZstdDictionary> {
+        let mut data = Vec::new();
+        self.open_raw_blob(&digest.to_string(), None)?
+            .read_to_end(&mut data)?;
+        let found = Digest::new(&Sha256::digest(&data).into());
+        if &found != digest {
+            return Err(WireFormatError::DigestMismatch {
+                expected: digest.clone(),
+                found,
+                backtrace: Backtrace::capture(),
+            });
+        }
+        Ok(ZstdDictionary::new(data)?)
     }
 
-    pub fn get_pfs_rootfs(&self, tag: &str, verity: Option<&[u8]>) -> Result<cap_std::fs::File> {
-        let manifest = self.0.find_manifest_with_tag(tag)?.ok_or_else(|| {
-            WireFormatError::MissingManifest(tag.to_string(), Backtrace::capture())
+    // Decompresses a zstd blob, with the dictionary it was compressed with if any.
+    fn decompress_zstd<R: Read + Seek + 'static>(
+        &self,
+        mut source: R,
+        digest: &Digest,
+    ) -> Result<Box<dyn Decompressor>> {
+        let Some(id) = Zstd::dictionary_id(&mut source)? else {
+            return Ok(Zstd::decompress(source)?);
+        };
+        let dictionary = self.9.lock().unwrap().get(&id).cloned().ok_or_else(|| {
+            WireFormatError::DictionaryError(
+                format!("blob {digest} is compressed with dictionary {id}, which isn't loaded"),
+                Backtrace::capture(),
+            )
         })?;
+        Ok(Zstd::decompress_with_dictionary(source, Some(&dictionary))?)
+    }
 
-        let rootfs_desc = manifest
-            .layers()
-            .iter()
-            .find(|desc| desc.media_type() == &MediaType::Other(PUZZLEFS_ROOTFS.to_string()))
-            .ok_or_else(|| WireFormatError::MissingRootfs(Backtrace::capture()))?;
+    pub fn get_pfs_rootfs_verity(&self, tag: &str) -> Result<[u8; SHA256_BLOCK_SIZE]> {
+        let manifest = self.find_manifest(tag)?;
+        rootfs_verity(rootfs_descriptor(&manifest)?)
+    }
+
+    /// The annotations of the manifest of `tag`, e.g. the ones given with
+    /// [`crate::builder::BuildOptions::annotations`], without the ones puzzlefs uses itself.
+    pub fn annotations(&self, tag: &str) -> Result<BTreeMap<String, String>> {
+        let manifest = self.find_manifest(tag)?;
+        Ok(custom_annotations(manifest.annotations()))
+    }
+
+    /// The annotations of the rootfs blob of `tag`, like [`Image::annotations`].
+    pub fn rootfs_annotations(&self, tag: &str) -> Result<BTreeMap<String, String>> {
+        let manifest = self.find_manifest(tag)?;
+        Ok(custom_annotations(
+            rootfs_descriptor(&manifest)?.annotations(),
+        ))
+    }
 
-        let rootfs_digest = rootfs_desc.digest().digest();
+    /// The runtime configuration of `tag` (entrypoint, environment, labels...), see
+    /// [`crate::builder::BuildOptions::config`]. `None` if the image was built without one.
+    pub fn config(&self, tag: &str) -> Result<Option<ImageConfiguration>> {
+        let manifest = self.find_manifest(tag)?;
+        let config = manifest.config();
+        if config.media_type() != &MediaType::ImageConfig {
+            return Ok(None);
+        }
+        let file = self.open_raw_blob(config.digest().digest(), None)?;
+        Ok(Some(serde_json::from_reader(file)?))
+    }
+
+    pub fn get_pfs_rootfs(&self, tag: &str, verity: Option<&[u8]>) -> Result<cap_std::fs::File> {
+        let manifest = self.find_manifest(tag)?;
+        let rootfs_digest = rootfs_descriptor(&manifest)?.digest().digest();
         let file = self.open_raw_blob(rootfs_digest, verity)?;
         Ok(file)
     }
 
+    /// The rootfs blobs in the manifest of `tag`: the image's own comes first, followed by those
+    /// of the images it is a delta on, if any.
+    pub fn get_pfs_rootfs_descriptors(&self, tag: &str) -> Result<Vec<Descriptor>> {
+        let manifest = self.find_manifest(tag)?;
+
+        Ok(manifest
+            .layers()
+            .iter()
+            .filter(|desc| is_rootfs(desc.media_type()))
+            .cloned()
+            .collect())
+    }
+
     pub fn get_image_manifest_fd(&self, tag: &str) -> Result<cap_std::fs::File> {
-        let image_manifest = self
-            .0
-            .find_manifest_descriptor_with_tag(tag)?
-            .ok_or_else(|| {
-                WireFormatError::MissingManifest(tag.to_string(), Backtrace::capture())
-            })?;
+        let image_manifest = self.find_manifest_descriptor(tag)?;
         let file = self.open_raw_blob(image_manifest.digest().digest(), None)?;
         Ok(file)
     }
 
+    /// Opens the rootfs of `tag`. With `verity`, the manifest is checked against it and the
+    /// rootfs against the digest the manifest has for it. The manifest is read once, so the
+    /// checks (including the one of the signature, see [`Image::check_signature`]) and the rootfs
+    /// opened are all about the same manifest, even if the tag is moved meanwhile.
     pub fn open_rootfs_blob(&self, tag: &str, verity: Option<&[u8]>) -> Result<RootfsReader> {
-        let temp_verity;
-        let rootfs_verity = if let Some(verity) = verity {
-            let manifest = self.get_image_manifest_fd(tag)?;
-            check_fs_verity(&manifest, verity)?;
-            temp_verity = self.get_pfs_rootfs_verity(tag)?;
-            Some(&temp_verity[..])
-        } else {
-            None
+        let mut manifest_file = self.get_image_manifest_fd(tag)?;
+        if let Some(verity) = verity {
+            check_fs_verity(&manifest_file, verity)?;
+        }
+        let mut manifest_bytes = Vec::new();
+        manifest_file.read_to_end(&mut manifest_bytes)?;
+        let manifest: ImageManifest = serde_json::from_slice(&manifest_bytes)?;
+
+        let rootfs_desc = rootfs_descriptor(&manifest)?;
+        let rootfs_verity = match verity {
+            Some(_) => Some(rootfs_verity(rootfs_desc)?),
+            None => None,
         };
+        let mut rootfs_file = self.open_raw_blob(
+            rootfs_desc.digest().digest(),
+            rootfs_verity.as_ref().map(|v| &v[..]),
+        )?;
+        self.check_manifest_signature(tag, &manifest_bytes, rootfs_desc, &mut rootfs_file)?;
+        Ok(self.read_rootfs(rootfs_file, rootfs_verity.is_some())?.0)
+    }
 
-        let rootfs_file = self.get_pfs_rootfs(tag, rootfs_verity)?;
-        RootfsReader::open(rootfs_file)
+    /// Opens the rootfs blob with the given digest, e.g. the parent of a delta image.
+    pub fn open_rootfs_blob_by_digest(
+        &self,
+        digest: &[u8; SHA256_BLOCK_SIZE],
+        verity: Option<&[u8]>,
+    ) -> Result<RootfsReader> {
+        let rootfs_file = self.open_raw_blob(&hex::encode(digest), verity)?;
+        Ok(self.read_rootfs(rootfs_file, verity.is_some())?.0)
     }
 
+    /// Reads the data of `chunk` from `addl_offset` on. Chunks with a checksum are read whole
+    /// to check it, even when only part of them is wanted, and kept for the reads of their other
+    /// parts.
     pub fn fill_from_chunk(
         &self,
-        chunk: crate::format::BlobRef,
+        chunk: &FileChunk,
         addl_offset: u64,
         buf: &mut [u8],
-        verity_data: &Option<VerityData>,
+        verity: Option<&[u8]>,
+        cancel: Option<&CancellationToken>,
     ) -> crate::format::Result<usize> {
+        let Some(blob_ref) = &chunk.blob else {
+            let n = min(buf.len() as u64, chunk.len.saturating_sub(addl_offset)) as usize;
+            match &chunk.inline {
+                Some(data) => {
+                    let start = addl_offset as usize;
+                    buf[..n].copy_from_slice(&data[start..start + n]);
+                }
+                // holes aren't stored anywhere, they just read as zeros
+                None => buf[..n].fill(0),
+            }
+            return Ok(n);
+        };
+        let Some(cancel) = cancel else {
+            return self.read_chunk(chunk, blob_ref, addl_offset, buf, verity, None);
+        };
+        cancel.check()?;
+        // a read which failed, e.g. because its blob source timed out, failed because of the
+        // deadline if it passed meanwhile
+        self.read_chunk(chunk, blob_ref, addl_offset, buf, verity, Some(cancel))
+            .or_else(|e| cancel.check().and(Err(e)))
+    }
+
+    fn read_chunk(
+        &self,
+        chunk: &FileChunk,
+        blob_ref: &BlobRef,
+        addl_offset: u64,
+        buf: &mut [u8],
+        verity: Option<&[u8]>,
+        cancel: Option<&CancellationToken>,
+    ) -> crate::format::Result<usize> {
+        if chunk.checksum.is_none() {
+            let offset = blob_ref.offset + addl_offset;
+            let mut blob = self.open_chunk_blob(blob_ref, offset, verity, cancel)?;
+            // the token's reader hands out the data in pieces
+            let len = min(buf.len() as u64, chunk.len.saturating_sub(addl_offset)) as usize;
+            let mut n = 0;
+            while n < len {
+                match blob.read(&mut buf[n..len])? {
+                    0 => break,
+                    read => n += read,
+                }
+            }
+            return Ok(n);
+        }
+
+        let key = (*blob_ref, chunk.len, chunk.checksum);
+        let data = self.8.get_or_load(key, verity, || {
+            let mut blob = self.open_chunk_blob(blob_ref, blob_ref.offset, verity, cancel)?;
+            let mut data = vec![0; chunk.len as usize];
+            blob.read_exact(&mut data)?;
+            chunk.verify(&data)?;
+            Ok(data)
+        })?;
+        let data = data.get(addl_offset as usize..).unwrap_or_default();
+        let n = min(buf.len(), data.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        Ok(n)
+    }
+
+    // Opens the blob of a chunk at `offset`, to be read through `cancel`, if given.
+    fn open_chunk_blob<'a>(
+        &self,
+        blob_ref: &BlobRef,
+        offset: u64,
+        verity: Option<&[u8]>,
+        cancel: Option<&'a CancellationToken>,
+    ) -> crate::format::Result<Box<dyn Read + 'a>> {
+        let mut blob = self.open_blob_ref(blob_ref, verity)?;
+        // opening (and verifying) the blob may have taken a while
+        if let Some(cancel) = cancel {
+            cancel.check()?;
+        }
+        blob.seek(io::SeekFrom::Start(offset))?;
+        Ok(match cancel {
+            Some(cancel) => Box::new(cancel.reader(blob)),
+            None => Box::new(blob),
+        })
+    }
+
+    // Opens the blob `chunk` refers to, decrypting and decompressing it as needed.
+    fn open_blob_ref(
+        &self,
+        chunk: &BlobRef,
+        verity: Option<&[u8]>,
+    ) -> Result<Box<dyn Decompressor>> {
         let digest = &<Digest>::try_from(chunk)?;
-        let file_verity;
-        if let Some(verity) = verity_data {
-            file_verity = Some(
-                &verity
-                    .get(&digest.underlying())
-                    .ok_or(WireFormatError::InvalidFsVerityData(
-                        format!("missing verity data {digest}"),
+        Ok(match chunk.encryption {
+            None => match (chunk.compressed, chunk.algorithm) {
+                (false, _) => self.open_compressed_blob::<Noop>(digest, verity)?,
+                (true, CompressionAlgorithm::Zstd) => {
+                    let blob = self.open_raw_blob(&digest.to_string(), verity)?;
+                    self.decompress_zstd(blob, digest)?
+                }
+                (true, CompressionAlgorithm::Lz4) => {
+                    self.open_compressed_blob::<Lz4>(digest, verity)?
+                }
+                (true, CompressionAlgorithm::Xz) => {
+                    self.open_compressed_blob::<Xz>(digest, verity)?
+                }
+            },
+            Some(cipher) => self.open_encrypted_blob(chunk, cipher, verity)?,
+        })
+    }
+
+    // The authentication tag covers the whole blob, so it is read and decrypted in one go before
+    // it's decompressed; the plaintext is kept for the reads of the other chunks packed into it.
+    fn open_encrypted_blob(
+        &self,
+        chunk: &BlobRef,
+        cipher: Cipher,
+        verity: Option<&[u8]>,
+    ) -> Result<Box<dyn Decompressor>> {
+        let digest = Digest::new(&chunk.digest);
+        let key = self.1.as_ref().ok_or_else(|| {
+            WireFormatError::MissingKey(
+                format!("blob {digest} is encrypted, but no key was given"),
+                Backtrace::capture(),
+            )
+        })?;
+
+        let data = self.7.get_or_load(chunk.digest, verity, || {
+            let mut data = Vec::new();
+            self.open_raw_blob(&digest.to_string(), verity)?
+                .read_to_end(&mut data)?;
+            // the authentication fails the same way for a wrong key and a corrupted blob, the
+            // blob's digest tells them apart
+            key.decrypt(cipher, &data).map_err(|_| {
+                if Sha256::digest(&data)[..] != chunk.digest {
+                    WireFormatError::CorruptedChunk(
+                        format!("encrypted blob {digest} doesn't match its digest"),
                         Backtrace::capture(),
-                    ))?[..],
-            );
-        } else {
-            file_verity = None;
+                    )
+                } else {
+                    WireFormatError::WrongKey(
+                        format!("blob {digest} doesn't decrypt with key {}", key.id()),
+                        Backtrace::capture(),
+                    )
+                }
+            })
+        })?;
+        let data = Cursor::new(data);
+        Ok(match (chunk.compressed, chunk.algorithm) {
+            (false, _) => Noop::decompress(data)?,
+            (true, CompressionAlgorithm::Zstd) => self.decompress_zstd(data, &digest)?,
+            (true, CompressionAlgorithm::Lz4) => Lz4::decompress(data)?,
+            (true, CompressionAlgorithm::Xz) => Xz::decompress(data)?,
+        })
+    }
+
+    /// Checks that the key given with [`Image::with_key`] is the one the chunks of `tag` were
+    /// encrypted with, so a wrong key fails when opening the image rather than on every read.
+    pub fn check_key(&self, tag: &str) -> Result<()> {
+        let Some(key) = &self.1 else {
+            return Ok(());
+        };
+        match self.key_id(tag)? {
+            Some(key_id) if key_id != key.id() => Err(WireFormatError::WrongKey(
+                format!("{tag} is encrypted with key {key_id}, not {}", key.id()),
+                Backtrace::capture(),
+            )),
+            _ => Ok(()),
         }
-        let mut blob = if chunk.compressed {
-            self.open_compressed_blob::<Zstd>(digest, file_verity)?
-        } else {
-            self.open_compressed_blob::<Noop>(digest, file_verity)?
+    }
+
+    /// The id (see [`EncryptionKey::id`]) of the key the chunks of `tag` are encrypted with,
+    /// `None` if they aren't.
+    pub fn key_id(&self, tag: &str) -> Result<Option<String>> {
+        let manifest = self.find_manifest(tag)?;
+        let reference = match manifest
+            .annotations()
+            .as_ref()
+            .and_then(|annotations| annotations.get(BUILD_OPTIONS_ANNOTATION))
+        {
+            Some(build_options) => key_reference(build_options)?,
+            None => None,
         };
-        blob.seek(io::SeekFrom::Start(chunk.offset + addl_offset))?;
-        let n = blob.read(buf)?;
-        Ok(n)
+        Ok(reference.map(|reference| reference.key_id))
+    }
+
+    /// Checks that the manifest tagged `tag` is signed as required by the policy given with
+    /// [`Image::with_signature_policy`], and that the rootfs is the one the manifest names. The
+    /// chunks are only checked when they are read with fs-verity, i.e. the signature doesn't
+    /// replace the manifest digest passed to [`crate::reader::PuzzleFS::open`].
+    pub fn check_signature(&self, tag: &str) -> Result<()> {
+        if self.3.is_none() {
+            return Ok(());
+        }
+        let mut manifest_bytes = Vec::new();
+        self.get_image_manifest_fd(tag)?
+            .read_to_end(&mut manifest_bytes)?;
+        let manifest: ImageManifest = serde_json::from_slice(&manifest_bytes)?;
+        let rootfs_desc = rootfs_descriptor(&manifest)?;
+        let mut rootfs_file = self.open_raw_blob(rootfs_desc.digest().digest(), None)?;
+        self.check_manifest_signature(tag, &manifest_bytes, rootfs_desc, &mut rootfs_file)
+    }
+
+    // Checks the signature of `manifest_bytes`, the manifest of `tag`, and that `rootfs_file` is
+    // the rootfs it names, `rootfs_desc`. The file is rewound after reading it.
+    fn check_manifest_signature(
+        &self,
+        tag: &str,
+        manifest_bytes: &[u8],
+        rootfs_desc: &Descriptor,
+        rootfs_file: &mut cap_std::fs::File,
+    ) -> Result<()> {
+        let Some(policy) = &self.3 else {
+            return Ok(());
+        };
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(manifest_bytes)));
+        policy.verify(&digest, &self.signatures(tag)?)?;
+
+        let mut hasher = Sha256::new();
+        io::copy(rootfs_file, &mut hasher)?;
+        rootfs_file.rewind()?;
+        if hex::encode(hasher.finalize()) != rootfs_desc.digest().digest() {
+            return Err(WireFormatError::SignatureError(
+                format!("the rootfs of {tag} doesn't match its signed manifest"),
+                Backtrace::capture(),
+            ));
+        }
+        Ok(())
     }
 
     pub fn get_index(&self) -> Result<ImageIndex> {
@@ -267,10 +998,85 @@ impl Image {
     }
 }
 
+// The descriptor of the rootfs blob of `manifest`, the image's own if it's a delta.
+fn rootfs_descriptor(manifest: &ImageManifest) -> Result<&Descriptor> {
+    manifest
+        .layers()
+        .iter()
+        .find(|desc| is_rootfs(desc.media_type()))
+        .ok_or_else(|| WireFormatError::MissingRootfs(Backtrace::capture()))
+}
+
+// The fs-verity digest of the rootfs blob described by `rootfs_desc`, from its annotation.
+fn rootfs_verity(rootfs_desc: &Descriptor) -> Result<[u8; SHA256_BLOCK_SIZE]> {
+    let rootfs_verity = rootfs_desc
+        .annotations()
+        .as_ref()
+        .ok_or_else(|| {
+            WireFormatError::InvalidFsVerityData(
+                "missing rootfs annotations".to_string(),
+                Backtrace::capture(),
+            )
+        })?
+        .get(VERITY_ROOT_HASH_ANNOTATION)
+        .ok_or_else(|| {
+            WireFormatError::InvalidFsVerityData(
+                "missing rootfs verity annotation".to_string(),
+                Backtrace::capture(),
+            )
+        })?;
+    let mut verity_digest: [u8; SHA256_BLOCK_SIZE] = [0; SHA256_BLOCK_SIZE];
+    hex::decode_to_slice(rootfs_verity, &mut verity_digest)?;
+
+    Ok(verity_digest)
+}
+
+// The annotations of a manifest or descriptor, but the ones puzzlefs sets itself.
+fn custom_annotations(annotations: &Option<HashMap<String, String>>) -> BTreeMap<String, String> {
+    annotations
+        .iter()
+        .flatten()
+        .filter(|(key, _)| !key.starts_with(PUZZLEFS_ANNOTATION_PREFIX))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+/// Parses a platform like `linux/arm64` or `linux/arm/v7`.
+pub fn parse_platform(platform: &str) -> Result<Platform> {
+    let mut parts = platform.split('/');
+    let (Some(os), Some(arch)) = (parts.next(), parts.next()) else {
+        return Err(WireFormatError::InvalidPlatform(
+            format!("{platform}, expected os/arch[/variant]"),
+            Backtrace::capture(),
+        ));
+    };
+    let mut builder = PlatformBuilder::default()
+        .os(Os::from(os))
+        .architecture(Arch::from(arch));
+    if let Some(variant) = parts.next() {
+        builder = builder.variant(variant.to_string());
+    }
+    Ok(builder.build()?)
+}
+
+// The manifest in a multi-platform `index` for `platform`. Platforms without a variant take any.
+pub(crate) fn select_platform<'a>(
+    index: &'a ImageIndex,
+    platform: &Platform,
+) -> Option<&'a Descriptor> {
+    index.manifests().iter().find(|d| {
+        d.platform().as_ref().is_some_and(|p| {
+            p.os() == platform.os()
+                && p.architecture() == platform.architecture()
+                && (platform.variant().is_none() || p.variant() == platform.variant())
+        })
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ocidir::oci_spec::image::{ImageIndexBuilder, Platform, ANNOTATION_REF_NAME};
+    use crate::builder::tests::random_data;
     use std::collections::HashMap;
     use tempfile::tempdir;
     type DefaultCompression = Zstd;
@@ -297,6 +1103,22 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_blob_missing() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let digest = Digest::new(&[0x42; SHA256_BLOCK_SIZE]);
+        match image.open_raw_blob(&digest.to_string(), None) {
+            Err(e @ WireFormatError::BlobMissing { .. }) => {
+                assert_eq!(e.to_errno(), Errno::EIO as i32);
+                assert_eq!(e.to_string(), format!("missing blob {digest}"));
+            }
+            Err(e) => panic!("unexpected {e}"),
+            Ok(_) => panic!("opened a missing blob"),
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_open_can_open_new_image() -> anyhow::Result<()> {
         let dir = tempdir()?;
@@ -347,4 +1169,143 @@ mod tests {
         assert_eq!(desc1, desc2);
         Ok(())
     }
+
+    #[test]
+    fn test_incompressible_blob_stored_raw() -> anyhow::Result<()> {
+        let random = random_data(65536, 0);
+
+        let blob = Image::prepare_blob::<Zstd>(&random, media_types::Chunk {})?;
+        assert!(!blob.compressed);
+        assert_eq!(blob.data, random);
+
+        let text = "meshuggah rocks\n".repeat(4096);
+        let blob = Image::prepare_blob::<Zstd>(text.as_bytes(), media_types::Chunk {})?;
+        assert!(blob.compressed);
+        assert!(blob.data.len() < text.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_mixed_blobs() -> anyhow::Result<()> {
+        fn put<C: Compression + Any>(
+            image: &Image,
+            manifest: &mut ImageManifest,
+            data: &[u8],
+        ) -> anyhow::Result<FileChunk> {
+            let (desc, _, compressed) =
+                image.put_blob::<C>(data, manifest, media_types::Chunk {})?;
+            Ok(FileChunk {
+                blob: Some(BlobRef {
+                    digest: Digest::try_from(desc.digest().digest())?.underlying(),
+                    offset: 0,
+                    compressed,
+                    algorithm: C::ALGORITHM.unwrap_or_default(),
+                    encryption: None,
+                }),
+                len: data.len() as u64,
+                checksum: Some(FileChunk::checksum(data)),
+                inline: None,
+            })
+        }
+
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let mut manifest = image.get_empty_manifest()?;
+        let text = |word: &str| format!("{word} rocks\n").repeat(4096).into_bytes();
+        let random = random_data(65536, 0);
+        // one image can hold chunks of every kind, each read as its own blob ref says
+        let chunks = [
+            (
+                put::<Noop>(&image, &mut manifest, &text("noop"))?,
+                text("noop"),
+            ),
+            (
+                put::<Zstd>(&image, &mut manifest, &text("zstd"))?,
+                text("zstd"),
+            ),
+            (
+                put::<Lz4>(&image, &mut manifest, &text("lz4"))?,
+                text("lz4"),
+            ),
+            (put::<Xz>(&image, &mut manifest, &text("xz"))?, text("xz")),
+            (put::<Zstd>(&image, &mut manifest, &random)?, random.clone()),
+        ];
+        let compressed = chunks
+            .iter()
+            .map(|(chunk, _)| chunk.blob.unwrap().compressed)
+            .collect::<Vec<_>>();
+        assert_eq!(compressed, [false, true, true, true, false]);
+
+        for (chunk, data) in &chunks {
+            let mut buf = vec![0; data.len()];
+            assert_eq!(
+                image.fill_from_chunk(chunk, 0, &mut buf, None, None)?,
+                data.len()
+            );
+            assert_eq!(&buf, data);
+            let mut buf = vec![0; 100];
+            assert_eq!(
+                image.fill_from_chunk(chunk, 1000, &mut buf, None, None)?,
+                100
+            );
+            assert_eq!(buf, data[1000..1100]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_platform() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let amd64 = Image::new(dir.path())?.with_platform(parse_platform("linux/amd64")?);
+        crate::builder::build_test_fs(Path::new("src/builder/test/test-1"), &amd64, "test")?;
+        let arm64 = Image::open(dir.path())?.with_platform(parse_platform("linux/arm64/v8")?);
+        let rootfs = tempdir()?;
+        std::fs::write(rootfs.path().join("file"), b"arm")?;
+        crate::builder::build_test_fs(rootfs.path(), &arm64, "test")?;
+
+        let index = amd64.get_index()?;
+        let tagged = index
+            .manifests()
+            .iter()
+            .find(|d| d.media_type() == &MediaType::ImageIndex)
+            .unwrap();
+        let platforms: ImageIndex = amd64.0.read_json_blob(tagged)?;
+        assert_eq!(platforms.manifests().len(), 2);
+
+        let amd64_manifest = amd64.find_manifest_descriptor("test")?;
+        let arm64_manifest = arm64.find_manifest_descriptor("test")?;
+        assert_ne!(amd64_manifest.digest(), arm64_manifest.digest());
+        // the variant only has to match if it's asked for
+        let any_arm64 = Image::open(dir.path())?.with_platform(parse_platform("linux/arm64")?);
+        assert_eq!(
+            any_arm64.find_manifest_descriptor("test")?.digest(),
+            arm64_manifest.digest()
+        );
+        let s390x = Image::open(dir.path())?.with_platform(parse_platform("linux/s390x")?);
+        assert!(s390x.find_manifest_descriptor("test").is_err());
+        assert!(parse_platform("linux").is_err());
+
+        // the fs-verity digests of both platforms are still there
+        assert!(amd64.verity_digests("test")?.is_some());
+        assert!(arm64.verity_digests("test")?.is_some());
+        assert_eq!(index.manifests().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_timeout() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let other = Image::open(dir.path())?.with_lock_timeout(Duration::from_millis(100));
+        let lock = image.lock_layout()?;
+        let manifest = other.get_empty_manifest()?;
+        assert!(matches!(
+            other.insert_manifest(manifest.clone(), "test"),
+            Err(WireFormatError::LockTimeout(..))
+        ));
+        drop(lock);
+        other.insert_manifest(manifest, "test")?;
+        assert_eq!(image.tags()?, vec!["test"]);
+        Ok(())
+    }
 }
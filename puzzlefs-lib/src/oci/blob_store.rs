@@ -0,0 +1,481 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Seek, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use cap_std::fs::{Dir, File};
+use ocidir::oci_spec::image::{self, MediaType};
+use ocidir::OciDir;
+use sha2::{Digest as Sha2Digest, Sha256};
+
+use super::media_types::PUZZLEFS_CHUNK_DATA;
+use super::Descriptor;
+use crate::builder::enable_verity_for_file;
+use crate::format::Result;
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+// lazily fetched blobs are downloaded in ranges of this size, each retried on its own
+const FETCH_RANGE_SIZE: u64 = 8 * 1024 * 1024;
+const FETCH_ATTEMPTS: usize = 3;
+
+/// Where an [`super::Image`] keeps its blobs, by (hex) sha256 digest. The layout itself, i.e. the
+/// index and the manifests, always stays in the OCI directory; only the blobs may be somewhere
+/// else, e.g. fetched from a registry or a web server into a local cache.
+///
+/// Blobs are handed out as local files, since the rootfs is mmap()ed and fs-verity needs a file
+/// to check.
+pub trait BlobStore: Send + Sync {
+    fn open_blob(&self, digest: &str) -> io::Result<File>;
+
+    fn has_blob(&self, digest: &str) -> io::Result<bool>;
+
+    /// Stores the blob read from `data`, which has to match `descriptor`. Readers never see a
+    /// partially written or mismatching blob.
+    fn write_blob(&self, descriptor: &Descriptor, data: &mut dyn Read) -> io::Result<()>;
+
+    /// The digests of all the blobs in the store.
+    fn list_blobs(&self) -> io::Result<Vec<String>>;
+
+    fn remove_blob(&self, digest: &str) -> io::Result<()>;
+}
+
+/// Somewhere blobs can be downloaded from, in ranges.
+pub trait BlobSource: Send + Sync {
+    fn blob_size(&self, digest: &str) -> Result<u64>;
+
+    /// Downloads the bytes `range` of the blob with the (prefixed, e.g. `sha256:...`) `digest`.
+    fn get_blob_range(&self, digest: &str, range: Range<u64>) -> Result<Vec<u8>>;
+}
+
+/// The blobs directory of an OCI layout, `blobs/sha256`.
+pub struct LayoutBlobStore {
+    blobs: Dir,
+}
+
+impl LayoutBlobStore {
+    pub fn new(oci: &OciDir) -> io::Result<Self> {
+        Ok(LayoutBlobStore {
+            blobs: oci.blobs_dir().try_clone()?,
+        })
+    }
+}
+
+impl BlobStore for LayoutBlobStore {
+    fn open_blob(&self, digest: &str) -> io::Result<File> {
+        self.blobs.open(digest)
+    }
+
+    fn has_blob(&self, digest: &str) -> io::Result<bool> {
+        self.blobs.try_exists(digest)
+    }
+
+    fn write_blob(&self, descriptor: &Descriptor, data: &mut dyn Read) -> io::Result<()> {
+        let digest = descriptor.digest().digest();
+        // written to a temporary file and renamed into place, so a concurrent build sharing the
+        // blob store never sees a partially written blob
+        let temp = format!(
+            ".tmp-{digest}-{}-{}",
+            process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let result = (|| {
+            let mut file = self.blobs.create(&temp)?;
+            let mut writer = HashingWriter {
+                inner: &mut file,
+                hasher: Sha256::new(),
+                size: 0,
+            };
+            io::copy(data, &mut writer)?;
+            let (found, size) = (hex::encode(writer.hasher.finalize()), writer.size);
+            if found != digest || size != descriptor.size() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "blob mismatch: expected {digest} ({} bytes), got {found} ({size} bytes)",
+                        descriptor.size()
+                    ),
+                ));
+            }
+            file.sync_all()?;
+            self.blobs.rename(&temp, &self.blobs, digest)
+        })();
+        if result.is_err() {
+            let _ = self.blobs.remove_file(&temp);
+        }
+        result
+    }
+
+    fn list_blobs(&self) -> io::Result<Vec<String>> {
+        let mut digests = Vec::new();
+        for entry in self.blobs.entries()? {
+            let entry = entry?;
+            let name = entry.file_name();
+            // leftovers of interrupted writes don't count
+            match name.to_str() {
+                Some(name) if !name.starts_with('.') => digests.push(name.to_string()),
+                _ => {}
+            }
+        }
+        Ok(digests)
+    }
+
+    fn remove_blob(&self, digest: &str) -> io::Result<()> {
+        self.blobs.remove_file(digest)
+    }
+}
+
+/// A cache of blobs in a directory of its own, e.g. `/var/cache/puzzlefs`, to be shared by all
+/// the mounts of a host as the cache of their [`LazyFetcher`], so the blobs of an image mounted
+/// again, or shared with other images, are only downloaded once.
+///
+/// Each process checks the blobs against their digest the first time it opens them; the ones
+/// which don't match are removed, and downloaded again. Once the cache grows over its maximum
+/// size, the blobs which were least recently opened are removed; mounts which have them open
+/// keep reading them.
+pub struct BlobCache {
+    store: LayoutBlobStore,
+    max_size: Option<u64>,
+    // the blobs this process checked, or wrote
+    checked: Mutex<HashSet<String>>,
+}
+
+impl BlobCache {
+    pub fn new(dir: &Path, max_size: Option<u64>) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(BlobCache {
+            store: LayoutBlobStore {
+                blobs: Dir::open_ambient_dir(dir, cap_std::ambient_authority())?,
+            },
+            max_size,
+            checked: Mutex::new(HashSet::new()),
+        })
+    }
+
+    // Checks a blob opened for the first time, and marks it as used, for eviction.
+    fn check(&self, digest: &str, file: &File) -> io::Result<()> {
+        let mut hasher = Sha256::new();
+        // the clone shares the offset of `file`, which is rewound for the caller
+        let mut clone = file.try_clone()?;
+        io::copy(&mut clone, &mut hasher)?;
+        clone.rewind()?;
+        if hex::encode(hasher.finalize()) != digest {
+            log::warn!("removing corrupted blob {digest} from the cache");
+            self.remove_blob(digest)?;
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("corrupted blob {digest}"),
+            ));
+        }
+        // the cache may be read-only, it can't be evicted from then either
+        if let Err(e) = clone.into_std().set_modified(SystemTime::now()) {
+            log::debug!("can't mark {digest} as used: {e}");
+        }
+        self.checked.lock().unwrap().insert(digest.to_string());
+        Ok(())
+    }
+
+    // Removes the least recently opened blobs, but `keep`, until the cache fits in its maximum
+    // size.
+    fn evict(&self, keep: &str) -> io::Result<()> {
+        let Some(max_size) = self.max_size else {
+            return Ok(());
+        };
+        let mut blobs = Vec::new();
+        let mut total = 0;
+        for digest in self.store.list_blobs()? {
+            // other mounts may be evicting too
+            let metadata = match self.store.blobs.metadata(&digest) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                metadata => metadata?,
+            };
+            total += metadata.len();
+            if digest != keep {
+                blobs.push((metadata.modified()?, metadata.len(), digest));
+            }
+        }
+        blobs.sort();
+
+        for (_, size, digest) in blobs {
+            if total <= max_size {
+                break;
+            }
+            match self.store.remove_blob(&digest) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            log::debug!("evicted {digest} from the cache");
+            self.checked.lock().unwrap().remove(&digest);
+            total -= size;
+        }
+        Ok(())
+    }
+}
+
+impl BlobStore for BlobCache {
+    fn open_blob(&self, digest: &str) -> io::Result<File> {
+        let file = self.store.open_blob(digest)?;
+        if !self.checked.lock().unwrap().contains(digest) {
+            self.check(digest, &file)?;
+        }
+        Ok(file)
+    }
+
+    fn has_blob(&self, digest: &str) -> io::Result<bool> {
+        self.store.has_blob(digest)
+    }
+
+    fn write_blob(&self, descriptor: &Descriptor, data: &mut dyn Read) -> io::Result<()> {
+        self.store.write_blob(descriptor, data)?;
+        let digest = descriptor.digest().digest();
+        self.checked.lock().unwrap().insert(digest.to_string());
+        if let Err(e) = self.evict(digest) {
+            log::warn!("can't evict blobs from the cache: {e}");
+        }
+        Ok(())
+    }
+
+    fn list_blobs(&self) -> io::Result<Vec<String>> {
+        self.store.list_blobs()
+    }
+
+    fn remove_blob(&self, digest: &str) -> io::Result<()> {
+        self.checked.lock().unwrap().remove(digest);
+        self.store.remove_blob(digest)
+    }
+}
+
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A [`BlobStore`] downloading the blobs missing from a local cache store as they are first read,
+/// e.g. for images pulled with [`crate::registry::pull_lazy`].
+pub struct LazyFetcher {
+    source: Box<dyn BlobSource>,
+    cache: Box<dyn BlobStore>,
+    // the blobs being fetched, so concurrent reads of a blob download it only once
+    fetching: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl LazyFetcher {
+    pub fn new(source: impl BlobSource + 'static, cache: impl BlobStore + 'static) -> Self {
+        LazyFetcher {
+            source: Box::new(source),
+            cache: Box::new(cache),
+            fetching: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Fetches the blob with the (hex) sha256 `digest` into the cache. The cache only takes it
+    // once it's verified, so an interrupted fetch leaves nothing behind.
+    fn fetch(&self, digest: &str) -> Result<()> {
+        let lock = Arc::clone(
+            self.fetching
+                .lock()
+                .unwrap()
+                .entry(digest.to_string())
+                .or_default(),
+        );
+        let _guard = lock.lock().unwrap();
+        let result = self.fetch_locked(digest);
+        self.fetching.lock().unwrap().remove(digest);
+        result
+    }
+
+    fn fetch_locked(&self, digest: &str) -> Result<()> {
+        // some other read may have fetched it while we waited
+        if self.cache.has_blob(digest)? {
+            return Ok(());
+        }
+
+        fetch_blob(self.source.as_ref(), self.cache.as_ref(), digest)
+    }
+}
+
+// Downloads the blob with the (hex) sha256 `digest` from `source` into `store`, replacing whatever
+// is there under its name once the download is verified.
+pub(crate) fn fetch_blob(
+    source: &dyn BlobSource,
+    store: &dyn BlobStore,
+    digest: &str,
+) -> Result<()> {
+    let digest = format!("sha256:{digest}");
+    let size = source.blob_size(&digest)?;
+    let descriptor = Descriptor::new(
+        MediaType::Other(PUZZLEFS_CHUNK_DATA.to_string()),
+        size,
+        image::Digest::from_str(&digest)?,
+    );
+    let mut reader = RangeReader {
+        source,
+        digest: &digest,
+        size,
+        offset: 0,
+        buffer: io::Cursor::new(Vec::new()),
+    };
+    store.write_blob(&descriptor, &mut reader)?;
+
+    // so mounts checking fs-verity digests work, on filesystems supporting it
+    let blob = store.open_blob(descriptor.digest().digest())?;
+    if let Err(e) = enable_verity_for_file(&blob) {
+        log::debug!("can't enable fs-verity for {digest}: {e}");
+    }
+    Ok(())
+}
+
+impl BlobStore for LazyFetcher {
+    fn open_blob(&self, digest: &str) -> io::Result<File> {
+        match self.cache.open_blob(digest) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.fetch(digest).map_err(io::Error::other)?;
+                self.cache.open_blob(digest)
+            }
+            file => file,
+        }
+    }
+
+    // only what's in the cache, the blobs of the source are there for the reading
+    fn has_blob(&self, digest: &str) -> io::Result<bool> {
+        self.cache.has_blob(digest)
+    }
+
+    fn write_blob(&self, descriptor: &Descriptor, data: &mut dyn Read) -> io::Result<()> {
+        self.cache.write_blob(descriptor, data)
+    }
+
+    fn list_blobs(&self) -> io::Result<Vec<String>> {
+        self.cache.list_blobs()
+    }
+
+    fn remove_blob(&self, digest: &str) -> io::Result<()> {
+        self.cache.remove_blob(digest)
+    }
+}
+
+// Reads a blob from its source in ranges, retrying each of them on its own.
+struct RangeReader<'a> {
+    source: &'a dyn BlobSource,
+    digest: &'a str,
+    size: u64,
+    offset: u64,
+    buffer: io::Cursor<Vec<u8>>,
+}
+
+impl Read for RangeReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.buffer.read(buf)?;
+        if n > 0 || buf.is_empty() || self.offset == self.size {
+            return Ok(n);
+        }
+
+        let range = self.offset..self.size.min(self.offset + FETCH_RANGE_SIZE);
+        let mut attempt = 1;
+        let data = loop {
+            match self.source.get_blob_range(self.digest, range.clone()) {
+                Ok(data) => break data,
+                Err(e) if attempt >= FETCH_ATTEMPTS => return Err(io::Error::other(e)),
+                Err(e) => {
+                    log::warn!("fetching {} failed, retrying: {e}", self.digest);
+                    attempt += 1;
+                }
+            }
+        };
+        self.offset = range.end;
+        self.buffer = io::Cursor::new(data);
+        self.buffer.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ocidir::oci_spec::image::Digest;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_layout_blob_store() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = super::super::Image::new(dir.path())?;
+        let store = LayoutBlobStore::new(&image.0)?;
+
+        const DIGEST: &str = "3abd5ce0f91f640d88dca1f26b37037b02415927cacec9626d87668a715ec12d";
+        let data = b"meshuggah rocks";
+        let descriptor = |size| {
+            Descriptor::new(
+                MediaType::Other("test".to_string()),
+                size,
+                Digest::from_str(&format!("sha256:{DIGEST}")).unwrap(),
+            )
+        };
+
+        assert!(!store.has_blob(DIGEST)?);
+        assert!(store.write_blob(&descriptor(1), &mut &data[..]).is_err());
+        assert!(!store.has_blob(DIGEST)?);
+
+        store.write_blob(&descriptor(data.len() as u64), &mut &data[..])?;
+        assert!(store.has_blob(DIGEST)?);
+        let mut contents = Vec::new();
+        store.open_blob(DIGEST)?.read_to_end(&mut contents)?;
+        assert_eq!(contents, data);
+        assert_eq!(store.list_blobs()?, [DIGEST]);
+        Ok(())
+    }
+
+    fn descriptor(data: &[u8]) -> Descriptor {
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(data)));
+        Descriptor::new(
+            MediaType::Other("test".to_string()),
+            data.len() as u64,
+            Digest::from_str(&digest).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_blob_cache() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let cache = BlobCache::new(dir.path(), Some(20))?;
+        let (first, second) = (
+            descriptor(b"meshuggah rocks"),
+            descriptor(b"gojira rocks too"),
+        );
+
+        cache.write_blob(&first, &mut &b"meshuggah rocks"[..])?;
+        assert!(cache.has_blob(first.digest().digest())?);
+        // both don't fit, the older one goes
+        cache.write_blob(&second, &mut &b"gojira rocks too"[..])?;
+        assert!(!cache.has_blob(first.digest().digest())?);
+        assert!(cache.has_blob(second.digest().digest())?);
+
+        // another mount finds corrupted blobs missing, so they're downloaded again
+        fs::write(
+            dir.path().join(second.digest().digest()),
+            b"gojira rocks to",
+        )?;
+        let cache = BlobCache::new(dir.path(), Some(20))?;
+        let err = cache.open_blob(second.digest().digest()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(!cache.has_blob(second.digest().digest())?);
+        Ok(())
+    }
+}
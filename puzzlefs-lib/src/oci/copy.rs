@@ -0,0 +1,136 @@
+// Copying images between layouts, without a registry in between. Blobs are copied by digest
+// whatever their media type, so the puzzlefs rootfs and chunks go along like any other blob, and
+// the manifest (or the image index of multi-platform images) is referred to as it is, keeping its
+// digest, its annotations and the referrers attached to it.
+
+use std::backtrace::Backtrace;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use ocidir::oci_spec::image::{self, ImageIndex, MediaType, ANNOTATION_REF_NAME};
+
+use super::refs::BlobRefs;
+use super::tags::tag_of;
+use super::{Descriptor, Image};
+use crate::format::{Result, WireFormatError};
+
+/// What [`Image::copy_to`] copied.
+#[derive(Debug, Default)]
+pub struct CopyReport {
+    /// the digests of the blobs the target didn't have yet
+    pub copied: Vec<String>,
+    pub copied_bytes: u64,
+}
+
+impl Image {
+    /// Copies the image tagged `tag`, along with its referrers, into `target`, tagged
+    /// `target_tag`. Only the blobs `target` doesn't have are copied; lazily pulled images miss
+    /// the chunks which weren't read yet, `target` misses them too.
+    pub fn copy_to(&self, tag: &str, target: &Image, target_tag: &str) -> Result<CopyReport> {
+        let tagged = self
+            .0
+            .find_manifest_descriptor_with_tag(tag)?
+            .ok_or_else(|| {
+                WireFormatError::MissingManifest(tag.to_string(), Backtrace::capture())
+            })?;
+        // the referrers of multi-platform images are attached to the manifest of each platform
+        let mut subjects = vec![tagged.digest().to_string()];
+        if tagged.media_type() == &MediaType::ImageIndex {
+            let index: ImageIndex = self.0.read_json_blob(&tagged)?;
+            subjects.extend(index.manifests().iter().map(|d| d.digest().to_string()));
+        }
+        let mut referrers = Vec::new();
+        for subject in &subjects {
+            referrers.extend(
+                self.referrers_of(subject, None)?
+                    .into_iter()
+                    .map(|(d, _)| d),
+            );
+        }
+
+        let mut refs = BlobRefs::load(self)?;
+        let mut blobs = HashSet::new();
+        for descriptor in std::iter::once(&tagged).chain(&referrers) {
+            refs.mark(self, descriptor, &mut blobs)?;
+        }
+        refs.save(self);
+        let mut blobs = blobs.into_iter().collect::<Vec<_>>();
+        blobs.sort();
+        let mut report = CopyReport::default();
+        for digest in blobs {
+            if !self.2.has_blob(&digest)? || target.2.has_blob(&digest)? {
+                continue;
+            }
+            let mut blob = self.2.open_blob(&digest)?;
+            let size = blob.metadata()?.len();
+            // the store only looks at the digest and the size, which it checks
+            let descriptor = Descriptor::new(
+                MediaType::Other("application/octet-stream".to_string()),
+                size,
+                image::Digest::from_str(&format!("sha256:{digest}"))?,
+            );
+            target.2.write_blob(&descriptor, &mut blob)?;
+            report.copied_bytes += size;
+            report.copied.push(digest);
+        }
+
+        let _lock = target.lock_layout()?;
+        let mut tagged = tagged;
+        let mut annotations = tagged.annotations().clone().unwrap_or_default();
+        annotations.insert(ANNOTATION_REF_NAME.to_string(), target_tag.to_string());
+        tagged.set_annotations(Some(annotations));
+        let mut index = target.read_index_or_empty()?;
+        let mut manifests = index
+            .manifests()
+            .iter()
+            .filter(|d| {
+                tag_of(d) != Some(target_tag) && !referrers.iter().any(|r| r.digest() == d.digest())
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        manifests.push(tagged);
+        manifests.extend(referrers);
+        index.set_manifests(target.drop_dangling_referrers(manifests)?);
+        target.write_index(&index)?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use crate::reader::{PuzzleFS, WalkPuzzleFS};
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_copy() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let target_dir = tempdir()?;
+        let target = Image::new(target_dir.path())?;
+
+        let report = image.copy_to("test", &target, "copy")?;
+        assert!(!report.copied.is_empty());
+        assert_eq!(
+            target.find_manifest_descriptor("copy")?.digest(),
+            image.find_manifest_descriptor("test")?.digest()
+        );
+        assert_eq!(
+            target.verity_digests("copy")?,
+            image.verity_digests("test")?
+        );
+        assert_eq!(target.fsck()?, Vec::new());
+        let mut pfs = PuzzleFS::open(target, "copy", None)?;
+        assert!(WalkPuzzleFS::walk(&mut pfs)?.count() > 1);
+
+        // copying again finds everything there already
+        let target = Image::open(target_dir.path())?;
+        let report = image.copy_to("test", &target, "again")?;
+        assert!(report.copied.is_empty());
+        assert_eq!(target.tags()?, vec!["copy", "again"]);
+        Ok(())
+    }
+}
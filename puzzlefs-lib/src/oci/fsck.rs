@@ -0,0 +1,298 @@
+// Checking a whole layout up front, instead of finding out about broken images when they're
+// mounted and read: the index, every manifest reachable from it, every rootfs, the blobs they refer
+// to and the blobs themselves, against their names.
+//
+// Builds only lock the layout to update the index, so checking a layout while an image is built
+// into it reports the blobs written so far as dangling.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Read};
+
+use ocidir::oci_spec::image::{ImageIndex, ImageManifest, MediaType};
+use sha2::{Digest as Sha2Digest, Sha256};
+
+use super::media_types::{is_rootfs, VERITY_ROOT_HASH_ANNOTATION};
+use super::{Descriptor, Image, INDEX};
+use crate::builder::checkpointed_blobs;
+use crate::format::Result;
+use crate::fsverity_helpers::get_fs_verity_digest;
+
+/// Something [`Image::fsck`] found wrong with a layout. Digests are hex, without the `sha256:`
+/// prefix, like the names of the blobs.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FsckProblem {
+    /// index.json is missing or isn't a valid image index
+    InvalidIndex(String),
+    /// a manifest, or the image index of a multi-platform image, which doesn't parse
+    InvalidManifest { digest: String, error: String },
+    /// a rootfs which doesn't parse, or doesn't match the fs-verity digest it's annotated with
+    InvalidRootfs { digest: String, error: String },
+    /// a blob which `referrer` (the digest of a blob, or index.json) refers to, but which isn't in
+    /// the layout
+    MissingBlob { digest: String, referrer: String },
+    /// a blob of another size than its descriptor says
+    SizeMismatch {
+        digest: String,
+        expected: u64,
+        found: u64,
+    },
+    /// a blob whose contents don't hash to its name
+    DigestMismatch { digest: String, found: String },
+    /// a blob nothing refers to, which [`Image::gc`] would remove
+    DanglingBlob(String),
+}
+
+impl fmt::Display for FsckProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsckProblem::InvalidIndex(error) => write!(f, "invalid {INDEX}: {error}"),
+            FsckProblem::InvalidManifest { digest, error } => {
+                write!(f, "invalid manifest {digest}: {error}")
+            }
+            FsckProblem::InvalidRootfs { digest, error } => {
+                write!(f, "invalid rootfs {digest}: {error}")
+            }
+            FsckProblem::MissingBlob { digest, referrer } => {
+                write!(f, "missing blob {digest}, referred to by {referrer}")
+            }
+            FsckProblem::SizeMismatch {
+                digest,
+                expected,
+                found,
+            } => write!(f, "blob {digest} has {found} bytes, expected {expected}"),
+            FsckProblem::DigestMismatch { digest, found } => {
+                write!(f, "blob {digest} hashes to {found}")
+            }
+            FsckProblem::DanglingBlob(digest) => write!(f, "dangling blob {digest}"),
+        }
+    }
+}
+
+struct Fsck<'a> {
+    image: &'a Image,
+    reachable: HashSet<String>,
+    problems: Vec<FsckProblem>,
+}
+
+impl Image {
+    /// Checks the whole layout and lists what's wrong with it; an empty list means every image in
+    /// it is intact. Lazily pulled images miss the chunks which weren't read yet, those are
+    /// reported as missing.
+    pub fn fsck(&self) -> Result<Vec<FsckProblem>> {
+        let mut fsck = Fsck {
+            image: self,
+            reachable: checkpointed_blobs(self)?,
+            problems: Vec::new(),
+        };
+        let index_ok = match self.0.read_index() {
+            Ok(index) => {
+                for descriptor in index.manifests() {
+                    fsck.check(descriptor, INDEX)?;
+                }
+                true
+            }
+            Err(e) => {
+                fsck.problems.push(FsckProblem::InvalidIndex(e.to_string()));
+                false
+            }
+        };
+
+        let mut blobs = self.2.list_blobs()?;
+        blobs.sort();
+        for digest in blobs {
+            let mut hasher = Sha256::new();
+            io::copy(&mut self.2.open_blob(&digest)?, &mut hasher)?;
+            let found = hex::encode(hasher.finalize());
+            if found != digest {
+                fsck.problems.push(FsckProblem::DigestMismatch {
+                    digest: digest.clone(),
+                    found,
+                });
+            }
+            // without an index, everything would be dangling
+            if index_ok && !fsck.reachable.contains(&digest) {
+                fsck.problems.push(FsckProblem::DanglingBlob(digest));
+            }
+        }
+        Ok(fsck.problems)
+    }
+}
+
+impl Fsck<'_> {
+    // Checks the blob of `descriptor` and everything it refers to.
+    fn check(&mut self, descriptor: &Descriptor, referrer: &str) -> Result<()> {
+        let digest = descriptor.digest().digest().to_string();
+        if !self.visit(&digest, referrer)? {
+            return Ok(());
+        }
+        let found = self.image.2.open_blob(&digest)?.metadata()?.len();
+        if found != descriptor.size() {
+            self.problems.push(FsckProblem::SizeMismatch {
+                digest: digest.clone(),
+                expected: descriptor.size(),
+                found,
+            });
+        }
+
+        match descriptor.media_type() {
+            MediaType::ImageManifest => {
+                match serde_json::from_reader::<_, ImageManifest>(
+                    self.image.open_raw_blob(&digest, None)?,
+                ) {
+                    Ok(manifest) => {
+                        self.check(manifest.config(), &digest)?;
+                        for layer in manifest.layers() {
+                            self.check(layer, &digest)?;
+                        }
+                    }
+                    Err(e) => self.problems.push(FsckProblem::InvalidManifest {
+                        digest,
+                        error: e.to_string(),
+                    }),
+                }
+            }
+            MediaType::ImageIndex => {
+                match serde_json::from_reader::<_, ImageIndex>(
+                    self.image.open_raw_blob(&digest, None)?,
+                ) {
+                    Ok(index) => {
+                        for manifest in index.manifests() {
+                            self.check(manifest, &digest)?;
+                        }
+                    }
+                    Err(e) => self.problems.push(FsckProblem::InvalidManifest {
+                        digest,
+                        error: e.to_string(),
+                    }),
+                }
+            }
+            media_type if is_rootfs(media_type) => {
+                let verity = descriptor
+                    .annotations()
+                    .as_ref()
+                    .and_then(|annotations| annotations.get(VERITY_ROOT_HASH_ANNOTATION));
+                self.check_rootfs(&digest, verity.map(String::as_str))?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Checks a rootfs, the chunks it refers to and the rootfs it's a delta on, if any.
+    fn check_rootfs(&mut self, digest: &str, verity: Option<&str>) -> Result<()> {
+        let mut data = Vec::new();
+        self.image
+            .open_raw_blob(digest, None)?
+            .read_to_end(&mut data)?;
+        if let Some(verity) = verity {
+            let found = hex::encode(get_fs_verity_digest(&data)?);
+            if found != verity {
+                self.problems.push(FsckProblem::InvalidRootfs {
+                    digest: digest.to_string(),
+                    error: format!("fs-verity digest {found}, annotated with {verity}"),
+                });
+            }
+        }
+
+        let parsed = (|| -> Result<_> {
+            // rootfs of older versions are converted, their metadata blobs have to be there
+            let (rootfs, metadatas) = self
+                .image
+                .read_rootfs(self.image.open_raw_blob(digest, None)?, false)?;
+            rootfs.get_verity_data()?;
+            Ok((rootfs.blob_digests()?, metadatas, rootfs.get_parent()?))
+        })();
+        let (chunks, metadatas, parent) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                self.problems.push(FsckProblem::InvalidRootfs {
+                    digest: digest.to_string(),
+                    error: e.to_string(),
+                });
+                return Ok(());
+            }
+        };
+
+        let mut chunks = chunks.iter().map(hex::encode).collect::<Vec<_>>();
+        chunks.extend(metadatas);
+        chunks.sort();
+        for chunk in chunks {
+            self.visit(&chunk, digest)?;
+        }
+        if let Some(parent) = parent {
+            let parent = hex::encode(parent);
+            if self.visit(&parent, digest)? {
+                self.check_rootfs(&parent, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Marks a blob as reachable, reporting it if it's missing. Returns whether it's there and
+    // wasn't visited before.
+    fn visit(&mut self, digest: &str, referrer: &str) -> Result<bool> {
+        if !self.reachable.insert(digest.to_string()) {
+            return Ok(false);
+        }
+        if !self.image.2.has_blob(digest)? {
+            self.problems.push(FsckProblem::MissingBlob {
+                digest: digest.to_string(),
+                referrer: referrer.to_string(),
+            });
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_fsck() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        assert_eq!(image.fsck()?, Vec::new());
+
+        let blobs = dir.path().join("blobs/sha256");
+        let chunk = image
+            .find_manifest("test")?
+            .layers()
+            .iter()
+            .find(|d| !is_rootfs(d.media_type()))
+            .unwrap()
+            .digest()
+            .digest()
+            .to_string();
+        let rootfs = image.find_manifest("test")?.layers()[0]
+            .digest()
+            .digest()
+            .to_string();
+        fs::remove_file(blobs.join(&chunk))?;
+        let corrupt = hex::encode(Sha256::digest(b"data"));
+        fs::write(blobs.join(&corrupt), b"tampered")?;
+
+        assert_eq!(
+            image.fsck()?,
+            vec![
+                FsckProblem::MissingBlob {
+                    digest: chunk,
+                    referrer: rootfs,
+                },
+                FsckProblem::DigestMismatch {
+                    digest: corrupt.clone(),
+                    found: hex::encode(Sha256::digest(b"tampered")),
+                },
+                FsckProblem::DanglingBlob(corrupt),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+// Garbage collection of the blobs nothing in the layout refers to any more, e.g. the ones of
+// images which were rebuilt under the same tag.
+//
+// Everything in the index is kept: the images, the referrers attached to them and whatever other
+// tools put there, along with the blobs their manifests list and, for puzzlefs images, the chunks
+// their rootfs (and the rootfs of the images they are deltas on) refer to. So are the blobs the
+// checkpoints of interrupted builds refer to. Builds only lock the layout to update the index, so
+// collecting garbage while an image is built into the same layout may remove the blobs it
+// already wrote.
+
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use ocidir::oci_spec::image::{self, ImageIndex, ImageManifest, MediaType};
+
+use super::media_types::{is_rootfs, PUZZLEFS_ROOTFS};
+use super::refs::BlobRefs;
+use super::{Descriptor, Image};
+use crate::builder::checkpointed_blobs;
+use crate::format::Result;
+
+/// What [`Image::gc`] removed, or would remove for dry runs.
+#[derive(Debug, Default)]
+pub struct GcReport {
+    /// the digests of the removed blobs
+    pub removed: Vec<String>,
+    pub removed_bytes: u64,
+}
+
+impl Image {
+    /// Removes the blobs which nothing in the index refers to. A dry run only reports them.
+    pub fn gc(&self, dry_run: bool) -> Result<GcReport> {
+        let _lock = self.lock_layout()?;
+        let mut reachable = checkpointed_blobs(self)?;
+        let mut refs = BlobRefs::load(self)?;
+        for descriptor in self.0.read_index()?.manifests() {
+            refs.mark(self, descriptor, &mut reachable)?;
+        }
+        refs.save(self);
+
+        let mut report = GcReport::default();
+        let mut blobs = self.2.list_blobs()?;
+        blobs.sort();
+        for digest in blobs {
+            if reachable.contains(&digest) {
+                continue;
+            }
+            report.removed_bytes += self.2.open_blob(&digest)?.metadata()?.len();
+            if !dry_run {
+                self.2.remove_blob(&digest)?;
+            }
+            report.removed.push(digest);
+        }
+        Ok(report)
+    }
+
+    // Adds the blob of `descriptor` and everything it refers to to `reachable`.
+    pub(super) fn mark(
+        &self,
+        descriptor: &Descriptor,
+        reachable: &mut HashSet<String>,
+    ) -> Result<()> {
+        let digest = descriptor.digest().digest().to_string();
+        // lazily pulled images don't have all their blobs
+        if !reachable.insert(digest.clone()) || !self.2.has_blob(&digest)? {
+            return Ok(());
+        }
+
+        match descriptor.media_type() {
+            MediaType::ImageManifest => {
+                let manifest: ImageManifest =
+                    serde_json::from_reader(self.open_raw_blob(&digest, None)?)?;
+                self.mark(manifest.config(), reachable)?;
+                for layer in manifest.layers() {
+                    self.mark(layer, reachable)?;
+                }
+            }
+            MediaType::ImageIndex => {
+                let index: ImageIndex =
+                    serde_json::from_reader(self.open_raw_blob(&digest, None)?)?;
+                for manifest in index.manifests() {
+                    self.mark(manifest, reachable)?;
+                }
+            }
+            media_type if is_rootfs(media_type) => {
+                let (rootfs, metadatas) =
+                    self.read_rootfs(self.open_raw_blob(&digest, None)?, false)?;
+                reachable.extend(metadatas);
+                reachable.extend(rootfs.blob_digests()?.iter().map(hex::encode));
+                if let Some(parent) = rootfs.get_parent()? {
+                    let parent = Descriptor::new(
+                        MediaType::Other(PUZZLEFS_ROOTFS.to_string()),
+                        0,
+                        image::Digest::from_str(&format!("sha256:{}", hex::encode(parent)))?,
+                    );
+                    self.mark(&parent, reachable)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{add_rootfs_delta, build_test_fs};
+    use crate::compression::Zstd;
+    use crate::reader::{PuzzleFS, WalkPuzzleFS};
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_gc() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "base")?;
+        let delta = tempdir()?;
+        fs::write(delta.path().join("file"), b"delta")?;
+        let (_, image) = add_rootfs_delta::<Zstd>(delta.path(), image, "delta", "base")?;
+        // nothing to collect yet
+        assert!(image.gc(false)?.removed.is_empty());
+
+        // the old base stays, the delta is built on it
+        let rootfs = tempdir()?;
+        fs::write(rootfs.path().join("file"), b"new base")?;
+        build_test_fs(rootfs.path(), &image, "base")?;
+        let blobs = image.blob_store().list_blobs()?.len();
+        let dry_run = image.gc(true)?;
+        assert!(!dry_run.removed.is_empty());
+        assert!(dry_run.removed_bytes > 0);
+        assert_eq!(image.blob_store().list_blobs()?.len(), blobs);
+
+        // the old manifest and the verity digests attached to it
+        let removed = image.gc(false)?;
+        assert_eq!(removed.removed, dry_run.removed);
+        assert_eq!(
+            image.blob_store().list_blobs()?.len(),
+            blobs - removed.removed.len()
+        );
+        assert!(image.gc(false)?.removed.is_empty());
+
+        let image = Image::open(dir.path())?;
+        let mut pfs = PuzzleFS::open(image, "delta", None)?;
+        let mut files = 0;
+        for de in WalkPuzzleFS::walk(&mut pfs)? {
+            let de = de?;
+            if de.inode.file_len().is_ok() {
+                de.open()?.read_to_end(&mut Vec::new())?;
+                files += 1;
+            }
+        }
+        assert!(files > 1);
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use ocidir::oci_spec::image::MediaType;
+
 pub trait PuzzleFSMediaType {
     fn name(&self) -> &'static str;
 }
@@ -12,6 +14,36 @@ impl PuzzleFSMediaType for Rootfs {
     }
 }
 
+// a rootfs compressed with zstd as a whole, which older readers don't know to look for
+pub(crate) const PUZZLEFS_ROOTFS_ZSTD: &str = "application/vnd.puzzlefs.image.rootfs.v1+zstd";
+
+pub struct RootfsZstd {}
+
+impl PuzzleFSMediaType for RootfsZstd {
+    fn name(&self) -> &'static str {
+        PUZZLEFS_ROOTFS_ZSTD
+    }
+}
+
+pub(crate) fn is_rootfs(media_type: &MediaType) -> bool {
+    matches!(media_type, MediaType::Other(name) if is_rootfs_name(name))
+}
+
+pub(crate) fn is_rootfs_name(name: &str) -> bool {
+    name == PUZZLEFS_ROOTFS || name == PUZZLEFS_ROOTFS_ZSTD
+}
+
+// a blob holding some of the inodes of a sharded rootfs
+pub(crate) const PUZZLEFS_METADATA_SHARD: &str = "application/vnd.puzzlefs.image.metadata.v1";
+
+pub struct MetadataShard {}
+
+impl PuzzleFSMediaType for MetadataShard {
+    fn name(&self) -> &'static str {
+        PUZZLEFS_METADATA_SHARD
+    }
+}
+
 pub(crate) const PUZZLEFS_CHUNK_DATA: &str = "application/vnd.puzzlefs.image.filedata.v1";
 
 pub struct Chunk {}
@@ -22,5 +54,28 @@ impl PuzzleFSMediaType for Chunk {
     }
 }
 
+// a zstd dictionary the chunks of the image may be compressed with
+pub(crate) const PUZZLEFS_ZSTD_DICTIONARY: &str = "application/vnd.puzzlefs.image.dictionary.v1";
+
+pub struct ZstdDictionary {}
+
+impl PuzzleFSMediaType for ZstdDictionary {
+    fn name(&self) -> &'static str {
+        PUZZLEFS_ZSTD_DICTIONARY
+    }
+}
+
+pub(crate) fn is_zstd_dictionary(media_type: &MediaType) -> bool {
+    matches!(media_type, MediaType::Other(name) if name == PUZZLEFS_ZSTD_DICTIONARY)
+}
+
+// the artifact type of the fs-verity digests attached to images as referrers
+pub(crate) const PUZZLEFS_VERITY: &str = "application/vnd.puzzlefs.verity.v1+json";
+
+// the namespace of the annotations puzzlefs uses itself
+pub(crate) const PUZZLEFS_ANNOTATION_PREFIX: &str = "io.puzzlefsoci.puzzlefs.";
+
 pub(crate) const VERITY_ROOT_HASH_ANNOTATION: &str =
     "io.puzzlefsoci.puzzlefs.puzzlefs_verity_root_hash";
+
+pub(crate) const BUILD_OPTIONS_ANNOTATION: &str = "io.puzzlefsoci.puzzlefs.build_options";
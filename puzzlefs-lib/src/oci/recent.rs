@@ -0,0 +1,110 @@
+// The data readers derived from blobs last, so that the reads of one chunk, or of the chunks
+// packed into one blob, don't redo the work every time: the plaintext of encrypted blobs, whose
+// authentication tag covers a whole blob, and the chunks whose checksum was checked, which have
+// to be read whole to check it. The data read least recently is dropped once it all adds up to
+// more than the maximum size.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::format::Result;
+
+pub(crate) struct RecentlyRead<K> {
+    entries: Mutex<VecDeque<Entry<K>>>,
+    max_size: usize,
+}
+
+struct Entry<K> {
+    key: K,
+    // the fs-verity digest the blob was checked against, if any
+    verity: Option<Vec<u8>>,
+    data: Arc<[u8]>,
+}
+
+impl<K: PartialEq> RecentlyRead<K> {
+    pub(crate) fn new(max_size: usize) -> Self {
+        RecentlyRead {
+            entries: Mutex::new(VecDeque::new()),
+            max_size,
+        }
+    }
+
+    // The data for `key`, as returned by `load` unless it's kept. Data read without checking
+    // the blob against `verity` doesn't do for reads which have to check it.
+    pub(crate) fn get_or_load(
+        &self,
+        key: K,
+        verity: Option<&[u8]>,
+        load: impl FnOnce() -> Result<Vec<u8>>,
+    ) -> Result<Arc<[u8]>> {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            let kept = entries.iter().position(|entry| {
+                entry.key == key && (verity.is_none() || entry.verity.as_deref() == verity)
+            });
+            if let Some(entry) = kept.and_then(|i| entries.remove(i)) {
+                let data = Arc::clone(&entry.data);
+                entries.push_front(entry);
+                return Ok(data);
+            }
+        }
+
+        // loaded without holding the lock, so reads of other blobs don't wait for it
+        let data: Arc<[u8]> = load()?.into();
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_front(Entry {
+            key,
+            verity: verity.map(<[u8]>::to_vec),
+            data: Arc::clone(&data),
+        });
+        // the data just loaded stays, however large it is
+        let mut size = 0;
+        let keep = entries
+            .iter()
+            .take_while(|entry| {
+                size += entry.data.len();
+                size <= self.max_size
+            })
+            .count();
+        entries.truncate(keep.max(1));
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_recently_read() -> anyhow::Result<()> {
+        let recent = RecentlyRead::new(10);
+        let loads = Cell::new(0);
+        let get = |key: u8, verity: Option<&[u8]>, len: usize| {
+            recent.get_or_load(key, verity, || {
+                loads.set(loads.get() + 1);
+                Ok(vec![key; len])
+            })
+        };
+
+        assert_eq!(&*get(1, None, 4)?, &[1; 4]);
+        assert_eq!(&*get(1, None, 4)?, &[1; 4]);
+        assert_eq!(loads.get(), 1);
+        // data read without checking fs-verity isn't trusted by verified reads
+        get(1, Some(&[9; 32]), 4)?;
+        get(1, Some(&[9; 32]), 4)?;
+        get(1, None, 4)?;
+        assert_eq!(loads.get(), 2);
+
+        // 1 was read last, so 2 is dropped to make room for 3
+        get(2, None, 4)?;
+        get(1, None, 4)?;
+        get(3, None, 4)?;
+        assert_eq!(loads.get(), 4);
+        get(1, None, 4)?;
+        assert_eq!(loads.get(), 4);
+        get(2, None, 4)?;
+        assert_eq!(loads.get(), 5);
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+// Artifacts attached to images through the `subject` of their manifests, as referrers (OCI image
+// spec 1.1). Tools like oras and regctl discover and copy them along with the image, without
+// knowing anything about puzzlefs.
+//
+// Referrers live in the index next to the images, untagged, with their artifact type set on
+// their descriptor. Attaching one drops the referrers of manifests which aren't in the index any
+// more, e.g. the ones of an image which was rebuilt under the same tag.
+
+use std::backtrace::Backtrace;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::str::FromStr;
+
+use ocidir::oci_spec::image::{
+    self, ImageIndex, ImageManifest, ImageManifestBuilder, MediaType, SCHEMA_VERSION,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as Sha2Digest, Sha256};
+
+use super::media_types::PUZZLEFS_VERITY;
+use super::{Descriptor, Image};
+use crate::format::{Result, WireFormatError};
+use crate::fsverity_helpers::get_fs_verity_digest;
+
+const EMPTY_JSON: &[u8] = b"{}";
+
+/// The fs-verity digests of an image, as attached to it by the builder.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerityDigests {
+    /// the digest to check the manifest with, e.g. `puzzlefs mount --digest`
+    pub manifest: String,
+    pub rootfs: String,
+}
+
+impl Image {
+    /// Attaches `data` to the manifest tagged `tag`, as a referrer with the given artifact type.
+    /// Attaching the same data twice is a no-op.
+    pub fn add_referrer(
+        &self,
+        tag: &str,
+        artifact_type: &str,
+        data: &[u8],
+        annotations: Option<HashMap<String, String>>,
+    ) -> Result<Descriptor> {
+        let _lock = self.lock_layout()?;
+        let subject = self.find_subject(tag)?;
+        let artifact_type = MediaType::Other(artifact_type.to_string());
+
+        let config = self.write_raw_blob(EMPTY_JSON, MediaType::EmptyJSON)?;
+        let layer = self.write_raw_blob(data, artifact_type.clone())?;
+        let mut manifest = ImageManifestBuilder::default()
+            .schema_version(SCHEMA_VERSION)
+            .media_type(MediaType::ImageManifest)
+            .artifact_type(artifact_type)
+            .config(config)
+            .layers(vec![layer])
+            .subject(subject)
+            .build()?;
+        manifest.set_annotations(annotations);
+        self.insert_referrer_locked(&manifest)
+    }
+
+    // Stores a referrer manifest, e.g. one pulled from a registry, and adds it to the index.
+    pub(crate) fn insert_referrer(&self, manifest: &ImageManifest) -> Result<Descriptor> {
+        let _lock = self.lock_layout()?;
+        self.insert_referrer_locked(manifest)
+    }
+
+    fn insert_referrer_locked(&self, manifest: &ImageManifest) -> Result<Descriptor> {
+        let mut descriptor = self
+            .0
+            .write_json_blob(manifest, MediaType::ImageManifest)?
+            .build()?;
+        descriptor.set_artifact_type(manifest.artifact_type().clone());
+
+        let mut index = self.0.read_index()?;
+        let mut manifests = index
+            .manifests()
+            .iter()
+            .filter(|d| d.digest() != descriptor.digest())
+            .cloned()
+            .collect::<Vec<_>>();
+        manifests.push(descriptor.clone());
+        index.set_manifests(self.drop_dangling_referrers(manifests)?);
+        self.write_index(&index)?;
+        Ok(descriptor)
+    }
+
+    // Drops the referrers of manifests which aren't among `manifests` any more.
+    pub(super) fn drop_dangling_referrers(
+        &self,
+        manifests: Vec<Descriptor>,
+    ) -> Result<Vec<Descriptor>> {
+        let mut live = HashSet::new();
+        for d in &manifests {
+            live.insert(d.digest().to_string());
+            // the manifests for each platform of multi-platform images
+            if d.media_type() == &MediaType::ImageIndex {
+                let index: ImageIndex = self.0.read_json_blob(d)?;
+                live.extend(index.manifests().iter().map(|m| m.digest().to_string()));
+            }
+        }
+        let mut kept = Vec::with_capacity(manifests.len());
+        for d in manifests {
+            match self.subject_of(&d)? {
+                Some(subject) if !live.contains(&subject) => {}
+                _ => kept.push(d),
+            }
+        }
+        Ok(kept)
+    }
+
+    /// The referrers of the manifest tagged `tag`, optionally only the ones of an artifact type,
+    /// along with their manifests.
+    pub fn referrers(
+        &self,
+        tag: &str,
+        artifact_type: Option<&str>,
+    ) -> Result<Vec<(Descriptor, ImageManifest)>> {
+        let subject = self.find_subject(tag)?.digest().to_string();
+        self.referrers_of(&subject, artifact_type)
+    }
+
+    // The referrers of the manifest with digest `subject`, e.g. one platform of a multi-platform
+    // image.
+    pub(crate) fn referrers_of(
+        &self,
+        subject: &str,
+        artifact_type: Option<&str>,
+    ) -> Result<Vec<(Descriptor, ImageManifest)>> {
+        let mut referrers = Vec::new();
+        for d in self.0.read_index()?.manifests() {
+            let Some(found) = d.artifact_type() else {
+                continue;
+            };
+            if artifact_type.is_some_and(|wanted| found.to_string() != wanted) {
+                continue;
+            }
+            let manifest: ImageManifest = self.0.read_json_blob(d)?;
+            if manifest
+                .subject()
+                .as_ref()
+                .is_some_and(|s| s.digest().to_string() == subject)
+            {
+                referrers.push((d.clone(), manifest));
+            }
+        }
+        Ok(referrers)
+    }
+
+    /// Reads the data of a referrer, checking it against its manifest.
+    pub fn read_referrer(&self, manifest: &ImageManifest) -> Result<Vec<u8>> {
+        let layer = manifest
+            .layers()
+            .first()
+            .ok_or_else(|| WireFormatError::InvalidSerializedData(Backtrace::capture()))?;
+        let mut data = Vec::new();
+        self.open_raw_blob(layer.digest().digest(), None)?
+            .read_to_end(&mut data)?;
+        if hex::encode(Sha256::digest(&data)) != layer.digest().digest() {
+            return Err(WireFormatError::InvalidSerializedData(Backtrace::capture()));
+        }
+        Ok(data)
+    }
+
+    // Attaches the fs-verity digests of the manifest and the rootfs of `tag`, which the builder
+    // otherwise only prints or keeps in annotations.
+    pub(crate) fn attach_verity(&self, tag: &str) -> Result<Descriptor> {
+        let mut manifest = Vec::new();
+        self.get_image_manifest_fd(tag)?
+            .read_to_end(&mut manifest)?;
+        let digests = VerityDigests {
+            manifest: hex::encode(get_fs_verity_digest(&manifest)?),
+            rootfs: hex::encode(self.get_pfs_rootfs_verity(tag)?),
+        };
+        self.add_referrer(tag, PUZZLEFS_VERITY, &serde_json::to_vec(&digests)?, None)
+    }
+
+    /// The fs-verity digests attached to `tag` when it was built, if any. They aren't covered by
+    /// the manifest digest, so they only say what to expect; they don't prove anything.
+    pub fn verity_digests(&self, tag: &str) -> Result<Option<VerityDigests>> {
+        let Some((_, manifest)) = self
+            .referrers(tag, Some(PUZZLEFS_VERITY))?
+            .into_iter()
+            .next()
+        else {
+            return Ok(None);
+        };
+        Ok(Some(serde_json::from_slice(
+            &self.read_referrer(&manifest)?,
+        )?))
+    }
+
+    pub(crate) fn find_subject(&self, tag: &str) -> Result<Descriptor> {
+        let tagged = self.find_manifest_descriptor(tag)?;
+        // the subject is just the manifest, not its tag or platform
+        Ok(Descriptor::new(
+            tagged.media_type().clone(),
+            tagged.size(),
+            tagged.digest().clone(),
+        ))
+    }
+
+    // The digest of the manifest a referrer in the index refers to.
+    fn subject_of(&self, descriptor: &Descriptor) -> Result<Option<String>> {
+        if descriptor.artifact_type().is_none() {
+            return Ok(None);
+        }
+        let manifest: ImageManifest = self.0.read_json_blob(descriptor)?;
+        Ok(manifest.subject().as_ref().map(|s| s.digest().to_string()))
+    }
+
+    pub(crate) fn write_raw_blob(&self, data: &[u8], media_type: MediaType) -> Result<Descriptor> {
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(data)));
+        let descriptor = Descriptor::new(
+            media_type,
+            data.len() as u64,
+            image::Digest::from_str(&digest)?,
+        );
+        if !self.has_blob(&descriptor) {
+            self.2.write_blob(&descriptor, &mut &data[..])?;
+        }
+        Ok(descriptor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_referrers() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+
+        let digests = image.verity_digests("test")?.unwrap();
+        assert_eq!(
+            digests.rootfs,
+            hex::encode(image.get_pfs_rootfs_verity("test")?)
+        );
+
+        image.add_referrer("test", "application/example", b"data", None)?;
+        image.add_referrer("test", "application/example", b"data", None)?;
+        let referrers = image.referrers("test", Some("application/example"))?;
+        assert_eq!(referrers.len(), 1);
+        assert_eq!(image.read_referrer(&referrers[0].1)?, b"data");
+        assert_eq!(image.referrers("test", None)?.len(), 2);
+
+        // rebuilding the image drops the referrers of the old one
+        let rootfs = tempdir()?;
+        std::fs::write(rootfs.path().join("file"), b"new")?;
+        build_test_fs(rootfs.path(), &image, "test")?;
+        assert_eq!(image.referrers("test", None)?.len(), 1);
+        assert_eq!(image.get_index()?.manifests().len(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+// A map of the blobs each manifest in the index refers to, directly or through its rootfs, kept
+// in the layout so that gc and stats don't parse every rootfs again, which gets slow for layouts
+// with hundreds of tags.
+//
+// Manifests are content addressed, so what one refers to never changes: entries are added when
+// manifests get into the index and dropped when they leave it, along with the index update. The
+// map is only a cache, though. Manifests it doesn't know about, e.g. the ones other tools added to
+// the index, are looked at when they're first needed, and a missing or invalid map is rebuilt.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io::{self, Write};
+use std::process;
+
+use log::warn;
+use ocidir::oci_spec::image::ImageIndex;
+use serde::{Deserialize, Serialize};
+
+use super::tags::tag_of;
+use super::{Descriptor, Image};
+use crate::format::Result;
+
+const REFS: &str = "puzzlefs-refs.json";
+
+#[derive(Default, Serialize, Deserialize)]
+pub(super) struct BlobRefs {
+    // the (hex) digests of the blobs each manifest refers to, itself included
+    manifests: BTreeMap<String, BTreeSet<String>>,
+    #[serde(skip)]
+    changed: bool,
+}
+
+impl BlobRefs {
+    pub(super) fn load(image: &Image) -> Result<Self> {
+        match image.0.dir().read(REFS) {
+            Ok(data) => Ok(serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("rebuilding invalid {REFS}: {e}");
+                BlobRefs::default()
+            })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BlobRefs::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Adds the blobs `descriptor` refers to to `reachable`, like Image::mark.
+    pub(super) fn mark(
+        &mut self,
+        image: &Image,
+        descriptor: &Descriptor,
+        reachable: &mut HashSet<String>,
+    ) -> Result<()> {
+        let digest = descriptor.digest().digest();
+        if let Some(blobs) = self.manifests.get(digest) {
+            reachable.extend(blobs.iter().cloned());
+            return Ok(());
+        }
+        let mut blobs = HashSet::new();
+        image.mark(descriptor, &mut blobs)?;
+        // what's found of a manifest with missing blobs, e.g. the rootfs of a damaged image,
+        // may be incomplete, so those are looked at again next time, as are lazily pulled images
+        let mut complete = true;
+        for blob in &blobs {
+            if !image.2.has_blob(blob)? {
+                complete = false;
+                break;
+            }
+        }
+        if complete {
+            self.manifests
+                .insert(digest.to_string(), blobs.iter().cloned().collect());
+            self.changed = true;
+        }
+        reachable.extend(blobs);
+        Ok(())
+    }
+
+    // Drops the manifests which aren't in `index`, and adds the ones which are.
+    fn update(&mut self, image: &Image, index: &ImageIndex) -> Result<()> {
+        let live = index
+            .manifests()
+            .iter()
+            .map(|d| d.digest().digest())
+            .collect::<HashSet<_>>();
+        let count = self.manifests.len();
+        self.manifests
+            .retain(|digest, _| live.contains(digest.as_str()));
+        self.changed |= self.manifests.len() != count;
+        for descriptor in index.manifests() {
+            self.mark(image, descriptor, &mut HashSet::new())?;
+        }
+        Ok(())
+    }
+
+    // Writes the map back if it changed. Every entry is right whatever other processes do, so
+    // concurrent saves only lose each other's additions, which are found again when needed. Not
+    // being able to save, e.g. in a read-only layout, only costs time.
+    pub(super) fn save(&self, image: &Image) {
+        if !self.changed {
+            return;
+        }
+        let temp = format!(".{REFS}-{}.tmp", process::id());
+        let dir = image.0.dir();
+        let result = (|| -> Result<()> {
+            let mut file = dir.create(&temp)?;
+            file.write_all(&serde_json::to_vec(self)?)?;
+            dir.rename(&temp, dir, REFS)?;
+            Ok(())
+        })();
+        if let Err(e) = result {
+            let _ = dir.remove_file(&temp);
+            warn!("can't save {REFS}: {e}");
+        }
+    }
+}
+
+impl Image {
+    /// The tags referring to each blob in the layout, directly or through the images they're
+    /// deltas on, by (hex) digest. Blobs only untagged manifests, like referrers, refer to have
+    /// no tags.
+    pub fn blob_tags(&self) -> Result<BTreeMap<String, Vec<String>>> {
+        let mut refs = BlobRefs::load(self)?;
+        let mut tags = BTreeMap::<String, Vec<String>>::new();
+        for descriptor in self.0.read_index()?.manifests() {
+            let mut blobs = HashSet::new();
+            refs.mark(self, descriptor, &mut blobs)?;
+            for blob in blobs {
+                let blob_tags = tags.entry(blob).or_default();
+                if let Some(tag) = tag_of(descriptor) {
+                    blob_tags.push(tag.to_string());
+                }
+            }
+        }
+        refs.save(self);
+        Ok(tags)
+    }
+
+    // Brings the map in line with a new index.
+    pub(super) fn update_blob_refs(&self, index: &ImageIndex) {
+        let result = BlobRefs::load(self).and_then(|mut refs| {
+            refs.update(self, index)?;
+            refs.save(self);
+            Ok(())
+        });
+        if let Err(e) = result {
+            warn!("can't update {REFS}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{add_rootfs_delta, build_test_fs};
+    use crate::compression::Zstd;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_blob_tags() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "base")?;
+        let delta = tempdir()?;
+        fs::write(delta.path().join("file"), b"delta")?;
+        let (_, image) = add_rootfs_delta::<Zstd>(delta.path(), image, "delta", "base")?;
+
+        // the map is kept up to date with the builds
+        let saved: BlobRefs = serde_json::from_slice(&fs::read(dir.path().join(REFS))?)?;
+        let base = image.find_manifest_descriptor("base")?;
+        let delta = image.find_manifest_descriptor("delta")?;
+        assert!(saved.manifests.contains_key(base.digest().digest()));
+        assert!(saved.manifests.contains_key(delta.digest().digest()));
+
+        let base_rootfs = image.find_manifest("base")?.layers()[0]
+            .digest()
+            .digest()
+            .to_string();
+        let tags = image.blob_tags()?;
+        assert_eq!(tags[base_rootfs.as_str()], vec!["base", "delta"]);
+        assert_eq!(tags[delta.digest().digest()], vec!["delta"]);
+
+        image.delete_tag("delta")?;
+        let saved: BlobRefs = serde_json::from_slice(&fs::read(dir.path().join(REFS))?)?;
+        assert!(!saved.manifests.contains_key(delta.digest().digest()));
+
+        // a lost map is rebuilt
+        fs::remove_file(dir.path().join(REFS))?;
+        assert_eq!(image.blob_tags()?[base_rootfs.as_str()], vec!["base"]);
+        assert!(dir.path().join(REFS).exists());
+        Ok(())
+    }
+}
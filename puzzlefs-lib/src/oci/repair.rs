@@ -0,0 +1,131 @@
+// Healing a layout from a remote copy of its images, e.g. the registry they were pulled from: the
+// blobs fsck finds missing or corrupted are downloaded again, the rest stays as it is.
+
+use std::collections::HashSet;
+
+use super::blob_store::{fetch_blob, BlobSource};
+use super::{FsckProblem, Image};
+use crate::format::Result;
+
+/// What [`Image::repair`] did.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// the (hex) digests of the blobs downloaded again
+    pub repaired: Vec<String>,
+    /// what's still wrong with the layout, e.g. blobs the source doesn't have either
+    pub remaining: Vec<FsckProblem>,
+}
+
+impl Image {
+    /// Downloads the blobs [`Image::fsck`] finds missing or corrupted from `source`. Blobs which
+    /// were missing all along, like the chunks of lazily pulled images which weren't read yet,
+    /// are downloaded too.
+    pub fn repair(&self, source: &dyn BlobSource) -> Result<RepairReport> {
+        let mut report = RepairReport::default();
+        let mut failed = HashSet::new();
+        // a repaired rootfs or manifest may refer to more missing blobs, so until there's
+        // nothing left to fetch
+        loop {
+            let problems = self.fsck()?;
+            let mut repaired_any = false;
+            for problem in &problems {
+                let digest = match problem {
+                    FsckProblem::MissingBlob { digest, .. }
+                    | FsckProblem::DigestMismatch { digest, .. } => digest,
+                    _ => continue,
+                };
+                if failed.contains(digest) {
+                    continue;
+                }
+                match fetch_blob(source, self.2.as_ref(), digest) {
+                    Ok(()) => {
+                        report.repaired.push(digest.clone());
+                        repaired_any = true;
+                    }
+                    Err(e) => {
+                        log::warn!("can't fetch {digest}: {e}");
+                        failed.insert(digest.clone());
+                    }
+                }
+            }
+            if !repaired_any {
+                report.remaining = problems;
+                return Ok(report);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use sha2::{Digest, Sha256};
+    use std::fs;
+    use std::ops::Range;
+    use std::path::{Path, PathBuf};
+    use tempfile::tempdir;
+
+    // the blobs of another copy of the layout
+    struct DirSource(PathBuf);
+
+    impl DirSource {
+        fn read(&self, digest: &str) -> Result<Vec<u8>> {
+            let digest = digest.strip_prefix("sha256:").unwrap();
+            Ok(fs::read(self.0.join(digest))?)
+        }
+    }
+
+    impl BlobSource for DirSource {
+        fn blob_size(&self, digest: &str) -> Result<u64> {
+            Ok(self.read(digest)?.len() as u64)
+        }
+
+        fn get_blob_range(&self, digest: &str, range: Range<u64>) -> Result<Vec<u8>> {
+            Ok(self.read(digest)?[range.start as usize..range.end as usize].to_vec())
+        }
+    }
+
+    #[test]
+    fn test_repair() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let blobs = dir.path().join("blobs/sha256");
+        let remote = tempdir()?;
+        for entry in fs::read_dir(&blobs)? {
+            let entry = entry?;
+            fs::copy(entry.path(), remote.path().join(entry.file_name()))?;
+        }
+
+        // lose the rootfs, and with it sight of the chunks, and corrupt a chunk
+        let manifest = image.find_manifest("test")?;
+        let rootfs = manifest.layers()[0].digest().digest().to_string();
+        let chunk = manifest.layers()[1].digest().digest().to_string();
+        fs::remove_file(blobs.join(&rootfs))?;
+        fs::write(blobs.join(&chunk), b"bit rot")?;
+        let dangling = "0".repeat(64);
+        fs::write(blobs.join(&dangling), b"garbage")?;
+
+        let report = image.repair(&DirSource(remote.path().to_path_buf()))?;
+        assert_eq!(report.repaired.len(), 2);
+        assert!(report.repaired.contains(&rootfs));
+        assert!(report.repaired.contains(&chunk));
+        // the source doesn't have it
+        assert_eq!(
+            report.remaining,
+            vec![
+                FsckProblem::DigestMismatch {
+                    digest: dangling.clone(),
+                    found: hex::encode(Sha256::digest(b"garbage")),
+                },
+                FsckProblem::DanglingBlob(dangling),
+            ]
+        );
+        assert_eq!(
+            fs::read(blobs.join(&chunk))?,
+            fs::read(remote.path().join(&chunk))?
+        );
+        Ok(())
+    }
+}
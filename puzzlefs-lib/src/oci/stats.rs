@@ -0,0 +1,221 @@
+// What an image is made of: how much data it holds, how much room it takes in the layout and how
+// much of that it shares with the other tags, e.g. the base images it was built on.
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use super::refs::BlobRefs;
+use super::tags::tag_of;
+use super::Image;
+use crate::format::{InodeMode, Result};
+use crate::reader::{PuzzleFS, WalkPuzzleFS};
+
+// how many of the largest files the stats list
+const LARGEST_FILES: usize = 10;
+
+/// What [`Image::stats`] found out about an image.
+#[derive(Debug, Default, Serialize)]
+pub struct ImageStats {
+    /// the size of the files, as they read when the image is mounted
+    pub logical_size: u64,
+    /// the size of the blobs in the layout the image is made of, i.e. its manifest, config, rootfs
+    /// (and those of the images it's a delta on) and chunks, as stored: compressed or encrypted
+    pub stored_size: u64,
+    pub blob_count: u64,
+    /// the chunks the files are made of, holes excluded
+    pub chunk_count: u64,
+    /// the part of the blobs other tags in the layout use as well
+    pub shared_size: u64,
+    pub shared_blob_count: u64,
+    /// largest first
+    pub largest_files: Vec<FileSize>,
+    /// the rootfs blobs of the thin delta images it was built on, its parent first
+    pub parents: Vec<Parent>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct FileSize {
+    #[serde(serialize_with = "crate::format::serialize_escaped_path")]
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// A rootfs in the chain of parents of a thin delta image.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Parent {
+    pub digest: String,
+    /// the fs-verity digest the image above it records for it
+    pub verity: Option<String>,
+    /// the tags in the layout whose rootfs it is
+    pub tags: Vec<String>,
+}
+
+impl Image {
+    /// Works out the sizes of `tag` and what it shares with the other tags. Lazily pulled images
+    /// only count the blobs fetched so far as stored.
+    pub fn stats(self: &Arc<Self>, tag: &str) -> Result<ImageStats> {
+        let mut stats = ImageStats::default();
+        let mut pfs = PuzzleFS::open_shared(Arc::clone(self), &[(tag, None)])?;
+        for entry in WalkPuzzleFS::walk(&mut pfs)? {
+            let entry = entry?;
+            let InodeMode::File { chunks } = &entry.inode.mode else {
+                continue;
+            };
+            let size = entry.inode.file_len()?;
+            stats.logical_size += size;
+            stats.chunk_count += chunks.iter().filter(|c| c.blob.is_some()).count() as u64;
+            stats.largest_files.push(FileSize {
+                path: entry.path,
+                size,
+            });
+        }
+        stats
+            .largest_files
+            .sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+        stats.largest_files.truncate(LARGEST_FILES);
+        stats.parents = self.parents(tag)?;
+
+        let mut refs = BlobRefs::load(self)?;
+        let mut blobs = HashSet::new();
+        refs.mark(self, &self.find_manifest_descriptor(tag)?, &mut blobs)?;
+        let mut others = HashSet::new();
+        for descriptor in self.0.read_index()?.manifests() {
+            if tag_of(descriptor).is_some_and(|other| other != tag) {
+                refs.mark(self, descriptor, &mut others)?;
+            }
+        }
+        refs.save(self);
+
+        for digest in &blobs {
+            if !self.2.has_blob(digest)? {
+                continue;
+            }
+            let size = self.2.open_blob(digest)?.metadata()?.len();
+            stats.blob_count += 1;
+            stats.stored_size += size;
+            if others.contains(digest) {
+                stats.shared_blob_count += 1;
+                stats.shared_size += size;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// The chain of rootfs blobs the thin delta image `tag` was built on, its parent first.
+    pub fn parents(&self, tag: &str) -> Result<Vec<Parent>> {
+        let mut tags_by_rootfs = HashMap::<String, Vec<String>>::new();
+        for descriptor in self.0.read_index()?.manifests() {
+            let Some(other) = tag_of(descriptor) else {
+                continue;
+            };
+            if let Some(rootfs) = self.get_pfs_rootfs_descriptors(other)?.first() {
+                tags_by_rootfs
+                    .entry(rootfs.digest().digest().to_string())
+                    .or_default()
+                    .push(other.to_string());
+            }
+        }
+
+        let mut parents = Vec::new();
+        let mut rootfs = self.open_rootfs_blob(tag, None)?;
+        while let Some(parent) = rootfs.get_parent()? {
+            let digest = hex::encode(parent);
+            parents.push(Parent {
+                verity: rootfs.find_verity(&parent)?.map(hex::encode),
+                tags: tags_by_rootfs.remove(&digest).unwrap_or_default(),
+                digest,
+            });
+            rootfs = self.open_rootfs_blob_by_digest(&parent, None)?;
+        }
+        Ok(parents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{
+        add_rootfs_delta, add_rootfs_delta_with_options, build_test_fs, BuildOptions,
+    };
+    use crate::compression::Zstd;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_stats() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "base")?;
+        let delta = tempdir()?;
+        fs::copy(
+            "src/builder/test/test-1/SekienAkashita.jpg",
+            delta.path().join("SekienAkashita.jpg"),
+        )?;
+        fs::write(delta.path().join("file"), b"delta")?;
+        let (_, image) = add_rootfs_delta::<Zstd>(delta.path(), image, "delta", "base")?;
+
+        let base = image.stats("base")?;
+        assert_eq!(base.logical_size, 109466);
+        assert_eq!(base.largest_files.len(), 1);
+        assert_eq!(base.largest_files[0].path, Path::new("/SekienAkashita.jpg"));
+        assert!(base.chunk_count > 0);
+        assert!(base.stored_size > 0);
+
+        let delta = image.stats("delta")?;
+        assert_eq!(delta.logical_size, 109466 + 5);
+        assert_eq!(delta.chunk_count, base.chunk_count + 1);
+        assert_eq!(delta.largest_files[1].path, Path::new("/file"));
+        // the chunks of the picture are the base's
+        assert!(delta.shared_blob_count >= base.chunk_count);
+        assert!(delta.shared_blob_count < delta.blob_count);
+        assert_eq!(base.shared_blob_count, delta.shared_blob_count);
+        assert!(delta.parents.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parents() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        fs::write(rootfs.join("a"), b"a")?;
+        let image = Image::new(&dir.path().join("oci"))?;
+        build_test_fs(&rootfs, &image, "base")?;
+
+        let options = BuildOptions {
+            thin_delta: true,
+            ..Default::default()
+        };
+        fs::write(rootfs.join("b"), b"b")?;
+        add_rootfs_delta_with_options::<Zstd>(&rootfs, image, "delta", "base", &options)?;
+        fs::write(rootfs.join("c"), b"c")?;
+        let image = Image::open(&dir.path().join("oci"))?;
+        let (_, image, _) =
+            add_rootfs_delta_with_options::<Zstd>(&rootfs, image, "delta2", "delta", &options)?;
+
+        let rootfs_digest = |tag| -> anyhow::Result<String> {
+            Ok(image.get_pfs_rootfs_descriptors(tag)?[0]
+                .digest()
+                .digest()
+                .to_string())
+        };
+        let parents = image.stats("delta2")?.parents;
+        assert_eq!(
+            parents
+                .iter()
+                .map(|p| (p.digest.clone(), p.tags.clone()))
+                .collect::<Vec<_>>(),
+            [
+                (rootfs_digest("delta")?, vec!["delta".to_string()]),
+                (rootfs_digest("base")?, vec!["base".to_string()]),
+            ]
+        );
+        assert!(parents.iter().all(|p| p.verity.is_some()));
+        assert!(image.parents("base")?.is_empty());
+        Ok(())
+    }
+}
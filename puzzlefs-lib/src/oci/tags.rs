@@ -0,0 +1,115 @@
+// Managing the tags of a layout, i.e. the `org.opencontainers.image.ref.name` annotations of the
+// manifests in the index. Every change rewrites the index under the layout lock and renames it
+// into place, so concurrent builds and readers never see half of it.
+
+use std::backtrace::Backtrace;
+
+use ocidir::oci_spec::image::ANNOTATION_REF_NAME;
+
+use super::{Descriptor, Image};
+use crate::format::{Result, WireFormatError};
+
+impl Image {
+    /// The tags in the layout, in the order of the index.
+    pub fn tags(&self) -> Result<Vec<String>> {
+        Ok(self
+            .0
+            .read_index()?
+            .manifests()
+            .iter()
+            .filter_map(|d| tag_of(d).map(str::to_string))
+            .collect())
+    }
+
+    /// Removes `tag` from the layout, along with the referrers of its manifest unless it's still
+    /// in the index otherwise. The blobs stay until [`Image::gc`] collects them.
+    pub fn delete_tag(&self, tag: &str) -> Result<()> {
+        let _lock = self.lock_layout()?;
+        let mut index = self.0.read_index()?;
+        let manifests = index
+            .manifests()
+            .iter()
+            .filter(|d| tag_of(d) != Some(tag))
+            .cloned()
+            .collect::<Vec<_>>();
+        if manifests.len() == index.manifests().len() {
+            return Err(WireFormatError::MissingManifest(
+                tag.to_string(),
+                Backtrace::capture(),
+            ));
+        }
+        index.set_manifests(self.drop_dangling_referrers(manifests)?);
+        self.write_index(&index)
+    }
+
+    /// Tags the manifest tagged `tag` as `new_tag` too, replacing whatever `new_tag` was before.
+    pub fn retag(&self, tag: &str, new_tag: &str) -> Result<()> {
+        let _lock = self.lock_layout()?;
+        let mut index = self.0.read_index()?;
+        let mut tagged = index
+            .manifests()
+            .iter()
+            .find(|d| tag_of(d) == Some(tag))
+            .cloned()
+            .ok_or_else(|| {
+                WireFormatError::MissingManifest(tag.to_string(), Backtrace::capture())
+            })?;
+        let mut annotations = tagged.annotations().clone().unwrap_or_default();
+        annotations.insert(ANNOTATION_REF_NAME.to_string(), new_tag.to_string());
+        tagged.set_annotations(Some(annotations));
+
+        let mut manifests = index
+            .manifests()
+            .iter()
+            .filter(|d| tag_of(d) != Some(new_tag))
+            .cloned()
+            .collect::<Vec<_>>();
+        manifests.push(tagged);
+        index.set_manifests(self.drop_dangling_referrers(manifests)?);
+        self.write_index(&index)
+    }
+}
+
+pub(super) fn tag_of(descriptor: &Descriptor) -> Option<&str> {
+    descriptor
+        .annotations()
+        .as_ref()?
+        .get(ANNOTATION_REF_NAME)
+        .map(String::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use crate::reader::PuzzleFS;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_tags() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        assert_eq!(image.tags()?, ["test"]);
+
+        image.retag("test", "latest")?;
+        assert_eq!(image.tags()?, ["test", "latest"]);
+        assert_eq!(
+            image.manifest_digest("test")?,
+            image.manifest_digest("latest")?
+        );
+        // the referrers still have their subject
+        image.delete_tag("test")?;
+        assert_eq!(image.tags()?, ["latest"]);
+        assert!(image.verity_digests("latest")?.is_some());
+        assert!(image.delete_tag("test").is_err());
+        assert!(image.retag("test", "other").is_err());
+
+        PuzzleFS::open(Image::open(dir.path())?, "latest", None)?;
+        image.delete_tag("latest")?;
+        assert!(image.tags()?.is_empty());
+        assert!(image.get_index()?.manifests().is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,323 @@
+// Images built with older manifest versions. Their rootfs is converted to the current version in
+// memory whenever it's opened, so they stay mountable, and Image::upgrade rewrites them in the
+// current version once and for all.
+//
+// Version 2 kept each metadata (InodeVector) in a blob of its own, which the rootfs referred to,
+// instead of in the rootfs itself. Version 3 is version 4 without the feature flags, so its
+// images are read as they are; upgrading them records their features. There's no reader for
+// version 1 images left.
+
+use std::backtrace::Backtrace;
+use std::io::{self, Read, Seek};
+use std::sync::Arc;
+
+use super::media_types::{self, is_rootfs, VERITY_ROOT_HASH_ANNOTATION};
+use super::{open_blob, Image};
+use crate::builder::serialize_metadata;
+use crate::compression::Noop;
+use crate::format::{
+    DigestAlgorithm, Features, Result, Rootfs, RootfsReader, RootfsV2, WireFormatError,
+    SHA256_BLOCK_SIZE,
+};
+use crate::reader::{
+    check_manifest_version, PUZZLEFS_IMAGE_MANIFEST_VERSION, UNFLAGGED_MANIFEST_VERSION,
+};
+
+/// The oldest manifest version of the images which can still be read, see [`Image::upgrade`].
+pub const OLDEST_READABLE_MANIFEST_VERSION: u64 = 2;
+
+impl Image {
+    // Reads a rootfs blob of any readable version, converting older ones to the current version.
+    // Also returns the (hex) digests of the metadata blobs, the ones of version 2 rootfs or the
+    // shards of sharded rootfs, which are referred to on top of the chunks. `verified` tells
+    // whether `file` was opened with its fs-verity digest checked, in which case the metadata
+    // blobs are checked too.
+    pub(super) fn read_rootfs(
+        &self,
+        file: cap_std::fs::File,
+        verified: bool,
+    ) -> Result<(RootfsReader, Vec<String>)> {
+        let rootfs = RootfsReader::open(file.try_clone()?)?.with_limits(self.6);
+        match rootfs.get_manifest_version()? {
+            UNFLAGGED_MANIFEST_VERSION | PUZZLEFS_IMAGE_MANIFEST_VERSION => {
+                check_manifest_version(&rootfs)?;
+                let shards = rootfs.shard_digests()?.iter().map(hex::encode).collect();
+                let blobs = Arc::clone(&self.2);
+                let open = move |digest: &[u8; SHA256_BLOCK_SIZE],
+                                 verity: Option<&[u8]>|
+                      -> Result<cap_std::fs::File> {
+                    open_blob(&*blobs, &hex::encode(digest), verity)
+                };
+                Ok((rootfs.with_shards(Box::new(open), verified), shards))
+            }
+            2 => {
+                let old = RootfsV2::open(file)?;
+                let metadatas = old
+                    .metadatas
+                    .iter()
+                    .map(|blob| hex::encode(blob.digest))
+                    .collect();
+                let rootfs = self.convert_v2(old, verified)?;
+                let rootfs =
+                    RootfsReader::from_bytes(&serialize_metadata(rootfs)?)?.with_limits(self.6);
+                Ok((rootfs, metadatas))
+            }
+            version => Err(WireFormatError::InvalidImageVersion(
+                format!(
+                    "got {version}, expected {OLDEST_READABLE_MANIFEST_VERSION} to {PUZZLEFS_IMAGE_MANIFEST_VERSION}"
+                ),
+                Backtrace::capture(),
+            )),
+        }
+    }
+
+    fn convert_v2(&self, old: RootfsV2, verified: bool) -> Result<Rootfs> {
+        let mut fs_verity_data = old.fs_verity_data;
+        let mut metadatas = Vec::new();
+        for blob in &old.metadatas {
+            // the metadata blobs aren't referred to anymore once converted
+            let verity = fs_verity_data.remove(&blob.digest);
+            let verity = match verity {
+                Some(verity) if verified => Some(verity),
+                None if verified => {
+                    return Err(WireFormatError::InvalidFsVerityData(
+                        format!(
+                            "missing verity data for metadata blob {}",
+                            hex::encode(blob.digest)
+                        ),
+                        Backtrace::capture(),
+                    ))
+                }
+                _ => None,
+            };
+            let mut data = Vec::new();
+            let mut reader = self.open_blob_ref(blob, verity.as_ref().map(|v| &v[..]))?;
+            reader.seek(io::SeekFrom::Start(blob.offset))?;
+            reader.read_to_end(&mut data)?;
+            metadatas.push(RootfsV2::read_metadata(&data)?);
+        }
+
+        Ok(Rootfs {
+            features: Features::used_by(&metadatas),
+            metadatas,
+            fs_verity_data,
+            manifest_version: PUZZLEFS_IMAGE_MANIFEST_VERSION,
+            parent: None,
+            digest_algorithm: DigestAlgorithm::default(),
+        })
+    }
+
+    /// Rewrites the image `tag` in the current manifest version if it was built with an older
+    /// one, returning whether it was. The upgraded image gets a new manifest, so it has to be
+    /// signed again, and the blobs only the old one refers to are left for [`Image::gc`].
+    pub fn upgrade(&self, tag: &str) -> Result<bool> {
+        let mut manifest = self.find_manifest(tag)?;
+        let old_descriptor = manifest.layers()[0].clone();
+        if !is_rootfs(old_descriptor.media_type()) {
+            return Err(WireFormatError::MissingRootfs(Backtrace::capture()));
+        }
+        let old_digest = old_descriptor.digest().digest().to_string();
+        let file = self.open_raw_blob(&old_digest, None)?;
+        if RootfsReader::open(file.try_clone()?)?.get_manifest_version()?
+            == PUZZLEFS_IMAGE_MANIFEST_VERSION
+        {
+            return Ok(false);
+        }
+
+        let (rootfs, metadatas) = self.read_rootfs(file, false)?;
+        let mut rootfs = Rootfs::try_from(rootfs)?;
+        rootfs.manifest_version = PUZZLEFS_IMAGE_MANIFEST_VERSION;
+        rootfs.features = Features::used_by(&rootfs.metadatas);
+        if rootfs.parent.is_some() {
+            rootfs.features = rootfs.features | Features::THIN_DELTA;
+        }
+        manifest.layers_mut().retain(|layer| {
+            let digest = layer.digest().digest();
+            digest != old_digest && !metadatas.iter().any(|m| m == digest)
+        });
+        self.put_blob::<Noop>(
+            &serialize_metadata(rootfs)?,
+            &mut manifest,
+            media_types::Rootfs {},
+        )?;
+        // the rootfs is the first layer; it keeps the annotations of the old one but its verity
+        let rootfs_descriptor = &mut manifest.layers_mut()[0];
+        let mut annotations = old_descriptor.annotations().clone().unwrap_or_default();
+        annotations.remove(VERITY_ROOT_HASH_ANNOTATION);
+        annotations.extend(rootfs_descriptor.annotations().clone().unwrap_or_default());
+        rootfs_descriptor.set_annotations(Some(annotations));
+        self.insert_manifest(manifest, tag)?;
+        self.attach_verity(tag)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use crate::format::{BlobRef, InodeVector, SHA256_BLOCK_SIZE};
+    use crate::metadata_capnp;
+    use crate::reader::{PuzzleFS, WalkPuzzleFS};
+    use std::path::{Path, PathBuf};
+    use tempfile::tempdir;
+
+    fn serialize(message: &capnp::message::Builder<capnp::message::HeapAllocator>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        capnp::serialize::write_message(&mut buf, message).unwrap();
+        buf
+    }
+
+    // Stores the image `tag` as `old`, the way version 2 did.
+    fn downgrade(image: &Image, tag: &str, old: &str) -> anyhow::Result<()> {
+        let rootfs = Rootfs::try_from(image.open_rootfs_blob(tag, None)?)?;
+        let mut manifest = image.find_manifest(tag)?;
+        manifest.layers_mut().remove(0);
+
+        let mut fs_verity_data = rootfs.fs_verity_data.clone();
+        let mut metadatas = Vec::new();
+        for inodes in &rootfs.metadatas {
+            let mut message = capnp::message::Builder::new_default();
+            let mut builder = message.init_root::<metadata_capnp::inode_vector::Builder<'_>>();
+            InodeVector::fill_capnp(inodes, &mut builder, false)?;
+            let (descriptor, verity, _) = image.put_blob::<Noop>(
+                &serialize(&message),
+                &mut manifest,
+                media_types::Chunk {},
+            )?;
+            let mut digest = [0; SHA256_BLOCK_SIZE];
+            hex::decode_to_slice(descriptor.digest().digest(), &mut digest)?;
+            fs_verity_data.insert(digest, verity);
+            metadatas.push(BlobRef {
+                digest,
+                offset: 0,
+                compressed: false,
+                algorithm: Default::default(),
+                encryption: None,
+            });
+        }
+
+        let mut message = capnp::message::Builder::new_default();
+        let mut builder = message.init_root::<metadata_capnp::rootfs_v2::Builder<'_>>();
+        builder.set_manifest_version(2);
+        let mut capnp_metadatas = builder.reborrow().init_metadatas(metadatas.len() as u32);
+        for (i, metadata) in metadatas.iter().enumerate() {
+            metadata.fill_capnp(&mut capnp_metadatas.reborrow().get(i as u32));
+        }
+        let mut capnp_verities = builder.init_fs_verity_data(fs_verity_data.len() as u32);
+        for (i, (digest, verity)) in fs_verity_data.iter().enumerate() {
+            let mut capnp_verity = capnp_verities.reborrow().get(i as u32);
+            capnp_verity.set_digest(digest);
+            capnp_verity.set_verity(verity);
+        }
+        image.put_blob::<Noop>(&serialize(&message), &mut manifest, media_types::Rootfs {})?;
+        image.insert_manifest(manifest, old)?;
+        Ok(())
+    }
+
+    // Stores the image `tag` as `old`, the way version 3 did.
+    fn downgrade_v3(image: &Image, tag: &str, old: &str) -> anyhow::Result<()> {
+        let mut rootfs = Rootfs::try_from(image.open_rootfs_blob(tag, None)?)?;
+        rootfs.manifest_version = UNFLAGGED_MANIFEST_VERSION;
+        rootfs.features = Features::empty();
+        let mut manifest = image.find_manifest(tag)?;
+        manifest.layers_mut().remove(0);
+        image.put_blob::<Noop>(
+            &serialize_metadata(rootfs)?,
+            &mut manifest,
+            media_types::Rootfs {},
+        )?;
+        image.insert_manifest(manifest, old)?;
+        Ok(())
+    }
+
+    fn paths(image: Image, tag: &str) -> anyhow::Result<Vec<PathBuf>> {
+        let mut pfs = PuzzleFS::open(image, tag, None)?;
+        let mut paths = WalkPuzzleFS::walk(&mut pfs)?
+            .map(|entry| Ok(entry?.path))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        paths.sort();
+        Ok(paths)
+    }
+
+    #[test]
+    fn test_upgrade() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        downgrade(&image, "test", "old")?;
+        let expected = paths(Image::open(dir.path())?, "test")?;
+
+        // old images can be read as they are
+        assert_eq!(paths(Image::open(dir.path())?, "old")?, expected);
+        assert_eq!(image.fsck()?, Vec::new());
+
+        assert!(image.upgrade("old")?);
+        let rootfs = RootfsReader::open(image.get_pfs_rootfs("old", None)?)?;
+        assert_eq!(
+            rootfs.get_manifest_version()?,
+            PUZZLEFS_IMAGE_MANIFEST_VERSION
+        );
+        assert!(!image.upgrade("old")?);
+        assert!(!image.upgrade("test")?);
+
+        // the metadata blobs are gone along with the old manifest
+        image.delete_tag("test")?;
+        image.gc(false)?;
+        assert_eq!(image.fsck()?, Vec::new());
+        assert_eq!(paths(Image::open(dir.path())?, "old")?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_unflagged() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let rootfs_dir = dir.path().join("rootfs");
+        std::fs::create_dir(&rootfs_dir)?;
+        std::fs::write(rootfs_dir.join("a"), b"linked")?;
+        std::fs::hard_link(rootfs_dir.join("a"), rootfs_dir.join("b"))?;
+        build_test_fs(&rootfs_dir, &image, "test")?;
+        downgrade_v3(&image, "test", "old")?;
+        let expected = paths(Image::open(dir.path())?, "test")?;
+
+        // version 3 images don't record their features, but they are read all the same
+        assert_eq!(paths(Image::open(dir.path())?, "old")?, expected);
+
+        assert!(image.upgrade("old")?);
+        let rootfs = RootfsReader::open(image.get_pfs_rootfs("old", None)?)?;
+        assert_eq!(
+            rootfs.get_manifest_version()?,
+            PUZZLEFS_IMAGE_MANIFEST_VERSION
+        );
+        assert_eq!(rootfs.get_features()?, Features::HARDLINKS);
+        assert_eq!(paths(Image::open(dir.path())?, "old")?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsupported_features() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let mut rootfs = Rootfs::try_from(image.open_rootfs_blob("test", None)?)?;
+        rootfs.features = rootfs.features | Features::from_bits(1 << 63);
+        let mut manifest = image.find_manifest("test")?;
+        manifest.layers_mut().remove(0);
+        image.put_blob::<Noop>(
+            &serialize_metadata(rootfs)?,
+            &mut manifest,
+            media_types::Rootfs {},
+        )?;
+        image.insert_manifest(manifest, "future")?;
+
+        match PuzzleFS::open(Image::open(dir.path())?, "future", None) {
+            Err(WireFormatError::UnsupportedFeature { flag, .. }) => {
+                assert_eq!(flag, Features::from_bits(1 << 63))
+            }
+            Err(e) => panic!("unexpected {e}"),
+            Ok(_) => panic!("opened an image with unsupported features"),
+        }
+        Ok(())
+    }
+}
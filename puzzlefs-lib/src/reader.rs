@@ -1,20 +1,29 @@
 extern crate fuser as fuse_ffi;
 
 use std::path::Path;
+use std::time::Duration;
 
 use crate::format::Result;
 use crate::oci::Image;
 
+mod cancellation;
+pub use cancellation::CancellationToken;
+
 mod puzzlefs;
+pub(crate) use puzzlefs::check_manifest_version;
+pub(crate) use puzzlefs::FileReader;
 pub use puzzlefs::PuzzleFS;
-pub use puzzlefs::PUZZLEFS_IMAGE_MANIFEST_VERSION;
+pub use puzzlefs::{PUZZLEFS_IMAGE_MANIFEST_VERSION, UNFLAGGED_MANIFEST_VERSION};
 
 pub mod fuse;
 pub use fuse::Fuse;
 
 mod walk;
 use fuse::PipeDescriptor;
-pub use walk::WalkPuzzleFS;
+pub use walk::{DirEntry, WalkPuzzleFS};
+
+mod verify;
+pub use verify::{verify_image, VerifyResult, VerifyTarget};
 
 // copied from the fuser function 'MountOption::from_str' because it's not exported
 fn mount_option_from_str(s: &str) -> fuse_ffi::MountOption {
@@ -49,9 +58,11 @@ pub fn mount<T: AsRef<str>>(
     options: &[T],
     init_notify: Option<PipeDescriptor>,
     manifest_verity: Option<&[u8]>,
+    read_timeout: Option<Duration>,
 ) -> Result<()> {
     let pfs = PuzzleFS::open(image, tag, manifest_verity)?;
-    let fuse = Fuse::new(pfs, None, init_notify);
+    let mut fuse = Fuse::new(pfs, None, init_notify);
+    fuse.set_read_timeout(read_timeout);
     fuse_ffi::mount2(
         fuse,
         mountpoint,
@@ -71,9 +82,11 @@ pub fn spawn_mount<T: AsRef<str>>(
     init_notify: Option<PipeDescriptor>,
     sender: Option<std::sync::mpsc::Sender<()>>,
     manifest_verity: Option<&[u8]>,
+    read_timeout: Option<Duration>,
 ) -> Result<fuse_ffi::BackgroundSession> {
     let pfs = PuzzleFS::open(image, tag, manifest_verity)?;
-    let fuse = Fuse::new(pfs, sender, init_notify);
+    let mut fuse = Fuse::new(pfs, sender, init_notify);
+    fuse.set_read_timeout(read_timeout);
     Ok(fuse_ffi::spawn_mount2(
         fuse,
         mountpoint,
@@ -0,0 +1,88 @@
+use nix::errno::Errno;
+use std::cmp::min;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::format::{Result, WireFormatError};
+
+// how much of a chunk is read between two checks of the token
+const CHECK_SIZE: usize = 64 * 1024;
+
+/// A deadline and/or cancellation flag for read operations. Reads check it before touching each
+/// chunk blob and while reading it, so a read against slow or hung blob storage fails with
+/// ETIMEDOUT (or ECANCELED if it was cancelled explicitly) instead of blocking forever; the
+/// blocking reads themselves are bounded by the timeouts of the blob source, see
+/// [`crate::registry::RegistryOptions::timeout`]. Clones share the cancellation flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_deadline(deadline: Instant) -> Self {
+        CancellationToken {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            deadline: Some(deadline),
+        }
+    }
+
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self::with_deadline(Instant::now() + timeout)
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(WireFormatError::from_errno(Errno::ECANCELED));
+        }
+
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return Err(WireFormatError::from_errno(Errno::ETIMEDOUT));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Wraps `inner` so that it's read in pieces, checking the token before each of them.
+    pub(crate) fn reader<R: Read>(&self, inner: R) -> CancellableReader<'_, R> {
+        CancellableReader {
+            inner,
+            cancel: self,
+        }
+    }
+}
+
+pub(crate) struct CancellableReader<'a, R> {
+    inner: R,
+    cancel: &'a CancellationToken,
+}
+
+impl<R: Read> Read for CancellableReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.cancel.check().is_err() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        let len = min(buf.len(), CHECK_SIZE);
+        self.inner.read(&mut buf[..len])
+    }
+}
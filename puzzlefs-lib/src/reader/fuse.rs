@@ -14,8 +14,8 @@ use std::path::{Path, PathBuf};
 use std::thread;
 
 use fuser::{
-    FileAttr, FileType, Filesystem, KernelConfig, ReplyData, ReplyEntry, ReplyOpen, Request,
-    TimeOrNow,
+    consts, FileAttr, FileType, Filesystem, KernelConfig, ReplyData, ReplyEntry, ReplyOpen,
+    Request, TimeOrNow,
 };
 use nix::errno::Errno;
 use nix::fcntl::OFlag;
@@ -24,6 +24,7 @@ use std::time::{Duration, SystemTime};
 use crate::format::{DirEnt, Inode, InodeMode, Result, WireFormatError};
 
 use super::puzzlefs::{file_read, PuzzleFS};
+use super::CancellationToken;
 
 pub enum PipeDescriptor {
     UnnamedPipe(PipeWriter),
@@ -34,6 +35,8 @@ pub struct Fuse {
     pfs: PuzzleFS,
     sender: Option<std::sync::mpsc::Sender<()>>,
     init_notify: Option<PipeDescriptor>,
+    // reads taking longer than this fail with ETIMEDOUT instead of hanging the caller
+    read_timeout: Option<Duration>,
     // TODO: LRU cache inodes or something. I had problems fiddling with the borrow checker for the
     // cache, so for now we just do each lookup every time.
 }
@@ -51,6 +54,11 @@ fn mode_to_fuse_type(inode: &Inode) -> Result<FileType> {
     })
 }
 
+// the kernel decodes the rdev of FUSE attributes with new_decode_dev()
+fn encode_rdev(major: u64, minor: u64) -> u32 {
+    ((minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)) as u32
+}
+
 impl Fuse {
     pub fn new(
         pfs: PuzzleFS,
@@ -61,12 +69,19 @@ impl Fuse {
             pfs,
             sender,
             init_notify,
+            read_timeout: None,
         }
     }
 
+    pub fn set_read_timeout(&mut self, read_timeout: Option<Duration>) {
+        self.read_timeout = read_timeout;
+    }
+
     fn _lookup(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr> {
-        let dir = self.pfs.find_inode(parent)?;
-        let ino = dir.dir_lookup(name.as_bytes())?;
+        let ino = self
+            .pfs
+            .lookup_name(parent, name.as_bytes())?
+            .ok_or_else(|| WireFormatError::from_errno(Errno::ENOENT))?;
         self._getattr(ino)
     }
 
@@ -74,20 +89,28 @@ impl Fuse {
         let ic = self.pfs.find_inode(ino)?;
         let kind = mode_to_fuse_type(&ic)?;
         let len = ic.file_len().unwrap_or(0);
+        // images don't keep access times, the files are as old as their contents
+        let mtime = ic.mtime.map_or(SystemTime::UNIX_EPOCH, |t| t.system_time());
         Ok(FileAttr {
             ino: ic.ino,
             size: len,
             blocks: 0,
-            atime: SystemTime::UNIX_EPOCH,
-            mtime: SystemTime::UNIX_EPOCH,
-            ctime: SystemTime::UNIX_EPOCH,
+            atime: mtime,
+            mtime,
+            ctime: ic.ctime.map_or(mtime, |t| t.system_time()),
             crtime: SystemTime::UNIX_EPOCH,
             kind,
             perm: ic.permissions,
-            nlink: 0,
+            // images built before link counts were recorded have zero here
+            nlink: ic.nlink.max(1),
             uid: ic.uid,
             gid: ic.gid,
-            rdev: 0,
+            rdev: match ic.mode {
+                InodeMode::Chr { major, minor } | InodeMode::Blk { major, minor } => {
+                    encode_rdev(major, minor)
+                }
+                _ => 0,
+            },
             blksize: 0,
             flags: 0,
         })
@@ -113,12 +136,14 @@ impl Fuse {
     fn _read(&mut self, ino: u64, offset: u64, size: u32) -> Result<Vec<u8>> {
         let inode = self.pfs.find_inode(ino)?;
         let mut buf = vec![0_u8; size as usize];
+        let cancel = self.read_timeout.map(CancellationToken::with_timeout);
         let read = file_read(
             &self.pfs.oci,
             &inode,
             offset as usize,
             &mut buf,
-            &self.pfs.verity_data,
+            &self.pfs.verity_layers(ino)?,
+            cancel.as_ref(),
         )?;
         buf.truncate(read);
         Ok(buf)
@@ -130,7 +155,10 @@ impl Fuse {
         for (index, DirEnt { name, ino: ino_r }) in entries.iter().enumerate().skip(offset as usize)
         {
             let ino = *ino_r;
-            let inode = self.pfs.find_inode(ino)?;
+            // entries deleted in an upper layer are not shown
+            let Some(inode) = self.pfs.lookup_inode(ino)? else {
+                continue;
+            };
             let kind = mode_to_fuse_type(&inode)?;
 
             // if the buffer is full, let's skip the extra lookups
@@ -155,13 +183,13 @@ impl Fuse {
         }
     }
 
-    fn _listxattr(&mut self, ino: u64) -> Result<Vec<u8>> {
+    // the trusted xattrs are hidden from unprivileged callers, like the kernel does
+    fn _listxattr(&mut self, ino: u64, privileged: bool) -> Result<Vec<u8>> {
         let inode = self.pfs.find_inode(ino)?;
         let xattr_list = inode
             .additional
             .map(|add| {
-                add.xattrs
-                    .iter()
+                add.visible_xattrs(privileged)
                     .flat_map(|x| {
                         CString::new(x.key.as_slice())
                             .expect("xattr is a valid string")
@@ -175,14 +203,14 @@ impl Fuse {
         Ok(xattr_list)
     }
 
-    fn _getxattr(&mut self, ino: u64, name: &OsStr) -> Result<Vec<u8>> {
+    fn _getxattr(&mut self, ino: u64, name: &OsStr, privileged: bool) -> Result<Vec<u8>> {
         let inode = self.pfs.find_inode(ino)?;
         inode
             .additional
             .and_then(|add| {
                 add.xattrs
                     .into_iter()
-                    .find(|elem| elem.key == name.as_bytes())
+                    .find(|elem| elem.key == name.as_bytes() && elem.is_visible(privileged))
             })
             .map(|xattr| xattr.val)
             .ok_or_else(|| WireFormatError::from_errno(Errno::ENODATA))
@@ -204,8 +232,14 @@ impl Filesystem for Fuse {
     fn init(
         &mut self,
         _req: &Request<'_>,
-        _config: &mut KernelConfig,
+        config: &mut KernelConfig,
     ) -> std::result::Result<(), c_int> {
+        // Without this the kernel refuses the ACL xattrs instead of asking us for them. It also
+        // turns on default_permissions, so the kernel enforces the ACLs and file modes itself.
+        if let Err(unsupported) = config.add_capabilities(consts::FUSE_POSIX_ACL) {
+            warn!("kernel doesn't support POSIX ACLs over FUSE ({unsupported:#x})");
+        }
+
         if let Some(init_notify) = self.init_notify.take() {
             match init_notify {
                 PipeDescriptor::UnnamedPipe(mut pipe_writer) => {
@@ -608,13 +642,13 @@ impl Filesystem for Fuse {
 
     fn getxattr(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         name: &OsStr,
         size: u32,
         reply: fuser::ReplyXattr,
     ) {
-        match self._getxattr(ino, name) {
+        match self._getxattr(ino, name, req.uid() == 0) {
             Ok(xattr) => {
                 let xattr_len: u32 = xattr
                     .len()
@@ -635,8 +669,8 @@ impl Filesystem for Fuse {
         }
     }
 
-    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: fuser::ReplyXattr) {
-        match self._listxattr(ino) {
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: fuser::ReplyXattr) {
+        match self._listxattr(ino, req.uid() == 0) {
             Ok(xattr) => {
                 let xattr_len: u32 = xattr
                     .len()
@@ -683,7 +717,9 @@ mod tests {
     use tempfile::tempdir;
 
     use crate::builder::build_test_fs;
+    use crate::fsverity_helpers::get_fs_verity_digest;
     use crate::oci::Image;
+    use crate::reader::PuzzleFS;
 
     #[test]
     fn test_fuse() {
@@ -699,6 +735,7 @@ mod tests {
             None,
             None,
             None,
+            None,
         )
         .unwrap();
         let ents = fs::read_dir(mountpoint.path())
@@ -719,4 +756,42 @@ mod tests {
             "d9e749d9367fc908876749d6502eb212fee88c9a94892fb07da5ef3ba8bc39ed";
         assert_eq!(hex::encode(digest), FILE_DIGEST);
     }
+
+    #[test]
+    fn test_fuse_mixed_verity() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "upper")?;
+        let base_rootfs = tempdir()?;
+        fs::write(base_rootfs.path().join("base"), b"base")?;
+        build_test_fs(base_rootfs.path(), &image, "base")?;
+        let manifest_verity = |tag| -> anyhow::Result<_> {
+            let digest = image.manifest_digest(tag)?;
+            let manifest = fs::read(
+                dir.path()
+                    .join("blobs/sha256")
+                    .join(digest.trim_start_matches("sha256:")),
+            )?;
+            Ok(get_fs_verity_digest(&manifest)?)
+        };
+        let upper_verity = manifest_verity("upper")?;
+        let base_verity = manifest_verity("base")?;
+
+        // only one of the layers is verified, the chunks of the other one are read as they are
+        for tags in [
+            [("upper", None), ("base", Some(&base_verity[..]))],
+            [("upper", Some(&upper_verity[..])), ("base", None)],
+        ] {
+            let pfs = PuzzleFS::open_layers(Image::open(dir.path())?, &tags)?;
+            let mountpoint = tempdir()?;
+            let _bg =
+                fuser::spawn_mount2(super::Fuse::new(pfs, None, None), mountpoint.path(), &[])?;
+            let contents = fs::read(mountpoint.path().join("SekienAkashita.jpg"))?;
+            assert_eq!(
+                contents,
+                fs::read("src/builder/test/test-1/SekienAkashita.jpg")?
+            );
+        }
+        Ok(())
+    }
 }
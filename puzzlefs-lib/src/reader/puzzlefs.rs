@@ -1,24 +1,70 @@
 use nix::errno::Errno;
 use std::backtrace::Backtrace;
 use std::cmp::min;
+use std::collections::BTreeMap;
 use std::io;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Component, Path};
 use std::sync::Arc;
 
 use crate::format::{
-    DirEnt, Ino, Inode, InodeMode, Result, RootfsReader, VerityData, WireFormatError,
+    BlobRef, DirList, Ino, Inode, InodeMode, NameLookup, Result, RootfsReader, WireFormatError,
+    SHA256_BLOCK_SIZE,
 };
-use crate::oci::Image;
+use crate::fsverity_helpers::read_fs_verity_digest;
+use crate::oci::{Digest, Image};
+
+use super::CancellationToken;
+
+pub const PUZZLEFS_IMAGE_MANIFEST_VERSION: u64 = 4;
+/// The last manifest version without [`crate::format::Features`]; its images are read as they are, and rewritten
+/// with their features recorded by [`Image::upgrade`].
+pub const UNFLAGGED_MANIFEST_VERSION: u64 = 3;
+
+/// Fails for the rootfs which can't be read as they are, because they were built with another
+/// manifest version or rely on features this puzzlefs doesn't know about.
+pub(crate) fn check_manifest_version(rootfs: &RootfsReader) -> Result<()> {
+    match rootfs.get_manifest_version()? {
+        UNFLAGGED_MANIFEST_VERSION => Ok(()),
+        PUZZLEFS_IMAGE_MANIFEST_VERSION => rootfs.get_features()?.check_supported(),
+        version => Err(WireFormatError::InvalidImageVersion(
+            format!(
+                "got {version}, expected {UNFLAGGED_MANIFEST_VERSION} or {PUZZLEFS_IMAGE_MANIFEST_VERSION}"
+            ),
+            Backtrace::capture(),
+        )),
+    }
+}
+
+// Finds the fs-verity digest of a chunk in the rootfs blobs we trust. If verity checking was
+// requested, the chunk must be covered by one of them.
+fn find_chunk_verity(
+    verity_layers: &[&RootfsReader],
+    blob: &BlobRef,
+) -> Result<Option<[u8; SHA256_BLOCK_SIZE]>> {
+    if verity_layers.is_empty() {
+        return Ok(None);
+    }
 
-pub const PUZZLEFS_IMAGE_MANIFEST_VERSION: u64 = 3;
+    for rootfs in verity_layers {
+        if let Some(verity) = rootfs.find_verity(&blob.digest)? {
+            return Ok(Some(verity));
+        }
+    }
+
+    Err(WireFormatError::InvalidFsVerityData(
+        format!("missing verity data {}", Digest::new(&blob.digest)),
+        Backtrace::capture(),
+    ))
+}
 
 pub(crate) fn file_read(
     oci: &Image,
     inode: &Inode,
     offset: usize,
     data: &mut [u8],
-    verity_data: &Option<VerityData>,
+    verity_layers: &[&RootfsReader],
+    cancel: Option<&CancellationToken>,
 ) -> Result<usize> {
     let chunks = match &inode.mode {
         InodeMode::File { chunks } => chunks,
@@ -53,11 +99,16 @@ pub(crate) fn file_read(
         file_offset += addl_offset;
 
         // how many did we actually read?
+        let verity = match &chunk.blob {
+            Some(blob) => find_chunk_verity(verity_layers, blob)?,
+            None => None,
+        };
         let n = oci.fill_from_chunk(
-            chunk.blob,
+            chunk,
             addl_offset as u64,
             &mut data[start..finish],
-            verity_data,
+            verity.as_ref().map(|v| &v[..]),
+            cancel,
         )?;
         file_offset += n;
         buf_offset += n;
@@ -67,44 +118,202 @@ pub(crate) fn file_read(
     Ok(buf_offset)
 }
 
+struct Layer {
+    rootfs: RootfsReader,
+    // whether the image manifest of this layer was checked against a trusted fs-verity digest
+    verified: bool,
+}
+
+impl Layer {
+    fn new(rootfs: RootfsReader, verified: bool) -> Result<Self> {
+        check_manifest_version(&rootfs)?;
+        Ok(Layer { rootfs, verified })
+    }
+
+    // The rootfs this one is a delta on. Its fs-verity digest is part of our verity data, so a
+    // verified layer vouches for its parent.
+    fn open_parent(&self, oci: &Image) -> Result<Option<Layer>> {
+        let Some(parent) = self.rootfs.get_parent()? else {
+            return Ok(None);
+        };
+
+        let verity = if self.verified {
+            Some(self.rootfs.find_verity(&parent)?.ok_or_else(|| {
+                WireFormatError::InvalidFsVerityData(
+                    format!(
+                        "missing verity data for parent rootfs {}",
+                        hex::encode(parent)
+                    ),
+                    Backtrace::capture(),
+                )
+            })?)
+        } else {
+            None
+        };
+
+        let rootfs = oci.open_rootfs_blob_by_digest(&parent, verity.as_ref().map(|v| &v[..]))?;
+        Ok(Some(Layer::new(rootfs, self.verified)?))
+    }
+}
+
 pub struct PuzzleFS {
     pub oci: Arc<Image>,
-    rootfs: RootfsReader,
-    pub verity_data: Option<VerityData>,
+    // topmost layer first
+    layers: Vec<Layer>,
     pub manifest_verity: Option<Vec<u8>>,
+    annotations: BTreeMap<String, String>,
 }
 
 impl PuzzleFS {
     pub fn open(oci: Image, tag: &str, manifest_verity: Option<&[u8]>) -> Result<PuzzleFS> {
-        let rootfs = oci.open_rootfs_blob(tag, manifest_verity)?;
-
-        if rootfs.get_manifest_version()? != PUZZLEFS_IMAGE_MANIFEST_VERSION {
-            return Err(WireFormatError::InvalidImageVersion(
-                format!(
-                    "got {}, expected {}",
-                    rootfs.get_manifest_version()?,
-                    PUZZLEFS_IMAGE_MANIFEST_VERSION
-                ),
-                Backtrace::capture(),
-            ));
-        }
+        Self::open_layers(oci, &[(tag, manifest_verity)])
+    }
 
-        let verity_data = if manifest_verity.is_some() {
-            Some(rootfs.get_verity_data()?)
-        } else {
-            None
-        };
+    /// Opens a stack of puzzlefs images (given as tag and optional manifest verity pairs), topmost
+    /// layer first. Inodes are resolved top-down: the first layer that contains an inode wins and
+    /// a whiteout hides the inode in all the layers below it. Directories marked with look_below
+    /// are merged with the same directory from the layers below. Delta images are followed by the
+    /// chain of images they were built on.
+    pub fn open_layers(oci: Image, tags: &[(&str, Option<&[u8]>)]) -> Result<PuzzleFS> {
+        Self::open_shared(Arc::new(oci), tags)
+    }
+
+    /// Like [`PuzzleFS::open_layers`], for an image which is shared with its other users.
+    pub fn open_shared(oci: Arc<Image>, tags: &[(&str, Option<&[u8]>)]) -> Result<PuzzleFS> {
+        let (top, manifest_verity) = tags
+            .first()
+            .ok_or_else(|| WireFormatError::from_errno(Errno::EINVAL))?;
+
+        let mut layers = Vec::new();
+        for (tag, verity) in tags {
+            oci.check_key(tag)?;
+            oci.load_dictionaries(tag)?;
+            // checks the signature too, of the same manifest the rootfs is opened from
+            let mut layer = Layer::new(oci.open_rootfs_blob(tag, *verity)?, verity.is_some())?;
+            loop {
+                let parent = layer.open_parent(&oci)?;
+                layers.push(layer);
+                match parent {
+                    Some(parent) => layer = parent,
+                    None => break,
+                }
+            }
+        }
 
+        let annotations = oci.annotations(top)?;
         Ok(PuzzleFS {
-            oci: Arc::new(oci),
-            rootfs,
-            verity_data,
+            oci,
+            layers,
             manifest_verity: manifest_verity.map(|e| e.to_vec()),
+            annotations,
         })
     }
 
+    /// The custom annotations of the topmost image, see [`Image::annotations`].
+    pub fn annotations(&self) -> &BTreeMap<String, String> {
+        &self.annotations
+    }
+
     pub fn find_inode(&self, ino: u64) -> Result<Inode> {
-        self.rootfs.find_inode(ino)
+        self.lookup_inode(ino)?
+            .ok_or_else(|| WireFormatError::from_errno(Errno::ENOENT))
+    }
+
+    /// Like find_inode, but returns None for inodes which don't exist or which were deleted by a
+    /// whiteout in an upper layer.
+    pub fn lookup_inode(&self, ino: u64) -> Result<Option<Inode>> {
+        self.lookup_inode_in(ino, &self.layers)
+    }
+
+    /// The version of an inode the topmost image adds on top of the ones it was built on, None
+    /// if it leaves the inode as it is. Whiteouts are returned as they are.
+    pub fn delta_inode(&self, ino: u64) -> Result<Option<Inode>> {
+        self.layers[0].rootfs.get_delta_inode(ino)
+    }
+
+    /// Like lookup_inode, in the images the topmost one was built on.
+    pub fn base_inode(&self, ino: u64) -> Result<Option<Inode>> {
+        let (top, lower) = self.layers.split_first().unwrap();
+        match top.rootfs.get_base_inode(ino)? {
+            Some(inode) => self.resolve_inode(inode, lower),
+            None => self.lookup_inode_in(ino, lower),
+        }
+    }
+
+    fn lookup_inode_in(&self, ino: u64, layers: &[Layer]) -> Result<Option<Inode>> {
+        for (i, layer) in layers.iter().enumerate() {
+            if let Some(inode) = layer.rootfs.get_inode(ino)? {
+                return self.resolve_inode(inode, &layers[i + 1..]);
+            }
+        }
+
+        Ok(None)
+    }
+
+    // The inode as seen through the layers above `lower`, which it was found in.
+    fn resolve_inode(&self, mut inode: Inode, lower: &[Layer]) -> Result<Option<Inode>> {
+        match inode.mode {
+            InodeMode::Wht => return Ok(None),
+            InodeMode::Dir { ref mut dir_list } if dir_list.look_below => {
+                self.merge_lower_dirs(inode.ino, dir_list, lower)?
+            }
+            _ => {}
+        }
+
+        Ok(Some(inode))
+    }
+
+    fn merge_lower_dirs(&self, ino: Ino, dir_list: &mut DirList, lower: &[Layer]) -> Result<()> {
+        for layer in lower {
+            match layer.rootfs.get_inode(ino)? {
+                None => continue,
+                Some(Inode {
+                    mode:
+                        InodeMode::Dir {
+                            dir_list: lower_list,
+                        },
+                    ..
+                }) => {
+                    for entry in lower_list.entries {
+                        if !dir_list.entries.iter().any(|e| e.name == entry.name) {
+                            dir_list.entries.push(entry);
+                        }
+                    }
+                    if !lower_list.look_below {
+                        break;
+                    }
+                }
+                // a whiteout or a non-directory hides everything below it
+                Some(_) => break,
+            }
+        }
+
+        // keep the same ordering the builder uses so readdir offsets stay stable
+        dir_list.entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(())
+    }
+
+    // The rootfs whose verity data covers the chunks of inode `ino`: the one of the layer the
+    // inode is found in and the ones below it, if its manifest digest was given. The chunks of
+    // inodes of the other layers are read without checking them, so a stack only some of whose
+    // layers are verified can still be read. The verity data is loaded lazily from the rootfs as
+    // chunks are read.
+    pub(crate) fn verity_layers(&self, ino: Ino) -> Result<Vec<&RootfsReader>> {
+        for (i, layer) in self.layers.iter().enumerate() {
+            if layer.rootfs.get_inode(ino)?.is_none() {
+                continue;
+            }
+            if !layer.verified {
+                return Ok(Vec::new());
+            }
+            return Ok(self.layers[i..]
+                .iter()
+                .filter(|layer| layer.verified)
+                .map(|layer| &layer.rootfs)
+                .collect());
+        }
+
+        Ok(Vec::new())
     }
 
     // lookup performs a path-based lookup in this puzzlefs
@@ -113,34 +322,66 @@ impl PuzzleFS {
         if !matches!(components[0], Component::RootDir) {
             return Err(WireFormatError::from_errno(Errno::EINVAL));
         }
+        self.oci.limits().check_depth(components.len() - 1)?;
 
-        let mut cur = self.find_inode(1)?;
+        let mut ino = 1;
 
         // TODO: better path resolution with .. and such?
         for comp in components.into_iter().skip(1) {
             match comp {
-                Component::Normal(p) => {
-                    if let InodeMode::Dir { dir_list } = cur.mode {
-                        if let Some(DirEnt { ino, name: _ }) = dir_list
-                            .entries
-                            .into_iter()
-                            .find(|dir_entry| dir_entry.name == p.as_bytes())
-                        {
-                            cur = self.find_inode(ino)?;
-                            continue;
-                        }
-                    }
-                    return Ok(None);
-                }
+                Component::Normal(p) => match self.lookup_name(ino, p.as_bytes())? {
+                    Some(next) => ino = next,
+                    None => return Ok(None),
+                },
                 _ => return Err(WireFormatError::from_errno(Errno::EINVAL)),
             }
         }
 
-        Ok(Some(cur))
+        if ino == 1 {
+            return self.find_inode(1).map(Some);
+        }
+        self.lookup_inode(ino)
+    }
+
+    /// Looks up `name` in the directory `ino`, going through the layers the directory is merged
+    /// from, without deserializing the directories. Returns None if there's no such entry, or
+    /// `ino` isn't a directory.
+    pub fn lookup_name(&self, ino: Ino, name: &[u8]) -> Result<Option<Ino>> {
+        for layer in &self.layers {
+            match layer.rootfs.lookup_name(ino, name)? {
+                NameLookup::NoInode | NameLookup::Missing { look_below: true } => continue,
+                NameLookup::Found(ino) => return Ok(Some(ino)),
+                NameLookup::NotDir | NameLookup::Missing { look_below: false } => return Ok(None),
+            }
+        }
+        Ok(None)
     }
 
     pub fn max_inode(&self) -> Result<Ino> {
-        self.rootfs.max_inode()
+        self.layers.iter().try_fold(1, |max, layer| {
+            Ok(std::cmp::max(max, layer.rootfs.max_inode()?))
+        })
+    }
+
+    /// Checks the contents of a regular file against the fs-verity digest stored in its inode,
+    /// see [`crate::builder::BuildOptions::file_verity`]. Only the chunks of the file are read,
+    /// rather than the whole blobs they're in.
+    pub fn verify_file(&self, inode: &Inode) -> Result<()> {
+        let Some(expected) = inode.verity else {
+            return Err(WireFormatError::InvalidFsVerityData(
+                format!("inode {} has no fs-verity digest", inode.ino),
+                Backtrace::capture(),
+            ));
+        };
+        let found = read_fs_verity_digest(FileReader::new(&self.oci, inode)?)?;
+        if found != expected {
+            return Err(WireFormatError::DigestMismatch {
+                expected: Digest::new(&expected),
+                found: Digest::new(&found),
+                backtrace: Backtrace::capture(),
+            });
+        }
+        Ok(())
     }
 }
 
@@ -175,7 +416,8 @@ impl io::Read for FileReader<'_> {
             self.inode,
             self.offset,
             &mut buf[0..to_read],
-            &None,
+            &[],
+            None,
         )
         .map_err(|e| io::Error::from_raw_os_error(e.to_errno()))?;
         self.offset += read;
@@ -185,10 +427,17 @@ impl io::Read for FileReader<'_> {
 
 #[cfg(test)]
 mod tests {
+    use ocidir::oci_spec::image::Platform;
     use sha2::{Digest, Sha256};
+    use std::fs;
+    use std::io::Read;
     use tempfile::tempdir;
 
-    use crate::builder::build_test_fs;
+    use crate::builder::{build_test_fs, serialize_metadata};
+    use crate::compression::Noop;
+    use crate::format::{DigestAlgorithm, DirEnt, Features, Limits, Rootfs, VerityData};
+    use crate::oci::media_types;
+    use crate::reader::WalkPuzzleFS;
 
     use super::*;
 
@@ -213,6 +462,29 @@ mod tests {
         assert_eq!(pfs.max_inode().unwrap(), 2);
     }
 
+    #[test]
+    fn test_cancelled_read() {
+        let oci_dir = tempdir().unwrap();
+        let image = Image::new(oci_dir.path()).unwrap();
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test").unwrap();
+        let pfs = PuzzleFS::open(image, "test", None).unwrap();
+        let inode = pfs.find_inode(2).unwrap();
+        let mut buf = vec![0_u8; 4096];
+
+        let timed_out = CancellationToken::with_deadline(std::time::Instant::now());
+        let err = file_read(&pfs.oci, &inode, 0, &mut buf, &[], Some(&timed_out)).unwrap_err();
+        assert_eq!(err.to_errno(), Errno::ETIMEDOUT as i32);
+
+        let cancelled = CancellationToken::new();
+        cancelled.cancel();
+        let err = file_read(&pfs.oci, &inode, 0, &mut buf, &[], Some(&cancelled)).unwrap_err();
+        assert_eq!(err.to_errno(), Errno::ECANCELED as i32);
+
+        let token = CancellationToken::with_timeout(std::time::Duration::from_secs(3600));
+        let n = file_read(&pfs.oci, &inode, 0, &mut buf, &[], Some(&token)).unwrap();
+        assert_eq!(n, buf.len());
+    }
+
     #[test]
     fn test_path_lookup() {
         let oci_dir = tempdir().unwrap();
@@ -232,4 +504,157 @@ mod tests {
         pfs.lookup(Path::new("./invalid-path")).unwrap_err();
         pfs.lookup(Path::new("invalid-path")).unwrap_err();
     }
+
+    #[test]
+    fn test_limits() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("a/b/c"))?;
+        fs::write(rootfs.join("a-rather-long-name"), b"data")?;
+        std::os::unix::fs::symlink("a/b/c", rootfs.join("link"))?;
+        let oci_dir = dir.path().join("oci");
+        build_test_fs(&rootfs, &Image::new(&oci_dir)?, "test")?;
+
+        let open =
+            |limits| PuzzleFS::open(Image::open(&oci_dir)?.with_limits(limits), "test", None);
+        let short_names = Limits {
+            max_name_len: 10,
+            ..Default::default()
+        };
+        match open(short_names)?.find_inode(1) {
+            Err(WireFormatError::LimitExceeded(..)) => {}
+            other => panic!("unexpected {other:?}"),
+        }
+
+        let short_symlinks = Limits {
+            max_symlink_len: 4,
+            ..Default::default()
+        };
+        let pfs = open(short_symlinks)?;
+        assert!(pfs.lookup(Path::new("/a/b/c"))?.is_some());
+        assert!(matches!(
+            pfs.lookup(Path::new("/link")),
+            Err(WireFormatError::LimitExceeded(..))
+        ));
+
+        let shallow = Limits {
+            max_depth: 2,
+            ..Default::default()
+        };
+        let mut pfs = open(shallow)?;
+        assert!(pfs.lookup(Path::new("/a/b")).is_ok());
+        assert!(pfs.lookup(Path::new("/a/b/c")).is_err());
+        assert!(WalkPuzzleFS::walk(&mut pfs)?.any(|entry| entry.is_err()));
+
+        let mut pfs = open(Limits::UNLIMITED)?;
+        assert_eq!(WalkPuzzleFS::walk(&mut pfs)?.count(), 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lazy_verity_lookup() {
+        let oci_dir = tempdir().unwrap();
+        let image = Image::new(oci_dir.path()).unwrap();
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test").unwrap();
+        let pfs = PuzzleFS::open(image, "test", None).unwrap();
+
+        let verity_data = pfs.layers[0].rootfs.get_verity_data().unwrap();
+        assert!(!verity_data.is_empty());
+        for (digest, verity) in &verity_data {
+            assert_eq!(
+                pfs.layers[0].rootfs.find_verity(digest).unwrap(),
+                Some(*verity)
+            );
+            // the second lookup is served from the cache
+            assert_eq!(
+                pfs.layers[0].rootfs.find_verity(digest).unwrap(),
+                Some(*verity)
+            );
+        }
+        assert_eq!(pfs.layers[0].rootfs.find_verity(&[0; 32]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_layered_lookup() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        fs::write(rootfs.join("bar"), b"bar")?;
+        fs::write(rootfs.join("foo"), b"foo")?;
+
+        let image = Image::new(&dir.path().join("oci"))?;
+        build_test_fs(&rootfs, &image, "base")?;
+
+        // an upper layer which only carries its own changes: it deletes "bar" (ino 2) and adds
+        // the "qux" directory, everything else is looked up in the base layer
+        let new_dir = |ino, look_below, entries| Inode {
+            ino,
+            mode: InodeMode::Dir {
+                dir_list: DirList {
+                    look_below,
+                    entries,
+                },
+            },
+            uid: 0,
+            gid: 0,
+            permissions: 0o755,
+            additional: None,
+            nlink: 1,
+            mtime: None,
+            ctime: None,
+            digest: None,
+            verity: None,
+        };
+        let upper = Rootfs {
+            metadatas: vec![vec![
+                new_dir(
+                    1,
+                    true,
+                    vec![
+                        DirEnt {
+                            ino: 2,
+                            name: b"bar".to_vec(),
+                        },
+                        DirEnt {
+                            ino: 4,
+                            name: b"qux".to_vec(),
+                        },
+                    ],
+                ),
+                Inode::new_whiteout(2),
+                new_dir(4, false, Vec::new()),
+            ]],
+            fs_verity_data: VerityData::new(),
+            manifest_version: PUZZLEFS_IMAGE_MANIFEST_VERSION,
+            parent: None,
+            features: Features::empty(),
+            digest_algorithm: DigestAlgorithm::default(),
+        };
+        let mut image_manifest = image.get_empty_manifest()?;
+        image.put_blob::<Noop>(
+            &serialize_metadata(upper)?,
+            &mut image_manifest,
+            media_types::Rootfs {},
+        )?;
+        image
+            .0
+            .insert_manifest(image_manifest, Some("upper"), Platform::default())?;
+
+        let mut pfs = PuzzleFS::open_layers(image, &[("upper", None), ("base", None)])?;
+        assert!(pfs.lookup(Path::new("/bar"))?.is_none());
+        assert_eq!(pfs.lookup(Path::new("/foo"))?.unwrap().ino, 3);
+        assert_eq!(pfs.lookup(Path::new("/qux"))?.unwrap().ino, 4);
+        assert_eq!(pfs.max_inode()?, 4);
+
+        let foo = pfs.find_inode(3)?;
+        let mut contents = String::new();
+        FileReader::new(&pfs.oci, &foo)?.read_to_string(&mut contents)?;
+        assert_eq!(contents, "foo");
+
+        let paths = WalkPuzzleFS::walk(&mut pfs)?
+            .map(|de| Ok(de?.path.to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(paths, vec!["/", "/foo", "/qux"]);
+        Ok(())
+    }
 }
@@ -0,0 +1,318 @@
+// Deep verification of one image, e.g. as a gate check before deploying it: the manifest against
+// its digest and, if given, the fs-verity digest it's trusted by; the rootfs blobs against the
+// manifest; every blob holding chunks of the image against its name; and the contents of every
+// regular file, read through its chunks the way a mount reads them, against the chunk checksums
+// and the digests the image stores for the file. Unlike [`Image::fsck`], which checks everything
+// in a layout, only what the image uses is checked, and everything is hashed here rather than
+// measured by the kernel, so images can be verified on filesystems without fs-verity.
+
+use std::backtrace::Backtrace;
+use std::cmp::min;
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use fs_verity::FsVeritySha256;
+use rayon::prelude::*;
+use sha2::{Digest as Sha2Digest, Sha256};
+
+use super::puzzlefs::file_read;
+use super::{PuzzleFS, WalkPuzzleFS};
+use crate::format::{
+    escape_path, Digest, Inode, InodeMode, Result, WireFormatError, SHA256_BLOCK_SIZE,
+};
+use crate::oci::Image;
+
+// how much of a file is read at once
+const READ_SIZE: usize = 1 << 20;
+
+/// What one of the checks of [`verify_image`] is about. Digests are hex, without the `sha256:`
+/// prefix, like the names of the blobs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerifyTarget {
+    Manifest(String),
+    /// the rootfs of the image, or of one of the images it's a delta on
+    Rootfs(String),
+    /// a blob holding chunks of files
+    Blob(String),
+    /// a regular file, checked once however many hard links it has
+    File(PathBuf),
+}
+
+impl fmt::Display for VerifyTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyTarget::Manifest(digest) => write!(f, "manifest {digest}"),
+            VerifyTarget::Rootfs(digest) => write!(f, "rootfs {digest}"),
+            VerifyTarget::Blob(digest) => write!(f, "blob {digest}"),
+            VerifyTarget::File(path) => write!(f, "{}", escape_path(path)),
+        }
+    }
+}
+
+/// The outcome of one of the checks of [`verify_image`].
+#[derive(Debug)]
+pub struct VerifyResult {
+    pub target: VerifyTarget,
+    /// what's wrong with the target, None if it's intact
+    pub problem: Option<String>,
+}
+
+impl VerifyResult {
+    fn new(target: VerifyTarget, check: Result<()>) -> Self {
+        VerifyResult {
+            target,
+            problem: check.err().map(|e| e.to_string()),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.problem.is_none()
+    }
+}
+
+impl fmt::Display for VerifyResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.problem {
+            None => write!(f, "ok {}", self.target),
+            Some(problem) => write!(f, "FAILED {}: {problem}", self.target),
+        }
+    }
+}
+
+fn mismatch(expected: &[u8; SHA256_BLOCK_SIZE], found: &[u8; SHA256_BLOCK_SIZE]) -> Result<()> {
+    if expected == found {
+        return Ok(());
+    }
+    Err(WireFormatError::DigestMismatch {
+        expected: Digest::new(expected),
+        found: Digest::new(found),
+        backtrace: Backtrace::capture(),
+    })
+}
+
+// Checks the blob `digest` against its name, its size against `size` and its fs-verity digest
+// against `verity`, if given.
+fn check_blob(
+    image: &Image,
+    digest: &str,
+    size: Option<u64>,
+    verity: Option<&[u8; SHA256_BLOCK_SIZE]>,
+) -> Result<()> {
+    let expected = Digest::try_from(digest)?;
+    let mut blob = image.open_raw_blob(digest, None)?;
+    let mut sha256 = Sha256::new();
+    let mut fs_verity = FsVeritySha256::new();
+    let mut buf = vec![0; READ_SIZE];
+    let mut len = 0;
+    loop {
+        let n = blob.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        sha256.update(&buf[..n]);
+        if verity.is_some() {
+            fs_verity.write_all(&buf[..n])?;
+        }
+        len += n as u64;
+    }
+    if let Some(size) = size.filter(|size| *size != len) {
+        return Err(WireFormatError::InvalidImageSchema(
+            format!("blob {digest} has {len} bytes, expected {size}"),
+            Backtrace::capture(),
+        ));
+    }
+    mismatch(&expected.underlying(), &sha256.finalize().into())?;
+    match verity {
+        Some(verity) => mismatch(verity, &fs_verity.finalize().into()),
+        None => Ok(()),
+    }
+}
+
+// Reads the whole file, which checks its chunks, and checks its contents against the digests
+// stored for it.
+fn check_file(pfs: &PuzzleFS, inode: &Inode) -> Result<()> {
+    let mut digest = inode.digest.as_ref().map(|d| d.algorithm.hasher());
+    let mut fs_verity = FsVeritySha256::new();
+    let len = inode.file_len()? as usize;
+    let mut buf = vec![0; min(len, READ_SIZE)];
+    let mut offset = 0;
+    while offset < len {
+        let to_read = min(len - offset, READ_SIZE);
+        let n = file_read(&pfs.oci, inode, offset, &mut buf[..to_read], &[], None)?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        if let Some(digest) = &mut digest {
+            digest.update(&buf[..n]);
+        }
+        if inode.verity.is_some() {
+            fs_verity.write_all(&buf[..n])?;
+        }
+        offset += n;
+    }
+
+    if let (Some(expected), Some(found)) = (&inode.digest, digest.map(|d| d.finish())) {
+        if *expected != found {
+            return Err(WireFormatError::InvalidImageSchema(
+                format!("the contents hash to {found}, expected {expected}"),
+                Backtrace::capture(),
+            ));
+        }
+    }
+    match &inode.verity {
+        Some(verity) => mismatch(verity, &fs_verity.finalize().into()),
+        None => Ok(()),
+    }
+}
+
+/// Checks the image tagged `tag` from its manifest down to the contents of every file, reporting
+/// the outcome of every check: the manifest, then the rootfs blobs, the blobs holding chunks and
+/// the files. `manifest_verity` is the fs-verity digest the manifest has to have, as given to
+/// [`PuzzleFS::open`]. The blobs and files are checked on up to `jobs` threads, 0 meaning one
+/// per CPU.
+///
+/// Only what keeps the image from being read at all, e.g. a rootfs which doesn't parse, is an
+/// error; any other problem is reported in the results.
+pub fn verify_image(
+    image: Image,
+    tag: &str,
+    manifest_verity: Option<&[u8]>,
+    jobs: usize,
+) -> Result<Vec<VerifyResult>> {
+    let mut results = Vec::new();
+    let manifest = image.find_manifest_descriptor(tag)?;
+    let manifest_digest = manifest.digest().digest().to_string();
+    let manifest_verity = manifest_verity
+        .map(|verity| {
+            <[u8; SHA256_BLOCK_SIZE]>::try_from(verity).map_err(|_| {
+                WireFormatError::InvalidFsVerityData(
+                    format!("invalid fs-verity digest {}", hex::encode(verity)),
+                    Backtrace::capture(),
+                )
+            })
+        })
+        .transpose()?;
+    results.push(VerifyResult::new(
+        VerifyTarget::Manifest(manifest_digest.clone()),
+        check_blob(
+            &image,
+            &manifest_digest,
+            Some(manifest.size()),
+            manifest_verity.as_ref(),
+        ),
+    ));
+
+    // the image's own rootfs is vouched for by the manifest, those it's a delta on by the rootfs
+    // above them
+    let rootfs_verity = image.get_pfs_rootfs_verity(tag)?;
+    for (i, rootfs) in image.get_pfs_rootfs_descriptors(tag)?.iter().enumerate() {
+        let digest = rootfs.digest().digest();
+        let verity = (i == 0).then_some(&rootfs_verity);
+        results.push(VerifyResult::new(
+            VerifyTarget::Rootfs(digest.to_string()),
+            check_blob(&image, digest, Some(rootfs.size()), verity),
+        ));
+    }
+
+    let mut pfs = PuzzleFS::open(image, tag, None)?;
+    let mut inos = HashSet::new();
+    let mut files = Vec::new();
+    for entry in WalkPuzzleFS::walk(&mut pfs)? {
+        let entry = entry?;
+        if matches!(entry.inode.mode, InodeMode::File { .. }) && inos.insert(entry.inode.ino) {
+            files.push((entry.path, entry.inode));
+        }
+    }
+    let blobs = files
+        .iter()
+        .flat_map(|(_, inode)| match &inode.mode {
+            InodeMode::File { chunks } => chunks.as_slice(),
+            _ => &[],
+        })
+        .filter_map(|chunk| chunk.blob.as_ref())
+        .map(|blob| hex::encode(blob.digest))
+        .collect::<BTreeSet<_>>();
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .map_err(io::Error::other)?;
+    pool.install(|| {
+        results.par_extend(blobs.into_par_iter().map(|digest| {
+            let check = check_blob(&pfs.oci, &digest, None, None);
+            VerifyResult::new(VerifyTarget::Blob(digest), check)
+        }));
+        results.par_extend(files.into_par_iter().map(|(path, inode)| {
+            VerifyResult::new(VerifyTarget::File(path), check_file(&pfs, &inode))
+        }));
+    });
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use crate::fsverity_helpers::get_fs_verity_digest;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_verify_image() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let blobs = dir.path().join("blobs/sha256");
+        let manifest_digest = image.manifest_digest("test")?;
+        let manifest_digest = manifest_digest.trim_start_matches("sha256:");
+        let manifest = fs::read(blobs.join(manifest_digest))?;
+        let manifest_verity = get_fs_verity_digest(&manifest)?;
+
+        let results = verify_image(image, "test", Some(&manifest_verity), 2)?;
+        assert!(results.iter().all(VerifyResult::is_ok), "{results:?}");
+        assert_eq!(
+            results[0].target,
+            VerifyTarget::Manifest(manifest_digest.to_string())
+        );
+        assert!(results
+            .iter()
+            .any(|r| r.target == VerifyTarget::File(PathBuf::from("/SekienAkashita.jpg"))));
+
+        let image = Image::open(dir.path())?;
+        let results = verify_image(image, "test", Some(&[0; SHA256_BLOCK_SIZE]), 0)?;
+        assert!(!results[0].is_ok());
+        assert!(results[1..].iter().all(VerifyResult::is_ok));
+
+        // flip a byte of the blob holding the chunks
+        let image = Image::open(dir.path())?;
+        let blob = results
+            .iter()
+            .find_map(|r| match &r.target {
+                VerifyTarget::Blob(digest) => Some(digest.clone()),
+                _ => None,
+            })
+            .unwrap();
+        let path = blobs.join(&blob);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644))?;
+        let mut data = fs::read(&path)?;
+        data[100] ^= 0xff;
+        fs::write(&path, data)?;
+        let failed = verify_image(image, "test", None, 0)?
+            .into_iter()
+            .filter(|r| !r.is_ok())
+            .map(|r| r.target)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            failed,
+            [
+                VerifyTarget::Blob(blob),
+                VerifyTarget::File(PathBuf::from("/SekienAkashita.jpg"))
+            ]
+        );
+        Ok(())
+    }
+}
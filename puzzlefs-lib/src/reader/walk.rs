@@ -33,8 +33,14 @@ impl<'a> WalkPuzzleFS<'a> {
 
     fn add_dir_entries(&mut self, dir: &DirEntry) -> Result<()> {
         if let InodeMode::Dir { ref dir_list } = dir.inode.mode {
+            // the entries are one level below the directory, whose path starts with /
+            let depth = dir.path.components().count();
+            self.pfs.oci.limits().check_depth(depth)?;
             for entry in &dir_list.entries {
-                let inode = self.pfs.find_inode(entry.ino)?;
+                // skip the entries deleted in an upper layer
+                let Some(inode) = self.pfs.lookup_inode(entry.ino)? else {
+                    continue;
+                };
                 let path = dir.path.join(OsStr::from_bytes(&entry.name));
                 self.q.push_back(DirEntry {
                     oci: Arc::clone(&self.pfs.oci),
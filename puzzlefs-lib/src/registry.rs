@@ -0,0 +1,1366 @@
+//! A client for OCI registries (the [distribution spec](https://github.com/opencontainers/distribution-spec)),
+//! to move puzzlefs images between registries and local OCI layouts without going through other
+//! tools. Anonymous access, basic auth and bearer tokens (as used by Docker Hub, ghcr.io and most
+//! other registries) are supported. Without explicit credentials, the ones `docker login` stored
+//! (in the docker config.json or a credential helper) are used. Requests go through the proxy of
+//! `HTTPS_PROXY` (or `HTTP_PROXY` for plain http registries), unless `NO_PROXY` lists the registry.
+//! Blobs are pushed and pulled several at a time, optionally under a bandwidth cap.
+
+use std::backtrace::Backtrace;
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, warn};
+use ocidir::oci_spec::image::{
+    ImageIndex, ImageIndexBuilder, ImageManifest, MediaType, SCHEMA_VERSION,
+};
+use serde::Deserialize;
+use sha2::{Digest as Sha2Digest, Sha256};
+
+use crate::format::{Result, WireFormatError};
+use crate::http::read_range;
+use crate::oci::media_types::{is_rootfs, is_zstd_dictionary};
+use crate::oci::{select_platform, BlobSource, Descriptor, Image};
+
+mod auth;
+use auth::DockerConfig;
+mod transfer;
+use transfer::{Metered, Transfers};
+pub use transfer::{TransferProgress, TransferReporter, DEFAULT_PARALLELISM};
+
+const DOCKER_HUB: &str = "docker.io";
+const DOCKER_HUB_API: &str = "registry-1.docker.io";
+const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+const OCI_MANIFEST_OR_INDEX: &str =
+    "application/vnd.oci.image.manifest.v1+json, application/vnd.oci.image.index.v1+json";
+// where the blobs being pulled are downloaded to, in the layout, so an interrupted pull resumes
+// them instead of starting over
+const PULL_STAGING: &str = "puzzlefs-pull-staging";
+const PULL_ATTEMPTS: u32 = 5;
+// doubled after every failed attempt
+const PULL_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// An image in a registry, e.g. `ghcr.io/project-machine/alpine:3.19`. Images without a
+/// registry are on Docker Hub, and the tag defaults to `latest`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reference {
+    pub registry: String,
+    pub repository: String,
+    /// a tag, or a digest for references like `alpine@sha256:...`
+    pub reference: String,
+}
+
+impl FromStr for Reference {
+    type Err = WireFormatError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || registry_error(format!("invalid image reference {s}"));
+
+        let (name, reference) = match s.split_once('@') {
+            Some((name, digest)) => (name, digest.to_string()),
+            None => match s.rsplit_once(':') {
+                // a colon in the last path component starts the tag, otherwise it's a port
+                Some((name, tag)) if !tag.contains('/') => (name, tag.to_string()),
+                _ => (s, "latest".to_string()),
+            },
+        };
+
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, path))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (host.to_string(), path.to_string())
+            }
+            _ if !name.contains('/') => (DOCKER_HUB.to_string(), format!("library/{name}")),
+            _ => (DOCKER_HUB.to_string(), name.to_string()),
+        };
+
+        if repository.is_empty() || reference.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Reference {
+            registry,
+            repository,
+            reference,
+        })
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let separator = if self.reference.contains(':') {
+            '@'
+        } else {
+            ':'
+        };
+        write!(
+            f,
+            "{}/{}{separator}{}",
+            self.registry, self.repository, self.reference
+        )
+    }
+}
+
+/// How to talk to a registry.
+#[derive(Clone, Debug, Default)]
+pub struct RegistryOptions {
+    /// Use http instead of https, for local test registries.
+    pub plain_http: bool,
+    /// A username and password (or token) for the registry, instead of the ones stored by
+    /// `docker login`.
+    pub credentials: Option<(String, String)>,
+    /// Other repositories of the registry which may have the blobs of pushed images, e.g. the
+    /// one of the image they were built on. The registry mounts those blobs from there instead
+    /// of them being uploaded again.
+    pub mount_from: Vec<String>,
+    /// How many blobs are transferred at once, 0 for [`DEFAULT_PARALLELISM`].
+    pub parallelism: usize,
+    /// A cap on the bandwidth all the transfers use together, in bytes per second.
+    pub limit_rate: Option<u64>,
+    pub progress: TransferReporter,
+    /// How long connecting, or waiting for more of a response, may take before a request fails,
+    /// e.g. so reads of lazily mounted images don't hang on a registry which stopped answering.
+    pub timeout: Option<Duration>,
+}
+
+/// A repository in a registry.
+pub struct Registry {
+    agent: ureq::Agent,
+    base_url: String,
+    registry: String,
+    repository: String,
+    credentials: Option<(String, String)>,
+    mount_from: Vec<String>,
+    // the Authorization header the registry asked for, once it did
+    authorization: Mutex<Option<String>>,
+    transfers: Arc<Transfers>,
+}
+
+// A request body which can be sent again, after authenticating.
+type Body<'a> = &'a dyn Fn() -> io::Result<Box<dyn Read>>;
+
+impl Registry {
+    pub fn new(reference: &Reference, options: &RegistryOptions) -> Self {
+        let scheme = if options.plain_http { "http" } else { "https" };
+        let host = match reference.registry.as_str() {
+            DOCKER_HUB => DOCKER_HUB_API,
+            host => host,
+        };
+        let mut agent = ureq::AgentBuilder::new();
+        if let Some(timeout) = options.timeout {
+            agent = agent.timeout_connect(timeout).timeout_read(timeout);
+        }
+        if let Some(proxy) = env_proxy(scheme, host, |name| env::var(name).ok()) {
+            match ureq::Proxy::new(&proxy) {
+                Ok(proxy) => agent = agent.proxy(proxy),
+                Err(e) => warn!("ignoring the proxy {proxy}: {e}"),
+            }
+        }
+        Registry {
+            agent: agent.build(),
+            base_url: format!("{scheme}://{host}"),
+            registry: reference.registry.clone(),
+            repository: reference.repository.clone(),
+            credentials: options.credentials.clone(),
+            mount_from: options.mount_from.clone(),
+            authorization: Mutex::new(None),
+            transfers: Arc::new(Transfers::new(
+                options.parallelism,
+                options.limit_rate,
+                options.progress.clone(),
+            )),
+        }
+    }
+
+    fn metered<R: Read>(&self, inner: R) -> Metered<R> {
+        Metered {
+            inner,
+            transfers: Arc::clone(&self.transfers),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/v2/{}/{path}", self.base_url, self.repository)
+    }
+
+    fn send(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Option<Body<'_>>,
+    ) -> Result<ureq::Response> {
+        self.send_optional(method, url, headers, body)?
+            .ok_or_else(|| registry_error(format!("{method} {url}: not found")))
+    }
+
+    // Sends a request, authenticating and sending it again if the registry asks for it. Returns
+    // None if the registry doesn't have what was asked for.
+    fn send_optional(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Option<Body<'_>>,
+    ) -> Result<Option<ureq::Response>> {
+        let mut authenticated = false;
+        loop {
+            let mut request = self.agent.request(method, url);
+            for (name, value) in headers {
+                request = request.set(name, value);
+            }
+            if let Some(authorization) = self.authorization.lock().unwrap().as_deref() {
+                request = request.set("Authorization", authorization);
+            }
+            let response = match body {
+                Some(body) => request.send(body()?),
+                None => request.call(),
+            };
+
+            match response {
+                Ok(response) => return Ok(Some(response)),
+                Err(ureq::Error::Status(404, _)) => return Ok(None),
+                Err(ureq::Error::Status(401, response)) if !authenticated => {
+                    let challenge = response.header("WWW-Authenticate").unwrap_or_default();
+                    let authorization = self.authenticate(challenge)?;
+                    *self.authorization.lock().unwrap() = Some(authorization);
+                    authenticated = true;
+                }
+                Err(ureq::Error::Status(status, response)) => {
+                    let message = response.into_string().unwrap_or_default();
+                    return Err(registry_error(format!(
+                        "{method} {url} failed with status {status}: {}",
+                        message.trim()
+                    )));
+                }
+                Err(e) => return Err(registry_error(format!("{method} {url} failed: {e}"))),
+            }
+        }
+    }
+
+    // Works out the Authorization header answering a WWW-Authenticate challenge, fetching a
+    // token from the registry's token server for bearer auth.
+    fn authenticate(&self, challenge: &str) -> Result<String> {
+        let credentials = match &self.credentials {
+            Some(credentials) => Some(credentials.clone()),
+            None => DockerConfig::load()
+                .and_then(|config| config.credentials(&self.registry))
+                .unwrap_or_else(|e| {
+                    warn!("no stored credentials for {}: {e}", self.registry);
+                    None
+                }),
+        };
+        let basic = credentials.as_ref().map(|(user, password)| {
+            use base64::Engine;
+            let encoded =
+                base64::engine::general_purpose::STANDARD.encode(format!("{user}:{password}"));
+            format!("Basic {encoded}")
+        });
+
+        let Some((scheme, params)) = parse_challenge(challenge) else {
+            return Err(registry_error(format!(
+                "unsupported authentication challenge: {challenge}"
+            )));
+        };
+        if scheme.eq_ignore_ascii_case("basic") {
+            return basic
+                .ok_or_else(|| registry_error("the registry requires credentials".to_string()));
+        }
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return Err(registry_error(format!(
+                "unsupported authentication scheme {scheme}"
+            )));
+        }
+
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        };
+        let realm = param("realm")
+            .ok_or_else(|| registry_error("bearer challenge without a realm".to_string()))?;
+        let mut request = self.agent.get(realm);
+        if let Some(service) = param("service") {
+            request = request.query("service", service);
+        }
+        let scope = param("scope")
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("repository:{}:pull", self.repository));
+        request = request.query("scope", &scope);
+        if let Some(basic) = &basic {
+            request = request.set("Authorization", basic);
+        }
+
+        #[derive(Deserialize)]
+        struct Token {
+            token: Option<String>,
+            access_token: Option<String>,
+        }
+        let response = request
+            .call()
+            .map_err(|e| registry_error(format!("fetching a token from {realm} failed: {e}")))?;
+        let token: Token = serde_json::from_reader(response.into_reader())?;
+        let token = token
+            .token
+            .or(token.access_token)
+            .ok_or_else(|| registry_error(format!("no token from {realm}")))?;
+        Ok(format!("Bearer {token}"))
+    }
+
+    pub fn has_blob(&self, digest: &str) -> Result<bool> {
+        let url = self.url(&format!("blobs/{digest}"));
+        Ok(self.send_optional("HEAD", &url, &[], None)?.is_some())
+    }
+
+    /// Uploads a blob in a single request; `data` is called again if the upload has to be
+    /// retried after authenticating.
+    pub fn put_blob(&self, digest: &str, size: u64, data: Body<'_>) -> Result<()> {
+        let response = self.send("POST", &self.url("blobs/uploads/"), &[], None)?;
+        let location = response
+            .header("Location")
+            .ok_or_else(|| registry_error("upload without a location".to_string()))?;
+        let location = if location.starts_with('/') {
+            format!("{}{location}", self.base_url)
+        } else {
+            location.to_string()
+        };
+        let separator = if location.contains('?') { '&' } else { '?' };
+        let url = format!("{location}{separator}digest={digest}");
+        let size = size.to_string();
+        self.send(
+            "PUT",
+            &url,
+            &[
+                ("Content-Type", "application/octet-stream"),
+                ("Content-Length", &size),
+            ],
+            Some(data),
+        )?;
+        Ok(())
+    }
+
+    /// Asks the registry to mount the blob from one of the repositories given with
+    /// [`RegistryOptions::mount_from`], returning whether it did. Registries which don't have it
+    /// there, or which don't mount blobs at all, start an upload instead, which is left to expire.
+    pub fn mount_blob(&self, digest: &str) -> bool {
+        for from in &self.mount_from {
+            let url = self.url(&format!("blobs/uploads/?mount={digest}&from={from}"));
+            match self.send("POST", &url, &[], None) {
+                Ok(response) if response.status() == 201 => {
+                    debug!("mounted {digest} from {from}");
+                    return true;
+                }
+                Ok(_) => {}
+                // e.g. no pull access to `from`, the blob is uploaded as usual
+                Err(e) => warn!("can't mount {digest} from {from}: {e}"),
+            }
+        }
+        false
+    }
+
+    // The blobs the manifest tagged `reference` refers to, or the manifests of the image index
+    // it is, if there's such a tag.
+    fn tagged_blobs(&self, reference: &str) -> Result<HashSet<String>> {
+        let url = self.url(&format!("manifests/{reference}"));
+        let accept = [("Accept", OCI_MANIFEST_OR_INDEX)];
+        let Some(response) = self.send_optional("GET", &url, &accept, None)? else {
+            return Ok(HashSet::new());
+        };
+        let mut manifest_bytes = Vec::new();
+        response.into_reader().read_to_end(&mut manifest_bytes)?;
+        let manifests = match serde_json::from_slice::<ImageIndex>(&manifest_bytes) {
+            Ok(index) => index
+                .manifests()
+                .iter()
+                .map(|descriptor| Ok(self.get_manifest(&descriptor.digest().to_string())?.0))
+                .collect::<Result<Vec<_>>>()?,
+            Err(_) => vec![manifest_bytes],
+        };
+
+        let mut blobs = HashSet::new();
+        for manifest_bytes in manifests {
+            let manifest: ImageManifest = serde_json::from_slice(&manifest_bytes)?;
+            blobs.insert(manifest.config().digest().to_string());
+            blobs.extend(manifest.layers().iter().map(|d| d.digest().to_string()));
+        }
+        Ok(blobs)
+    }
+
+    /// Downloads the manifest tagged (or with the digest) `reference`, along with the digest the
+    /// registry gave for it, if any.
+    pub fn get_manifest(&self, reference: &str) -> Result<(Vec<u8>, Option<String>)> {
+        let response = self.send(
+            "GET",
+            &self.url(&format!("manifests/{reference}")),
+            &[("Accept", OCI_MANIFEST_OR_INDEX)],
+            None,
+        )?;
+        let digest = response.header("Docker-Content-Digest").map(str::to_string);
+        let mut manifest = Vec::new();
+        response.into_reader().read_to_end(&mut manifest)?;
+        Ok((manifest, digest))
+    }
+
+    pub fn get_blob(&self, digest: &str) -> Result<impl Read> {
+        let response = self.send("GET", &self.url(&format!("blobs/{digest}")), &[], None)?;
+        Ok(response.into_reader())
+    }
+
+    // Appends the rest of the blob `digest` of `size` bytes to `partial`, which has its start.
+    fn resume_blob(&self, digest: &str, size: u64, partial: &mut cap_std::fs::File) -> Result<()> {
+        let offset = partial.metadata()?.len();
+        if offset >= size {
+            return Ok(());
+        }
+        let range = format!("bytes={offset}-{}", size - 1);
+        let headers: &[(&str, &str)] = if offset > 0 {
+            &[("Range", &range)]
+        } else {
+            &[]
+        };
+        let response = self.send("GET", &self.url(&format!("blobs/{digest}")), headers, None)?;
+        if offset > 0 && response.status() != 206 {
+            debug!("{digest} can't be resumed, downloading it again");
+            partial.set_len(0)?;
+        }
+        io::copy(&mut self.metered(response.into_reader()), partial)?;
+        Ok(())
+    }
+
+    pub fn put_manifest(&self, reference: &str, media_type: &str, manifest: &[u8]) -> Result<()> {
+        self.send_manifest(reference, media_type, manifest)?;
+        Ok(())
+    }
+
+    fn send_manifest(
+        &self,
+        reference: &str,
+        media_type: &str,
+        manifest: &[u8],
+    ) -> Result<ureq::Response> {
+        let size = manifest.len().to_string();
+        let body =
+            || -> io::Result<Box<dyn Read>> { Ok(Box::new(io::Cursor::new(manifest.to_vec()))) };
+        self.send(
+            "PUT",
+            &self.url(&format!("manifests/{reference}")),
+            &[("Content-Type", media_type), ("Content-Length", &size)],
+            Some(&body),
+        )
+    }
+
+    /// The referrers of the manifest with the digest `subject`. Registries without the referrers
+    /// API keep them in an index tagged after the subject (`sha256-<digest>`) instead.
+    pub fn get_referrers(&self, subject: &str) -> Result<Vec<Descriptor>> {
+        let accept = [("Accept", OCI_INDEX)];
+        let url = self.url(&format!("referrers/{subject}"));
+        let response = match self.send_optional("GET", &url, &accept, None)? {
+            Some(response) => Some(response),
+            None => {
+                let url = self.url(&format!("manifests/{}", referrers_tag(subject)));
+                self.send_optional("GET", &url, &accept, None)?
+            }
+        };
+        match response {
+            Some(response) => Ok(ImageIndex::from_reader(response.into_reader())?
+                .manifests()
+                .clone()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Uploads the referrer `manifest` of the manifest with the digest `subject`, adding it to
+    /// the `sha256-<digest>` index if the registry doesn't do the referrers API.
+    pub fn put_referrer(
+        &self,
+        descriptor: &Descriptor,
+        subject: &str,
+        manifest: &[u8],
+    ) -> Result<()> {
+        let reference = descriptor.digest().to_string();
+        let response = self.send_manifest(&reference, OCI_MANIFEST, manifest)?;
+        // registries doing the referrers API say so when taking a manifest with a subject
+        if response.header("OCI-Subject").is_some() {
+            return Ok(());
+        }
+
+        let tag = referrers_tag(subject);
+        let url = self.url(&format!("manifests/{tag}"));
+        let mut index = match self.send_optional("GET", &url, &[("Accept", OCI_INDEX)], None)? {
+            Some(response) => ImageIndex::from_reader(response.into_reader())?,
+            None => ImageIndexBuilder::default()
+                .schema_version(SCHEMA_VERSION)
+                .media_type(MediaType::ImageIndex)
+                .manifests(Vec::new())
+                .build()?,
+        };
+        if index
+            .manifests()
+            .iter()
+            .any(|d| d.digest() == descriptor.digest())
+        {
+            return Ok(());
+        }
+        let mut manifests = index.manifests().clone();
+        manifests.push(descriptor.clone());
+        index.set_manifests(manifests);
+        self.put_manifest(&tag, OCI_INDEX, &serde_json::to_vec(&index)?)
+    }
+}
+
+impl BlobSource for Registry {
+    fn blob_size(&self, digest: &str) -> Result<u64> {
+        let url = self.url(&format!("blobs/{digest}"));
+        let response = self.send("HEAD", &url, &[], None)?;
+        response
+            .header("Content-Length")
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| registry_error(format!("HEAD {url}: no content length")))
+    }
+
+    fn get_blob_range(&self, digest: &str, range: Range<u64>) -> Result<Vec<u8>> {
+        let header = format!("bytes={}-{}", range.start, range.end - 1);
+        let response = self.send(
+            "GET",
+            &self.url(&format!("blobs/{digest}")),
+            &[("Range", &header)],
+            None,
+        )?;
+        Ok(read_range(response, &range)?)
+    }
+}
+
+/// What [`sync`] uploaded.
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// the digest of the manifest
+    pub digest: String,
+    /// the digests of the blobs the registry didn't have
+    pub uploaded: Vec<String>,
+    pub uploaded_bytes: u64,
+}
+
+// The blobs known to be in the registry, and the ones uploaded, during a push.
+#[derive(Default)]
+struct Pushed {
+    known: HashSet<String>,
+    uploaded: Vec<String>,
+    uploaded_bytes: u64,
+}
+
+/// Uploads the image tagged `tag` in `image` to the registry as `reference`, along with all the
+/// blobs it refers to, skipping the blobs the registry already has or can mount from the
+/// repositories in [`RegistryOptions::mount_from`]. Returns the digest of the manifest.
+pub fn push(image: &Image, tag: &str, registry: &Registry, reference: &str) -> Result<String> {
+    push_tagged(image, tag, registry, reference, &mut Pushed::default())
+}
+
+/// Like [`push`], for images the registry has an earlier version of as `reference`, e.g. nightly
+/// builds shipped to the registry edge devices pull from. The chunks and the other blobs of the
+/// earlier version are known to be there, so only the ones which aren't are looked up and
+/// uploaded, rather than asking the registry about every blob of the image.
+pub fn sync(image: &Image, tag: &str, registry: &Registry, reference: &str) -> Result<SyncReport> {
+    let mut pushed = Pushed {
+        known: registry.tagged_blobs(reference)?,
+        ..Default::default()
+    };
+    let digest = push_tagged(image, tag, registry, reference, &mut pushed)?;
+    Ok(SyncReport {
+        digest,
+        uploaded: pushed.uploaded,
+        uploaded_bytes: pushed.uploaded_bytes,
+    })
+}
+
+fn push_tagged(
+    image: &Image,
+    tag: &str,
+    registry: &Registry,
+    reference: &str,
+    pushed: &mut Pushed,
+) -> Result<String> {
+    let tagged = image
+        .0
+        .find_manifest_descriptor_with_tag(tag)?
+        .ok_or_else(|| WireFormatError::MissingManifest(tag.to_string(), Backtrace::capture()))?;
+    if tagged.media_type() != &MediaType::ImageIndex {
+        return push_manifest(image, registry, &tagged, reference, pushed);
+    }
+
+    // multi-platform images go with all their platforms, each manifest before the index
+    let mut index_bytes = Vec::new();
+    image
+        .open_raw_blob(tagged.digest().digest(), None)?
+        .read_to_end(&mut index_bytes)?;
+    let index: ImageIndex = serde_json::from_slice(&index_bytes)?;
+    for descriptor in index.manifests() {
+        let digest = descriptor.digest().to_string();
+        push_manifest(image, registry, descriptor, &digest, pushed)?;
+    }
+    registry.put_manifest(reference, OCI_INDEX, &index_bytes)?;
+    Ok(tagged.digest().to_string())
+}
+
+// Pushes the manifest `descriptor` points to, its blobs and its referrers.
+fn push_manifest(
+    image: &Image,
+    registry: &Registry,
+    descriptor: &Descriptor,
+    reference: &str,
+    pushed: &mut Pushed,
+) -> Result<String> {
+    let mut manifest_bytes = Vec::new();
+    image
+        .open_raw_blob(descriptor.digest().digest(), None)?
+        .read_to_end(&mut manifest_bytes)?;
+    let manifest: ImageManifest = serde_json::from_slice(&manifest_bytes)?;
+
+    push_blobs(image, registry, &manifest, pushed)?;
+    let media_type = manifest
+        .media_type()
+        .as_ref()
+        .map_or_else(|| OCI_MANIFEST.to_string(), MediaType::to_string);
+    registry.put_manifest(reference, &media_type, &manifest_bytes)?;
+    let digest = format!("sha256:{}", hex::encode(Sha256::digest(&manifest_bytes)));
+
+    // the referrers go after their subject, registries may refuse them otherwise
+    for (descriptor, referrer) in image.referrers_of(&digest, None)? {
+        push_blobs(image, registry, &referrer, pushed)?;
+        let mut referrer_bytes = Vec::new();
+        image
+            .open_raw_blob(descriptor.digest().digest(), None)?
+            .read_to_end(&mut referrer_bytes)?;
+        registry.put_referrer(&descriptor, &digest, &referrer_bytes)?;
+    }
+    Ok(digest)
+}
+
+// Uploads the config and the layers of `manifest` the registry doesn't have yet.
+fn push_blobs(
+    image: &Image,
+    registry: &Registry,
+    manifest: &ImageManifest,
+    pushed: &mut Pushed,
+) -> Result<()> {
+    let descriptors = std::iter::once(manifest.config())
+        .chain(manifest.layers())
+        .filter(|descriptor| pushed.known.insert(descriptor.digest().to_string()))
+        .collect::<Vec<_>>();
+    registry
+        .transfers
+        .add_blobs(descriptors.iter().map(|descriptor| descriptor.size()));
+
+    let uploaded = Mutex::new(Vec::new());
+    registry.transfers.for_each(&descriptors, |descriptor| {
+        let digest = descriptor.digest().to_string();
+        if !registry.has_blob(&digest)? && !registry.mount_blob(&digest) {
+            let file = descriptor.digest().digest();
+            let blob = || -> io::Result<Box<dyn Read>> {
+                let blob = image.open_raw_blob(file, None).map_err(io::Error::other)?;
+                Ok(Box::new(registry.metered(blob)))
+            };
+            registry.put_blob(&digest, descriptor.size(), &blob)?;
+            uploaded.lock().unwrap().push((digest, descriptor.size()));
+        }
+        registry.transfers.blob_done(descriptor.size());
+        Ok(())
+    })?;
+    for (digest, size) in uploaded.into_inner().unwrap() {
+        pushed.uploaded.push(digest);
+        pushed.uploaded_bytes += size;
+    }
+    Ok(())
+}
+
+/// Downloads the image `reference` from the registry into `image`, tagged `tag`, along with all
+/// the blobs it refers to which `image` doesn't have yet. The digests of the manifest and the
+/// blobs are checked as they are downloaded. Returns the digest of the manifest.
+pub fn pull(image: &Image, tag: &str, registry: &Registry, reference: &str) -> Result<String> {
+    pull_blobs(image, tag, registry, reference, |_| true)
+}
+
+/// Like [`pull`], only downloading the config, the rootfs and the compression dictionaries: the
+/// image can be mounted right away with [`Image::with_lazy_fetcher`], which downloads the chunks
+/// as they are first read.
+pub fn pull_lazy(image: &Image, tag: &str, registry: &Registry, reference: &str) -> Result<String> {
+    pull_blobs(image, tag, registry, reference, |descriptor| {
+        is_rootfs(descriptor.media_type()) || is_zstd_dictionary(descriptor.media_type())
+    })
+}
+
+fn pull_blobs(
+    image: &Image,
+    tag: &str,
+    registry: &Registry,
+    reference: &str,
+    wanted_layer: impl Fn(&Descriptor) -> bool,
+) -> Result<String> {
+    let (manifest_bytes, registry_digest) = registry.get_manifest(reference)?;
+    let digest = format!("sha256:{}", hex::encode(Sha256::digest(&manifest_bytes)));
+    // a manifest pulled by digest has to match it, otherwise trust the registry as far as it
+    // tells us what it sent
+    let expected = if reference.contains(':') {
+        Some(reference)
+    } else {
+        registry_digest.as_deref()
+    };
+    if let Some(expected) = expected.filter(|expected| *expected != digest) {
+        return Err(registry_error(format!(
+            "manifest digest mismatch: expected {expected}, got {digest}"
+        )));
+    }
+    // multi-platform images point to the manifest for each platform, only the one of the image's
+    // platform is pulled
+    let (digest, manifest_bytes) = match serde_json::from_slice::<ImageIndex>(&manifest_bytes) {
+        Ok(index) => {
+            let platform = image.platform();
+            let descriptor = select_platform(&index, &platform).ok_or_else(|| {
+                registry_error(format!(
+                    "{reference} has no manifest for {}/{}",
+                    platform.os(),
+                    platform.architecture()
+                ))
+            })?;
+            let digest = descriptor.digest().to_string();
+            let manifest_bytes = get_verified_manifest(registry, &digest)?;
+            (digest, manifest_bytes)
+        }
+        Err(_) => (digest, manifest_bytes),
+    };
+    let manifest: ImageManifest = serde_json::from_slice(&manifest_bytes)?;
+
+    let mut pulled = HashSet::new();
+    let layers = manifest.layers().iter().filter(|d| wanted_layer(d));
+    pull_layers(image, registry, &manifest, layers, &mut pulled)?;
+
+    // the manifest is stored as canonical JSON, like the ones of the images puzzlefs builds, so
+    // those keep their digest
+    image.insert_manifest(manifest, tag)?;
+
+    // referrers of manifests which didn't keep their digest wouldn't refer to anything here
+    let local = image.find_manifest_descriptor(tag)?;
+    if local.digest().to_string() == digest {
+        for descriptor in registry.get_referrers(&digest)? {
+            let referrer_bytes = get_verified_manifest(registry, &descriptor.digest().to_string())?;
+            let referrer: ImageManifest = serde_json::from_slice(&referrer_bytes)?;
+            pull_layers(image, registry, &referrer, referrer.layers(), &mut pulled)?;
+            image.insert_referrer(&referrer)?;
+        }
+    }
+    Ok(digest)
+}
+
+// Fetches the manifest with the given digest, checking it matches.
+fn get_verified_manifest(registry: &Registry, digest: &str) -> Result<Vec<u8>> {
+    let (manifest_bytes, _) = registry.get_manifest(digest)?;
+    let found = format!("sha256:{}", hex::encode(Sha256::digest(&manifest_bytes)));
+    if found != digest {
+        return Err(registry_error(format!(
+            "manifest digest mismatch: expected {digest}, got {found}"
+        )));
+    }
+    Ok(manifest_bytes)
+}
+
+// Downloads the config of `manifest` and `layers` unless `image` has them already.
+fn pull_layers<'a>(
+    image: &Image,
+    registry: &Registry,
+    manifest: &'a ImageManifest,
+    layers: impl IntoIterator<Item = &'a Descriptor>,
+    pulled: &mut HashSet<String>,
+) -> Result<()> {
+    let descriptors = std::iter::once(manifest.config())
+        .chain(layers)
+        .filter(|descriptor| pulled.insert(descriptor.digest().to_string()))
+        .collect::<Vec<_>>();
+    registry
+        .transfers
+        .add_blobs(descriptors.iter().map(|descriptor| descriptor.size()));
+    registry.transfers.for_each(&descriptors, |descriptor| {
+        if !image.has_blob(descriptor) {
+            pull_blob(image, registry, descriptor)?;
+        }
+        registry.transfers.blob_done(descriptor.size());
+        Ok(())
+    })
+}
+
+// Downloads a blob into the staging area of `image`, picking up where an earlier attempt (or an
+// interrupted pull) stopped, and stores it once it's complete and verified. Failed attempts are
+// retried with exponential backoff.
+fn pull_blob(image: &Image, registry: &Registry, descriptor: &Descriptor) -> Result<()> {
+    let digest = descriptor.digest().to_string();
+    let name = descriptor.digest().digest();
+    image.0.dir().create_dir_all(PULL_STAGING)?;
+    let staging = image.0.dir().open_dir(PULL_STAGING)?;
+    let mut append = cap_std::fs::OpenOptions::new();
+    append.create(true).append(true);
+
+    let mut delay = PULL_RETRY_DELAY;
+    for attempt in 1.. {
+        let result = (|| -> Result<()> {
+            let mut partial = staging.open_with(name, &append)?;
+            registry.resume_blob(&digest, descriptor.size(), &mut partial)?;
+            let stored = image
+                .blob_store()
+                .write_blob(descriptor, &mut staging.open(name)?);
+            // a blob which doesn't match its digest is downloaded again from the start
+            staging.remove_file(name)?;
+            Ok(stored?)
+        })();
+        match result {
+            Ok(()) => break,
+            Err(e) if attempt >= PULL_ATTEMPTS => return Err(e),
+            Err(e) => {
+                warn!("pulling {digest} failed, retrying in {delay:?}: {e}");
+                thread::sleep(delay);
+                delay *= 2;
+            }
+        }
+    }
+    Ok(())
+}
+
+// The tag standing in for the referrers API, for the manifest with the digest `subject`.
+fn referrers_tag(subject: &str) -> String {
+    subject.replacen(':', "-", 1)
+}
+
+// Splits a WWW-Authenticate header like `Bearer realm="https://auth.docker.io/token",
+// service="registry.docker.io"` into the scheme and its parameters.
+fn parse_challenge(challenge: &str) -> Option<(&str, Vec<(String, String)>)> {
+    let challenge = challenge.trim();
+    let (scheme, mut rest) = challenge.split_once(' ').unwrap_or((challenge, ""));
+    if scheme.is_empty() {
+        return None;
+    }
+
+    let mut params = Vec::new();
+    loop {
+        rest = rest.trim_start_matches([' ', ',']);
+        if rest.is_empty() {
+            break;
+        }
+        let (key, value) = rest.split_once('=')?;
+        let (value, remaining) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], &quoted[end + 1..])
+            }
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        params.push((key.trim().to_string(), value.to_string()));
+        rest = remaining;
+    }
+    Some((scheme, params))
+}
+
+// The proxy for requests to `host` with `scheme`, from the environment variables curl uses:
+// `https_proxy` or `http_proxy` (in lower or upper case), unless `no_proxy` has the host, one of
+// its parent domains or `*`.
+fn env_proxy(scheme: &str, host: &str, var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let lookup = |name: &str| {
+        var(name)
+            .or_else(|| var(&name.to_uppercase()))
+            .filter(|value| !value.is_empty())
+    };
+    let proxy = lookup(&format!("{scheme}_proxy"))?;
+
+    let hostname = host.rsplit_once(':').map_or(host, |(hostname, _)| hostname);
+    let no_proxy = lookup("no_proxy").unwrap_or_default();
+    let exempt = no_proxy.split(',').map(str::trim).any(|entry| {
+        let domain = entry.trim_start_matches('.');
+        entry == "*"
+            || entry == host
+            || (!domain.is_empty()
+                && (hostname == domain || hostname.ends_with(&format!(".{domain}"))))
+    });
+    (!exempt).then_some(proxy)
+}
+
+fn registry_error(message: String) -> WireFormatError {
+    WireFormatError::RegistryError(message, Backtrace::capture())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{add_rootfs_delta, build_test_fs};
+    use crate::compression::Zstd;
+    use crate::oci::{LayoutBlobStore, LazyFetcher};
+    use crate::reader::{PuzzleFS, WalkPuzzleFS};
+    use std::io::{BufRead, Write};
+    use std::net::TcpListener;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    // Serves the manifest tagged "test", its referrers and the blobs of the layout at `oci_dir`,
+    // one request per connection, returning the address and the log of requests.
+    fn serve_layout(oci_dir: PathBuf) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&requests);
+        std::thread::spawn(move || {
+            let image = Image::open(&oci_dir).unwrap();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let mut range = None;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Range: bytes=") {
+                        let (start, end) = value.trim().split_once('-').unwrap();
+                        range = Some(
+                            start.parse::<usize>().unwrap()..end.parse::<usize>().unwrap() + 1,
+                        );
+                    }
+                }
+                let mut parts = request.split_whitespace();
+                let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+                log.lock().unwrap().push(match &range {
+                    Some(range) => format!("{method} {path} {range:?}"),
+                    None => format!("{method} {path}"),
+                });
+
+                let body = if path == "/v2/test/manifests/test" {
+                    let mut manifest = Vec::new();
+                    image
+                        .get_image_manifest_fd("test")
+                        .unwrap()
+                        .read_to_end(&mut manifest)
+                        .unwrap();
+                    Some(manifest)
+                } else if path.starts_with("/v2/test/referrers/") {
+                    let mut index = image.get_index().unwrap();
+                    let referrers = image.referrers("test", None).unwrap();
+                    index.set_manifests(referrers.into_iter().map(|(d, _)| d).collect());
+                    Some(serde_json::to_vec(&index).unwrap())
+                } else {
+                    path.strip_prefix("/v2/test/blobs/sha256:")
+                        .or_else(|| path.strip_prefix("/v2/test/manifests/sha256:"))
+                        .and_then(|digest| image.open_raw_blob(digest, None).ok())
+                        .map(|mut blob| {
+                            let mut data = Vec::new();
+                            blob.read_to_end(&mut data).unwrap();
+                            data
+                        })
+                };
+                let (status, body) = match (body, range) {
+                    (None, _) => ("404 Not Found", Vec::new()),
+                    (Some(body), Some(range)) => ("206 Partial Content", body[range].to_vec()),
+                    (Some(body), None) => ("200 OK", body),
+                };
+                let header = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(header.as_bytes()).unwrap();
+                if method != "HEAD" {
+                    stream.write_all(&body).unwrap();
+                }
+            }
+        });
+        (address, requests)
+    }
+
+    #[test]
+    fn test_lazy_fetch() -> anyhow::Result<()> {
+        let source = tempdir()?;
+        let image = Image::new(source.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let (address, requests) = serve_layout(source.path().to_path_buf());
+
+        let reference = Reference::from_str(&format!("{address}/test:test"))?;
+        let options = RegistryOptions {
+            plain_http: true,
+            ..Default::default()
+        };
+        let cache = tempdir()?;
+        let lazy = Image::new(cache.path())?;
+        pull_lazy(&lazy, "test", &Registry::new(&reference, &options), "test")?;
+        // the manifest, the config and the rootfs, then the referrers and the manifest, config
+        // and data of the verity digests attached to the image
+        assert_eq!(requests.lock().unwrap().len(), 7);
+        assert_eq!(lazy.verity_digests("test")?, image.verity_digests("test")?);
+
+        let cache = LayoutBlobStore::new(&lazy.0)?;
+        let lazy =
+            lazy.with_blob_store(LazyFetcher::new(Registry::new(&reference, &options), cache));
+        let mut pfs = PuzzleFS::open(lazy, "test", None)?;
+        let mut contents = Vec::new();
+        for de in WalkPuzzleFS::walk(&mut pfs)? {
+            let de = de?;
+            if de.inode.file_len().is_ok() {
+                de.open()?.read_to_end(&mut contents)?;
+            }
+        }
+        assert_eq!(
+            hex::encode(Sha256::digest(&contents)),
+            "d9e749d9367fc908876749d6502eb212fee88c9a94892fb07da5ef3ba8bc39ed"
+        );
+        assert!(requests
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|r| r.starts_with("HEAD /v2/test/blobs/")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_pull() -> anyhow::Result<()> {
+        let source = tempdir()?;
+        let image = Image::new(source.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let (address, requests) = serve_layout(source.path().to_path_buf());
+
+        // an interrupted pull left half of the rootfs, and a chunk got corrupted
+        let manifest = image.find_manifest("test")?;
+        let (rootfs, chunk) = (&manifest.layers()[0], &manifest.layers()[1]);
+        let read = |descriptor: &Descriptor| -> anyhow::Result<Vec<u8>> {
+            let mut data = Vec::new();
+            image
+                .open_raw_blob(descriptor.digest().digest(), None)?
+                .read_to_end(&mut data)?;
+            Ok(data)
+        };
+        let dest = tempdir()?;
+        let pulled = Image::new(dest.path())?;
+        let staging = dest.path().join(PULL_STAGING);
+        std::fs::create_dir(&staging)?;
+        let half = rootfs.size() as usize / 2;
+        std::fs::write(
+            staging.join(rootfs.digest().digest()),
+            &read(rootfs)?[..half],
+        )?;
+        std::fs::write(staging.join(chunk.digest().digest()), b"garbage")?;
+
+        let reference = Reference::from_str(&format!("{address}/test:test"))?;
+        let options = RegistryOptions {
+            plain_http: true,
+            ..Default::default()
+        };
+        pull(
+            &pulled,
+            "test",
+            &Registry::new(&reference, &options),
+            "test",
+        )?;
+        assert_eq!(
+            pulled.verity_digests("test")?,
+            image.verity_digests("test")?
+        );
+        assert_eq!(std::fs::read_dir(&staging)?.count(), 0);
+
+        // only the rest of the rootfs was downloaded, the chunk was downloaded again
+        let requests = requests.lock().unwrap();
+        let blob = |descriptor: &Descriptor| format!("GET /v2/test/blobs/{}", descriptor.digest());
+        assert!(requests.contains(&format!(
+            "{} {:?}",
+            blob(rootfs),
+            half..rootfs.size() as usize
+        )));
+        assert!(!requests.contains(&blob(rootfs)));
+        assert!(requests.contains(&format!("{} {:?}", blob(chunk), 7..chunk.size() as usize)));
+        assert!(requests.contains(&blob(chunk)));
+        Ok(())
+    }
+
+    // A registry which has every blob in the "base" repository and none in "test", answering
+    // every other request with an empty 201 or 404, returning the address and the log of
+    // requests.
+    fn serve_mounts() -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&requests);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                io::copy(&mut (&mut reader).take(length), &mut io::sink()).unwrap();
+                let mut parts = request.split_whitespace();
+                let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+                log.lock().unwrap().push(format!("{method} {path}"));
+
+                let status = match method {
+                    "POST" if path.ends_with("&from=base") => "201 Created",
+                    "PUT" => "201 Created",
+                    _ => "404 Not Found",
+                };
+                let response =
+                    format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (address, requests)
+    }
+
+    #[test]
+    fn test_mount_blobs() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let (address, requests) = serve_mounts();
+
+        let reference = Reference::from_str(&format!("{address}/test:test"))?;
+        let options = RegistryOptions {
+            plain_http: true,
+            mount_from: vec!["other".to_string(), "base".to_string()],
+            ..Default::default()
+        };
+        push(&image, "test", &Registry::new(&reference, &options), "test")?;
+
+        let requests = requests.lock().unwrap();
+        let manifest = image.find_manifest("test")?;
+        for descriptor in std::iter::once(manifest.config()).chain(manifest.layers()) {
+            let mount = format!(
+                "POST /v2/test/blobs/uploads/?mount={}&from=base",
+                descriptor.digest()
+            );
+            assert!(requests.contains(&mount));
+        }
+        // nothing was uploaded
+        assert!(!requests
+            .iter()
+            .any(|r| r.starts_with("PUT /v2/test/blobs/")));
+        Ok(())
+    }
+
+    // A registry keeping the blobs and manifests pushed to its "test" repository in memory,
+    // returning the address and the log of requests.
+    fn serve_registry() -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&requests);
+        std::thread::spawn(move || {
+            let mut stored = std::collections::HashMap::<String, Vec<u8>>::new();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = Vec::new();
+                (&mut reader).take(length).read_to_end(&mut body).unwrap();
+                let mut parts = request.split_whitespace();
+                let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+                log.lock().unwrap().push(format!("{method} {path}"));
+
+                let path = path.strip_prefix("/v2/test/").unwrap();
+                let (status, location, body) = match method {
+                    "POST" => ("202 Accepted", "/v2/test/blobs/uploads/1", Vec::new()),
+                    "PUT" => {
+                        let name = match path.split_once("?digest=") {
+                            Some((_, digest)) => format!("blobs/{digest}"),
+                            None => path.to_string(),
+                        };
+                        stored.insert(name, body);
+                        ("201 Created", "", Vec::new())
+                    }
+                    _ => match stored.get(path) {
+                        Some(body) => ("200 OK", "", body.clone()),
+                        None => ("404 Not Found", "", Vec::new()),
+                    },
+                };
+                let header = format!(
+                    "HTTP/1.1 {status}\r\nLocation: {location}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(header.as_bytes()).unwrap();
+                if method != "HEAD" {
+                    stream.write_all(&body).unwrap();
+                }
+            }
+        });
+        (address, requests)
+    }
+
+    #[test]
+    fn test_sync() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "base")?;
+        let delta = tempdir()?;
+        std::fs::write(delta.path().join("file"), b"delta")?;
+        let (_, image) = add_rootfs_delta::<Zstd>(delta.path(), image, "nightly", "base")?;
+        let (address, requests) = serve_registry();
+
+        let reference = Reference::from_str(&format!("{address}/test:nightly"))?;
+        let options = RegistryOptions {
+            plain_http: true,
+            ..Default::default()
+        };
+        let registry = Registry::new(&reference, &options);
+        let report = sync(&image, "base", &registry, "nightly")?;
+        let base = image.find_manifest("base")?;
+        for layer in base.layers() {
+            assert!(report.uploaded.contains(&layer.digest().to_string()));
+        }
+
+        requests.lock().unwrap().clear();
+        let report = sync(&image, "nightly", &registry, "nightly")?;
+        let nightly = image.find_manifest("nightly")?;
+        assert_eq!(
+            report.digest,
+            image
+                .find_manifest_descriptor("nightly")?
+                .digest()
+                .to_string()
+        );
+        // the registry isn't asked about what the earlier version has
+        let requests = requests.lock().unwrap();
+        for layer in base.layers() {
+            let digest = layer.digest().to_string();
+            assert!(!report.uploaded.contains(&digest));
+            assert!(!requests.contains(&format!("HEAD /v2/test/blobs/{digest}")));
+        }
+        // but the rootfs and the chunk of the new version are uploaded
+        for layer in nightly.layers() {
+            let digest = layer.digest().to_string();
+            if !base.layers().iter().any(|l| l.digest() == layer.digest()) {
+                assert!(report.uploaded.contains(&digest));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_reference() {
+        let parse = |s: &str| {
+            let r = Reference::from_str(s).unwrap();
+            (r.registry, r.repository, r.reference)
+        };
+        let owned = |a: &str, b: &str, c: &str| (a.to_string(), b.to_string(), c.to_string());
+
+        assert_eq!(
+            parse("alpine"),
+            owned("docker.io", "library/alpine", "latest")
+        );
+        assert_eq!(
+            parse("user/image:1.0"),
+            owned("docker.io", "user/image", "1.0")
+        );
+        assert_eq!(
+            parse("localhost:5000/puzzlefs"),
+            owned("localhost:5000", "puzzlefs", "latest")
+        );
+        assert_eq!(
+            parse("ghcr.io/project-machine/alpine:3.19"),
+            owned("ghcr.io", "project-machine/alpine", "3.19")
+        );
+        assert_eq!(
+            parse("quay.io/image@sha256:abcd"),
+            owned("quay.io", "image", "sha256:abcd")
+        );
+        assert_eq!(
+            Reference::from_str("quay.io/image@sha256:abcd")
+                .unwrap()
+                .to_string(),
+            "quay.io/image@sha256:abcd"
+        );
+        assert!(Reference::from_str("ghcr.io/:tag").is_err());
+    }
+
+    #[test]
+    fn test_parse_challenge() {
+        let (scheme, params) = parse_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/alpine:pull,push""#,
+        )
+        .unwrap();
+        assert_eq!(scheme, "Bearer");
+        assert_eq!(
+            params,
+            [
+                (
+                    "realm".to_string(),
+                    "https://auth.docker.io/token".to_string()
+                ),
+                ("service".to_string(), "registry.docker.io".to_string()),
+                (
+                    "scope".to_string(),
+                    "repository:library/alpine:pull,push".to_string()
+                ),
+            ]
+        );
+
+        let (scheme, params) = parse_challenge(r#"Basic realm="registry""#).unwrap();
+        assert_eq!(scheme, "Basic");
+        assert_eq!(params, [("realm".to_string(), "registry".to_string())]);
+    }
+
+    #[test]
+    fn test_env_proxy() {
+        let vars = |vars: &'static [(&str, &str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        let proxy = Some("http://proxy:3128".to_string());
+
+        let env = vars(&[
+            ("HTTPS_PROXY", "http://proxy:3128"),
+            ("no_proxy", "localhost, .internal.example.com"),
+        ]);
+        assert_eq!(env_proxy("https", "ghcr.io", &env), proxy);
+        assert_eq!(env_proxy("http", "ghcr.io", &env), None);
+        assert_eq!(env_proxy("https", "localhost:5000", &env), None);
+        assert_eq!(
+            env_proxy("https", "registry.internal.example.com", &env),
+            None
+        );
+        assert_eq!(env_proxy("https", "internal.example.com", &env), None);
+        assert_eq!(env_proxy("https", "example.com", &env), proxy);
+
+        let env = vars(&[("https_proxy", "http://proxy:3128"), ("NO_PROXY", "*")]);
+        assert_eq!(env_proxy("https", "ghcr.io", &env), None);
+        assert_eq!(env_proxy("https", "ghcr.io", vars(&[])), None);
+    }
+}
@@ -0,0 +1,188 @@
+// Credentials stored by `docker login`: the docker config.json either has them in its `auths`
+// (base64 encoded user:password), or names a credential helper (`docker-credential-<name>`)
+// keeping them, for one registry in `credHelpers` or for all of them in `credsStore`.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use base64::Engine;
+use serde::Deserialize;
+
+use super::{registry_error, DOCKER_HUB};
+use crate::format::Result;
+
+// the key docker uses for Docker Hub
+const DOCKER_HUB_SERVER: &str = "https://index.docker.io/v1/";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    creds_store: Option<String>,
+    #[serde(default)]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthEntry {
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+// The registry of a config key, which may be a URL like `https://index.docker.io/v1/`.
+fn registry_of(key: &str) -> &str {
+    let host = key
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    match host {
+        "index.docker.io" | "registry-1.docker.io" => DOCKER_HUB,
+        host => host,
+    }
+}
+
+impl DockerConfig {
+    /// The config of the docker CLI, `$DOCKER_CONFIG/config.json` or `~/.docker/config.json`.
+    pub(crate) fn load() -> Result<Self> {
+        let dir = match (env::var_os("DOCKER_CONFIG"), env::var_os("HOME")) {
+            (Some(dir), _) => PathBuf::from(dir),
+            (None, Some(home)) => Path::new(&home).join(".docker"),
+            (None, None) => return Ok(Self::default()),
+        };
+        Self::read(&dir.join("config.json"))
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        match fs::read(path) {
+            Ok(config) => Ok(serde_json::from_slice(&config)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The user and password stored for `registry`, if any.
+    pub(crate) fn credentials(&self, registry: &str) -> Result<Option<(String, String)>> {
+        let helper = self
+            .cred_helpers
+            .iter()
+            .find(|(key, _)| registry_of(key) == registry)
+            .map(|(_, helper)| helper)
+            .or(self.creds_store.as_ref());
+        if let Some(helper) = helper {
+            let server = if registry == DOCKER_HUB {
+                DOCKER_HUB_SERVER
+            } else {
+                registry
+            };
+            return run_helper(helper, server);
+        }
+
+        let Some(entry) = self
+            .auths
+            .iter()
+            .find(|(key, _)| registry_of(key) == registry)
+            .map(|(_, entry)| entry)
+        else {
+            return Ok(None);
+        };
+        if let (Some(user), Some(password)) = (&entry.username, &entry.password) {
+            return Ok(Some((user.clone(), password.clone())));
+        }
+        let Some(auth) = &entry.auth else {
+            return Ok(None);
+        };
+        let invalid = || registry_error(format!("invalid auth for {registry} in docker config"));
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(auth)
+            .map_err(|_| invalid())?;
+        let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+        let (user, password) = decoded.split_once(':').ok_or_else(invalid)?;
+        Ok(Some((user.to_string(), password.to_string())))
+    }
+}
+
+// Asks `docker-credential-<helper>` for the credentials of `server`.
+fn run_helper(helper: &str, server: &str) -> Result<Option<(String, String)>> {
+    let program = format!("docker-credential-{helper}");
+    let mut child = Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| registry_error(format!("running {program} failed: {e}")))?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(server.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stdout);
+        // the helpers print this when they have nothing for the server
+        if message.contains("credentials not found") {
+            return Ok(None);
+        }
+        return Err(registry_error(format!(
+            "{program} failed for {server}: {}",
+            message.trim()
+        )));
+    }
+    let credentials: HelperCredentials = serde_json::from_slice(&output.stdout)?;
+    Ok(Some((credentials.username, credentials.secret)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_docker_config_auths() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("config.json");
+        // user:secret and hub:token
+        fs::write(
+            &path,
+            r#"{"auths": {
+                "ghcr.io": {"auth": "dXNlcjpzZWNyZXQ="},
+                "https://index.docker.io/v1/": {"auth": "aHViOnRva2Vu"},
+                "localhost:5000": {"username": "local", "password": "pass"}
+            }}"#,
+        )?;
+        let config = DockerConfig::read(&path)?;
+        assert_eq!(
+            config.credentials("ghcr.io")?,
+            Some(("user".to_string(), "secret".to_string()))
+        );
+        assert_eq!(
+            config.credentials(DOCKER_HUB)?,
+            Some(("hub".to_string(), "token".to_string()))
+        );
+        assert_eq!(
+            config.credentials("localhost:5000")?,
+            Some(("local".to_string(), "pass".to_string()))
+        );
+        assert_eq!(config.credentials("quay.io")?, None);
+
+        // no config, no credentials
+        let config = DockerConfig::read(&dir.path().join("missing.json"))?;
+        assert_eq!(config.credentials("ghcr.io")?, None);
+        Ok(())
+    }
+}
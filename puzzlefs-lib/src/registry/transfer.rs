@@ -0,0 +1,190 @@
+// What the blob transfers of a registry share: the threads they run on, the bandwidth cap and
+// the progress they report.
+
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::format::Result;
+
+/// How many blobs are transferred at once unless [`super::RegistryOptions::parallelism`] says
+/// otherwise.
+pub const DEFAULT_PARALLELISM: usize = 4;
+
+/// Counters describing how far along the blob transfers of a push or a pull are. Blobs the
+/// destination already has count as done right away.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransferProgress {
+    pub blobs_total: u64,
+    pub blobs_done: u64,
+    /// The size of the blobs.
+    pub bytes_total: u64,
+    pub bytes_done: u64,
+    /// What went over the network so far, including the blobs being transferred.
+    pub bytes_transferred: u64,
+}
+
+/// A callback receiving [`TransferProgress`] updates, e.g. to draw a progress bar. It is called
+/// as data is sent or received, from all the transfer threads.
+#[derive(Clone, Default)]
+pub struct TransferReporter(Option<Arc<dyn Fn(&TransferProgress) + Send + Sync>>);
+
+impl TransferReporter {
+    pub fn new(callback: impl Fn(&TransferProgress) + Send + Sync + 'static) -> Self {
+        TransferReporter(Some(Arc::new(callback)))
+    }
+}
+
+impl fmt::Debug for TransferReporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("TransferReporter")
+            .field(&self.0.as_ref().map(|_| "callback"))
+            .finish()
+    }
+}
+
+pub(super) struct Transfers {
+    parallelism: usize,
+    // bytes per second, for all the transfers together
+    limit_rate: Option<u64>,
+    start: Instant,
+    progress: Mutex<TransferProgress>,
+    reporter: TransferReporter,
+}
+
+impl Transfers {
+    pub(super) fn new(
+        parallelism: usize,
+        limit_rate: Option<u64>,
+        reporter: TransferReporter,
+    ) -> Self {
+        Transfers {
+            parallelism: if parallelism == 0 {
+                DEFAULT_PARALLELISM
+            } else {
+                parallelism
+            },
+            limit_rate: limit_rate.filter(|rate| *rate > 0),
+            start: Instant::now(),
+            progress: Mutex::new(TransferProgress::default()),
+            reporter,
+        }
+    }
+
+    fn update(&self, update: impl FnOnce(&mut TransferProgress)) -> TransferProgress {
+        let mut progress = self.progress.lock().unwrap();
+        update(&mut progress);
+        if let Some(callback) = &self.reporter.0 {
+            callback(&progress);
+        }
+        *progress
+    }
+
+    pub(super) fn add_blobs(&self, sizes: impl IntoIterator<Item = u64>) {
+        self.update(|progress| {
+            for size in sizes {
+                progress.blobs_total += 1;
+                progress.bytes_total += size;
+            }
+        });
+    }
+
+    pub(super) fn blob_done(&self, size: u64) {
+        self.update(|progress| {
+            progress.blobs_done += 1;
+            progress.bytes_done += size;
+        });
+    }
+
+    // Counts `bytes` which went over the network, waiting for as long as it takes to stay under
+    // the rate limit on average since the transfers started.
+    fn transferred(&self, bytes: usize) {
+        let progress = self.update(|progress| progress.bytes_transferred += bytes as u64);
+        if let Some(rate) = self.limit_rate {
+            let due = Duration::from_secs_f64(progress.bytes_transferred as f64 / rate as f64);
+            if let Some(wait) = due.checked_sub(self.start.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+    }
+
+    // Runs `transfer` for every item, on up to `parallelism` threads, stopping at the first
+    // error.
+    pub(super) fn for_each<T: Sync>(
+        &self,
+        items: &[T],
+        transfer: impl Fn(&T) -> Result<()> + Sync,
+    ) -> Result<()> {
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let mut workers = Vec::new();
+            for _ in 0..self.parallelism.min(items.len()) {
+                workers.push(scope.spawn(|| {
+                    while !failed.load(Ordering::Relaxed) {
+                        let Some(item) = items.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                            break;
+                        };
+                        if let Err(e) = transfer(item) {
+                            failed.store(true, Ordering::Relaxed);
+                            return Err(e);
+                        }
+                    }
+                    Ok(())
+                }));
+            }
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().expect("transfer thread panicked"))
+        })
+    }
+}
+
+// A reader counting what it reads as transferred.
+pub(super) struct Metered<R> {
+    pub(super) inner: R,
+    pub(super) transfers: Arc<Transfers>,
+}
+
+impl<R: Read> Read for Metered<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.transfers.transferred(n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_rate() {
+        let transfers = Arc::new(Transfers::new(
+            2,
+            Some(100_000),
+            TransferReporter::default(),
+        ));
+        let start = Instant::now();
+        let data = vec![0; 25_000];
+        transfers
+            .for_each(&[(), (), (), ()], |_| {
+                let mut reader = Metered {
+                    inner: &data[..],
+                    transfers: Arc::clone(&transfers),
+                };
+                io::copy(&mut reader, &mut io::sink())?;
+                Ok(())
+            })
+            .unwrap();
+        // 100 KB at 100 KB/s
+        assert!(start.elapsed() >= Duration::from_millis(900));
+        assert_eq!(
+            transfers.progress.lock().unwrap().bytes_transferred,
+            100_000
+        );
+    }
+}
@@ -0,0 +1,763 @@
+//! Verification of [cosign](https://github.com/sigstore/cosign) signatures of images, so an
+//! image is only mounted if someone trusted signed its manifest. Images can also be signed with
+//! an Ed25519 key, see [`Image::sign`], the signature being stored the way cosign stores its own.
+//!
+//! Signatures are found where cosign puts them: as referrers of the manifest, or in the manifest
+//! tagged `sha256-<digest>.sig` (e.g. as copied into a layout by `cosign save` or `oras copy`).
+//! Each signature is a layer holding a "simple signing" payload which names the digest of the
+//! signed manifest, with the signature itself and, for keyless signatures, the Fulcio certificate
+//! and the Rekor bundle in its annotations.
+
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use base64::Engine;
+use ocidir::oci_spec::image::{
+    Descriptor, ImageManifest, ImageManifestBuilder, MediaType, SCHEMA_VERSION,
+};
+use p256::ecdsa::signature::Verifier;
+use p256::pkcs8::DecodePublicKey;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x509_cert::der::asn1::ObjectIdentifier;
+use x509_cert::der::{Decode, DecodePem, Encode, SecretDocument};
+use x509_cert::ext::pkix::name::GeneralName;
+use x509_cert::ext::pkix::{BasicConstraints, ExtendedKeyUsage, SubjectAltName};
+use x509_cert::spki::SubjectPublicKeyInfoOwned;
+use x509_cert::Certificate;
+
+use crate::format::{Result, WireFormatError};
+use crate::oci::Image;
+
+/// The artifact type of signatures attached as referrers.
+pub const COSIGN_SIGNATURE_ARTIFACT: &str = "application/vnd.dev.cosign.artifact.sig.v1+json";
+const SIMPLE_SIGNING: &str = "application/vnd.dev.cosign.simplesigning.v1+json";
+const SIMPLE_SIGNING_TYPE: &str = "cosign container image signature";
+const EMPTY_CONFIG: &[u8] = b"{}";
+
+const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+const CERTIFICATE_ANNOTATION: &str = "dev.sigstore.cosign/certificate";
+const CHAIN_ANNOTATION: &str = "dev.sigstore.cosign/chain";
+const BUNDLE_ANNOTATION: &str = "dev.sigstore.cosign/bundle";
+
+const ECDSA_WITH_SHA256: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.2");
+const ECDSA_WITH_SHA384: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.3");
+const ED25519_KEY: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+const SUBJECT_ALT_NAME: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.5.29.17");
+const BASIC_CONSTRAINTS: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.5.29.19");
+const EXTENDED_KEY_USAGE: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.5.29.37");
+const CODE_SIGNING: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.5.5.7.3.3");
+// the OIDC issuer of the identity in Fulcio certificates; the first one holds the raw string,
+// the second (newer) one a DER UTF8String
+const FULCIO_ISSUER: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.57264.1.1");
+const FULCIO_ISSUER_V2: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.57264.1.8");
+// how long a chain of intermediate certificates may get
+const MAX_CHAIN_DEPTH: usize = 4;
+
+/// Who has to have signed an image for it to be opened.
+pub enum SignaturePolicy {
+    /// Signed with the private half of this (ECDSA P-256) key, e.g. one from
+    /// `cosign generate-key-pair`.
+    Key(p256::ecdsa::VerifyingKey),
+    /// Signed with the private half of this Ed25519 key, e.g. by `puzzlefs build --sign-key`.
+    Ed25519([u8; 32]),
+    /// Signed keylessly: with a certificate Fulcio issued for `identity` (an email address or a
+    /// URI, e.g. of a CI workflow) as vouched for by `issuer`, and logged in Rekor while the
+    /// certificate was valid.
+    Keyless {
+        fulcio_roots: Vec<Certificate>,
+        rekor_key: p256::ecdsa::VerifyingKey,
+        identity: String,
+        issuer: String,
+    },
+}
+
+impl SignaturePolicy {
+    /// Reads a PEM encoded public key, as written by `cosign generate-key-pair` to `cosign.pub`.
+    pub fn from_key_file(path: &Path) -> Result<Self> {
+        let pem = fs::read_to_string(path)?;
+        let key = p256::ecdsa::VerifyingKey::from_public_key_pem(&pem)
+            .map_err(|e| signature_error(format!("invalid key {}: {e}", path.display())))?;
+        Ok(SignaturePolicy::Key(key))
+    }
+
+    /// Reads a PEM encoded Ed25519 public key, e.g. as written by `openssl pkey -pubout`.
+    pub fn from_ed25519_key_file(path: &Path) -> Result<Self> {
+        let invalid = |e: String| signature_error(format!("invalid key {}: {e}", path.display()));
+        let spki = SubjectPublicKeyInfoOwned::from_pem(fs::read(path)?)
+            .map_err(|e| invalid(e.to_string()))?;
+        if spki.algorithm.oid != ED25519_KEY {
+            return Err(invalid(format!("{} isn't Ed25519", spki.algorithm.oid)));
+        }
+        let key = spki
+            .subject_public_key
+            .raw_bytes()
+            .try_into()
+            .map_err(|_| invalid("wrong length".to_string()))?;
+        Ok(SignaturePolicy::Ed25519(key))
+    }
+
+    /// Reads the Fulcio root certificates and the Rekor public key (both PEM encoded) to check
+    /// keyless signatures of `identity` with, e.g. the ones of the public sigstore instance.
+    pub fn keyless(
+        fulcio_roots: &Path,
+        rekor_key: &Path,
+        identity: &str,
+        issuer: &str,
+    ) -> Result<Self> {
+        let roots = Certificate::load_pem_chain(&fs::read(fulcio_roots)?).map_err(|e| {
+            signature_error(format!(
+                "invalid certificates {}: {e}",
+                fulcio_roots.display()
+            ))
+        })?;
+        let rekor_key =
+            p256::ecdsa::VerifyingKey::from_public_key_pem(&fs::read_to_string(rekor_key)?)
+                .map_err(|e| {
+                    signature_error(format!("invalid key {}: {e}", rekor_key.display()))
+                })?;
+        Ok(SignaturePolicy::Keyless {
+            fulcio_roots: roots,
+            rekor_key,
+            identity: identity.to_string(),
+            issuer: issuer.to_string(),
+        })
+    }
+
+    /// Checks that one of `signatures` is a valid signature of the manifest with the digest
+    /// `manifest_digest` (e.g. `sha256:...`) under this policy.
+    pub fn verify(&self, manifest_digest: &str, signatures: &[CosignSignature]) -> Result<()> {
+        let mut errors = Vec::new();
+        for signature in signatures {
+            match self.verify_one(manifest_digest, signature) {
+                Ok(()) => return Ok(()),
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+        if errors.is_empty() {
+            return Err(signature_error(format!(
+                "no signatures of {manifest_digest}"
+            )));
+        }
+        Err(signature_error(format!(
+            "no valid signatures of {manifest_digest}: {}",
+            errors.join("; ")
+        )))
+    }
+
+    fn verify_one(&self, manifest_digest: &str, signature: &CosignSignature) -> Result<()> {
+        let payload: SimpleSigning = serde_json::from_slice(&signature.payload)?;
+        if payload.critical.kind != SIMPLE_SIGNING_TYPE {
+            return Err(signature_error(format!(
+                "unknown payload type {}",
+                payload.critical.kind
+            )));
+        }
+        if payload.critical.image.docker_manifest_digest != manifest_digest {
+            return Err(signature_error(format!(
+                "signature of {}",
+                payload.critical.image.docker_manifest_digest
+            )));
+        }
+        let raw_signature = base64::engine::general_purpose::STANDARD
+            .decode(&signature.signature)
+            .map_err(|e| signature_error(format!("invalid signature encoding: {e}")))?;
+        let ecdsa_signature = || {
+            p256::ecdsa::Signature::from_der(&raw_signature)
+                .map_err(|e| signature_error(format!("invalid signature: {e}")))
+        };
+
+        match self {
+            SignaturePolicy::Key(key) => key
+                .verify(&signature.payload, &ecdsa_signature()?)
+                .map_err(|_| signature_error("signature mismatch".to_string())),
+            SignaturePolicy::Ed25519(key) => UnparsedPublicKey::new(&ED25519, key)
+                .verify(&signature.payload, &raw_signature)
+                .map_err(|_| signature_error("signature mismatch".to_string())),
+            SignaturePolicy::Keyless {
+                fulcio_roots,
+                rekor_key,
+                identity,
+                issuer,
+            } => {
+                let pem = signature.certificate.as_deref().ok_or_else(|| {
+                    signature_error("keyless signature without a certificate".to_string())
+                })?;
+                let leaf = Certificate::from_pem(pem)
+                    .map_err(|e| signature_error(format!("invalid certificate: {e}")))?;
+                let intermediates = match &signature.chain {
+                    Some(chain) => Certificate::load_pem_chain(chain.as_bytes())
+                        .map_err(|e| signature_error(format!("invalid chain: {e}")))?,
+                    None => Vec::new(),
+                };
+                verify_chain(&leaf, &intermediates, fulcio_roots)?;
+                check_identity(&leaf, identity, issuer)?;
+                public_key(&leaf)?
+                    .verify(&signature.payload, &ecdsa_signature()?)
+                    .map_err(|_| signature_error("signature mismatch".to_string()))?;
+
+                let bundle = signature.bundle.as_deref().ok_or_else(|| {
+                    signature_error("keyless signature without a rekor bundle".to_string())
+                })?;
+                check_bundle(bundle, rekor_key, &leaf, signature)
+            }
+        }
+    }
+}
+
+/// An Ed25519 key to sign images with, see [`Image::sign`].
+pub struct SigningKey(Ed25519KeyPair);
+
+impl SigningKey {
+    /// Reads a PEM encoded PKCS#8 private key, e.g. as written by `openssl genpkey -algorithm
+    /// ed25519`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let pem = fs::read_to_string(path)?;
+        let invalid = |e: String| signature_error(format!("invalid key {}: {e}", path.display()));
+        let (label, der) = SecretDocument::from_pem(&pem).map_err(|e| invalid(e.to_string()))?;
+        if label != "PRIVATE KEY" {
+            return Err(invalid(format!("{label} isn't a PKCS#8 private key")));
+        }
+        Self::from_pkcs8(der.as_bytes()).map_err(|e| invalid(e.to_string()))
+    }
+
+    /// Reads a DER encoded PKCS#8 private key.
+    pub fn from_pkcs8(der: &[u8]) -> Result<Self> {
+        let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der)
+            .map_err(|e| signature_error(format!("invalid Ed25519 key: {e}")))?;
+        Ok(SigningKey(key_pair))
+    }
+
+    /// The policy accepting the images signed with this key.
+    pub fn policy(&self) -> SignaturePolicy {
+        // .unwrap() is fine, Ed25519 public keys are 32 bytes long
+        SignaturePolicy::Ed25519(self.0.public_key().as_ref().try_into().unwrap())
+    }
+
+    fn sign(&self, payload: Vec<u8>) -> CosignSignature {
+        let signature = self.0.sign(&payload);
+        CosignSignature {
+            payload,
+            signature: base64::engine::general_purpose::STANDARD.encode(signature.as_ref()),
+            ..Default::default()
+        }
+    }
+}
+
+/// A cosign signature, as kept in the layer of a signature manifest and its annotations.
+#[derive(Clone, Debug, Default)]
+pub struct CosignSignature {
+    /// the simple signing payload, which names the signed manifest
+    pub payload: Vec<u8>,
+    /// the base64 encoded (DER) ECDSA signature of the payload
+    pub signature: String,
+    /// the PEM encoded signing certificate of keyless signatures
+    pub certificate: Option<String>,
+    /// the PEM encoded intermediate certificates, if any
+    pub chain: Option<String>,
+    /// the Rekor bundle (JSON) proving when the signature was logged
+    pub bundle: Option<String>,
+}
+
+impl CosignSignature {
+    /// The simple signing payload to sign for the manifest with the digest `manifest_digest`,
+    /// as pushed to the registry as `reference`.
+    pub fn payload(reference: &str, manifest_digest: &str) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&SimpleSigning {
+            critical: Critical {
+                identity: Identity {
+                    docker_reference: reference.to_string(),
+                },
+                image: SignedImage {
+                    docker_manifest_digest: manifest_digest.to_string(),
+                },
+                kind: SIMPLE_SIGNING_TYPE.to_string(),
+            },
+            optional: None,
+        })?)
+    }
+
+    fn from_layer(image: &Image, layer: &Descriptor) -> Result<Self> {
+        let mut payload = Vec::new();
+        image
+            .open_raw_blob(layer.digest().digest(), None)?
+            .read_to_end(&mut payload)?;
+        if hex::encode(Sha256::digest(&payload)) != layer.digest().digest() {
+            return Err(WireFormatError::InvalidSerializedData(Backtrace::capture()));
+        }
+        let annotation = |name| {
+            layer
+                .annotations()
+                .as_ref()
+                .and_then(|a| a.get(name))
+                .cloned()
+        };
+        Ok(CosignSignature {
+            payload,
+            signature: annotation(SIGNATURE_ANNOTATION).unwrap_or_default(),
+            certificate: annotation(CERTIFICATE_ANNOTATION),
+            chain: annotation(CHAIN_ANNOTATION),
+            bundle: annotation(BUNDLE_ANNOTATION),
+        })
+    }
+
+    fn annotations(&self) -> HashMap<String, String> {
+        let mut annotations =
+            HashMap::from([(SIGNATURE_ANNOTATION.to_string(), self.signature.clone())]);
+        for (name, value) in [
+            (CERTIFICATE_ANNOTATION, &self.certificate),
+            (CHAIN_ANNOTATION, &self.chain),
+            (BUNDLE_ANNOTATION, &self.bundle),
+        ] {
+            if let Some(value) = value {
+                annotations.insert(name.to_string(), value.clone());
+            }
+        }
+        annotations
+    }
+}
+
+impl Image {
+    /// The cosign signatures of the manifest tagged `tag`, both the ones attached as referrers
+    /// and the ones in the `sha256-<digest>.sig` manifest.
+    pub fn signatures(&self, tag: &str) -> Result<Vec<CosignSignature>> {
+        let mut manifests = self
+            .referrers(tag, Some(COSIGN_SIGNATURE_ARTIFACT))?
+            .into_iter()
+            .map(|(_, manifest)| manifest)
+            .collect::<Vec<_>>();
+        let digest = self.manifest_digest(tag)?;
+        let sig_tag = format!("{}.sig", digest.replacen(':', "-", 1));
+        if let Some(manifest) = self.0.find_manifest_with_tag(&sig_tag)? {
+            manifests.push(manifest);
+        }
+
+        let mut signatures = Vec::new();
+        for manifest in manifests {
+            for layer in manifest.layers() {
+                if layer.media_type().to_string() == SIMPLE_SIGNING {
+                    signatures.push(CosignSignature::from_layer(self, layer)?);
+                }
+            }
+        }
+        Ok(signatures)
+    }
+
+    /// Attaches `signature` to the manifest tagged `tag` as a referrer, the way
+    /// `cosign sign --registry-referrers-mode=oci-1-1` does.
+    pub fn attach_signature(&self, tag: &str, signature: &CosignSignature) -> Result<Descriptor> {
+        let subject = self.find_subject(tag)?;
+        let config = self.write_raw_blob(EMPTY_CONFIG, MediaType::EmptyJSON)?;
+        let mut layer = self.write_raw_blob(
+            &signature.payload,
+            MediaType::Other(SIMPLE_SIGNING.to_string()),
+        )?;
+        layer.set_annotations(Some(signature.annotations()));
+        let manifest: ImageManifest = ImageManifestBuilder::default()
+            .schema_version(SCHEMA_VERSION)
+            .media_type(MediaType::ImageManifest)
+            .artifact_type(MediaType::Other(COSIGN_SIGNATURE_ARTIFACT.to_string()))
+            .config(config)
+            .layers(vec![layer])
+            .subject(subject)
+            .build()?;
+        self.insert_referrer(&manifest)
+    }
+
+    /// Signs the manifest tagged `tag` with `key`, attaching the signature as a referrer like
+    /// [`Image::attach_signature`] does; [`SigningKey::policy`] accepts the image then.
+    pub fn sign(&self, tag: &str, key: &SigningKey) -> Result<Descriptor> {
+        let payload = CosignSignature::payload(tag, &self.manifest_digest(tag)?)?;
+        self.attach_signature(tag, &key.sign(payload))
+    }
+
+    /// The digest (e.g. `sha256:...`) of the manifest tagged `tag`, computed from the manifest
+    /// itself.
+    pub fn manifest_digest(&self, tag: &str) -> Result<String> {
+        let mut manifest = Vec::new();
+        self.get_image_manifest_fd(tag)?
+            .read_to_end(&mut manifest)?;
+        Ok(format!("sha256:{}", hex::encode(Sha256::digest(&manifest))))
+    }
+}
+
+// https://github.com/containers/image/blob/main/docs/containers-signature.5.md
+#[derive(Serialize, Deserialize)]
+struct SimpleSigning {
+    critical: Critical,
+    optional: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Critical {
+    identity: Identity,
+    image: SignedImage,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Identity {
+    #[serde(rename = "docker-reference")]
+    docker_reference: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SignedImage {
+    #[serde(rename = "docker-manifest-digest")]
+    docker_manifest_digest: String,
+}
+
+// A Rekor bundle, the promise of the log to include an entry at some time.
+#[derive(Deserialize)]
+struct Bundle {
+    #[serde(rename = "SignedEntryTimestamp")]
+    signed_entry_timestamp: String,
+    #[serde(rename = "Payload")]
+    payload: BundlePayload,
+}
+
+// The fields are in the order of their names, so serializing it gives the canonical JSON the log
+// signed.
+#[derive(Serialize, Deserialize)]
+struct BundlePayload {
+    body: String,
+    #[serde(rename = "integratedTime")]
+    integrated_time: i64,
+    #[serde(rename = "logID")]
+    log_id: String,
+    #[serde(rename = "logIndex")]
+    log_index: i64,
+}
+
+// The body of a hashedrekord log entry, only as far as it's checked.
+#[derive(Deserialize)]
+struct HashedRekord {
+    spec: HashedRekordSpec,
+}
+
+#[derive(Deserialize)]
+struct HashedRekordSpec {
+    data: HashedRekordData,
+    signature: HashedRekordSignature,
+}
+
+#[derive(Deserialize)]
+struct HashedRekordData {
+    hash: HashedRekordHash,
+}
+
+#[derive(Deserialize)]
+struct HashedRekordHash {
+    algorithm: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct HashedRekordSignature {
+    content: String,
+    #[serde(rename = "publicKey")]
+    public_key: HashedRekordPublicKey,
+}
+
+#[derive(Deserialize)]
+struct HashedRekordPublicKey {
+    content: String,
+}
+
+// Checks that `leaf` was issued by one of `roots`, possibly through some of `intermediates`.
+fn verify_chain(
+    leaf: &Certificate,
+    intermediates: &[Certificate],
+    roots: &[Certificate],
+) -> Result<()> {
+    let mut current = leaf;
+    for _ in 0..MAX_CHAIN_DEPTH {
+        for root in roots {
+            if root.tbs_certificate.subject == current.tbs_certificate.issuer
+                && verify_issued(current, root).is_ok()
+            {
+                return Ok(());
+            }
+        }
+        current = intermediates
+            .iter()
+            .find(|c| {
+                c.tbs_certificate.subject == current.tbs_certificate.issuer
+                    && is_ca(c)
+                    && verify_issued(current, c).is_ok()
+            })
+            .ok_or_else(|| {
+                signature_error("the certificate isn't issued by a trusted root".to_string())
+            })?;
+    }
+    Err(signature_error("certificate chain too long".to_string()))
+}
+
+// Checks the signature of `issuer` on `cert`, Fulcio signs with P-256 or P-384 keys.
+fn verify_issued(cert: &Certificate, issuer: &Certificate) -> Result<()> {
+    let tbs = cert.tbs_certificate.to_der().map_err(der_error)?;
+    let spki = issuer
+        .tbs_certificate
+        .subject_public_key_info
+        .to_der()
+        .map_err(der_error)?;
+    let signature = cert.signature.raw_bytes();
+    let mismatch = |_| signature_error("certificate signature mismatch".to_string());
+    let algorithm = cert.signature_algorithm.oid;
+    if algorithm == ECDSA_WITH_SHA256 {
+        let key = p256::ecdsa::VerifyingKey::from_public_key_der(&spki)
+            .map_err(|e| signature_error(format!("invalid issuer key: {e}")))?;
+        let signature = p256::ecdsa::Signature::from_der(signature).map_err(mismatch)?;
+        key.verify(&tbs, &signature).map_err(mismatch)
+    } else if algorithm == ECDSA_WITH_SHA384 {
+        let key = p384::ecdsa::VerifyingKey::from_public_key_der(&spki)
+            .map_err(|e| signature_error(format!("invalid issuer key: {e}")))?;
+        let signature = p384::ecdsa::Signature::from_der(signature).map_err(mismatch)?;
+        key.verify(&tbs, &signature).map_err(mismatch)
+    } else {
+        Err(signature_error(format!(
+            "unsupported certificate signature algorithm {algorithm}"
+        )))
+    }
+}
+
+fn is_ca(cert: &Certificate) -> bool {
+    extension(cert, BASIC_CONSTRAINTS)
+        .and_then(|value| BasicConstraints::from_der(value).ok())
+        .is_some_and(|constraints| constraints.ca)
+}
+
+// Checks that `leaf` is a code signing certificate for `identity` as vouched for by `issuer`.
+fn check_identity(leaf: &Certificate, identity: &str, issuer: &str) -> Result<()> {
+    let code_signing = extension(leaf, EXTENDED_KEY_USAGE)
+        .and_then(|value| ExtendedKeyUsage::from_der(value).ok())
+        .is_some_and(|usage| usage.0.contains(&CODE_SIGNING));
+    if !code_signing {
+        return Err(signature_error(
+            "the certificate isn't for code signing".to_string(),
+        ));
+    }
+
+    let names = extension(leaf, SUBJECT_ALT_NAME)
+        .and_then(|value| SubjectAltName::from_der(value).ok())
+        .map(|names| names.0)
+        .unwrap_or_default();
+    let found = names.iter().any(|name| match name {
+        GeneralName::Rfc822Name(email) => email.to_string() == identity,
+        GeneralName::UniformResourceIdentifier(uri) => uri.to_string() == identity,
+        _ => false,
+    });
+    if !found {
+        return Err(signature_error(format!(
+            "the certificate isn't issued to {identity}"
+        )));
+    }
+
+    let found_issuer = match extension(leaf, FULCIO_ISSUER_V2) {
+        Some(value) => String::from_der(value).ok(),
+        None => {
+            extension(leaf, FULCIO_ISSUER).and_then(|value| String::from_utf8(value.to_vec()).ok())
+        }
+    };
+    if found_issuer.as_deref() != Some(issuer) {
+        return Err(signature_error(format!(
+            "the identity isn't vouched for by {issuer}"
+        )));
+    }
+    Ok(())
+}
+
+// Checks that the Rekor bundle is signed by the log, that the logged entry is `signature` and
+// that it was logged while `leaf` was valid.
+fn check_bundle(
+    bundle: &str,
+    rekor_key: &p256::ecdsa::VerifyingKey,
+    leaf: &Certificate,
+    signature: &CosignSignature,
+) -> Result<()> {
+    let bundle: Bundle = serde_json::from_str(bundle)?;
+    let engine = base64::engine::general_purpose::STANDARD;
+    let decode = |data: &str| {
+        engine
+            .decode(data)
+            .map_err(|e| signature_error(format!("invalid rekor bundle encoding: {e}")))
+    };
+
+    let set = p256::ecdsa::Signature::from_der(&decode(&bundle.signed_entry_timestamp)?)
+        .map_err(|e| signature_error(format!("invalid signed entry timestamp: {e}")))?;
+    rekor_key
+        .verify(&serde_json::to_vec(&bundle.payload)?, &set)
+        .map_err(|_| signature_error("signed entry timestamp mismatch".to_string()))?;
+
+    let entry: HashedRekord = serde_json::from_slice(&decode(&bundle.payload.body)?)?;
+    let payload_hash = hex::encode(Sha256::digest(&signature.payload));
+    let logged_signature = decode(&entry.spec.signature.content)?;
+    let logged_certificate =
+        Certificate::from_pem(decode(&entry.spec.signature.public_key.content)?)
+            .map_err(|e| signature_error(format!("invalid logged certificate: {e}")))?;
+    if entry.spec.data.hash.algorithm != "sha256"
+        || entry.spec.data.hash.value != payload_hash
+        || logged_signature != decode(&signature.signature)?
+        || &logged_certificate != leaf
+    {
+        return Err(signature_error(
+            "the rekor entry is for another signature".to_string(),
+        ));
+    }
+
+    let validity = &leaf.tbs_certificate.validity;
+    let logged = u64::try_from(bundle.payload.integrated_time)?;
+    if logged < validity.not_before.to_unix_duration().as_secs()
+        || logged > validity.not_after.to_unix_duration().as_secs()
+    {
+        return Err(signature_error(
+            "the signature was logged while the certificate wasn't valid".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn public_key(cert: &Certificate) -> Result<p256::ecdsa::VerifyingKey> {
+    let spki = cert
+        .tbs_certificate
+        .subject_public_key_info
+        .to_der()
+        .map_err(der_error)?;
+    p256::ecdsa::VerifyingKey::from_public_key_der(&spki)
+        .map_err(|e| signature_error(format!("unsupported certificate key: {e}")))
+}
+
+fn extension(cert: &Certificate, oid: ObjectIdentifier) -> Option<&[u8]> {
+    cert.tbs_certificate
+        .extensions
+        .as_ref()?
+        .iter()
+        .find(|extension| extension.extn_id == oid)
+        .map(|extension| extension.extn_value.as_bytes())
+}
+
+fn der_error(e: x509_cert::der::Error) -> WireFormatError {
+    signature_error(format!("invalid certificate: {e}"))
+}
+
+fn signature_error(message: String) -> WireFormatError {
+    WireFormatError::SignatureError(message, Backtrace::capture())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use crate::reader::PuzzleFS;
+    use p256::ecdsa::signature::Signer;
+    use p256::ecdsa::SigningKey;
+    use tempfile::tempdir;
+    use x509_cert::der::pem::LineEnding;
+    use x509_cert::der::Document;
+
+    fn sign(key: &SigningKey, payload: Vec<u8>) -> CosignSignature {
+        let signature: p256::ecdsa::Signature = key.sign(&payload);
+        CosignSignature {
+            payload,
+            signature: base64::engine::general_purpose::STANDARD
+                .encode(signature.to_der().as_bytes()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_verify_key() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let key = SigningKey::from_slice(&[7; 32])?;
+        let policy = SignaturePolicy::Key(*key.verifying_key());
+        let digest = image.manifest_digest("test")?;
+
+        // not signed at all
+        assert!(policy.verify(&digest, &image.signatures("test")?).is_err());
+
+        // signed by someone else, or another manifest
+        let other = SigningKey::from_slice(&[8; 32])?;
+        image.attach_signature(
+            "test",
+            &sign(&other, CosignSignature::payload("test", &digest)?),
+        )?;
+        image.attach_signature(
+            "test",
+            &sign(&key, CosignSignature::payload("test", "sha256:1234")?),
+        )?;
+        assert!(policy.verify(&digest, &image.signatures("test")?).is_err());
+
+        image.attach_signature(
+            "test",
+            &sign(&key, CosignSignature::payload("test", &digest)?),
+        )?;
+        assert_eq!(image.signatures("test")?.len(), 3);
+        policy.verify(&digest, &image.signatures("test")?)?;
+
+        let image = Image::open(dir.path())?.with_signature_policy(policy);
+        PuzzleFS::open(image, "test", None)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_ed25519_signature() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("oci"))?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+
+        let rng = ring::rand::SystemRandom::new();
+        let generate =
+            || Ed25519KeyPair::generate_pkcs8(&rng).map_err(|_| anyhow!("cannot generate a key"));
+        let pkcs8 = generate()?;
+        let pem = |label, der: &[u8]| Document::try_from(der)?.to_pem(label, LineEnding::LF);
+        let key_file = dir.path().join("key.pem");
+        fs::write(&key_file, pem("PRIVATE KEY", pkcs8.as_ref())?)?;
+        let key = super::SigningKey::from_file(&key_file)?;
+        let other = super::SigningKey::from_pkcs8(generate()?.as_ref())?;
+
+        let digest = image.manifest_digest("test")?;
+        assert!(key
+            .policy()
+            .verify(&digest, &image.signatures("test")?)
+            .is_err());
+        image.sign("test", &other)?;
+        assert!(key
+            .policy()
+            .verify(&digest, &image.signatures("test")?)
+            .is_err());
+        image.sign("test", &key)?;
+        key.policy().verify(&digest, &image.signatures("test")?)?;
+
+        // the public key as openssl writes it
+        let public_key = dir.path().join("key.pub");
+        let mut spki = hex::decode("302a300506032b6570032100")?;
+        spki.extend_from_slice(key.0.public_key().as_ref());
+        fs::write(&public_key, pem("PUBLIC KEY", &spki)?)?;
+        let policy = SignaturePolicy::from_ed25519_key_file(&public_key)?;
+        let image = Image::open(&dir.path().join("oci"))?.with_signature_policy(policy);
+        PuzzleFS::open(image, "test", None)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsigned_open_fails() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let key = SigningKey::from_slice(&[7; 32])?;
+        let image = image.with_signature_policy(SignaturePolicy::Key(*key.verifying_key()));
+        assert!(matches!(
+            image.open_rootfs_blob("test", None),
+            Err(WireFormatError::SignatureError(..))
+        ));
+        assert!(PuzzleFS::open(image, "test", None).is_err());
+        Ok(())
+    }
+}
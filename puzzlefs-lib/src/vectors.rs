@@ -0,0 +1,337 @@
+// Golden test vectors: canonical serializations of the format, written along with their digests
+// so that other implementations (e.g. the kernel driver) can check they read and write the same
+// bytes. Everything in them is fixed, so the same puzzlefs release always writes the same vectors,
+// and a change of their digests means a change of the format.
+
+use std::fs;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::builder::{build_initial_rootfs_with_options, serialize_metadata, BuildOptions};
+use crate::compression::Noop;
+use crate::format::{
+    Acl, AclEntry, AclTag, BlobRef, CompressionAlgorithm, DigestAlgorithm, DirEnt, DirList,
+    Features, FileChunk, FileDigest, Inode, InodeAdditional, InodeMode, InodeVector, Rootfs,
+    Timestamp, VerityData, Xattr, ACL_ACCESS_XATTR,
+};
+use crate::oci::Image;
+use crate::reader::PUZZLEFS_IMAGE_MANIFEST_VERSION;
+
+/// The tag of the image in the `image` directory of the vectors.
+pub const VECTORS_TAG: &str = "vectors";
+
+/// One of the files written by [`generate`], as listed in its `vectors.json`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TestVector {
+    pub name: String,
+    /// relative to the vectors directory
+    pub path: String,
+    /// hex encoded sha256 digest of the file
+    pub sha256: String,
+    pub description: String,
+}
+
+// the blob the file chunks of the inode vectors point to, which isn't part of the vectors
+const CHUNK_BLOB: [u8; 32] = [0x11; 32];
+const CHUNK_BLOB_VERITY: [u8; 32] = [0x22; 32];
+const MTIME: Timestamp = Timestamp {
+    sec: 1_700_000_000,
+    nsec: 123_456_789,
+};
+
+fn inode(ino: u64, mode: InodeMode, permissions: u16) -> Inode {
+    Inode {
+        ino,
+        mode,
+        uid: 1000,
+        gid: 1000,
+        permissions,
+        additional: None,
+        nlink: 1,
+        mtime: Some(MTIME),
+        ctime: Some(MTIME),
+        digest: None,
+        verity: None,
+    }
+}
+
+fn chunk(offset: u64, len: u64, compressed: bool) -> FileChunk {
+    FileChunk {
+        blob: Some(BlobRef {
+            digest: CHUNK_BLOB,
+            offset,
+            compressed,
+            algorithm: CompressionAlgorithm::Zstd,
+            encryption: None,
+        }),
+        len,
+        checksum: Some(0x1234_5678),
+        inline: None,
+    }
+}
+
+// One inode of every kind, using every optional part of the format but encryption and sharding.
+fn vector_inodes() -> anyhow::Result<Vec<Inode>> {
+    // sorted by name, as the builder writes them
+    let names = [
+        (10, "deleted"),
+        (7, "fifo"),
+        (2, "file"),
+        (2, "hardlink"),
+        (5, "link"),
+        (6, "null"),
+        (9, "sda"),
+        (8, "sock"),
+        (3, "sparse"),
+        (4, "tiny"),
+    ];
+    let entries = names
+        .iter()
+        .map(|(ino, name)| DirEnt {
+            ino: *ino,
+            name: name.as_bytes().to_vec(),
+        })
+        .collect();
+    let acl = Acl::new(vec![
+        AclEntry {
+            tag: AclTag::UserObj,
+            id: 0,
+            perm: 7,
+        },
+        AclEntry {
+            tag: AclTag::User,
+            id: 1001,
+            perm: 5,
+        },
+        AclEntry {
+            tag: AclTag::GroupObj,
+            id: 0,
+            perm: 5,
+        },
+        AclEntry {
+            tag: AclTag::Mask,
+            id: 0,
+            perm: 5,
+        },
+        AclEntry {
+            tag: AclTag::Other,
+            id: 0,
+            perm: 5,
+        },
+    ])?;
+
+    Ok(vec![
+        Inode {
+            additional: Some(InodeAdditional {
+                xattrs: vec![
+                    Xattr {
+                        key: ACL_ACCESS_XATTR.to_vec(),
+                        val: acl.to_xattr(),
+                    },
+                    Xattr {
+                        key: b"user.comment".to_vec(),
+                        val: b"golden".to_vec(),
+                    },
+                ],
+                symlink_target: None,
+            }),
+            ..inode(
+                1,
+                InodeMode::Dir {
+                    dir_list: DirList {
+                        look_below: false,
+                        entries,
+                    },
+                },
+                0o755,
+            )
+        },
+        Inode {
+            nlink: 2,
+            digest: Some(FileDigest {
+                algorithm: DigestAlgorithm::Sha256,
+                digest: vec![0x33; 32],
+            }),
+            verity: Some([0x44; 32]),
+            ..inode(
+                2,
+                InodeMode::File {
+                    chunks: vec![chunk(0, 65536, true), chunk(65536, 1000, false)],
+                },
+                0o644,
+            )
+        },
+        inode(
+            3,
+            InodeMode::File {
+                chunks: vec![FileChunk::hole(1 << 20), chunk(66536, 4096, true)],
+            },
+            0o600,
+        ),
+        inode(
+            4,
+            InodeMode::File {
+                chunks: vec![FileChunk::inline(b"tiny\n".to_vec())],
+            },
+            0o644,
+        ),
+        Inode {
+            additional: Some(InodeAdditional {
+                xattrs: Vec::new(),
+                symlink_target: Some(b"file".to_vec()),
+            }),
+            ..inode(5, InodeMode::Lnk, 0o777)
+        },
+        inode(6, InodeMode::Chr { major: 1, minor: 3 }, 0o666),
+        inode(7, InodeMode::Fifo, 0o644),
+        inode(8, InodeMode::Sock, 0o755),
+        inode(9, InodeMode::Blk { major: 8, minor: 0 }, 0o660),
+        Inode::new_whiteout(10),
+    ])
+}
+
+fn inode_vector_message(inodes: &[Inode]) -> anyhow::Result<Vec<u8>> {
+    let mut message = capnp::message::Builder::new_default();
+    let mut capnp_inodes = message.init_root::<crate::metadata_capnp::inode_vector::Builder<'_>>();
+    InodeVector::fill_capnp(inodes, &mut capnp_inodes, false)?;
+    let mut buf = Vec::new();
+    capnp::serialize::write_message(&mut buf, &message)?;
+    Ok(buf)
+}
+
+// The tree the image of the vectors is built from.
+fn vector_tree(rootfs: &Path) -> anyhow::Result<()> {
+    let data = (0..100_000u32)
+        .map(|i| (i * 7 % 251) as u8)
+        .collect::<Vec<_>>();
+    fs::create_dir_all(rootfs.join("dir"))?;
+    fs::write(rootfs.join("hello"), b"hello, world\n")?;
+    fs::write(rootfs.join("dir/data"), data)?;
+    fs::write(rootfs.join("dir/empty"), b"")?;
+    symlink("hello", rootfs.join("link"))?;
+    for (path, mode) in [
+        ("", 0o755),
+        ("dir", 0o755),
+        ("hello", 0o644),
+        ("dir/data", 0o600),
+        ("dir/empty", 0o644),
+    ] {
+        fs::set_permissions(rootfs.join(path), fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// Writes the test vectors to `out_dir`, along with `vectors.json`, which lists them with their
+/// sha256 digests:
+/// - `inodes.bin`: an InodeVector message with one inode of every kind;
+/// - `rootfs.bin`: a Rootfs message holding those inodes;
+/// - `image`: an OCI layout with a reproducible, uncompressed image built from a small tree,
+///   tagged [`VECTORS_TAG`], of which the manifest, rootfs and chunk blobs are listed.
+pub fn generate(out_dir: &Path) -> anyhow::Result<Vec<TestVector>> {
+    fs::create_dir_all(out_dir)?;
+    let mut vectors = Vec::new();
+    let mut add = |name: &str, path: &str, description: &str| -> anyhow::Result<()> {
+        let data = fs::read(out_dir.join(path))?;
+        vectors.push(TestVector {
+            name: name.to_string(),
+            path: path.to_string(),
+            sha256: hex::encode(Sha256::digest(&data)),
+            description: description.to_string(),
+        });
+        Ok(())
+    };
+
+    let inodes = vector_inodes()?;
+    fs::write(out_dir.join("inodes.bin"), inode_vector_message(&inodes)?)?;
+    add(
+        "inodes",
+        "inodes.bin",
+        "InodeVector message: a directory with an ACL and an xattr, a hard linked file with two \
+         chunks, a file digest and an fs-verity digest, a sparse file, a file with inline data, a symlink, character \
+         and block devices, a fifo, a socket and a whiteout",
+    )?;
+
+    let features = Features::used_by(&[inodes]);
+    let rootfs = Rootfs {
+        metadatas: vec![vector_inodes()?],
+        fs_verity_data: VerityData::from([(CHUNK_BLOB, CHUNK_BLOB_VERITY)]),
+        manifest_version: PUZZLEFS_IMAGE_MANIFEST_VERSION,
+        parent: None,
+        features,
+        digest_algorithm: DigestAlgorithm::Sha256,
+    };
+    fs::write(out_dir.join("rootfs.bin"), serialize_metadata(rootfs)?)?;
+    add(
+        "rootfs",
+        "rootfs.bin",
+        &format!(
+            "Rootfs message of manifest version {PUZZLEFS_IMAGE_MANIFEST_VERSION} holding the \
+             inodes of inodes.bin, with features {features}"
+        ),
+    )?;
+
+    let tree = tempfile::tempdir()?;
+    vector_tree(tree.path())?;
+    let image_dir = out_dir.join("image");
+    if image_dir.exists() {
+        fs::remove_dir_all(&image_dir)?;
+    }
+    let image = Image::new(&image_dir)?;
+    let options = BuildOptions {
+        reproducible: true,
+        ..Default::default()
+    };
+    build_initial_rootfs_with_options::<Noop>(tree.path(), &image, VECTORS_TAG, &options)?;
+
+    let blob_path = |digest: &str| format!("image/{}/{digest}", Image::blob_path().display());
+    let manifest = image.find_manifest_descriptor(VECTORS_TAG)?;
+    add(
+        "manifest",
+        &blob_path(manifest.digest().digest()),
+        &format!(
+            "image manifest tagged {VECTORS_TAG}: a reproducible build of hello (\"hello, \
+             world\\n\", 0644), dir (0755), dir/data (100000 bytes, byte i being i * 7 % 251, \
+             0600), dir/empty (0644) and link (a symlink to hello), without compression"
+        ),
+    )?;
+    for layer in image.find_manifest(VECTORS_TAG)?.layers() {
+        add(
+            &layer.media_type().to_string(),
+            &blob_path(layer.digest().digest()),
+            "blob of the image",
+        )?;
+    }
+
+    fs::write(
+        out_dir.join("vectors.json"),
+        serde_json::to_string_pretty(&vectors)?,
+    )?;
+    Ok(vectors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::RootfsReader;
+    use crate::reader::PuzzleFS;
+
+    #[test]
+    fn test_vectors_are_stable() -> anyhow::Result<()> {
+        let first = tempfile::tempdir()?;
+        let second = tempfile::tempdir()?;
+        let vectors = generate(first.path())?;
+        assert_eq!(vectors, generate(second.path())?);
+
+        let rootfs = RootfsReader::from_bytes(&fs::read(first.path().join("rootfs.bin"))?)?;
+        assert_eq!(rootfs.find_inode(4)?, vector_inodes()?.remove(3));
+        assert!(rootfs.get_features()?.contains(Features::INLINE_DATA));
+
+        let image = Image::open(&first.path().join("image"))?;
+        let pfs = PuzzleFS::open(image, VECTORS_TAG, None)?;
+        assert!(pfs.lookup(Path::new("/dir/data"))?.is_some());
+        Ok(())
+    }
+}